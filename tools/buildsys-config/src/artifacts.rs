@@ -0,0 +1,155 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A directory of build artifacts with a layout that stays the same between releases, unlike the
+/// `build` directory. It's set with `--artifacts-dir`, or `artifacts-dir` in Twoliter.toml, and
+/// each build of a variant for an architecture replaces the contents of:
+///
+/// * `<dir>/<variant>/<arch>/images`: the disk images and other files to boot or publish.
+/// * `<dir>/<variant>/<arch>/rpms`: the RPMs of the project's packages that the variant uses.
+/// * `<dir>/<variant>/<arch>/metadata`: the JSON descriptions of the build, and its changelog.
+///
+/// Each build of a kit for an architecture replaces the contents of `<dir>/kits/<kit>/<arch>`.
+///
+/// Files are hard links to the outputs in `build` where possible, so that they take no more space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactsDir {
+    root: PathBuf,
+}
+
+impl ArtifactsDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn images_dir(&self, variant: &str, arch: &str) -> PathBuf {
+        self.root.join(variant).join(arch).join("images")
+    }
+
+    pub fn rpms_dir(&self, variant: &str, arch: &str) -> PathBuf {
+        self.root.join(variant).join(arch).join("rpms")
+    }
+
+    pub fn metadata_dir(&self, variant: &str, arch: &str) -> PathBuf {
+        self.root.join(variant).join(arch).join("metadata")
+    }
+
+    pub fn kit_dir(&self, kit: &str, arch: &str) -> PathBuf {
+        self.root.join("kits").join(kit).join(arch)
+    }
+
+    /// Replaces the images and metadata of a variant with the files in `output_dir`, where its
+    /// build left them, and `extra_metadata`, such as a changelog kept elsewhere.
+    pub fn store_variant(
+        &self,
+        variant: &str,
+        arch: &str,
+        output_dir: &Path,
+        extra_metadata: &[PathBuf],
+    ) -> io::Result<()> {
+        let mut images = Vec::new();
+        let mut metadata = extra_metadata
+            .iter()
+            .filter(|path| path.exists())
+            .cloned()
+            .collect::<Vec<_>>();
+        for entry in fs::read_dir(output_dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                continue;
+            }
+            if is_metadata(&path) {
+                metadata.push(path);
+            } else {
+                images.push(path);
+            }
+        }
+        replace_dir(&self.images_dir(variant, arch), &images)?;
+        replace_dir(&self.metadata_dir(variant, arch), &metadata)
+    }
+
+    /// Replaces the RPMs of a variant with `rpms`.
+    pub fn store_rpms(&self, variant: &str, arch: &str, rpms: &[PathBuf]) -> io::Result<()> {
+        replace_dir(&self.rpms_dir(variant, arch), rpms)
+    }
+
+    /// Replaces the files of a kit with those in `output_dir`, where its build left them. Hidden
+    /// files, which builds keep for themselves, are left out.
+    pub fn store_kit(&self, kit: &str, arch: &str, output_dir: &Path) -> io::Result<()> {
+        let dir = self.kit_dir(kit, arch);
+        let mut files = Vec::new();
+        for entry in fs::read_dir(output_dir)? {
+            let path = entry?.path();
+            if !path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+            {
+                files.push(path);
+            }
+        }
+        replace_dir(&dir, &[])?;
+        for file in files {
+            if file.is_dir() && !file.is_symlink() {
+                let name = file.file_name().unwrap_or_default();
+                copy_tree(&file, &dir.join(name))?;
+            } else {
+                link_into(&dir, &file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a build output describes the build, rather than being something to boot or publish.
+fn is_metadata(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "json" || ext == "md")
+}
+
+/// Empties `dir` and links each of `files` into it by name. Symlinks are copied as symlinks, and
+/// files are copied if they can't be linked, such as when `dir` is on another filesystem.
+fn replace_dir(dir: &Path, files: &[PathBuf]) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    fs::create_dir_all(dir)?;
+    for file in files {
+        link_into(dir, file)?;
+    }
+    Ok(())
+}
+
+/// Links `file` into `dir` by name, as `replace_dir` does.
+fn link_into(dir: &Path, file: &Path) -> io::Result<()> {
+    let Some(name) = file.file_name() else {
+        return Ok(());
+    };
+    let target = dir.join(name);
+    if file.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(file)?, &target)?;
+    } else if fs::hard_link(file, &target).is_err() {
+        fs::copy(file, &target)?;
+    }
+    Ok(())
+}
+
+/// Links every file under `from` into the same place under `to`, which must not exist.
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.is_symlink() {
+            copy_tree(&path, &to.join(path.file_name().unwrap_or_default()))?;
+        } else {
+            link_into(to, &path)?;
+        }
+    }
+    Ok(())
+}
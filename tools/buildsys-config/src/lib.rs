@@ -1,2 +1,53 @@
+mod artifacts;
+
+pub use artifacts::ArtifactsDir;
+use std::path::{Path, PathBuf};
+
 pub const EXTERNAL_KIT_DIRECTORY: &str = "build/external-kits";
 pub const EXTERNAL_KIT_METADATA: &str = "build/external-kits/external-kit-metadata.json";
+
+/// Each package build writes the files it depends on to a file with the package's name in this
+/// directory, one path per line, so that tools can watch them for changes.
+pub const PACKAGE_WATCH_DIRECTORY: &str = "build/watch";
+
+/// Each build writes its progress to a JSON file in this directory, named for the artifact and
+/// architecture, so that twoliter can show what is being built while cargo hides the output.
+pub const BUILD_PROGRESS_DIRECTORY: &str = "build/progress";
+
+/// Builds append a line of JSON to this file for each kind of input or output they either served
+/// from a cache or had to download or build, so that twoliter can report how well caching works.
+pub const CACHE_LOG: &str = "build/cache-log.json";
+
+/// Package builds append a line of JSON to this file for each attempt to fetch an external file,
+/// including the files that are already in the local cache. Once it grows past a limit, it's moved
+/// to [`FETCH_LOG_PREVIOUS`], replacing the file there, so that the two hold the latest attempts.
+pub const FETCH_LOG: &str = "build/fetch-log.jsonl";
+
+/// The fetch attempts from before [`FETCH_LOG`] last grew past its limit.
+pub const FETCH_LOG_PREVIOUS: &str = "build/fetch-log.previous.jsonl";
+
+/// Each package build writes the digest and size of every file in its local source cache, the
+/// external files and the bundles made from them, to a JSON file with the package's name in this
+/// directory, so that damaged files can be found without reading the manifests.
+pub const SOURCE_CACHE_INDEX_DIRECTORY: &str = "build/cache-index";
+
+/// Each package and variant build that succeeds writes the digests of its inputs, and where the
+/// files among them are, to a JSON file in this directory, named for the artifact and architecture,
+/// so that twoliter can tell which builds are stale without running them.
+pub const BUILD_INPUTS_DIRECTORY: &str = "build/inputs";
+
+/// When rebuilds are explained, each build writes the files and environment variables that cargo
+/// watches for it, and why it ran, to a JSON file in this directory, named for the artifact and
+/// architecture, so that twoliter can say what made cargo run a build again.
+pub const REBUILD_REASONS_DIRECTORY: &str = "build/state/rebuilds";
+
+/// The directory in `images_dir` that the builds of `variant` for `arch` write their images to.
+/// Each build writes to a subdirectory named for its version, and `latest` links to the last one.
+pub fn variant_images_dir(images_dir: &Path, arch: &str, variant: &str) -> PathBuf {
+    images_dir.join(format!("{arch}-{variant}"))
+}
+
+/// Where the changelog for a variant is written, next to its images.
+pub fn variant_changelog_path(images_dir: &Path, arch: &str, variant: &str) -> PathBuf {
+    variant_images_dir(images_dir, arch, variant).join(format!("CHANGELOG-{variant}.md"))
+}
@@ -0,0 +1,98 @@
+/*!
+Narrows the build context of each package build to the files that the build reads.
+
+Docker sends the whole context to the builder before it starts, and a change to any file in it can
+invalidate steps that mount it. The shared Dockerfile's ignore file already leaves out most of the
+project, but still sends every spec, every tool, and the Rust sources to every package build. Each
+package build instead uses its own copy of the Dockerfile with an ignore file next to it, which
+Docker prefers to the shared one, listing only what that package needs.
+
+Package builds reach everything else, such as the package's sources and the RPMs of its
+dependencies, through the pipesys bypass, which doesn't go through the context.
+*/
+
+use super::error::{self, Result};
+use buildsys::manifest::ManifestInfo;
+use snafu::ResultExt;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The tools in `build/tools` that package builds run from the context.
+const PACKAGE_TOOLS: [&str; 2] = ["pipesys", "unplug"];
+
+/// The patterns of an ignore file that leaves only what the package build needs in the context.
+///
+/// `.cargo` and `sources` are mounted by every package build, so they're kept as empty directories
+/// for packages that don't build from them.
+pub(crate) fn package_ignore(info: &ManifestInfo) -> String {
+    let package = info.package_name();
+    let mut ignore = String::from("# Generated by buildsys for the build of this package.\n*\n\n");
+    let _ = writeln!(ignore, "!/Twoliter.toml");
+    for tool in PACKAGE_TOOLS {
+        let _ = writeln!(ignore, "!/build/tools/{tool}");
+    }
+    if info.dockerfile_fragment().is_some() {
+        let _ = writeln!(ignore, "!/packages/{package}");
+    } else {
+        let _ = writeln!(ignore, "!/packages/{package}/{package}.spec");
+    }
+
+    let _ = writeln!(ignore, "!/.cargo\n!/sources");
+    if info
+        .source_groups()
+        .is_some_and(|groups| !groups.is_empty())
+    {
+        let _ = writeln!(ignore, "*/target/*\n**/target/*");
+    } else {
+        let _ = writeln!(ignore, "/.cargo/*\n/sources/*");
+    }
+
+    for path in info.context_paths() {
+        let _ = writeln!(ignore, "!/{}", path.display().to_string().trim_matches('/'));
+    }
+    ignore
+}
+
+/// Writes a package's Dockerfile to `<dir>/<name>.Dockerfile`, with its ignore file next to it
+/// where Docker looks for it, and returns the Dockerfile's path.
+pub(crate) fn write_package_dockerfile(
+    dir: &Path,
+    name: &str,
+    dockerfile: &str,
+    ignore: &str,
+) -> Result<PathBuf> {
+    fs::create_dir_all(dir).context(error::DirectoryCreateSnafu { path: dir })?;
+    let path = dir.join(format!("{name}.Dockerfile"));
+    let ignore_path = dir.join(format!("{name}.Dockerfile.dockerignore"));
+    fs::write(&path, dockerfile).context(error::FileCreateSnafu { path: &path })?;
+    fs::write(&ignore_path, ignore).context(error::FileCreateSnafu { path: &ignore_path })?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info(build_package: &str) -> ManifestInfo {
+        toml::from_str(&format!(
+            "[package]\nname = \"glibc\"\n[package.metadata.build-package]\n{build_package}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_package_ignore() {
+        let ignore = package_ignore(&info(""));
+        assert!(ignore.contains("\n!/packages/glibc/glibc.spec\n"));
+        assert!(ignore.contains("\n!/build/tools/unplug\n"));
+        assert!(ignore.contains("\n/sources/*\n"));
+        assert!(!ignore.contains("!/build/tools\n"));
+
+        let ignore = package_ignore(&info(
+            "source-groups = [\"api\"]\ncontext-paths = [\"licenses/\"]",
+        ));
+        assert!(!ignore.contains("\n/sources/*\n"));
+        assert!(ignore.ends_with("\n!/licenses\n"));
+    }
+}
@@ -0,0 +1,105 @@
+/*!
+Gives a package build the devices that its manifest asks for in `check-devices`, so that test
+suites in `%check` can use hardware such as GPUs.
+
+BuildKit exposes CDI devices to a `RUN` step that names them with `--device`, which needs a newer
+Dockerfile frontend than the shared Dockerfile's, and a build that is allowed the `device`
+entitlement. Only the package's own copy of the Dockerfile is changed, so builds of other packages
+keep using the shared frontend and never see a device.
+
+Devices are shared with the host, so a package can only use the devices that the project allows.
+*/
+
+use super::error::{self, Result};
+use snafu::{ensure, OptionExt};
+
+/// The line in the spec build step that the package's `--device` flags replace.
+const DEVICE_MARKER: &str = "# buildsys: package devices";
+
+/// The Dockerfile frontend that supports `RUN --device`.
+const DEVICE_SYNTAX: &str = "# syntax=docker/dockerfile:1.14-labs";
+
+/// The arguments for `docker build` that allow a build to use devices.
+pub(crate) const DEVICE_ENTITLEMENT: [&str; 2] = ["--allow", "device"];
+
+/// Checks that each of `devices` is in `allowed`, the CDI devices that the project allows,
+/// separated by commas.
+pub(crate) fn check_allowed(devices: &[String], allowed: Option<&str>) -> Result<()> {
+    let allowed = allowed
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|device| !device.is_empty())
+        .collect::<Vec<_>>();
+    for device in devices {
+        ensure!(
+            allowed.contains(&device.as_str()),
+            error::DeviceNotAllowedSnafu { device }
+        );
+    }
+    Ok(())
+}
+
+/// Gives the spec build step of `dockerfile` the devices, and switches it to a frontend that
+/// supports them.
+pub(crate) fn splice(dockerfile: &str, devices: &[String]) -> Result<String> {
+    let mut lines = dockerfile.lines().map(String::from).collect::<Vec<_>>();
+    let marker = lines
+        .iter()
+        .position(|line| line.trim() == DEVICE_MARKER)
+        .context(error::DeviceMarkerSnafu)?;
+    let indent = &lines[marker][..lines[marker].len() - lines[marker].trim_start().len()];
+    let flags = devices
+        .iter()
+        .map(|device| format!("{indent}--device={device} \\"))
+        .collect::<Vec<_>>();
+    lines.splice(marker..=marker, flags);
+
+    match lines.first_mut() {
+        Some(first) if first.starts_with("# syntax=") => *first = DEVICE_SYNTAX.to_string(),
+        _ => lines.insert(0, DEVICE_SYNTAX.to_string()),
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHARED: &str = "\
+# syntax=docker/dockerfile:1.4.3
+FROM sdk AS rpmbuild
+RUN --mount=target=/host \\
+    # buildsys: package devices
+    rpmbuild -bb package.spec
+";
+
+    #[test]
+    fn test_splice() {
+        let devices = vec![
+            "nvidia.com/gpu=all".to_string(),
+            "vendor.com/fpga=0".to_string(),
+        ];
+        assert_eq!(
+            splice(SHARED, &devices).unwrap(),
+            "\
+# syntax=docker/dockerfile:1.14-labs
+FROM sdk AS rpmbuild
+RUN --mount=target=/host \\
+    --device=nvidia.com/gpu=all \\
+    --device=vendor.com/fpga=0 \\
+    rpmbuild -bb package.spec
+"
+        );
+        assert!(splice("FROM sdk AS rpmbuild\n", &devices).is_err());
+    }
+
+    #[test]
+    fn test_check_allowed() {
+        let devices = vec!["nvidia.com/gpu=0".to_string()];
+        check_allowed(&devices, Some("nvidia.com/gpu=all, nvidia.com/gpu=0")).unwrap();
+        assert!(check_allowed(&devices, Some("nvidia.com/gpu=all")).is_err());
+        assert!(check_allowed(&devices, None).is_err());
+        check_allowed(&[], None).unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+use lazy_static::lazy_static;
+use regex::Regex;
 use snafu::Snafu;
 use std::path::PathBuf;
 
@@ -16,18 +18,84 @@ pub(crate) enum Error {
     #[snafu(display("Failed to read repo root '{}'", root_json_path.display()))]
     BadRootJson { root_json_path: PathBuf },
 
+    #[snafu(display("Failed to open build slot '{}': {}", path.display(), source))]
+    BuildSlotOpen {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to lock build slot '{}': {}", path.display(), source))]
+    BuildSlotLock {
+        path: PathBuf,
+        source: nix::errno::Errno,
+    },
+
     #[snafu(display("Failed to start command: {}", source))]
     CommandStart { source: std::io::Error },
 
+    #[snafu(display("Failed to read command output: {}", source))]
+    CommandOutput { source: std::io::Error },
+
+    #[snafu(display("Command output ended before the command exited"))]
+    CommandStatus,
+
     #[snafu(display("Failed to execute command: 'docker {}'", args))]
     DockerExecution { args: String },
 
+    #[snafu(display(
+        "Failed to execute command: 'docker {}', the full output is in '{}'",
+        args,
+        log.display()
+    ))]
+    DockerExecutionLogged { args: String, log: PathBuf },
+
+    #[snafu(display("Interrupted while running 'docker {}'", args))]
+    Interrupted { args: String },
+
+    #[snafu(display(
+        "Only {} is free for {} in '{}', and the build needs about {}. To free space, {}",
+        available,
+        what,
+        path.display(),
+        required,
+        advice
+    ))]
+    LowSpace {
+        what: String,
+        path: PathBuf,
+        available: String,
+        required: String,
+        advice: String,
+    },
+
+    #[snafu(display(
+        "Stopped the build since only {} was left for {} in '{}'. To free space, {}",
+        available,
+        what,
+        path.display(),
+        advice
+    ))]
+    SpaceExhausted {
+        what: String,
+        path: PathBuf,
+        available: String,
+        advice: String,
+    },
+
     #[snafu(display("Failed to change directory to '{}': {}", path.display(), source))]
     DirectoryChange {
         path: PathBuf,
         source: std::io::Error,
     },
 
+    #[snafu(display("Unable to use Dockerfile fragment '{}': {}", path.display(), reason))]
+    DockerfileFragment { path: PathBuf, reason: String },
+
+    #[snafu(display("{source}"))]
+    Extract {
+        source: crate::extract::error::Error,
+    },
+
     #[snafu(display("Failed to get parent directory for '{}'", path.display()))]
     BadDirectory { path: PathBuf },
 
@@ -58,6 +126,18 @@ pub(crate) enum Error {
         source: std::io::Error,
     },
 
+    #[snafu(display("Failed to change owner of '{}': {}", path.display(), source))]
+    FileChown {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to read file '{}': {}", path.display(), source))]
+    FileRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Failed to remove file '{}': {}", path.display(), source))]
     FileRemove {
         path: PathBuf,
@@ -74,6 +154,56 @@ pub(crate) enum Error {
     #[snafu(display("Failed to create build arguments due to a dependency error: {source}"))]
     Graph { source: buildsys::manifest::Error },
 
+    #[snafu(display("{source}"))]
+    ImageLayout { source: buildsys::manifest::Error },
+
+    #[snafu(display("{source}"))]
+    BootConfig { source: buildsys::manifest::Error },
+
+    #[snafu(display(
+        "The variant sets boot-config, which needs the 'grub-set-private-var' image feature"
+    ))]
+    BootConfigFeature,
+
+    #[snafu(display("{source}"))]
+    FileOverlay { source: buildsys::manifest::Error },
+
+    #[snafu(display("File overlay path '{}' is given more than once", path.display()))]
+    DuplicateOverlay { path: PathBuf },
+
+    #[snafu(display("Failed to serialize file overlays: {source}"))]
+    OverlaySerialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to read the sources of package '{}': {}", path.display(), source))]
+    PackageSources {
+        path: PathBuf,
+        source: buildsys::manifest::Error,
+    },
+
+    #[snafu(display("Failed to serialize package sources: {source}"))]
+    PackageSourcesSerialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to write package sources to '{}': {}", path.display(), source))]
+    PackageSourcesWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("{source}"))]
+    KernelModule { source: buildsys::manifest::Error },
+
+    #[snafu(display("{source}"))]
+    CheckDevices { source: buildsys::manifest::Error },
+
+    #[snafu(display("Device '{device}' is not in the 'devices' table of Twoliter.toml"))]
+    DeviceNotAllowed { device: String },
+
+    #[snafu(display("The Dockerfile has no place for the devices of package builds"))]
+    DeviceMarker,
+
+    #[snafu(display("{source}"))]
+    KernelModules { source: crate::kmod::error::Error },
+
     #[snafu(display("Missing environment variable '{}'", var))]
     Environment {
         var: String,
@@ -87,6 +217,12 @@ pub(crate) enum Error {
         source: std::path::StripPrefixError,
     },
 
+    #[snafu(display(
+        "Docker uses userns-remap, but {}; set BUILDSYS_USERNS_REMAP to '<uid>:<gid>'",
+        reason
+    ))]
+    UsernsRemap { reason: String },
+
     #[snafu(display("Failed to parse variant: {source}"))]
     VariantParse {
         source: bottlerocket_variant::error::Error,
@@ -94,3 +230,157 @@ pub(crate) enum Error {
 }
 
 pub(super) type Result<T> = std::result::Result<T, Error>;
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/*
+There's a bug in BuildKit that can lead to a build failure during parallel
+`docker build` executions:
+   https://github.com/moby/buildkit/issues/1090
+
+Unfortunately we can't do much to control the concurrency here, and even when
+the bug is fixed there will be many older versions of Docker in the wild.
+
+The failure has an exit code of 1, which is too generic to be helpful. All we
+can do is check the output for the error's signature, and retry if we find it.
+*/
+lazy_static! {
+    static ref DOCKER_BUILD_FRONTEND_ERROR: Regex = Regex::new(concat!(
+        r#"failed to solve with frontend dockerfile.v0: "#,
+        r#"failed to solve with frontend gateway.v0: "#,
+        r#"frontend grpc server closed unexpectedly"#
+    ))
+    .unwrap();
+}
+
+/*
+There's a similar bug that's fixed in new releases of BuildKit but still in the wild in popular
+versions of Docker/BuildKit:
+   https://github.com/moby/buildkit/issues/1468
+*/
+lazy_static! {
+    static ref DOCKER_BUILD_DEAD_RECORD_ERROR: Regex = Regex::new(concat!(
+        r#"failed to solve with frontend dockerfile.v0: "#,
+        r#"failed to solve with frontend gateway.v0: "#,
+        r#"rpc error: code = Unknown desc = failed to build LLB: "#,
+        r#"failed to get dead record"#,
+    ))
+    .unwrap();
+}
+
+/*
+We also see sporadic CI failures with only this error message.
+We use (?m) for multi-line mode so we can match the message on a line of its own without splitting
+the output ourselves; we match the regexes against the whole of stdout.
+*/
+lazy_static! {
+    static ref UNEXPECTED_EOF_ERROR: Regex = Regex::new("(?m)unexpected EOF$").unwrap();
+}
+
+/*
+Sometimes new RPMs are not fully written to the host directory before another build starts, which
+exposes `createrepo_c` to partially-written RPMs that cannot be added to the repo metadata. Retry
+these errors by restarting the build since the alternatives are to ignore the `createrepo_c` exit
+code (masking other problems) or aggressively `sync()` the host directory (hurting performance).
+*/
+lazy_static! {
+    static ref CREATEREPO_C_READ_HEADER_ERROR: Regex = Regex::new(&regex::escape(
+        r#"C_CREATEREPOLIB: Warning: read_header: rpmReadPackageFile() error"#
+    ))
+    .unwrap();
+}
+
+/*
+Registries and package mirrors sometimes fail requests while under load or being deployed, and
+rate limit clients that pull too much at once. None of these say anything about the build itself.
+*/
+lazy_static! {
+    static ref REGISTRY_SERVER_ERROR: Regex = Regex::new(
+        r#"(?i)\b(500 Internal Server Error|502 Bad Gateway|503 Service Unavailable|504 Gateway Time-?out)\b"#
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    static ref REGISTRY_RATE_LIMIT_ERROR: Regex =
+        Regex::new(r#"(?i)\btoomanyrequests\b|429 Too Many Requests"#).unwrap();
+}
+
+lazy_static! {
+    static ref FETCH_METADATA_ERROR: Regex =
+        Regex::new(r#"(?i)failed to (fetch|download) metadata"#).unwrap();
+}
+
+lazy_static! {
+    static ref NETWORK_ERROR: Regex = Regex::new(
+        r#"(?i)(TLS handshake timeout|connection reset by peer|i/o timeout|no such host)"#
+    )
+    .unwrap();
+}
+
+/*
+Output that shows the build steps themselves failed. These failures happen the same way every time,
+so the build is never retried when they appear, even if the output also matches one of the
+transient failures above; a compiler error message may well contain "unexpected EOF".
+*/
+lazy_static! {
+    static ref DETERMINISTIC_FAILURE: Regex = Regex::new(concat!(
+        r#"(?m)error: could not compile"#,
+        r#"|error: Bad exit status from"#,
+        r#"|^RPM build errors:"#,
+        r#"|make(\[\d+\])?: \*\*\*"#,
+        r#"|Dockerfile parse error"#,
+    ))
+    .unwrap();
+}
+
+/// Returns whether the output of a failed `docker` command shows that it failed for a transient
+/// reason, so that running it again may succeed.
+pub(super) fn is_transient_failure(output: &str) -> bool {
+    if DETERMINISTIC_FAILURE.is_match(output) {
+        return false;
+    }
+    [
+        &*DOCKER_BUILD_FRONTEND_ERROR,
+        &*DOCKER_BUILD_DEAD_RECORD_ERROR,
+        &*UNEXPECTED_EOF_ERROR,
+        &*CREATEREPO_C_READ_HEADER_ERROR,
+        &*REGISTRY_SERVER_ERROR,
+        &*REGISTRY_RATE_LIMIT_ERROR,
+        &*FETCH_METADATA_ERROR,
+        &*NETWORK_ERROR,
+    ]
+    .iter()
+    .any(|r| r.is_match(output))
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_transient_failure;
+
+    #[test]
+    fn registry_errors_are_transient() {
+        assert!(is_transient_failure(
+            "ERROR: failed to solve: public.ecr.aws/bottlerocket/sdk: \
+            unexpected status code 503 Service Unavailable"
+        ));
+        assert!(is_transient_failure(
+            "ERROR: failed to solve: failed to fetch metadata: context deadline exceeded"
+        ));
+        assert!(is_transient_failure("toomanyrequests: Rate exceeded"));
+    }
+
+    #[test]
+    fn build_errors_are_not_transient() {
+        assert!(!is_transient_failure(
+            "error: could not compile `apiserver` (bin \"apiserver\") due to 2 previous errors"
+        ));
+        assert!(!is_transient_failure(
+            "error: could not compile `migrator`\nunexpected EOF"
+        ));
+        assert!(!is_transient_failure(
+            "error: Bad exit status from /var/tmp/rpm-tmp.abc (%build)"
+        ));
+        assert!(!is_transient_failure("exit code: 1"));
+    }
+}
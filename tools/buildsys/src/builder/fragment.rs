@@ -0,0 +1,194 @@
+/*!
+Splices a package's Dockerfile fragment into the shared Dockerfile, so that a package can add build
+stages of its own without adding them to every build.
+
+The shared Dockerfile marks where fragments go, before the `package-fragment` stage that the
+package build mounts. That stage is built from the stage the package names, or from an empty stage
+when there's no fragment. The fragment's build arguments are passed with a `FRAGMENT_` prefix, so
+that they can't be confused with the arguments that buildsys passes to every build.
+*/
+
+use super::error::{self, Result};
+use buildsys::manifest::DockerfileFragment;
+use snafu::ResultExt;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+/// The line in the shared Dockerfile that a fragment replaces.
+const FRAGMENT_MARKER: &str = "# buildsys: package Dockerfile fragment";
+
+/// The build argument that selects the stage `package-fragment` is built from.
+const FRAGMENT_STAGE_ARG: &str = "PACKAGE_FRAGMENT";
+
+/// The prefix of the build arguments that a manifest passes to its fragment.
+const FRAGMENT_ARG_PREFIX: &str = "FRAGMENT_";
+
+/// Splices the fragment of the package in `package_dir` into `shared`, the contents of the shared
+/// Dockerfile, and returns the result with the build arguments that the fragment needs.
+pub(crate) fn splice(
+    shared: &str,
+    package_dir: &Path,
+    fragment: &DockerfileFragment,
+) -> Result<(String, Vec<(String, String)>)> {
+    let fragment_path = package_dir.join(&fragment.path);
+    let contents = fs::read_to_string(&fragment_path).context(error::FileReadSnafu {
+        path: &fragment_path,
+    })?;
+    let spliced = splice_contents(shared, &contents, fragment).map_err(|reason| {
+        error::Error::DockerfileFragment {
+            path: fragment_path.clone(),
+            reason,
+        }
+    })?;
+    info!(
+        "Building with the Dockerfile fragment '{}'",
+        fragment_path.display()
+    );
+
+    let mut args = vec![(FRAGMENT_STAGE_ARG.to_string(), fragment.stage.clone())];
+    args.extend(
+        fragment
+            .args
+            .iter()
+            .map(|(name, value)| (format!("{FRAGMENT_ARG_PREFIX}{name}"), value.clone())),
+    );
+    Ok((spliced, args))
+}
+
+/// Replaces the marker in `shared` with `fragment`, after checking that the fragment only adds
+/// stages, that it has the stage the manifest names, and that its arguments have usable names.
+fn splice_contents(
+    shared: &str,
+    fragment: &str,
+    manifest: &DockerfileFragment,
+) -> std::result::Result<String, String> {
+    // Anything before the fragment's first stage would end up in the last stage before the marker.
+    let first = fragment
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'));
+    if !first.is_some_and(|line| instruction(line) == "FROM") {
+        return Err("it must start with a FROM instruction".to_string());
+    }
+
+    let shared_stages = stages(shared);
+    let fragment_stages = stages(fragment);
+    if let Some(stage) = fragment_stages.intersection(&shared_stages).next() {
+        return Err(format!(
+            "stage '{stage}' is already defined by the shared Dockerfile"
+        ));
+    }
+    if !fragment_stages.contains(&manifest.stage.to_lowercase()) {
+        return Err(format!("it has no stage named '{}'", manifest.stage));
+    }
+    if let Some(name) = manifest.args.keys().find(|name| !is_arg_name(name)) {
+        return Err(format!("'{name}' isn't a valid build argument name"));
+    }
+
+    let mut lines = shared.lines().collect::<Vec<_>>();
+    let marker = lines
+        .iter()
+        .position(|line| line.trim() == FRAGMENT_MARKER)
+        .ok_or_else(|| "the shared Dockerfile has no place for package fragments".to_string())?;
+    lines[marker] = fragment.trim_end();
+    Ok(lines.join("\n") + "\n")
+}
+
+/// The instruction on a Dockerfile line, in upper case.
+fn instruction(line: &str) -> String {
+    line.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_uppercase()
+}
+
+/// The names of the stages a Dockerfile defines with `FROM <image> AS <name>`, in lower case, since
+/// Docker doesn't distinguish them by case.
+fn stages(dockerfile: &str) -> BTreeSet<String> {
+    dockerfile
+        .lines()
+        .map(str::trim)
+        .filter(|line| instruction(line) == "FROM")
+        .filter_map(|line| {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let position = words.iter().position(|w| w.eq_ignore_ascii_case("AS"))?;
+            words.get(position + 1).map(|name| name.to_lowercase())
+        })
+        .collect()
+}
+
+fn is_arg_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const SHARED: &str = "\
+ARG SDK
+FROM ${SDK} AS sdk
+# buildsys: package Dockerfile fragment
+FROM ${PACKAGE_FRAGMENT} AS package-fragment
+";
+
+    fn manifest(stage: &str, args: &[&str]) -> DockerfileFragment {
+        DockerfileFragment {
+            path: "toolchain.Dockerfile".into(),
+            stage: stage.to_string(),
+            args: args
+                .iter()
+                .map(|name| (name.to_string(), "1.0".to_string()))
+                .collect::<BTreeMap<_, _>>(),
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_splice_contents() {
+        let fragment = "# Fetches the toolchain.\nFROM sdk AS toolchain\nARG FRAGMENT_VERSION\n";
+        let spliced = splice_contents(SHARED, fragment, &manifest("toolchain", &["VERSION"]));
+        assert_eq!(
+            spliced.unwrap(),
+            "ARG SDK\nFROM ${SDK} AS sdk\n# Fetches the toolchain.\nFROM sdk AS toolchain\n\
+             ARG FRAGMENT_VERSION\nFROM ${PACKAGE_FRAGMENT} AS package-fragment\n"
+        );
+    }
+
+    #[test]
+    fn test_splice_contents_rejects() {
+        let fragment = "FROM sdk AS toolchain\n";
+        assert!(splice_contents(
+            SHARED,
+            "ARG X\nFROM sdk AS toolchain\n",
+            &manifest("toolchain", &[])
+        )
+        .unwrap_err()
+        .contains("must start with a FROM"));
+        assert!(
+            splice_contents(SHARED, "FROM scratch AS SDK\n", &manifest("sdk", &[]))
+                .unwrap_err()
+                .contains("already defined")
+        );
+        assert!(splice_contents(SHARED, fragment, &manifest("tools", &[]))
+            .unwrap_err()
+            .contains("no stage named"));
+        assert!(
+            splice_contents(SHARED, fragment, &manifest("toolchain", &["1X"]))
+                .unwrap_err()
+                .contains("valid build argument")
+        );
+        assert!(
+            splice_contents("FROM sdk AS sdk\n", fragment, &manifest("toolchain", &[]))
+                .unwrap_err()
+                .contains("no place for package fragments")
+        );
+    }
+}
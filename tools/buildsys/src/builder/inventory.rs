@@ -0,0 +1,131 @@
+/*!
+Collects the upstream sources of the project's packages for the package inventory that `rpm2img`
+writes into a variant's image and next to its artifacts. The image build can't read the package
+manifests, so the sources are written to a JSON file in the project, which it reads through the
+bypass mount, keyed by package name. Installed RPMs find their package through their source RPM.
+
+Packages from external kits have no manifest here, so their inventory entries have no sources.
+*/
+
+use super::error::{self, Result};
+use buildsys::manifest::{ExternalFile, ManifestInfo};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use url::Url;
+
+/// Where the sources for the build of `variant` for `arch` are written, relative to the project.
+pub(super) fn sources_path(variant: &str, arch: &str) -> String {
+    format!("build/state/package-sources/{variant}-{arch}.json")
+}
+
+/// An external file of a package, as it is listed in the inventory.
+#[derive(Debug, PartialEq, Serialize)]
+struct Source {
+    file: String,
+    url: String,
+    sha512: String,
+}
+
+impl From<&ExternalFile> for Source {
+    fn from(f: &ExternalFile) -> Self {
+        let file = match &f.path {
+            Some(path) => path.display().to_string(),
+            None => Url::parse(&f.url)
+                .ok()
+                .and_then(|url| Some(url.path_segments()?.last()?.to_string()))
+                .unwrap_or_else(|| f.url.clone()),
+        };
+        Self {
+            file,
+            url: f.url.clone(),
+            sha512: f.sha512.clone(),
+        }
+    }
+}
+
+/// Writes the external files of every package in the project at `root` to `path`, which is
+/// relative to `root`.
+pub(super) fn write_sources(root: &Path, path: &str) -> Result<()> {
+    let mut sources = BTreeMap::new();
+    let packages_dir = root.join("packages");
+    let entries = fs::read_dir(&packages_dir).into_iter().flatten().flatten();
+    for entry in entries {
+        let manifest_path = entry.path().join("Cargo.toml");
+        if !manifest_path.is_file() {
+            continue;
+        }
+        let manifest = ManifestInfo::new(&manifest_path).context(error::PackageSourcesSnafu {
+            path: &manifest_path,
+        })?;
+        sources.insert(
+            manifest.package_name().to_string(),
+            package_sources(&manifest),
+        );
+    }
+
+    let path = root.join(path);
+    let json = serde_json::to_vec(&sources).context(error::PackageSourcesSerializeSnafu)?;
+    path.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&path, json))
+        .context(error::PackageSourcesWriteSnafu { path })
+}
+
+fn package_sources(manifest: &ManifestInfo) -> Vec<Source> {
+    manifest
+        .external_files()
+        .into_iter()
+        .flatten()
+        .map(Source::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_package_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("Cargo.toml");
+        fs::write(
+            &manifest_path,
+            r#"
+[package]
+name = "libexample"
+version = "0.1.0"
+
+[[package.metadata.build-package.external-files]]
+url = "https://example.com/releases/libexample-1.2.tar.gz?download=1"
+sha512 = "abc"
+
+[[package.metadata.build-package.external-files]]
+path = "example-vendor.tar.gz"
+url = "https://example.com/vendor"
+sha512 = "def"
+"#,
+        )
+        .unwrap();
+        let manifest = ManifestInfo::new(&manifest_path).unwrap();
+
+        assert_eq!(
+            package_sources(&manifest),
+            vec![
+                Source {
+                    file: "libexample-1.2.tar.gz".to_string(),
+                    url: "https://example.com/releases/libexample-1.2.tar.gz?download=1"
+                        .to_string(),
+                    sha512: "abc".to_string(),
+                },
+                Source {
+                    file: "example-vendor.tar.gz".to_string(),
+                    url: "https://example.com/vendor".to_string(),
+                    sha512: "def".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,91 @@
+/*!
+Settles the file overlays of a variant into the list that `rpm2img` installs into the image.
+*/
+
+use super::error::{self, Result};
+use buildsys::manifest::{FileOverlay, OverlayPartition};
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// A file overlay as `rpm2img` reads it, with its mode settled.
+#[derive(Debug, Serialize)]
+struct Overlay<'a> {
+    /// Relative to the variant's directory.
+    source: Option<&'a Path>,
+    path: &'a Path,
+    partition: OverlayPartition,
+    owner: u32,
+    group: u32,
+    /// In octal, as `install` and `chmod` take it.
+    mode: String,
+}
+
+/// Checks the overlays of the variant in `variant_dir`, and returns them as a JSON list.
+pub(crate) fn overlays_json(overlays: &[FileOverlay], variant_dir: &Path) -> Result<String> {
+    let mut paths = HashSet::new();
+    let mut settled = Vec::new();
+    for overlay in overlays {
+        let mode = overlay
+            .validate(variant_dir)
+            .context(error::FileOverlaySnafu)?;
+        ensure!(
+            paths.insert((overlay.partition, &overlay.path)),
+            error::DuplicateOverlaySnafu {
+                path: &overlay.path
+            }
+        );
+        settled.push(Overlay {
+            source: overlay.source.as_deref(),
+            path: &overlay.path,
+            partition: overlay.partition,
+            owner: overlay.owner,
+            group: overlay.group,
+            mode: format!("{mode:04o}"),
+        });
+    }
+    serde_json::to_string(&settled).context(error::OverlaySerializeSnafu)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    fn overlay(path: &str, partition: OverlayPartition) -> FileOverlay {
+        FileOverlay {
+            source: None,
+            path: PathBuf::from(path),
+            partition,
+            owner: 1000,
+            group: 1000,
+            mode: None,
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_overlays_json() {
+        let dir = Path::new("/nonexistent");
+        let overlays = [
+            overlay("/example", OverlayPartition::Root),
+            overlay("/example", OverlayPartition::Data),
+        ];
+        let json: serde_json::Value =
+            serde_json::from_str(&overlays_json(&overlays, dir).unwrap()).unwrap();
+        assert_eq!(
+            json[1],
+            serde_json::json!({
+                "source": null,
+                "path": "/example",
+                "partition": "data",
+                "owner": 1000,
+                "group": 1000,
+                "mode": "0755",
+            })
+        );
+        assert!(overlays_json(&[overlays[0].clone(), overlays[0].clone()], dir).is_err());
+    }
+}
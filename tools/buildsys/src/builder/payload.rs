@@ -0,0 +1,210 @@
+/*!
+Estimates how much space the packages included in a variant need once they are installed, so that
+an image layout that can't hold them is rejected before the build starts rather than failing when
+the root filesystem fills up.
+
+The estimate is the sum of the installed sizes recorded in the headers of the included packages'
+RPMs, from the project's local kits, external kits, and built packages. Dependencies that are only
+pulled in when the image is assembled aren't counted, so the estimate is a lower bound.
+*/
+
+use buildsys::manifest::SupportedArch;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+const RPM_LEAD_SIZE: usize = 96;
+const RPM_HEADER_MAGIC: [u8; 3] = [0x8e, 0xad, 0xe8];
+const RPM_HEADER_INTRO_SIZE: usize = 16;
+const RPM_INDEX_ENTRY_SIZE: usize = 16;
+
+const RPMTAG_SIZE: u32 = 1009;
+const RPMTAG_LONGSIZE: u32 = 5009;
+const RPM_INT32_TYPE: u32 = 4;
+const RPM_INT64_TYPE: u32 = 5;
+
+/// The directories under `build` that hold RPMs that can be included in a variant.
+const RPM_DIRS: [&str; 3] = ["kits", "external-kits", "rpms"];
+
+/// Returns the installed size of `packages` for `arch`, in MiB, rounded up. Packages without an
+/// RPM, or whose RPM can't be read, are left out.
+pub(super) fn estimate_mib(root_dir: &Path, arch: SupportedArch, packages: &[String]) -> u64 {
+    let rpms = find_rpms(root_dir, arch);
+    let bytes: u64 = packages
+        .iter()
+        .filter_map(|package| newest_rpm(&rpms, package))
+        .filter_map(|rpm| installed_size(rpm).ok().flatten())
+        .sum();
+    bytes.div_ceil(1024 * 1024)
+}
+
+/// Finds the RPMs for `arch` in the build directory, with when each was last modified.
+fn find_rpms(root_dir: &Path, arch: SupportedArch) -> Vec<(PathBuf, SystemTime)> {
+    let arch_suffix = format!(".{arch}.rpm");
+    RPM_DIRS
+        .iter()
+        .flat_map(|dir| WalkDir::new(root_dir.join("build").join(dir)))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            name.ends_with(&arch_suffix) || name.ends_with(".noarch.rpm")
+        })
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((entry.into_path(), modified))
+        })
+        .collect()
+}
+
+/// Picks the most recent RPM of `package`. RPM names have the form
+/// `bottlerocket-<package>-<version>`, and versions start with a digit, which tells `kernel-6.1`
+/// apart from `kernel-6.1-devel`.
+fn newest_rpm<'a>(rpms: &'a [(PathBuf, SystemTime)], package: &str) -> Option<&'a Path> {
+    let prefix = format!("bottlerocket-{package}-");
+    rpms.iter()
+        .filter(|(path, _)| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(&prefix))
+                .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+        })
+        .max_by_key(|(_, modified)| *modified)
+        .map(|(path, _)| path.as_path())
+}
+
+/// Reads the installed size of an RPM from its header. Returns `None` if the header doesn't have
+/// the expected layout.
+fn installed_size(path: &Path) -> io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let mut lead = [0u8; RPM_LEAD_SIZE];
+    file.read_exact(&mut lead)?;
+
+    // The signature header is padded to a multiple of eight bytes.
+    let Some(signature_size) = read_header(&mut file)?.map(|header| header.len()) else {
+        return Ok(None);
+    };
+    let padding = (8 - signature_size % 8) % 8;
+    io::copy(&mut (&mut file).take(padding as u64), &mut io::sink())?;
+
+    Ok(read_header(&mut file)?.and_then(|header| header_size_tag(&header)))
+}
+
+/// Reads a header structure, or returns `None` if it doesn't start with the header magic.
+fn read_header(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut intro = [0u8; RPM_HEADER_INTRO_SIZE];
+    reader.read_exact(&mut intro)?;
+    if intro[..3] != RPM_HEADER_MAGIC {
+        return Ok(None);
+    }
+    let index_count = be_u32(&intro[8..12]) as usize;
+    let data_size = be_u32(&intro[12..16]) as usize;
+    let mut header =
+        vec![0u8; RPM_HEADER_INTRO_SIZE + index_count * RPM_INDEX_ENTRY_SIZE + data_size];
+    header[..RPM_HEADER_INTRO_SIZE].copy_from_slice(&intro);
+    reader.read_exact(&mut header[RPM_HEADER_INTRO_SIZE..])?;
+    Ok(Some(header))
+}
+
+/// Finds the installed size tag in a header, preferring the 64-bit one.
+fn header_size_tag(header: &[u8]) -> Option<u64> {
+    let index_count = be_u32(&header[8..12]) as usize;
+    let data = &header[RPM_HEADER_INTRO_SIZE + index_count * RPM_INDEX_ENTRY_SIZE..];
+    let mut size = None;
+    for i in 0..index_count {
+        let start = RPM_HEADER_INTRO_SIZE + i * RPM_INDEX_ENTRY_SIZE;
+        let entry = &header[start..start + RPM_INDEX_ENTRY_SIZE];
+        let (tag, kind, offset) = (
+            be_u32(&entry[0..4]),
+            be_u32(&entry[4..8]),
+            be_u32(&entry[8..12]) as usize,
+        );
+        match (tag, kind) {
+            (RPMTAG_LONGSIZE, RPM_INT64_TYPE) => {
+                let bytes = data.get(offset..offset + 8)?;
+                return Some(u64::from_be_bytes(bytes.try_into().ok()?));
+            }
+            (RPMTAG_SIZE, RPM_INT32_TYPE) => {
+                size = Some(u64::from(be_u32(data.get(offset..offset + 4)?)));
+            }
+            _ => (),
+        }
+    }
+    size
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    /// Builds a header structure with 32-bit integer entries.
+    fn header(entries: &[(u32, u32)]) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend(RPM_HEADER_MAGIC);
+        header.extend([1, 0, 0, 0, 0]);
+        header.extend((entries.len() as u32).to_be_bytes());
+        header.extend((entries.len() as u32 * 4).to_be_bytes());
+        for (i, (tag, _)) in entries.iter().enumerate() {
+            header.extend(tag.to_be_bytes());
+            header.extend(RPM_INT32_TYPE.to_be_bytes());
+            header.extend((i as u32 * 4).to_be_bytes());
+            header.extend(1u32.to_be_bytes());
+        }
+        for (_, value) in entries {
+            header.extend(value.to_be_bytes());
+        }
+        header
+    }
+
+    fn write_rpm(path: &Path, size: u32) {
+        let mut rpm = vec![0u8; RPM_LEAD_SIZE];
+        // A signature header of 16 + 16 + 4 bytes, padded with 4 bytes.
+        rpm.extend(header(&[(1000, 0)]));
+        rpm.extend([0u8; 4]);
+        rpm.extend(header(&[(1000, 7), (RPMTAG_SIZE, size)]));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, rpm).unwrap();
+    }
+
+    #[test]
+    fn test_estimate_mib() {
+        let root = tempfile::TempDir::new().unwrap();
+        let build = root.path().join("build");
+        write_rpm(
+            &build
+                .join("kits/core-kit/x86_64/Packages/bottlerocket-kernel-6.1-6.1.90-1.x86_64.rpm"),
+            3 * 1024 * 1024,
+        );
+        write_rpm(
+            &build.join(
+                "kits/core-kit/x86_64/Packages/bottlerocket-kernel-6.1-devel-6.1.90-1.x86_64.rpm",
+            ),
+            100 * 1024 * 1024,
+        );
+        write_rpm(
+            &build.join("external-kits/vendor/kit/x86_64/bottlerocket-release-0.0-1.noarch.rpm"),
+            1024 * 1024 + 1,
+        );
+        write_rpm(
+            &build.join("rpms/release/bottlerocket-release-0.0-1.aarch64.rpm"),
+            100 * 1024 * 1024,
+        );
+
+        let packages = [
+            "kernel-6.1".to_string(),
+            "release".to_string(),
+            "missing".to_string(),
+        ];
+        assert_eq!(
+            estimate_mib(root.path(), SupportedArch::X86_64, &packages),
+            5
+        );
+    }
+}
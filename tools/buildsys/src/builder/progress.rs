@@ -0,0 +1,386 @@
+/*!
+Records the progress of a build for twoliter to display. Cargo captures the output of build
+scripts until they finish, so without these records a long build shows nothing at all.
+
+Each record is a small JSON file that is replaced as the build moves through the stages of the
+Dockerfile. Failing to write a record never fails the build. When the build ends, the number of
+steps that BuildKit served from its cache is added to the cache log.
+
+A record also points to the build's log, and says what failed the build if it can tell, such as a
+line of the spec that rpmbuild rejected, so that twoliter can point CI at it. Records of packages
+that list their `maintainers` name them too, so that failures can be sent to them.
+*/
+
+use crate::cache_log::{self, Item};
+use buildsys::BuildType;
+use buildsys_config::BUILD_PROGRESS_DIRECTORY;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    /// BuildKit's plain progress output names the stage of each step, as in
+    /// `#12 [rpmbuild 3/7] RUN rpmbuild ...`.
+    static ref STAGE: Regex = Regex::new(r"^#(\d+) \[([\w.-]+) \d+/\d+\]").unwrap();
+
+    /// Each step ends with a line such as `#12 CACHED` or `#12 DONE 3.4s`.
+    static ref STEP_END: Regex = Regex::new(r"^#(\d+) (CACHED|DONE)\b").unwrap();
+
+    /// rpmbuild reports problems such as `error: line 42: Unknown tag: Foo`, after BuildKit's
+    /// step number and timestamp.
+    static ref RPMBUILD_ERROR: Regex =
+        Regex::new(r"^#\d+ [\d.]+ error: (?:line (\d+): )?(.+)$").unwrap();
+
+    /// The line of a file that an error message points to, as in rpmbuild's `line 42:` or TOML's
+    /// `at line 5, column 3`.
+    static ref LINE: Regex = Regex::new(r"\bline (\d+)\b").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum State {
+    Running,
+    Cached,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    name: &'a str,
+    kind: &'a str,
+    arch: &'a str,
+    state: State,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<&'a str>,
+    /// Seconds since the epoch.
+    started: u64,
+    updated: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    problem: Option<&'a Problem>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    maintainers: &'a [String],
+}
+
+/// What failed a build, and the file and line that it was found in, if it was found in one.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Problem {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u32>,
+}
+
+impl Problem {
+    /// A problem with `file`, if there is one, described by `message`, which may say which line of
+    /// the file it's on.
+    pub(crate) fn new(message: impl Into<String>, file: Option<PathBuf>) -> Self {
+        let message = message.into();
+        let line = file
+            .as_ref()
+            .and_then(|_| LINE.captures(&message))
+            .and_then(|captures| captures[1].parse().ok());
+        Self {
+            message,
+            file,
+            line,
+        }
+    }
+
+    /// The first error that rpmbuild reported in the build log at `log`, as a problem with the
+    /// package's `spec`.
+    pub(crate) fn from_build_log(log: &Path, spec: &Path) -> Option<Self> {
+        let log = fs::read_to_string(log).ok()?;
+        let captures = log.lines().find_map(|line| RPMBUILD_ERROR.captures(line))?;
+        Some(Self {
+            message: captures[2].trim().to_string(),
+            file: Some(spec.to_path_buf()),
+            line: captures.get(1).and_then(|line| line.as_str().parse().ok()),
+        })
+    }
+}
+
+/// The progress of one build, written to `build/progress/<name>-<arch>.json`.
+pub(crate) struct Progress {
+    root: PathBuf,
+    path: PathBuf,
+    name: String,
+    kind: &'static str,
+    arch: String,
+    started: u64,
+    stage: Option<String>,
+    log: Option<PathBuf>,
+    problem: Option<Problem>,
+    maintainers: Vec<String>,
+    /// Whether each step of a stage was served from BuildKit's cache, once it has ended. Internal
+    /// steps, such as loading the Dockerfile, aren't layers and aren't counted.
+    layers: HashMap<u32, Option<bool>>,
+}
+
+impl Progress {
+    /// Starts recording the progress of a build.
+    pub(crate) fn start(root: &Path, build_type: BuildType, name: &str, arch: &str) -> Self {
+        let progress = Self::new(root, build_type, name, arch);
+        progress.write(State::Running);
+        progress
+    }
+
+    /// Records that a build failed before it could start, because of `problem`.
+    pub(crate) fn failed(
+        root: &Path,
+        build_type: BuildType,
+        name: &str,
+        arch: &str,
+        problem: Problem,
+        maintainers: &[String],
+    ) {
+        let mut progress = Self::new(root, build_type, name, arch);
+        progress.problem = Some(problem);
+        progress.maintainers = maintainers.to_vec();
+        progress.write(State::Failed);
+    }
+
+    fn new(root: &Path, build_type: BuildType, name: &str, arch: &str) -> Self {
+        let kind = match build_type {
+            BuildType::Package => "package",
+            BuildType::Kit => "kit",
+            BuildType::Variant => "variant",
+            BuildType::Repack => "repack",
+        };
+        Self {
+            root: root.to_path_buf(),
+            path: root
+                .join(BUILD_PROGRESS_DIRECTORY)
+                .join(format!("{}-{}.json", name, arch)),
+            name: name.to_string(),
+            kind,
+            arch: arch.to_string(),
+            started: now(),
+            stage: None,
+            log: None,
+            problem: None,
+            maintainers: Vec::new(),
+            layers: HashMap::new(),
+        }
+    }
+
+    /// Records where the output of the build is saved.
+    pub(crate) fn log(&mut self, path: &Path) {
+        self.log = Some(path.to_path_buf());
+        self.write(State::Running);
+    }
+
+    /// Records who owns what is being built, to be named if the build fails.
+    pub(crate) fn maintainers(&mut self, maintainers: &[String]) {
+        self.maintainers = maintainers.to_vec();
+    }
+
+    /// Records what failed the build, before it finishes.
+    pub(crate) fn problem(&mut self, problem: Problem) {
+        self.problem = Some(problem);
+    }
+
+    /// Checks a line of `docker build` output for the start of a new stage, or the end of a step.
+    pub(crate) fn observe(&mut self, line: &str) {
+        if let Some(end) = STEP_END.captures(line) {
+            if let Some(layer) = self
+                .layers
+                .get_mut(&end[1].parse::<u32>().unwrap_or_default())
+            {
+                *layer = Some(&end[2] == "CACHED");
+            }
+            return;
+        }
+        let Some(captures) = STAGE.captures(line) else {
+            return;
+        };
+        if let Ok(step) = captures[1].parse() {
+            self.layers.entry(step).or_insert(None);
+        }
+        let stage = &captures[2];
+        if self.stage.as_deref() != Some(stage) {
+            self.stage = Some(stage.to_string());
+            self.write(State::Running);
+        }
+    }
+
+    /// Records how the build ended, and how many of its layers came from BuildKit's cache.
+    pub(crate) fn finish(&self, state: State) {
+        self.write(state);
+        let (cached, built) = self.layer_counts();
+        cache_log::record(&self.root, &self.name, Item::DockerLayer, cached, built);
+    }
+
+    /// The number of layers that were served from the cache, and the number that were built.
+    fn layer_counts(&self) -> (u64, u64) {
+        self.layers
+            .values()
+            .flatten()
+            .fold((0, 0), |(cached, built), &is_cached| {
+                if is_cached {
+                    (cached + 1, built)
+                } else {
+                    (cached, built + 1)
+                }
+            })
+    }
+
+    fn write(&self, state: State) {
+        let record = Record {
+            name: &self.name,
+            kind: self.kind,
+            arch: &self.arch,
+            state,
+            stage: self.stage.as_deref(),
+            started: self.started,
+            updated: now(),
+            log: self.log.as_deref(),
+            problem: self.problem.as_ref(),
+            maintainers: &self.maintainers,
+        };
+        // Write the record next to its final path and rename it, so that readers never see a
+        // partial record.
+        let tmp = self.path.with_extension("json.tmp");
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| serde_json::to_vec(&record).map_err(std::io::Error::from))
+            .and_then(|data| fs::write(&tmp, data))
+            .and_then(|_| fs::rename(&tmp, &self.path));
+        if let Err(e) = result {
+            println!(
+                "cargo:warning=Unable to record build progress in '{}': {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_observe_stage() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut progress = Progress::start(dir.path(), BuildType::Package, "kernel-6.1", "x86_64");
+        progress.observe("#1 [internal] load build definition from build.Dockerfile");
+        assert_eq!(progress.stage, None);
+        progress.observe("#12 [rpmbuild 3/7] RUN rpmbuild -ba --clean kernel-6.1.spec");
+        assert_eq!(progress.stage.as_deref(), Some("rpmbuild"));
+        progress.observe("#12 12.34 + make -j8");
+        assert_eq!(progress.stage.as_deref(), Some("rpmbuild"));
+
+        let written = fs::read_to_string(
+            dir.path()
+                .join(BUILD_PROGRESS_DIRECTORY)
+                .join("kernel-6.1-x86_64.json"),
+        )
+        .unwrap();
+        assert!(written.contains(r#""stage":"rpmbuild""#));
+        assert!(written.contains(r#""state":"running""#));
+        assert!(!written.contains("maintainers"));
+    }
+
+    #[test]
+    fn test_failed_maintainers() {
+        let dir = tempfile::TempDir::new().unwrap();
+        Progress::failed(
+            dir.path(),
+            BuildType::Package,
+            "kernel-6.1",
+            "x86_64",
+            Problem::new("Unable to fetch", None),
+            &["@bottlerocket-os/kernel".to_string()],
+        );
+        let written = fs::read_to_string(
+            dir.path()
+                .join(BUILD_PROGRESS_DIRECTORY)
+                .join("kernel-6.1-x86_64.json"),
+        )
+        .unwrap();
+        assert!(written.contains(r#""state":"failed""#));
+        assert!(written.contains(r#""maintainers":["@bottlerocket-os/kernel"]"#));
+    }
+
+    #[test]
+    fn test_layer_counts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut progress = Progress::start(dir.path(), BuildType::Package, "kernel-6.1", "x86_64");
+        for line in [
+            "#1 [internal] load build definition from build.Dockerfile",
+            "#1 DONE 0.0s",
+            "#5 [sdk 1/1] FROM docker.io/library/sdk",
+            "#5 CACHED",
+            "#6 [rpmsetup 1/2] COPY ./packages/kernel-6.1/ .",
+            "#6 CACHED",
+            "#12 [rpmbuild 3/7] RUN rpmbuild -ba --clean kernel-6.1.spec",
+            "#12 12.34 + make -j8",
+            "#12 DONE 312.5s",
+            "#13 [rpmbuild 4/7] RUN cp *.rpm /output",
+        ] {
+            progress.observe(line);
+        }
+        assert_eq!(progress.layer_counts(), (2, 1));
+    }
+
+    #[test]
+    fn test_problem_from_build_log() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let log = dir.path().join("glibc-x86_64-100.log");
+        let spec = Path::new("/project/packages/glibc/glibc.spec");
+        fs::write(
+            &log,
+            "#12 [rpmbuild 3/7] RUN rpmbuild -ba --clean glibc.spec\n\
+             #12 0.412 error: line 42: Unknown tag: Sumary: The GNU libc libraries\n\
+             #12 0.413 error: query of specfile glibc.spec failed, can't parse\n",
+        )
+        .unwrap();
+        let problem = Problem::from_build_log(&log, spec).unwrap();
+        assert_eq!(
+            problem.message,
+            "Unknown tag: Sumary: The GNU libc libraries"
+        );
+        assert_eq!(problem.file.as_deref(), Some(spec));
+        assert_eq!(problem.line, Some(42));
+
+        fs::write(
+            &log,
+            "#12 30.2 + make -j8\n#12 31.0 error: Bad exit status\n",
+        )
+        .unwrap();
+        let problem = Problem::from_build_log(&log, spec).unwrap();
+        assert_eq!(problem.message, "Bad exit status");
+        assert_eq!(problem.line, None);
+
+        fs::write(&log, "#12 30.2 + make -j8\n").unwrap();
+        assert!(Problem::from_build_log(&log, spec).is_none());
+    }
+
+    #[test]
+    fn test_problem_line() {
+        let manifest = PathBuf::from("packages/glibc/Cargo.toml");
+        let problem = Problem::new(
+            "Failed to load manifest file: TOML parse error at line 5, column 3",
+            Some(manifest),
+        );
+        assert_eq!(problem.line, Some(5));
+        assert_eq!(Problem::new("Unable to fetch", None).line, None);
+    }
+}
@@ -0,0 +1,247 @@
+/*!
+Cargo runs as many build scripts at once as it has jobs, and builders that are shared by several
+checkouts run several Cargo processes, so the host can end up running more Docker builds than it
+has the resources for. A limit on Docker builds is kept with lock files, one for each build that
+may run at once. A build holds the lock on one of them while Docker runs, and waits for one to come
+free if they are all held.
+
+Builds that are waiting for a slot take them in order of how long they took the last time they ran,
+longest first, so that long builds such as the kernel don't start last and hold up the rest. Each
+waiting build writes how long it expects to take to a file that it keeps locked while it waits, and
+skips as many free slots as there are waiting builds that expect to take longer. Builds that have
+never run expect to take no time at all.
+
+The order can't be on by default. Cargo decides which build scripts run and when, and buildsys only
+sees the builds that Cargo has already started, which are never more than its `--jobs`. Builds
+only wait, and so only take turns, when Cargo starts more of them than there are slots. Without a
+limit, or with one of at least `BUILDSYS_JOBS`, each build gets a slot as soon as it starts, and the
+order is Cargo's. A default limit below `BUILDSYS_JOBS` would make the order take effect, but it
+would also quietly run fewer builds at once than `BUILDSYS_JOBS` asks for.
+
+Every buildsys process that uses the same directory of lock files shares the limit. Locks are
+released when the process exits, even if it crashes.
+*/
+
+use super::error::{self, Result};
+use crate::interrupt;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use snafu::{ensure, ResultExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// How often a build that is waiting for a slot checks for one.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The prefix of the files that waiting builds write how long they expect to take to.
+const WAITING_PREFIX: &str = "waiting-";
+
+/// The lock files that limit how many Docker builds run at once.
+#[derive(Debug, Clone)]
+pub(crate) struct BuildSlots {
+    dir: PathBuf,
+    limit: NonZeroU16,
+}
+
+/// A slot held by a build, which is released when this is dropped.
+pub(crate) struct BuildSlot {
+    _lock: Flock<File>,
+}
+
+impl BuildSlots {
+    pub(crate) fn new(dir: impl Into<PathBuf>, limit: NonZeroU16) -> Self {
+        Self {
+            dir: dir.into(),
+            limit,
+        }
+    }
+
+    /// Waits until it's the turn of the build of `name`, which is expected to take `expected`,
+    /// and one of the slots is free, and takes it.
+    pub(crate) fn acquire(&self, name: &str, expected: Duration) -> Result<BuildSlot> {
+        fs::create_dir_all(&self.dir).context(error::DirectoryCreateSnafu { path: &self.dir })?;
+        let mut waiting: Option<Waiting> = None;
+        loop {
+            let ours = waiting.as_ref().map(|waiting| waiting.path.as_path());
+            let ahead = self.waiting_ahead(ours, expected);
+            if let Some(slot) = self.try_acquire(ahead)? {
+                if let Some(waiting) = waiting {
+                    waiting.remove();
+                }
+                return Ok(slot);
+            }
+            if waiting.is_none() {
+                info!(
+                    "Waiting to build {}, since {} Docker builds are already running",
+                    name, self.limit
+                );
+                waiting = Some(Waiting::start(&self.dir, expected)?);
+            }
+            ensure!(
+                !interrupt::interrupted(),
+                error::InterruptedSnafu {
+                    args: "build".to_string()
+                }
+            );
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Takes a free slot, after leaving `skip` free slots for the builds that go first.
+    fn try_acquire(&self, skip: usize) -> Result<Option<BuildSlot>> {
+        let mut skipped = 0;
+        for slot in 0..self.limit.get() {
+            let path = self.dir.join(format!("slot-{slot}.lock"));
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .context(error::BuildSlotOpenSnafu { path: &path })?;
+            match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+                Ok(_) if skipped < skip => skipped += 1,
+                Ok(lock) => return Ok(Some(BuildSlot { _lock: lock })),
+                Err((_, Errno::EWOULDBLOCK)) => continue,
+                Err((_, source)) => return Err(source).context(error::BuildSlotLockSnafu { path }),
+            }
+        }
+        Ok(None)
+    }
+
+    /// The number of other builds that are waiting and go before a build that expects to take
+    /// `expected`, which waits with the file at `ours` if it's waiting. Files left by builds that
+    /// stopped waiting without removing them are removed.
+    fn waiting_ahead(&self, ours: Option<&Path>, expected: Duration) -> usize {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return 0;
+        };
+        let mut ahead = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_waiting = entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(WAITING_PREFIX);
+            if !is_waiting || Some(path.as_path()) == ours {
+                continue;
+            }
+            let Ok(file) = File::open(&path) else {
+                continue;
+            };
+            match Flock::lock(file, FlockArg::LockSharedNonblock) {
+                // Nothing holds the lock, so the build that wrote it has stopped waiting.
+                Ok(_) => {
+                    debug!("Removing '{}', which is no longer waiting", path.display());
+                    let _ = fs::remove_file(&path);
+                }
+                Err(_) => {
+                    let theirs = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|secs| secs.trim().parse().ok())
+                        .map(Duration::from_secs)
+                        .unwrap_or_default();
+                    // Break ties by the name of the file, so that exactly one build goes first.
+                    let first = ours.map_or(true, |ours| path.as_path() < ours);
+                    if theirs > expected || (theirs == expected && first) {
+                        ahead += 1;
+                    }
+                }
+            }
+        }
+        ahead
+    }
+}
+
+/// The file a build writes how long it expects to take to while it waits for a slot. The build
+/// keeps it locked, so that others can tell whether it's still waiting.
+struct Waiting {
+    path: PathBuf,
+    _lock: Flock<File>,
+}
+
+impl Waiting {
+    fn start(dir: &Path, expected: Duration) -> Result<Self> {
+        // The file is locked before it's given its name, so that no one takes it for a file that
+        // was left behind.
+        let name = format!("{WAITING_PREFIX}{}", std::process::id());
+        let tmp = dir.join(format!(".{name}"));
+        let mut file = File::create(&tmp).context(error::BuildSlotOpenSnafu { path: &tmp })?;
+        writeln!(file, "{}", expected.as_secs())
+            .context(error::BuildSlotOpenSnafu { path: &tmp })?;
+        let lock = Flock::lock(file, FlockArg::LockExclusiveNonblock)
+            .map_err(|(_, e)| e)
+            .context(error::BuildSlotLockSnafu { path: &tmp })?;
+        let path = dir.join(name);
+        fs::rename(&tmp, &path).context(error::BuildSlotOpenSnafu { path: &tmp })?;
+        Ok(Self { path, _lock: lock })
+    }
+
+    fn remove(self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// How long the build of `name` took the last time it succeeded, as recorded in `state_dir`, or
+/// no time at all if it hasn't been built.
+pub(crate) fn last_duration(state_dir: &Path, name: &str) -> Duration {
+    fs::read_to_string(duration_path(state_dir, name))
+        .ok()
+        .and_then(|secs| secs.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default()
+}
+
+/// Records how long the build of `name` took in `state_dir`. Failing to record it is not an error.
+pub(crate) fn record_duration(state_dir: &Path, name: &str, duration: Duration) {
+    let path = duration_path(state_dir, name);
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, format!("{}\n", duration.as_secs())));
+    if let Err(e) = result {
+        debug!(
+            "Unable to record the build duration in '{}': {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+fn duration_path(state_dir: &Path, name: &str) -> PathBuf {
+    state_dir.join("build-durations").join(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_try_acquire() {
+        let dir = TempDir::new().unwrap();
+        let slots = BuildSlots::new(dir.path(), NonZeroU16::new(2).unwrap());
+        let first = slots.try_acquire(0).unwrap().unwrap();
+        let second = slots.try_acquire(0).unwrap().unwrap();
+        assert!(slots.try_acquire(0).unwrap().is_none());
+        drop(first);
+        assert!(slots.try_acquire(1).unwrap().is_none());
+        assert!(slots.try_acquire(0).unwrap().is_some());
+        drop(second);
+    }
+
+    #[test]
+    fn test_durations() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(last_duration(dir.path(), "kernel"), Duration::ZERO);
+        record_duration(dir.path(), "kernel", Duration::from_secs(1800));
+        assert_eq!(
+            last_duration(dir.path(), "kernel"),
+            Duration::from_secs(1800)
+        );
+    }
+}
@@ -0,0 +1,359 @@
+/*!
+Variant builds write gigabytes of images to the project's `build` directory and to Docker's
+storage, and a build that runs out of room there fails late, with `no space left on device` from
+somewhere deep in the container.
+
+Before such a build starts, the free space of each filesystem it writes to is checked against how
+much the last build of the same variant took from it, and the build fails early with advice on
+freeing space if there isn't enough. Builds that haven't run before need at least [`MIN_FREE`].
+While the build runs, the free space is checked every few seconds, and the build is stopped
+cleanly if it falls below [`STOP_BELOW`], rather than left to fail on its own. A build that
+succeeds anyway, because it finished before it could be stopped, is kept.
+
+How much a build took is how far the free space fell while it ran, which is recorded in the state
+directory after every build that succeeds. Other builds that run at the same time make it look
+larger than it is, which errs on the side of leaving room.
+*/
+
+use super::error::{self, Result};
+use crate::interrupt;
+use duct::cmd;
+use snafu::ensure;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+
+/// The free space that a build needs on each filesystem if it hasn't run before.
+const MIN_FREE: u64 = 4 * GIB;
+
+/// The free space below which a running build is stopped.
+const STOP_BELOW: u64 = 512 * MIB;
+
+/// How often the free space is checked while a build runs.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the thread that checks the free space looks for the end of the build, which is much
+/// more often than it checks, so that the build isn't kept waiting for it.
+const WAKE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The free space of the filesystems that a build writes to. Builds that aren't checked have none.
+#[derive(Debug, Default)]
+pub(crate) struct Space {
+    record: PathBuf,
+    filesystems: Vec<Filesystem>,
+}
+
+#[derive(Debug)]
+struct Filesystem {
+    /// What the build keeps there, such as `the build directory`.
+    what: String,
+    path: PathBuf,
+    id: u64,
+    /// The free space when the build started.
+    before: AtomicU64,
+    /// The least free space seen while the build ran.
+    least: AtomicU64,
+    /// Whether the build was stopped because the free space fell too low.
+    exhausted: AtomicBool,
+}
+
+impl Space {
+    /// Checks that there is enough free space for the build of `name` in the build directory at
+    /// `build_dir` and in Docker's storage, from what the last build of `name` took, as recorded
+    /// in `state_dir`. Filesystems whose free space can't be found, such as the storage of a
+    /// remote Docker daemon, aren't checked.
+    pub(crate) fn check(state_dir: &Path, name: &str, build_dir: &Path) -> Result<Self> {
+        let mut space = Self {
+            record: state_dir.join("build-space").join(format!("{name}.json")),
+            filesystems: Vec::new(),
+        };
+        let mut paths = vec![("the build directory", build_dir.to_path_buf())];
+        if let Some(docker_root) = docker_root() {
+            paths.push(("Docker's storage", docker_root));
+        }
+        for (what, path) in paths {
+            let Some((id, available)) = available(&path) else {
+                debug!("Unable to find the free space in '{}'", path.display());
+                continue;
+            };
+            match space.filesystems.iter_mut().find(|f| f.id == id) {
+                Some(filesystem) => filesystem.what = format!("{} and {}", filesystem.what, what),
+                None => space.filesystems.push(Filesystem {
+                    what: what.to_string(),
+                    path,
+                    id,
+                    before: AtomicU64::new(available),
+                    least: AtomicU64::new(available),
+                    exhausted: AtomicBool::new(false),
+                }),
+            }
+        }
+
+        let used = space.last_used();
+        for filesystem in &space.filesystems {
+            let available = filesystem.before.load(Ordering::SeqCst);
+            let required = required(used.get(&filesystem.what).copied());
+            ensure!(
+                available >= required,
+                error::LowSpaceSnafu {
+                    what: &filesystem.what,
+                    path: &filesystem.path,
+                    available: format_bytes(available),
+                    required: format_bytes(required),
+                    advice: advice(&filesystem.what),
+                }
+            );
+        }
+        Ok(space)
+    }
+
+    /// Runs `f`, which runs the build, and stops the `docker` command that it's running if the
+    /// free space of any filesystem falls too low. If `f` succeeds anyway, the free space that
+    /// fell too low is ignored.
+    pub(crate) fn watch<T, E>(
+        &self,
+        f: impl FnOnce() -> std::result::Result<T, E>,
+    ) -> std::result::Result<T, E> {
+        if self.filesystems.is_empty() {
+            return f();
+        }
+        for filesystem in &self.filesystems {
+            if let Some((_, available)) = available(&filesystem.path) {
+                filesystem.before.store(available, Ordering::SeqCst);
+                filesystem.least.store(available, Ordering::SeqCst);
+            }
+        }
+        let done = AtomicBool::new(false);
+        let result = thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut next_poll = Instant::now();
+                while !done.load(Ordering::SeqCst) {
+                    if Instant::now() >= next_poll {
+                        if self.poll() {
+                            interrupt::cancel();
+                            return;
+                        }
+                        next_poll += POLL_INTERVAL;
+                    }
+                    thread::sleep(WAKE_INTERVAL);
+                }
+            });
+            let result = f();
+            done.store(true, Ordering::SeqCst);
+            result
+        });
+        if result.is_ok() {
+            for filesystem in &self.filesystems {
+                filesystem.exhausted.store(false, Ordering::SeqCst);
+            }
+            interrupt::uncancel();
+        }
+        result
+    }
+
+    /// Checks the free space of each filesystem, and returns whether any is too low.
+    fn poll(&self) -> bool {
+        let mut exhausted = false;
+        for filesystem in &self.filesystems {
+            let Some((_, available)) = available(&filesystem.path) else {
+                continue;
+            };
+            filesystem.least.fetch_min(available, Ordering::SeqCst);
+            if available < STOP_BELOW {
+                filesystem.exhausted.store(true, Ordering::SeqCst);
+                exhausted = true;
+            }
+        }
+        exhausted
+    }
+
+    /// Fails if the build was stopped because the free space fell too low.
+    pub(crate) fn exhausted(&self) -> Result<()> {
+        for filesystem in &self.filesystems {
+            ensure!(
+                !filesystem.exhausted.load(Ordering::SeqCst),
+                error::SpaceExhaustedSnafu {
+                    what: &filesystem.what,
+                    path: &filesystem.path,
+                    available: format_bytes(filesystem.least.load(Ordering::SeqCst)),
+                    advice: advice(&filesystem.what),
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Records how much space the build took from each filesystem, for the next build to check.
+    /// Failing to record it is not an error.
+    pub(crate) fn record(&self) {
+        if self.filesystems.is_empty() {
+            return;
+        }
+        let used = self
+            .filesystems
+            .iter()
+            .map(|f| {
+                let before = f.before.load(Ordering::SeqCst);
+                let least = f.least.load(Ordering::SeqCst);
+                (f.what.clone(), before.saturating_sub(least))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let result = self
+            .record
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| serde_json::to_vec(&used).map_err(std::io::Error::from))
+            .and_then(|data| fs::write(&self.record, data));
+        if let Err(e) = result {
+            debug!(
+                "Unable to record the space the build took in '{}': {}",
+                self.record.display(),
+                e
+            );
+        }
+    }
+
+    /// How much space the last build took from each filesystem, by what the build keeps there.
+    fn last_used(&self) -> BTreeMap<String, u64> {
+        fs::read(&self.record)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// The free space a build needs, given how much the last build took, if it ran before. Builds
+/// tend to grow, so a quarter more is asked for, on top of what the build is stopped below.
+fn required(used: Option<u64>) -> u64 {
+    match used {
+        Some(used) => (used + used / 4 + STOP_BELOW).max(MIN_FREE),
+        None => MIN_FREE,
+    }
+}
+
+/// The ID of the filesystem that `path` is on, and how much of it is free for unprivileged users.
+/// Paths that don't exist yet are on the filesystem of their closest parent that does.
+#[allow(clippy::unnecessary_cast)] // The types of the fields vary by platform.
+fn available(path: &Path) -> Option<(u64, u64)> {
+    let path = path.ancestors().find(|p| p.exists())?;
+    let stat = nix::sys::statvfs::statvfs(path).ok()?;
+    Some((
+        stat.filesystem_id() as u64,
+        (stat.blocks_available() as u64).saturating_mul(stat.fragment_size() as u64),
+    ))
+}
+
+/// The directory that the local Docker daemon keeps its images and build cache in.
+fn docker_root() -> Option<PathBuf> {
+    let root = cmd!("docker", "info", "--format", "{{.DockerRootDir}}")
+        .stderr_null()
+        .read()
+        .ok()?;
+    let root = PathBuf::from(root.trim());
+    (root.is_absolute() && root.exists()).then_some(root)
+}
+
+/// How to free space for what the build keeps on a filesystem.
+fn advice(what: &str) -> String {
+    let mut advice = Vec::new();
+    if what.contains("build directory") {
+        advice.push("remove old images with `twoliter make clean-images`");
+    }
+    if what.contains("Docker") {
+        advice.push("remove Docker's unused images and build cache with `docker system prune`");
+    }
+    advice.join(", and ")
+}
+
+/// Formats bytes like `1.5 GiB` or `200 MiB`.
+fn format_bytes(bytes: u64) -> String {
+    if bytes >= GIB {
+        format!("{:.1} GiB", bytes as f64 / GIB as f64)
+    } else {
+        format!("{} MiB", bytes / MIB)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_required() {
+        assert_eq!(required(None), MIN_FREE);
+        assert_eq!(required(Some(GIB)), MIN_FREE);
+        assert_eq!(required(Some(16 * GIB)), 20 * GIB + STOP_BELOW);
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(200 * MIB), "200 MiB");
+        assert_eq!(format_bytes(3 * GIB / 2), "1.5 GiB");
+    }
+
+    #[test]
+    fn test_advice() {
+        assert_eq!(
+            advice("the build directory"),
+            "remove old images with `twoliter make clean-images`"
+        );
+        assert!(advice("the build directory and Docker's storage").contains("docker system prune"));
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let space = Space {
+            record: dir.path().join("build-space").join("aws-k8s.json"),
+            filesystems: vec![Filesystem {
+                what: "the build directory".to_string(),
+                path: dir.path().to_path_buf(),
+                id: 0,
+                before: AtomicU64::new(10 * GIB),
+                least: AtomicU64::new(4 * GIB),
+                exhausted: AtomicBool::new(false),
+            }],
+        };
+        assert!(space.last_used().is_empty());
+        space.record();
+        assert_eq!(
+            space.last_used(),
+            BTreeMap::from([("the build directory".to_string(), 6 * GIB)])
+        );
+        assert!(space.exhausted().is_ok());
+
+        space.filesystems[0].exhausted.store(true, Ordering::SeqCst);
+        let error = space.exhausted().unwrap_err().to_string();
+        assert!(error.contains("4.0 GiB"), "{error}");
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (id, available) = available(dir.path()).unwrap();
+        let space = Space {
+            record: dir.path().join("build-space").join("aws-k8s.json"),
+            filesystems: vec![Filesystem {
+                what: "the build directory".to_string(),
+                path: dir.path().to_path_buf(),
+                id,
+                before: AtomicU64::new(available),
+                least: AtomicU64::new(available),
+                exhausted: AtomicBool::new(true),
+            }],
+        };
+        // The build isn't kept waiting for the next check of the free space.
+        let started = Instant::now();
+        assert_eq!(space.watch(|| Ok::<_, ()>(1)), Ok(1));
+        assert!(started.elapsed() < POLL_INTERVAL);
+        // The build succeeded, so the free space that fell too low doesn't matter.
+        assert!(space.exhausted().is_ok());
+    }
+}
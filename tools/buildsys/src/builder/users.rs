@@ -0,0 +1,287 @@
+/*!
+Maps the users that builds run as inside SDK containers to users on the host, so that build outputs
+end up owned by whoever ran the build.
+
+Outputs are written by root in the build container and then handed to the builder user, whose UID
+and GID are passed to the Dockerfile. Without `userns-remap`, container IDs are host IDs, so the
+builder user is the owner of the outputs. With `userns-remap`, the Docker daemon maps container IDs
+into a subordinate range on the host, so the builder user has to be the owner's ID within that
+range. An owner outside of the range can't be reached from the container at all, so its outputs
+are reclaimed after they are moved into place.
+*/
+
+use super::error::{self, Result};
+use super::{docker, Retry};
+use crate::args::{Common, UsernsRemap};
+use snafu::{OptionExt, ResultExt};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tracing::info;
+
+/// The UID of privileged processes inside the build container.
+pub(crate) const ROOT_UID: u32 = 0;
+
+/// The user that Docker creates for `userns-remap` when it's set to `default`.
+const DEFAULT_REMAP_USER: &str = "dockremap";
+
+/// The size of the subordinate ID range that Docker asks for by default.
+const DEFAULT_REMAP_COUNT: u32 = 65536;
+
+const DOCKER_DAEMON_CONFIG: &str = "/etc/docker/daemon.json";
+const SUBUID: &str = "/etc/subuid";
+const SUBGID: &str = "/etc/subgid";
+
+/// A user and group, by ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Ids {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+}
+
+impl Ids {
+    /// The user and group running buildsys.
+    fn current() -> Result<Self> {
+        let path = "/proc/self/comm";
+        let metadata = fs::metadata(path).context(error::FileReadSnafu { path })?;
+        Ok(Self {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        })
+    }
+}
+
+/// A range of host IDs that container IDs are mapped to, starting from container ID 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IdRange {
+    start: u32,
+    count: u32,
+}
+
+impl IdRange {
+    fn to_host(self, id: u32) -> u32 {
+        self.start.saturating_add(id)
+    }
+
+    fn to_container(self, id: u32) -> Option<u32> {
+        id.checked_sub(self.start).filter(|id| *id < self.count)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UserMapping {
+    /// The host user and group that should own build outputs.
+    owner: Ids,
+    /// The host UIDs and GIDs that container IDs are mapped to, if the Docker daemon uses
+    /// `userns-remap`.
+    remap: Option<(IdRange, IdRange)>,
+}
+
+impl UserMapping {
+    pub(crate) fn from_args(args: &Common) -> Result<Self> {
+        let current = Ids::current()?;
+        let owner = Ids {
+            uid: args.builder_uid.unwrap_or(current.uid),
+            gid: args.builder_gid.unwrap_or(current.gid),
+        };
+        let remap = match args.userns_remap {
+            UsernsRemap::None => None,
+            UsernsRemap::Base { uid, gid } => Some((
+                IdRange {
+                    start: uid,
+                    count: DEFAULT_REMAP_COUNT,
+                },
+                IdRange {
+                    start: gid,
+                    count: DEFAULT_REMAP_COUNT,
+                },
+            )),
+            UsernsRemap::Auto => detect_remap()?,
+        };
+        Ok(Self { owner, remap })
+    }
+
+    /// Whether the Docker daemon maps container users to other users on the host.
+    pub(crate) fn is_remapped(&self) -> bool {
+        self.remap.is_some()
+    }
+
+    /// The host UID that root in a build container appears as.
+    pub(crate) fn root_uid(&self) -> u32 {
+        self.remap
+            .map_or(ROOT_UID, |(uids, _)| uids.to_host(ROOT_UID))
+    }
+
+    /// The user and group that outputs are given to inside the build container, so that they
+    /// belong to the owner on the host. If the owner isn't in the daemon's subordinate ranges,
+    /// these are the owner's IDs, and the outputs have to be reclaimed after the build.
+    pub(crate) fn builder(&self) -> Ids {
+        match self.remap {
+            Some((uids, gids)) => Ids {
+                uid: uids.to_container(self.owner.uid).unwrap_or(self.owner.uid),
+                gid: gids.to_container(self.owner.gid).unwrap_or(self.owner.gid),
+            },
+            None => self.owner,
+        }
+    }
+
+    /// Gives `path` to the owner of build outputs, if it belongs to someone else. Files that
+    /// can't be changed, because they belong to a remapped user, are replaced by a copy when the
+    /// owner is the user running buildsys. Returns whether `path` belongs to the owner afterward.
+    pub(crate) fn reclaim(&self, path: &Path) -> Result<bool> {
+        let metadata = fs::symlink_metadata(path).context(error::FileReadSnafu { path })?;
+        if metadata.uid() == self.owner.uid && metadata.gid() == self.owner.gid {
+            return Ok(true);
+        }
+
+        match std::os::unix::fs::lchown(path, Some(self.owner.uid), Some(self.owner.gid)) {
+            Ok(()) => return Ok(true),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => (),
+            Err(e) => return Err(e).context(error::FileChownSnafu { path }),
+        }
+
+        if !metadata.is_file() || Ids::current()? != self.owner {
+            return Ok(false);
+        }
+        let mut copy = path.as_os_str().to_owned();
+        copy.push(".reclaim");
+        fs::copy(path, &copy).context(error::FileCreateSnafu { path: &copy })?;
+        fs::rename(&copy, path).context(error::FileRenameSnafu {
+            old_path: &copy,
+            new_path: path,
+        })?;
+        Ok(true)
+    }
+}
+
+/// Asks the Docker daemon whether it uses `userns-remap`, and if it does, finds the subordinate
+/// ID ranges of the user it remaps to.
+fn detect_remap() -> Result<Option<(IdRange, IdRange)>> {
+    let args = ["info", "--format", "{{json .SecurityOptions}}"].map(String::from);
+    let output = docker(&args, Retry::No)?;
+    if !String::from_utf8_lossy(&output.stdout).contains("name=userns") {
+        return Ok(None);
+    }
+
+    let config = fs::read_to_string(DOCKER_DAEMON_CONFIG).ok();
+    let (user, group) =
+        config
+            .as_deref()
+            .and_then(remap_user)
+            .context(error::UsernsRemapSnafu {
+                reason: format!("unable to read 'userns-remap' from '{DOCKER_DAEMON_CONFIG}'"),
+            })?;
+    let uids = subordinate_range(SUBUID, &user)?;
+    let gids = subordinate_range(SUBGID, &group)?;
+    info!(
+        "Docker maps container users to UIDs from {} and GIDs from {}",
+        uids.start, gids.start
+    );
+    Ok(Some((uids, gids)))
+}
+
+/// Reads the user and group that the daemon remaps containers to from its `daemon.json`.
+fn remap_user(config: &str) -> Option<(String, String)> {
+    let config: serde_json::Value = serde_json::from_str(config).ok()?;
+    let remap = config.get("userns-remap")?.as_str()?;
+    let (user, group) = match remap {
+        "default" => (DEFAULT_REMAP_USER, DEFAULT_REMAP_USER),
+        _ => remap.split_once(':').unwrap_or((remap, remap)),
+    };
+    Some((user.to_string(), group.to_string()))
+}
+
+fn subordinate_range(path: &str, name: &str) -> Result<IdRange> {
+    let data = fs::read_to_string(path).context(error::FileReadSnafu { path })?;
+    parse_subordinate_range(&data, name).context(error::UsernsRemapSnafu {
+        reason: format!("no subordinate range for '{name}' in '{path}'"),
+    })
+}
+
+/// Finds the first range for `name` in the `name:start:count` lines of `/etc/subuid` or
+/// `/etc/subgid`.
+fn parse_subordinate_range(data: &str, name: &str) -> Option<IdRange> {
+    data.lines().find_map(|line| {
+        let mut fields = line.trim().split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        Some(IdRange {
+            start: fields.next()?.parse().ok()?,
+            count: fields.next()?.parse().ok()?,
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_remap_user() {
+        assert_eq!(
+            remap_user(r#"{"userns-remap": "default"}"#),
+            Some(("dockremap".to_string(), "dockremap".to_string()))
+        );
+        assert_eq!(
+            remap_user(r#"{"userns-remap": "builder:docker"}"#),
+            Some(("builder".to_string(), "docker".to_string()))
+        );
+        assert_eq!(
+            remap_user(r#"{"userns-remap": "builder"}"#),
+            Some(("builder".to_string(), "builder".to_string()))
+        );
+        assert_eq!(remap_user(r#"{"debug": true}"#), None);
+    }
+
+    #[test]
+    fn test_parse_subordinate_range() {
+        let data = "builder:100000:65536\ndockremap:165536:65536\n";
+        assert_eq!(
+            parse_subordinate_range(data, "dockremap"),
+            Some(IdRange {
+                start: 165536,
+                count: 65536
+            })
+        );
+        assert_eq!(parse_subordinate_range(data, "dock"), None);
+    }
+
+    #[test]
+    fn test_builder_ids() {
+        let owner = Ids {
+            uid: 1000,
+            gid: 1000,
+        };
+        let unmapped = UserMapping { owner, remap: None };
+        assert_eq!(unmapped.builder(), owner);
+        assert_eq!(unmapped.root_uid(), 0);
+
+        let range = |start| IdRange {
+            start,
+            count: 65536,
+        };
+        let outside = UserMapping {
+            owner,
+            remap: Some((range(100000), range(100000))),
+        };
+        assert_eq!(outside.builder(), owner);
+        assert_eq!(outside.root_uid(), 100000);
+
+        let inside = UserMapping {
+            owner: Ids {
+                uid: 101000,
+                gid: 100100,
+            },
+            remap: Some((range(100000), range(100000))),
+        };
+        assert_eq!(
+            inside.builder(),
+            Ids {
+                uid: 1000,
+                gid: 100
+            }
+        );
+    }
+}
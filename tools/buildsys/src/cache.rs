@@ -9,20 +9,81 @@ given the (name, url, hash) data that uniquely identifies each file.
 It implements a two-tier approach to retrieval: files are first pulled from the
 "lookaside" cache and only fetched from the upstream site if that access fails.
 
+If there is a remote cache, it is tried before the lookaside cache, and files that it doesn't
+have are uploaded to it once they are fetched, if uploads are enabled.
+
+The lookaside cache can also be a local directory, given as a `file://` URL, with the same
+`<name>/<sha512>/<name>` layout. Files are copied from it without going through HTTP, a file that
+isn't there is a cache miss like any other, and files fetched from upstream are added to it, so
+that it fills up like a shared cache would.
+
+A developer can also point buildsys at a directory of overrides. A file there with the same name
+as an external file is used instead of fetching it, which makes it easy to try out a patched
+tarball before it's published. Overrides have to match the hash in the manifest unless unverified
+overrides are explicitly allowed.
+
+Files that are already present are checked against the hash before they're used, and fetched again
+if they no longer match, since a file can be cut short by an interrupted build or damaged on disk.
+The checked files are recorded in the package's source cache index.
+
 */
 pub(crate) mod error;
+mod index;
 use error::Result;
+pub(crate) use index::{IndexedFile, SourceCacheIndex};
 
+use crate::gitsource::GitSource;
+use crate::remote_cache::RemoteCache;
+use crate::rerun;
 use buildsys::manifest;
+use buildsys_config::{FETCH_LOG, FETCH_LOG_PREVIOUS};
 use filetime::{set_file_mtime, FileTime};
 use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+use serde::Serialize;
 use sha2::{Digest, Sha512};
 use snafu::{ensure, OptionExt, ResultExt};
-use std::fs::{self, File};
-use std::io::{self, BufWriter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 use url::Url;
 
+/// How large the fetch log grows before it's moved aside.
+const FETCH_LOG_MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The most that a throttled download reads at once.
+const THROTTLE_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Controls how downloads are throttled and retried.
+#[derive(Debug, Clone)]
+pub(crate) struct FetchPolicy {
+    /// The maximum download rate, in bytes per second.
+    pub(crate) rate_limit: Option<NonZeroU64>,
+
+    /// How many times a download that fails with a transient error is retried.
+    pub(crate) retries: u32,
+
+    /// How long to wait before the first retry. The wait doubles after each retry.
+    pub(crate) backoff: Duration,
+
+    /// The longest wait between retries.
+    pub(crate) max_backoff: Duration,
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        Self {
+            rate_limit: None,
+            retries: 0,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
 pub(crate) struct LookasideCache {
     /// The version string to include in HTTP headers.
     version: String,
@@ -33,6 +94,64 @@ pub(crate) struct LookasideCache {
     /// Whether we are allowed to pull sources from upstream URLs. When this is false, it can be
     /// overridden by `upstream-fallback` in the manifest.
     upstream_fallback: bool,
+
+    /// Where to record each fetch attempt, and the package to record them for.
+    fetch_log: Option<(PathBuf, String)>,
+
+    /// The SDK image, used to clone external files that come from git repositories.
+    sdk: Option<String>,
+
+    /// How downloads are throttled and retried.
+    policy: FetchPolicy,
+
+    /// A directory of files that are used instead of the external files with the same names, and
+    /// whether they are used even if they don't match the hashes in the manifest.
+    overrides: Option<(PathBuf, bool)>,
+
+    /// A remote cache that is tried before the lookaside cache.
+    remote_cache: Option<RemoteCache>,
+}
+
+/// Where an external file was fetched from.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum FetchSource {
+    LocalCache,
+    RemoteCache,
+    LookasideCache,
+    Upstream,
+    Mirror,
+}
+
+/// How many external files were served from a cache, and how many had to be downloaded from
+/// upstream. Files that were already present count as cached, as do files from the lookaside cache.
+/// Files taken from the overrides directory count as neither.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct FetchCounts {
+    pub(crate) cached: u64,
+    pub(crate) downloaded: u64,
+    pub(crate) overridden: u64,
+    /// How many of the overrides didn't match the hash in the manifest.
+    pub(crate) unverified: u64,
+}
+
+/// A single fetch attempt, written to the fetch log as one line of JSON.
+#[derive(Debug, Serialize)]
+struct FetchRecord<'a> {
+    package: &'a str,
+    file: &'a str,
+    url: &'a str,
+    source: FetchSource,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha512: Option<&'a str>,
+    /// Seconds since the Unix epoch when the attempt started.
+    started: u64,
+    duration_ms: u128,
 }
 
 impl LookasideCache {
@@ -45,11 +164,75 @@ impl LookasideCache {
             version: version.as_ref().to_string(),
             lookaside_cache,
             upstream_fallback,
+            fetch_log: None,
+            sdk: None,
+            policy: FetchPolicy::default(),
+            overrides: None,
+            remote_cache: None,
+        }
+    }
+
+    /// Append a record of every fetch attempt for `package` to the fetch log of the project at
+    /// `root`, one JSON object per line. Builds run concurrently, so each record is written with a
+    /// single append.
+    pub(crate) fn fetch_log(mut self, root: &Path, package: impl AsRef<str>) -> Self {
+        let log = root.join(FETCH_LOG);
+        if let Err(e) = Self::rotate_log(&log, &root.join(FETCH_LOG_PREVIOUS)) {
+            println!(
+                "cargo:warning=Failed to rotate fetch log '{}': {}",
+                log.display(),
+                e
+            );
+        }
+        self.fetch_log = Some((log, package.as_ref().to_string()));
+        self
+    }
+
+    /// Moves the fetch log to `previous` once it's grown too large. A record that another build
+    /// appends while the log is moved ends up in `previous`, which is still read.
+    fn rotate_log(log: &Path, previous: &Path) -> io::Result<()> {
+        match fs::metadata(log) {
+            Ok(metadata) if metadata.len() >= FETCH_LOG_MAX_SIZE => fs::rename(log, previous),
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
         }
     }
 
-    /// Fetch files stored out-of-tree and ensure they match the stored hash.
-    pub(crate) fn fetch(&self, files: &[manifest::ExternalFile], mtime: FileTime) -> Result<()> {
+    /// Use the given SDK image to clone git sources that aren't in the lookaside cache.
+    pub(crate) fn sdk(mut self, sdk: impl AsRef<str>) -> Self {
+        self.sdk = Some(sdk.as_ref().to_string());
+        self
+    }
+
+    /// Throttle and retry downloads according to `policy`.
+    pub(crate) fn policy(mut self, policy: FetchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Try `remote_cache` before the lookaside cache, and upload the files that it doesn't have.
+    pub(crate) fn remote_cache(mut self, remote_cache: Option<RemoteCache>) -> Self {
+        self.remote_cache = remote_cache;
+        self
+    }
+
+    /// Use the files in `dir` instead of fetching the external files with the same names. Unless
+    /// `allow_unverified` is set, a file that doesn't match the manifest's hash is an error.
+    pub(crate) fn overrides(mut self, dir: impl Into<PathBuf>, allow_unverified: bool) -> Self {
+        self.overrides = Some((dir.into(), allow_unverified));
+        self
+    }
+
+    /// Fetch files stored out-of-tree and ensure they match the stored hash. The files that match
+    /// are recorded in `index`.
+    pub(crate) fn fetch(
+        &self,
+        files: &[manifest::ExternalFile],
+        mtime: FileTime,
+        index: &mut SourceCacheIndex,
+    ) -> Result<FetchCounts> {
+        let mut counts = FetchCounts::default();
         for f in files {
             let url_file_name = Self::extract_file_name(&f.url)?;
             let path = &f.path.as_ref().unwrap_or(&url_file_name);
@@ -59,20 +242,59 @@ impl LookasideCache {
             );
 
             let hash = &f.sha512;
+            if let Some(verified) = self.use_override(path, hash, mtime)? {
+                counts.overridden += 1;
+                counts.unverified += u64::from(!verified);
+                match verified {
+                    true => index.record(path, IndexedFile::ExternalFile, hash),
+                    false => index.forget(path),
+                }
+                continue;
+            }
+
+            let name = &path.display().to_string();
             if path.is_file() {
-                match Self::verify_file(path, hash) {
-                    Ok(_) => continue,
+                let verified = self.fetch_logged(name, name, hash, FetchSource::LocalCache, || {
+                    Self::verify_file(path, hash).map(|_| 0)
+                });
+                match verified {
+                    Ok(_) => {
+                        index.record(path, IndexedFile::ExternalFile, hash);
+                        counts.cached += 1;
+                        continue;
+                    }
                     Err(e) => {
-                        println!("{}", e);
+                        warn!(
+                            "Cached file {:?} is {}, fetching it again: {}",
+                            path,
+                            index.damage(path),
+                            e
+                        );
+                        index.forget(path);
                         fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
                     }
                 }
             }
 
-            let name = &path.display().to_string();
             let tmp = PathBuf::from(format!(".{}", name));
 
-            // first check the lookaside cache
+            // first check the remote cache, if there is one
+            if let Some(remote_cache) = &self.remote_cache {
+                match self.fetch_remote(remote_cache, name, hash, &tmp) {
+                    Ok(()) => {
+                        fs::rename(&tmp, path)
+                            .context(error::ExternalFileRenameSnafu { path: &tmp })?;
+                        set_file_mtime(path, mtime).context(error::SetMtimeSnafu { path })?;
+                        index.record(path, IndexedFile::ExternalFile, hash);
+                        counts.cached += 1;
+                        continue;
+                    }
+                    Err(error::Error::RemoteCacheMiss) => (),
+                    Err(e) => warn!("Error fetching from the remote cache: {}", e),
+                }
+            }
+
+            // next check the lookaside cache
             let mut url = self.lookaside_cache.clone();
             url.path_segments_mut()
                 .map_err(|_| {
@@ -81,24 +303,42 @@ impl LookasideCache {
                     }
                     .build()
                 })?
+                .pop_if_empty()
                 .extend([name, hash, name]);
             let url = url.to_string();
-            match self.fetch_file(&url, &tmp, hash) {
+            let from_cache = self.fetch_logged(
+                name,
+                &url,
+                hash,
+                FetchSource::LookasideCache,
+                || match local_path(&url) {
+                    Some(local) => Self::copy_local(&local, &tmp, hash),
+                    None => self.fetch_file(&url, &tmp, hash),
+                },
+            );
+            match from_cache {
                 Ok(_) => {
                     fs::rename(&tmp, path)
                         .context(error::ExternalFileRenameSnafu { path: &tmp })?;
                     set_file_mtime(path, mtime).context(error::SetMtimeSnafu { path })?;
+                    index.record(path, IndexedFile::ExternalFile, hash);
+                    self.share(path, hash);
+                    counts.cached += 1;
                     continue;
                 }
                 Err(e) => {
                     // next check with upstream, if permitted
                     if f.force_upstream.unwrap_or(false) || self.upstream_fallback {
-                        println!("Error fetching from lookaside cache: {}", e);
-                        println!("Fetching {:?} from upstream source", url_file_name);
-                        self.fetch_file(&f.url, &tmp, hash)?;
+                        warn!("Error fetching from lookaside cache: {}", e);
+                        info!("Fetching {:?} from upstream source", url_file_name);
+                        self.fetch_upstream(f, name, &tmp)?;
                         fs::rename(&tmp, path)
                             .context(error::ExternalFileRenameSnafu { path: &tmp })?;
                         set_file_mtime(path, mtime).context(error::SetMtimeSnafu { path })?;
+                        index.record(path, IndexedFile::ExternalFile, hash);
+                        self.share(path, hash);
+                        Self::store_local(path, &url);
+                        counts.downloaded += 1;
                     } else {
                         // we failed to fetch from the lookaside cache, and we cannot fall back to
                         // upstream sources, so we should not continue, we need to return the error
@@ -108,14 +348,261 @@ impl LookasideCache {
             }
         }
 
-        Ok(())
+        Ok(counts)
+    }
+
+    /// Copies the override of the external file at `path` into place, if there is one. Returns
+    /// whether the override matches `hash`, or `None` if there is no override.
+    fn use_override(&self, path: &Path, hash: &str, mtime: FileTime) -> Result<Option<bool>> {
+        let Some((dir, allow_unverified)) = &self.overrides else {
+            return Ok(None);
+        };
+        let source = dir.join(path);
+        if !source.is_file() {
+            return Ok(None);
+        }
+        rerun::file(&source);
+
+        let digest = Self::digest(&source)?;
+        let verified = digest == hash;
+        if !verified {
+            ensure!(
+                *allow_unverified,
+                error::UnverifiedOverrideSnafu {
+                    path: &source,
+                    hash
+                }
+            );
+            println!(
+                "cargo:warning=Using '{}', which doesn't match the hash in the manifest",
+                source.display()
+            );
+        }
+
+        // Only copy the override if it changed, so that the files made from it aren't remade.
+        if !path.is_file() || Self::digest(path)? != digest {
+            info!("Using {:?} from '{}'", path, dir.display());
+            let tmp = PathBuf::from(format!(".{}", path.display()));
+            fs::copy(&source, &tmp).context(error::ExternalFileCopySnafu {
+                from: &source,
+                path: &tmp,
+            })?;
+            fs::rename(&tmp, path).context(error::ExternalFileRenameSnafu { path: &tmp })?;
+        }
+        set_file_mtime(path, mtime).context(error::SetMtimeSnafu { path })?;
+        Ok(Some(verified))
+    }
+
+    /// Fetches the external file named `name` from the remote cache to `tmp`, and checks it against
+    /// `hash`.
+    fn fetch_remote(
+        &self,
+        remote_cache: &RemoteCache,
+        name: &str,
+        hash: &str,
+        tmp: &Path,
+    ) -> Result<()> {
+        let url = format!("remote-cache:sources/{hash}");
+        self.fetch_logged(name, &url, hash, FetchSource::RemoteCache, || {
+            let found = remote_cache
+                .fetch_source(hash, tmp)
+                .context(error::RemoteCacheFetchSnafu)?;
+            ensure!(found, error::RemoteCacheMissSnafu);
+            if let Err(e) = Self::verify_file(tmp, hash) {
+                fs::remove_file(tmp).context(error::ExternalFileDeleteSnafu { path: tmp })?;
+                return Err(e);
+            }
+            fs::metadata(tmp)
+                .map(|metadata| metadata.len())
+                .context(error::ExternalFileLoadSnafu { path: tmp })
+        })
+    }
+
+    /// Uploads the external file at `path` to the remote cache, if uploads are enabled. Failing to
+    /// upload it doesn't fail the build.
+    fn share(&self, path: &Path, hash: &str) {
+        let Some(remote_cache) = self.remote_cache.as_ref().filter(|c| c.upload_enabled()) else {
+            return;
+        };
+        if let Err(e) = remote_cache.store_source(hash, path) {
+            println!(
+                "cargo:warning=Unable to upload {} to the remote cache: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Adds the external file at `path` to the local lookaside cache at `url`, if that's where the
+    /// lookaside cache is. Failing to add it doesn't fail the build.
+    fn store_local(path: &Path, url: &str) {
+        let Some(local) = local_path(url) else {
+            return;
+        };
+        let result = local
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| {
+                // Copy to a temporary name first, so that a partial copy is never found.
+                let tmp = local.with_extension("tmp");
+                fs::copy(path, &tmp)?;
+                fs::rename(&tmp, &local)
+            });
+        if let Err(e) = result {
+            println!(
+                "cargo:warning=Unable to add {} to the lookaside cache: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Copies a file from a local lookaside cache to `path`, and checks it against the SHA-512
+    /// hash provided. Returns the size of the file.
+    fn copy_local(local: &Path, path: &Path, hash: &str) -> Result<u64> {
+        ensure!(local.is_file(), error::LocalCacheMissSnafu { path: local });
+        let bytes =
+            fs::copy(local, path).context(error::ExternalFileCopySnafu { from: local, path })?;
+        match Self::verify_file(path, hash) {
+            Ok(_) => Ok(bytes),
+            Err(e) => {
+                fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Tries the upstream URL of an external file, then each of its mirrors in order, until one
+    /// of them provides a file with the expected hash. Returns the last error if none do.
+    fn fetch_upstream(&self, f: &manifest::ExternalFile, name: &str, tmp: &Path) -> Result<()> {
+        let mut result = self.fetch_source(f, name, &f.url, tmp, FetchSource::Upstream);
+        for mirror in f.mirrors.iter().flatten() {
+            let Err(e) = &result else { break };
+            warn!("Error fetching from upstream source: {}", e);
+            info!("Fetching {:?} from mirror {}", name, mirror);
+            result = self.fetch_source(f, name, mirror, tmp, FetchSource::Mirror);
+        }
+        result
+    }
+
+    /// Fetches an external file from one of its upstream URLs, by cloning it if it's a git source.
+    fn fetch_source(
+        &self,
+        f: &manifest::ExternalFile,
+        name: &str,
+        url: &str,
+        tmp: &Path,
+        source: FetchSource,
+    ) -> Result<()> {
+        let hash = &f.sha512;
+        self.fetch_logged(name, url, hash, source, || {
+            if f.git_commit.is_some() {
+                self.fetch_git(f, url, tmp, hash)
+            } else {
+                self.fetch_file(url, tmp, hash)
+            }
+        })
+    }
+
+    /// Runs a fetch and records the outcome in the fetch log, if there is one.
+    fn fetch_logged(
+        &self,
+        name: &str,
+        url: &str,
+        hash: &str,
+        source: FetchSource,
+        fetch: impl FnOnce() -> Result<u64>,
+    ) -> Result<()> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let timer = Instant::now();
+        let result = fetch();
+
+        if let Some((log, package)) = &self.fetch_log {
+            let record = FetchRecord {
+                package,
+                file: name,
+                url,
+                source,
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                bytes: result.as_ref().ok().copied(),
+                sha512: result.is_ok().then_some(hash),
+                started,
+                duration_ms: timer.elapsed().as_millis(),
+            };
+            if let Err(e) = Self::append_record(log, &record) {
+                println!(
+                    "cargo:warning=Failed to write fetch log '{}': {}",
+                    log.display(),
+                    e
+                );
+            }
+        }
+
+        result.map(|_| ())
+    }
+
+    fn append_record(log: &Path, record: &FetchRecord<'_>) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        if let Some(parent) = log.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)?
+            .write_all(line.as_bytes())
     }
 
     /// Retrieves a file from the specified URL and write it to the given path,
-    /// then verifies the contents against the SHA-512 hash provided.
-    fn fetch_file<P: AsRef<Path>>(&self, url: &str, path: P, hash: &str) -> Result<()> {
+    /// then verifies the contents against the SHA-512 hash provided. Returns the number of bytes
+    /// downloaded. Transient failures are retried according to the fetch policy.
+    fn fetch_file<P: AsRef<Path>>(&self, url: &str, path: P, hash: &str) -> Result<u64> {
         let path = path.as_ref();
 
+        let mut backoff = self.policy.backoff;
+        let mut attempt = 0;
+        let bytes = loop {
+            match self.download(url, path) {
+                Ok(bytes) => break bytes,
+                Err(e) if attempt < self.policy.retries && e.is_transient() => {
+                    attempt += 1;
+                    warn!(
+                        "Error fetching '{}', retrying in {}s ({}/{}): {}",
+                        url,
+                        backoff.as_secs_f32(),
+                        attempt,
+                        self.policy.retries,
+                        e
+                    );
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2).min(self.policy.max_backoff);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        match Self::verify_file(path, hash) {
+            Ok(_) => Ok(bytes),
+            Err(e) => {
+                fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Downloads the file at `url` to `path`, honoring the rate limit. Returns the number of bytes
+    /// downloaded. A `file://` URL is copied from the local filesystem instead.
+    fn download(&self, url: &str, path: &Path) -> Result<u64> {
+        if let Some(local) = local_path(url) {
+            return fs::copy(&local, path)
+                .context(error::ExternalFileCopySnafu { from: local, path });
+        }
+
         let mut headers = HeaderMap::new();
         headers.insert(
             USER_AGENT,
@@ -142,12 +629,30 @@ impl LookasideCache {
 
         let f = File::create(path).context(error::ExternalFileOpenSnafu { path })?;
         let mut f = BufWriter::new(f);
-        resp.copy_to(&mut f)
-            .context(error::ExternalFileSaveSnafu { path })?;
-        drop(f);
+        let bytes = match self.policy.rate_limit {
+            Some(rate) => copy_throttled(&mut resp, &mut f, rate)
+                .context(error::ExternalFileWriteSnafu { path })?,
+            None => resp
+                .copy_to(&mut f)
+                .context(error::ExternalFileSaveSnafu { path })?,
+        };
+        f.flush().context(error::ExternalFileWriteSnafu { path })?;
+        Ok(bytes)
+    }
 
+    /// Clones a git source in the SDK and archives it to the given path, then verifies the
+    /// archive against the SHA-512 hash provided. Returns the size of the archive.
+    fn fetch_git(
+        &self,
+        f: &manifest::ExternalFile,
+        url: &str,
+        path: &Path,
+        hash: &str,
+    ) -> Result<u64> {
+        let sdk = self.sdk.as_deref().context(error::GitSdkSnafu { url })?;
+        let bytes = GitSource::archive(f, url, sdk, path).context(error::GitSourceSnafu)?;
         match Self::verify_file(path, hash) {
-            Ok(_) => Ok(()),
+            Ok(_) => Ok(bytes),
             Err(e) => {
                 fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
                 Err(e)
@@ -168,16 +673,56 @@ impl LookasideCache {
     /// Reads a file from disk and compares it to the expected SHA-512 hash.
     fn verify_file<P: AsRef<Path>>(path: P, hash: &str) -> Result<()> {
         let path = path.as_ref();
-        let mut f = File::open(path).context(error::ExternalFileOpenSnafu { path })?;
-        let mut d = Sha512::new();
-
-        io::copy(&mut f, &mut d).context(error::ExternalFileLoadSnafu { path })?;
-        let digest = hex::encode(d.finalize());
-
+        let digest = Self::digest(path)?;
         ensure!(
             digest == hash,
             error::ExternalFileVerifySnafu { path, hash }
         );
         Ok(())
     }
+
+    /// Reads a file from disk and returns its SHA-512 hash.
+    fn digest(path: &Path) -> Result<String> {
+        let mut f = File::open(path).context(error::ExternalFileOpenSnafu { path })?;
+        let mut d = Sha512::new();
+        io::copy(&mut f, &mut d).context(error::ExternalFileLoadSnafu { path })?;
+        Ok(hex::encode(d.finalize()))
+    }
+}
+
+/// The local path of a `file://` URL.
+fn local_path(url: &str) -> Option<PathBuf> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok())
+}
+
+/// Copies everything from `reader` to `writer`, sleeping as needed to keep the average rate under
+/// `rate` bytes per second.
+fn copy_throttled(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    rate: NonZeroU64,
+) -> io::Result<u64> {
+    let start = Instant::now();
+    // Read at most one second's worth at a time, so slow rates don't start with a burst.
+    let mut buf = vec![0; THROTTLE_CHUNK_SIZE.min(rate.get()) as usize];
+    let mut total = 0u64;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+
+        let expected = Duration::from_secs_f64(total as f64 / rate.get() as f64);
+        if let Some(wait) = expected.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+    Ok(total)
 }
@@ -24,6 +24,16 @@ pub(crate) enum Error {
         status: reqwest::StatusCode,
     },
 
+    #[snafu(display("Failed to copy '{}' to '{}': {}", from.display(), path.display(), source))]
+    ExternalFileCopy {
+        from: PathBuf,
+        path: PathBuf,
+        source: io::Error,
+    },
+
+    #[snafu(display("'{}' is not in the local lookaside cache", path.display()))]
+    LocalCacheMiss { path: PathBuf },
+
     #[snafu(display("Failed to open file '{}': {}", path.display(), source))]
     ExternalFileOpen { path: PathBuf, source: io::Error },
 
@@ -33,6 +43,9 @@ pub(crate) enum Error {
         source: reqwest::Error,
     },
 
+    #[snafu(display("Failed to write file '{}': {}", path.display(), source))]
+    ExternalFileWrite { path: PathBuf, source: io::Error },
+
     #[snafu(display("Failed to load file '{}': {}", path.display(), source))]
     ExternalFileLoad { path: PathBuf, source: io::Error },
 
@@ -45,11 +58,50 @@ pub(crate) enum Error {
     #[snafu(display("Failed to delete file '{}': {}", path.display(), source))]
     ExternalFileDelete { path: PathBuf, source: io::Error },
 
+    #[snafu(display("No SDK image was provided to clone git source '{}'", url))]
+    GitSdk { url: String },
+
+    #[snafu(display("{}", source))]
+    GitSource {
+        source: crate::gitsource::error::Error,
+    },
+
+    #[snafu(display("Failed to fetch from the remote cache: {}", source))]
+    RemoteCacheFetch {
+        source: crate::remote_cache::error::Error,
+    },
+
+    #[snafu(display("Not found in the remote cache"))]
+    RemoteCacheMiss,
+
     #[snafu(display("Failed to set modification time for file '{}': {}", path.display(), source))]
     SetMtime { path: PathBuf, source: io::Error },
 
+    #[snafu(display(
+        "Override '{}' doesn't match hash '{}', set BUILDSYS_ALLOW_UNVERIFIED_OVERRIDES to use it",
+        path.display(),
+        hash
+    ))]
+    UnverifiedOverride { path: PathBuf, hash: String },
+
     #[snafu(display("Failed to get path segments from URL '{}'", url))]
     UrlPathSegments { url: String },
 }
 
+impl Error {
+    /// Whether the error might go away if the download is retried, such as a dropped connection or
+    /// an overloaded server, as opposed to a missing file or a hash mismatch.
+    pub(super) fn is_transient(&self) -> bool {
+        match self {
+            Error::ExternalFileRequest { .. }
+            | Error::ExternalFileSave { .. }
+            | Error::ExternalFileWrite { .. } => true,
+            Error::ExternalFileFetch { status, .. } => {
+                status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+
 pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,176 @@
+/*!
+The index of a package's local source cache records the SHA-512 digest and size of each external
+file, once it has been verified, and of each bundle made from them. A cached file that no longer
+matches its hash is fetched again, and the index tells whether it was cut short or changed in
+place. Twoliter reads the index to check the whole cache at once, with `twoliter cache verify`.
+
+Each package has its own index, since packages are built concurrently. Failing to write the index
+never fails the build.
+*/
+
+use super::LookasideCache;
+use buildsys_config::SOURCE_CACHE_INDEX_DIRECTORY;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The kinds of files in the local source cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum IndexedFile {
+    /// A file fetched from the lookaside cache or upstream, which matches the manifest's hash.
+    ExternalFile,
+    /// A bundle of vendored dependencies made from an external file.
+    Bundle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct IndexEntry {
+    kind: IndexedFile,
+    sha512: String,
+    size: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SourceCacheIndex {
+    /// Where the index is written.
+    #[serde(skip)]
+    path: PathBuf,
+    /// The directory that the files are cached in, relative to the project's root if it's in it.
+    package_dir: PathBuf,
+    files: BTreeMap<String, IndexEntry>,
+    /// The files recorded by this build. Only these are saved, so that files the package no
+    /// longer uses are dropped.
+    #[serde(skip)]
+    recorded: BTreeSet<String>,
+}
+
+impl SourceCacheIndex {
+    /// Loads the index for `package`, whose files are cached in `package_dir`. An index that is
+    /// missing or can't be read is started over.
+    pub(crate) fn load(root: &Path, package: &str, package_dir: &Path) -> Self {
+        let path = root
+            .join(SOURCE_CACHE_INDEX_DIRECTORY)
+            .join(format!("{package}.json"));
+        let files = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Self>(&data).ok())
+            .map(|index| index.files)
+            .unwrap_or_default();
+        Self {
+            path,
+            package_dir: package_dir
+                .strip_prefix(root)
+                .unwrap_or(package_dir)
+                .to_path_buf(),
+            files,
+            recorded: BTreeSet::new(),
+        }
+    }
+
+    /// Records the cached file at `path`, whose digest is `sha512`.
+    pub(crate) fn record(&mut self, path: &Path, kind: IndexedFile, sha512: &str) {
+        let Ok(metadata) = fs::metadata(path) else {
+            return;
+        };
+        self.recorded.insert(Self::key(path));
+        self.files.insert(
+            Self::key(path),
+            IndexEntry {
+                kind,
+                sha512: sha512.to_string(),
+                size: metadata.len(),
+            },
+        );
+    }
+
+    /// Records the cached file at `path`, finding its digest first.
+    pub(crate) fn record_digest(&mut self, path: &Path, kind: IndexedFile) {
+        if let Ok(digest) = LookasideCache::digest(path) {
+            self.record(path, kind, &digest);
+        }
+    }
+
+    /// Forgets the file at `path`, which is no longer known to be intact.
+    pub(crate) fn forget(&mut self, path: &Path) {
+        self.files.remove(&Self::key(path));
+        self.recorded.remove(&Self::key(path));
+    }
+
+    /// Describes how the file at `path` differs from when it was recorded, given that its digest
+    /// no longer matches.
+    pub(crate) fn damage(&self, path: &Path) -> &'static str {
+        let recorded = self.files.get(&Self::key(path)).map(|entry| entry.size);
+        let size = fs::metadata(path).map(|metadata| metadata.len()).ok();
+        match (recorded, size) {
+            (Some(recorded), Some(size)) if size < recorded => "truncated",
+            (Some(_), Some(_)) => "corrupt",
+            _ => "damaged",
+        }
+    }
+
+    /// Writes the files recorded by this build to the index.
+    pub(crate) fn save(mut self) {
+        let recorded = std::mem::take(&mut self.recorded);
+        self.files.retain(|name, _| recorded.contains(name));
+        if let Err(e) = self.write() {
+            println!(
+                "cargo:warning=Unable to write the source cache index '{}': {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn write(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    fn key(path: &Path) -> String {
+        path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_index() {
+        let root = TempDir::new().unwrap();
+        let package_dir = root.path().join("packages/hello");
+        fs::create_dir_all(&package_dir).unwrap();
+        let file = package_dir.join("hello-1.0.tar.gz");
+        fs::write(&file, b"hello world").unwrap();
+
+        let mut index = SourceCacheIndex::load(root.path(), "hello", &package_dir);
+        index.record(&file, IndexedFile::ExternalFile, "abc");
+        index.save();
+
+        let index = SourceCacheIndex::load(root.path(), "hello", &package_dir);
+        assert_eq!(index.package_dir, PathBuf::from("packages/hello"));
+        assert_eq!(index.files["hello-1.0.tar.gz"].size, 11);
+
+        fs::write(&file, b"hello").unwrap();
+        assert_eq!(index.damage(&file), "truncated");
+        fs::write(&file, b"jello world").unwrap();
+        assert_eq!(index.damage(&file), "corrupt");
+
+        // Files that a build doesn't record are dropped.
+        index.save();
+        let index = SourceCacheIndex::load(root.path(), "hello", &package_dir);
+        assert!(index.files.is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+/*!
+Records how much of each build was served from a cache, so that twoliter can report how well the
+caches work. For each kind of item a build uses or produces - external files, vendored bundles,
+RPMs, and docker layers - the build appends one line of JSON to `build/cache-log.json` with how
+many were served from a cache and how many had to be downloaded or built again.
+
+Builds run concurrently, so each record is written with a single append. Failing to write a record
+never fails the build.
+*/
+
+use buildsys_config::CACHE_LOG;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The kinds of items whose cache use is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Item {
+    /// Files that are already present, or that come from the lookaside cache, are cached.
+    ExternalFile,
+    /// Go modules are vendored again whenever a package build runs.
+    VendoredBundle,
+    /// RPMs from the remote build cache are cached.
+    Rpm,
+    /// Steps of the Dockerfile that BuildKit reports as `CACHED`.
+    DockerLayer,
+}
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    artifact: &'a str,
+    item: Item,
+    cached: u64,
+    fresh: u64,
+    /// Seconds since the epoch.
+    recorded: u64,
+}
+
+/// Records that the build of `artifact` served `cached` items of a kind from a cache, and had to
+/// download or build `fresh` of them. Nothing is recorded if there were none.
+pub(crate) fn record(root: &Path, artifact: &str, item: Item, cached: u64, fresh: u64) {
+    if cached == 0 && fresh == 0 {
+        return;
+    }
+    let record = Record {
+        artifact,
+        item,
+        cached,
+        fresh,
+        recorded: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    };
+    let log = root.join(CACHE_LOG);
+    if let Err(e) = append(&log, &record) {
+        println!(
+            "cargo:warning=Unable to record cache use in '{}': {}",
+            log.display(),
+            e
+        );
+    }
+}
+
+fn append(log: &Path, record: &Record<'_>) -> io::Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    if let Some(parent) = log.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)?
+        .write_all(line.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::TempDir::new().unwrap();
+        record(dir.path(), "glibc", Item::ExternalFile, 2, 1);
+        record(dir.path(), "glibc", Item::VendoredBundle, 0, 0);
+        record(dir.path(), "glibc", Item::Rpm, 0, 4);
+
+        let log = fs::read_to_string(dir.path().join(CACHE_LOG)).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""item":"external-file","cached":2,"fresh":1"#));
+        assert!(lines[1].contains(r#""item":"rpm","cached":0,"fresh":4"#));
+    }
+}
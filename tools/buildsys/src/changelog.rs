@@ -0,0 +1,329 @@
+/*!
+Release notes for a variant are mostly the changelogs of the packages that changed since the last
+release. When `BUILDSYS_CHANGELOG_BASELINE` names a git revision, a variant build collects the new
+changelog entries of every included package, and every package they depend on, whose directory
+changed since that revision, and writes them to `CHANGELOG-<variant>.md` next to the variant's
+images. Packages are found wherever their manifests are in the project, and the build runs again
+when the project's git refs move.
+
+A package's changelog is the `%changelog` section of its spec, where each entry starts with a line
+beginning with `* `, unless its manifest names a Markdown file with the `changelog` key, where each
+entry starts with a `## ` heading. An entry is new if the baseline's changelog doesn't have it.
+
+Packages can also have entries generated from git history at build time; see the `generate`
+module.
+
+*/
+pub(crate) mod error;
+pub(crate) mod generate;
+use error::Result;
+
+use crate::rerun;
+use buildsys::manifest::ManifestInfo;
+use duct::cmd;
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The aggregated changelog of the packages in a variant.
+#[derive(Debug)]
+pub(crate) struct Changelog {
+    variant: String,
+    arch: String,
+    baseline: String,
+    packages: Vec<PackageChanges>,
+}
+
+/// The new changelog entries of a package that changed since the baseline.
+#[derive(Debug, PartialEq)]
+struct PackageChanges {
+    name: String,
+    entries: Vec<String>,
+}
+
+/// Where a package keeps its changelog, and how its entries are marked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Spec,
+    Markdown,
+}
+
+impl Changelog {
+    /// Collects the changelog entries that were added since `baseline` to each of `packages`, given
+    /// by name with their directories in the project at `root`.
+    pub(crate) fn collect(
+        root: &Path,
+        baseline: &str,
+        variant: &str,
+        arch: &str,
+        packages: &BTreeMap<String, PathBuf>,
+    ) -> Result<Self> {
+        // Make sure the baseline itself is valid, so a typo isn't mistaken for new packages.
+        let output = cmd!("git", "rev-parse", "--verify", "--quiet", baseline)
+            .dir(root)
+            .stdout_capture()
+            .unchecked()
+            .run()
+            .context(error::CommandStartSnafu)?;
+        ensure!(
+            output.status.success(),
+            error::BadBaselineSnafu { baseline }
+        );
+
+        // Git is given paths relative to the root, so that the baseline's files can be shown.
+        let canonical_root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let mut changes = Vec::new();
+        for (name, package_dir) in packages {
+            let Ok(dir) = package_dir
+                .strip_prefix(root)
+                .or_else(|_| package_dir.strip_prefix(&canonical_root))
+            else {
+                continue;
+            };
+            if !package_dir.is_dir() || !changed_since(root, baseline, dir)? {
+                continue;
+            }
+
+            let manifest_path = package_dir.join("Cargo.toml");
+            let manifest =
+                ManifestInfo::new(&manifest_path).context(error::ManifestParseSnafu {
+                    path: &manifest_path,
+                })?;
+            let (file, format) = match manifest.changelog() {
+                Some(file) => (dir.join(file), Format::Markdown),
+                None => (
+                    dir.join(format!("{}.spec", manifest.package_name())),
+                    Format::Spec,
+                ),
+            };
+
+            let current = fs::read_to_string(root.join(&file))
+                .context(error::FileReadSnafu { path: &file })?;
+            let baseline_text = show(root, baseline, &file)?;
+            changes.push(PackageChanges {
+                name: name.clone(),
+                entries: new_entries(
+                    &entries(&current, format),
+                    baseline_text.map(|text| entries(&text, format)).as_deref(),
+                ),
+            });
+        }
+
+        Ok(Self {
+            variant: variant.to_string(),
+            arch: arch.to_string(),
+            baseline: baseline.to_string(),
+            packages: changes,
+        })
+    }
+
+    /// Renders the changelog as Markdown.
+    pub(crate) fn render(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(
+            s,
+            "# Changes in {} ({}) since {}",
+            self.variant, self.arch, self.baseline
+        );
+        if self.packages.is_empty() {
+            let _ = writeln!(s, "\nNo packages changed.");
+        }
+        for package in &self.packages {
+            let _ = writeln!(s, "\n## {}\n", package.name);
+            if package.entries.is_empty() {
+                let _ = writeln!(s, "Changed, with no new changelog entries.");
+            }
+            for entry in &package.entries {
+                let _ = writeln!(s, "{}", entry);
+            }
+        }
+        s
+    }
+
+    /// Writes the rendered changelog to `path`.
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.render()).context(error::FileWriteSnafu { path })
+    }
+}
+
+/// Asks cargo to run the build again when the project's git HEAD or refs move, since changelogs
+/// are made from its history.
+pub(crate) fn rerun_for_git(root: &Path) {
+    for (dir, files) in [
+        ("--git-dir", &["HEAD"][..]),
+        ("--git-common-dir", &["packed-refs", "refs"][..]),
+    ] {
+        let Ok(dir) = cmd!("git", "rev-parse", dir).dir(root).stderr_null().read() else {
+            continue;
+        };
+        let dir = root.join(dir.trim());
+        // Cargo always runs the build again if a file it watches doesn't exist.
+        for file in files.iter().map(|f| dir.join(f)).filter(|f| f.exists()) {
+            rerun::file(file);
+        }
+    }
+}
+
+/// Whether anything under `dir` changed between `baseline` and the working tree.
+fn changed_since(root: &Path, baseline: &str, dir: &Path) -> Result<bool> {
+    let output = cmd!("git", "diff", "--quiet", baseline, "--", dir)
+        .dir(root)
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+    match output.status.code() {
+        Some(0) => Ok(false),
+        Some(1) => Ok(true),
+        _ => error::GitSnafu {
+            command: format!("git diff {baseline} -- {}", dir.display()),
+            output: String::from_utf8_lossy(&output.stderr),
+        }
+        .fail(),
+    }
+}
+
+/// The contents of `file` at `baseline`, or `None` if it didn't exist then.
+fn show(root: &Path, baseline: &str, file: &Path) -> Result<Option<String>> {
+    let output = cmd!("git", "show", format!("{baseline}:{}", file.display()))
+        .dir(root)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Splits a changelog into its entries, newest first, each with its surrounding blank lines
+/// removed.
+fn entries(text: &str, format: Format) -> Vec<String> {
+    let (lines, marker): (Box<dyn Iterator<Item = &str>>, &str) = match format {
+        Format::Spec => (
+            // The changelog is the last section of most specs, but stop at any section after it.
+            Box::new(
+                text.lines()
+                    .skip_while(|l| l.trim_end() != "%changelog")
+                    .skip(1)
+                    .take_while(|l| !is_spec_section(l)),
+            ),
+            "* ",
+        ),
+        Format::Markdown => (Box::new(text.lines()), "## "),
+    };
+
+    let mut entries: Vec<Vec<&str>> = Vec::new();
+    for line in lines {
+        if line.starts_with(marker) {
+            entries.push(vec![line]);
+        } else if let Some(entry) = entries.last_mut() {
+            entry.push(line);
+        }
+    }
+    entries
+        .into_iter()
+        .map(|lines| lines.join("\n").trim().to_string())
+        .collect()
+}
+
+fn is_spec_section(line: &str) -> bool {
+    line.strip_prefix('%')
+        .and_then(|rest| rest.chars().next())
+        .is_some_and(|c| c.is_ascii_alphabetic())
+}
+
+/// The entries of `current` that aren't in `baseline`. A package that didn't exist at the
+/// baseline only contributes its latest entry, rather than its whole history.
+fn new_entries(current: &[String], baseline: Option<&[String]>) -> Vec<String> {
+    match baseline {
+        Some(baseline) => {
+            let old = baseline.iter().collect::<HashSet<_>>();
+            current
+                .iter()
+                .filter(|e| !old.contains(e))
+                .cloned()
+                .collect()
+        }
+        None => current.iter().take(1).cloned().collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SPEC: &str = "\
+Name: %{_cross_os}hello
+Version: 1.1
+
+%files
+%{_cross_bindir}/hello
+
+%changelog
+* Tue Mar 05 2024 Someone <someone@example.com> - 1.1-1
+- Update to 1.1
+
+* Mon Jan 01 2024 Someone <someone@example.com> - 1.0-1
+- Initial package
+";
+
+    #[test]
+    fn test_spec_entries() {
+        let entries = entries(SPEC, Format::Spec);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0],
+            "* Tue Mar 05 2024 Someone <someone@example.com> - 1.1-1\n- Update to 1.1"
+        );
+    }
+
+    #[test]
+    fn test_markdown_entries() {
+        let text = "# Changelog\n\n## 1.1\n\n- Faster\n\n## 1.0\n\n- First\n";
+        assert_eq!(
+            entries(text, Format::Markdown),
+            vec!["## 1.1\n\n- Faster", "## 1.0\n\n- First"]
+        );
+    }
+
+    #[test]
+    fn test_new_entries() {
+        let current = entries(SPEC, Format::Spec);
+        let baseline = current[1..].to_vec();
+        assert_eq!(
+            new_entries(&current, Some(&baseline)),
+            vec![current[0].clone()]
+        );
+        assert_eq!(new_entries(&current, None), vec![current[0].clone()]);
+    }
+
+    #[test]
+    fn test_render() {
+        let changelog = Changelog {
+            variant: "aws-dev".to_string(),
+            arch: "x86_64".to_string(),
+            baseline: "v1.19.0".to_string(),
+            packages: vec![
+                PackageChanges {
+                    name: "hello".to_string(),
+                    entries: vec!["* Tue Mar 05 2024 Someone - 1.1-1\n- Update to 1.1".to_string()],
+                },
+                PackageChanges {
+                    name: "libz".to_string(),
+                    entries: Vec::new(),
+                },
+            ],
+        };
+        assert_eq!(
+            changelog.render(),
+            "# Changes in aws-dev (x86_64) since v1.19.0\n\n\
+            ## hello\n\n* Tue Mar 05 2024 Someone - 1.1-1\n- Update to 1.1\n\n\
+            ## libz\n\nChanged, with no new changelog entries.\n"
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Changelog baseline '{}' is not a git revision", baseline))]
+    BadBaseline { baseline: String },
+
+    #[snafu(display("Failed to start command: {}", source))]
+    CommandStart { source: std::io::Error },
+
+    #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+    FileRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+    FileWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("'{}' failed: {}", command, output))]
+    Git { command: String, output: String },
+
+    #[snafu(display("Failed to parse manifest '{}': {}", path.display(), source))]
+    ManifestParse {
+        path: PathBuf,
+        source: buildsys::manifest::Error,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,189 @@
+/*!
+Generates `%changelog` entries for packages that set `generate-changelog = true`, so that their RPM
+changelogs follow the project's history without anyone having to edit the spec.
+
+Each commit that touched the package directory since the spec's `Version` last changed becomes an
+entry, starting with the commit that changed it. The entries are written to a file in the state
+directory, outside the package directory so they never end up in the tree, and the package build
+adds them to the top of the spec's `%changelog`.
+*/
+
+use super::error::{self, Result};
+use duct::cmd;
+use snafu::{ensure, ResultExt};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Separates the fields of each commit in the output of `git log`.
+const FIELD_SEPARATOR: char = '\x1f';
+
+/// The fields of each commit: its hash, commit time, commit date, author and subject.
+const LOG_FORMAT: &str = "--format=%H%x1f%ct%x1f%cd%x1f%an%x1f%ae%x1f%s";
+
+/// Dates in the form RPM expects, such as `Tue Mar 05 2024`.
+const DATE_FORMAT: &str = "--date=format-local:%a %b %d %Y";
+
+/// A commit, with what its changelog entry needs.
+#[derive(Debug, Clone, PartialEq)]
+struct Commit {
+    hash: String,
+    timestamp: i64,
+    date: String,
+    author: String,
+    email: String,
+    subject: String,
+}
+
+/// The file in the state directory that holds the generated entries of `package`.
+pub(crate) fn path(state_dir: &Path, package: &str) -> PathBuf {
+    state_dir
+        .join("changelogs")
+        .join(format!("{package}.changelog"))
+}
+
+/// Generates the changelog entries of the package in `package_dir`, which is in the git repository
+/// at `root`. Returns `None` if `root` isn't in a git repository, or if no commits touched the
+/// package.
+pub(crate) fn package_changelog(
+    root: &Path,
+    package_dir: &Path,
+    package: &str,
+) -> Result<Option<String>> {
+    let in_repo = cmd!("git", "rev-parse", "--is-inside-work-tree")
+        .dir(root)
+        .stdout_null()
+        .stderr_null()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+    if !in_repo.status.success() {
+        return Ok(None);
+    }
+
+    let dir = package_dir.strip_prefix(root).unwrap_or(package_dir);
+    let spec = dir.join(format!("{package}.spec"));
+    let bump = git(
+        root,
+        &[
+            "log",
+            "-1",
+            "--format=%H",
+            "-G",
+            "^Version:",
+            "--",
+            &spec.display().to_string(),
+        ],
+    )?;
+    let log = git(
+        root,
+        &[
+            "log",
+            LOG_FORMAT,
+            DATE_FORMAT,
+            "--",
+            &dir.display().to_string(),
+        ],
+    )?;
+
+    let commits = commits_since(&log, bump.trim());
+    if commits.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(render(commits)))
+}
+
+/// Runs git in `root` with dates in UTC, and returns what it printed.
+fn git(root: &Path, args: &[&str]) -> Result<String> {
+    let output = cmd("git", args.iter().copied())
+        .dir(root)
+        .env("TZ", "UTC")
+        .env("LC_ALL", "C")
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+    ensure!(
+        output.status.success(),
+        error::GitSnafu {
+            command: format!("git {}", args.join(" ")),
+            output: String::from_utf8_lossy(&output.stderr),
+        }
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses the output of `git log`, newest first, and keeps the commits up to and including `bump`.
+/// Every commit is kept if `bump` isn't among them.
+fn commits_since(log: &str, bump: &str) -> Vec<Commit> {
+    let mut commits = Vec::new();
+    for line in log.lines() {
+        let fields = line.split(FIELD_SEPARATOR).collect::<Vec<_>>();
+        let [hash, timestamp, date, author, email, subject] = fields[..] else {
+            continue;
+        };
+        commits.push(Commit {
+            hash: hash.to_string(),
+            timestamp: timestamp.parse().unwrap_or_default(),
+            date: date.to_string(),
+            author: author.to_string(),
+            email: email.to_string(),
+            subject: subject.to_string(),
+        });
+        if hash == bump {
+            break;
+        }
+    }
+    commits
+}
+
+/// Renders commits as `%changelog` entries. RPM wants the entries newest first by date, which the
+/// order of a rebased or merged history doesn't guarantee, so they're sorted by commit time.
+fn render(mut commits: Vec<Commit>) -> String {
+    commits.sort_by_key(|commit| std::cmp::Reverse(commit.timestamp));
+    let mut s = String::new();
+    for commit in commits {
+        let short = commit.hash.get(..8).unwrap_or(&commit.hash);
+        // Macros are expanded in the changelog, so a literal '%' has to be doubled.
+        let _ = writeln!(
+            s,
+            "* {} {} <{}>\n- {} ({})\n",
+            commit.date,
+            commit.author.replace('%', "%%"),
+            commit.email.replace('%', "%%"),
+            commit.subject.replace('%', "%%"),
+            short
+        );
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LOG: &str = "\
+c0ffee0123\x1f1710000000\x1fSat Mar 09 2024\x1fSomeone\x1fsomeone@example.com\x1fhello: fix 100% CPU
+beef012345\x1f1709600000\x1fTue Mar 05 2024\x1fOther\x1fother@example.com\x1fhello: update to 1.1
+d00d012345\x1f1704100000\x1fMon Jan 01 2024\x1fSomeone\x1fsomeone@example.com\x1fhello: add package
+";
+
+    #[test]
+    fn test_commits_since() {
+        let commits = commits_since(LOG, "beef012345");
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[1].subject, "hello: update to 1.1");
+        assert_eq!(commits_since(LOG, "").len(), 3);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut commits = commits_since(LOG, "beef012345");
+        commits.reverse();
+        assert_eq!(
+            render(commits),
+            "* Sat Mar 09 2024 Someone <someone@example.com>\n- hello: fix 100%% CPU (c0ffee01)\n\n\
+             * Tue Mar 05 2024 Other <other@example.com>\n- hello: update to 1.1 (beef0123)\n\n"
+        );
+    }
+}
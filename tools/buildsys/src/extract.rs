@@ -0,0 +1,239 @@
+/*!
+Specs that build from an archive usually start by unpacking it in `%prep`, and often have to
+rename or flatten its top-level directory to get the layout they want. For an external file that
+is an archive, `extract = true` in `package.metadata.build-package.external-files[]` has buildsys
+unpack it instead, after it is fetched and patched.
+
+The archive is unpacked in the SDK, so every compression the SDK's `tar` knows works, with
+`strip-components` leading path components removed from each entry. The result is written to a
+directory next to the archive, named by `rename`, or else after the archive without its extension.
+The package build copies the directory into `SOURCES`, so the spec finds it at
+`%{_sourcedir}/<name>`.
+
+A hidden stamp file next to the directory records what it was extracted from, so that the archive
+is only unpacked again when it changes.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use crate::patch;
+use buildsys::manifest;
+use duct::cmd;
+use sha2::{Digest, Sha512};
+use snafu::{ensure, ResultExt};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tracing::info;
+
+// Unpacks the archive and writes its contents to stdout as an uncompressed tar, with the entries
+// sorted and their owners fixed.
+const EXTRACT_SCRIPT: &str = r#"
+set -eu -o pipefail
+work="$(mktemp -d)"
+tar -xf "/package/${ARCHIVE}" --strip-components="${STRIP}" -C "${work}"
+tar --sort=name --owner=0 --group=0 --numeric-owner -cf - -C "${work}" .
+"#;
+
+/// Archive suffixes that are left out of the default name of the extracted directory.
+const ARCHIVE_SUFFIXES: [&str; 9] = [
+    ".tar.gz", ".tar.xz", ".tar.bz2", ".tar.zst", ".tgz", ".txz", ".tbz2", ".tzst", ".tar",
+];
+
+pub(crate) struct SourceExtract {
+    /// The archive to extract, in the package directory.
+    archive: PathBuf,
+    /// The directory to extract it to, in the package directory.
+    name: PathBuf,
+    strip: u32,
+}
+
+impl SourceExtract {
+    /// How `external_file` is extracted, if it asks to be.
+    pub(crate) fn new(external_file: &manifest::ExternalFile) -> Result<Option<Self>> {
+        if !external_file.extract.unwrap_or(false) {
+            ensure!(
+                external_file.strip_components.is_none() && external_file.rename.is_none(),
+                error::NotExtractedSnafu {
+                    url: &external_file.url
+                }
+            );
+            return Ok(None);
+        }
+
+        // Patched archives are extracted instead of the originals, so the patches show up in the
+        // extracted directory.
+        let mut archive = patch::archive_name(external_file).context(error::PatchSnafu)?;
+        if external_file
+            .patches
+            .as_ref()
+            .is_some_and(|p| !p.is_empty())
+        {
+            archive = patch::patched_name(&archive);
+        }
+        let name = match &external_file.rename {
+            Some(rename) => rename.clone(),
+            None => extracted_name(&archive),
+        };
+        ensure!(
+            is_single_name(&name) && name != archive,
+            error::BadDestinationSnafu { path: &name }
+        );
+        Ok(Some(Self {
+            archive,
+            name,
+            strip: external_file.strip_components.unwrap_or(0),
+        }))
+    }
+
+    /// The name of the extracted directory.
+    pub(crate) fn name(&self) -> &Path {
+        &self.name
+    }
+
+    /// Extracts the archive, which has been fetched to `package_dir`, unless it was already
+    /// extracted.
+    pub(crate) fn apply(&self, package_dir: &Path, sdk: &str) -> Result<()> {
+        let (archive, name, strip) = (&self.archive, &self.name, self.strip);
+        let archive_path = package_dir.join(archive);
+        ensure!(
+            archive_path.is_file(),
+            error::MissingArchiveSnafu {
+                path: &archive_path
+            }
+        );
+
+        let output = package_dir.join(&name);
+        let stamp_path = package_dir.join(format!(".{}.extracted", name.display()));
+        let stamp = format!("{} {}", digest(&archive_path)?, strip);
+        if output.is_dir() && fs::read_to_string(&stamp_path).ok().as_deref() == Some(&stamp) {
+            return Ok(());
+        }
+
+        info!("Extracting {} to {}", archive.display(), name.display());
+        let tar_path = package_dir.join(format!(".{}.tar", name.display()));
+        let result = cmd(
+            "docker",
+            [
+                "run".into(),
+                "--rm".into(),
+                "--network".into(),
+                "none".into(),
+                "-v".into(),
+                format!("{}:/package:ro", package_dir.display()),
+                "--env".into(),
+                format!("ARCHIVE={}", archive.display()),
+                "--env".into(),
+                format!("STRIP={strip}"),
+                sdk.to_string(),
+                "bash".into(),
+                "-c".into(),
+                EXTRACT_SCRIPT.into(),
+            ],
+        )
+        .stdout_path(&tar_path)
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+        if !result.status.success() {
+            let _ = fs::remove_file(&tar_path);
+            return error::ExtractSnafu {
+                archive: archive.clone(),
+                output: String::from_utf8_lossy(&result.stderr),
+            }
+            .fail();
+        }
+
+        // Unpack next to the old directory, and only replace it once that worked.
+        let tmp = package_dir.join(format!(".{}.tmp", name.display()));
+        remove_dir(&tmp)?;
+        fs::create_dir(&tmp).context(error::CreateDirSnafu { path: &tmp })?;
+        let result = cmd!("tar", "-xf", &tar_path, "-C", &tmp)
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .context(error::CommandStartSnafu)?;
+        let _ = fs::remove_file(&tar_path);
+        if !result.status.success() {
+            let _ = fs::remove_dir_all(&tmp);
+            return error::ExtractSnafu {
+                archive: archive.clone(),
+                output: String::from_utf8_lossy(&result.stderr),
+            }
+            .fail();
+        }
+        remove_dir(&output)?;
+        fs::rename(&tmp, &output).context(error::RenameSnafu { path: &tmp })?;
+        fs::write(&stamp_path, stamp).context(error::WriteStampSnafu { path: &stamp_path })
+    }
+}
+
+/// The default name of the directory an archive is extracted to, such as `foo-1.0` for
+/// `foo-1.0.tar.gz`.
+fn extracted_name(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    ARCHIVE_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| archive.file_stem().map(PathBuf::from).unwrap_or_default())
+}
+
+/// Extracted directories go directly in the package directory, where the package build copies
+/// them from, and are passed to it in a list separated by spaces.
+fn is_single_name(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !path.to_string_lossy().contains(char::is_whitespace)
+}
+
+fn digest(path: &Path) -> Result<String> {
+    let mut f = File::open(path).context(error::ReadArchiveSnafu { path })?;
+    let mut d = Sha512::new();
+    io::copy(&mut f, &mut d).context(error::ReadArchiveSnafu { path })?;
+    Ok(hex::encode(d.finalize()))
+}
+
+fn remove_dir(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context(error::RemoveSnafu { path }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extracted_names() {
+        assert_eq!(
+            extracted_name(Path::new("foo-1.0.tar.gz")),
+            PathBuf::from("foo-1.0")
+        );
+        assert_eq!(
+            extracted_name(Path::new("patched-foo-1.0.tgz")),
+            PathBuf::from("patched-foo-1.0")
+        );
+        assert_eq!(
+            extracted_name(Path::new("foo-1.0.cpio")),
+            PathBuf::from("foo-1.0")
+        );
+    }
+
+    #[test]
+    fn destination_names() {
+        assert!(is_single_name(Path::new("foo")));
+        assert!(!is_single_name(Path::new("foo/bar")));
+        assert!(!is_single_name(Path::new("../foo")));
+        assert!(!is_single_name(Path::new("/foo")));
+        assert!(!is_single_name(Path::new("foo bar")));
+    }
+}
@@ -0,0 +1,59 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display(
+        "Extracted directory '{}' must be a single name in the package directory",
+        path.display()
+    ))]
+    BadDestination { path: PathBuf },
+
+    #[snafu(display("Failed to start command: {}", source))]
+    CommandStart { source: std::io::Error },
+
+    #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
+    CreateDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to extract '{}': {}", archive.display(), output))]
+    Extract { archive: PathBuf, output: String },
+
+    #[snafu(display("'{}' was not fetched, so it can't be extracted", path.display()))]
+    MissingArchive { path: PathBuf },
+
+    #[snafu(display("'strip-components' and 'rename' need 'extract = true' for '{}'", url))]
+    NotExtracted { url: String },
+
+    #[snafu(display("{}", source))]
+    Patch { source: crate::patch::error::Error },
+
+    #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+    ReadArchive {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to remove '{}': {}", path.display(), source))]
+    Remove {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to rename '{}': {}", path.display(), source))]
+    Rename {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+    WriteStamp {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
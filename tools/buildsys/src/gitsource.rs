@@ -0,0 +1,150 @@
+/*!
+Some upstream projects don't publish release tarballs, or publish tarballs that don't match the
+tagged source. For those, an entry in `package.metadata.build-package.external-files` can name a
+git repository and the commit to build from instead of a file to download.
+
+The repository is shallow-cloned inside the SDK container, so the host only needs docker. The
+checked-out commit is compared to the one in the manifest, and `git archive` is used to produce a
+tarball whose contents only depend on the commit. That tarball is then treated like any other
+external file: it's verified against `sha512` and can be served from the lookaside cache.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use buildsys::manifest;
+use duct::cmd;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+// Fetches a single commit, either directly or through a tag, and writes a gzipped tarball of it
+// to stdout. `gzip -n` leaves out the name and timestamp so the output is reproducible.
+const GIT_ARCHIVE_SCRIPT: &str = r#"
+set -eu -o pipefail
+repo="$(mktemp -d)"
+cd "${repo}"
+git init -q
+git remote add origin "${GIT_URL}"
+git fetch -q --depth 1 origin "${GIT_REF}"
+git -c advice.detachedHead=false checkout -q FETCH_HEAD
+actual="$(git rev-parse HEAD)"
+if [ "${actual}" != "${GIT_COMMIT}" ]; then
+    echo "expected commit ${GIT_COMMIT} but ${GIT_REF} is ${actual}" >&2
+    exit 1
+fi
+git -c tar.umask=0022 archive --format=tar --prefix="${GIT_PREFIX}/" HEAD | gzip -n -9
+"#;
+
+pub(crate) struct GitSource;
+
+impl GitSource {
+    /// Clones the repository at `url`, which is the URL of `external_file` or one of its mirrors,
+    /// in the SDK and writes an archive of the pinned commit to `path`. Returns the size of the
+    /// archive.
+    pub(crate) fn archive(
+        external_file: &manifest::ExternalFile,
+        url: &str,
+        sdk: &str,
+        path: &Path,
+    ) -> Result<u64> {
+        let commit = external_file
+            .git_commit
+            .as_deref()
+            .context(error::MissingCommitSnafu { url })?;
+        ensure!(
+            is_commit_hash(commit),
+            error::BadCommitSnafu { url, commit }
+        );
+        let name = external_file
+            .path
+            .as_ref()
+            .context(error::MissingPathSnafu { url })?;
+        let git_ref = match &external_file.git_tag {
+            Some(tag) => format!("refs/tags/{tag}"),
+            None => commit.to_string(),
+        };
+
+        info!(
+            "Cloning {} at {} to create {}",
+            url,
+            git_ref,
+            name.display()
+        );
+        let output = cmd!(
+            "docker",
+            "run",
+            "--rm",
+            "--env",
+            format!("GIT_URL={url}"),
+            "--env",
+            format!("GIT_REF={git_ref}"),
+            "--env",
+            format!("GIT_COMMIT={commit}"),
+            "--env",
+            format!("GIT_PREFIX={}", archive_prefix(name)),
+            sdk,
+            "bash",
+            "-c",
+            GIT_ARCHIVE_SCRIPT
+        )
+        .stdout_path(path)
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+
+        if !output.status.success() {
+            // Don't leave a partial archive behind.
+            let _ = fs::remove_file(path);
+            return error::CloneSnafu {
+                url,
+                git_ref,
+                output: String::from_utf8_lossy(&output.stderr),
+            }
+            .fail();
+        }
+
+        Ok(fs::metadata(path)
+            .context(error::ArchiveReadSnafu { path })?
+            .len())
+    }
+}
+
+/// Git commits are named by a SHA-1 or SHA-256 hash. Requiring the full hash means the manifest
+/// pins exactly one commit.
+fn is_commit_hash(commit: &str) -> bool {
+    matches!(commit.len(), 40 | 64) && commit.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The top-level directory in the archive, which is the file name without its tar extension, so
+/// that `foo-1.0.tar.gz` unpacks into `foo-1.0/` like most release tarballs.
+fn archive_prefix(name: &Path) -> String {
+    let name = name.to_string_lossy();
+    [".tar.gz", ".tgz", ".tar"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(&name)
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commit_hashes() {
+        assert!(is_commit_hash("0123456789abcdef0123456789abcdef01234567"));
+        assert!(!is_commit_hash("0123456"));
+        assert!(!is_commit_hash("v1.0.0"));
+        assert!(!is_commit_hash("0123456789abcdef0123456789abcdef0123456g"));
+    }
+
+    #[test]
+    fn prefix_from_file_name() {
+        assert_eq!(archive_prefix(Path::new("foo-1.0.tar.gz")), "foo-1.0");
+        assert_eq!(archive_prefix(Path::new("foo-1.0.tgz")), "foo-1.0");
+        assert_eq!(archive_prefix(Path::new("foo-1.0")), "foo-1.0");
+    }
+}
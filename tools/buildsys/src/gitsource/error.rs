@@ -0,0 +1,37 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to read archive '{}': {}", path.display(), source))]
+    ArchiveRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "Bad git-commit '{}' for '{}', expected a full commit hash",
+        commit,
+        url
+    ))]
+    BadCommit { url: String, commit: String },
+
+    #[snafu(display("Failed to clone '{}' at '{}': {}", url, git_ref, output))]
+    Clone {
+        url: String,
+        git_ref: String,
+        output: String,
+    },
+
+    #[snafu(display("Failed to start command: {}", source))]
+    CommandStart { source: std::io::Error },
+
+    #[snafu(display("Git source '{}' has no git-commit", url))]
+    MissingCommit { url: String },
+
+    #[snafu(display("Git source '{}' must set 'path' to name the archive", url))]
+    MissingPath { url: String },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -17,7 +17,9 @@ when the docker-go script is invoked.
 
 pub(crate) mod error;
 
-use buildsys::manifest;
+use crate::args::ProjectSecret;
+use crate::rerun;
+use buildsys::{manifest, redact};
 use duct::cmd;
 use error::Result;
 use filetime::{set_file_mtime, FileTime};
@@ -26,6 +28,7 @@ use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
+use tracing::debug;
 
 pub(crate) struct GoMod;
 
@@ -72,13 +75,31 @@ touch -r __LOCAL_FILE_NAME__ __OUTPUT__
 "#;
 
 impl GoMod {
+    /// The path of the bundle that vendoring the Go modules of an external file makes, relative to
+    /// the package's directory.
+    pub(crate) fn bundle_path(external_file: &manifest::ExternalFile) -> Result<PathBuf> {
+        if let Some(path) = &external_file.bundle_output_path {
+            return Ok(path.clone());
+        }
+        // Use a default "bundled-{name-of-file}" if no output path was provided
+        let url_file_name = extract_file_name(&external_file.url)?;
+        let local_file_name = external_file.path.as_ref().unwrap_or(&url_file_name);
+        Ok(PathBuf::from(format!(
+            "bundled-{}",
+            local_file_name.to_string_lossy()
+        )))
+    }
+
+    /// Vendors the Go modules of an external file into a bundle, and returns the bundle's path
+    /// relative to `package_dir`.
     pub(crate) fn vendor(
         root_dir: &Path,
         package_dir: &Path,
         external_file: &manifest::ExternalFile,
         sdk: &str,
+        secrets: &[ProjectSecret],
         mtime: FileTime,
-    ) -> Result<()> {
+    ) -> Result<PathBuf> {
         let url_file_name = extract_file_name(&external_file.url)?;
         let local_file_name = &external_file.path.as_ref().unwrap_or(&url_file_name);
         ensure!(
@@ -101,22 +122,14 @@ impl GoMod {
             .as_ref()
             .unwrap_or(&default_empty_path);
 
-        // Use a default "bundle-{name-of-file}" if no output path was provided
-        let default_output_path =
-            PathBuf::from(format!("bundled-{}", local_file_name.to_string_lossy()));
-        let output_path_arg = external_file
-            .bundle_output_path
-            .as_ref()
-            .unwrap_or(&default_output_path);
-        println!(
-            "cargo:rerun-if-changed={}",
-            output_path_arg.to_string_lossy()
-        );
+        let output_path_arg = &Self::bundle_path(external_file)?;
+        rerun::file(output_path_arg);
 
         let args = DockerGoArgs {
             module_path: package_dir,
             sdk_image: sdk.to_string(),
             go_mod_cache: &root_dir.join(".gomodcache"),
+            secrets,
             command: format!("./{}", GO_MOD_DOCKER_SCRIPT_NAME),
         };
 
@@ -147,13 +160,11 @@ impl GoMod {
         let res = docker_go(&args);
         fs::remove_file(&script_path).context(error::RemoveFileSnafu { path: &script_path })?;
 
-        if res.is_ok() {
-            set_file_mtime(output_path_arg, mtime).context(error::SetMtimeSnafu {
-                path: output_path_arg,
-            })?;
-        }
-
-        res
+        res?;
+        set_file_mtime(output_path_arg, mtime).context(error::SetMtimeSnafu {
+            path: output_path_arg,
+        })?;
+        Ok(output_path_arg.clone())
     }
 }
 
@@ -171,12 +182,18 @@ struct DockerGoArgs<'a> {
     module_path: &'a Path,
     sdk_image: String,
     go_mod_cache: &'a Path,
+    secrets: &'a [ProjectSecret],
     command: String,
 }
 
 /// Run `docker-go` with the specified arguments.
 fn docker_go(dg_args: &DockerGoArgs) -> Result<()> {
-    let args = vec![
+    let secrets = dg_args
+        .secrets
+        .iter()
+        .map(|secret| secret.docker_spec())
+        .collect::<Vec<_>>();
+    let mut args = vec![
         "--module-path",
         dg_args
             .module_path
@@ -189,15 +206,19 @@ fn docker_go(dg_args: &DockerGoArgs) -> Result<()> {
             .go_mod_cache
             .to_str()
             .context(error::InputFileSnafu)?,
-        "--command",
-        &dg_args.command,
     ];
-    let arg_string = args.join(" ");
+    // Secrets let private Go modules be fetched, for example with a `netrc` secret. They must
+    // come before the command, which takes up the rest of the arguments.
+    for secret in &secrets {
+        args.extend(["--secret", secret.as_str()]);
+    }
+    args.extend(["--command", &dg_args.command]);
+    let arg_string = redact::text(&args.join(" "));
     let twoliter_tools_dir = env::var("TWOLITER_TOOLS_DIR").context(error::EnvironmentSnafu {
         var: "TWOLITER_TOOLS_DIR",
     })?;
     let program = PathBuf::from(twoliter_tools_dir).join("docker-go");
-    println!("program: {}", program.to_string_lossy());
+    debug!("program: {}", program.to_string_lossy());
     let output = cmd(program, args)
         .stderr_to_stdout()
         .stdout_capture()
@@ -205,7 +226,7 @@ fn docker_go(dg_args: &DockerGoArgs) -> Result<()> {
         .run()
         .context(error::CommandStartSnafu)?;
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stdout = redact::text(&String::from_utf8_lossy(&output.stdout));
     println!("{}", &stdout);
     ensure!(
         output.status.success(),
@@ -0,0 +1,527 @@
+/*!
+Projects can run their own commands before and after package builds, variant builds and publishes,
+such as a compliance scanner or an upload of the artifacts. Hooks are declared in the `hooks` table
+of Twoliter.toml:
+
+```toml
+[hooks.compliance-scan]
+stage = "post-variant-build"
+run-in = "sdk"
+command = ["tools/scan.sh", "--report", "build/reports/{name}-{arch}.json"]
+inputs = ["tools/scan.sh", "tools/policy"]
+outputs = ["build/reports/{name}-{arch}.json"]
+```
+
+The stages are `pre-` and `post-` with `package-build`, `variant-build` and `publish`. Buildsys
+runs the hooks of builds, which twoliter passes to it as JSON, the package build hooks in
+`BUILDSYS_PACKAGE_HOOKS` and the variant build hooks in `BUILDSYS_VARIANT_HOOKS`, so that changing
+the hooks of one kind of build doesn't rebuild the other. Twoliter runs the publish hooks around
+`twoliter publish kit`, the publishing tasks of `twoliter make`, such as `ami` and `repo`, and the
+publish stages of `twoliter release`.
+
+Commands run in the project directory, on the host (the default) or in the SDK with the project
+mounted at the same path. `{name}` and `{arch}` in the command and outputs are replaced with what
+is built or published and its architecture, which the hook also sees as `TWOLITER_HOOK_NAME` and
+`TWOLITER_HOOK_ARCH`. `TWOLITER_HOOK_KIND` tells what it is, such as `package`, `kit`, or the
+publishing task, like `ami`.
+
+`inputs` are the files and directories that the hook reads, relative to the project, and `outputs`
+are the files that it must create. Builds run again when a hook's inputs change. After a hook
+succeeds, the digest of the hook, its inputs and the build it ran for is stamped in
+`build/state/hooks`, and the hook is skipped while the stamp matches and its outputs exist, so that
+a build that runs again with nothing new for the hook, or that comes from a cache, doesn't repeat
+it.
+*/
+
+pub mod error;
+use error::Result;
+
+use crate::redact;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ensure, ResultExt};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::str::FromStr;
+use tracing::{info, warn};
+use walkdir::WalkDir;
+
+/// The variable that passes a project's package build hooks to buildsys.
+pub const PACKAGE_HOOKS_VAR: &str = "BUILDSYS_PACKAGE_HOOKS";
+
+/// The variable that passes a project's variant build hooks to buildsys.
+pub const VARIANT_HOOKS_VAR: &str = "BUILDSYS_VARIANT_HOOKS";
+
+/// Where the stamps of hooks that succeeded are kept, relative to the project.
+const HOOK_STAMPS_DIRECTORY: &str = "build/state/hooks";
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    PrePackageBuild,
+    PostPackageBuild,
+    PreVariantBuild,
+    PostVariantBuild,
+    PrePublish,
+    PostPublish,
+}
+
+impl Stage {
+    /// The stages of package builds, whose hooks buildsys runs.
+    pub const PACKAGE_BUILD: [Stage; 2] = [Stage::PrePackageBuild, Stage::PostPackageBuild];
+
+    /// The stages of variant builds, whose hooks buildsys runs.
+    pub const VARIANT_BUILD: [Stage; 2] = [Stage::PreVariantBuild, Stage::PostVariantBuild];
+
+    /// The stages of publishes, whose hooks twoliter runs.
+    pub const PUBLISH: [Stage; 2] = [Stage::PrePublish, Stage::PostPublish];
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::PrePackageBuild => "pre-package-build",
+            Stage::PostPackageBuild => "post-package-build",
+            Stage::PreVariantBuild => "pre-variant-build",
+            Stage::PostVariantBuild => "post-variant-build",
+            Stage::PrePublish => "pre-publish",
+            Stage::PostPublish => "post-publish",
+        })
+    }
+}
+
+/// Where a hook's command runs.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunIn {
+    #[default]
+    Host,
+    Sdk,
+}
+
+/// A command that a project runs at a stage of its builds or publishes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Hook {
+    pub stage: Stage,
+    #[serde(default)]
+    pub run_in: RunIn,
+    /// The program and its arguments.
+    pub command: Vec<String>,
+    /// Files and directories that the hook reads, relative to the project.
+    #[serde(default)]
+    pub inputs: Vec<PathBuf>,
+    /// Files that the hook creates, relative to the project.
+    #[serde(default)]
+    pub outputs: Vec<PathBuf>,
+}
+
+/// What a hook runs for.
+#[derive(Debug, Clone)]
+pub struct Target<'a> {
+    /// The project directory.
+    pub root: &'a Path,
+    /// The kind of thing that is built or published, such as `package`.
+    pub kind: &'a str,
+    /// The name of the package, variant or kit.
+    pub name: &'a str,
+    pub arch: Option<&'a str>,
+    /// The SDK image, for hooks that run in it.
+    pub sdk: &'a str,
+    /// Anything else that decides whether a hook has to run again, such as the digest of the
+    /// inputs of the build that it runs after.
+    pub key: Option<String>,
+    /// More variables for the hook, such as the directory of a build's outputs.
+    pub envs: Vec<(&'static str, String)>,
+}
+
+impl Target<'_> {
+    /// Replaces `{name}` and `{arch}` in a part of a hook.
+    fn expand(&self, s: &str) -> String {
+        s.replace("{name}", self.name)
+            .replace("{arch}", self.arch.unwrap_or_default())
+    }
+}
+
+impl fmt::Display for Target<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} '{}'", self.kind, self.name)?;
+        if let Some(arch) = self.arch {
+            write!(f, " for {arch}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The hooks of a project, by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Hooks(BTreeMap<String, Hook>);
+
+impl Hooks {
+    pub fn new(hooks: BTreeMap<String, Hook>) -> Self {
+        Self(hooks)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The hooks that run at any of `stages`.
+    pub fn at(&self, stages: &[Stage]) -> Self {
+        Self(
+            self.0
+                .iter()
+                .filter(|(_, hook)| stages.contains(&hook.stage))
+                .map(|(name, hook)| (name.clone(), hook.clone()))
+                .collect(),
+        )
+    }
+
+    /// The hooks in the form that buildsys reads from its variables.
+    pub fn to_json(&self) -> String {
+        // Keys are strings and values have no maps, so this can't fail.
+        serde_json::to_string(&self.0).unwrap_or_default()
+    }
+
+    /// The inputs of the hooks that run at any of `stages`, relative to the project.
+    pub fn inputs(&self, stages: &[Stage]) -> Vec<&Path> {
+        self.0
+            .values()
+            .filter(|hook| stages.contains(&hook.stage))
+            .flat_map(|hook| hook.inputs.iter().map(PathBuf::as_path))
+            .collect()
+    }
+
+    /// Runs each hook of `stage` for `target`, in order of their names, unless its stamp shows
+    /// that it already ran for the same inputs and its outputs are still there.
+    pub fn run(&self, stage: Stage, target: &Target) -> Result<()> {
+        for (name, hook) in self.0.iter().filter(|(_, hook)| hook.stage == stage) {
+            run_hook(name, hook, target)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Hooks {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let hooks: BTreeMap<String, Hook> = serde_json::from_str(s)
+            .map_err(|e| format!("'{s}' is not a JSON map of hooks: {e}"))?;
+        match hooks.iter().find(|(_, hook)| hook.command.is_empty()) {
+            Some((name, _)) => Err(format!("hook '{name}' has an empty command")),
+            None => Ok(Self(hooks)),
+        }
+    }
+}
+
+impl fmt::Display for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hooks = self
+            .0
+            .iter()
+            .map(|(name, hook)| format!("{name} ({})", hook.stage))
+            .collect::<Vec<_>>();
+        f.write_str(&hooks.join(", "))
+    }
+}
+
+fn run_hook(name: &str, hook: &Hook, target: &Target) -> Result<()> {
+    let stage = hook.stage;
+    let context = || (name.to_string(), stage, target.to_string());
+    let outputs = hook
+        .outputs
+        .iter()
+        .map(|output| target.root.join(target.expand(&output.to_string_lossy())))
+        .collect::<Vec<_>>();
+
+    let arch = target
+        .arch
+        .map(|arch| format!("-{arch}"))
+        .unwrap_or_default();
+    let stamp_path = target
+        .root
+        .join(HOOK_STAMPS_DIRECTORY)
+        .join(format!("{name}-{}-{}{arch}", target.kind, target.name));
+    let stamp = stamp(name, hook, target)?;
+    let stamped = fs::read_to_string(&stamp_path).is_ok_and(|s| s == stamp);
+    if stamped && outputs.iter().all(|output| output.exists()) {
+        info!("Skipping hook '{name}' for {target}, since nothing it depends on changed");
+        return Ok(());
+    }
+    let _ = fs::remove_file(&stamp_path);
+
+    info!("Running hook '{name}' ({stage}) for {target}");
+    let mut command = command(hook, target, name);
+    let output = command.output().context(error::SpawnSnafu {
+        hook: name,
+        stage,
+        target: target.to_string(),
+    })?;
+    log_output(name, &output);
+    if !output.status.success() {
+        let (hook, stage, target) = context();
+        return error::FailedSnafu {
+            hook,
+            stage,
+            target,
+            status: output.status.to_string(),
+            stderr: tail(&output.stderr),
+        }
+        .fail();
+    }
+    for output in &outputs {
+        let (hook, stage, target) = context();
+        ensure!(
+            output.exists(),
+            error::OutputMissingSnafu {
+                hook,
+                stage,
+                target,
+                path: output,
+            }
+        );
+    }
+
+    if let Err(e) = stamp_path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&stamp_path, stamp))
+    {
+        warn!("Unable to record that hook '{name}' ran for {target}: {e}");
+    }
+    Ok(())
+}
+
+/// The command for a hook, with its variables, in the project directory.
+fn command(hook: &Hook, target: &Target, name: &str) -> Command {
+    let args = hook
+        .command
+        .iter()
+        .map(|arg| target.expand(arg))
+        .collect::<Vec<_>>();
+    let mut envs = vec![
+        ("TWOLITER_HOOK", name.to_string()),
+        ("TWOLITER_HOOK_STAGE", hook.stage.to_string()),
+        ("TWOLITER_HOOK_KIND", target.kind.to_string()),
+        ("TWOLITER_HOOK_NAME", target.name.to_string()),
+        (
+            "TWOLITER_HOOK_ARCH",
+            target.arch.unwrap_or_default().to_string(),
+        ),
+        ("TWOLITER_PROJECT_DIR", target.root.display().to_string()),
+    ];
+    envs.extend(target.envs.iter().cloned());
+
+    let root = target.root.display().to_string();
+    match hook.run_in {
+        RunIn::Host => {
+            let mut command = Command::new(&args[0]);
+            command.args(&args[1..]).current_dir(target.root).envs(envs);
+            command
+        }
+        RunIn::Sdk => {
+            // Run as the owner of the project, so that the outputs belong to them.
+            let user = fs::metadata(target.root)
+                .map(|m| format!("{}:{}", m.uid(), m.gid()))
+                .unwrap_or_else(|_| "0:0".to_string());
+            let mut command = Command::new("docker");
+            command
+                .args(["run", "--rm", "--network", "host", "--user", user.as_str()])
+                .args(["--volume", format!("{root}:{root}").as_str()])
+                .args(["--workdir", root.as_str()]);
+            for (key, value) in envs {
+                command.args(["--env", format!("{key}={value}").as_str()]);
+            }
+            command.arg(target.sdk).args(args);
+            command
+        }
+    }
+}
+
+/// The digest of everything that decides whether a hook has to run again: the hook itself, the
+/// contents of its inputs, and what it runs for.
+fn stamp(name: &str, hook: &Hook, target: &Target) -> Result<String> {
+    let mut d = Sha256::new();
+    let mut add = |value: &[u8]| {
+        d.update(value);
+        d.update([0]);
+    };
+    add(name.as_bytes());
+    add(&serde_json::to_vec(hook).unwrap_or_default());
+    add(target.name.as_bytes());
+    add(target.arch.unwrap_or_default().as_bytes());
+    add(target.key.as_deref().unwrap_or_default().as_bytes());
+    for input in &hook.inputs {
+        let path = target.root.join(input);
+        ensure!(
+            path.exists(),
+            error::InputMissingSnafu {
+                hook: name,
+                path: &path,
+            }
+        );
+        let entries = WalkDir::new(&path).sort_by_file_name().follow_links(true);
+        for entry in entries {
+            let entry = entry.context(error::InputReadSnafu { hook: name })?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let data = fs::read(entry.path()).context(error::InputFileSnafu {
+                hook: name,
+                path: entry.path(),
+            })?;
+            add(entry.path().to_string_lossy().as_bytes());
+            add(Sha256::digest(data).as_slice());
+        }
+    }
+    Ok(hex::encode(d.finalize()))
+}
+
+fn log_output(name: &str, output: &Output) {
+    for line in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+    {
+        info!("[{name}] {}", redact::text(line));
+    }
+}
+
+/// The last lines of a failed hook's standard error, to show with the failure.
+fn tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines = stderr.lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(10)..]
+        .iter()
+        .map(|line| format!("\n  {}", redact::text(line)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn target(root: &Path) -> Target {
+        Target {
+            root,
+            kind: "variant",
+            name: "aws-dev",
+            arch: Some("x86_64"),
+            sdk: "sdk",
+            key: Some("abc".to_string()),
+            envs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let hooks: Hooks = r#"{"scan": {"stage": "post-variant-build", "command": ["scan"]}}"#
+            .parse()
+            .unwrap();
+        assert_eq!(hooks.to_string(), "scan (post-variant-build)");
+        assert_eq!(hooks.0["scan"].run_in, RunIn::Host);
+        assert!("".parse::<Hooks>().unwrap().is_empty());
+        assert!(r#"{"scan": {"stage": "later", "command": ["scan"]}}"#
+            .parse::<Hooks>()
+            .is_err());
+        assert!(r#"{"scan": {"stage": "pre-publish", "command": []}}"#
+            .parse::<Hooks>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_at() {
+        let hook = |stage| Hook {
+            stage,
+            run_in: RunIn::Host,
+            command: vec!["true".to_string()],
+            inputs: vec![PathBuf::from("tools/scan.sh")],
+            outputs: Vec::new(),
+        };
+        let hooks = Hooks::new(BTreeMap::from([
+            ("scan".to_string(), hook(Stage::PostVariantBuild)),
+            ("upload".to_string(), hook(Stage::PostPublish)),
+        ]));
+        let variant_hooks: Hooks = hooks.at(&Stage::VARIANT_BUILD).to_json().parse().unwrap();
+        assert_eq!(variant_hooks.to_string(), "scan (post-variant-build)");
+        assert!(hooks.at(&Stage::PACKAGE_BUILD).is_empty());
+        assert_eq!(
+            hooks.at(&Stage::PUBLISH).to_string(),
+            "upload (post-publish)"
+        );
+        assert_eq!(
+            hooks.inputs(&Stage::VARIANT_BUILD),
+            vec![Path::new("tools/scan.sh")]
+        );
+        assert!(hooks.inputs(&[Stage::PrePackageBuild]).is_empty());
+    }
+
+    #[test]
+    fn test_run() {
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        fs::write(root.join("policy"), "strict").unwrap();
+        let hook = Hook {
+            stage: Stage::PostVariantBuild,
+            run_in: RunIn::Host,
+            command: ["sh", "-c", "echo $TWOLITER_HOOK_ARCH >> {name}.log"]
+                .map(String::from)
+                .to_vec(),
+            inputs: vec![PathBuf::from("policy")],
+            outputs: vec![PathBuf::from("{name}.log")],
+        };
+        let hooks = Hooks::new(BTreeMap::from([("scan".to_string(), hook.clone())]));
+        let runs = || fs::read_to_string(root.join("aws-dev.log")).unwrap();
+
+        hooks.run(Stage::PostVariantBuild, &target(root)).unwrap();
+        hooks.run(Stage::PreVariantBuild, &target(root)).unwrap();
+        assert_eq!(runs(), "x86_64\n");
+        // Nothing changed, so the hook is skipped.
+        hooks.run(Stage::PostVariantBuild, &target(root)).unwrap();
+        assert_eq!(runs(), "x86_64\n");
+        // Its input changed.
+        fs::write(root.join("policy"), "lenient").unwrap();
+        hooks.run(Stage::PostVariantBuild, &target(root)).unwrap();
+        assert_eq!(runs(), "x86_64\nx86_64\n");
+
+        let failing = Hooks::new(BTreeMap::from([(
+            "scan".to_string(),
+            Hook {
+                command: ["sh", "-c", "echo bad policy >&2; exit 3"]
+                    .map(String::from)
+                    .to_vec(),
+                ..hook.clone()
+            },
+        )]));
+        let e = failing
+            .run(Stage::PostVariantBuild, &target(root))
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("Hook 'scan' (post-variant-build) failed for variant 'aws-dev'"));
+        assert!(e.contains("bad policy"));
+
+        let no_output = Hooks::new(BTreeMap::from([(
+            "scan".to_string(),
+            Hook {
+                command: vec!["true".to_string()],
+                outputs: vec![PathBuf::from("report.json")],
+                ..hook
+            },
+        )]));
+        assert!(no_output
+            .run(Stage::PostVariantBuild, &target(root))
+            .is_err());
+    }
+}
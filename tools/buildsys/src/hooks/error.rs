@@ -0,0 +1,54 @@
+use super::Stage;
+use snafu::Snafu;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub enum Error {
+    #[snafu(display("Hook '{hook}' ({stage}) failed for {target} with {status}:{stderr}"))]
+    Failed {
+        hook: String,
+        stage: Stage,
+        target: String,
+        status: String,
+        stderr: String,
+    },
+
+    #[snafu(display("Failed to read input '{}' of hook '{hook}': {source}", path.display()))]
+    InputFile {
+        hook: String,
+        path: PathBuf,
+        source: io::Error,
+    },
+
+    #[snafu(display("Input '{}' of hook '{hook}' does not exist", path.display()))]
+    InputMissing { hook: String, path: PathBuf },
+
+    #[snafu(display("Failed to walk the inputs of hook '{hook}': {source}"))]
+    InputRead {
+        hook: String,
+        source: walkdir::Error,
+    },
+
+    #[snafu(display(
+        "Hook '{hook}' ({stage}) did not create '{}' for {target}",
+        path.display()
+    ))]
+    OutputMissing {
+        hook: String,
+        stage: Stage,
+        target: String,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Failed to start hook '{hook}' ({stage}) for {target}: {source}"))]
+    Spawn {
+        hook: String,
+        stage: Stage,
+        target: String,
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
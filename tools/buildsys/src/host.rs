@@ -0,0 +1,165 @@
+/*!
+Builds of the same inputs can still differ between machines, because of the host's OS, its Docker
+daemon, or the versions of the build tools. After a variant build, a fingerprint of the host is
+added to `build-metadata.json`, so that two builds that differ can be told apart by where they were
+built, along with the buildsys settings that the build used. Image features that the build
+enabled or disabled on top of the variant's manifest are added as well, so that an image built with
+them isn't mistaken for one built from the manifest alone.
+
+Twoliter records the same details about the host that it locks a project on, and warns when a
+build's host differs from it.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use buildsys::manifest::ImageFeature;
+use buildsys::os_release::os_name;
+use duct::cmd;
+use serde::Serialize;
+use serde_json::json;
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// The metadata that the image build writes next to the images.
+const BUILD_METADATA: &str = "build-metadata.json";
+
+/// The host a build ran on. Anything that couldn't be found out is left out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct HostEnvironment {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kernel: Option<String>,
+    arch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    docker: Option<String>,
+    buildsys: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    twoliter: Option<String>,
+    /// The buildsys settings, with credentials masked.
+    env: BTreeMap<String, String>,
+}
+
+impl HostEnvironment {
+    /// Finds out what host buildsys is running on. `twoliter` is the version of twoliter that
+    /// started the build, if it was started by twoliter.
+    pub(crate) fn detect(twoliter: Option<String>, settings: Vec<(&str, String)>) -> Self {
+        Self {
+            os: os_name(fs::read_to_string("/etc/os-release").ok().as_deref()),
+            kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+                .ok()
+                .map(|s| s.trim().to_string()),
+            arch: std::env::consts::ARCH.to_string(),
+            docker: cmd!("docker", "version", "--format", "{{.Server.Version}}")
+                .stderr_null()
+                .read()
+                .ok()
+                .map(|version| version.trim().to_string())
+                .filter(|version| !version.is_empty()),
+            buildsys: env!("CARGO_PKG_VERSION").to_string(),
+            twoliter: twoliter.filter(|version| !version.is_empty()),
+            env: settings
+                .into_iter()
+                .map(|(var, value)| (var.to_string(), value))
+                .collect(),
+        }
+    }
+
+    /// Adds the host to the `build-metadata.json` of the variant build in `output_dir`. Builds
+    /// that didn't write any metadata are left alone.
+    pub(crate) fn record(&self, output_dir: &Path) -> Result<()> {
+        let host = serde_json::to_value(self).context(error::SerializeSnafu)?;
+        insert_metadata(output_dir, "host", host)
+    }
+}
+
+/// Adds the image features that the variant build in `output_dir` enabled and disabled on top of
+/// its manifest to `build-metadata.json`, as `image-feature-overrides`, if there are any.
+pub(crate) fn record_feature_overrides(
+    output_dir: &Path,
+    enabled: &[ImageFeature],
+    disabled: &[ImageFeature],
+) -> Result<()> {
+    if enabled.is_empty() && disabled.is_empty() {
+        return Ok(());
+    }
+    let names = |features: &[ImageFeature]| features.iter().map(|f| f.name()).collect::<Vec<_>>();
+    let overrides = json!({
+        "enabled": names(enabled),
+        "disabled": names(disabled),
+    });
+    insert_metadata(output_dir, "image-feature-overrides", overrides)
+}
+
+/// Sets `key` in the `build-metadata.json` in `output_dir`. Builds that didn't write any metadata
+/// are left alone.
+fn insert_metadata(output_dir: &Path, key: &str, value: serde_json::Value) -> Result<()> {
+    let path = output_dir.join(BUILD_METADATA);
+    if !path.is_file() {
+        return Ok(());
+    }
+    let data = fs::read_to_string(&path).context(error::FileReadSnafu { path: &path })?;
+    let mut metadata: serde_json::Value =
+        serde_json::from_str(&data).context(error::MetadataParseSnafu { path: &path })?;
+    metadata
+        .as_object_mut()
+        .context(error::MetadataTypeSnafu { path: &path })?
+        .insert(key.to_string(), value);
+    let json = serde_json::to_string_pretty(&metadata).context(error::SerializeSnafu)?;
+    fs::write(&path, json + "\n").context(error::FileWriteSnafu { path: &path })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_record() {
+        let dir = TempDir::new().unwrap();
+        let host = HostEnvironment {
+            arch: "x86_64".to_string(),
+            buildsys: "0.1.0".to_string(),
+            twoliter: Some("0.5.0".to_string()),
+            env: BTreeMap::from([("BUILDSYS_ARCH".to_string(), "x86_64".to_string())]),
+            ..Default::default()
+        };
+        host.record(dir.path()).unwrap();
+        assert!(!dir.path().join(BUILD_METADATA).exists());
+
+        fs::write(dir.path().join(BUILD_METADATA), r#"{"variant": "aws-dev"}"#).unwrap();
+        host.record(dir.path()).unwrap();
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.path().join(BUILD_METADATA)).unwrap()).unwrap();
+        assert_eq!(metadata["variant"], "aws-dev");
+        assert_eq!(
+            metadata["host"],
+            serde_json::json!({
+                "arch": "x86_64",
+                "buildsys": "0.1.0",
+                "twoliter": "0.5.0",
+                "env": {"BUILDSYS_ARCH": "x86_64"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_record_feature_overrides() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(BUILD_METADATA), r#"{"variant": "aws-dev"}"#).unwrap();
+        record_feature_overrides(dir.path(), &[], &[]).unwrap();
+        let metadata = || -> serde_json::Value {
+            serde_json::from_slice(&fs::read(dir.path().join(BUILD_METADATA)).unwrap()).unwrap()
+        };
+        assert!(metadata().get("image-feature-overrides").is_none());
+
+        record_feature_overrides(dir.path(), &[ImageFeature::Fips], &[]).unwrap();
+        assert_eq!(
+            metadata()["image-feature-overrides"],
+            json!({"enabled": ["fips"], "disabled": []})
+        );
+    }
+}
@@ -0,0 +1,93 @@
+/*!
+Pressing Ctrl-C during a build sends SIGINT to every process in the terminal's foreground group,
+including buildsys and the `docker` command it runs. If buildsys exited right away, the bypass
+container and the images it tagged would be left behind.
+
+Instead, buildsys only notes the signal, or SIGTERM, when it arrives. The `docker` command that is
+running is asked to stop, which cancels the build, and the build then fails as interrupted once it
+has cleaned up after itself. Commands that start after the signal, such as the cleanup, run as
+usual.
+
+Buildsys can also cancel the running command itself, such as when the disk is about to fill up. The
+command is stopped the same way, but the build fails for that reason rather than as interrupted.
+*/
+
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::Pid;
+use std::ffi::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// The exit status of a process that was stopped by SIGINT, which shells report as interrupted.
+pub(crate) const EXIT_STATUS: i32 = 130;
+
+/// How often a running command checks whether buildsys was interrupted.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Notes SIGINT and SIGTERM instead of exiting on them.
+pub(crate) fn install() {
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is safe in a signal handler.
+        if let Err(e) = unsafe { signal::sigaction(signal, &action) } {
+            println!("cargo:warning=Unable to handle {signal}: {e}");
+        }
+    }
+}
+
+/// Whether buildsys has been asked to stop.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Stops the command that is running, as if buildsys was interrupted.
+pub(crate) fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Forgets that the command was cancelled, for a command that finished before it could stop.
+pub(crate) fn uncancel() {
+    CANCELLED.store(false, Ordering::SeqCst);
+}
+
+/// Whether buildsys has cancelled the command that was running.
+pub(crate) fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Runs `f`, and sends SIGINT to the processes in `pids` if buildsys is interrupted, or cancels
+/// the command, before `f` returns. Nothing is sent if that happened before `f` started.
+pub(crate) fn forward<T>(pids: &[u32], f: impl FnOnce() -> T) -> T {
+    if interrupted() || cancelled() {
+        return f();
+    }
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            while !done.load(Ordering::SeqCst) {
+                if interrupted() || cancelled() {
+                    for &pid in pids {
+                        let _ = signal::kill(Pid::from_raw(pid as i32), Signal::SIGINT);
+                    }
+                    return;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+        let result = f();
+        done.store(true, Ordering::SeqCst);
+        result
+    })
+}
@@ -0,0 +1,344 @@
+/*!
+Kernel modules built out of tree have to be built against the headers of the exact kernel they're
+loaded into. A package that builds them names that kernel with `kernel-module` in its manifest,
+and its build defines macros that the spec uses to require the kernel's headers and the kernel
+itself at that version.
+
+A kit with such packages is a kernel module kit. Its build records the kernel it targets in
+`metadata/kernel-modules.json`, which is stored in the kit alongside its repository, and a variant
+build that uses the kit checks that it includes that kernel, at that version, before it assembles
+the image. The version is taken from the kernel's RPMs in the kits and the project's packages; if
+none are there yet, RPM still checks it when the packages are installed.
+
+A variant build also produces a kmod kit, an archive of the kernel's development sources and
+config, the toolchain, and `toolchain.env` with the settings for building modules with them, so
+that modules can be built out of tree against exactly that build. The build writes a SHA-256
+checksum of the kit next to it, in the format of `sha256sum`.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use buildsys::manifest::ManifestInfo;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Where a kit records the kernel its modules are built for, relative to the kit's repository.
+pub(crate) const KERNEL_MODULES_METADATA: &str = "metadata/kernel-modules.json";
+
+/// The kernel that a kit's modules are built for, and the packages that have them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct KernelModules {
+    pub(crate) kernel: String,
+    pub(crate) version: String,
+    pub(crate) packages: Vec<String>,
+}
+
+impl KernelModules {
+    /// Collects the kernel modules among `packages`, the packages of a kit by name and the
+    /// directory of their manifest. Returns `None` if none of them have kernel modules.
+    pub(crate) fn for_kit(packages: &BTreeMap<String, PathBuf>) -> Result<Option<Self>> {
+        let mut modules: Option<Self> = None;
+        for (package, dir) in packages {
+            let path = dir.join("Cargo.toml");
+            let info =
+                ManifestInfo::new(&path).context(error::ManifestParseSnafu { path: &path })?;
+            let Some(target) = info.kernel_module() else {
+                continue;
+            };
+            match &mut modules {
+                None => {
+                    modules = Some(Self {
+                        kernel: target.kernel.clone(),
+                        version: target.version.clone(),
+                        packages: vec![package.clone()],
+                    })
+                }
+                Some(modules) => {
+                    ensure!(
+                        modules.kernel == target.kernel && modules.version == target.version,
+                        error::MixedKernelsSnafu {
+                            first: format!("{} {}", modules.kernel, modules.version),
+                            second: format!("{} {}", target.kernel, target.version),
+                        }
+                    );
+                    modules.packages.push(package.clone());
+                }
+            }
+        }
+        Ok(modules)
+    }
+
+    /// The metadata as JSON, which the kit build writes to `KERNEL_MODULES_METADATA`.
+    pub(crate) fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).context(error::MetadataSerializeSnafu)
+    }
+
+    /// Reads the metadata of the kit whose repository is `kit_dir`, if it has kernel modules.
+    fn load(kit_dir: &Path) -> Result<Option<Self>> {
+        let path = kit_dir.join(KERNEL_MODULES_METADATA);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(&path).context(error::FileReadSnafu { path: &path })?;
+        serde_json::from_str(&data)
+            .map(Some)
+            .context(error::MetadataParseSnafu { path })
+    }
+}
+
+/// Checks that a variant with `packages` includes the kernel of each kernel module kit among
+/// `kits`, which are given by name and the directory of their repository for the variant's
+/// architecture, at the version the kit was built against. The kernel's version is found from its
+/// RPMs in the kits and in `packages_dir`, where the project's packages are built.
+pub(crate) fn check_variant(
+    kits: &[(String, PathBuf)],
+    packages: &[String],
+    packages_dir: &Path,
+) -> Result<()> {
+    for (kit, kit_dir) in kits {
+        let Some(modules) = KernelModules::load(kit_dir)? else {
+            continue;
+        };
+        ensure!(
+            packages.contains(&modules.kernel),
+            error::MissingKernelSnafu {
+                kit,
+                kernel: &modules.kernel,
+                version: &modules.version,
+            }
+        );
+        let repos = kits
+            .iter()
+            .map(|(_, dir)| dir.as_path())
+            .chain(std::iter::once(packages_dir));
+        let versions = rpm_versions(&modules.kernel, repos);
+        ensure!(
+            versions.is_empty() || versions.contains(&modules.version),
+            error::KernelVersionSnafu {
+                kit,
+                kernel: &modules.kernel,
+                version: &modules.version,
+                found: versions.into_iter().collect::<Vec<_>>().join(", "),
+            }
+        );
+    }
+    Ok(())
+}
+
+/// The versions of the RPMs of `package` in `repos`, which are named like
+/// `bottlerocket-kernel-6.1-6.1.102-1.x86_64.rpm`. Subpackages, such as `kernel-6.1-devel`, are
+/// left out.
+fn rpm_versions<'a>(package: &str, repos: impl Iterator<Item = &'a Path>) -> BTreeSet<String> {
+    let mut versions = BTreeSet::new();
+    for repo in repos {
+        let entries = WalkDir::new(repo).follow_links(true).into_iter();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy();
+            let Some((nvr, _arch)) = name
+                .strip_suffix(".rpm")
+                .and_then(|name| name.rsplit_once('.'))
+            else {
+                continue;
+            };
+            let mut fields = nvr.rsplitn(3, '-');
+            let (Some(_release), Some(version), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if name == package || name.ends_with(&format!("-{package}")) {
+                versions.insert(version.to_string());
+            }
+        }
+    }
+    versions
+}
+
+/// Writes the checksum of the kmod kit in `output_dir`, the output of a variant build, to a file
+/// with `.sha256` added to its name, and links to it for each link to the kit. Returns the path of
+/// the checksum, or `None` if the build didn't produce a kit. Checksums of kits from earlier builds
+/// are removed, so that none are left that don't match a kit.
+pub(crate) fn checksum_kit(output_dir: &Path) -> Result<Option<PathBuf>> {
+    let mut kit = None;
+    let mut links = Vec::new();
+    let mut stale = Vec::new();
+    let entries = fs::read_dir(output_dir).context(error::FileReadSnafu { path: output_dir })?;
+    for entry in entries {
+        let entry = entry.context(error::FileReadSnafu { path: output_dir })?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // The kit and its links are named by `rpm2kmodkit`.
+        if name.contains("-kmod-kit") && name.ends_with(".tar.xz.sha256") {
+            stale.push(entry.path());
+            continue;
+        }
+        if !(name.contains("-kmod-kit") && name.ends_with(".tar.xz")) {
+            continue;
+        }
+        if entry.path().is_symlink() {
+            links.push(name);
+        } else {
+            kit = Some(name);
+        }
+    }
+    for path in stale {
+        fs::remove_file(&path).context(error::FileRemoveSnafu { path: &path })?;
+    }
+    let Some(kit) = kit else {
+        return Ok(None);
+    };
+
+    let kit_path = output_dir.join(&kit);
+    let mut hasher = Sha256::new();
+    File::open(&kit_path)
+        .and_then(|mut f| io::copy(&mut f, &mut hasher))
+        .context(error::FileReadSnafu { path: &kit_path })?;
+    let digest = hex::encode(hasher.finalize());
+
+    let checksum = format!("{kit}.sha256");
+    let path = output_dir.join(&checksum);
+    fs::write(&path, format!("{digest}  {kit}\n"))
+        .context(error::FileWriteSnafu { path: &path })?;
+    for link in links {
+        let link = output_dir.join(format!("{link}.sha256"));
+        std::os::unix::fs::symlink(&checksum, &link)
+            .context(error::FileWriteSnafu { path: &link })?;
+    }
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_package(dir: &Path, name: &str, build_package: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"{name}\"\n[package.metadata.build-package]\n{build_package}"
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_for_kit() {
+        let root = TempDir::new().unwrap();
+        // Package directories don't have to be named after their packages.
+        let packages: BTreeMap<String, PathBuf> = [
+            ("efa-kmod", "packages/drivers/efa"),
+            ("libfoo", "packages/libfoo"),
+            ("nvidia-kmod", "packages/drivers/nvidia"),
+        ]
+        .into_iter()
+        .map(|(name, dir)| (name.to_string(), root.path().join(dir)))
+        .collect();
+        let target = "kernel-module = { kernel = \"kernel-6.1\", version = \"6.1.102\" }";
+        write_package(&packages["nvidia-kmod"], "nvidia-kmod", target);
+        write_package(&packages["efa-kmod"], "efa-kmod", target);
+        write_package(&packages["libfoo"], "libfoo", "");
+
+        let modules = KernelModules::for_kit(&packages).unwrap().unwrap();
+        assert_eq!(modules.kernel, "kernel-6.1");
+        assert_eq!(modules.packages, vec!["efa-kmod", "nvidia-kmod"]);
+        let libfoo = packages
+            .iter()
+            .filter(|(name, _)| *name == "libfoo")
+            .map(|(name, dir)| (name.clone(), dir.clone()))
+            .collect();
+        assert!(KernelModules::for_kit(&libfoo).unwrap().is_none());
+
+        write_package(
+            &packages["efa-kmod"],
+            "efa-kmod",
+            "kernel-module = { kernel = \"kernel-5.10\", version = \"5.10.223\" }",
+        );
+        assert!(KernelModules::for_kit(&packages).is_err());
+    }
+
+    #[test]
+    fn test_check_variant() {
+        let kit_dir = TempDir::new().unwrap();
+        let modules = KernelModules {
+            kernel: "kernel-6.1".to_string(),
+            version: "6.1.102".to_string(),
+            packages: vec!["nvidia-kmod".to_string()],
+        };
+        fs::create_dir(kit_dir.path().join("metadata")).unwrap();
+        fs::write(
+            kit_dir.path().join(KERNEL_MODULES_METADATA),
+            modules.to_json().unwrap(),
+        )
+        .unwrap();
+        let kits = [("nvidia-kit".to_string(), kit_dir.path().to_path_buf())];
+
+        let packages_dir = TempDir::new().unwrap();
+        let kernel = ["kernel-6.1".to_string()];
+        check_variant(&kits, &kernel, packages_dir.path()).unwrap();
+        assert!(check_variant(&kits, &["kernel-5.10".to_string()], packages_dir.path()).is_err());
+
+        let kernel_dir = packages_dir.path().join("kernel-6.1");
+        fs::create_dir(&kernel_dir).unwrap();
+        for rpm in [
+            "bottlerocket-kernel-6.1-6.1.90-1.x86_64.rpm",
+            "bottlerocket-kernel-6.1-devel-6.1.102-1.x86_64.rpm",
+        ] {
+            fs::write(kernel_dir.join(rpm), "").unwrap();
+        }
+        assert_eq!(
+            rpm_versions("kernel-6.1", std::iter::once(packages_dir.path())),
+            BTreeSet::from(["6.1.90".to_string()])
+        );
+        assert!(check_variant(&kits, &kernel, packages_dir.path()).is_err());
+
+        fs::write(
+            kernel_dir.join("bottlerocket-kernel-6.1-6.1.102-1.x86_64.rpm"),
+            "",
+        )
+        .unwrap();
+        check_variant(&kits, &kernel, packages_dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_kit() {
+        let output_dir = TempDir::new().unwrap();
+        let dir = output_dir.path();
+        assert!(checksum_kit(dir).unwrap().is_none());
+
+        let kit = "bottlerocket-aws-dev-x86_64-1.20.0-abcdef01-kmod-kit.tar.xz";
+        let links = [
+            "bottlerocket-aws-dev-x86_64-kmod-kit.tar.xz",
+            "aws-dev-x86_64-kmod-kit-v1.20.0.tar.xz",
+        ];
+        fs::write(dir.join(kit), "kit").unwrap();
+        for link in links {
+            std::os::unix::fs::symlink(kit, dir.join(link)).unwrap();
+        }
+        fs::write(dir.join("bottlerocket-aws-dev-x86_64.img.lz4"), "image").unwrap();
+        // The checksum of a kit from an earlier build, which the build replaced.
+        let old = "bottlerocket-aws-dev-x86_64-1.19.0-01234567-kmod-kit.tar.xz.sha256";
+        fs::write(dir.join(old), "old").unwrap();
+
+        for _ in 0..2 {
+            let path = checksum_kit(dir).unwrap().unwrap();
+            assert_eq!(path, dir.join(format!("{kit}.sha256")));
+            let expected = format!("{}  {kit}\n", hex::encode(Sha256::digest(b"kit")));
+            assert_eq!(fs::read_to_string(&path).unwrap(), expected);
+            for link in links {
+                assert_eq!(
+                    fs::read_to_string(dir.join(format!("{link}.sha256"))).unwrap(),
+                    expected
+                );
+            }
+            assert!(!dir.join(old).exists());
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to parse manifest '{}': {}", path.display(), source))]
+    ManifestParse {
+        path: PathBuf,
+        source: buildsys::manifest::Error,
+    },
+
+    #[snafu(display(
+        "The kit has kernel modules for {} and for {}, but a kit can only target one kernel",
+        first,
+        second
+    ))]
+    MixedKernels { first: String, second: String },
+
+    #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+    FileRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+    FileWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to remove '{}': {}", path.display(), source))]
+    FileRemove {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to parse kernel module metadata '{}': {}", path.display(), source))]
+    MetadataParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Failed to serialize kernel module metadata: {}", source))]
+    MetadataSerialize { source: serde_json::Error },
+
+    #[snafu(display(
+        "Kit '{}' has kernel modules for {} {}, but the variant doesn't include '{}'",
+        kit,
+        kernel,
+        version,
+        kernel
+    ))]
+    MissingKernel {
+        kit: String,
+        kernel: String,
+        version: String,
+    },
+
+    #[snafu(display(
+        "Kit '{}' has kernel modules for {} {}, but the variant's '{}' is at {}",
+        kit,
+        kernel,
+        version,
+        kernel,
+        found
+    ))]
+    KernelVersion {
+        kit: String,
+        kernel: String,
+        version: String,
+        found: String,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
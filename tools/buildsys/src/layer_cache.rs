@@ -0,0 +1,38 @@
+/*!
+The layer cache is a registry repository with the BuildKit cache of a reference build, such as the
+release pipeline's, for each build and architecture. The reference build exports it with
+`--cache-to` and other builds import it with `--cache-from`, both as `type=registry` caches, so
+builds fetch the layers they reuse from the registry as they need them. buildsys and twoliter both
+name the caches here, so that they agree on where each build's cache is.
+*/
+
+use std::path::Path;
+
+/// The name of a build in the layer cache, which is the name of the directory of its manifest,
+/// such as the package's directory.
+pub fn build_name(manifest_dir: &Path) -> String {
+    manifest_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// The reference of the cache of the build named `name` for `arch` in the layer cache `repo`.
+pub fn cache_ref(repo: &str, name: &str, arch: &str) -> String {
+    format!("{repo}:{name}-{arch}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_ref() {
+        let name = build_name(Path::new("/project/packages/glibc"));
+        assert_eq!(name, "glibc");
+        assert_eq!(
+            cache_ref("registry.example.com/cache", &name, "x86_64"),
+            "registry.example.com/cache:glibc-x86_64"
+        );
+    }
+}
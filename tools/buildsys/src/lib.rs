@@ -1,4 +1,10 @@
+pub mod hooks;
+pub mod layer_cache;
 pub mod manifest;
+pub mod os_release;
+pub mod rebuild;
+pub mod redact;
+pub mod spec;
 
 /// The thing that buildsys is being asked to build.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -11,25 +11,54 @@ The implementation is closely tied to the top-level Dockerfile.
 mod args;
 mod builder;
 mod cache;
+mod cache_log;
+mod changelog;
+mod extract;
+mod gitsource;
 mod gomod;
+mod host;
+mod interrupt;
+mod kmod;
+mod patch;
 mod project;
-mod spec;
+mod remote_cache;
+mod rerun;
+mod settings_defaults;
 
 use crate::args::{
-    BuildKitArgs, BuildPackageArgs, BuildVariantArgs, Buildsys, Command, RepackVariantArgs,
+    BuildKitArgs, BuildPackageArgs, BuildVariantArgs, Buildsys, Command, Common, RepackVariantArgs,
 };
+use crate::builder::progress::{Problem, Progress};
 use crate::builder::DockerBuild;
-use buildsys::manifest::{BundleModule, Manifest, ManifestInfo, SupportedArch};
-use buildsys_config::EXTERNAL_KIT_METADATA;
-use cache::LookasideCache;
+use crate::changelog::Changelog;
+use buildsys::hooks::{Hooks, Stage, Target};
+use buildsys::manifest::{
+    BundleModule, ExternalFile, ExternalKitMetadataView, ImageFeature, Manifest, ManifestInfo,
+    SupportedArch,
+};
+use buildsys::spec::SpecInfo;
+use buildsys::BuildType;
+use buildsys_config::{
+    variant_changelog_path, variant_images_dir, EXTERNAL_KIT_METADATA, PACKAGE_WATCH_DIRECTORY,
+};
+use cache::{FetchPolicy, IndexedFile, LookasideCache, SourceCacheIndex};
+use cache_log::Item;
 use clap::Parser;
+use extract::SourceExtract;
 use filetime::FileTime;
 use gomod::GoMod;
+use host::HostEnvironment;
+use patch::SourcePatch;
 use project::ProjectInfo;
+use remote_cache::{BuildInputs, RemoteCache};
+use rerun::Explanation;
+use semver::{Version, VersionReq};
 use snafu::{ensure, ResultExt};
-use spec::SpecInfo;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
+use tracing::{info, info_span};
+use tracing_subscriber::EnvFilter;
 
 mod error {
     use snafu::Snafu;
@@ -38,11 +67,34 @@ mod error {
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(super) enum Error {
+        #[snafu(display("Invalid build configuration:\n{}", problems.join("\n")))]
+        InvalidConfig { problems: Vec<String> },
+
         #[snafu(display("{source}"))]
         ManifestParse { source: buildsys::manifest::Error },
 
         #[snafu(display("{source}"))]
-        SpecParse { source: super::spec::error::Error },
+        SpecParse {
+            source: buildsys::spec::error::Error,
+        },
+
+        #[snafu(display(
+            "{what} requires {tool} {required}, but this is {tool} {version}. Upgrade twoliter, \
+            which comes with buildsys, to a version that meets the requirement"
+        ))]
+        UnsupportedVersion {
+            what: String,
+            tool: &'static str,
+            required: semver::VersionReq,
+            version: String,
+        },
+
+        #[snafu(display("Unable to parse {tool} version '{version}': {source}"))]
+        VersionParse {
+            tool: &'static str,
+            version: String,
+            source: semver::Error,
+        },
 
         #[snafu(display("{source}"))]
         ExternalFileFetch { source: super::cache::error::Error },
@@ -56,6 +108,19 @@ mod error {
         #[snafu(display("{source}"))]
         GoMod { source: super::gomod::error::Error },
 
+        #[snafu(display("{source}"))]
+        SourcePatch { source: super::patch::error::Error },
+
+        #[snafu(display("{source}"))]
+        SourceExtract {
+            source: super::extract::error::Error,
+        },
+
+        #[snafu(display("{source}"))]
+        RemoteCache {
+            source: super::remote_cache::error::Error,
+        },
+
         #[snafu(display("{source}"))]
         ProjectCrawl {
             source: super::project::error::Error,
@@ -66,6 +131,38 @@ mod error {
             source: super::builder::error::Error,
         },
 
+        #[snafu(display("Unable to write the variant changelog: {source}"))]
+        Changelog {
+            source: super::changelog::error::Error,
+        },
+
+        #[snafu(display("Unable to record the build host: {source}"))]
+        BuildHost { source: super::host::error::Error },
+
+        #[snafu(display("Unable to export the settings defaults: {source}"))]
+        SettingsDefaults {
+            source: super::settings_defaults::error::Error,
+        },
+
+        #[snafu(display("Unable to check the variant's kernel module kits: {source}"))]
+        KernelModules { source: super::kmod::error::Error },
+
+        #[snafu(display("{source}"))]
+        Hook {
+            source: buildsys::hooks::error::Error,
+        },
+
+        #[snafu(display("Unable to generate the package changelog: {source}"))]
+        GenerateChangelog {
+            source: super::changelog::error::Error,
+        },
+
+        #[snafu(display("Unable to write '{}': {}", path.display(), source))]
+        FileWrite {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
         #[snafu(display("Unable to instantiate the builder: {source}"))]
         BuilderInstantiation {
             source: crate::builder::error::Error,
@@ -95,19 +192,123 @@ mod error {
 
 type Result<T> = std::result::Result<T, error::Error>;
 
+/// Filters for the log, such as `buildsys::builder=debug`, in the syntax of `EnvFilter`. Twoliter
+/// sets it to log at its own level.
+const LOG_FILTER_ENV: &str = "TWOLITER_LOG";
+
+/// The log level when `TWOLITER_LOG` doesn't set one.
+const DEFAULT_LOG_FILTER: &str = "buildsys=info";
+
 // Returning a Result from main makes it print a Debug representation of the error, but with Snafu
 // we have nice Display representations of the error, so we wrap "main" (run) and print any error.
 // https://github.com/shepmaster/snafu/issues/110
 fn main() {
     let args = Buildsys::parse();
-    if let Err(e) = run(args) {
+    init_logger();
+    interrupt::install();
+    let build = FailedBuild::new(&args.command);
+    let explanation = Explanation::start(&args);
+    let result = run(args);
+    if let Some(explanation) = explanation {
+        explanation.finish(result.is_ok());
+    }
+    if let Err(e) = result {
+        if interrupt::interrupted() {
+            eprintln!("Build interrupted");
+            process::exit(interrupt::EXIT_STATUS);
+        }
+        let maintainers = build.maintainers();
+        build.record(&e, &maintainers);
         eprintln!("{}", e);
+        if !maintainers.is_empty() {
+            eprintln!("{} is maintained by {}", build.name, maintainers.join(", "));
+        }
         process::exit(1);
     }
 }
 
+/// What is needed to record the progress of a build that failed, once its arguments are gone.
+struct FailedBuild {
+    root_dir: PathBuf,
+    build_type: BuildType,
+    name: String,
+    arch: String,
+    manifest_dir: PathBuf,
+}
+
+impl FailedBuild {
+    fn new(command: &Command) -> Self {
+        let common = command.common();
+        Self {
+            root_dir: common.root_dir.clone(),
+            build_type: command.build_type(),
+            name: common.name(),
+            arch: common.arch.to_string(),
+            manifest_dir: common.cargo_manifest_dir.clone(),
+        }
+    }
+
+    /// Who owns the package, if its manifest can be read and lists them.
+    fn maintainers(&self) -> Vec<String> {
+        ManifestInfo::new(self.manifest_dir.join("Cargo.toml"))
+            .map(|manifest| manifest.maintainers().to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Records that the build failed with `error`, so that twoliter can point to the problem and
+    /// the people who own it. Builds that failed in Docker have already recorded how they failed.
+    fn record(&self, error: &error::Error, maintainers: &[String]) {
+        use error::Error;
+        let file = match error {
+            Error::BuildAttempt { .. } => return,
+            Error::ManifestParse { .. } => Some(self.manifest_dir.join("Cargo.toml")),
+            Error::PackageFeatures { path, .. } | Error::VariantSensitive { path, .. } => {
+                Some(path.clone())
+            }
+            Error::SpecParse {
+                source: buildsys::spec::error::Error::SpecFileRead { path, .. },
+            } => Some(self.manifest_dir.join(path)),
+            _ => None,
+        };
+        Progress::failed(
+            &self.root_dir,
+            self.build_type,
+            &self.name,
+            &self.arch,
+            Problem::new(error.to_string(), file),
+            maintainers,
+        );
+    }
+}
+
+/// Logs to stderr, since cargo reads instructions from the stdout of build scripts. The filters in
+/// `TWOLITER_LOG` are added to the default level, so that they can change it for single modules.
+fn init_logger() {
+    let filter = match std::env::var(LOG_FILTER_ENV) {
+        Ok(env) if !env.trim().is_empty() => format!("{DEFAULT_LOG_FILTER},{env}"),
+        _ => DEFAULT_LOG_FILTER.to_string(),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::builder().parse_lossy(filter))
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .init();
+}
+
 fn run(args: Buildsys) -> Result<()> {
-    args::rerun_for_envs(args.command.build_type());
+    if args.print_config {
+        println!("{}", args::settings_table(&args.command.settings()));
+    }
+
+    // Catch settings that would fail the build before doing any of the work.
+    let problems = args.command.validate();
+    ensure!(problems.is_empty(), error::InvalidConfigSnafu { problems });
+    if args.print_config {
+        return Ok(());
+    }
+    check_required_versions(args.command.common())?;
+
+    let _build = args.command.span().entered();
     match args.command {
         Command::BuildPackage(args) => build_package(*args),
         Command::BuildKit(args) => build_kit(*args),
@@ -116,60 +317,173 @@ fn run(args: Buildsys) -> Result<()> {
     }
 }
 
+/// Checks that this buildsys, and the twoliter that started it, are versions that the project and
+/// the manifest require, before any of the work is done.
+fn check_required_versions(common: &Common) -> Result<()> {
+    let buildsys_version = env!("CARGO_PKG_VERSION");
+    if let Some(required) = &common.required_version {
+        check_version("The project", "buildsys", required, buildsys_version)?;
+    }
+
+    let manifest_path = common.cargo_manifest_dir.join("Cargo.toml");
+    let manifest = ManifestInfo::new(&manifest_path).context(error::ManifestParseSnafu)?;
+    let what = format!("'{}'", manifest_path.display());
+    if let Some(required) = manifest.required_buildsys_version() {
+        check_version(&what, "buildsys", required, buildsys_version)?;
+    }
+    if let Some(required) = manifest.required_twoliter_version() {
+        match &common.twoliter_version {
+            Some(version) => check_version(&what, "twoliter", required, version)?,
+            None => println!(
+                "cargo:warning={what} requires twoliter {required}, but the build wasn't started \
+                by twoliter, so the requirement can't be checked"
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn check_version(
+    what: &str,
+    tool: &'static str,
+    required: &VersionReq,
+    version: &str,
+) -> Result<()> {
+    let parsed = Version::parse(version).context(error::VersionParseSnafu { tool, version })?;
+    ensure!(
+        required.matches(&parsed),
+        error::UnsupportedVersionSnafu {
+            what,
+            tool,
+            required: required.clone(),
+            version,
+        }
+    );
+    Ok(())
+}
+
 fn build_package(args: BuildPackageArgs) -> Result<()> {
     let manifest_file = "Cargo.toml";
     let manifest_path = args.common.cargo_manifest_dir.join(manifest_file);
-    println!("cargo:rerun-if-changed={}", manifest_file);
-    println!(
-        "cargo:rerun-if-changed={}",
-        args.common.root_dir.join(EXTERNAL_KIT_METADATA).display()
-    );
+    rerun::file(manifest_file);
+    rerun::file(args.common.root_dir.join(EXTERNAL_KIT_METADATA));
 
     let manifest = Manifest::new(&manifest_path, &args.common.cargo_metadata_path)
         .context(error::ManifestParseSnafu)?;
+    args::rerun_for_envs(BuildType::Package, manifest.info());
 
     // Check for a deprecated key and error if it is detected.
     ensure_package_is_not_variant_sensitive(&manifest, &manifest_path)?;
 
+    let mut unverified_overrides = 0;
+    let remote_cache = match args.remote_cache.as_deref().filter(|url| !url.is_empty()) {
+        Some(url) => Some(
+            RemoteCache::new(
+                &args.common.version_full,
+                url,
+                args.remote_cache_upload == "true",
+            )
+            .context(error::RemoteCacheSnafu)?
+            .token(args.remote_cache_token.clone()),
+        ),
+        None => None,
+    };
+
     if let Some(files) = manifest.info().external_files() {
+        let _sources = info_span!("sources").entered();
         // We need the modification time for any external files or bundled modules to be no later
         // than the manifest's modification time, to avoid triggering spurious rebuilds.
         let metadata =
             std::fs::metadata(manifest_path.clone()).context(error::FileMetadataSnafu {
-                path: manifest_path,
+                path: &manifest_path,
             })?;
         let mtime = FileTime::from_last_modification_time(&metadata);
 
-        let lookaside_cache = LookasideCache::new(
+        let mut lookaside_cache = LookasideCache::new(
             &args.common.version_full,
             args.lookaside_cache.clone(),
             args.upstream_source_fallback == "true",
-        );
+        )
+        .fetch_log(&args.common.root_dir, manifest.info().package_name())
+        .sdk(&args.common.sdk_image)
+        .remote_cache(remote_cache.clone())
+        .policy(FetchPolicy {
+            rate_limit: args.fetch_rate_limit,
+            retries: args.fetch_retries,
+            backoff: Duration::from_secs(args.fetch_backoff),
+            max_backoff: Duration::from_secs(args.fetch_max_backoff),
+        });
+        if let Some(dir) = args.source_overrides_dir() {
+            lookaside_cache =
+                lookaside_cache.overrides(dir, args.allow_unverified_overrides == "true");
+        }
 
-        lookaside_cache
-            .fetch(files, mtime)
+        let mut index = SourceCacheIndex::load(
+            &args.common.root_dir,
+            manifest.info().package_name(),
+            &args.common.cargo_manifest_dir,
+        );
+        let counts = lookaside_cache
+            .fetch(files, mtime, &mut index)
             .context(error::ExternalFileFetchSnafu)?;
+        cache_log::record(
+            &args.common.root_dir,
+            manifest.info().package_name(),
+            Item::ExternalFile,
+            counts.cached,
+            counts.downloaded,
+        );
+        unverified_overrides = counts.unverified;
+
+        for f in files {
+            SourcePatch::apply(
+                &args.common.cargo_manifest_dir,
+                f,
+                &args.common.sdk_image,
+                mtime,
+            )
+            .context(error::SourcePatchSnafu)?;
+        }
+
+        for f in files {
+            if let Some(extract) = SourceExtract::new(f).context(error::SourceExtractSnafu)? {
+                extract
+                    .apply(&args.common.cargo_manifest_dir, &args.common.sdk_image)
+                    .context(error::SourceExtractSnafu)?;
+            }
+        }
 
+        // Bundles made from unverified overrides don't match the hashes in the manifest, so they
+        // neither use the remote cache nor add to it.
+        let bundle_cache = remote_cache.as_ref().filter(|_| unverified_overrides == 0);
+        let mut cached = 0;
+        let mut vendored = 0;
         for f in files {
             if f.bundle_modules.is_none() {
                 continue;
             }
 
             for b in f.bundle_modules.as_ref().unwrap() {
-                match b {
-                    BundleModule::Go => GoMod::vendor(
-                        &args.common.root_dir,
-                        &args.common.cargo_manifest_dir,
-                        f,
-                        &args.common.sdk_image,
-                        mtime,
-                    )
-                    .context(error::GoModSnafu)?,
+                let (bundle, from_cache) = vendor_bundle(&args, f, b, bundle_cache, mtime)?;
+                index.record_digest(&bundle, IndexedFile::Bundle);
+                if from_cache {
+                    cached += 1;
+                } else {
+                    vendored += 1;
                 }
             }
         }
+        index.save();
+        cache_log::record(
+            &args.common.root_dir,
+            manifest.info().package_name(),
+            Item::VendoredBundle,
+            cached,
+            vendored,
+        );
     }
 
+    let mut source_group_files = Vec::new();
     if let Some(groups) = manifest.info().source_groups() {
         let dirs = groups
             .iter()
@@ -177,7 +491,8 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
             .collect::<Vec<_>>();
         let info = ProjectInfo::crawl(&dirs).context(error::ProjectCrawlSnafu)?;
         for f in info.files {
-            println!("cargo:rerun-if-changed={}", f.display());
+            rerun::file(&f);
+            source_group_files.push(f);
         }
     }
 
@@ -185,46 +500,393 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
     // characters invalid in Cargo crate names
     let package = manifest.info().package_name();
     let spec = format!("{}.spec", package);
-    println!("cargo:rerun-if-changed={}", spec);
+    rerun::file(&spec);
 
     let info = SpecInfo::new(PathBuf::from(&spec)).context(error::SpecParseSnafu)?;
 
-    for f in info.sources {
-        println!("cargo:rerun-if-changed={}", f.display());
+    if manifest.info().generate_changelog() {
+        // The entries follow the project's history, so they need to be regenerated whenever it
+        // moves.
+        changelog::rerun_for_git(&args.common.root_dir);
+        let path = changelog::generate::path(&args.common.state_dir, package);
+        let entries = changelog::generate::package_changelog(
+            &args.common.root_dir,
+            &args.common.cargo_manifest_dir,
+            package,
+        )
+        .context(error::GenerateChangelogSnafu)?;
+        match entries {
+            Some(entries) => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir).context(error::FileWriteSnafu { path: dir })?;
+                }
+                std::fs::write(&path, entries).context(error::FileWriteSnafu { path: &path })?
+            }
+            None => {
+                println!("cargo:warning=No changelog entries could be generated for {package}");
+                let _ = std::fs::remove_file(&path);
+            }
+        }
     }
 
-    for f in info.patches {
-        println!("cargo:rerun-if-changed={}", f.display());
+    for f in &info.sources {
+        rerun::file(f);
     }
 
+    for f in &info.patches {
+        rerun::file(f);
+    }
+
+    let fragment = manifest
+        .info()
+        .dockerfile_fragment()
+        .map(|fragment| fragment.path.clone());
+    if let Some(f) = &fragment {
+        rerun::file(f);
+    }
+
+    let external_patches = manifest
+        .info()
+        .external_files()
+        .into_iter()
+        .flatten()
+        .flat_map(|f| f.patches.iter().flatten().cloned());
+    let watched = [manifest_path.clone(), PathBuf::from(&spec)]
+        .into_iter()
+        .chain(info.sources.iter().cloned())
+        .chain(info.patches.iter().cloned())
+        .chain(external_patches)
+        .chain(fragment)
+        .map(|f| args.common.cargo_manifest_dir.join(f))
+        .chain(source_group_files.iter().cloned())
+        .collect::<Vec<_>>();
+    if let Err(e) = write_watch_list(
+        &args.common.root_dir,
+        manifest.info().manifest_name(),
+        &watched,
+    ) {
+        println!("cargo:warning=Unable to record the files this package depends on: {e}");
+    }
+    rerun_for_hooks(&args.common, &args.hooks, &Stage::PACKAGE_BUILD);
+
     if args.common.cicd_hack {
         return Ok(());
     }
 
-    DockerBuild::new_package(args, &manifest)
-        .context(error::BuilderInstantiationSnafu)?
-        .build()
-        .context(error::BuildAttemptSnafu)
+    // The inputs are read before the build, so that files changed while it runs make it stale.
+    let sdk_digest = remote_cache::sdk_digest(&args.common.sdk_image);
+    let inputs = package_build_inputs(
+        &args,
+        &manifest,
+        &spec,
+        &info,
+        &source_group_files,
+        sdk_digest.as_deref().unwrap_or(&args.common.sdk_image),
+    )?;
+    let dependencies = manifest
+        .package_dependencies()
+        .context(error::ManifestParseSnafu)?;
+    let root_dir = args.common.root_dir.clone();
+    let arch = args.common.arch.to_string();
+
+    // The cache key assumes that external files match the manifest, so builds from unverified
+    // overrides neither use the remote cache nor add to it.
+    let remote_cache = match (remote_cache, &sdk_digest) {
+        (Some(_), _) if unverified_overrides > 0 => {
+            info!("Not using the remote cache, since some sources are unverified overrides");
+            None
+        }
+        (Some(_), Err(e)) => {
+            println!("cargo:warning=Not using the remote cache: {e}");
+            None
+        }
+        (Some(cache), Ok(_)) => Some((cache, inputs.key())),
+        (None, _) => None,
+    };
+
+    let hooks = args.hooks.clone().unwrap_or_default();
+    let sdk = args.common.sdk_image.clone();
+    let target = Target {
+        root: &root_dir,
+        kind: "package",
+        name: package,
+        arch: Some(&arch),
+        sdk: &sdk,
+        key: Some(inputs.key()),
+        envs: Vec::new(),
+    };
+    hooks
+        .run(Stage::PrePackageBuild, &target)
+        .context(error::HookSnafu)?;
+
+    info_span!("docker").in_scope(|| {
+        DockerBuild::new_package(args, &manifest)
+            .context(error::BuilderInstantiationSnafu)?
+            .remote_cache(remote_cache)
+            .build()
+            .context(error::BuildAttemptSnafu)
+    })?;
+
+    hooks
+        .run(Stage::PostPackageBuild, &target)
+        .context(error::HookSnafu)?;
+
+    let vars = args::watched_env_values(BuildType::Package, manifest.info());
+    if let Err(e) = inputs.save(
+        &root_dir,
+        BuildType::Package,
+        package,
+        &arch,
+        &vars,
+        &dependencies,
+    ) {
+        println!("cargo:warning=Unable to record the inputs of this build: {e}");
+    }
+    Ok(())
+}
+
+/// Vendors the modules of an external file into a bundle, unless `remote_cache` has a bundle made
+/// from the same inputs. Returns the bundle's path, and whether it came from the remote cache.
+fn vendor_bundle(
+    args: &BuildPackageArgs,
+    f: &ExternalFile,
+    module: &BundleModule,
+    remote_cache: Option<&RemoteCache>,
+    mtime: FileTime,
+) -> Result<(PathBuf, bool)> {
+    let path = match module {
+        BundleModule::Go => GoMod::bundle_path(f).context(error::GoModSnafu)?,
+    };
+    let key = match remote_cache.map(|_| remote_cache::sdk_digest(&args.common.sdk_image)) {
+        Some(Ok(sdk)) => Some(bundle_inputs(f, module, &path, &sdk).key()),
+        Some(Err(e)) => {
+            println!("cargo:warning=Not using the remote cache for bundles: {e}");
+            None
+        }
+        None => None,
+    };
+    if let (Some(cache), Some(key)) = (remote_cache, &key) {
+        let tmp = path.with_file_name(format!(
+            ".{}",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        match cache.fetch_bundle(key, &tmp) {
+            Ok(true) => {
+                std::fs::rename(&tmp, &path).context(error::FileWriteSnafu { path: &path })?;
+                filetime::set_file_mtime(&path, mtime)
+                    .context(error::FileWriteSnafu { path: &path })?;
+                info!("Using {} from the remote cache", path.display());
+                return Ok((path, true));
+            }
+            Ok(false) => (),
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                println!("cargo:warning=Unable to fetch a bundle from the remote cache: {e}");
+            }
+        }
+    }
+
+    let bundle = match module {
+        BundleModule::Go => GoMod::vendor(
+            &args.common.root_dir,
+            &args.common.cargo_manifest_dir,
+            f,
+            &args.common.sdk_image,
+            &args.common.build_secrets,
+            mtime,
+        )
+        .context(error::GoModSnafu)?,
+    };
+    if let (Some(cache), Some(key)) = (remote_cache.filter(|c| c.upload_enabled()), &key) {
+        if let Err(e) = cache.store_bundle(key, &bundle) {
+            println!("cargo:warning=Unable to upload a bundle to the remote cache: {e}");
+        }
+    }
+    Ok((bundle, false))
+}
+
+/// Gathers everything that affects the bundle that vendoring `module` for an external file makes,
+/// with the SDK's image ID.
+fn bundle_inputs(f: &ExternalFile, module: &BundleModule, path: &Path, sdk: &str) -> BuildInputs {
+    let mut inputs = BuildInputs::default();
+    inputs
+        .value("module", format!("{module:?}"))
+        .value("external-file", &f.sha512)
+        .value("output", path.display().to_string())
+        .value(
+            "root",
+            f.bundle_root_path
+                .as_ref()
+                .map(|root| root.display().to_string())
+                .unwrap_or_default(),
+        )
+        .value("sdk", sdk)
+        .value(
+            "output-generation",
+            std::env::var("BUILDSYS_OUTPUT_GENERATION_ID").unwrap_or_default(),
+        );
+    inputs
+}
+
+/// Records the files that a package build depends on, so that `twoliter dev watch` can rebuild
+/// the package when one of them changes.
+fn write_watch_list(root_dir: &Path, package: &str, files: &[PathBuf]) -> std::io::Result<()> {
+    let dir = root_dir.join(PACKAGE_WATCH_DIRECTORY);
+    std::fs::create_dir_all(&dir)?;
+    let list = files
+        .iter()
+        .map(|f| format!("{}\n", f.display()))
+        .collect::<String>();
+    std::fs::write(dir.join(package), list)
+}
+
+/// Gathers everything that affects the RPMs produced by a package build, so that a build with the
+/// same inputs can be satisfied from the remote cache. `sdk` is the SDK's image ID.
+fn package_build_inputs(
+    args: &BuildPackageArgs,
+    manifest: &Manifest,
+    spec: &str,
+    info: &SpecInfo,
+    source_group_files: &[PathBuf],
+    sdk: &str,
+) -> Result<BuildInputs> {
+    let mut inputs = BuildInputs::default();
+    inputs
+        .value("package", manifest.info().package_name())
+        .value("arch", args.common.arch.to_string())
+        .value("sdk", sdk)
+        .value(
+            "output-generation",
+            std::env::var("BUILDSYS_OUTPUT_GENERATION_ID").unwrap_or_default(),
+        );
+    // The settings that change the RPMs are only recorded when they differ from the default, so
+    // that the inputs of projects that don't use them don't change.
+    if args.common.fips == "true" {
+        inputs.value("fips", "true");
+    }
+    if let Some(epoch) = args.common.source_date_epoch() {
+        inputs.value("source-date-epoch", epoch.to_string());
+    }
+    if args.hermetic_packages == "true" || manifest.info().hermetic() {
+        inputs.value("hermetic", "true");
+    }
+    if !args::run_checks(args.run_checks.as_deref(), manifest.info().run_checks()) {
+        inputs.value("run-checks", "false");
+    }
+    if args.debuginfo != "true" {
+        inputs.value("debuginfo", "false");
+    }
+
+    inputs
+        .file("Cargo.toml", "Cargo.toml")
+        .context(error::RemoteCacheSnafu)?;
+    inputs.file("spec", spec).context(error::RemoteCacheSnafu)?;
+    for f in info.sources.iter().chain(info.patches.iter()) {
+        inputs
+            .file(format!("source/{}", f.display()), f)
+            .context(error::RemoteCacheSnafu)?;
+    }
+    for f in manifest.info().external_files().into_iter().flatten() {
+        inputs.value(format!("external-file/{}", f.url), &f.sha512);
+        if let Some(options) = external_file_options(f) {
+            inputs.value(format!("external-file-options/{}", f.url), options);
+        }
+        for patch in f.patches.iter().flatten() {
+            inputs
+                .file(format!("external-patch/{}", patch.display()), patch)
+                .context(error::RemoteCacheSnafu)?;
+        }
+    }
+    if let Some(fragment) = manifest.info().dockerfile_fragment() {
+        inputs
+            .file("dockerfile-fragment", &fragment.path)
+            .context(error::RemoteCacheSnafu)?;
+    }
+    let generated_changelog =
+        changelog::generate::path(&args.common.state_dir, manifest.info().package_name());
+    if manifest.info().generate_changelog() && generated_changelog.is_file() {
+        inputs
+            .file("generated-changelog", &generated_changelog)
+            .context(error::RemoteCacheSnafu)?;
+    }
+    for f in source_group_files {
+        let name = f.strip_prefix(&args.sources_dir).unwrap_or(f);
+        inputs
+            .file(format!("source-group/{}", name.display()), f)
+            .context(error::RemoteCacheSnafu)?;
+    }
+
+    // The RPMs of the packages we depend on are installed into the build, so they are inputs too.
+    for dependency in manifest
+        .package_dependencies()
+        .context(error::ManifestParseSnafu)?
+    {
+        inputs
+            .dir(
+                format!("dependency/{dependency}"),
+                args.packages_dir.join(&dependency),
+            )
+            .context(error::RemoteCacheSnafu)?;
+    }
+    inputs
+        .file(
+            "external-kits",
+            args.common.root_dir.join(EXTERNAL_KIT_METADATA),
+        )
+        .context(error::RemoteCacheSnafu)?;
+
+    Ok(inputs)
+}
+
+/// The options that change where an external file ends up in the build, and what it becomes, such
+/// as whether it's extracted. `None` if it has none of them.
+fn external_file_options(f: &ExternalFile) -> Option<String> {
+    let options = [
+        ("path", f.path.as_ref().map(|p| p.display().to_string())),
+        ("extract", f.extract.map(|e| e.to_string())),
+        (
+            "strip-components",
+            f.strip_components.map(|n| n.to_string()),
+        ),
+        ("rename", f.rename.as_ref().map(|p| p.display().to_string())),
+        (
+            "bundle-modules",
+            f.bundle_modules.as_ref().map(|m| format!("{m:?}")),
+        ),
+        (
+            "bundle-root-path",
+            f.bundle_root_path.as_ref().map(|p| p.display().to_string()),
+        ),
+        (
+            "bundle-output-path",
+            f.bundle_output_path
+                .as_ref()
+                .map(|p| p.display().to_string()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|value| format!("{name}={value}")))
+    .collect::<Vec<_>>();
+    (!options.is_empty()).then(|| options.join(" "))
 }
 
 fn build_kit(args: BuildKitArgs) -> Result<()> {
     let manifest_file = "Cargo.toml";
-    println!("cargo:rerun-if-changed={}", manifest_file);
-    println!(
-        "cargo:rerun-if-changed={}",
-        args.common.root_dir.join(EXTERNAL_KIT_METADATA).display()
-    );
+    rerun::file(manifest_file);
+    rerun::file(args.common.root_dir.join(EXTERNAL_KIT_METADATA));
 
     let manifest = Manifest::new(
         args.common.cargo_manifest_dir.join(manifest_file),
         &args.common.cargo_metadata_path,
     )
     .context(error::ManifestParseSnafu)?;
+    args::rerun_for_envs(BuildType::Kit, manifest.info());
 
     if args.common.cicd_hack {
         return Ok(());
     }
 
+    let _docker = info_span!("docker").entered();
     DockerBuild::new_kit(args, &manifest)
         .context(error::BuilderInstantiationSnafu)?
         .build()
@@ -233,28 +895,164 @@ fn build_kit(args: BuildKitArgs) -> Result<()> {
 
 fn build_variant(args: BuildVariantArgs) -> Result<()> {
     let manifest_file = "Cargo.toml";
-    println!("cargo:rerun-if-changed={}", manifest_file);
-    println!(
-        "cargo:rerun-if-changed={}",
-        args.common.root_dir.join(EXTERNAL_KIT_METADATA).display()
-    );
+    rerun::file(manifest_file);
+    rerun::file(args.common.root_dir.join(EXTERNAL_KIT_METADATA));
 
-    let manifest = Manifest::new(
+    let mut manifest = Manifest::new(
         args.common.cargo_manifest_dir.join(manifest_file),
         &args.common.cargo_metadata_path,
     )
     .context(error::ManifestParseSnafu)?;
+    args::rerun_for_envs(BuildType::Variant, manifest.info());
+    // Overrides apply to everything that depends on image features, such as the packages.
+    manifest.override_image_features(&args.enable_features, &args.disable_features);
+    let feature_overrides = args.feature_overrides();
+    if !feature_overrides.is_empty() {
+        println!(
+            "cargo:warning=Building with image feature overrides that aren't in the manifest: {}",
+            feature_overrides.join(" ")
+        );
+    }
 
     check_arch_support(manifest.info(), args.common.arch);
 
+    for overlay in manifest.info().file_overlays().into_iter().flatten() {
+        if let Some(source) = &overlay.source {
+            rerun::file(source);
+        }
+    }
+    rerun_for_hooks(&args.common, &args.hooks, &Stage::VARIANT_BUILD);
+    if args.changelog_baseline.is_some() {
+        changelog::rerun_for_git(&args.common.root_dir);
+    }
+
     if args.common.cicd_hack {
         return Ok(());
     }
 
-    DockerBuild::new_variant(args, &manifest)
-        .context(error::BuilderInstantiationSnafu)?
-        .build()
-        .context(error::BuildAttemptSnafu)
+    // The builder takes the arguments, so keep what the changelog and hooks need.
+    let variant = args
+        .common
+        .cargo_manifest_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let arch = args.common.arch.to_string();
+    let fips = manifest
+        .info()
+        .image_features()
+        .unwrap_or_default()
+        .contains(&ImageFeature::Fips);
+    if fips && args.common.fips != "true" {
+        println!(
+            "cargo:warning={variant} has the 'fips' image feature, but BUILDSYS_FIPS isn't set, so \
+            the project's packages were built without their FIPS-validated programs"
+        );
+    }
+    // The packages depend on the architecture and the image features, so settle them once for
+    // both the build and the changelog.
+    let packages = manifest.info().included_packages_for(args.common.arch);
+
+    // Kernel module kits only work with the kernel they were built against.
+    let mut kits = Vec::new();
+    for kit in manifest
+        .kit_dependencies()
+        .context(error::ManifestParseSnafu)?
+    {
+        let kit_dir = args.kits_dir.join(&kit).join(&arch);
+        kits.push((kit, kit_dir));
+    }
+    let external_kits = ExternalKitMetadataView::load(&args.common.root_dir)
+        .context(error::ManifestParseSnafu)?
+        .list();
+    for kit in external_kits {
+        let kit_dir = args.external_kits_dir.join(&kit).join(&arch);
+        kits.push((kit, kit_dir));
+    }
+    kmod::check_variant(&kits, &packages, &args.packages_dir).context(error::KernelModulesSnafu)?;
+
+    let host = HostEnvironment::detect(
+        args.common.twoliter_version.clone(),
+        args::redacted(args.settings()),
+    );
+    let (enabled_features, disabled_features) =
+        (args.enable_features.clone(), args.disable_features.clone());
+    let changelog_baseline = args.changelog_baseline.clone();
+    let changelog_path = variant_changelog_path(&args.image_dir, &arch, &variant);
+    let root_dir = args.common.root_dir.clone();
+    let output_dir = variant_images_dir(&args.image_dir, &arch, &variant)
+        .join(format!("{}-{}", args.version_image, args.version_build));
+
+    let mut inputs = BuildInputs::default();
+    inputs
+        .value("variant", &variant)
+        .value("arch", &arch)
+        .dir("variant", &args.common.cargo_manifest_dir)
+        .context(error::RemoteCacheSnafu)?;
+    if !feature_overrides.is_empty() {
+        inputs.value("image-feature-overrides", feature_overrides.join(" "));
+    }
+    for overlay in manifest.info().file_overlays().into_iter().flatten() {
+        if let Some(source) = &overlay.source {
+            inputs
+                .file(format!("overlay/{}", source.display()), source)
+                .context(error::RemoteCacheSnafu)?;
+        }
+    }
+
+    let hooks = args.hooks.clone().unwrap_or_default();
+    let sdk = args.common.sdk_image.clone();
+    let target = Target {
+        root: &root_dir,
+        kind: "variant",
+        name: &variant,
+        arch: Some(&arch),
+        sdk: &sdk,
+        key: Some(inputs.key()),
+        envs: vec![("TWOLITER_HOOK_OUTPUT_DIR", output_dir.display().to_string())],
+    };
+    hooks
+        .run(Stage::PreVariantBuild, &target)
+        .context(error::HookSnafu)?;
+
+    info_span!("docker").in_scope(|| {
+        DockerBuild::new_variant(args, &manifest, &packages)
+            .context(error::BuilderInstantiationSnafu)?
+            .build()
+            .context(error::BuildAttemptSnafu)
+    })?;
+
+    host.record(&output_dir).context(error::BuildHostSnafu)?;
+    host::record_feature_overrides(&output_dir, &enabled_features, &disabled_features)
+        .context(error::BuildHostSnafu)?;
+    settings_defaults::export(&output_dir).context(error::SettingsDefaultsSnafu)?;
+    kmod::checksum_kit(&output_dir).context(error::KernelModulesSnafu)?;
+
+    if let Some(baseline) = changelog_baseline {
+        let packages = manifest
+            .package_dirs(&packages)
+            .context(error::ManifestParseSnafu)?;
+        Changelog::collect(&root_dir, &baseline, &variant, &arch, &packages)
+            .and_then(|changelog| changelog.write(&changelog_path))
+            .context(error::ChangelogSnafu)?;
+    }
+
+    hooks
+        .run(Stage::PostVariantBuild, &target)
+        .context(error::HookSnafu)?;
+
+    let vars = args::watched_env_values(BuildType::Variant, manifest.info());
+    if let Err(e) = inputs.save(
+        &root_dir,
+        BuildType::Variant,
+        &variant,
+        &arch,
+        &vars,
+        &packages,
+    ) {
+        println!("cargo:warning=Unable to record the inputs of this build: {e}");
+    }
+    Ok(())
 }
 
 fn repack_variant(args: RepackVariantArgs) -> Result<()> {
@@ -265,6 +1063,7 @@ fn repack_variant(args: RepackVariantArgs) -> Result<()> {
         &args.common.cargo_metadata_path,
     )
     .context(error::ManifestParseSnafu)?;
+    args::rerun_for_envs(BuildType::Repack, manifest.info());
 
     check_arch_support(manifest.info(), args.common.arch);
 
@@ -272,12 +1071,20 @@ fn repack_variant(args: RepackVariantArgs) -> Result<()> {
         return Ok(());
     }
 
+    let _docker = info_span!("docker").entered();
     DockerBuild::repack_variant(args, &manifest)
         .context(error::BuilderInstantiationSnafu)?
         .build()
         .context(error::BuildAttemptSnafu)
 }
 
+/// Asks cargo to run the build again when an input of the project's hooks for `stages` changes.
+fn rerun_for_hooks(common: &Common, hooks: &Option<Hooks>, stages: &[Stage]) {
+    for input in hooks.iter().flat_map(|hooks| hooks.inputs(stages)) {
+        rerun::file(common.root_dir.join(input));
+    }
+}
+
 /// Ensure that the current arch is supported by the current variant
 fn check_arch_support(manifest: &ManifestInfo, arch: SupportedArch) {
     if let Some(supported_arches) = manifest.supported_arches() {
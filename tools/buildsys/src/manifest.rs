@@ -71,6 +71,76 @@ bundle-root-path = "path/to/module"
 bundle-output-path = "path/to/output.tar.gz"
 ```
 
+`url` can also be a `file://` URL, to use a local file such as an upstream
+tarball that isn't published yet. It's copied rather than downloaded, and must
+match `sha512` like any other file. The lookaside cache can be a local
+directory too, set with `BUILDSYS_LOOKASIDE_CACHE`.
+```ignore
+[[package.metadata.build-package.external-files]]
+url = "file:///home/builder/src/foo-1.1.tar.gz"
+sha512 = "abcdef"
+```
+
+To try out a file without changing the manifest, put it in the directory set
+with `BUILDSYS_SOURCE_OVERRIDES_DIR`, under the name that buildsys would save it
+as. A file there is used instead of fetching, as long as it matches `sha512`,
+or regardless with `BUILDSYS_ALLOW_UNVERIFIED_OVERRIDES=true`.
+
+`mirrors` is an optional list of alternate URLs for an external file. When the
+file can't be fetched from the lookaside cache and upstream sources are allowed,
+`url` is tried first, then each mirror in order, until one of them provides a
+file matching `sha512`.
+```ignore
+[[package.metadata.build-package.external-files]]
+url = "https://foo.example.com/foo-1.0.tar.gz"
+mirrors = [
+    "https://mirror.example.com/foo/foo-1.0.tar.gz",
+    "https://archive.example.com/foo-1.0.tar.gz",
+]
+sha512 = "abcdef"
+```
+
+`git-commit` turns an external file into a git source: `url` is a git repository,
+and buildsys clones it at the given commit and archives it to `path`, which is
+required. `git-tag` is optional; when set, the tag is fetched instead of the
+commit, and must point at `git-commit`. The archive is reproducible, so `sha512`
+is its hash, and it can be uploaded to the lookaside cache like any other file.
+The top-level directory in the archive is `path` without its tar extension.
+```ignore
+[[package.metadata.build-package.external-files]]
+path = "foo-1.0.tar.gz"
+url = "https://github.com/example/foo.git"
+git-commit = "0123456789abcdef0123456789abcdef01234567"
+git-tag = "v1.0"
+sha512 = "abcdef"
+```
+
+`patches` is an optional list of patch files, relative to the package directory,
+to apply to an archive after it is fetched. Each is applied with `patch -p1` from
+the archive's top-level directory, and the result is written to `patched-<path>`,
+which the spec can use as its source instead of carrying the patches itself.
+The original archive is kept, and is what `bundle-modules` works from.
+```ignore
+[[package.metadata.build-package.external-files]]
+url = "https://foo.example.com/foo-1.0.tar.gz"
+sha512 = "abcdef"
+patches = ["patches/fix-build.patch"]
+```
+
+`extract = true` unpacks an archive after it is fetched and patched, into a
+directory next to it that the package build copies into `SOURCES`, so that the
+spec doesn't need to unpack it in `%prep`. `rename` names the directory, which
+otherwise is the archive's name without its extension, and `strip-components`
+removes that many leading path components from each entry, like `tar` does.
+```ignore
+[[package.metadata.build-package.external-files]]
+url = "https://foo.example.com/foo-1.0.tar.gz"
+sha512 = "abcdef"
+extract = true
+strip-components = 1
+rename = "foo"
+```
+
 `package-name` lets you override the package name in Cargo.toml; this is useful
 if you have a package with "." in its name, for example, which Cargo doesn't
 allow.  This means the directory name and spec file name can use your preferred
@@ -80,6 +150,115 @@ naming.
 package-name = "better.name"
 ```
 
+`hermetic` runs the package build without network access, to prove that the
+build only depends on the sources buildsys has already fetched. The few build
+steps that need to talk to buildsys itself are allowed through. This can be
+enforced for every package by setting `BUILDSYS_HERMETIC_PACKAGES=true`.
+```ignore
+[package.metadata.build-package]
+hermetic = true
+```
+
+`run-checks = false` skips the spec's `%check` section, which otherwise runs as
+part of the package build, so that a failing test suite fails the build.
+Setting `BUILDSYS_RUN_CHECKS` to `true` or `false` runs or skips the checks of
+every package, whatever their manifests say. The checks are stopped after
+`BUILDSYS_CHECK_TIMEOUT` seconds, one hour by default.
+```ignore
+[package.metadata.build-package]
+run-checks = false
+```
+
+Changes to a few buildsys environment variables, such as `BUILDSYS_ARCH`,
+rerun every package build. `rerun-if-env-changed` lists more variables that
+should rerun the build of this package, and `ignore-env-changes` lists
+variables whose changes it doesn't care about.
+```ignore
+[package.metadata.build-package]
+rerun-if-env-changed = ["GOPROXY"]
+ignore-env-changes = ["BUILDSYS_HERMETIC_PACKAGES"]
+```
+
+`changelog` names a Markdown file, relative to the package directory, that
+holds the package's changelog, with each entry under a `## ` heading. Without
+it, the `%changelog` section of the spec is used. Variant builds collect new
+entries from either when `BUILDSYS_CHANGELOG_BASELINE` is set.
+```ignore
+[package.metadata.build-package]
+changelog = "CHANGELOG.md"
+```
+
+`generate-changelog` adds an entry to the RPM's `%changelog` for each git commit
+that touched the package directory since the spec's `Version` last changed,
+starting with the commit that changed it. The generated entries come before any
+in the spec. Projects built from outside a git repository get none.
+```ignore
+[package.metadata.build-package]
+generate-changelog = true
+```
+
+`dockerfile-fragment` names a Dockerfile, relative to the package directory,
+with build stages that the package needs and the shared Dockerfile doesn't
+provide, such as one that fetches a toolchain component. buildsys splices the
+fragment into the shared Dockerfile for this package's build only. `stage` is
+the fragment's stage whose `/fragment` directory is mounted read-only at
+`/home/builder/fragment` while the spec is built. Each of `args` is passed to
+the build as `FRAGMENT_<name>`, so the fragment declares it with that name, and
+it can't override the arguments that buildsys passes to every build.
+```ignore
+[package.metadata.build-package.dockerfile-fragment]
+path = "toolchain.Dockerfile"
+stage = "toolchain"
+args = { TOOLCHAIN_VERSION = "1.2.3" }
+```
+
+The build context that Docker receives for a package holds only what its build
+reads: the spec, `Twoliter.toml`, and the tools that run in the build. The
+package's directory is added when it has a Dockerfile fragment, and `.cargo`
+and `sources` are added when it has `source-groups`. Everything else the build
+needs is reached through buildsys rather than the context. `context-paths` adds
+more paths, relative to the project root, for builds that read them from `/host`.
+```ignore
+[package.metadata.build-package]
+context-paths = ["licenses"]
+```
+
+`kernel-module` marks a package that builds kernel modules out of tree, and names
+the kernel package and version that they're built against. The build defines
+`%_cross_kmod_kernel` and `%_cross_kmod_kernel_version` for the spec, and
+`%kmod_requires`, which expands to a `BuildRequires` on the kernel's `-devel`
+package and a `Requires` on the kernel, both at that version. The kernel package
+must also be one of the package's dependencies, so that it's built first. A kit
+with these packages records the kernel it targets, and variants that use the kit
+must include that kernel.
+```ignore
+[package.metadata.build-package.kernel-module]
+kernel = "kernel-6.1"
+version = "6.1.102"
+```
+
+`check-devices` gives the package build access to devices of the host, for test
+suites in `%check` that need hardware such as a GPU. `devices` are CDI device
+names, and `gpus` are the NVIDIA GPUs to expose, by index or `all`, which are
+the CDI devices `nvidia.com/gpu=<gpu>`. Each device must be allowed by the
+project, in the `devices` table of `Twoliter.toml`, which twoliter passes to
+buildsys as `BUILDSYS_ALLOWED_DEVICES`. The host needs a BuildKit that supports
+CDI devices.
+```ignore
+[package.metadata.build-package.check-devices]
+devices = ["vendor.com/fpga=0"]
+gpus = ["all"]
+```
+
+`maintainers` names the people or teams that own the package, such as the
+handles in a CODEOWNERS file. When the package fails to build, they are named
+in the error, in twoliter's summary of the build, and in its JSON report, so
+that CI can send the failure to them.
+```ignore
+[package.metadata.build-package]
+maintainers = ["@bottlerocket-os/kernel"]
+```
+
 `releases-url` is ignored by buildsys, but can be used by packager maintainers
 to indicate a good URL for checking whether the software has had a new release.
 ```ignore
@@ -106,6 +285,10 @@ another-kit = { path = "../../kits/another-kit" }
 some-package = { path = "../../packages/some-package" }
 ```
 
+A kit whose packages build kernel modules, as described for `kernel-module`, is a kernel module
+kit. All of its kernel modules must be built against the same kernel, which the kit records in
+`metadata/kernel-modules.json` next to its repository.
+
 ## Metadata for variants
 
 `included-packages` is a list of packages that should be included in a variant.
@@ -114,6 +297,19 @@ some-package = { path = "../../packages/some-package" }
 included-packages = ["release"]
 ```
 
+`excluded-packages` is a list of packages to leave out of a variant, for packages that only make
+sense on some architectures. An entry is either the name of a package, which is left out for
+every architecture, or a table with the `name` of the package and the `arches` to leave it out for.
+The packages that remain are the ones built into the image and listed in the changelog.
+```ignore
+[package.metadata.build-variant]
+included-packages = ["release", "amazon-ssm-agent", "nvidia-k8s-device-plugin"]
+excluded-packages = [
+    "amazon-ssm-agent",
+    { name = "nvidia-k8s-device-plugin", arches = ["aarch64"] },
+]
+```
+
 `image-format` is the desired format for the built images.
 This can be `raw` (the default), `vmdk`, or `qcow2`.
 ```ignore
@@ -153,6 +349,11 @@ publish-image-size-hint-gib = 22
 partition-plan = "split"
 ```
 
+Buildsys checks the layout before it builds the variant. Both images must be at least 1 GiB, and
+the root filesystem must be large enough for the RPMs of the `included-packages`, after they are
+installed. Their dependencies aren't counted, so a layout that passes the check can still be too
+small, but one that fails it can never work.
+
 `supported-arches` is the list of architectures the variant is able to run on.
 The values can be `x86_64` and `aarch64`.
 If not specified, the variant can run on any of those architectures.
@@ -162,12 +363,29 @@ supported-arches = ["x86_64"]
 ```
 
 `kernel-parameters` is a list of extra parameters to be added to the kernel command line.
-The given parameters are inserted at the start of the command line.
+The given parameters are inserted at the start of the command line. Each one is a single
+parameter, which can only have spaces inside double quotes, as in `dyndbg="module foo +p"`; the
+`--` separator, single quotes, backslashes, `$` and backticks aren't allowed.
 ```ignore
 [package.metadata.build-variant]
 kernel-parameters = [
    "console=ttyS42",
 ]
+```
+
+`boot-config` adds to the bootconfig that the variant's packages provide, for parameters of the
+kernel and of init that the variant sets at boot. It has a `kernel` and an `init` table, whose keys
+are the parameters, in dotted form or as nested tables; each value is a string, number or boolean,
+or a list of them for parameters that are given more than once. The variant must enable the
+`grub-set-private-var` image feature, which bootconfig needs. A parameter that a package's
+bootconfig also sets fails the image build.
+```ignore
+[package.metadata.build-variant.boot-config.kernel]
+console = ["tty0", "ttyS0,115200n8"]
+
+[package.metadata.build-variant.boot-config.init]
+systemd.log_level = "debug"
+```
 
 `image-features` is a map of image feature flags, which can be enabled or disabled. This allows us
 to conditionally use or exclude certain image-level features in variants.
@@ -232,13 +450,77 @@ uefi-secure-boot = true
 
 `fips` means that FIPS-certified modules will be used for cryptographic operations. This affects
 the kernel at runtime. It also causes alternate versions of Go and Rust programs that use
-FIPS-compliant ciphers to be included in the image.
+FIPS-compliant ciphers to be included in the image. Packages build those versions when
+`BUILDSYS_FIPS` is set for the project, which they see as the `fips` bcond, and the variant's
+metadata package provides `image-feature(fips)` for them to be selected by. The variant's
+`build-metadata.json` and package inventory record whether the image uses FIPS.
 
 ```ignore
 [package.metadata.build-variant.image-features]
 fips = true
 ```
 
+A build can enable or disable image features without changing the manifest, such as to try out a
+feature, with `twoliter build variant --enable-feature` and `--disable-feature`. These reach
+buildsys as `BUILDSYS_ENABLE_FEATURES` and `BUILDSYS_DISABLE_FEATURES`, and are recorded as
+`image-feature-overrides` in the variant's `build-metadata.json`.
+
+`feature-packages` is a map from image features to packages that are only included in the variant
+when the feature is enabled. They're added to `included-packages`, and `excluded-packages` still
+applies to them.
+```ignore
+[package.metadata.build-variant.feature-packages]
+fips = ["openssl-fips-provider"]
+```
+
+`file-overlays` is a list of files and directories to install into the image as it is assembled,
+for small customizations that don't call for a package of their own. `source` is a file or
+directory in the variant's directory, and directories are copied with everything in them; an entry
+without a `source` creates an empty directory. `path` is where it is installed, as an absolute path
+in the `root` filesystem (the default) or in the `data` partition, which is mounted at `/local`.
+`owner` and `group` are numeric IDs that default to 0, and apply to everything the entry installs.
+`mode` is an octal string that applies to `path` itself, and defaults to "0644" for files and
+"0755" for directories; files copied from a source directory keep their own modes.
+```ignore
+[[package.metadata.build-variant.file-overlays]]
+source = "files/motd"
+path = "/etc/motd"
+
+[[package.metadata.build-variant.file-overlays]]
+path = "/example"
+partition = "data"
+owner = 1000
+group = 1000
+mode = "0750"
+```
+
+Buildsys checks the overlays before it builds the variant. Paths must be absolute and can't use
+`..`, sources must exist in the variant's directory, and no path can be given twice.
+
+`migrations` names the packages with the variant's data store migrations, which install them to
+`/usr/share/migrations`. They aren't installed in the image; the variant build compresses each one
+and archives them in `<image name>-migrations.tar`, next to the images, and `pubsys repo` adds the
+ones that `Release.toml` lists to the repo as targets. Migrations must be named
+`migrate_v<version>_<name>`, where the version is the one that they migrate to. The packages default
+to `["migrations"]`.
+```ignore
+[package.metadata.build-variant.migrations]
+packages = ["migrations", "my-migrations"]
+```
+
+## Tool versions
+
+`required-buildsys-version` and `required-twoliter-version` can be set for packages, kits and
+variants, to the versions of buildsys and twoliter that can build them, as semver requirements.
+Buildsys checks them before it builds anything, and fails with a hint to upgrade, so that a manifest
+that uses newer keys isn't quietly misbuilt by an older buildsys that doesn't know them. Buildsys is
+installed with twoliter, so both are met by upgrading twoliter.
+```ignore
+[package.metadata.build-package]
+required-buildsys-version = ">=0.2.0"
+required-twoliter-version = ">=0.6.0"
+```
+
 */
 
 mod error;
@@ -247,15 +529,17 @@ use crate::BuildType;
 use buildsys_config::EXTERNAL_KIT_METADATA;
 use guppy::graph::{DependencyDirection, PackageGraph, PackageLink, PackageMetadata};
 use guppy::{CargoMetadata, PackageId};
+use semver::VersionReq;
 use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use std::cmp::max;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt::{self, Display};
 use std::fs;
 use std::fs::read;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Debug, Snafu)]
 pub struct Error(error::Error);
@@ -318,6 +602,37 @@ impl Manifest {
         Ok(packages)
     }
 
+    /// The directories of the project's packages that are named in `packages`, and of the packages
+    /// they depend on, by package name. Packages that aren't in the project, such as those from
+    /// external kits, are left out.
+    pub fn package_dirs(&self, packages: &[String]) -> Result<BTreeMap<String, PathBuf>> {
+        let ids = self
+            .graph
+            .packages()
+            .filter(|p| p.in_workspace() && is_manifest_type(p, BuildType::Package))
+            .filter(|p| packages.contains(&get_buildsys_package_name(p)))
+            .map(|p| p.id())
+            .collect::<Vec<_>>();
+        let Some(first) = ids.first() else {
+            return Ok(BTreeMap::new());
+        };
+        let query = self.graph.query_forward(ids.iter().copied()).context(
+            error::CargoPackageQuerySnafuSnafu {
+                id: (*first).clone(),
+            },
+        )?;
+        let package_set =
+            query.resolve_with_fn(|_, link| is_manifest_type(&link.to(), BuildType::Package));
+        Ok(package_set
+            .packages(DependencyDirection::Forward)
+            .filter(|p| p.in_workspace())
+            .filter_map(|p| {
+                let dir = p.manifest_path().parent()?.as_std_path().to_path_buf();
+                Some((get_buildsys_package_name(&p), dir))
+            })
+            .collect())
+    }
+
     /// List all kits needed for the build.
     pub fn kit_dependencies(&self) -> Result<Vec<String>> {
         let name = self.info().manifest_name();
@@ -342,6 +657,11 @@ impl Manifest {
     pub fn info(&self) -> &ManifestInfo {
         &self.manifest_info
     }
+
+    /// See [`ManifestInfo::override_image_features`].
+    pub fn override_image_features(&mut self, enable: &[ImageFeature], disable: &[ImageFeature]) {
+        self.manifest_info.override_image_features(enable, disable)
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -405,16 +725,160 @@ impl ManifestInfo {
         &self.package.name
     }
 
+    /// The keys in the manifest's build metadata that buildsys doesn't recognize, such as
+    /// `build-package.hermetc`. Keys under `package.metadata` that belong to other tools are
+    /// left alone.
+    pub fn unknown_keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        let Some(metadata) = &self.package.metadata else {
+            return keys;
+        };
+        let mut add = |prefix: &str, unknown: &BTreeMap<String, toml::Value>| {
+            keys.extend(unknown.keys().map(|key| format!("{prefix}.{key}")));
+        };
+        if let Some(build_package) = &metadata.build_package {
+            add("build-package", &build_package.unknown);
+            for (i, f) in build_package.external_files.iter().flatten().enumerate() {
+                add(&format!("build-package.external-files[{i}]"), &f.unknown);
+            }
+            if let Some(fragment) = &build_package.dockerfile_fragment {
+                add("build-package.dockerfile-fragment", &fragment.unknown);
+            }
+            if let Some(kernel_module) = &build_package.kernel_module {
+                add("build-package.kernel-module", &kernel_module.unknown);
+            }
+            if let Some(check_devices) = &build_package.check_devices {
+                add("build-package.check-devices", &check_devices.unknown);
+            }
+        }
+        if let Some(build_kit) = &metadata.build_kit {
+            add("build-kit", &build_kit.unknown);
+        }
+        if let Some(build_variant) = &metadata.build_variant {
+            add("build-variant", &build_variant.unknown);
+            if let Some(boot_config) = &build_variant.boot_config {
+                add("build-variant.boot-config", &boot_config.unknown);
+            }
+            if let Some(migrations) = &build_variant.migrations {
+                add("build-variant.migrations", &migrations.unknown);
+            }
+            for (i, f) in build_variant.file_overlays.iter().flatten().enumerate() {
+                add(&format!("build-variant.file-overlays[{i}]"), &f.unknown);
+            }
+        }
+        keys
+    }
+
     /// Convenience method to return the list of source groups.
     pub fn source_groups(&self) -> Option<&Vec<PathBuf>> {
         self.build_package().and_then(|b| b.source_groups.as_ref())
     }
 
+    /// Convenience method to return the Markdown changelog of a package, if it has one.
+    pub fn changelog(&self) -> Option<&PathBuf> {
+        self.build_package().and_then(|b| b.changelog.as_ref())
+    }
+
+    /// Convenience method to return whether changelog entries should be generated from git.
+    pub fn generate_changelog(&self) -> bool {
+        self.build_package()
+            .and_then(|b| b.generate_changelog)
+            .unwrap_or(false)
+    }
+
+    /// Convenience method to return whether the package should be built without network access.
+    pub fn hermetic(&self) -> bool {
+        self.build_package()
+            .and_then(|b| b.hermetic)
+            .unwrap_or(false)
+    }
+
+    /// Convenience method to return whether the package asks for the spec's `%check` to run or
+    /// to be skipped, if it says either.
+    pub fn run_checks(&self) -> Option<bool> {
+        self.build_package().and_then(|b| b.run_checks)
+    }
+
+    /// Convenience method to return the environment variables whose changes should rerun the
+    /// package build, in addition to the ones buildsys always tracks.
+    pub fn rerun_if_env_changed(&self) -> &[String] {
+        self.build_package()
+            .and_then(|b| b.rerun_if_env_changed.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Convenience method to return the environment variables whose changes should not rerun the
+    /// package build, even if buildsys would otherwise track them.
+    pub fn ignore_env_changes(&self) -> &[String] {
+        self.build_package()
+            .and_then(|b| b.ignore_env_changes.as_deref())
+            .unwrap_or_default()
+    }
+
     /// Convenience method to return the list of external files.
     pub fn external_files(&self) -> Option<&Vec<ExternalFile>> {
         self.build_package().and_then(|b| b.external_files.as_ref())
     }
 
+    /// Convenience method to return the paths to add to the package's build context.
+    pub fn context_paths(&self) -> &[PathBuf] {
+        self.build_package()
+            .and_then(|b| b.context_paths.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// The versions of buildsys that can build this, if the manifest limits them.
+    pub fn required_buildsys_version(&self) -> Option<&VersionReq> {
+        self.build_package()
+            .and_then(|b| b.required_buildsys_version.as_ref())
+            .or_else(|| {
+                self.build_kit()
+                    .and_then(|b| b.required_buildsys_version.as_ref())
+            })
+            .or_else(|| {
+                self.build_variant()
+                    .and_then(|b| b.required_buildsys_version.as_ref())
+            })
+    }
+
+    /// The versions of twoliter that can build this, if the manifest limits them.
+    pub fn required_twoliter_version(&self) -> Option<&VersionReq> {
+        self.build_package()
+            .and_then(|b| b.required_twoliter_version.as_ref())
+            .or_else(|| {
+                self.build_kit()
+                    .and_then(|b| b.required_twoliter_version.as_ref())
+            })
+            .or_else(|| {
+                self.build_variant()
+                    .and_then(|b| b.required_twoliter_version.as_ref())
+            })
+    }
+
+    /// Convenience method to return the package's Dockerfile fragment, if it has one.
+    pub fn dockerfile_fragment(&self) -> Option<&DockerfileFragment> {
+        self.build_package()
+            .and_then(|b| b.dockerfile_fragment.as_ref())
+    }
+
+    /// Convenience method to return the kernel that the package's kernel modules are built
+    /// against, if it builds any.
+    pub fn kernel_module(&self) -> Option<&KernelModule> {
+        self.build_package().and_then(|b| b.kernel_module.as_ref())
+    }
+
+    /// Convenience method to return the people or teams that own the package, if any are listed.
+    pub fn maintainers(&self) -> &[String] {
+        self.build_package()
+            .and_then(|b| b.maintainers.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Convenience method to return the devices that the package build can use, if any.
+    pub fn check_devices(&self) -> Option<&CheckDevices> {
+        self.build_package().and_then(|b| b.check_devices.as_ref())
+    }
+
     /// Convenience method to return the package name. If the manifest has an override in the
     /// `package.metadata.build-package.package-name` key, it is returned, otherwise the Cargo
     /// manifest name is returned from `package.name`.
@@ -462,6 +926,53 @@ impl ManifestInfo {
             .and_then(|b| b.included_packages.as_ref())
     }
 
+    /// Convenience method to return the list of excluded packages.
+    pub fn excluded_packages(&self) -> Option<&Vec<ExcludedPackage>> {
+        self.build_variant()
+            .and_then(|b| b.excluded_packages.as_ref())
+    }
+
+    /// Convenience method to return the file overlays for this variant.
+    pub fn file_overlays(&self) -> Option<&Vec<FileOverlay>> {
+        self.build_variant().and_then(|b| b.file_overlays.as_ref())
+    }
+
+    /// Convenience method to return the packages that depend on image features.
+    pub fn feature_packages(&self) -> Option<&HashMap<ImageFeature, Vec<String>>> {
+        self.build_variant()
+            .and_then(|b| b.feature_packages.as_ref())
+    }
+
+    /// Returns the included packages, with the ones for enabled image features, and without the
+    /// ones excluded for `arch`.
+    pub fn included_packages_for(&self, arch: SupportedArch) -> Vec<String> {
+        let mut packages = self.included_packages().cloned().unwrap_or_default();
+        let features = self.enabled_image_features().unwrap_or_default();
+        if let Some(feature_packages) = self.feature_packages() {
+            // Sort by feature so that the list doesn't change from one build to the next.
+            let mut feature_packages = feature_packages
+                .iter()
+                .filter(|(feature, _)| features.contains(feature))
+                .collect::<Vec<_>>();
+            feature_packages.sort_by_key(|(feature, _)| feature.to_string());
+            for package in feature_packages.into_iter().flat_map(|(_, p)| p) {
+                if !packages.contains(package) {
+                    packages.push(package.clone());
+                }
+            }
+        }
+
+        let excluded = self.excluded_packages().cloned().unwrap_or_default();
+        packages
+            .into_iter()
+            .filter(|package| {
+                !excluded
+                    .iter()
+                    .any(|excluded| excluded.name() == package && excluded.excludes(arch))
+            })
+            .collect()
+    }
+
     /// Convenience method to return the image format override, if any.
     pub fn image_format(&self) -> Option<&ImageFormat> {
         self.build_variant().and_then(|b| b.image_format.as_ref())
@@ -484,8 +995,60 @@ impl ManifestInfo {
             .and_then(|b| b.kernel_parameters.as_ref())
     }
 
+    /// Checks that the kernel parameters for this variant can be added to its command line.
+    pub fn check_kernel_parameters(&self) -> Result<()> {
+        self.kernel_parameters()
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .try_for_each(check_kernel_parameter)
+    }
+
+    /// Convenience method to return the bootconfig for this variant.
+    pub fn boot_config(&self) -> Option<&BootConfig> {
+        self.build_variant().and_then(|b| b.boot_config.as_ref())
+    }
+
+    /// Convenience method to return the packages with the migrations for this variant.
+    pub fn migration_packages(&self) -> Vec<String> {
+        self.build_variant()
+            .and_then(|b| b.migrations.as_ref())
+            .map(|m| m.packages.clone())
+            .unwrap_or_else(Migrations::default_packages)
+    }
+
     /// Convenience method to return the enabled image features for this variant.
     pub fn image_features(&self) -> Option<HashSet<ImageFeature>> {
+        let features = self.enabled_image_features()?;
+        for experiment in EXPERIMENTAL_IMAGE_FEATURES {
+            if features.contains(experiment) {
+                println!("cargo:warning=Image feature {experiment} is experimental; use at your own risk!");
+            }
+        }
+        Some(features)
+    }
+
+    /// Enables and disables image features on top of the variant's `image-features`, for a build
+    /// that overrides them. Does nothing if this isn't a variant.
+    pub fn override_image_features(&mut self, enable: &[ImageFeature], disable: &[ImageFeature]) {
+        let Some(variant) = self
+            .package
+            .metadata
+            .as_mut()
+            .and_then(|m| m.build_variant.as_mut())
+        else {
+            return;
+        };
+        let features = variant.image_features.get_or_insert_with(HashMap::new);
+        for feature in enable {
+            features.insert(*feature, true);
+        }
+        for feature in disable {
+            features.insert(*feature, false);
+        }
+    }
+
+    fn enabled_image_features(&self) -> Option<HashSet<ImageFeature>> {
         let variant = self.build_variant()?;
         let mut features =
             HashSet::from([ImageFeature::InPlaceUpdates, ImageFeature::HostContainers]);
@@ -498,11 +1061,6 @@ impl ManifestInfo {
                 }
             }
         }
-        for experiment in EXPERIMENTAL_IMAGE_FEATURES {
-            if features.contains(experiment) {
-                println!("cargo:warning=Image feature {experiment} is experimental; use at your own risk!");
-            }
-        }
         Some(features)
     }
 
@@ -628,10 +1186,141 @@ struct Metadata {
 pub struct BuildPackage {
     pub external_files: Option<Vec<ExternalFile>>,
     pub package_name: Option<String>,
+    pub changelog: Option<PathBuf>,
+    pub generate_changelog: Option<bool>,
     pub releases_url: Option<String>,
+    pub maintainers: Option<Vec<String>>,
     pub source_groups: Option<Vec<PathBuf>>,
     pub variant_sensitive: Option<VariantSensitivity>,
     pub package_features: Option<Vec<ImageFeature>>,
+    pub hermetic: Option<bool>,
+    pub run_checks: Option<bool>,
+    pub rerun_if_env_changed: Option<Vec<String>>,
+    pub ignore_env_changes: Option<Vec<String>>,
+    pub dockerfile_fragment: Option<DockerfileFragment>,
+    pub context_paths: Option<Vec<PathBuf>>,
+    pub kernel_module: Option<KernelModule>,
+    pub check_devices: Option<CheckDevices>,
+    pub required_buildsys_version: Option<VersionReq>,
+    pub required_twoliter_version: Option<VersionReq>,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+/// The kernel that a package's out-of-tree kernel modules are built against.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct KernelModule {
+    /// The name of the kernel package, such as `kernel-6.1`.
+    pub kernel: String,
+    /// The kernel's version, without its release.
+    pub version: String,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+impl KernelModule {
+    /// Checks that the kernel and version can be used in the spec's dependencies.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.kernel.is_empty()
+                && self
+                    .kernel
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._+".contains(c)),
+            error::KernelModuleSnafu {
+                message: format!("'{}' isn't a package name", self.kernel),
+            }
+        );
+        ensure!(
+            !self.version.is_empty()
+                && self
+                    .version
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._+~^".contains(c)),
+            error::KernelModuleSnafu {
+                message: format!("'{}' isn't a version", self.version),
+            }
+        );
+        Ok(())
+    }
+}
+
+/// Devices of the host that a package build can use.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct CheckDevices {
+    /// CDI device names, such as `vendor.com/class=name`.
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// NVIDIA GPUs, by index or `all`.
+    #[serde(default)]
+    pub gpus: Vec<String>,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+impl CheckDevices {
+    /// The CDI names of every device, with the GPUs after the other devices.
+    pub fn cdi_devices(&self) -> Result<Vec<String>> {
+        let mut devices = Vec::new();
+        for device in &self.devices {
+            ensure!(
+                is_cdi_device(device),
+                error::CheckDeviceSnafu {
+                    device,
+                    message: "it isn't a CDI device name like 'vendor.com/class=name'",
+                }
+            );
+            devices.push(device.clone());
+        }
+        for gpu in &self.gpus {
+            ensure!(
+                gpu == "all" || (!gpu.is_empty() && gpu.chars().all(|c| c.is_ascii_digit())),
+                error::CheckDeviceSnafu {
+                    device: gpu,
+                    message: "GPUs are given by index or 'all'",
+                }
+            );
+            devices.push(format!("nvidia.com/gpu={gpu}"));
+        }
+        Ok(devices)
+    }
+}
+
+/// Whether `device` is a fully qualified CDI device name, `vendor/class=name`.
+pub fn is_cdi_device(device: &str) -> bool {
+    let Some((kind, name)) = device.split_once('=') else {
+        return false;
+    };
+    let Some((vendor, class)) = kind.split_once('/') else {
+        return false;
+    };
+    [vendor, class, name].iter().all(|part| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+    })
+}
+
+/// Build stages that a package adds to the shared Dockerfile.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct DockerfileFragment {
+    /// The fragment's path, relative to the package directory.
+    pub path: PathBuf,
+    /// The stage whose `/fragment` directory is available to the package build.
+    pub stage: String,
+    /// Build arguments for the fragment, by name without the `FRAGMENT_` prefix.
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -640,6 +1329,11 @@ pub struct BuildPackage {
 pub struct BuildKit {
     pub kit_name: Option<String>,
     pub vendor: String,
+    pub required_buildsys_version: Option<VersionReq>,
+    pub required_twoliter_version: Option<VersionReq>,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -663,12 +1357,252 @@ pub enum SensitivityType {
 #[serde(rename_all = "kebab-case")]
 pub struct BuildVariant {
     pub included_packages: Option<Vec<String>>,
+    pub excluded_packages: Option<Vec<ExcludedPackage>>,
     pub image_format: Option<ImageFormat>,
     #[serde(default)]
     pub image_layout: ImageLayout,
     pub supported_arches: Option<HashSet<SupportedArch>>,
     pub kernel_parameters: Option<Vec<String>>,
+    pub boot_config: Option<BootConfig>,
     pub image_features: Option<HashMap<ImageFeature, bool>>,
+    pub feature_packages: Option<HashMap<ImageFeature, Vec<String>>>,
+    pub file_overlays: Option<Vec<FileOverlay>>,
+    pub migrations: Option<Migrations>,
+    pub required_buildsys_version: Option<VersionReq>,
+    pub required_twoliter_version: Option<VersionReq>,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+/// Bootconfig parameters that a variant adds to the ones from its packages.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct BootConfig {
+    #[serde(default)]
+    pub kernel: toml::Table,
+    #[serde(default)]
+    pub init: toml::Table,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+impl BootConfig {
+    /// Renders the parameters as bootconfig, on a single line with `;` between them, after
+    /// checking that bootconfig can parse them.
+    pub fn render(&self) -> Result<String> {
+        let mut params = Vec::new();
+        flatten_boot_config(&mut params, "kernel", &self.kernel)?;
+        flatten_boot_config(&mut params, "init", &self.init)?;
+        Ok(params.join("; "))
+    }
+}
+
+/// The packages with a variant's data store migrations.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Migrations {
+    #[serde(default = "Migrations::default_packages")]
+    pub packages: Vec<String>,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+impl Migrations {
+    fn default_packages() -> Vec<String> {
+        vec!["migrations".to_string()]
+    }
+}
+
+fn flatten_boot_config(params: &mut Vec<String>, prefix: &str, table: &toml::Table) -> Result<()> {
+    for (key, value) in table {
+        ensure!(
+            key.split('.')
+                .all(|word| !word.is_empty() && word.chars().all(is_boot_config_key_char)),
+            error::BootConfigSnafu {
+                message: format!("'{prefix}.{key}' isn't a valid key"),
+            }
+        );
+        let key = format!("{prefix}.{key}");
+        let values = match value {
+            toml::Value::Table(table) => {
+                flatten_boot_config(params, &key, table)?;
+                continue;
+            }
+            toml::Value::Array(values) => values.iter().collect::<Vec<_>>(),
+            value => vec![value],
+        };
+        ensure!(
+            !values.is_empty(),
+            error::BootConfigSnafu {
+                message: format!("'{key}' has no values"),
+            }
+        );
+        let mut quoted = Vec::new();
+        for value in values {
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Array(_) | toml::Value::Table(_) => error::BootConfigSnafu {
+                    message: format!("'{key}' can only have strings, numbers or booleans"),
+                }
+                .fail()?,
+                value => value.to_string(),
+            };
+            ensure!(
+                !value.contains(|c: char| c == '"' || c.is_control()),
+                error::BootConfigSnafu {
+                    message: format!("'{key}' has a value with quotes or control characters"),
+                }
+            );
+            quoted.push(format!("\"{value}\""));
+        }
+        params.push(format!("{key} = {}", quoted.join(", ")));
+    }
+    Ok(())
+}
+
+fn is_boot_config_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Checks that each kernel parameter is a single parameter that can be written to the GRUB config
+/// as it is.
+fn check_kernel_parameter(param: &str) -> Result<()> {
+    let message = if param.is_empty() || param == "--" {
+        Some("it must be a single parameter")
+    } else if param.contains(|c: char| matches!(c, '\'' | '\\' | '$' | '`') || c.is_control()) {
+        Some("single quotes, backslashes, '$', backticks and control characters aren't allowed")
+    } else if param.matches('"').count() % 2 != 0 {
+        Some("its double quotes aren't balanced")
+    } else if param
+        .split('"')
+        .step_by(2)
+        .any(|unquoted| unquoted.contains(char::is_whitespace))
+    {
+        Some("it can only have spaces inside double quotes")
+    } else {
+        None
+    };
+    match message {
+        Some(message) => error::KernelParameterSnafu { param, message }.fail()?,
+        None => Ok(()),
+    }
+}
+
+/// A file or directory that is installed into the image of a variant as it is assembled.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileOverlay {
+    /// What to install, relative to the variant's directory. An empty directory if absent.
+    pub source: Option<PathBuf>,
+    /// Where to install it, as an absolute path in `partition`.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub partition: OverlayPartition,
+    #[serde(default)]
+    pub owner: u32,
+    #[serde(default)]
+    pub group: u32,
+    pub mode: Option<String>,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlayPartition {
+    #[default]
+    Root,
+    Data,
+}
+
+/// The largest mode a file overlay can have, which includes the setuid, setgid and sticky bits.
+const MAX_OVERLAY_MODE: u32 = 0o7777;
+
+impl FileOverlay {
+    /// Checks the overlay against the variant's directory, and returns its mode, which defaults
+    /// according to whether it installs a directory.
+    pub fn validate(&self, variant_dir: &Path) -> Result<u32> {
+        let mut components = self.path.components().peekable();
+        ensure!(
+            components.next() == Some(Component::RootDir)
+                && components.peek().is_some()
+                && components.all(|c| matches!(c, Component::Normal(_))),
+            error::FileOverlaySnafu {
+                path: &self.path,
+                message: "the path must be absolute, and can't be '/' or use '..'",
+            }
+        );
+
+        let is_dir = match &self.source {
+            Some(source) => {
+                ensure!(
+                    source.components().next().is_some()
+                        && source
+                            .components()
+                            .all(|c| matches!(c, Component::Normal(_))),
+                    error::FileOverlaySnafu {
+                        path: &self.path,
+                        message: format!(
+                            "the source '{}' must be a relative path in the variant's directory",
+                            source.display()
+                        ),
+                    }
+                );
+                let metadata = fs::metadata(variant_dir.join(source)).ok().context(
+                    error::FileOverlaySnafu {
+                        path: &self.path,
+                        message: format!("the source '{}' doesn't exist", source.display()),
+                    },
+                )?;
+                metadata.is_dir()
+            }
+            None => true,
+        };
+
+        let Some(mode) = &self.mode else {
+            return Ok(if is_dir { 0o755 } else { 0o644 });
+        };
+        let mode = u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= MAX_OVERLAY_MODE)
+            .context(error::FileOverlaySnafu {
+                path: &self.path,
+                message: format!("the mode '{mode}' isn't an octal mode such as \"0644\""),
+            })?;
+        Ok(mode)
+    }
+}
+
+/// A package to leave out of a variant, either for every architecture or only for some.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ExcludedPackage {
+    All(String),
+    Arches {
+        name: String,
+        arches: HashSet<SupportedArch>,
+    },
+}
+
+impl ExcludedPackage {
+    pub fn name(&self) -> &str {
+        match self {
+            ExcludedPackage::All(name) => name,
+            ExcludedPackage::Arches { name, .. } => name,
+        }
+    }
+
+    /// Whether the package is left out of builds for `arch`.
+    pub fn excludes(&self, arch: SupportedArch) -> bool {
+        match self {
+            ExcludedPackage::All(_) => true,
+            ExcludedPackage::Arches { arches, .. } => arches.contains(&arch),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -702,6 +1636,13 @@ pub struct ImageLayout {
     pub partition_plan: PartitionPlan,
 }
 
+/// The smallest image that `partyplanner` can lay out.
+const MIN_IMAGE_SIZE_GIB: u16 = 1;
+
+/// The size of each root partition for each GiB of the "os" image, from `partyplanner`. Images
+/// without in-place updates have one bank, with a root partition twice as large.
+const ROOT_MIB_PER_OS_IMAGE_GIB: u64 = 460;
+
 /// These are the historical defaults for all variants, before we added support
 /// for customizing these properties.
 static DEFAULT_OS_IMAGE_SIZE_GIB: ImageSize = ImageSize(2);
@@ -726,6 +1667,59 @@ impl ImageLayout {
         DEFAULT_PARTITION_PLAN
     }
 
+    /// Checks that each image is large enough to be laid out.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.os_image_size_gib.0 >= MIN_IMAGE_SIZE_GIB,
+            error::ImageLayoutSnafu {
+                message: format!(
+                    "os-image-size-gib is {}, but must be at least {}",
+                    self.os_image_size_gib, MIN_IMAGE_SIZE_GIB
+                ),
+            }
+        );
+        if let PartitionPlan::Split = self.partition_plan {
+            ensure!(
+                self.data_image_size_gib.0 >= MIN_IMAGE_SIZE_GIB,
+                error::ImageLayoutSnafu {
+                    message: format!(
+                        "data-image-size-gib is {}, but must be at least {} with the split \
+                        partition plan",
+                        self.data_image_size_gib, MIN_IMAGE_SIZE_GIB
+                    ),
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that `payload_mib` of installed packages fit in the root filesystem.
+    pub fn check_payload(&self, payload_mib: u64, in_place_updates: bool) -> Result<()> {
+        let root_mib = self.root_partition_mib(in_place_updates);
+        ensure!(
+            payload_mib <= root_mib,
+            error::ImageLayoutSnafu {
+                message: format!(
+                    "the included packages need about {payload_mib} MiB once installed, but \
+                    the root partition of a {} GiB os image is {root_mib} MiB; increase \
+                    os-image-size-gib",
+                    self.os_image_size_gib
+                ),
+            }
+        );
+        Ok(())
+    }
+
+    /// The size of the root partition that `partyplanner` makes for this layout.
+    pub fn root_partition_mib(&self, in_place_updates: bool) -> u64 {
+        let root_mib = u64::from(self.os_image_size_gib.0) * ROOT_MIB_PER_OS_IMAGE_GIB;
+        if in_place_updates {
+            root_mib
+        } else {
+            root_mib * 2
+        }
+    }
+
     // At publish time we will need specific sizes for the OS image and the (optional) data image.
     // The sizes returned by this function depend on the image layout, and whether the publish
     // image hint is larger than the required minimum size.
@@ -821,6 +1815,29 @@ impl TryFrom<String> for ImageFeature {
     }
 }
 
+impl FromStr for ImageFeature {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::try_from(s.to_string())
+    }
+}
+
+impl ImageFeature {
+    /// The name of the feature in manifests, such as `in-place-updates`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFeature::GrubSetPrivateVar => "grub-set-private-var",
+            ImageFeature::SystemdNetworkd => "systemd-networkd",
+            ImageFeature::XfsDataPartition => "xfs-data-partition",
+            ImageFeature::ErofsRootPartition => "erofs-root-partition",
+            ImageFeature::UefiSecureBoot => "uefi-secure-boot",
+            ImageFeature::Fips => "fips",
+            ImageFeature::InPlaceUpdates => "in-place-updates",
+            ImageFeature::HostContainers => "host-containers",
+        }
+    }
+}
+
 impl fmt::Display for ImageFeature {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -852,6 +1869,16 @@ pub struct ExternalFile {
     pub bundle_modules: Option<Vec<BundleModule>>,
     pub bundle_root_path: Option<PathBuf>,
     pub bundle_output_path: Option<PathBuf>,
+    pub git_commit: Option<String>,
+    pub git_tag: Option<String>,
+    pub mirrors: Option<Vec<String>>,
+    pub patches: Option<Vec<PathBuf>>,
+    pub extract: Option<bool>,
+    pub strip_components: Option<u32>,
+    pub rename: Option<PathBuf>,
+    /// Keys that buildsys doesn't recognize, which are usually typos.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
 }
 
 // =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^=
@@ -945,6 +1972,30 @@ mod test {
         assert_eq!(package_list, expected);
     }
 
+    #[test]
+    fn test_package_dirs() {
+        let manifest_path = cargo_manifest("extra-3-kit");
+        let temp_dir = TempDir::new().unwrap();
+        let cargo_metadata_path = cargo_metadata_path(&temp_dir);
+        let manifest = Manifest::new(manifest_path, cargo_metadata_path).unwrap();
+        // pkg-g depends on pkg-f, and packages from external kits aren't in the project.
+        let dirs = manifest
+            .package_dirs(&["pkg-g".to_string(), "pkg-external".to_string()])
+            .unwrap();
+        let packages_dir = test_projects_dir()
+            .join("local-kit")
+            .join("packages")
+            .canonicalize()
+            .unwrap();
+        assert_eq!(
+            dirs,
+            BTreeMap::from([
+                ("pkg-f".to_string(), packages_dir.join("pkg-f")),
+                ("pkg-g".to_string(), packages_dir.join("pkg-g")),
+            ])
+        );
+    }
+
     #[test]
     fn test_kit_dependencies_pkg_e() {
         let manifest_path = cargo_manifest("pkg-e");
@@ -975,4 +2026,364 @@ mod test {
         ];
         assert_eq!(kit_list, expected);
     }
+
+    #[test]
+    fn test_image_layout_checks() {
+        let layout = ImageLayout::default();
+        layout.validate().unwrap();
+        assert_eq!(layout.root_partition_mib(true), 920);
+        assert_eq!(layout.root_partition_mib(false), 1840);
+        layout.check_payload(920, true).unwrap();
+        assert!(layout.check_payload(921, true).is_err());
+        layout.check_payload(921, false).unwrap();
+
+        let unified = ImageLayout {
+            data_image_size_gib: ImageSize(0),
+            partition_plan: PartitionPlan::Unified,
+            ..ImageLayout::default()
+        };
+        unified.validate().unwrap();
+        let split = ImageLayout {
+            partition_plan: PartitionPlan::Split,
+            ..unified
+        };
+        assert!(split.validate().is_err());
+        let empty = ImageLayout {
+            os_image_size_gib: ImageSize(0),
+            ..ImageLayout::default()
+        };
+        assert!(empty.validate().is_err());
+    }
+
+    #[test]
+    fn test_included_packages_for() {
+        let manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "hello-ootb"
+
+            [package.metadata.build-variant]
+            included-packages = ["release", "ssm", "gpu", "kernel"]
+            excluded-packages = [
+                "ssm",
+                { name = "gpu", arches = ["aarch64"] },
+                { name = "kernel", arches = [] },
+                { name = "fips-provider", arches = ["aarch64"] },
+            ]
+
+            [package.metadata.build-variant.image-features]
+            fips = true
+
+            [package.metadata.build-variant.feature-packages]
+            fips = ["fips-provider", "release"]
+            systemd-networkd = ["networkd"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.included_packages_for(SupportedArch::X86_64),
+            vec!["release", "gpu", "kernel", "fips-provider"]
+        );
+        assert_eq!(
+            manifest.included_packages_for(SupportedArch::Aarch64),
+            vec!["release", "kernel"]
+        );
+    }
+
+    #[test]
+    fn test_override_image_features() {
+        let mut manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "hello-ootb"
+
+            [package.metadata.build-variant]
+            included-packages = ["release"]
+
+            [package.metadata.build-variant.image-features]
+            systemd-networkd = true
+
+            [package.metadata.build-variant.feature-packages]
+            fips = ["fips-provider"]
+            "#,
+        )
+        .unwrap();
+        manifest.override_image_features(
+            &[ImageFeature::Fips],
+            &[ImageFeature::SystemdNetworkd, ImageFeature::HostContainers],
+        );
+        assert_eq!(
+            manifest.image_features().unwrap(),
+            HashSet::from([ImageFeature::InPlaceUpdates, ImageFeature::Fips])
+        );
+        assert_eq!(
+            manifest.included_packages_for(SupportedArch::X86_64),
+            vec!["release", "fips-provider"]
+        );
+        assert_eq!(
+            "host-containers".parse::<ImageFeature>().unwrap().name(),
+            "host-containers"
+        );
+        assert!("unified-cgroup".parse::<ImageFeature>().is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "hello"
+
+            [package.metadata.build-package]
+            hermetc = true
+            source-groups = ["hello"]
+
+            [[package.metadata.build-package.external-files]]
+            url = "https://example.com/hello-1.0.tar.gz"
+            sha512 = "abcdef"
+            strip-component = 1
+
+            [package.metadata.other-tool]
+            anything = "goes"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.unknown_keys(),
+            vec![
+                "build-package.hermetc",
+                "build-package.external-files[0].strip-component"
+            ]
+        );
+        assert_eq!(manifest.source_groups().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_migration_packages() {
+        let variant = |build_variant: &str| {
+            toml::from_str::<ManifestInfo>(&format!(
+                "[package]\nname = \"aws-dev\"\n[package.metadata.build-variant]\n{build_variant}"
+            ))
+            .unwrap()
+        };
+        assert_eq!(variant("").migration_packages(), vec!["migrations"]);
+        let manifest = variant("migrations = { packages = [\"my-migrations\"], packges = [] }");
+        assert_eq!(manifest.migration_packages(), vec!["my-migrations"]);
+        assert_eq!(
+            manifest.unknown_keys(),
+            vec!["build-variant.migrations.packges"]
+        );
+        assert_eq!(
+            variant("migrations = {}").migration_packages(),
+            vec!["migrations"]
+        );
+    }
+
+    #[test]
+    fn test_required_versions() {
+        let manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "my-kit"
+
+            [package.metadata.build-kit]
+            vendor = "example"
+            required-buildsys-version = ">=0.8.0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.required_buildsys_version(),
+            Some(&VersionReq::parse(">=0.8.0").unwrap())
+        );
+        assert_eq!(manifest.required_twoliter_version(), None);
+        assert!(manifest.unknown_keys().is_empty());
+
+        let bad = toml::from_str::<ManifestInfo>(
+            r#"
+            [package]
+            name = "hello"
+
+            [package.metadata.build-package]
+            required-twoliter-version = "latest"
+            "#,
+        );
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn test_kernel_parameters() {
+        for good in [
+            "console=ttyS0",
+            "quiet",
+            r#"dyndbg="module foo +p""#,
+            "systemd.unit=multi-user.target",
+        ] {
+            check_kernel_parameter(good).unwrap();
+        }
+        for bad in [
+            "",
+            "--",
+            "console=ttyS0 quiet",
+            r#"dyndbg="module foo +p"#,
+            "init=$HOME",
+            "a='b c'",
+            "a\\b",
+        ] {
+            assert!(check_kernel_parameter(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_check_devices() {
+        let manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "gpu-driver"
+
+            [package.metadata.build-package.check-devices]
+            devices = ["vendor.com/fpga=0"]
+            gpus = ["0", "all"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.check_devices().unwrap().cdi_devices().unwrap(),
+            vec![
+                "vendor.com/fpga=0",
+                "nvidia.com/gpu=0",
+                "nvidia.com/gpu=all"
+            ]
+        );
+        assert!(!is_cdi_device("/dev/nvidia0"));
+        assert!(!is_cdi_device("vendor.com/fpga"));
+        assert!(!is_cdi_device("vendor.com/fpga=a,b"));
+
+        let devices = CheckDevices {
+            devices: Vec::new(),
+            gpus: vec!["first".to_string()],
+            unknown: BTreeMap::new(),
+        };
+        assert!(devices.cdi_devices().is_err());
+    }
+
+    #[test]
+    fn test_maintainers() {
+        let manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "kernel-6.1"
+
+            [package.metadata.build-package]
+            maintainers = ["@bottlerocket-os/kernel", "jane@example.com"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.maintainers(),
+            ["@bottlerocket-os/kernel", "jane@example.com"]
+        );
+        assert!(manifest.unknown_keys().is_empty());
+
+        let manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "glibc"
+
+            [package.metadata.build-package]
+            "#,
+        )
+        .unwrap();
+        assert!(manifest.maintainers().is_empty());
+    }
+
+    #[test]
+    fn test_boot_config() {
+        let manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "hello-ootb"
+
+            [package.metadata.build-variant.boot-config.kernel]
+            console = ["tty0", "ttyS0,115200n8"]
+            "nr_cpus" = 4
+
+            [package.metadata.build-variant.boot-config.init]
+            systemd.log_level = "debug"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.boot_config().unwrap().render().unwrap(),
+            r#"kernel.console = "tty0", "ttyS0,115200n8"; kernel.nr_cpus = "4"; "#.to_string()
+                + r#"init.systemd.log_level = "debug""#
+        );
+
+        for bad in [
+            r#"kernel."bad key" = "x""#,
+            r#"kernel.console = []"#,
+            r#"kernel.console = "a"b""#,
+            r#"init.x = [["nested"]]"#,
+        ] {
+            let config: BootConfig = toml::from_str(bad).unwrap();
+            assert!(config.render().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_file_overlays() {
+        let variant_dir = TempDir::new().unwrap();
+        fs::create_dir(variant_dir.path().join("files")).unwrap();
+        fs::write(variant_dir.path().join("files").join("motd"), "hello").unwrap();
+
+        let manifest: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "hello-ootb"
+
+            [[package.metadata.build-variant.file-overlays]]
+            source = "files/motd"
+            path = "/etc/motd"
+
+            [[package.metadata.build-variant.file-overlays]]
+            source = "files"
+            path = "/etc/hello"
+            mode = "0750"
+
+            [[package.metadata.build-variant.file-overlays]]
+            path = "/example"
+            partition = "data"
+            owner = 1000
+            "#,
+        )
+        .unwrap();
+        let overlays = manifest.file_overlays().unwrap();
+        let modes = overlays
+            .iter()
+            .map(|o| o.validate(variant_dir.path()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(modes, vec![0o644, 0o750, 0o755]);
+        assert_eq!(overlays[2].partition, OverlayPartition::Data);
+
+        let overlay = |source: Option<&str>, path: &str, mode: Option<&str>| FileOverlay {
+            source: source.map(PathBuf::from),
+            path: PathBuf::from(path),
+            partition: OverlayPartition::Root,
+            owner: 0,
+            group: 0,
+            mode: mode.map(String::from),
+            unknown: BTreeMap::new(),
+        };
+        for bad in [
+            overlay(None, "etc/motd", None),
+            overlay(None, "/", None),
+            overlay(None, "/etc/../root", None),
+            overlay(Some("../motd"), "/etc/motd", None),
+            overlay(Some("files/issue"), "/etc/issue", None),
+            overlay(None, "/etc/hello", Some("0999")),
+            overlay(None, "/etc/hello", Some("17777")),
+        ] {
+            assert!(bad.validate(variant_dir.path()).is_err(), "{bad:?}");
+        }
+    }
 }
@@ -42,6 +42,24 @@ pub(super) enum Error {
     #[snafu(display("Failed to parse image feature '{}'", what))]
     ParseImageFeature { what: String },
 
+    #[snafu(display("Invalid image layout: {message}"))]
+    ImageLayout { message: String },
+
+    #[snafu(display("Invalid device '{device}' in check-devices: {message}"))]
+    CheckDevice { device: String, message: String },
+
+    #[snafu(display("Invalid kernel-module: {message}"))]
+    KernelModule { message: String },
+
+    #[snafu(display("Invalid kernel parameter '{param}': {message}"))]
+    KernelParameter { param: String, message: String },
+
+    #[snafu(display("Invalid bootconfig: {message}"))]
+    BootConfig { message: String },
+
+    #[snafu(display("Invalid file overlay for '{}': {message}", path.display()))]
+    FileOverlay { path: PathBuf, message: String },
+
     #[snafu(display(
         "The cargo package we are building, '{name}', could not be found in the graph"
     ))]
@@ -0,0 +1,30 @@
+/*!
+Reads the name of the host's OS out of `/etc/os-release`, for the records of the hosts that
+projects are locked and built on, which buildsys and twoliter both keep.
+*/
+
+/// The `PRETTY_NAME` in the contents of `/etc/os-release`, or else its `NAME`.
+pub fn os_name(os_release: Option<&str>) -> Option<String> {
+    let os_release = os_release?;
+    let value = |key: &str| {
+        os_release.lines().find_map(|line| {
+            line.strip_prefix(key)?
+                .strip_prefix('=')
+                .map(|value| value.trim().trim_matches('"').to_string())
+        })
+    };
+    value("PRETTY_NAME").or_else(|| value("NAME"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_os_name() {
+        let os_release = "NAME=\"Amazon Linux\"\nPRETTY_NAME=\"Amazon Linux 2023\"\n";
+        assert_eq!(os_name(Some(os_release)).unwrap(), "Amazon Linux 2023");
+        assert_eq!(os_name(Some("NAME=Ubuntu\n")).unwrap(), "Ubuntu");
+        assert!(os_name(None).is_none());
+    }
+}
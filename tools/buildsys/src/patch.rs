@@ -0,0 +1,169 @@
+/*!
+Small fixes to upstream code are usually carried as patches in the spec, which means adding a
+`PatchN` tag and applying it in `%prep`. For an external file that is an archive, the patches can
+instead be listed in `package.metadata.build-package.external-files[].patches`, and buildsys
+applies them after the file is fetched.
+
+The archive is unpacked in the SDK, each patch is applied with `patch -p1` from the archive's
+top-level directory, and the result is packed into `patched-<name>` next to the original, with
+the same compression. The original archive is left alone so that it still matches `sha512` and can
+be served from the lookaside cache, and the spec uses the patched archive as its source.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use crate::rerun;
+use buildsys::manifest;
+use duct::cmd;
+use filetime::{set_file_mtime, FileTime};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use tracing::info;
+
+// Unpacks the archive, applies each patch given as an argument, and writes the patched archive to
+// stdout. The entries are sorted and their owners and times are fixed, so that the patched archive
+// only depends on its inputs.
+const PATCH_SCRIPT: &str = r#"
+set -eu -o pipefail
+shopt -s nullglob dotglob
+work="$(mktemp -d)"
+tar -xf "/package/${ARCHIVE}" -C "${work}"
+entries=("${work}"/*)
+srcdir="${work}"
+if [ "${#entries[@]}" -eq 1 ] && [ -d "${entries[0]}" ]; then
+    srcdir="${entries[0]}"
+fi
+for patch in "$@"; do
+    echo "Applying ${patch}" >&2
+    patch --batch --forward -p1 -d "${srcdir}" -i "/package/${patch}" >&2
+done
+cd "${work}"
+tar --sort=name --mtime=@0 --owner=0 --group=0 --numeric-owner \
+    -caf "/tmp/${OUTPUT}" "${entries[@]##*/}"
+cat "/tmp/${OUTPUT}"
+"#;
+
+pub(crate) struct SourcePatch;
+
+impl SourcePatch {
+    /// Applies the patches listed for `external_file`, which has been fetched to `package_dir`,
+    /// and writes the patched archive next to it. Does nothing if the file has no patches.
+    pub(crate) fn apply(
+        package_dir: &Path,
+        external_file: &manifest::ExternalFile,
+        sdk: &str,
+        mtime: FileTime,
+    ) -> Result<()> {
+        let Some(patches) = external_file.patches.as_ref().filter(|p| !p.is_empty()) else {
+            return Ok(());
+        };
+        let archive = &archive_name(external_file)?;
+        for patch in patches {
+            ensure!(
+                is_relative_inside(patch) && package_dir.join(patch).is_file(),
+                error::BadPatchSnafu { path: patch }
+            );
+            rerun::file(patch);
+        }
+
+        let output = patched_name(archive);
+        let output_path = package_dir.join(&output);
+        info!(
+            "Applying {} patches to {}",
+            patches.len(),
+            archive.display()
+        );
+        let result = cmd(
+            "docker",
+            [
+                "run".into(),
+                "--rm".into(),
+                "--network".into(),
+                "none".into(),
+                "-v".into(),
+                format!("{}:/package:ro", package_dir.display()),
+                "--env".into(),
+                format!("ARCHIVE={}", archive.display()),
+                "--env".into(),
+                format!("OUTPUT={}", output.display()),
+                sdk.to_string(),
+                "bash".into(),
+                "-c".into(),
+                PATCH_SCRIPT.into(),
+                "bash".into(),
+            ]
+            .into_iter()
+            .chain(patches.iter().map(|p| p.display().to_string())),
+        )
+        .stdout_path(&output_path)
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+
+        if !result.status.success() {
+            // Don't leave a partial archive behind.
+            let _ = fs::remove_file(&output_path);
+            return error::PatchSnafu {
+                archive,
+                output: String::from_utf8_lossy(&result.stderr),
+            }
+            .fail();
+        }
+
+        set_file_mtime(&output_path, mtime).context(error::SetMtimeSnafu { path: &output_path })
+    }
+}
+
+/// The name the external file was fetched to, which is its `path`, or else the last part of its
+/// URL.
+pub(crate) fn archive_name(external_file: &manifest::ExternalFile) -> Result<PathBuf> {
+    if let Some(path) = &external_file.path {
+        return Ok(path.clone());
+    }
+    let url = &external_file.url;
+    let parsed = reqwest::Url::parse(url).context(error::InputUrlSnafu { url })?;
+    parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .map(PathBuf::from)
+        .context(error::ArchiveNameSnafu { url })
+}
+
+/// The name of the patched archive, such as `patched-foo-1.0.tar.gz` for `foo-1.0.tar.gz`.
+pub(crate) fn patched_name(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    PathBuf::from(format!("patched-{name}"))
+}
+
+/// Patches are read from the package directory, so they can't be absolute or leave it.
+fn is_relative_inside(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn patch_paths() {
+        assert!(is_relative_inside(Path::new("fix-build.patch")));
+        assert!(is_relative_inside(Path::new("patches/fix-build.patch")));
+        assert!(!is_relative_inside(Path::new("/tmp/fix-build.patch")));
+        assert!(!is_relative_inside(Path::new("../other/fix-build.patch")));
+    }
+
+    #[test]
+    fn patched_names() {
+        assert_eq!(
+            patched_name(Path::new("foo-1.0.tar.gz")),
+            PathBuf::from("patched-foo-1.0.tar.gz")
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Unable to find the file name in URL '{}'", url))]
+    ArchiveName { url: String },
+
+    #[snafu(display(
+        "Patch '{}' must be a file in the package directory, given by a relative path",
+        path.display()
+    ))]
+    BadPatch { path: PathBuf },
+
+    #[snafu(display("Failed to start command: {}", source))]
+    CommandStart { source: std::io::Error },
+
+    #[snafu(display("Bad file URL '{}': {}", url, source))]
+    InputUrl {
+        url: String,
+        source: url::ParseError,
+    },
+
+    #[snafu(display("Failed to patch '{}': {}", archive.display(), output))]
+    Patch { archive: PathBuf, output: String },
+
+    #[snafu(display("Failed to set modification time of '{}': {}", path.display(), source))]
+    SetMtime {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,303 @@
+/*!
+Cargo runs a build script again when a file or an environment variable that the script asked it to
+watch has changed since the last run, but it doesn't say which one. When rebuilds are explained,
+each run of a build records what it watched, and the next run compares that with how things are
+now to find what changed.
+
+This module has those records, which buildsys writes and twoliter reads.
+*/
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The length in bytes of the key that secret values are recorded with.
+const KEY_LEN: usize = 32;
+
+/// A watched file as it was seen. Files that didn't exist have no state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    /// Nanoseconds since the epoch.
+    pub modified: u128,
+    pub size: u64,
+}
+
+impl FileState {
+    /// The state of the file at `path`, or `None` if there is nothing there.
+    pub fn read(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        Some(Self {
+            modified,
+            size: metadata.len(),
+        })
+    }
+}
+
+/// Why cargo ran a build again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Reason {
+    /// No earlier run was recorded, as after a clean, when cargo has no fingerprint of the build
+    /// either.
+    MissingFingerprint,
+    /// The last run failed, and cargo always runs a failed build script again.
+    LastRunFailed,
+    FileCreated {
+        path: String,
+    },
+    FileModified {
+        path: String,
+    },
+    FileDeleted {
+        path: String,
+    },
+    EnvChanged {
+        name: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    /// Nothing that was watched changed, so cargo ran the build because a build it depends on
+    /// ran, or because buildsys itself changed.
+    NothingWatchedChanged,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = |value: &Option<String>| match value {
+            Some(value) => format!("'{value}'"),
+            None => "unset".to_string(),
+        };
+        match self {
+            Reason::MissingFingerprint => write!(f, "no earlier build was recorded"),
+            Reason::LastRunFailed => write!(f, "the last build failed"),
+            Reason::FileCreated { path } => write!(f, "'{path}' was created"),
+            Reason::FileModified { path } => write!(f, "'{path}' was modified"),
+            Reason::FileDeleted { path } => write!(f, "'{path}' was deleted"),
+            Reason::EnvChanged { name, old, new } => {
+                write!(f, "{name} changed from {} to {}", value(old), value(new))
+            }
+            Reason::NothingWatchedChanged => write!(
+                f,
+                "nothing watched changed, so a dependency or buildsys itself likely did"
+            ),
+        }
+    }
+}
+
+/// What a run of a build watched, and why it ran.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub kind: String,
+    pub name: String,
+    pub arch: String,
+    /// Seconds since the epoch.
+    pub started: u64,
+    pub succeeded: bool,
+    pub reasons: Vec<Reason>,
+    /// The watched files, by the path that cargo was given, as they were when the run ended.
+    pub files: BTreeMap<String, Option<FileState>>,
+    /// The watched environment variables, with their values.
+    pub env: BTreeMap<String, Option<String>>,
+}
+
+impl Record {
+    /// Where the record of the build of `name` for `arch` is kept in the project at `root`.
+    pub fn path(root: &Path, name: &str, arch: &str) -> PathBuf {
+        root.join(buildsys_config::REBUILD_REASONS_DIRECTORY)
+            .join(format!("{name}-{arch}.json"))
+    }
+
+    /// The key that the values of secret variables are recorded with in the project at `root`.
+    /// It's made at random the first time it's needed, and only readable by its owner, so that
+    /// records show when a secret changed without being enough to guess it.
+    pub fn key(root: &Path) -> io::Result<Vec<u8>> {
+        let dir = root.join(buildsys_config::REBUILD_REASONS_DIRECTORY);
+        let path = dir.join(".key");
+        match fs::read(&path) {
+            Ok(key) if !key.is_empty() => return Ok(key),
+            _ => fs::create_dir_all(&dir)?,
+        }
+
+        // Builds run in parallel, so the key is written in full before it's linked into place,
+        // and a build that loses the race uses the key of the one that won.
+        let mut key = vec![0; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        let temp = dir.join(format!(".key.{}", std::process::id()));
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)?
+            .write_all(&key)?;
+        let linked = fs::hard_link(&temp, &path);
+        let _ = fs::remove_file(&temp);
+        match linked {
+            Ok(()) => Ok(key),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => fs::read(&path),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Finds why a build ran again, given the record of its last run and the current state of the
+/// files and environment variables. Like cargo, a file counts as modified if it changed after
+/// the last run started, even if that run saw the change.
+pub fn reasons(
+    last: Option<&Record>,
+    file: impl Fn(&str) -> Option<FileState>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Vec<Reason> {
+    let Some(last) = last else {
+        return vec![Reason::MissingFingerprint];
+    };
+    if !last.succeeded {
+        return vec![Reason::LastRunFailed];
+    }
+
+    let started = u128::from(last.started) * 1_000_000_000;
+    let mut reasons = Vec::new();
+    for (path, old) in &last.files {
+        let path = path.clone();
+        match (old, file(&path)) {
+            (None, Some(_)) => reasons.push(Reason::FileCreated { path }),
+            (Some(_), None) => reasons.push(Reason::FileDeleted { path }),
+            (Some(old), Some(new)) if *old != new || new.modified > started => {
+                reasons.push(Reason::FileModified { path })
+            }
+            _ => {}
+        }
+    }
+    for (name, old) in &last.env {
+        let new = env(name);
+        if *old != new {
+            reasons.push(Reason::EnvChanged {
+                name: name.clone(),
+                old: old.clone(),
+                new,
+            });
+        }
+    }
+
+    if reasons.is_empty() {
+        reasons.push(Reason::NothingWatchedChanged);
+    }
+    reasons
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(modified: u128) -> Option<FileState> {
+        Some(FileState { modified, size: 1 })
+    }
+
+    #[test]
+    fn test_reasons() {
+        let second = 1_000_000_000;
+        let mut last = Record {
+            kind: "package".to_string(),
+            name: "kernel-6.1".to_string(),
+            arch: "x86_64".to_string(),
+            started: 100,
+            succeeded: true,
+            reasons: Vec::new(),
+            files: [
+                ("Cargo.toml", state(50 * second)),
+                ("kernel-6.1.spec", state(50 * second)),
+                ("config-extra", None),
+                ("0001-fix.patch", state(50 * second)),
+                ("late.patch", state(150 * second)),
+            ]
+            .into_iter()
+            .map(|(path, state)| (path.to_string(), state))
+            .collect(),
+            env: [
+                ("GOPROXY", None),
+                ("BUILDSYS_ARCH", Some("x86_64".to_string())),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+        };
+        let file = |path: &str| match path {
+            "kernel-6.1.spec" => state(200 * second),
+            "config-extra" => state(200 * second),
+            "0001-fix.patch" => None,
+            "late.patch" => state(150 * second),
+            _ => state(50 * second),
+        };
+        let env = |name: &str| (name == "GOPROXY").then(|| "direct".to_string());
+
+        assert_eq!(
+            reasons(Some(&last), file, env),
+            vec![
+                Reason::FileDeleted {
+                    path: "0001-fix.patch".to_string()
+                },
+                Reason::FileCreated {
+                    path: "config-extra".to_string()
+                },
+                Reason::FileModified {
+                    path: "kernel-6.1.spec".to_string()
+                },
+                Reason::FileModified {
+                    path: "late.patch".to_string()
+                },
+                Reason::EnvChanged {
+                    name: "BUILDSYS_ARCH".to_string(),
+                    old: Some("x86_64".to_string()),
+                    new: None,
+                },
+                Reason::EnvChanged {
+                    name: "GOPROXY".to_string(),
+                    old: None,
+                    new: Some("direct".to_string()),
+                },
+            ]
+        );
+
+        last.files.remove("late.patch");
+        let (files, vars) = (last.files.clone(), last.env.clone());
+        let unchanged = |path: &str| files[path];
+        let same_env = |name: &str| vars[name].clone();
+        assert_eq!(
+            reasons(Some(&last), unchanged, same_env),
+            vec![Reason::NothingWatchedChanged]
+        );
+
+        last.succeeded = false;
+        assert_eq!(
+            reasons(Some(&last), unchanged, same_env),
+            vec![Reason::LastRunFailed]
+        );
+        assert_eq!(
+            reasons(None, unchanged, same_env),
+            vec![Reason::MissingFingerprint]
+        );
+    }
+
+    #[test]
+    fn test_key() {
+        let root = tempfile::TempDir::new().unwrap();
+        let key = Record::key(root.path()).unwrap();
+        assert_eq!(key.len(), KEY_LEN);
+        assert_eq!(Record::key(root.path()).unwrap(), key);
+
+        let other = tempfile::TempDir::new().unwrap();
+        assert_ne!(Record::key(other.path()).unwrap(), key);
+    }
+}
@@ -0,0 +1,523 @@
+/*!
+Package builds are expensive, and most of them produce the same RPMs as the last time they ran.
+
+This module provides an optional remote cache of built RPMs, shared between CI and developers.
+Each entry is keyed by a hash of everything that goes into a package build: the spec, its sources
+and patches, the external files, the SDK, the architecture, the settings that change what it
+builds, and the RPMs of the packages it depends on. Before building a package, buildsys looks for
+an entry with a matching key and uses it instead of building. After a successful build, the
+outputs can be uploaded for others to use. Builds whose SDK image ID can't be found don't use the
+cache.
+
+The same cache also holds the external files that packages fetch, and the bundles of vendored
+dependencies made from them, so that an organization only fetches each of them once. Entries are
+named relative to the cache URL, by a hex-encoded SHA-512 digest:
+
+* `<key>.tar` is a tar archive of the outputs of a package build, whose inputs hash to `key`.
+* `sources/<sha512>` is an external file whose contents hash to `sha512`, the hash in the manifest.
+* `bundles/<key>` is a bundle of vendored dependencies, whose inputs hash to `key`.
+
+The cache can be served over HTTP(S). An entry is fetched with GET, which answers 200 with the
+entry, or 404 if there is no such entry, and uploaded with PUT, which answers with any 2xx status
+once the entry is stored. Entries never change once they are stored, so a server may ignore a PUT
+of an entry it already has. If a token is given, every request carries it as
+`Authorization: Bearer <token>`. `twoliter cache serve` is a server that stores entries in a local
+directory.
+
+The cache can also be stored in S3 with the same layout, in which case the `aws` CLI is used with
+the caller's credentials.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use buildsys::BuildType;
+use buildsys_config::BUILD_INPUTS_DIRECTORY;
+use duct::cmd;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, USER_AGENT};
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+use snafu::{ensure, ResultExt};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// The file name of an entry in the remote cache, relative to the cache URL.
+fn entry_name(key: &str) -> String {
+    format!("{key}.tar")
+}
+
+/// The name of the entry for the external file whose digest is `sha512`.
+fn source_entry_name(sha512: &str) -> String {
+    format!("sources/{sha512}")
+}
+
+/// The name of the entry for the bundle whose inputs hash to `key`.
+fn bundle_entry_name(key: &str) -> String {
+    format!("bundles/{key}")
+}
+
+/// Collects the inputs to a package build and computes the key of its remote cache entry.
+#[derive(Debug, Default)]
+pub(crate) struct BuildInputs {
+    inputs: BTreeMap<String, String>,
+    /// Where each file input was read from, by the name of the input.
+    files: BTreeMap<String, PathBuf>,
+    /// Where each directory input was read from, by the name its files are recorded under.
+    dirs: BTreeMap<String, PathBuf>,
+}
+
+/// The inputs of a successful build, as `BuildInputs::save` writes them.
+#[derive(Debug, Serialize)]
+struct InputsRecord<'a> {
+    kind: &'static str,
+    name: &'a str,
+    arch: &'a str,
+    inputs: &'a BTreeMap<String, String>,
+    /// Where each file input is, relative to the project's root if it's in it.
+    files: BTreeMap<&'a str, &'a Path>,
+    /// Where each directory input is, like `files`, so that files added to it since can be found.
+    dirs: BTreeMap<&'a str, &'a Path>,
+    /// The environment variables that the build watches, as they were set for it.
+    vars: &'a BTreeMap<String, String>,
+    /// The packages whose outputs went into the build.
+    dependencies: &'a [String],
+}
+
+impl BuildInputs {
+    /// Record a named value, such as the architecture or the SDK image digest.
+    pub(crate) fn value(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> &mut Self {
+        self.inputs
+            .insert(name.as_ref().to_string(), value.as_ref().to_string());
+        self
+    }
+
+    /// Record the contents of a file under the given name. The name is used instead of the path
+    /// so that keys don't depend on where the project is checked out. Missing files are recorded
+    /// as such, so that a build that is missing an input does not match one that has it.
+    pub(crate) fn file(
+        &mut self,
+        name: impl AsRef<str>,
+        path: impl AsRef<Path>,
+    ) -> Result<&mut Self> {
+        let path = path.as_ref();
+        let name = format!("file:{}", name.as_ref());
+        let absolute = std::env::current_dir()
+            .map(|dir| dir.join(path))
+            .unwrap_or_else(|_| path.to_path_buf());
+        self.files.insert(name.clone(), absolute);
+        let digest = if path.is_file() {
+            let mut f = File::open(path).context(error::InputReadSnafu { path })?;
+            let mut d = Sha512::new();
+            io::copy(&mut f, &mut d).context(error::InputReadSnafu { path })?;
+            hex::encode(d.finalize())
+        } else {
+            "missing".to_string()
+        };
+        Ok(self.value(name, digest))
+    }
+
+    /// Record the contents of every file in a directory, named by their path relative to `dir`.
+    pub(crate) fn dir(
+        &mut self,
+        name: impl AsRef<str>,
+        dir: impl AsRef<Path>,
+    ) -> Result<&mut Self> {
+        let dir = dir.as_ref();
+        let absolute = std::env::current_dir()
+            .map(|cwd| cwd.join(dir))
+            .unwrap_or_else(|_| dir.to_path_buf());
+        self.dirs.insert(name.as_ref().to_string(), absolute);
+        if !dir.is_dir() {
+            return Ok(self);
+        }
+        for entry in walkdir::WalkDir::new(dir).follow_links(false) {
+            let entry = entry.context(error::InputWalkSnafu { path: dir })?;
+            if entry.file_type().is_file() {
+                let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+                self.file(
+                    format!("{}/{}", name.as_ref(), relative.display()),
+                    entry.path(),
+                )?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// The cache key for these inputs. Inputs are sorted by name, so the order in which they were
+    /// recorded does not matter.
+    pub(crate) fn key(&self) -> String {
+        let mut d = Sha512::new();
+        for (name, value) in &self.inputs {
+            d.update(name.as_bytes());
+            d.update([0]);
+            d.update(value.as_bytes());
+            d.update([0]);
+        }
+        hex::encode(d.finalize())
+    }
+
+    /// Writes these inputs to the project's inputs directory after a successful build, so that
+    /// `twoliter status` can compare them, and the `vars` the build watched, with the project as it
+    /// is later. Files are hashed the same way as for the cache key. Each kind of build has its own
+    /// record, since a package and a kit can share a name.
+    pub(crate) fn save(
+        &self,
+        root: &Path,
+        build_type: BuildType,
+        name: &str,
+        arch: &str,
+        vars: &BTreeMap<String, String>,
+        dependencies: &[String],
+    ) -> io::Result<()> {
+        let kind = match build_type {
+            BuildType::Package => "package",
+            BuildType::Kit => "kit",
+            BuildType::Variant => "variant",
+            BuildType::Repack => "repack",
+        };
+        let record = InputsRecord {
+            kind,
+            name,
+            arch,
+            inputs: &self.inputs,
+            files: self
+                .files
+                .iter()
+                .map(|(input, path)| (input.as_str(), path.strip_prefix(root).unwrap_or(path)))
+                .collect(),
+            dirs: self
+                .dirs
+                .iter()
+                .map(|(input, path)| (input.as_str(), path.strip_prefix(root).unwrap_or(path)))
+                .collect(),
+            vars,
+            dependencies,
+        };
+        let dir = root.join(BUILD_INPUTS_DIRECTORY);
+        fs::create_dir_all(&dir)?;
+        let json = serde_json::to_vec_pretty(&record).map_err(io::Error::other)?;
+        fs::write(dir.join(format!("{kind}-{name}-{arch}.json")), json)
+    }
+}
+
+/// Returns the image ID of the SDK, so that a retagged SDK still produces a different key. The
+/// image name isn't a substitute, since a tag can be moved to a different image.
+pub(crate) fn sdk_digest(sdk: &str) -> Result<String> {
+    let id = cmd!("docker", "image", "inspect", "--format", "{{.Id}}", sdk)
+        .stderr_null()
+        .read()
+        .context(error::SdkDigestSnafu { sdk })?;
+    let id = id.trim();
+    ensure!(!id.is_empty(), error::SdkDigestMissingSnafu { sdk });
+    Ok(id.to_string())
+}
+
+#[derive(Debug, Clone)]
+enum Backend {
+    Http(Url),
+    S3(String),
+}
+
+#[derive(Clone)]
+pub(crate) struct RemoteCache {
+    /// The version string to include in HTTP headers.
+    version: String,
+
+    backend: Backend,
+
+    /// Whether successful builds should be uploaded to the cache.
+    upload: bool,
+
+    /// The bearer token that HTTP requests are authorized with.
+    token: Option<String>,
+}
+
+impl RemoteCache {
+    /// Create a remote cache from an `http(s)://` or `s3://` URL.
+    pub(crate) fn new(version: impl AsRef<str>, url: &str, upload: bool) -> Result<Self> {
+        let parsed = Url::parse(url).context(error::CacheUrlSnafu { url })?;
+        let backend = match parsed.scheme() {
+            "http" | "https" => {
+                // Make sure joining an entry name appends to the path rather than replacing the
+                // last segment.
+                let mut parsed = parsed;
+                if !parsed.path().ends_with('/') {
+                    parsed.set_path(&format!("{}/", parsed.path()));
+                }
+                Backend::Http(parsed)
+            }
+            "s3" => Backend::S3(url.trim_end_matches('/').to_string()),
+            scheme => {
+                return error::CacheSchemeSnafu {
+                    url,
+                    scheme: scheme.to_string(),
+                }
+                .fail()
+            }
+        };
+        Ok(Self {
+            version: version.as_ref().to_string(),
+            backend,
+            upload,
+            token: None,
+        })
+    }
+
+    /// Authorize HTTP requests with `token`, as a bearer token.
+    pub(crate) fn token(mut self, token: Option<String>) -> Self {
+        self.token = token.filter(|token| !token.is_empty());
+        self
+    }
+
+    pub(crate) fn upload_enabled(&self) -> bool {
+        self.upload
+    }
+
+    /// Looks up the entry for `key` and, if it exists, unpacks it into `dir`. Returns whether the
+    /// entry was found.
+    pub(crate) fn fetch(&self, key: &str, dir: &Path) -> Result<bool> {
+        let archive = dir.join(format!(".{}", entry_name(key)));
+        if !self.get(&entry_name(key), &archive)? {
+            return Ok(false);
+        }
+
+        let unpack = cmd!("tar", "-xf", &archive, "-C", dir)
+            .stderr_to_stdout()
+            .stdout_capture()
+            .unchecked()
+            .run()
+            .context(error::CommandStartSnafu)?;
+        fs::remove_file(&archive).context(error::FileRemoveSnafu { path: &archive })?;
+        ensure!(
+            unpack.status.success(),
+            error::ArchiveSnafu {
+                path: &archive,
+                output: String::from_utf8_lossy(&unpack.stdout),
+            }
+        );
+        Ok(true)
+    }
+
+    /// Packs the contents of `dir` and uploads it as the entry for `key`.
+    pub(crate) fn store(&self, key: &str, dir: &Path) -> Result<()> {
+        let archive = std::env::temp_dir().join(entry_name(key));
+        let pack = cmd!("tar", "-cf", &archive, "-C", dir, ".")
+            .stderr_to_stdout()
+            .stdout_capture()
+            .unchecked()
+            .run()
+            .context(error::CommandStartSnafu)?;
+        ensure!(
+            pack.status.success(),
+            error::ArchiveSnafu {
+                path: &archive,
+                output: String::from_utf8_lossy(&pack.stdout),
+            }
+        );
+
+        let result = self.put(&entry_name(key), &archive);
+        fs::remove_file(&archive).context(error::FileRemoveSnafu { path: &archive })?;
+        result
+    }
+
+    /// Downloads the external file whose digest is `sha512` to `path`. Returns whether the cache
+    /// has it. The caller checks that the file matches the digest.
+    pub(crate) fn fetch_source(&self, sha512: &str, path: &Path) -> Result<bool> {
+        self.get(&source_entry_name(sha512), path)
+    }
+
+    /// Uploads the external file at `path`, whose digest is `sha512`.
+    pub(crate) fn store_source(&self, sha512: &str, path: &Path) -> Result<()> {
+        self.put(&source_entry_name(sha512), path)
+    }
+
+    /// Downloads the bundle whose inputs hash to `key` to `path`. Returns whether the cache has it.
+    pub(crate) fn fetch_bundle(&self, key: &str, path: &Path) -> Result<bool> {
+        self.get(&bundle_entry_name(key), path)
+    }
+
+    /// Uploads the bundle at `path`, whose inputs hash to `key`.
+    pub(crate) fn store_bundle(&self, key: &str, path: &Path) -> Result<()> {
+        self.put(&bundle_entry_name(key), path)
+    }
+
+    /// Downloads the entry named `name` to `path`. Returns whether the entry exists.
+    fn get(&self, name: &str, path: &Path) -> Result<bool> {
+        match &self.backend {
+            Backend::Http(base) => self.http_get(base, name, path),
+            Backend::S3(base) => Ok(cmd!(
+                "aws",
+                "s3",
+                "cp",
+                "--only-show-errors",
+                format!("{}/{}", base, name),
+                path
+            )
+            .stdout_null()
+            .stderr_null()
+            .unchecked()
+            .run()
+            .context(error::CommandStartSnafu)?
+            .status
+            .success()),
+        }
+    }
+
+    /// Uploads the file at `path` as the entry named `name`.
+    fn put(&self, name: &str, path: &Path) -> Result<()> {
+        match &self.backend {
+            Backend::Http(base) => self.http_put(base, name, path),
+            Backend::S3(base) => {
+                let url = format!("{}/{}", base, name);
+                let output = cmd!("aws", "s3", "cp", "--only-show-errors", path, &url)
+                    .stderr_to_stdout()
+                    .stdout_capture()
+                    .unchecked()
+                    .run()
+                    .context(error::CommandStartSnafu)?;
+                ensure!(
+                    output.status.success(),
+                    error::UploadSnafu {
+                        url,
+                        output: String::from_utf8_lossy(&output.stdout),
+                    }
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn entry_url(&self, base: &Url, name: &str) -> Result<Url> {
+        base.join(name).context(error::CacheUrlSnafu { url: name })
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            USER_AGENT,
+            HeaderValue::from_str(&format!(
+                "Bottlerocket buildsys {} (https://github.com/bottlerocket-os/bottlerocket)",
+                self.version
+            ))
+            .unwrap_or(HeaderValue::from_static(
+                "Bottlerocket buildsys (https://github.com/bottlerocket-os/bottlerocket)",
+            )),
+        );
+        if let Some(token) = &self.token {
+            if let Ok(mut value) = HeaderValue::from_str(&format!("Bearer {token}")) {
+                value.set_sensitive(true);
+                headers.insert(AUTHORIZATION, value);
+            }
+        }
+        headers
+    }
+
+    fn http_get(&self, base: &Url, name: &str, path: &Path) -> Result<bool> {
+        let url = self.entry_url(base, name)?;
+        let mut resp = reqwest::blocking::Client::new()
+            .get(url.clone())
+            .headers(self.headers())
+            .send()
+            .context(error::RequestSnafu { url: url.as_str() })?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let status = resp.status();
+        ensure!(
+            status.is_success(),
+            error::ResponseSnafu {
+                url: url.as_str(),
+                status
+            }
+        );
+
+        let f = File::create(path).context(error::FileCreateSnafu { path })?;
+        let mut f = BufWriter::new(f);
+        resp.copy_to(&mut f)
+            .context(error::DownloadSnafu { path })?;
+        Ok(true)
+    }
+
+    fn http_put(&self, base: &Url, name: &str, path: &Path) -> Result<()> {
+        let url = self.entry_url(base, name)?;
+        let f = File::open(path).context(error::FileOpenSnafu { path })?;
+        let resp = reqwest::blocking::Client::new()
+            .put(url.clone())
+            .headers(self.headers())
+            .body(f)
+            .send()
+            .context(error::RequestSnafu { url: url.as_str() })?;
+        let status = resp.status();
+        ensure!(
+            status.is_success(),
+            error::ResponseSnafu {
+                url: url.as_str(),
+                status
+            }
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_ignores_input_order() {
+        let mut a = BuildInputs::default();
+        a.value("arch", "x86_64").value("sdk", "sha256:1234");
+        let mut b = BuildInputs::default();
+        b.value("sdk", "sha256:1234").value("arch", "x86_64");
+        assert_eq!(a.key(), b.key());
+
+        b.value("arch", "aarch64");
+        assert_ne!(a.key(), b.key());
+    }
+
+    #[test]
+    fn key_separates_names_and_values() {
+        let mut a = BuildInputs::default();
+        a.value("ab", "c");
+        let mut b = BuildInputs::default();
+        b.value("a", "bc");
+        assert_ne!(a.key(), b.key());
+    }
+
+    #[test]
+    fn http_url_is_a_directory() {
+        let cache = RemoteCache::new("0.0", "https://example.com/rpm-cache", false).unwrap();
+        let Backend::Http(base) = &cache.backend else {
+            panic!("expected an HTTP backend");
+        };
+        assert_eq!(
+            cache.entry_url(base, &entry_name("abc")).unwrap().as_str(),
+            "https://example.com/rpm-cache/abc.tar"
+        );
+        assert_eq!(
+            cache
+                .entry_url(base, &source_entry_name("abc"))
+                .unwrap()
+                .as_str(),
+            "https://example.com/rpm-cache/sources/abc"
+        );
+    }
+
+    #[test]
+    fn token_is_a_bearer_token() {
+        let cache = RemoteCache::new("0.0", "https://example.com/cache", false)
+            .unwrap()
+            .token(Some("secret".to_string()));
+        assert_eq!(cache.headers()[AUTHORIZATION], "Bearer secret");
+        let cache = cache.token(Some(String::new()));
+        assert!(!cache.headers().contains_key(AUTHORIZATION));
+    }
+
+    #[test]
+    fn unsupported_scheme() {
+        assert!(RemoteCache::new("0.0", "ftp://example.com/cache", false).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use snafu::Snafu;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to pack or unpack archive '{}': {}", path.display(), output))]
+    Archive { path: PathBuf, output: String },
+
+    #[snafu(display("Unsupported remote cache URL scheme '{}' in '{}'", scheme, url))]
+    CacheScheme { url: String, scheme: String },
+
+    #[snafu(display("Bad remote cache URL '{}': {}", url, source))]
+    CacheUrl {
+        url: String,
+        source: url::ParseError,
+    },
+
+    #[snafu(display("Failed to start command: {}", source))]
+    CommandStart { source: io::Error },
+
+    #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+    Download {
+        path: PathBuf,
+        source: reqwest::Error,
+    },
+
+    #[snafu(display("Failed to create file '{}': {}", path.display(), source))]
+    FileCreate { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to open file '{}': {}", path.display(), source))]
+    FileOpen { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to remove file '{}': {}", path.display(), source))]
+    FileRemove { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to read build input '{}': {}", path.display(), source))]
+    InputRead { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to walk build inputs in '{}': {}", path.display(), source))]
+    InputWalk {
+        path: PathBuf,
+        source: walkdir::Error,
+    },
+
+    #[snafu(display("Failed to request '{}': {}", url, source))]
+    Request { url: String, source: reqwest::Error },
+
+    #[snafu(display("Request to '{}' failed: {}", url, status))]
+    Response {
+        url: String,
+        status: reqwest::StatusCode,
+    },
+
+    #[snafu(display("Failed to find the image ID of SDK '{}': {}", sdk, source))]
+    SdkDigest { sdk: String, source: io::Error },
+
+    #[snafu(display("Docker did not report an image ID for SDK '{}'", sdk))]
+    SdkDigestMissing { sdk: String },
+
+    #[snafu(display("Failed to upload to '{}': {}", url, output))]
+    Upload { url: String, output: String },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
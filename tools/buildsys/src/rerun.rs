@@ -0,0 +1,152 @@
+/*!
+Tells cargo which files and environment variables should make it run a build again, and keeps
+track of them. When rebuilds are explained, the start of each run finds why it happened from the
+record of the last run, and the end of the run replaces that record with what it watched.
+*/
+
+use crate::args::Buildsys;
+use buildsys::rebuild::{self, FileState, Record};
+use buildsys::redact;
+use buildsys::BuildType;
+use lazy_static::lazy_static;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref WATCHED: Mutex<Watched> = Mutex::new(Watched::default());
+}
+
+/// The files and environment variables that cargo was told to watch during this run.
+#[derive(Debug, Default)]
+struct Watched {
+    files: BTreeSet<String>,
+    env: BTreeSet<String>,
+}
+
+fn watched() -> MutexGuard<'static, Watched> {
+    WATCHED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The value of a watched environment variable as it is recorded. Credentials are replaced with an
+/// HMAC with `key`, which still shows when they change.
+fn recorded_env(var: &str, key: &[u8]) -> Option<String> {
+    env::var(var)
+        .ok()
+        .map(|value| redact::recorded(var, &value, key))
+}
+
+/// Asks cargo to run the build again when the file at `path` changes. Relative paths are relative
+/// to the directory of the package or variant.
+pub(crate) fn file(path: impl AsRef<Path>) {
+    let path = path.as_ref().display().to_string();
+    println!("cargo:rerun-if-changed={}", path);
+    watched().files.insert(path);
+}
+
+/// Asks cargo to run the build again when the environment variable `var` changes.
+pub(crate) fn env(var: &str) {
+    println!("cargo:rerun-if-env-changed={}", var);
+    watched().env.insert(var.to_string());
+}
+
+/// Why this run of a build happened, which is recorded along with what it watched once it ends.
+pub(crate) struct Explanation {
+    path: PathBuf,
+    dir: PathBuf,
+    kind: &'static str,
+    name: String,
+    arch: String,
+    key: Vec<u8>,
+    started: u64,
+    reasons: Vec<rebuild::Reason>,
+}
+
+impl Explanation {
+    /// Finds why this run happened from the record of the last run, if rebuilds are explained.
+    pub(crate) fn start(args: &Buildsys) -> Option<Self> {
+        let common = args.command.common();
+        if args.print_config || common.explain_rebuilds != "true" {
+            return None;
+        }
+        let name = common.name();
+        let arch = common.arch.to_string();
+        let path = Record::path(&common.root_dir, &name, &arch);
+        let dir = common.cargo_manifest_dir.clone();
+        let key = match Record::key(&common.root_dir) {
+            Ok(key) => key,
+            Err(e) => {
+                println!("cargo:warning=Unable to explain why {name} was built: {e}");
+                return None;
+            }
+        };
+        let last = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Record>(&data).ok());
+        let reasons = rebuild::reasons(
+            last.as_ref(),
+            |file| FileState::read(&dir.join(file)),
+            |var| recorded_env(var, &key),
+        );
+        let kind = match args.command.build_type() {
+            BuildType::Package => "package",
+            BuildType::Kit => "kit",
+            BuildType::Variant => "variant",
+            BuildType::Repack => "repack",
+        };
+        Some(Self {
+            path,
+            dir,
+            kind,
+            name,
+            arch,
+            key,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            reasons,
+        })
+    }
+
+    /// Records what this run watched and why it happened. Failing to write the record never fails
+    /// the build.
+    pub(crate) fn finish(self, succeeded: bool) {
+        let watched = watched();
+        let record = Record {
+            kind: self.kind.to_string(),
+            name: self.name,
+            arch: self.arch,
+            started: self.started,
+            succeeded,
+            reasons: self.reasons,
+            files: watched
+                .files
+                .iter()
+                .map(|file| (file.clone(), FileState::read(&self.dir.join(file))))
+                .collect(),
+            env: watched
+                .env
+                .iter()
+                .map(|var| (var.clone(), recorded_env(var, &self.key)))
+                .collect(),
+        };
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| serde_json::to_vec_pretty(&record).map_err(std::io::Error::other))
+            .and_then(|json| fs::write(&self.path, json));
+        if let Err(e) = result {
+            println!(
+                "cargo:warning=Unable to record why {} was built in '{}': {}",
+                record.name,
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
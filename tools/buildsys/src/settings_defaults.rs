@@ -0,0 +1,220 @@
+/*!
+Storewolf loads a variant's settings defaults from the TOML files in `defaults.d`, where later
+files override what earlier ones set. That makes it hard to tell, without booting the image, what
+a setting defaults to and which package decided it.
+
+After a variant build, the defaults files that the image build recorded in `build-metadata.json`
+are merged the way storewolf merges them, and the result is written to `settings-defaults.json`
+next to the images. Each default is keyed by its dotted path, as in `settings.motd`, with its value
+and the file that set it, so that settings documentation and other tools can be generated from it.
+
+Host containers and bootstrap containers are checked along the way, and the build fails if any of
+them are set up wrong; see the `containers` module.
+
+*/
+mod containers;
+pub(crate) mod error;
+use error::Result;
+
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The metadata that the image build writes next to the images.
+const BUILD_METADATA: &str = "build-metadata.json";
+
+/// The merged defaults, written next to the images.
+pub(crate) const SETTINGS_DEFAULTS: &str = "settings-defaults.json";
+
+/// The part of the build metadata with the settings defaults.
+#[derive(Debug, Deserialize)]
+struct BuildMetadata {
+    /// The contents of each defaults file, by its path in the image.
+    #[serde(default)]
+    settings_defaults: BTreeMap<String, String>,
+}
+
+/// A merged default, and the defaults file that set it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct SettingDefault {
+    value: serde_json::Value,
+    source: String,
+}
+
+/// Merges the settings defaults of the variant build in `output_dir`, and writes them to
+/// `settings-defaults.json` there. Returns the path that was written, or `None` if the build
+/// didn't record its defaults.
+pub(crate) fn export(output_dir: &Path) -> Result<Option<PathBuf>> {
+    let metadata_path = output_dir.join(BUILD_METADATA);
+    if !metadata_path.is_file() {
+        return Ok(None);
+    }
+    let data = fs::read_to_string(&metadata_path).context(error::FileReadSnafu {
+        path: &metadata_path,
+    })?;
+    let metadata: BuildMetadata =
+        serde_json::from_str(&data).context(error::MetadataParseSnafu {
+            path: &metadata_path,
+        })?;
+
+    let merged = merge(&metadata.settings_defaults)?;
+    let problems = containers::check(&metadata.settings_defaults);
+    ensure!(
+        problems.is_empty(),
+        error::ContainerDefaultsSnafu {
+            problems: problems.join("\n  "),
+        }
+    );
+    let path = output_dir.join(SETTINGS_DEFAULTS);
+    let json = serde_json::to_string_pretty(&merged).context(error::SerializeSnafu)?;
+    fs::write(&path, json + "\n").context(error::FileWriteSnafu { path: &path })?;
+    Ok(Some(path))
+}
+
+/// Merges defaults files, given by name, in order of their names. Tables are merged key by key,
+/// and any other value replaces the one before it, along with where it came from.
+fn merge(files: &BTreeMap<String, String>) -> Result<BTreeMap<String, SettingDefault>> {
+    let mut merged = BTreeMap::new();
+    for (name, content) in files {
+        let table: toml::Table =
+            toml::from_str(content).context(error::DefaultsParseSnafu { name })?;
+        flatten(&mut merged, name, &[], &table);
+    }
+    Ok(merged)
+}
+
+/// Adds the values in `table` to `merged`. A value replaces everything that was set below its
+/// key, and a table replaces a value.
+fn flatten(
+    merged: &mut BTreeMap<String, SettingDefault>,
+    source: &str,
+    prefix: &[&str],
+    table: &toml::Table,
+) {
+    for (key, value) in table {
+        let mut path = prefix.to_vec();
+        path.push(key);
+        let dotted = dotted(&path);
+        merged.remove(&dotted);
+        match value {
+            toml::Value::Table(table) => flatten(merged, source, &path, table),
+            value => {
+                let below = format!("{dotted}.");
+                merged.retain(|key, _| !key.starts_with(&below));
+                merged.insert(
+                    dotted,
+                    SettingDefault {
+                        value: to_json(value),
+                        source: source.to_string(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Joins keys with dots, quoting the keys that aren't bare TOML keys.
+fn dotted(path: &[&str]) -> String {
+    path.iter()
+        .map(|key| {
+            let bare = !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if bare {
+                key.to_string()
+            } else {
+                format!("{key:?}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// TOML values as JSON. Dates and times have no JSON type, so they're written as strings.
+fn to_json(value: &toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(s) => s.clone().into(),
+        toml::Value::Integer(i) => (*i).into(),
+        toml::Value::Float(f) => (*f).into(),
+        toml::Value::Boolean(b) => (*b).into(),
+        toml::Value::Datetime(d) => d.to_string().into(),
+        toml::Value::Array(a) => a.iter().map(to_json).collect(),
+        toml::Value::Table(t) => t.iter().map(|(k, v)| (k.clone(), to_json(v))).collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    const BASE: &str = "/usr/share/storewolf/defaults.d/10-base.toml";
+    const VARIANT: &str = "/usr/share/storewolf/defaults.d/50-variant.toml";
+
+    #[test]
+    fn test_merge() {
+        let files = BTreeMap::from([
+            (
+                VARIANT.to_string(),
+                r#"
+settings.motd = "Welcome"
+settings.ntp.time-servers = ["time.example.com"]
+settings.kubernetes.node-labels."example.com/role" = "worker"
+"#
+                .to_string(),
+            ),
+            (
+                BASE.to_string(),
+                r#"
+[settings]
+motd = "Hello"
+ntp.time-servers = ["a.example.com", "b.example.com"]
+updates.seed = 42
+
+[metadata.settings.motd]
+affected-services = ["motd"]
+"#
+                .to_string(),
+            ),
+        ]);
+        let merged = merge(&files).unwrap();
+
+        assert_eq!(
+            merged["settings.motd"],
+            SettingDefault {
+                value: json!("Welcome"),
+                source: VARIANT.to_string()
+            }
+        );
+        assert_eq!(
+            merged["settings.ntp.time-servers"].value,
+            json!(["time.example.com"])
+        );
+        assert_eq!(merged["settings.updates.seed"].source, BASE);
+        assert_eq!(
+            merged[r#"settings.kubernetes.node-labels."example.com/role""#].source,
+            VARIANT
+        );
+        assert_eq!(
+            merged["metadata.settings.motd.affected-services"].value,
+            json!(["motd"])
+        );
+    }
+
+    #[test]
+    fn test_merge_replaces_table() {
+        let files = BTreeMap::from([
+            (
+                BASE.to_string(),
+                "settings.a.b = 1\nsettings.a.c = 2\n".to_string(),
+            ),
+            (VARIANT.to_string(), "settings.a = \"flat\"\n".to_string()),
+        ]);
+        let merged = merge(&files).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged["settings.a"].value, json!("flat"));
+    }
+}
@@ -0,0 +1,278 @@
+/*!
+Host containers and bootstrap containers are easy to get subtly wrong in defaults files: a key with
+a typo is ignored, an image reference that doesn't parse only fails once a host boots, and a
+container that two files of the same precedence define with different images is quietly taken
+from whichever file's name sorts later. A file of a higher precedence, like `50-variant.toml` after
+`10-base.toml`, is meant to override the containers of earlier ones, so that isn't a problem.
+
+These checks run when the defaults are merged, and each problem names the file and the dotted path
+of the setting, such as `settings.host-containers.admin.source`.
+*/
+
+use super::dotted;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::BTreeMap;
+
+lazy_static! {
+    /// The names of containers, which become the names of their systemd units and directories.
+    static ref NAME: Regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_-]*$").unwrap();
+
+    /// An image reference: an optional registry, a repository path, and a tag, a digest, or both.
+    static ref IMAGE: Regex = Regex::new(concat!(
+        r"^(?:[a-zA-Z0-9.-]+(?::[0-9]+)?/)?",
+        r"[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*",
+        r"(?:/[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*)*",
+        r"(?::[\w][\w.-]{0,127})?",
+        r"(?:@sha256:[0-9a-f]{64})?$",
+    ))
+    .unwrap();
+}
+
+/// The kinds of containers, by their table in `settings`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Host,
+    Bootstrap,
+}
+
+impl Kind {
+    fn table(&self) -> &'static str {
+        match self {
+            Kind::Host => "host-containers",
+            Kind::Bootstrap => "bootstrap-containers",
+        }
+    }
+
+    fn keys(&self) -> &'static [&'static str] {
+        match self {
+            Kind::Host => &["source", "enabled", "superpowered", "user-data"],
+            Kind::Bootstrap => &["source", "mode", "essential", "user-data"],
+        }
+    }
+}
+
+/// Checks the host and bootstrap containers in the defaults files, given by name, and returns a
+/// description of each problem. Files that don't parse are left to the merge to report.
+pub(super) fn check(files: &BTreeMap<String, String>) -> Vec<String> {
+    let mut problems = Vec::new();
+    // The file that set the source of each container, and the source, to find conflicts.
+    let mut sources: BTreeMap<(&str, String), (&str, String)> = BTreeMap::new();
+    for (file, content) in files {
+        let Ok(table) = toml::from_str::<toml::Table>(content) else {
+            continue;
+        };
+        let Some(settings) = table.get("settings").and_then(|s| s.as_table()) else {
+            continue;
+        };
+        for kind in [Kind::Host, Kind::Bootstrap] {
+            let Some(value) = settings.get(kind.table()) else {
+                continue;
+            };
+            let mut problem = |path: &[&str], message: String| {
+                problems.push(format!("'{file}' at {}: {message}", dotted(path)))
+            };
+            let Some(containers) = value.as_table() else {
+                problem(&["settings", kind.table()], "must be a table".to_string());
+                continue;
+            };
+            for (name, container) in containers {
+                let path = ["settings", kind.table(), name.as_str()];
+                if !NAME.is_match(name) {
+                    problem(
+                        &path,
+                        "names may only have letters, digits, '-' and '_', and must start \
+                        with a letter or digit"
+                            .to_string(),
+                    );
+                }
+                let Some(container) = container.as_table() else {
+                    problem(&path, "must be a table".to_string());
+                    continue;
+                };
+                for (key, value) in container {
+                    let path = ["settings", kind.table(), name.as_str(), key.as_str()];
+                    if let Some(message) = check_value(kind, key, value) {
+                        problem(&path, message);
+                    }
+                }
+
+                let Some(source) = container.get("source").and_then(|s| s.as_str()) else {
+                    continue;
+                };
+                match sources.get(&(kind.table(), name.clone())) {
+                    Some((earlier, earlier_source))
+                        if earlier_source != source && precedence(earlier) == precedence(file) =>
+                    {
+                        problem(
+                            &["settings", kind.table(), name.as_str(), "source"],
+                            format!(
+                                "'{source}' conflicts with '{earlier_source}' from '{earlier}', \
+                                 which has the same precedence"
+                            ),
+                        )
+                    }
+                    _ => {
+                        sources.insert(
+                            (kind.table(), name.clone()),
+                            (file.as_str(), source.to_string()),
+                        );
+                    }
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// The precedence of a defaults file, which is the number that its name starts with, like `50` in
+/// `50-variant.toml`. Which of two files with the same number wins only depends on the rest of
+/// their names. A file whose name doesn't start with a number has a precedence of its own.
+fn precedence(file: &str) -> &str {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    let digits = name.len() - name.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits == 0 {
+        name
+    } else {
+        &name[..digits]
+    }
+}
+
+/// Checks one setting of a container, and describes what is wrong with it.
+fn check_value(kind: Kind, key: &str, value: &toml::Value) -> Option<String> {
+    if !kind.keys().contains(&key) {
+        return Some(format!(
+            "is not a setting of {}; expected one of {}",
+            kind.table(),
+            kind.keys().join(", ")
+        ));
+    }
+    match (key, value) {
+        ("source", toml::Value::String(source)) => (!IMAGE.is_match(source))
+            .then(|| format!("'{source}' is not an image reference, like 'registry/repo:tag'")),
+        ("mode", toml::Value::String(mode)) => (!["off", "once", "always"]
+            .contains(&mode.as_str()))
+        .then(|| format!("'{mode}' is not one of 'off', 'once', or 'always'")),
+        ("user-data", toml::Value::String(_)) => None,
+        ("source" | "mode" | "user-data", value) => {
+            Some(format!("must be a string, not {}", value.type_str()))
+        }
+        // The rest of the settings are flags.
+        (_, toml::Value::Boolean(_)) => None,
+        (_, value) => Some(format!("must be a boolean, not {}", value.type_str())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: &str = "/usr/share/storewolf/defaults.d/10-base.toml";
+    const VARIANT: &str = "/usr/share/storewolf/defaults.d/50-variant.toml";
+    const ADMIN: &str = "/usr/share/storewolf/defaults.d/50-admin.toml";
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(precedence(BASE), "10");
+        assert_eq!(precedence(VARIANT), precedence(ADMIN));
+        assert_eq!(precedence("/defaults.d/variant.toml"), "variant.toml");
+    }
+
+    #[test]
+    fn test_image_references() {
+        for image in [
+            "public.ecr.aws/bottlerocket/bottlerocket-admin:v0.11.0",
+            "localhost:5000/admin",
+            "admin@sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+            "example.com/team/control_v2:latest",
+        ] {
+            assert!(IMAGE.is_match(image), "{image}");
+        }
+        for image in [
+            "",
+            "public.ecr.aws/Bottlerocket/admin",
+            "admin:",
+            "admin v1",
+            "admin@sha256:abc",
+        ] {
+            assert!(!IMAGE.is_match(image), "{image}");
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let files = BTreeMap::from([
+            (
+                BASE.to_string(),
+                r#"
+[settings.host-containers.admin]
+enabled = false
+source = "public.ecr.aws/bottlerocket/bottlerocket-admin:v0.11.0"
+superpowered = true
+
+[settings.bootstrap-containers.setup]
+essentail = true
+mode = "sometimes"
+source = "example.com/Setup:v1"
+"#
+                .to_string(),
+            ),
+            (
+                ADMIN.to_string(),
+                r#"
+[settings.host-containers.admin]
+source = "public.ecr.aws/bottlerocket/bottlerocket-admin:v0.13.0"
+"#
+                .to_string(),
+            ),
+            (
+                VARIANT.to_string(),
+                r#"
+[settings.host-containers.admin]
+enabled = "yes"
+source = "public.ecr.aws/bottlerocket/bottlerocket-admin:v0.12.0"
+
+[settings.host-containers."my admin"]
+source = "example.com/admin:v1"
+"#
+                .to_string(),
+            ),
+        ]);
+        assert_eq!(
+            check(&files),
+            vec![
+                format!(
+                    "'{BASE}' at settings.bootstrap-containers.setup.essentail: is not a setting \
+                     of bootstrap-containers; expected one of source, mode, essential, user-data"
+                ),
+                format!(
+                    "'{BASE}' at settings.bootstrap-containers.setup.mode: 'sometimes' is not one \
+                     of 'off', 'once', or 'always'"
+                ),
+                format!(
+                    "'{BASE}' at settings.bootstrap-containers.setup.source: \
+                     'example.com/Setup:v1' is not an image reference, like 'registry/repo:tag'"
+                ),
+                format!(
+                    "'{VARIANT}' at settings.host-containers.admin.enabled: must be a boolean, \
+                     not string"
+                ),
+                format!(
+                    "'{VARIANT}' at settings.host-containers.admin.source: \
+                     'public.ecr.aws/bottlerocket/bottlerocket-admin:v0.12.0' conflicts with \
+                     'public.ecr.aws/bottlerocket/bottlerocket-admin:v0.13.0' from '{ADMIN}', \
+                     which has the same precedence"
+                ),
+                format!(
+                    "'{VARIANT}' at settings.host-containers.\"my admin\": names may only have \
+                     letters, digits, '-' and '_', and must start with a letter or digit"
+                ),
+            ]
+        );
+        assert!(check(&BTreeMap::from([(
+            BASE.to_string(),
+            "[settings".to_string()
+        )]))
+        .is_empty());
+    }
+}
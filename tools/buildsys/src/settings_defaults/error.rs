@@ -0,0 +1,38 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+    FileRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+    FileWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to parse build metadata '{}': {}", path.display(), source))]
+    MetadataParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Failed to parse settings defaults in '{}': {}", name, source))]
+    DefaultsParse {
+        name: String,
+        source: toml::de::Error,
+    },
+
+    #[snafu(display("Invalid host or bootstrap container defaults:\n  {}", problems))]
+    ContainerDefaults { problems: String },
+
+    #[snafu(display("Failed to serialize settings defaults: {}", source))]
+    Serialize { source: serde_json::Error },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -6,7 +6,7 @@ only purpose is to extract Source and Patch declarations so they can be passed
 to Cargo as files to watch for changes.
 
 */
-pub(crate) mod error;
+pub mod error;
 use error::Result;
 
 use snafu::ResultExt;
@@ -15,14 +15,14 @@ use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 
-pub(crate) struct SpecInfo {
-    pub(crate) sources: Vec<PathBuf>,
-    pub(crate) patches: Vec<PathBuf>,
+pub struct SpecInfo {
+    pub sources: Vec<PathBuf>,
+    pub patches: Vec<PathBuf>,
 }
 
 impl SpecInfo {
     /// Returns a list of 'Source' and 'Patch' lines found in a spec file.
-    pub(crate) fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
         let (sources, patches) = Self::parse(path)?;
         let sources = Self::filter(&sources);
         let patches = Self::filter(&patches);
@@ -4,7 +4,7 @@ use std::path::PathBuf;
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(super)))]
-pub(crate) enum Error {
+pub enum Error {
     #[snafu(display("Failed to read spec file '{}': {}", path.display(), source))]
     SpecFileRead { path: PathBuf, source: io::Error },
 }
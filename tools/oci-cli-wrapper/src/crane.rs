@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::Path;
 
@@ -37,6 +38,17 @@ impl ImageToolImpl for CraneCLI {
             .await
     }
 
+    async fn get_digest(&self, uri: &str) -> Result<String> {
+        let bytes = self
+            .cli
+            .output(
+                &["digest", uri],
+                format!("failed to fetch digest for resource at {}", uri),
+            )
+            .await?;
+        Ok(String::from_utf8_lossy(&bytes).trim().to_string())
+    }
+
     async fn get_config(&self, uri: &str) -> Result<ConfigView> {
         let bytes = self
             .cli
@@ -71,6 +83,7 @@ impl ImageToolImpl for CraneCLI {
         &self,
         platform_images: Vec<(DockerArchitecture, String)>,
         uri: &str,
+        annotations: &BTreeMap<String, String>,
     ) -> Result<()> {
         let images: Vec<&str> = platform_images
             .iter()
@@ -89,6 +102,36 @@ impl ImageToolImpl for CraneCLI {
             )
             .await?;
 
+        if annotations.is_empty() {
+            return Ok(());
+        }
+        // `index append` can't set annotations, so mutate the index in place after pushing it.
+        let annotation_args: Vec<String> = annotations
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        let mut mutate_args = vec!["mutate", uri];
+        for annotation in &annotation_args {
+            mutate_args.extend_from_slice(&["--annotation", annotation]);
+        }
+        mutate_args.extend_from_slice(&["-t", uri]);
+        self.cli
+            .output(
+                &mutate_args,
+                format!("could not annotate multi-platform manifest at {}", uri),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn tag(&self, uri: &str, tag: &str) -> Result<()> {
+        self.cli
+            .output(
+                &["tag", uri, tag],
+                format!("could not tag {} as {}", uri, tag),
+            )
+            .await?;
         Ok(())
     }
 }
@@ -12,7 +12,10 @@
 //!     metadata. In addition, in order to operate with OCI image format, the containerd-snapshotter
 //!     feature has to be enabled in the docker daemon
 use std::fmt::{Display, Formatter};
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
 use async_trait::async_trait;
 use cli::CommandLine;
@@ -74,21 +77,32 @@ impl ImageTool {
         Ok(canonicalized_manifest)
     }
 
+    /// Fetch the registry digest of an image's manifest, e.g. "sha256:abc..."
+    pub async fn get_digest(&self, uri: &str) -> Result<String> {
+        self.image_tool_impl.get_digest(uri).await
+    }
+
     /// Push a single-arch image in oci archive format
     pub async fn push_oci_archive(&self, path: &Path, uri: &str) -> Result<()> {
         self.image_tool_impl.push_oci_archive(path, uri).await
     }
 
-    /// Push the multi-arch kit manifest list
+    /// Push the multi-arch kit manifest list, with the given annotations on the index
     pub async fn push_multi_platform_manifest(
         &self,
         platform_images: Vec<(DockerArchitecture, String)>,
         uri: &str,
+        annotations: &BTreeMap<String, String>,
     ) -> Result<()> {
         self.image_tool_impl
-            .push_multi_platform_manifest(platform_images, uri)
+            .push_multi_platform_manifest(platform_images, uri, annotations)
             .await
     }
+
+    /// Add another tag to an image that already exists in the registry
+    pub async fn tag(&self, uri: &str, tag: &str) -> Result<()> {
+        self.image_tool_impl.tag(uri, tag).await
+    }
 }
 
 #[async_trait]
@@ -99,14 +113,19 @@ pub trait ImageToolImpl: std::fmt::Debug + Send + Sync + 'static {
     async fn get_config(&self, uri: &str) -> Result<ConfigView>;
     /// Fetch the manifest
     async fn get_manifest(&self, uri: &str) -> Result<Vec<u8>>;
+    /// Fetch the registry digest of an image's manifest
+    async fn get_digest(&self, uri: &str) -> Result<String>;
     /// Push a single-arch image in oci archive format
     async fn push_oci_archive(&self, path: &Path, uri: &str) -> Result<()>;
-    /// Push the multi-arch kit manifest list
+    /// Push the multi-arch kit manifest list, with the given annotations on the index
     async fn push_multi_platform_manifest(
         &self,
         platform_images: Vec<(DockerArchitecture, String)>,
         uri: &str,
+        annotations: &BTreeMap<String, String>,
     ) -> Result<()>;
+    /// Add another tag to an image that already exists in the registry
+    async fn tag(&self, uri: &str, tag: &str) -> Result<()>;
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
@@ -0,0 +1,572 @@
+//! The marketplace module owns the 'publish-marketplace' subcommand, which adds a published AMI
+//! to an AWS Marketplace product as a new version, or updates the details of a version the
+//! product already has, through the AWS Marketplace Catalog API.
+//!
+//! Changes to a product are made as a change set, which AWS Marketplace scans and applies over
+//! the following minutes or hours, so the change set is polled until it succeeds or fails.
+
+use crate::aws::ami::Image;
+use crate::aws::client::build_client_config;
+use crate::aws::region_from_string;
+use crate::Args;
+use aws_sdk_marketplacecatalog::types::{Change, ChangeStatus, Entity};
+use aws_sdk_marketplacecatalog::Client as CatalogClient;
+use chrono::Duration;
+use clap::Parser;
+use log::{info, trace};
+use parse_datetime::parse_offset;
+use pubsys_config::InfraConfig;
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::fs;
+
+/// The catalog that AMI products are in.
+const CATALOG: &str = "AWSMarketplace";
+
+/// The type of the entities that are AMI products.
+const ENTITY_TYPE: &str = "AmiProduct@1.0";
+
+/// The only region that the Catalog API is served from.
+const CATALOG_REGION: &str = "us-east-1";
+
+/// How long to wait between checks of a change set's status.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Adds an AMI to an AWS Marketplace product as a new version, or updates an existing version
+#[derive(Debug, Parser)]
+pub(crate) struct MarketplaceArgs {
+    /// Path to the JSON file containing regional AMI IDs, as written by the 'ami' subcommand
+    #[arg(long)]
+    ami_input: PathBuf,
+
+    /// The region of the AMI to publish; AWS Marketplace copies it from there
+    #[arg(long, default_value = "us-east-1")]
+    region: String,
+
+    /// The ID of the product, e.g. "prod-abcdefgh12345"
+    #[arg(long)]
+    product_id: String,
+
+    /// The IAM role that AWS Marketplace assumes to copy the AMI of a new version
+    #[arg(long, required_unless_present = "delivery_option_id")]
+    access_role_arn: Option<String>,
+
+    /// The title of the new version, e.g. "1.20.0"
+    #[arg(long, required_unless_present = "delivery_option_id")]
+    version_title: Option<String>,
+
+    /// The release notes of the new version
+    #[arg(long, required_unless_present = "delivery_option_id")]
+    release_notes: Option<String>,
+
+    /// Update the details of the version with this delivery option ID, rather than adding a new
+    /// version. The AMI of a version can't be changed
+    #[arg(long)]
+    delivery_option_id: Option<String>,
+
+    /// The user that customers log in as
+    #[arg(long, default_value = "ec2-user")]
+    username: String,
+
+    /// The operating system name that AWS Marketplace shows, e.g. "OTHERLINUX"
+    #[arg(long, default_value = "OTHERLINUX")]
+    os_name: String,
+
+    /// The operating system version that AWS Marketplace shows for a new version
+    #[arg(long, required_unless_present = "delivery_option_id")]
+    os_version: Option<String>,
+
+    /// How customers use the AMI once it's launched
+    #[arg(long)]
+    usage_instructions: String,
+
+    /// The instance type that AWS Marketplace recommends, e.g. "m5.large"
+    #[arg(long)]
+    recommended_instance_type: String,
+
+    /// Ingress rules for the security group that AWS Marketplace suggests, as
+    /// "<protocol>:<port>[-<port>]:<cidr>", e.g. "tcp:443:0.0.0.0/0"
+    #[arg(long = "ingress", value_parser = parse_ingress)]
+    ingress: Vec<SecurityGroup>,
+
+    /// How long to wait for AWS Marketplace to apply the change set, e.g. "4 hours"
+    #[arg(long, value_parser = parse_offset, default_value = "2 hours")]
+    timeout: Duration,
+
+    /// Print the change set without starting it
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// A change set document, as the Catalog API takes it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ChangeSetDocument {
+    catalog: &'static str,
+    change_set_name: String,
+    change_set: Vec<ChangeDocument>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ChangeDocument {
+    change_type: &'static str,
+    entity: EntityDocument,
+    details: ChangeDetails,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EntityDocument {
+    #[serde(rename = "Type")]
+    entity_type: &'static str,
+    identifier: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ChangeDetails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<Version>,
+    delivery_options: Vec<DeliveryOption>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Version {
+    version_title: String,
+    release_notes: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeliveryOption {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    details: DeliveryOptionDetails,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeliveryOptionDetails {
+    ami_delivery_option_details: AmiDeliveryOptionDetails,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AmiDeliveryOptionDetails {
+    /// Only new versions have a source, since the AMI of a version can't be changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ami_source: Option<AmiSource>,
+    usage_instructions: String,
+    recommended_instance_type: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    security_groups: Vec<SecurityGroup>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AmiSource {
+    ami_id: String,
+    access_role_arn: String,
+    user_name: String,
+    operating_system_name: String,
+    operating_system_version: String,
+}
+
+/// An ingress rule of the security group that AWS Marketplace suggests for the AMI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct SecurityGroup {
+    ip_protocol: String,
+    from_port: u16,
+    to_port: u16,
+    ip_ranges: Vec<String>,
+}
+
+/// Parses an ingress rule like "tcp:443:0.0.0.0/0" or "tcp:8000-8080:10.0.0.0/8".
+fn parse_ingress(input: &str) -> std::result::Result<SecurityGroup, String> {
+    let invalid = || format!("'{input}' is not like '<protocol>:<port>[-<port>]:<cidr>'");
+    let mut parts = input.splitn(3, ':');
+    let (Some(protocol), Some(ports), Some(cidr)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    let (from, to) = ports.split_once('-').unwrap_or((ports, ports));
+    let (Ok(from_port), Ok(to_port)) = (from.parse::<u16>(), to.parse::<u16>()) else {
+        return Err(invalid());
+    };
+    if protocol.is_empty() || cidr.is_empty() || from_port > to_port {
+        return Err(invalid());
+    }
+    Ok(SecurityGroup {
+        ip_protocol: protocol.to_string(),
+        from_port,
+        to_port,
+        ip_ranges: vec![cidr.to_string()],
+    })
+}
+
+impl MarketplaceArgs {
+    /// The change set that adds `image` to the product as a new version, or that updates the
+    /// version with the given delivery option. The details of a new version are required unless
+    /// there is a delivery option, so they're only missing from updates, which don't use them.
+    fn change_set(&self, image: &Image) -> ChangeSetDocument {
+        let (change_type, change_set_name, version, ami_source) = match &self.delivery_option_id {
+            Some(id) => (
+                "UpdateDeliveryOptions",
+                format!("Update {} {}", image.name, id),
+                None,
+                None,
+            ),
+            None => {
+                let version_title = self.version_title.clone().unwrap_or_default();
+                (
+                    "AddDeliveryOptions",
+                    format!("Publish {} {}", image.name, version_title),
+                    Some(Version {
+                        version_title,
+                        release_notes: self.release_notes.clone().unwrap_or_default(),
+                    }),
+                    Some(AmiSource {
+                        ami_id: image.id.clone(),
+                        access_role_arn: self.access_role_arn.clone().unwrap_or_default(),
+                        user_name: self.username.clone(),
+                        operating_system_name: self.os_name.clone(),
+                        operating_system_version: self.os_version.clone().unwrap_or_default(),
+                    }),
+                )
+            }
+        };
+        ChangeSetDocument {
+            catalog: CATALOG,
+            change_set_name,
+            change_set: vec![ChangeDocument {
+                change_type,
+                entity: EntityDocument {
+                    entity_type: ENTITY_TYPE,
+                    identifier: self.product_id.clone(),
+                },
+                details: ChangeDetails {
+                    version,
+                    delivery_options: vec![DeliveryOption {
+                        id: self.delivery_option_id.clone(),
+                        details: DeliveryOptionDetails {
+                            ami_delivery_option_details: AmiDeliveryOptionDetails {
+                                ami_source,
+                                usage_instructions: self.usage_instructions.clone(),
+                                recommended_instance_type: self.recommended_instance_type.clone(),
+                                security_groups: self.ingress.clone(),
+                            },
+                        },
+                    }],
+                },
+            }],
+        }
+    }
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, marketplace_args: &MarketplaceArgs) -> Result<()> {
+    info!(
+        "Using AMI data from path: {}",
+        marketplace_args.ami_input.display()
+    );
+    let ami_input_bytes =
+        fs::read(&marketplace_args.ami_input)
+            .await
+            .context(error::FileSnafu {
+                path: &marketplace_args.ami_input,
+            })?;
+    let ami_input: HashMap<String, Image> =
+        serde_json::from_slice(&ami_input_bytes).context(error::DeserializeSnafu {
+            path: &marketplace_args.ami_input,
+        })?;
+    trace!("Parsed AMI input: {:?}", ami_input);
+    let image = ami_input
+        .get(&marketplace_args.region)
+        .context(error::MissingRegionSnafu {
+            path: &marketplace_args.ami_input,
+            region: &marketplace_args.region,
+        })?;
+
+    let document = marketplace_args.change_set(image);
+    if marketplace_args.dry_run {
+        let json = serde_json::to_string_pretty(&document).context(error::SerializeSnafu)?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    // If a lock file exists, use that, otherwise use Infra.toml or default
+    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
+        .context(error::ConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+    let aws = infra_config.aws.unwrap_or_default();
+    let region = region_from_string(CATALOG_REGION);
+    let client_config = build_client_config(&region, &region, &aws).await;
+    let client = CatalogClient::new(&client_config);
+
+    let change_set_id = start_change_set(&client, &document).await?;
+    info!(
+        "Started change set {} for product {}",
+        change_set_id, marketplace_args.product_id
+    );
+    wait_for_change_set(&client, &change_set_id, marketplace_args.timeout).await
+}
+
+/// Starts the change set in `document`, and returns its ID.
+async fn start_change_set(client: &CatalogClient, document: &ChangeSetDocument) -> Result<String> {
+    let mut request = client
+        .start_change_set()
+        .catalog(document.catalog)
+        .change_set_name(&document.change_set_name);
+    for change in &document.change_set {
+        let details = serde_json::to_string(&change.details).context(error::SerializeSnafu)?;
+        let entity = Entity::builder()
+            .r#type(change.entity.entity_type)
+            .identifier(&change.entity.identifier)
+            .build()
+            .context(error::BuildChangeSnafu)?;
+        request = request.change_set(
+            Change::builder()
+                .change_type(change.change_type)
+                .entity(entity)
+                .details(details)
+                .build()
+                .context(error::BuildChangeSnafu)?,
+        );
+    }
+    let output = request.send().await.context(error::StartChangeSetSnafu)?;
+    output
+        .change_set_id()
+        .map(str::to_string)
+        .context(error::MissingChangeSetIdSnafu)
+}
+
+/// Polls the change set until AWS Marketplace has applied it, or it fails, or `timeout` passes.
+async fn wait_for_change_set(
+    client: &CatalogClient,
+    change_set_id: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let started = Instant::now();
+    let timeout = timeout.to_std().unwrap_or_default();
+    loop {
+        let output = client
+            .describe_change_set()
+            .catalog(CATALOG)
+            .change_set_id(change_set_id)
+            .send()
+            .await
+            .context(error::DescribeChangeSetSnafu { change_set_id })?;
+        match output.status() {
+            Some(ChangeStatus::Succeeded) => {
+                info!("Change set {} succeeded", change_set_id);
+                return Ok(());
+            }
+            Some(status @ (ChangeStatus::Failed | ChangeStatus::Cancelled)) => {
+                let mut reasons = output
+                    .failure_description()
+                    .map(str::to_string)
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                for change in output.change_set() {
+                    for detail in change.error_detail_list() {
+                        reasons.push(format!(
+                            "{}: {}",
+                            detail.error_code().unwrap_or("error"),
+                            detail.error_message().unwrap_or_default()
+                        ));
+                    }
+                }
+                return error::ChangeSetSnafu {
+                    change_set_id,
+                    status: status.as_str(),
+                    reasons: reasons.join("; "),
+                }
+                .fail();
+            }
+            status => {
+                ensure!(
+                    started.elapsed() < timeout,
+                    error::TimeoutSnafu { change_set_id }
+                );
+                info!(
+                    "Waiting for change set {}, which is {}",
+                    change_set_id,
+                    status.map_or("pending", |s| s.as_str())
+                );
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+mod error {
+    use aws_sdk_marketplacecatalog::error::{BuildError, SdkError};
+    use aws_sdk_marketplacecatalog::operation::{
+        describe_change_set::DescribeChangeSetError, start_change_set::StartChangeSetError,
+    };
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    #[allow(clippy::large_enum_variant)]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to build change: {}", source))]
+        BuildChange { source: BuildError },
+
+        #[snafu(display("Change set {} is {}: {}", change_set_id, status, reasons))]
+        ChangeSet {
+            change_set_id: String,
+            status: String,
+            reasons: String,
+        },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to describe change set {}: {}",
+            change_set_id,
+            DisplayErrorContext(source)
+        ))]
+        DescribeChangeSet {
+            change_set_id: String,
+            source: SdkError<DescribeChangeSetError>,
+        },
+
+        #[snafu(display("Failed to deserialize input from '{}': {}", path.display(), source))]
+        Deserialize {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        File {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Starting the change set returned no ID"))]
+        MissingChangeSetId,
+
+        #[snafu(display("'{}' has no AMI in {}", path.display(), region))]
+        MissingRegion { path: PathBuf, region: String },
+
+        #[snafu(display("Failed to serialize change set: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to start change set: {}", DisplayErrorContext(source)))]
+        StartChangeSet {
+            source: SdkError<StartChangeSetError>,
+        },
+
+        #[snafu(display("Timed out waiting for change set {}", change_set_id))]
+        Timeout { change_set_id: String },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_ingress() {
+        assert_eq!(
+            parse_ingress("tcp:8000-8080:10.0.0.0/8").unwrap(),
+            SecurityGroup {
+                ip_protocol: "tcp".to_string(),
+                from_port: 8000,
+                to_port: 8080,
+                ip_ranges: vec!["10.0.0.0/8".to_string()],
+            }
+        );
+        assert!(parse_ingress("tcp:443").is_err());
+        assert!(parse_ingress("tcp:443-80:0.0.0.0/0").is_err());
+    }
+
+    #[test]
+    fn test_change_set() {
+        let mut args = MarketplaceArgs::try_parse_from([
+            "publish-marketplace",
+            "--ami-input",
+            "amis.json",
+            "--product-id",
+            "prod-abcdefgh12345",
+            "--access-role-arn",
+            "arn:aws:iam::123456789012:role/marketplace",
+            "--version-title",
+            "1.20.0",
+            "--release-notes",
+            "Fixes",
+            "--os-version",
+            "1.20.0",
+            "--usage-instructions",
+            "Join a cluster",
+            "--recommended-instance-type",
+            "m5.large",
+            "--ingress",
+            "tcp:443:0.0.0.0/0",
+        ])
+        .unwrap();
+        let image = Image {
+            id: "ami-0123".to_string(),
+            name: "bottlerocket-aws-k8s-1.29-x86_64-v1.20.0-abcd".to_string(),
+            public: None,
+            launch_permissions: None,
+        };
+
+        let document = serde_json::to_value(args.change_set(&image)).unwrap();
+        let change = &document["ChangeSet"][0];
+        assert_eq!(change["ChangeType"], "AddDeliveryOptions");
+        assert_eq!(change["Entity"]["Type"], "AmiProduct@1.0");
+        assert_eq!(change["Details"]["Version"]["VersionTitle"], "1.20.0");
+        let details = &change["Details"]["DeliveryOptions"][0]["Details"];
+        let ami = &details["AmiDeliveryOptionDetails"];
+        assert_eq!(ami["AmiSource"]["AmiId"], "ami-0123");
+        assert_eq!(ami["AmiSource"]["UserName"], "ec2-user");
+        assert_eq!(ami["SecurityGroups"][0]["FromPort"], 443);
+
+        args.delivery_option_id = Some("do-0123".to_string());
+        let document = serde_json::to_value(args.change_set(&image)).unwrap();
+        let change = &document["ChangeSet"][0];
+        assert_eq!(change["ChangeType"], "UpdateDeliveryOptions");
+        assert!(change["Details"].get("Version").is_none());
+        let option = &change["Details"]["DeliveryOptions"][0];
+        assert_eq!(option["Id"], "do-0123");
+        assert!(option["Details"]["AmiDeliveryOptionDetails"]
+            .get("AmiSource")
+            .is_none());
+    }
+
+    #[test]
+    fn test_update_args() {
+        let update = [
+            "publish-marketplace",
+            "--ami-input",
+            "amis.json",
+            "--product-id",
+            "prod-abcdefgh12345",
+            "--usage-instructions",
+            "Join a cluster",
+            "--recommended-instance-type",
+            "m5.large",
+        ];
+        assert!(MarketplaceArgs::try_parse_from(update).is_err());
+        let args = MarketplaceArgs::try_parse_from(
+            update
+                .into_iter()
+                .chain(["--delivery-option-id", "do-0123"]),
+        )
+        .unwrap();
+        assert!(args.version_title.is_none());
+    }
+}
@@ -5,8 +5,10 @@ use aws_sdk_ec2::types::ArchitectureValues;
 pub(crate) mod client;
 
 pub(crate) mod ami;
+pub(crate) mod marketplace;
 pub(crate) mod promote_ssm;
 pub(crate) mod publish_ami;
+pub(crate) mod retire_ami;
 pub(crate) mod ssm;
 pub(crate) mod validate_ami;
 pub(crate) mod validate_ssm;
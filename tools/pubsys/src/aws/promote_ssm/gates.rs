@@ -0,0 +1,198 @@
+//! Checks that promoted parameters point at AMIs that are ready to be promoted, before any
+//! parameter is changed.
+
+use crate::aws::ami::public::ami_is_public;
+use crate::aws::ami::Image;
+use crate::aws::ssm::SsmKey;
+use aws_sdk_ec2::{config::Region, Client as Ec2Client};
+use log::{error, info};
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Checks every parameter in `set_parameters` whose value is an AMI ID: the AMI must exist in the
+/// parameter's region, must be the one in `expected_amis` for that region if given, and must be
+/// public if `expected_amis` says it should be or the parameter is in the public namespace.
+pub(super) async fn check_images(
+    set_parameters: &HashMap<SsmKey, String>,
+    expected_amis: Option<&HashMap<String, Image>>,
+    ec2_clients: &HashMap<Region, Ec2Client>,
+) -> Result<()> {
+    let mut failures = Vec::new();
+    for (key, value) in set_parameters {
+        if !value.starts_with("ami-") {
+            continue;
+        }
+        let expected = expected_amis.map(|amis| amis.get(key.region.as_ref()));
+        let failure =
+            match ami_is_public(&ec2_clients[&key.region], key.region.as_ref(), value).await {
+                Ok(public) => check_image(key, value, expected, public),
+                Err(e) => Some(format!(
+                    "{} - {} - unable to find image {}: {}",
+                    key.name, key.region, value, e
+                )),
+            };
+        if let Some(failure) = failure {
+            error!("{}", failure);
+            failures.push(failure);
+        }
+    }
+    ensure!(
+        failures.is_empty(),
+        error::GatesSnafu {
+            count: failures.len()
+        }
+    );
+    info!("All promoted AMIs passed validation.");
+    Ok(())
+}
+
+/// Returns why the AMI `value` of parameter `key` can't be promoted, if it can't. `expected` is
+/// `None` if there's no release metadata to compare against.
+fn check_image(
+    key: &SsmKey,
+    value: &str,
+    expected: Option<Option<&Image>>,
+    public: bool,
+) -> Option<String> {
+    let region = key.region.as_ref();
+    if key.is_in_public_namespace() && !public {
+        return Some(format!(
+            "{} - {} - image {} is in a public parameter, but isn't public",
+            key.name, region, value
+        ));
+    }
+    match expected {
+        None => None,
+        Some(None) => Some(format!(
+            "{} - {} - the release has no AMI in this region",
+            key.name, region
+        )),
+        Some(Some(image)) if image.id != value => Some(format!(
+            "{} - {} - image {} isn't the release's AMI, {}",
+            key.name, region, value, image.id
+        )),
+        Some(Some(image)) if image.public == Some(true) && !public => Some(format!(
+            "{} - {} - image {} should be public, but isn't",
+            key.name, region, value
+        )),
+        Some(Some(_)) => None,
+    }
+}
+
+/// A parameter changed by a promotion.
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+struct Change<'a> {
+    region: &'a str,
+    name: &'a str,
+    old_value: Option<&'a str>,
+    new_value: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    source: &'a str,
+    target: &'a str,
+    changes: Vec<Change<'a>>,
+}
+
+/// Writes a JSON report of every parameter in `set_parameters`, with its value before the
+/// promotion from `current_parameters`.
+pub(super) async fn write_report(
+    path: &Path,
+    source: &str,
+    target: &str,
+    set_parameters: &HashMap<SsmKey, String>,
+    current_parameters: &HashMap<SsmKey, String>,
+) -> Result<()> {
+    let mut changes = set_parameters
+        .iter()
+        .map(|(key, value)| Change {
+            region: key.region.as_ref(),
+            name: &key.name,
+            old_value: current_parameters.get(key).map(String::as_str),
+            new_value: value,
+        })
+        .collect::<Vec<_>>();
+    changes.sort();
+    let report = Report {
+        source,
+        target,
+        changes,
+    };
+    let json = serde_json::to_string_pretty(&report).context(error::SerializeReportSnafu)?;
+    tokio::fs::write(path, json)
+        .await
+        .context(error::WriteReportSnafu { path })?;
+    info!("Wrote promotion report to {}", path.display());
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display(
+            "{} promoted AMIs failed validation; no parameters were changed",
+            count
+        ))]
+        Gates { count: usize },
+
+        #[snafu(display("Failed to serialize promotion report: {}", source))]
+        SerializeReport { source: serde_json::Error },
+
+        #[snafu(display("Failed to write promotion report to {}: {}", path.display(), source))]
+        WriteReport {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(id: &str, public: Option<bool>) -> Image {
+        Image {
+            id: id.to_string(),
+            name: "bottlerocket".to_string(),
+            public,
+            launch_permissions: None,
+        }
+    }
+
+    #[test]
+    fn test_check_image() {
+        let key = SsmKey::new(Region::new("us-west-2"), "latest/image_id".to_string());
+        let release = image("ami-1", Some(true));
+
+        assert!(check_image(&key, "ami-1", None, false).is_none());
+        assert!(check_image(&key, "ami-1", Some(Some(&release)), true).is_none());
+        assert!(check_image(&key, "ami-1", Some(None), true)
+            .unwrap()
+            .contains("no AMI in this region"));
+        assert!(check_image(&key, "ami-2", Some(Some(&release)), true)
+            .unwrap()
+            .contains("isn't the release's AMI"));
+        assert!(check_image(&key, "ami-1", Some(Some(&release)), false)
+            .unwrap()
+            .contains("should be public"));
+        let private = image("ami-1", Some(false));
+        assert!(check_image(&key, "ami-1", Some(Some(&private)), false).is_none());
+
+        let public_key = SsmKey::new(
+            Region::new("us-west-2"),
+            "/aws/service/bottlerocket/latest/image_id".to_string(),
+        );
+        assert!(check_image(&public_key, "ami-1", None, false)
+            .unwrap()
+            .contains("isn't public"));
+    }
+}
@@ -1,6 +1,10 @@
 //! The promote_ssm module owns the 'promote-ssm' subcommand and controls the process of copying
-//! SSM parameters from one version to another
+//! SSM parameters from one version to another, such as from a "candidate" to "latest". Nothing is
+//! copied unless every AMI the parameters refer to passes the checks in `gates`.
 
+mod gates;
+
+use crate::aws::ami::Image;
 use crate::aws::client::build_client_config;
 use crate::aws::ssm::template::RenderedParametersMap;
 use crate::aws::ssm::{key_difference, ssm, template, BuildContext, SsmKey};
@@ -8,6 +12,7 @@ use crate::aws::validate_ssm::parse_parameters;
 use crate::aws::{parse_arch, region_from_string};
 use crate::Args;
 use aws_sdk_ec2::types::ArchitectureValues;
+use aws_sdk_ec2::Client as Ec2Client;
 use aws_sdk_ssm::{config::Region, Client as SsmClient};
 use clap::Parser;
 use log::{info, trace};
@@ -47,6 +52,15 @@ pub(crate) struct PromoteArgs {
     /// and where the newly promoted parameters will be written
     #[arg(long)]
     ssm_parameter_output: Option<PathBuf>,
+
+    /// If set, the path to the JSON file of the release's regional AMIs; promoted AMIs must be
+    /// the ones it lists, and must be public if it says so
+    #[arg(long)]
+    ami_input: Option<PathBuf>,
+
+    /// If set, write a JSON report of every parameter that was changed to this path
+    #[arg(long)]
+    report: Option<PathBuf>,
 }
 
 /// Common entrypoint from main()
@@ -85,12 +99,19 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
     let base_region = &regions[0];
 
     let mut ssm_clients = HashMap::with_capacity(regions.len());
+    let mut ec2_clients = HashMap::with_capacity(regions.len());
     for region in &regions {
         let client_config = build_client_config(region, base_region, &aws).await;
         let ssm_client = SsmClient::new(&client_config);
         ssm_clients.insert(region.clone(), ssm_client);
+        ec2_clients.insert(region.clone(), Ec2Client::new(&client_config));
     }
 
+    let expected_amis = match &promote_args.ami_input {
+        Some(path) => Some(read_amis(path).await?),
+        None => None,
+    };
+
     // Template setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     // Non-image-specific context for building and rendering templates
@@ -207,6 +228,11 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
         return Ok(());
     }
 
+    info!("Validating the AMIs referenced by the promoted parameters.");
+    gates::check_images(&set_parameters, expected_amis.as_ref(), &ec2_clients)
+        .await
+        .context(error::GatesSnafu)?;
+
     // If an output file path was given, read the existing parameters in `ssm_parameter_output` and
     // write the newly promoted parameters to `ssm_parameter_output` along with the original
     // parameters
@@ -227,9 +253,29 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
         .context(error::ValidateSsmSnafu)?;
 
     info!("All parameters match requested values.");
+
+    if let Some(report) = &promote_args.report {
+        gates::write_report(
+            report,
+            &promote_args.source,
+            &promote_args.target,
+            &set_parameters,
+            &current_target_parameters,
+        )
+        .await
+        .context(error::GatesSnafu)?;
+    }
     Ok(())
 }
 
+/// Reads a JSON file of regional AMIs, as written by the `ami` subcommand.
+async fn read_amis(path: &PathBuf) -> Result<HashMap<String, Image>> {
+    let bytes = tokio::fs::read(path)
+        .await
+        .context(error::ReadAmiInputSnafu { path })?;
+    serde_json::from_slice(&bytes).context(error::ParseAmiInputSnafu { path })
+}
+
 /// Read parameters in given file, add newly promoted parameters, and write combined parameters to
 /// the given file
 async fn append_rendered_parameters(
@@ -315,12 +361,27 @@ mod error {
         #[snafu(display("Failed to fetch parameters from SSM: {}", source))]
         FetchSsm { source: ssm::Error },
 
+        #[snafu(display("{}", source))]
+        Gates { source: super::gates::Error },
+
         #[snafu(display("Failed to find templates: {}", source))]
         FindTemplates { source: template::Error },
 
         #[snafu(display("Infra.toml is missing {}", missing))]
         MissingConfig { missing: String },
 
+        #[snafu(display("Failed to parse AMI input '{}': {}", path.display(), source))]
+        ParseAmiInput {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read AMI input '{}': {}", path.display(), source))]
+        ReadAmiInput {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
         #[snafu(display("Failed to render templates: {}", source))]
         RenderTemplates { source: template::Error },
 
@@ -0,0 +1,572 @@
+//! The retire_ami module owns the 'retire-ami' subcommand, which finds the AMIs of a variant in
+//! each region, deprecates or deregisters them, and deletes the snapshots they leave behind.
+//!
+//! AMIs are found by name, so this relies on the default AMI names of the form
+//! `<name>-<variant>-<arch>-v<version>-<build>`. Snapshots that pubsys uploads are described by
+//! the name of the image file, `<name>-<variant>-<arch>-<version>-<build>...`, which is how
+//! snapshots are found whose AMIs are already gone.
+
+use crate::aws::client::build_client_config;
+use crate::aws::region_from_string;
+use crate::Args;
+use aws_sdk_ec2::types::{Filter, Image};
+use aws_sdk_ec2::{config::Region, Client as Ec2Client};
+use aws_smithy_types::DateTime as AwsDateTime;
+use chrono::{DateTime, Duration, Utc};
+use clap::{ArgGroup, Parser};
+use log::{error, info, trace};
+use parse_datetime::parse_offset;
+use pubsys_config::InfraConfig;
+use snafu::{ensure, ResultExt};
+use std::collections::HashSet;
+
+/// The architectures as they appear in AMI and image file names.
+const ARCHES: [&str; 2] = ["x86_64", "aarch64"];
+
+/// How many snapshot IDs to look up in one DescribeImages filter.
+const SNAPSHOT_FILTER_CHUNK: usize = 100;
+
+/// Deprecates or deregisters a variant's AMIs, and deletes their orphaned snapshots
+#[derive(Debug, Parser)]
+// Without a version or an age, every AMI of the variant would be retired.
+#[command(group(
+    ArgGroup::new("which")
+        .args(["version", "older_than"])
+        .required(true)
+        .multiple(true)
+))]
+pub(crate) struct RetireAmiArgs {
+    /// The name at the start of the AMI names, e.g. "bottlerocket"
+    #[arg(long)]
+    name: String,
+
+    /// The variant whose AMIs to retire
+    #[arg(long)]
+    variant: String,
+
+    /// Only retire AMIs for this architecture
+    #[arg(long, value_parser = ARCHES)]
+    arch: Option<String>,
+
+    /// Only retire AMIs of this version, e.g. "1.20.0"
+    #[arg(long)]
+    version: Option<String>,
+
+    /// Only retire AMIs and snapshots created at least this long ago, e.g. "90 days"
+    #[arg(long, value_parser = parse_offset)]
+    older_than: Option<Duration>,
+
+    /// Deregister the AMIs and delete their snapshots, rather than deprecating them
+    #[arg(long)]
+    deregister: bool,
+
+    /// Only report what would be retired
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Comma-separated list of regions to retire AMIs in, overriding Infra.toml
+    #[arg(long, value_delimiter = ',')]
+    regions: Vec<String>,
+}
+
+impl RetireAmiArgs {
+    /// The prefixes of the names of the AMIs to retire, and of the image files their snapshots
+    /// were uploaded from.
+    fn name_prefixes(&self) -> (Vec<String>, Vec<String>) {
+        let arches = match &self.arch {
+            Some(arch) => vec![arch.as_str()],
+            None => ARCHES.to_vec(),
+        };
+        arches
+            .into_iter()
+            .map(|arch| {
+                let prefix = format!("{}-{}-{}", self.name, self.variant, arch);
+                match &self.version {
+                    Some(version) => (
+                        format!("{prefix}-v{version}-"),
+                        format!("{prefix}-{version}-"),
+                    ),
+                    None => (format!("{prefix}-v"), format!("{prefix}-")),
+                }
+            })
+            .unzip()
+    }
+}
+
+/// An AMI to retire.
+#[derive(Debug)]
+struct Candidate {
+    id: String,
+    name: String,
+    snapshot_ids: Vec<String>,
+    deprecated: bool,
+}
+
+impl Candidate {
+    /// Returns the AMI if it was created before `cutoff`.
+    fn from_image(image: &Image, cutoff: DateTime<Utc>) -> Option<Self> {
+        let created = image
+            .creation_date()
+            .and_then(|date| DateTime::parse_from_rfc3339(date).ok())?;
+        if created > cutoff {
+            return None;
+        }
+        Some(Self {
+            id: image.image_id()?.to_string(),
+            name: image.name().unwrap_or_default().to_string(),
+            snapshot_ids: image
+                .block_device_mappings()
+                .iter()
+                .filter_map(|bdm| bdm.ebs().and_then(|ebs| ebs.snapshot_id()))
+                .map(str::to_string)
+                .collect(),
+            deprecated: image.deprecation_time().is_some(),
+        })
+    }
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, retire_args: &RetireAmiArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml or default
+    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
+        .context(error::ConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+
+    let aws = infra_config.aws.unwrap_or_default();
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config.
+    let regions = if !retire_args.regions.is_empty() {
+        retire_args.regions.clone()
+    } else {
+        aws.regions.clone().into()
+    };
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = region_from_string(&regions[0]);
+
+    let cutoff = Utc::now() - retire_args.older_than.unwrap_or_else(Duration::zero);
+    let (ami_prefixes, snapshot_prefixes) = retire_args.name_prefixes();
+
+    let mut failures = 0;
+    for name in &regions {
+        let region = region_from_string(name);
+        let client_config = build_client_config(&region, &base_region, &aws).await;
+        let ec2_client = Ec2Client::new(&client_config);
+
+        let candidates = find_images(&ec2_client, &region, &ami_prefixes, cutoff).await?;
+        info!(
+            "Found {} AMIs to retire in {}",
+            candidates.len(),
+            region.as_ref()
+        );
+
+        let mut deleted = HashSet::new();
+        let mut deregistered = HashSet::new();
+        for candidate in &candidates {
+            let result = if retire_args.deregister {
+                deregister(&ec2_client, &region, candidate, retire_args.dry_run)
+                    .await
+                    .map(|snapshots| {
+                        deregistered.insert(candidate.id.clone());
+                        deleted.extend(snapshots);
+                    })
+            } else {
+                deprecate(&ec2_client, &region, candidate, retire_args.dry_run).await
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+                failures += 1;
+            }
+        }
+
+        let orphans = find_orphaned_snapshots(
+            &ec2_client,
+            &region,
+            &snapshot_prefixes,
+            cutoff,
+            &deregistered,
+        )
+        .await?;
+        for snapshot_id in orphans.difference(&deleted) {
+            if let Err(e) =
+                delete_snapshot(&ec2_client, &region, snapshot_id, retire_args.dry_run).await
+            {
+                error!("{}", e);
+                failures += 1;
+            }
+        }
+    }
+
+    ensure!(failures == 0, error::RetireSnafu { failures });
+    Ok(())
+}
+
+/// Finds our AMIs whose names start with one of `prefixes` and that were created before `cutoff`.
+async fn find_images(
+    ec2_client: &Ec2Client,
+    region: &Region,
+    prefixes: &[String],
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<Candidate>> {
+    let mut candidates = Vec::new();
+    let mut pages = ec2_client
+        .describe_images()
+        .owners("self")
+        .include_deprecated(true)
+        .filters(
+            Filter::builder()
+                .name("name")
+                .set_values(Some(
+                    prefixes.iter().map(|prefix| format!("{prefix}*")).collect(),
+                ))
+                .build(),
+        )
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        let page = page.context(error::DescribeImagesSnafu {
+            region: region.as_ref(),
+        })?;
+        candidates.extend(
+            page.images()
+                .iter()
+                .filter_map(|image| Candidate::from_image(image, cutoff)),
+        );
+    }
+    candidates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(candidates)
+}
+
+/// Finds the snapshots uploaded for images whose file names start with one of `prefixes`, and
+/// the snapshots of the AMIs in `deregistered`, that were created before `cutoff` and aren't used
+/// by any other AMI.
+async fn find_orphaned_snapshots(
+    ec2_client: &Ec2Client,
+    region: &Region,
+    prefixes: &[String],
+    cutoff: DateTime<Utc>,
+    deregistered: &HashSet<String>,
+) -> Result<HashSet<String>> {
+    let mut snapshot_ids = HashSet::new();
+    let mut pages = ec2_client
+        .describe_snapshots()
+        .owner_ids("self")
+        .filters(
+            Filter::builder()
+                .name("description")
+                .set_values(Some(
+                    prefixes.iter().map(|prefix| format!("{prefix}*")).collect(),
+                ))
+                .build(),
+        )
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        let page = page.context(error::DescribeSnapshotsSnafu {
+            region: region.as_ref(),
+        })?;
+        snapshot_ids.extend(
+            page.snapshots()
+                .iter()
+                .filter(|snapshot| {
+                    snapshot
+                        .start_time()
+                        .is_some_and(|start| start.secs() <= cutoff.timestamp())
+                })
+                .filter_map(|snapshot| snapshot.snapshot_id())
+                .map(str::to_string),
+        );
+    }
+
+    // Keep the snapshots that are still used by an AMI we aren't deregistering.
+    let ids = snapshot_ids.iter().cloned().collect::<Vec<_>>();
+    for chunk in ids.chunks(SNAPSHOT_FILTER_CHUNK) {
+        let mut pages = ec2_client
+            .describe_images()
+            .owners("self")
+            .include_deprecated(true)
+            .filters(
+                Filter::builder()
+                    .name("block-device-mapping.snapshot-id")
+                    .set_values(Some(chunk.to_vec()))
+                    .build(),
+            )
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.context(error::DescribeImagesSnafu {
+                region: region.as_ref(),
+            })?;
+            for image in page.images() {
+                if image.image_id().is_some_and(|id| deregistered.contains(id)) {
+                    continue;
+                }
+                for bdm in image.block_device_mappings() {
+                    if let Some(snapshot_id) = bdm.ebs().and_then(|ebs| ebs.snapshot_id()) {
+                        snapshot_ids.remove(snapshot_id);
+                    }
+                }
+            }
+        }
+    }
+    Ok(snapshot_ids)
+}
+
+/// Deprecates an AMI, so that it's hidden from searches but can still be launched by ID.
+async fn deprecate(
+    ec2_client: &Ec2Client,
+    region: &Region,
+    candidate: &Candidate,
+    dry_run: bool,
+) -> Result<()> {
+    if candidate.deprecated {
+        info!(
+            "{} ({}) in {} is already deprecated",
+            candidate.name,
+            candidate.id,
+            region.as_ref()
+        );
+        return Ok(());
+    }
+    if dry_run {
+        info!(
+            "Would deprecate {} ({}) in {}",
+            candidate.name,
+            candidate.id,
+            region.as_ref()
+        );
+        return Ok(());
+    }
+    // EC2 doesn't accept deprecation times in the past.
+    let deprecate_at = AwsDateTime::from_secs(Utc::now().timestamp() + 60);
+    ec2_client
+        .enable_image_deprecation()
+        .image_id(&candidate.id)
+        .deprecate_at(deprecate_at)
+        .send()
+        .await
+        .context(error::DeprecateImageSnafu {
+            image_id: &candidate.id,
+            region: region.as_ref(),
+        })?;
+    info!(
+        "Deprecated {} ({}) in {}",
+        candidate.name,
+        candidate.id,
+        region.as_ref()
+    );
+    Ok(())
+}
+
+/// Deregisters an AMI and deletes its snapshots, returning the IDs of the snapshots.
+async fn deregister(
+    ec2_client: &Ec2Client,
+    region: &Region,
+    candidate: &Candidate,
+    dry_run: bool,
+) -> Result<Vec<String>> {
+    if dry_run {
+        info!(
+            "Would deregister {} ({}) in {}",
+            candidate.name,
+            candidate.id,
+            region.as_ref()
+        );
+    } else {
+        ec2_client
+            .deregister_image()
+            .image_id(&candidate.id)
+            .send()
+            .await
+            .context(error::DeregisterImageSnafu {
+                image_id: &candidate.id,
+                region: region.as_ref(),
+            })?;
+        info!(
+            "Deregistered {} ({}) in {}",
+            candidate.name,
+            candidate.id,
+            region.as_ref()
+        );
+    }
+    for snapshot_id in &candidate.snapshot_ids {
+        delete_snapshot(ec2_client, region, snapshot_id, dry_run).await?;
+    }
+    Ok(candidate.snapshot_ids.clone())
+}
+
+async fn delete_snapshot(
+    ec2_client: &Ec2Client,
+    region: &Region,
+    snapshot_id: &str,
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        info!(
+            "Would delete snapshot {} in {}",
+            snapshot_id,
+            region.as_ref()
+        );
+        return Ok(());
+    }
+    ec2_client
+        .delete_snapshot()
+        .snapshot_id(snapshot_id)
+        .send()
+        .await
+        .context(error::DeleteSnapshotSnafu {
+            snapshot_id,
+            region: region.as_ref(),
+        })?;
+    info!("Deleted snapshot {} in {}", snapshot_id, region.as_ref());
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_ec2::error::SdkError;
+    use aws_sdk_ec2::operation::{
+        delete_snapshot::DeleteSnapshotError, deregister_image::DeregisterImageError,
+        describe_images::DescribeImagesError, describe_snapshots::DescribeSnapshotsError,
+        enable_image_deprecation::EnableImageDeprecationError,
+    };
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    #[allow(clippy::large_enum_variant)]
+    pub(crate) enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Failed to delete snapshot {} in {}: {}",
+            snapshot_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DeleteSnapshot {
+            snapshot_id: String,
+            region: String,
+            source: SdkError<DeleteSnapshotError>,
+        },
+
+        #[snafu(display(
+            "Failed to deprecate {} in {}: {}",
+            image_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DeprecateImage {
+            image_id: String,
+            region: String,
+            source: SdkError<EnableImageDeprecationError>,
+        },
+
+        #[snafu(display(
+            "Failed to deregister {} in {}: {}",
+            image_id,
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DeregisterImage {
+            image_id: String,
+            region: String,
+            source: SdkError<DeregisterImageError>,
+        },
+
+        #[snafu(display(
+            "Failed to describe images in {}: {}",
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DescribeImages {
+            region: String,
+            source: SdkError<DescribeImagesError>,
+        },
+
+        #[snafu(display(
+            "Failed to describe snapshots in {}: {}",
+            region,
+            DisplayErrorContext(source)
+        ))]
+        DescribeSnapshots {
+            region: String,
+            source: SdkError<DescribeSnapshotsError>,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(display("Failed to retire {} AMIs or snapshots", failures))]
+        Retire { failures: usize },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(arch: Option<&str>, version: Option<&str>) -> RetireAmiArgs {
+        RetireAmiArgs {
+            name: "bottlerocket".to_string(),
+            variant: "aws-k8s-1.29".to_string(),
+            arch: arch.map(str::to_string),
+            version: version.map(str::to_string),
+            older_than: None,
+            deregister: false,
+            dry_run: true,
+            regions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_name_prefixes() {
+        assert_eq!(
+            args(None, None).name_prefixes(),
+            (
+                vec![
+                    "bottlerocket-aws-k8s-1.29-x86_64-v".to_string(),
+                    "bottlerocket-aws-k8s-1.29-aarch64-v".to_string(),
+                ],
+                vec![
+                    "bottlerocket-aws-k8s-1.29-x86_64-".to_string(),
+                    "bottlerocket-aws-k8s-1.29-aarch64-".to_string(),
+                ]
+            )
+        );
+        assert_eq!(
+            args(Some("aarch64"), Some("1.20.0")).name_prefixes(),
+            (
+                vec!["bottlerocket-aws-k8s-1.29-aarch64-v1.20.0-".to_string()],
+                vec!["bottlerocket-aws-k8s-1.29-aarch64-1.20.0-".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn test_version_or_age_required() {
+        let base = [
+            "retire-ami",
+            "--name",
+            "bottlerocket",
+            "--variant",
+            "aws-dev",
+        ];
+        assert!(RetireAmiArgs::try_parse_from(base).is_err());
+        for which in [
+            &["--version", "1.20.0"][..],
+            &["--older-than", "90 days"],
+            &["--version", "1.20.0", "--older-than", "90 days"],
+        ] {
+            let args = base.iter().chain(which);
+            assert!(RetireAmiArgs::try_parse_from(args).is_ok(), "{which:?}");
+        }
+    }
+}
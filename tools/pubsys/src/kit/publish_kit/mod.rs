@@ -1,9 +1,11 @@
 use crate::Args;
+use chrono::{SecondsFormat, Utc};
 use clap::Parser;
 use log::{debug, info, trace};
 use oci_cli_wrapper::{DockerArchitecture, ImageTool};
 use pubsys_config::InfraConfig;
 use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 /// Takes a local kit built using buildsys and publishes it to a vendor specified in Infra.toml
@@ -28,6 +30,58 @@ pub(crate) struct PublishKitArgs {
     /// The build id of the kit that should be published
     #[arg(long)]
     build_id: String,
+
+    /// Additional tags to apply to the multi-arch kit image, e.g. "latest"
+    #[arg(long, value_delimiter = ',')]
+    tags: Vec<String>,
+
+    /// Additional annotations to add to the multi-arch kit image, in the form "key=value"
+    #[arg(long = "annotation", value_parser = parse_annotation)]
+    annotations: Vec<(String, String)>,
+}
+
+fn parse_annotation(s: &str) -> std::result::Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| {
+            format!(
+                "Expected an annotation in the form 'key=value', got '{}'",
+                s
+            )
+        })
+}
+
+/// Builds the annotations for the kit's image index.  The standard OCI annotations describe the kit,
+/// and any annotations given by the user are added on top of them.
+fn kit_annotations(
+    publish_kit_args: &PublishKitArgs,
+    kit_name: &str,
+    created: &str,
+) -> BTreeMap<String, String> {
+    let mut annotations = BTreeMap::from([
+        (
+            "org.opencontainers.image.title".to_string(),
+            kit_name.to_string(),
+        ),
+        (
+            "org.opencontainers.image.version".to_string(),
+            publish_kit_args.version.clone(),
+        ),
+        (
+            "org.opencontainers.image.revision".to_string(),
+            publish_kit_args.build_id.clone(),
+        ),
+        (
+            "org.opencontainers.image.vendor".to_string(),
+            publish_kit_args.vendor.clone(),
+        ),
+        (
+            "org.opencontainers.image.created".to_string(),
+            created.to_string(),
+        ),
+    ]);
+    annotations.extend(publish_kit_args.annotations.iter().cloned());
+    annotations
 }
 
 pub(crate) async fn run(args: &Args, publish_kit_args: &PublishKitArgs) -> Result<()> {
@@ -117,11 +171,25 @@ async fn publish_kit(
 
     info!("Pushing kit to {}", &target_uri);
 
+    let created = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let annotations = kit_annotations(publish_kit_args, &kit_name, &created);
     image_tool
-        .push_multi_platform_manifest(platform_images, &target_uri)
+        .push_multi_platform_manifest(platform_images, &target_uri, &annotations)
         .await
         .context(error::PublishKitSnafu)?;
 
+    // Tag the index with the build id too, so that a specific build can always be found even after
+    // the version tag has been republished.
+    let tags = std::iter::once(format!("{}-{}", kit_version, build_id))
+        .chain(publish_kit_args.tags.iter().cloned());
+    for tag in tags {
+        info!("Tagging {} as {}", &target_uri, tag);
+        image_tool
+            .tag(&target_uri, &tag)
+            .await
+            .context(error::PublishKitSnafu)?;
+    }
+
     info!("Successfully published kit to {}", target_uri);
 
     Ok(())
@@ -165,3 +233,37 @@ mod error {
 pub(crate) use error::Error;
 
 type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{kit_annotations, PublishKitArgs};
+    use clap::Parser;
+
+    #[test]
+    fn user_annotations_override_defaults() {
+        let args = PublishKitArgs::parse_from([
+            "publish-kit",
+            "--kit-path",
+            "build/kits/core-kit",
+            "--vendor",
+            "bottlerocket",
+            "--version",
+            "v1.0.0",
+            "--build-id",
+            "abcdef",
+            "--tags",
+            "latest,stable",
+            "--annotation",
+            "org.opencontainers.image.vendor=Example",
+            "--annotation",
+            "com.example.channel=beta",
+        ]);
+        assert_eq!(args.tags, vec!["latest", "stable"]);
+        let annotations = kit_annotations(&args, "core-kit", "2024-01-01T00:00:00Z");
+        assert_eq!(annotations["org.opencontainers.image.title"], "core-kit");
+        assert_eq!(annotations["org.opencontainers.image.version"], "v1.0.0");
+        assert_eq!(annotations["org.opencontainers.image.revision"], "abcdef");
+        assert_eq!(annotations["org.opencontainers.image.vendor"], "Example");
+        assert_eq!(annotations["com.example.channel"], "beta");
+    }
+}
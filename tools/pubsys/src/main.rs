@@ -5,9 +5,12 @@ Currently implemented:
 * building repos, whether starting from an existing repo or from scratch
 * validating repos by loading them and retrieving their targets
 * checking for repository metadata expirations within specified number of days
+* comparing repos' targets and role versions, and finding which metadata needs re-signing
 * refreshing and re-signing repos' non-root metadata files
+* validating local repos and syncing them to S3, optionally invalidating CloudFront
 * registering and copying EC2 AMIs
 * Marking EC2 AMIs public (or private again)
+* deprecating or deregistering old EC2 AMIs, and deleting their snapshots
 * setting SSM parameters based on built AMIs
 * promoting SSM parameters from versioned entries to named (e.g. 'latest')
 * validating SSM parameters by comparing the returned parameters in a region to a given list of parameters
@@ -90,11 +93,17 @@ async fn run() -> Result<()> {
                 .await
                 .context(error::CheckExpirationsSnafu)
         }
+        SubCommands::DiffRepo(ref diff_repo_args) => repo::diff_repo::run(&args, diff_repo_args)
+            .await
+            .context(error::DiffRepoSnafu),
         SubCommands::RefreshRepo(ref refresh_repo_args) => {
             repo::refresh_repo::run(&args, refresh_repo_args)
                 .await
                 .context(error::RefreshRepoSnafu)
         }
+        SubCommands::SyncRepo(ref sync_repo_args) => repo::sync_repo::run(&args, sync_repo_args)
+            .await
+            .context(error::SyncRepoSnafu),
         SubCommands::FetchVariant(ref fetch_variant_args) => {
             repo::fetch_variant::run(&args, fetch_variant_args)
                 .await
@@ -106,6 +115,14 @@ async fn run() -> Result<()> {
         SubCommands::PublishAmi(ref publish_args) => aws::publish_ami::run(&args, publish_args)
             .await
             .context(error::PublishAmiSnafu),
+        SubCommands::RetireAmi(ref retire_args) => aws::retire_ami::run(&args, retire_args)
+            .await
+            .context(error::RetireAmiSnafu),
+        SubCommands::PublishMarketplace(ref marketplace_args) => {
+            aws::marketplace::run(&args, marketplace_args)
+                .await
+                .context(error::PublishMarketplaceSnafu)
+        }
         SubCommands::Ssm(ref ssm_args) => aws::ssm::run(&args, ssm_args)
             .await
             .context(error::SsmSnafu),
@@ -163,11 +180,15 @@ enum SubCommands {
     Repo(repo::RepoArgs),
     ValidateRepo(repo::validate_repo::ValidateRepoArgs),
     CheckRepoExpirations(repo::check_expirations::CheckExpirationsArgs),
+    DiffRepo(repo::diff_repo::DiffRepoArgs),
     RefreshRepo(repo::refresh_repo::RefreshRepoArgs),
+    SyncRepo(repo::sync_repo::SyncRepoArgs),
     FetchVariant(repo::fetch_variant::FetchVariantArgs),
     Ami(aws::ami::AmiArgs),
     PublishAmi(aws::publish_ami::Who),
     ValidateAmi(aws::validate_ami::ValidateAmiArgs),
+    RetireAmi(aws::retire_ami::RetireAmiArgs),
+    PublishMarketplace(aws::marketplace::MarketplaceArgs),
 
     Ssm(aws::ssm::SsmArgs),
     PromoteSsm(aws::promote_ssm::PromoteArgs),
@@ -228,11 +249,21 @@ mod error {
             source: crate::aws::publish_ami::Error,
         },
 
+        #[snafu(display("Failed to publish to AWS Marketplace: {}", source))]
+        PublishMarketplace {
+            source: crate::aws::marketplace::Error,
+        },
+
         #[snafu(display("Failed to promote SSM: {}", source))]
         PromoteSsm {
             source: crate::aws::promote_ssm::Error,
         },
 
+        #[snafu(display("Failed to retire AMIs: {}", source))]
+        RetireAmi {
+            source: crate::aws::retire_ami::Error,
+        },
+
         #[snafu(display("Failed to build repo: {}", source))]
         Repo { source: crate::repo::Error },
 
@@ -246,11 +277,21 @@ mod error {
             source: crate::repo::check_expirations::Error,
         },
 
+        #[snafu(display("Failed to compare repositories: {}", source))]
+        DiffRepo {
+            source: crate::repo::diff_repo::Error,
+        },
+
         #[snafu(display("Failed to refresh repository metadata: {}", source))]
         RefreshRepo {
             source: crate::repo::refresh_repo::Error,
         },
 
+        #[snafu(display("Failed to sync repository: {}", source))]
+        SyncRepo {
+            source: crate::repo::sync_repo::Error,
+        },
+
         #[snafu(display("Failed to update SSM: {}", source))]
         Ssm { source: crate::aws::ssm::Error },
 
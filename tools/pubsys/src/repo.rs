@@ -1,8 +1,10 @@
 //! The repo module owns the 'repo' subcommand and controls the process of building a repository.
 
 pub(crate) mod check_expirations;
+pub(crate) mod diff_repo;
 pub(crate) mod fetch_variant;
 pub(crate) mod refresh_repo;
+pub(crate) mod sync_repo;
 pub(crate) mod validate_repo;
 
 use crate::{friendly_version, read_stream, Args};
@@ -17,9 +19,10 @@ use pubsys_config::{
 };
 use semver::Version;
 use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeSet, HashSet};
 use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempDir};
 use tokio::fs;
 use tokio::runtime::Runtime;
 use tough::{
@@ -73,6 +76,10 @@ pub(crate) struct RepoArgs {
     #[arg(long = "copy-target")]
     /// Optional paths to add as targets and copy into repo
     copy_targets: Vec<PathBuf>,
+    #[arg(long)]
+    /// Optional path to the variant's migrations archive; the migrations from it that
+    /// Release.toml lists are added as targets and copied into repo
+    migrations_archive: Option<PathBuf>,
 
     // Policies that pubsys interprets to set repo parameters
     #[arg(long)]
@@ -194,6 +201,66 @@ fn update_manifest(repo_args: &RepoArgs, manifest: &mut Manifest) -> Result<()>
     Ok(())
 }
 
+/// Extracts the migrations archive of the update into `dir`, and returns the paths of the
+/// migrations in it that `manifest` lists, to be added as targets. `published` has the migrations
+/// that earlier releases added to the repo.
+fn migration_targets(
+    archive: &Path,
+    dir: &Path,
+    manifest: &Manifest,
+    published: &HashSet<String>,
+) -> Result<Vec<PathBuf>> {
+    info!("Extracting migrations from '{}'", archive.display());
+    duct::cmd!("tar", "-xf", archive, "-C", dir)
+        .run()
+        .context(error::ExtractMigrationsSnafu { path: archive })?;
+    select_migrations(archive, dir, manifest, published)
+}
+
+/// Finds the migrations that `manifest` lists among those extracted to `dir`. Any that aren't
+/// there must have been published by an earlier release; migrations that aren't listed are left
+/// out of the repo.
+fn select_migrations(
+    archive: &Path,
+    dir: &Path,
+    manifest: &Manifest,
+    published: &HashSet<String>,
+) -> Result<Vec<PathBuf>> {
+    let listed: BTreeSet<&String> = manifest.migrations.values().flatten().collect();
+    let mut targets = Vec::new();
+    let mut missing = Vec::new();
+    for name in &listed {
+        let path = dir.join(name);
+        // Names are file names in the repo's targets, and can't point outside the archive.
+        if !name.contains('/') && path.is_file() {
+            targets.push(path);
+        } else if !published.contains(*name) {
+            missing.push(name.to_string());
+        }
+    }
+    ensure!(
+        missing.is_empty(),
+        error::MissingMigrationsSnafu {
+            archive,
+            missing: missing.join(", "),
+        }
+    );
+
+    let entries = std::fs::read_dir(dir).context(error::FileSnafu { path: dir })?;
+    for entry in entries {
+        let entry = entry.context(error::FileSnafu { path: dir })?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !listed.contains(&name) {
+            warn!(
+                "Migration '{}' isn't listed in the release config, so it won't be added to the \
+                 repo",
+                name
+            );
+        }
+    }
+    Ok(targets)
+}
+
 /// Set expirations of all non-root role metadata based on a given `RepoExpirationPolicy` and an
 /// expiration start time
 fn set_expirations(
@@ -336,6 +403,26 @@ fn repo_urls<'a>(
     Ok(None)
 }
 
+/// Returns file URLs for the metadata and targets of a local repo, as written to `--outdir` by the
+/// 'repo' subcommand.
+pub(crate) fn local_repo_urls(repo_dir: &Path, variant: &str, arch: &str) -> Result<(Url, Url)> {
+    let repo_dir = repo_dir
+        .canonicalize()
+        .context(error::LocalRepoSnafu { path: repo_dir })?;
+    let metadata_dir = repo_dir.join(variant).join(arch);
+    let targets_dir = repo_dir.join("targets");
+    let metadata_url =
+        Url::from_directory_path(&metadata_dir)
+            .ok()
+            .context(error::DirUrlSnafu {
+                path: &metadata_dir,
+            })?;
+    let targets_url = Url::from_directory_path(&targets_dir)
+        .ok()
+        .context(error::DirUrlSnafu { path: &targets_dir })?;
+    Ok((metadata_url, targets_url))
+}
+
 /// Builds an editor and manifest; will start from an existing repo if one is specified in the
 /// configuration.  Returns Err if we fail to read from the repo.  Returns Ok(None) if we detect
 /// that the repo does not exist.
@@ -524,6 +611,11 @@ pub(crate) async fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
         )
     };
 
+    // Remember the migrations that earlier releases added, since the manifest's migrations are
+    // replaced with those from the release config.
+    let published_migrations: HashSet<String> =
+        manifest.migrations.values().flatten().cloned().collect();
+
     // Add update information to manifest
     update_manifest(repo_args, &mut manifest)?;
     // Write manifest to tempfile so it can be copied in as target later
@@ -534,8 +626,25 @@ pub(crate) async fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
         path: &manifest_path,
     })?;
 
+    // Add the migrations from this update that the release config lists
+    let migrations_dir = TempDir::new().context(error::TempDirSnafu)?;
+    let migrations = match &repo_args.migrations_archive {
+        Some(archive) => migration_targets(
+            archive,
+            migrations_dir.path(),
+            &manifest,
+            &published_migrations,
+        )?,
+        None => Vec::new(),
+    };
+
     // Add manifest and targets to editor
-    let copy_targets = &repo_args.copy_targets;
+    let copy_targets: Vec<PathBuf> = repo_args
+        .copy_targets
+        .iter()
+        .cloned()
+        .chain(migrations)
+        .collect();
     let link_targets = repo_args.link_targets.iter().chain(vec![
         &repo_args.boot_image,
         &repo_args.root_image,
@@ -601,7 +710,7 @@ pub(crate) async fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
         })?;
 
     // Copy / link any other user requested targets
-    for copy_target in copy_targets {
+    for copy_target in &copy_targets {
         debug!(
             "Copying target '{}' into {}",
             copy_target.display(),
@@ -690,12 +799,18 @@ mod error {
         #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
         CreateDir { path: PathBuf, source: io::Error },
 
+        #[snafu(display("Failed to convert '{}' to a URL", path.display()))]
+        DirUrl { path: PathBuf },
+
         #[snafu(display("Failed to create repo editor from given repo: {}", source))]
         EditorFromRepo {
             #[snafu(source(from(tough::error::Error, Box::new)))]
             source: Box<tough::error::Error>,
         },
 
+        #[snafu(display("Failed to extract migrations from '{}': {}", path.display(), source))]
+        ExtractMigrations { path: PathBuf, source: io::Error },
+
         #[snafu(display("Failed to read '{}': {}", path.display(), source))]
         File { path: PathBuf, source: io::Error },
 
@@ -716,6 +831,9 @@ mod error {
             source: Box<tough::error::Error>,
         },
 
+        #[snafu(display("Failed to find local repo at '{}': {}", path.display(), source))]
+        LocalRepo { path: PathBuf, source: io::Error },
+
         #[snafu(display("Failed to write Manifest to '{}': {}", path.display(), source))]
         ManifestWrite {
             path: PathBuf,
@@ -726,6 +844,13 @@ mod error {
         #[snafu(display("Infra.toml is missing {}", missing))]
         MissingConfig { missing: String },
 
+        #[snafu(display(
+            "Release config lists migrations that aren't in '{}' or the repo: {}",
+            archive.display(),
+            missing
+        ))]
+        MissingMigrations { archive: PathBuf, missing: String },
+
         #[snafu(display("Repo URLs not specified for repo '{}'", repo))]
         MissingRepoUrls { repo: String },
 
@@ -818,6 +943,9 @@ mod error {
             source: Box<tough::error::Error>,
         },
 
+        #[snafu(display("Failed to create temporary directory: {}", source))]
+        TempDir { source: io::Error },
+
         #[snafu(display("Failed to create temporary file: {}", source))]
         TempFile { source: io::Error },
 
@@ -831,3 +959,44 @@ mod error {
 }
 pub(crate) use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_select_migrations() {
+        let archive = Path::new("migrations.tar");
+        let dir = TempDir::new().unwrap();
+        for name in [
+            "migrate_v1.1.0_a.lz4",
+            "migrate_v1.2.0_b.lz4",
+            "migrate_v1.2.0_c.lz4",
+        ] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let mut manifest = Manifest::default();
+        let version = |v: &str| Version::parse(v).unwrap();
+        manifest.migrations.insert(
+            (version("1.0.0"), version("1.1.0")),
+            vec!["migrate_v1.1.0_a.lz4".to_string()],
+        );
+        manifest.migrations.insert(
+            (version("1.1.0"), version("1.2.0")),
+            vec![
+                "migrate_v1.2.0_b.lz4".to_string(),
+                "migrate_v1.2.0_old.lz4".to_string(),
+            ],
+        );
+        let published = HashSet::from(["migrate_v1.2.0_old.lz4".to_string()]);
+
+        assert_eq!(
+            select_migrations(archive, dir.path(), &manifest, &published).unwrap(),
+            vec![
+                dir.path().join("migrate_v1.1.0_a.lz4"),
+                dir.path().join("migrate_v1.2.0_b.lz4"),
+            ]
+        );
+        assert!(select_migrations(archive, dir.path(), &manifest, &HashSet::new()).is_err());
+    }
+}
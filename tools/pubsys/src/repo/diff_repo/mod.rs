@@ -0,0 +1,373 @@
+//! The diff_repo module owns the 'diff-repo' subcommand and provides methods for comparing two TUF
+//! repositories, reporting the differences in their targets and role versions, and determining
+//! which metadata files need to be re-signed to move from one to the other.
+
+use crate::repo::{error as repo_error, local_repo_urls, repo_urls};
+use crate::{repo, Args};
+use clap::Parser;
+use log::{info, trace};
+use pubsys_config::InfraConfig;
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use tough::{Repository, RepositoryLoader};
+use url::Url;
+
+/// Compares a published TUF repository against another repository, local or published
+#[derive(Debug, Parser)]
+pub(crate) struct DiffRepoArgs {
+    #[arg(long)]
+    /// Use this named repo infrastructure from Infra.toml as the base of the comparison
+    repo: String,
+
+    #[arg(long)]
+    /// The architecture of the repos being compared
+    arch: String,
+    #[arg(long)]
+    /// The variant of the repos being compared
+    variant: String,
+
+    #[arg(long)]
+    /// Path to root.json for the repos
+    root_role_path: PathBuf,
+
+    #[arg(long, conflicts_with_all = &["new_metadata_url", "new_targets_url"])]
+    /// Compare against a local repo, as written to '--outdir' by the 'repo' subcommand
+    new_repo_dir: Option<PathBuf>,
+
+    #[arg(long, requires = "new_targets_url")]
+    /// Compare against the repo with this metadata URL
+    new_metadata_url: Option<Url>,
+    #[arg(long, requires = "new_metadata_url")]
+    /// Compare against the repo with this targets URL
+    new_targets_url: Option<Url>,
+
+    #[arg(long)]
+    /// Write the comparison as JSON to this path
+    output: Option<PathBuf>,
+}
+
+/// The length and sha256 digest of a target, which is all we need to tell if it changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct TargetSummary {
+    length: u64,
+    sha256: String,
+}
+
+/// The version of a role in each repo.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct VersionChange {
+    base: u64,
+    new: u64,
+}
+
+/// The differences between two repos.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct RepoDiff {
+    added: BTreeMap<String, TargetSummary>,
+    removed: BTreeMap<String, TargetSummary>,
+    changed: BTreeMap<String, VersionChange>,
+    modified: BTreeMap<String, (TargetSummary, TargetSummary)>,
+    /// The metadata files that need to be signed to publish the new repo over the base repo.
+    resign: Vec<String>,
+}
+
+impl RepoDiff {
+    fn report(&self) -> String {
+        let mut report = String::new();
+        for (name, target) in &self.added {
+            let _ = writeln!(report, "+ {} ({} bytes)", name, target.length);
+        }
+        for (name, target) in &self.removed {
+            let _ = writeln!(report, "- {} ({} bytes)", name, target.length);
+        }
+        for (name, (base, new)) in &self.modified {
+            let _ = writeln!(
+                report,
+                "~ {} ({} -> {})",
+                name,
+                short_digest(&base.sha256),
+                short_digest(&new.sha256)
+            );
+        }
+        for (role, versions) in &self.changed {
+            let _ = writeln!(
+                report,
+                "{} version: {} -> {}",
+                role, versions.base, versions.new
+            );
+        }
+        if self.resign.is_empty() {
+            report.push_str("Repos are identical, nothing needs to be re-signed\n");
+        } else {
+            let _ = writeln!(report, "Needs re-signing: {}", self.resign.join(", "));
+        }
+        report
+    }
+}
+
+fn short_digest(digest: &str) -> &str {
+    digest.get(..12).unwrap_or(digest)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Summarizes the targets of a loaded repo by name.
+fn target_summaries(repo: &Repository) -> BTreeMap<String, TargetSummary> {
+    repo.targets()
+        .signed
+        .targets
+        .iter()
+        .map(|(name, target)| {
+            (
+                name.raw().to_string(),
+                TargetSummary {
+                    length: target.length,
+                    sha256: hex(&target.hashes.sha256),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Collects the versions of each role in a loaded repo.
+fn role_versions(repo: &Repository) -> BTreeMap<String, u64> {
+    [
+        ("root", repo.root().signed.version.get()),
+        ("snapshot", repo.snapshot().signed.version.get()),
+        ("targets", repo.targets().signed.version.get()),
+        ("timestamp", repo.timestamp().signed.version.get()),
+    ]
+    .into_iter()
+    .map(|(role, version)| (role.to_string(), version))
+    .collect()
+}
+
+/// Compares the targets and role versions of two repos. Any change to the targets requires new
+/// targets metadata, which then requires new snapshot and timestamp metadata; a change that only
+/// touches the snapshot requires a new timestamp, and so on down the chain.
+pub(crate) fn diff(
+    base_targets: &BTreeMap<String, TargetSummary>,
+    new_targets: &BTreeMap<String, TargetSummary>,
+    base_versions: &BTreeMap<String, u64>,
+    new_versions: &BTreeMap<String, u64>,
+) -> RepoDiff {
+    let mut diff = RepoDiff::default();
+    for (name, new) in new_targets {
+        match base_targets.get(name) {
+            None => {
+                diff.added.insert(name.clone(), new.clone());
+            }
+            Some(base) if base != new => {
+                diff.modified
+                    .insert(name.clone(), (base.clone(), new.clone()));
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, base) in base_targets {
+        if !new_targets.contains_key(name) {
+            diff.removed.insert(name.clone(), base.clone());
+        }
+    }
+    for (role, new) in new_versions {
+        let base = base_versions.get(role).copied().unwrap_or_default();
+        if base != *new {
+            diff.changed
+                .insert(role.clone(), VersionChange { base, new: *new });
+        }
+    }
+
+    let targets_changed = !diff.added.is_empty()
+        || !diff.removed.is_empty()
+        || !diff.modified.is_empty()
+        || diff.changed.contains_key("targets");
+    let snapshot_changed = targets_changed || diff.changed.contains_key("snapshot");
+    let timestamp_changed = snapshot_changed || diff.changed.contains_key("timestamp");
+    if diff.changed.contains_key("root") {
+        diff.resign.push("root.json".to_string());
+    }
+    if targets_changed {
+        diff.resign.push("targets.json".to_string());
+    }
+    if snapshot_changed {
+        diff.resign.push("snapshot.json".to_string());
+    }
+    if timestamp_changed {
+        diff.resign.push("timestamp.json".to_string());
+    }
+    diff
+}
+
+async fn load_repo(
+    root_role_path: &Path,
+    metadata_url: &Url,
+    targets_url: &Url,
+) -> Result<Repository> {
+    let repo = RepositoryLoader::new(
+        &repo::root_bytes(root_role_path).await?,
+        metadata_url.clone(),
+        targets_url.clone(),
+    )
+    .load()
+    .await
+    .context(repo_error::RepoLoadSnafu {
+        metadata_base_url: metadata_url.clone(),
+    })?;
+    info!("Loaded TUF repo: {}", metadata_url);
+    Ok(repo)
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, diff_repo_args: &DiffRepoArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
+        .context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .context(repo_error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .get(&diff_repo_args.repo)
+        .with_context(|| repo_error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", &diff_repo_args.repo),
+        })?;
+    let (base_metadata_url, base_targets_url) =
+        repo_urls(repo_config, &diff_repo_args.variant, &diff_repo_args.arch)?.context(
+            repo_error::MissingRepoUrlsSnafu {
+                repo: &diff_repo_args.repo,
+            },
+        )?;
+
+    let (new_metadata_url, new_targets_url) = match (
+        &diff_repo_args.new_repo_dir,
+        &diff_repo_args.new_metadata_url,
+        &diff_repo_args.new_targets_url,
+    ) {
+        (Some(repo_dir), _, _) => {
+            local_repo_urls(repo_dir, &diff_repo_args.variant, &diff_repo_args.arch)?
+        }
+        (None, Some(metadata_url), Some(targets_url)) => {
+            (metadata_url.clone(), targets_url.clone())
+        }
+        _ => return error::MissingNewRepoSnafu.fail(),
+    };
+
+    let root_role_path = &diff_repo_args.root_role_path;
+    let base = load_repo(root_role_path, &base_metadata_url, base_targets_url).await?;
+    let new = load_repo(root_role_path, &new_metadata_url, &new_targets_url).await?;
+
+    let diff = diff(
+        &target_summaries(&base),
+        &target_summaries(&new),
+        &role_versions(&base),
+        &role_versions(&new),
+    );
+    print!("{}", diff.report());
+
+    if let Some(output) = &diff_repo_args.output {
+        let json = serde_json::to_string_pretty(&diff).context(error::SerializeSnafu)?;
+        tokio::fs::write(output, json)
+            .await
+            .context(error::WriteSnafu { path: output })?;
+        info!("Wrote repo comparison to {}", output.display());
+    }
+
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display(
+            "One of --new-repo-dir or --new-metadata-url and --new-targets-url must be given"
+        ))]
+        MissingNewRepo,
+
+        #[snafu(context(false), display("{}", source))]
+        Repo {
+            #[snafu(source(from(crate::repo::Error, Box::new)))]
+            source: Box<crate::repo::Error>,
+        },
+
+        #[snafu(display("Failed to serialize repo comparison: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        Write { path: PathBuf, source: io::Error },
+    }
+}
+pub(crate) use error::Error;
+
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{diff, TargetSummary};
+    use std::collections::BTreeMap;
+
+    fn target(length: u64, sha256: &str) -> TargetSummary {
+        TargetSummary {
+            length,
+            sha256: sha256.to_string(),
+        }
+    }
+
+    fn versions(version: u64) -> BTreeMap<String, u64> {
+        ["root", "snapshot", "targets", "timestamp"]
+            .into_iter()
+            .map(|role| (role.to_string(), if role == "root" { 1 } else { version }))
+            .collect()
+    }
+
+    #[test]
+    fn identical_repos() {
+        let targets = BTreeMap::from([("a".to_string(), target(1, "aa"))]);
+        let diff = diff(&targets, &targets, &versions(1), &versions(1));
+        assert!(diff.resign.is_empty());
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty());
+    }
+
+    #[test]
+    fn changed_targets() {
+        let base = BTreeMap::from([
+            ("kept".to_string(), target(1, "aa")),
+            ("modified".to_string(), target(2, "bb")),
+            ("removed".to_string(), target(3, "cc")),
+        ]);
+        let new = BTreeMap::from([
+            ("kept".to_string(), target(1, "aa")),
+            ("modified".to_string(), target(2, "dd")),
+            ("added".to_string(), target(4, "ee")),
+        ]);
+        let diff = diff(&base, &new, &versions(1), &versions(2));
+        assert_eq!(diff.added.keys().collect::<Vec<_>>(), vec!["added"]);
+        assert_eq!(diff.removed.keys().collect::<Vec<_>>(), vec!["removed"]);
+        assert_eq!(diff.modified.keys().collect::<Vec<_>>(), vec!["modified"]);
+        assert_eq!(diff.changed.len(), 3);
+        assert_eq!(
+            diff.resign,
+            vec!["targets.json", "snapshot.json", "timestamp.json"]
+        );
+    }
+
+    #[test]
+    fn timestamp_only() {
+        let targets = BTreeMap::from([("a".to_string(), target(1, "aa"))]);
+        let mut new_versions = versions(1);
+        new_versions.insert("timestamp".to_string(), 2);
+        let diff = diff(&targets, &targets, &versions(1), &new_versions);
+        assert_eq!(diff.resign, vec!["timestamp.json"]);
+    }
+}
@@ -0,0 +1,467 @@
+//! The sync_repo module owns the 'sync-repo' subcommand and provides methods for validating a
+//! locally built TUF repository and uploading it to the S3 bucket that hosts the repo.
+
+use crate::aws::client::build_client_config;
+use crate::repo::{error as repo_error, local_repo_urls};
+use crate::{repo, Args};
+use aws_sdk_cloudfront::types::{InvalidationBatch, Paths};
+use aws_sdk_cloudfront::Client as CloudFrontClient;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use aws_types::region::Region;
+use clap::Parser;
+use log::{debug, info, trace};
+use pubsys_config::InfraConfig;
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tough::RepositoryLoader;
+
+/// Metadata changes with every publish, so clients should only cache it briefly.
+const METADATA_CACHE_CONTROL: &str = "max-age=60";
+/// Targets are written with their digest in the name, so they never change once uploaded.
+const TARGETS_CACHE_CONTROL: &str = "max-age=31536000, immutable";
+
+/// Validates a locally built TUF repository and syncs it to S3
+#[derive(Debug, Parser)]
+pub(crate) struct SyncRepoArgs {
+    #[arg(long)]
+    /// Use this named repo infrastructure from Infra.toml
+    repo: String,
+
+    #[arg(long)]
+    /// The architecture of the repo being synced
+    arch: String,
+    #[arg(long)]
+    /// The variant of the repo being synced
+    variant: String,
+
+    #[arg(long)]
+    /// Path to root.json for this repo
+    root_role_path: PathBuf,
+
+    #[arg(long)]
+    /// The local repo to sync, as written to '--outdir' by the 'repo' subcommand
+    repo_dir: PathBuf,
+
+    #[arg(long)]
+    /// Print the files that would be uploaded without uploading them
+    dry_run: bool,
+
+    #[arg(long)]
+    /// Invalidate the repo's paths in this CloudFront distribution after syncing
+    cloudfront_distribution_id: Option<String>,
+}
+
+/// A file from the local repo that needs to be uploaded.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Upload {
+    path: PathBuf,
+    key: String,
+    reason: &'static str,
+    cache_control: &'static str,
+}
+
+/// Returns the content type S3 should serve for the given file.
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Joins an S3 prefix and a relative key, without doubling or adding leading slashes.
+fn s3_key(prefix: &str, key: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}/{}", prefix, key)
+    }
+}
+
+/// Lists the files in `dir`, following symlinks, since the 'repo' subcommand links large targets
+/// rather than copying them.
+fn local_files(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).context(error::ReadDirSnafu { path: dir })? {
+        let path = entry.context(error::ReadDirSnafu { path: dir })?.path();
+        let metadata = fs::metadata(&path).context(error::ReadDirSnafu { path: &path })?;
+        if metadata.is_file() {
+            files.push((path, metadata.len()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Decides which local files need to be uploaded.  Targets are skipped if an object of the same
+/// size already exists, since their names include their digest.  Metadata is always uploaded, and
+/// is ordered after the targets with timestamp.json last, so that clients never see metadata that
+/// refers to targets that haven't been uploaded yet.
+pub(crate) fn plan_uploads(
+    targets: Vec<(PathBuf, u64)>,
+    mut metadata: Vec<(PathBuf, u64)>,
+    targets_prefix: &str,
+    metadata_prefix: &str,
+    remote: &HashMap<String, u64>,
+) -> Vec<Upload> {
+    let mut uploads = Vec::new();
+    for (path, size) in targets {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let key = s3_key(targets_prefix, &name);
+        let reason = match remote.get(&key) {
+            None => "new",
+            Some(remote_size) if *remote_size != size => "changed",
+            Some(_) => continue,
+        };
+        uploads.push(Upload {
+            path,
+            key,
+            reason,
+            cache_control: TARGETS_CACHE_CONTROL,
+        });
+    }
+
+    metadata.sort_by_key(|(path, _)| path.ends_with("timestamp.json"));
+    for (path, _) in metadata {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let key = s3_key(metadata_prefix, &name);
+        let reason = if remote.contains_key(&key) {
+            "metadata"
+        } else {
+            "new"
+        };
+        uploads.push(Upload {
+            path,
+            key,
+            reason,
+            cache_control: METADATA_CACHE_CONTROL,
+        });
+    }
+    uploads
+}
+
+/// Lists the sizes of the objects under `prefix` in `bucket`, by key.
+async fn remote_objects(
+    client: &S3Client,
+    bucket: &str,
+    prefix: &str,
+) -> Result<HashMap<String, u64>> {
+    let mut objects = HashMap::new();
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        let page = page.context(error::ListObjectsSnafu { bucket })?;
+        for object in page.contents() {
+            if let (Some(key), Some(size)) = (object.key(), object.size()) {
+                objects.insert(key.to_string(), size.max(0) as u64);
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/// Uploads a file from the local repo to `bucket`.
+async fn upload(client: &S3Client, bucket: &str, upload: &Upload) -> Result<()> {
+    let body = ByteStream::from_path(&upload.path)
+        .await
+        .context(error::ReadUploadSnafu { path: &upload.path })?;
+    client
+        .put_object()
+        .bucket(bucket)
+        .key(&upload.key)
+        .body(body)
+        .content_type(content_type(&upload.path))
+        .cache_control(upload.cache_control)
+        .send()
+        .await
+        .context(error::PutObjectSnafu {
+            bucket,
+            key: &upload.key,
+        })?;
+    Ok(())
+}
+
+/// Invalidates `paths` in a CloudFront distribution, so that clients see the new metadata.
+async fn invalidate(client: &CloudFrontClient, distribution_id: &str, paths: &str) -> Result<()> {
+    let batch = InvalidationBatch::builder()
+        .paths(
+            Paths::builder()
+                .quantity(1)
+                .items(paths)
+                .build()
+                .context(error::BuildInvalidationSnafu)?,
+        )
+        // CloudFront ignores a request whose caller reference it has already seen.
+        .caller_reference(chrono::Utc::now().timestamp_millis().to_string())
+        .build()
+        .context(error::BuildInvalidationSnafu)?;
+    client
+        .create_invalidation()
+        .distribution_id(distribution_id)
+        .invalidation_batch(batch)
+        .send()
+        .await
+        .context(error::InvalidateSnafu { distribution_id })?;
+    Ok(())
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, sync_repo_args: &SyncRepoArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
+        .context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .context(repo_error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .get(&sync_repo_args.repo)
+        .with_context(|| repo_error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", &sync_repo_args.repo),
+        })?;
+    let s3_config_name =
+        repo_config
+            .file_hosting_config_name
+            .as_ref()
+            .context(repo_error::MissingConfigSnafu {
+                missing: format!("file_hosting_config_name for repo {}", &sync_repo_args.repo),
+            })?;
+    let s3_config = infra_config
+        .aws
+        .as_ref()
+        .and_then(|aws| aws.s3.as_ref())
+        .and_then(|s3| s3.get(s3_config_name))
+        .with_context(|| repo_error::MissingConfigSnafu {
+            missing: format!("aws.s3 definition for {}", s3_config_name),
+        })?;
+    let bucket =
+        s3_config
+            .bucket_name
+            .as_ref()
+            .with_context(|| repo_error::MissingConfigSnafu {
+                missing: format!("bucket_name for aws.s3.{}", s3_config_name),
+            })?;
+    let aws = infra_config.aws.clone().unwrap_or_default();
+    let region = s3_config
+        .region
+        .as_ref()
+        .or(aws.regions.front())
+        .map(|region| Region::new(region.clone()))
+        .with_context(|| repo_error::MissingConfigSnafu {
+            missing: format!("region for aws.s3.{}", s3_config_name),
+        })?;
+    let client_config = build_client_config(&region, &region, &aws).await;
+    let s3_client = S3Client::new(&client_config);
+
+    // Make sure the local repo is complete and correctly signed before we publish anything.
+    let (metadata_url, targets_url) = local_repo_urls(
+        &sync_repo_args.repo_dir,
+        &sync_repo_args.variant,
+        &sync_repo_args.arch,
+    )?;
+    RepositoryLoader::new(
+        &repo::root_bytes(&sync_repo_args.root_role_path).await?,
+        metadata_url.clone(),
+        targets_url,
+    )
+    .load()
+    .await
+    .context(repo_error::RepoLoadSnafu {
+        metadata_base_url: metadata_url,
+    })?;
+    info!(
+        "Validated local repo at {}",
+        sync_repo_args.repo_dir.display()
+    );
+
+    let metadata_prefix = s3_key(
+        &s3_config.s3_prefix,
+        &format!("{}/{}", sync_repo_args.variant, sync_repo_args.arch),
+    );
+    let targets_prefix = s3_key(&s3_config.s3_prefix, "targets");
+    let mut remote = remote_objects(&s3_client, bucket, &metadata_prefix).await?;
+    remote.extend(remote_objects(&s3_client, bucket, &targets_prefix).await?);
+    debug!("Found {} existing objects in s3://{}", remote.len(), bucket);
+
+    let uploads = plan_uploads(
+        local_files(&sync_repo_args.repo_dir.join("targets"))?,
+        local_files(
+            &sync_repo_args
+                .repo_dir
+                .join(&sync_repo_args.variant)
+                .join(&sync_repo_args.arch),
+        )?,
+        &targets_prefix,
+        &metadata_prefix,
+        &remote,
+    );
+
+    for planned in &uploads {
+        let destination = format!("s3://{}/{}", bucket, planned.key);
+        if sync_repo_args.dry_run {
+            println!("(dry run) upload {} ({})", destination, planned.reason);
+            continue;
+        }
+        info!("Uploading {} ({})", destination, planned.reason);
+        upload(&s3_client, bucket, planned).await?;
+    }
+    info!(
+        "{} {} files to s3://{}",
+        if sync_repo_args.dry_run {
+            "Would upload"
+        } else {
+            "Uploaded"
+        },
+        uploads.len(),
+        bucket
+    );
+
+    if let Some(distribution_id) = &sync_repo_args.cloudfront_distribution_id {
+        let paths = format!("/{}/*", metadata_prefix);
+        if sync_repo_args.dry_run {
+            println!(
+                "(dry run) invalidate {} in CloudFront distribution {}",
+                paths, distribution_id
+            );
+        } else {
+            info!(
+                "Invalidating {} in CloudFront distribution {}",
+                paths, distribution_id
+            );
+            let cloudfront_client = CloudFrontClient::new(&client_config);
+            invalidate(&cloudfront_client, distribution_id, &paths).await?;
+        }
+    }
+
+    Ok(())
+}
+
+mod error {
+    use aws_sdk_cloudfront::error::BuildError;
+    use aws_sdk_cloudfront::operation::create_invalidation::CreateInvalidationError;
+    use aws_sdk_s3::error::SdkError;
+    use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Error;
+    use aws_sdk_s3::operation::put_object::PutObjectError;
+    use aws_sdk_s3::primitives::ByteStreamError;
+    use aws_smithy_types::error::display::DisplayErrorContext;
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    #[allow(clippy::large_enum_variant)]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to build CloudFront invalidation: {}", source))]
+        BuildInvalidation { source: BuildError },
+
+        #[snafu(display(
+            "Failed to invalidate CloudFront distribution '{}': {}",
+            distribution_id,
+            DisplayErrorContext(source)
+        ))]
+        Invalidate {
+            distribution_id: String,
+            source: SdkError<CreateInvalidationError>,
+        },
+
+        #[snafu(display(
+            "Failed to list objects in bucket '{}': {}",
+            bucket,
+            DisplayErrorContext(source)
+        ))]
+        ListObjects {
+            bucket: String,
+            source: SdkError<ListObjectsV2Error>,
+        },
+
+        #[snafu(display(
+            "Failed to upload s3://{}/{}: {}",
+            bucket,
+            key,
+            DisplayErrorContext(source)
+        ))]
+        PutObject {
+            bucket: String,
+            key: String,
+            source: SdkError<PutObjectError>,
+        },
+
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        ReadDir { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read '{}' for upload: {}", path.display(), source))]
+        ReadUpload {
+            path: PathBuf,
+            source: ByteStreamError,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Repo {
+            #[snafu(source(from(crate::repo::Error, Box::new)))]
+            source: Box<crate::repo::Error>,
+        },
+    }
+}
+pub(crate) use error::Error;
+
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{content_type, plan_uploads, s3_key};
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn keys_and_content_types() {
+        assert_eq!(s3_key("", "targets"), "targets");
+        assert_eq!(s3_key("/prefix/", "targets"), "prefix/targets");
+        assert_eq!(content_type(Path::new("1.root.json")), "application/json");
+        assert_eq!(
+            content_type(Path::new("abc.os.img.lz4")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn plan_skips_existing_targets_and_orders_timestamp_last() {
+        let targets = vec![
+            (PathBuf::from("/repo/targets/aa.existing"), 10),
+            (PathBuf::from("/repo/targets/bb.resized"), 20),
+            (PathBuf::from("/repo/targets/cc.new"), 30),
+        ];
+        let metadata = vec![
+            (PathBuf::from("/repo/v/a/timestamp.json"), 1),
+            (PathBuf::from("/repo/v/a/1.snapshot.json"), 1),
+        ];
+        let remote = HashMap::from([
+            ("p/targets/aa.existing".to_string(), 10),
+            ("p/targets/bb.resized".to_string(), 10),
+            ("p/v/a/timestamp.json".to_string(), 1),
+        ]);
+        let uploads = plan_uploads(targets, metadata, "p/targets", "p/v/a", &remote);
+        let planned: Vec<_> = uploads
+            .iter()
+            .map(|upload| (upload.key.as_str(), upload.reason))
+            .collect();
+        assert_eq!(
+            planned,
+            vec![
+                ("p/targets/bb.resized", "changed"),
+                ("p/targets/cc.new", "new"),
+                ("p/v/a/1.snapshot.json", "new"),
+                ("p/v/a/timestamp.json", "metadata"),
+            ]
+        );
+    }
+}
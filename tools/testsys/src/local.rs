@@ -0,0 +1,790 @@
+use crate::error::{self, Result};
+use clap::Parser;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::time::Instant;
+
+/// Boot a locally built image in QEMU and run smoke checks against it. This does not require a
+/// testsys cluster or a cloud account, only `qemu-system-<arch>` and `lz4` on the build host, and
+/// `ssh` for the checks that run commands on the host.
+///
+/// Boot checks watch the console until the host is up. Command checks then run commands, such as
+/// `apiclient get os`, in the host's admin container over SSH, through a port that QEMU forwards
+/// from the build host, so that a host whose API server doesn't answer fails.
+#[derive(Debug, Parser)]
+pub(crate) struct Local {
+    /// The architecture of the image that is being tested.
+    #[arg(long, env = "BUILDSYS_ARCH")]
+    arch: String,
+
+    /// Path to the OS disk image. Images ending in `.lz4` are decompressed into `--work-dir`.
+    #[arg(long)]
+    os_image: PathBuf,
+
+    /// Path to the data disk image, for variants that use a separate data partition.
+    #[arg(long)]
+    data_image: Option<PathBuf>,
+
+    /// Directory used for decompressed images and the console log.
+    #[arg(long)]
+    work_dir: PathBuf,
+
+    /// A YAML file containing the smoke checks that should be run. If this is not provided, a
+    /// default set of checks will be used that waits for the API server and host containers to
+    /// start, and then, with `--ssh-key`, queries the API server.
+    #[arg(long)]
+    checks: Option<PathBuf>,
+
+    /// Additional boot checks in the form `name=expected console output`.
+    #[arg(long = "check", value_parser = parse_check)]
+    extra_checks: Vec<SmokeCheck>,
+
+    /// Additional command checks in the form `name=command`, such as
+    /// `os=apiclient get os`, which pass when the command succeeds on the host.
+    #[arg(long = "command-check", value_parser = parse_command_check)]
+    extra_command_checks: Vec<SmokeCheck>,
+
+    /// The private key to SSH to the host's admin container with, for the command checks. The
+    /// image must enable the admin container and authorize this key, such as in the variant's
+    /// settings defaults.
+    #[arg(long, env = "TESTSYS_LOCAL_SSH_KEY")]
+    ssh_key: Option<PathBuf>,
+
+    /// The user to SSH to the host's admin container as.
+    #[arg(long, default_value = "ec2-user")]
+    ssh_user: String,
+
+    /// The number of virtual CPUs to give the VM.
+    #[arg(long, default_value = "2")]
+    cpus: u16,
+
+    /// The amount of memory to give the VM in MiB.
+    #[arg(long, default_value = "4096")]
+    memory: u32,
+
+    /// The number of seconds to wait for all checks to pass before declaring failure.
+    #[arg(long, default_value = "600")]
+    timeout: u64,
+
+    /// Path to the UEFI firmware. This is required for aarch64, which has no BIOS boot path.
+    #[arg(long, env = "TESTSYS_LOCAL_FIRMWARE")]
+    firmware: Option<PathBuf>,
+
+    /// Disable KVM acceleration, e.g. when testing an image for a foreign architecture.
+    #[arg(long)]
+    no_kvm: bool,
+}
+
+/// A single smoke check. A boot check passes when the VM's console shows a line that is exactly its
+/// expected output, or a systemd status line whose message is its expected output, such as
+/// `[  OK  ] Started Bottlerocket API server.` A command check, which has a `command`, passes when
+/// the command succeeds in the host's admin container and prints the expected output, if any.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SmokeCheck {
+    name: String,
+    expect: Option<String>,
+    command: Option<Vec<String>>,
+}
+
+impl SmokeCheck {
+    fn boot(name: &str, expect: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            expect: Some(expect.to_string()),
+            command: None,
+        }
+    }
+
+    fn command(name: &str, command: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            expect: None,
+            command: Some(command.iter().map(|arg| arg.to_string()).collect()),
+        }
+    }
+}
+
+/// The contents of a `--checks` file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SmokeChecks {
+    checks: Vec<SmokeCheck>,
+}
+
+fn parse_check(s: &str) -> std::result::Result<SmokeCheck, String> {
+    let (name, expect) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected 'name=expected output', got '{}'", s))?;
+    Ok(SmokeCheck::boot(name, expect))
+}
+
+fn parse_command_check(s: &str) -> std::result::Result<SmokeCheck, String> {
+    let (name, command) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected 'name=command', got '{}'", s))?;
+    let command = command.split_whitespace().collect::<Vec<_>>();
+    if command.is_empty() {
+        return Err(format!("Check '{}' has no command", name));
+    }
+    Ok(SmokeCheck::command(name, &command))
+}
+
+/// The marker that systemd prints on the console when a unit has started or a target is reached.
+const OK_MARKER: &str = "[  OK  ] ";
+
+/// How long to wait between attempts of a command check that failed.
+const COMMAND_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The checks that are run when the user does not provide a `--checks` file.
+fn default_checks() -> Vec<SmokeCheck> {
+    vec![
+        SmokeCheck::boot("api-server", "Started Bottlerocket API server"),
+        SmokeCheck::boot("host-containerd", "Started Host container daemon"),
+        SmokeCheck::boot("control-container", "Started Host container: control"),
+        SmokeCheck::boot("multi-user", "Reached target Multi-User System"),
+        SmokeCheck::command("api-responds", &["apiclient", "get", "os"]),
+    ]
+}
+
+/// Checks that each check either watches the console or runs a command, which needs a way into
+/// the host.
+fn validate_checks(checks: &[SmokeCheck], has_ssh: bool) -> Result<()> {
+    for check in checks {
+        match &check.command {
+            None => ensure!(
+                check.expect.is_some(),
+                error::InvalidSnafu {
+                    what: format!(
+                        "Boot check '{}' needs the console output to expect",
+                        check.name
+                    )
+                }
+            ),
+            Some(command) => {
+                ensure!(
+                    !command.is_empty(),
+                    error::InvalidSnafu {
+                        what: format!("Command check '{}' has an empty command", check.name)
+                    }
+                );
+                ensure!(
+                    has_ssh,
+                    error::MissingSnafu {
+                        item: "--ssh-key",
+                        what: format!("the arguments for command check '{}'", check.name),
+                    }
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Local {
+    pub(crate) async fn run(self) -> Result<()> {
+        let mut checks = match &self.checks {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path).context(error::FileSnafu { path })?;
+                serde_yaml::from_str::<SmokeChecks>(&contents)
+                    .context(error::SerdeYamlSnafu {
+                        what: format!("Unable to parse smoke checks from '{}'", path.display()),
+                    })?
+                    .checks
+            }
+            None => {
+                let mut checks = default_checks();
+                if self.ssh_key.is_none() {
+                    warn!("No SSH key was provided, so only boot checks will be run");
+                    checks.retain(|check| check.command.is_none());
+                }
+                checks
+            }
+        };
+        checks.extend(self.extra_checks.iter().cloned());
+        checks.extend(self.extra_command_checks.iter().cloned());
+        ensure!(
+            !checks.is_empty(),
+            error::InvalidSnafu {
+                what: "At least one smoke check must be provided"
+            }
+        );
+        validate_checks(&checks, self.ssh_key.is_some())?;
+        let (command_checks, boot_checks): (Vec<_>, Vec<_>) = checks
+            .iter()
+            .cloned()
+            .partition(|check| check.command.is_some());
+
+        tokio::fs::create_dir_all(&self.work_dir)
+            .await
+            .context(error::IOSnafu {
+                what: format!("Unable to create '{}'", self.work_dir.display()),
+            })?;
+        let os_image = self.prepare_image(&self.os_image).await?;
+        let data_image = match &self.data_image {
+            Some(data_image) => Some(self.prepare_image(data_image).await?),
+            None => None,
+        };
+
+        let ssh_port = if command_checks.is_empty() {
+            None
+        } else {
+            Some(free_port()?)
+        };
+        let mut qemu = self.spawn_qemu(&os_image, data_image.as_deref(), ssh_port)?;
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        let results = self
+            .run_checks(&mut qemu, &boot_checks, &command_checks, ssh_port, deadline)
+            .await;
+        if let Err(e) = qemu.kill().await {
+            warn!("Unable to stop QEMU: {}", e);
+        }
+        let failures = results?;
+
+        let mut failed = 0;
+        for check in &checks {
+            match failures.iter().find(|(name, _)| name == &check.name) {
+                None => info!("PASS  {}", check.name),
+                Some((_, reason)) => {
+                    error!("FAIL  {} ({})", check.name, reason);
+                    failed += 1;
+                }
+            }
+        }
+        ensure!(
+            failed == 0,
+            error::InvalidSnafu {
+                what: format!(
+                    "{} of {} smoke checks failed, see '{}' for the console output",
+                    failed,
+                    checks.len(),
+                    self.console_log().display()
+                )
+            }
+        );
+        info!("All {} smoke checks passed", checks.len());
+        Ok(())
+    }
+
+    fn console_log(&self) -> PathBuf {
+        self.work_dir.join("console.log")
+    }
+
+    /// Returns a path to a raw disk image, decompressing `image` into the work dir if needed.
+    async fn prepare_image(&self, image: &Path) -> Result<PathBuf> {
+        if image.extension().and_then(|ext| ext.to_str()) != Some("lz4") {
+            return Ok(image.to_path_buf());
+        }
+        let file_name = image.file_stem().context(error::InvalidSnafu {
+            what: format!("Unable to get file name from '{}'", image.display()),
+        })?;
+        let output = self.work_dir.join(file_name);
+        info!("Decompressing '{}'", image.display());
+        let status = Command::new("lz4")
+            .arg("-d")
+            .arg("-f")
+            .arg(image)
+            .arg(&output)
+            .stdout(Stdio::null())
+            .status()
+            .await
+            .context(error::IOSnafu {
+                what: "Unable to run lz4",
+            })?;
+        ensure!(
+            status.success(),
+            error::InvalidSnafu {
+                what: format!("Unable to decompress '{}'", image.display())
+            }
+        );
+        Ok(output)
+    }
+
+    fn spawn_qemu(
+        &self,
+        os_image: &Path,
+        data_image: Option<&Path>,
+        ssh_port: Option<u16>,
+    ) -> Result<Child> {
+        let mut command = Command::new(format!("qemu-system-{}", self.arch));
+        match self.arch.as_str() {
+            "x86_64" => {
+                command.args(["-machine", "q35"]);
+            }
+            "aarch64" => {
+                let firmware = self.firmware.as_ref().context(error::MissingSnafu {
+                    item: "--firmware",
+                    what: "the arguments for an aarch64 image",
+                })?;
+                command
+                    .args(["-machine", "virt"])
+                    .arg("-bios")
+                    .arg(firmware);
+            }
+            _ => {
+                return error::UnsupportedSnafu {
+                    what: format!("Local testing of '{}' images", self.arch),
+                }
+                .fail()
+            }
+        }
+        if self.no_kvm {
+            command.args(["-cpu", "max"]);
+        } else {
+            command.args(["-enable-kvm", "-cpu", "host"]);
+        }
+        command
+            .arg("-smp")
+            .arg(self.cpus.to_string())
+            .arg("-m")
+            .arg(self.memory.to_string())
+            // Never write back to the build artifacts.
+            .arg("-snapshot")
+            .args(["-nographic", "-serial", "stdio", "-monitor", "none"])
+            .arg("-nic")
+            .arg(match ssh_port {
+                // Forward a port on the build host to the admin container's SSH server.
+                Some(port) => format!("user,model=virtio-net-pci,hostfwd=tcp:127.0.0.1:{port}-:22"),
+                None => "user,model=virtio-net-pci".to_string(),
+            })
+            .arg("-drive")
+            .arg(format!("if=virtio,format=raw,file={}", os_image.display()));
+        if let Some(data_image) = data_image {
+            command.arg("-drive").arg(format!(
+                "if=virtio,format=raw,file={}",
+                data_image.display()
+            ));
+        }
+        debug!("Starting QEMU: {:?}", command);
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context(error::IOSnafu {
+                what: format!("Unable to start qemu-system-{}", self.arch),
+            })
+    }
+
+    /// Reads the console until every boot check has passed, then runs the command checks, until
+    /// `deadline`. Returns the names of the checks that failed, and why.
+    async fn run_checks(
+        &self,
+        qemu: &mut Child,
+        boot_checks: &[SmokeCheck],
+        command_checks: &[SmokeCheck],
+        ssh_port: Option<u16>,
+        deadline: Instant,
+    ) -> Result<Vec<(String, String)>> {
+        let stdout = qemu.stdout.take().context(error::MissingSnafu {
+            item: "stdout",
+            what: "the QEMU process",
+        })?;
+        let console_log = self.console_log();
+        let mut log = File::create(&console_log)
+            .await
+            .context(error::FileSnafu { path: &console_log })?;
+        let mut console = BufReader::new(stdout);
+        let passed = watch_lines(
+            &mut console,
+            &mut log,
+            boot_checks,
+            deadline.saturating_duration_since(Instant::now()),
+        )
+        .await?;
+        let mut failures = boot_checks
+            .iter()
+            .filter(|check| !passed.contains(&check.name))
+            .map(|check| {
+                let expect = check.expect.as_deref().unwrap_or_default();
+                (check.name.clone(), format!("never saw '{}'", expect))
+            })
+            .collect::<Vec<_>>();
+        let Some(port) = ssh_port else {
+            return Ok(failures);
+        };
+        if !failures.is_empty() {
+            for check in command_checks {
+                failures.push((check.name.clone(), "a boot check failed".to_string()));
+            }
+            return Ok(failures);
+        }
+
+        // Keep copying the console to the log so that QEMU never blocks on a full pipe.
+        let drain = tokio::spawn(async move { tokio::io::copy(&mut console, &mut log).await });
+        for check in command_checks {
+            match self.command_check(port, check, deadline).await? {
+                None => info!("Smoke check '{}' passed", check.name),
+                Some(reason) => failures.push((check.name.clone(), reason)),
+            }
+        }
+        drain.abort();
+        Ok(failures)
+    }
+
+    /// Runs a command check until it passes or `deadline` passes, since the admin container may
+    /// still be starting after the boot checks pass. Returns why the last attempt failed, if it
+    /// did.
+    async fn command_check(
+        &self,
+        port: u16,
+        check: &SmokeCheck,
+        deadline: Instant,
+    ) -> Result<Option<String>> {
+        let command = check.command.as_deref().unwrap_or_default();
+        loop {
+            let reason = match tokio::time::timeout_at(deadline, self.ssh(port, command)).await {
+                Ok(output) => command_failure(&output?, check.expect.as_deref()),
+                Err(_) => Some("timed out".to_string()),
+            };
+            let Some(reason) = reason else {
+                return Ok(None);
+            };
+            if Instant::now() + COMMAND_RETRY_INTERVAL >= deadline {
+                return Ok(Some(reason));
+            }
+            debug!("Smoke check '{}' failed, retrying: {}", check.name, reason);
+            tokio::time::sleep(COMMAND_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Runs `command` in the host's admin container.
+    async fn ssh(&self, port: u16, command: &[String]) -> Result<Output> {
+        let key = self.ssh_key.as_ref().context(error::MissingSnafu {
+            item: "--ssh-key",
+            what: "the arguments for command checks",
+        })?;
+        let command = command
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        Command::new("ssh")
+            // The host's keys are new every boot.
+            .args(["-o", "StrictHostKeyChecking=no"])
+            .args(["-o", "UserKnownHostsFile=/dev/null"])
+            .args(["-o", "BatchMode=yes"])
+            .args(["-o", "ConnectTimeout=10"])
+            .args(["-o", "LogLevel=ERROR"])
+            .arg("-i")
+            .arg(key)
+            .arg("-p")
+            .arg(port.to_string())
+            .arg(format!("{}@127.0.0.1", self.ssh_user))
+            .arg("--")
+            .arg(command)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .context(error::IOSnafu {
+                what: "Unable to run ssh",
+            })
+    }
+}
+
+/// Finds a port on the build host that QEMU can forward to the VM.
+fn free_port() -> Result<u16> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").context(error::IOSnafu {
+        what: "Unable to find a free port for SSH",
+    })?;
+    Ok(listener
+        .local_addr()
+        .context(error::IOSnafu {
+            what: "Unable to find a free port for SSH",
+        })?
+        .port())
+}
+
+/// Quotes `arg` for the shell that SSH runs the command in.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Why a command check with the `expect`ed output failed, if it did.
+fn command_failure(output: &Output, expect: Option<&str>) -> Option<String> {
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Some(format!("{}: {}", output.status, stderr.trim()));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match expect {
+        Some(expect) if !stdout.contains(expect) => {
+            Some(format!("output did not contain '{}'", expect))
+        }
+        _ => None,
+    }
+}
+
+/// Copies lines from `console` to `log` until every check has passed, `console` closes, or
+/// `timeout` expires. Returns the names of the checks that passed.
+async fn watch_lines<R, W>(
+    console: R,
+    log: &mut W,
+    checks: &[SmokeCheck],
+    timeout: Duration,
+) -> Result<Vec<String>>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = console.lines();
+    let mut passed = Vec::new();
+
+    let watch = async {
+        while passed.len() < checks.len() {
+            let line = match lines.next_line().await.context(error::IOSnafu {
+                what: "Unable to read console output",
+            })? {
+                Some(line) => line,
+                None => {
+                    warn!("QEMU exited before all smoke checks passed");
+                    break;
+                }
+            };
+            log.write_all(format!("{}\n", line).as_bytes())
+                .await
+                .context(error::IOSnafu {
+                    what: "Unable to write the console log",
+                })?;
+            for check in checks {
+                let expect = check.expect.as_deref().unwrap_or_default();
+                if !passed.contains(&check.name) && check_passed(&line, expect) {
+                    info!("Smoke check '{}' passed", check.name);
+                    passed.push(check.name.clone());
+                }
+            }
+        }
+        Ok::<_, error::Error>(())
+    };
+
+    match tokio::time::timeout(timeout, watch).await {
+        Ok(result) => result?,
+        Err(_) => warn!(
+            "Timed out after {} seconds waiting for smoke checks",
+            timeout.as_secs()
+        ),
+    }
+    Ok(passed)
+}
+
+/// Whether a console line shows that a check with the `expect`ed output passed. Newer versions of
+/// systemd name the unit in status lines, as in
+/// `[  OK  ] Started apiserver.service - Bottlerocket API server.`, which matches the same check as
+/// `[  OK  ] Started Bottlerocket API server.`
+fn check_passed(line: &str, expect: &str) -> bool {
+    let line = strip_ansi(line);
+    let line = line.trim();
+    if line == expect {
+        return true;
+    }
+    let Some(message) = line.strip_prefix(OK_MARKER) else {
+        return false;
+    };
+    let message = message.strip_suffix('.').unwrap_or(message);
+    if message == expect {
+        return true;
+    }
+    let Some((head, description)) = message.split_once(" - ") else {
+        return false;
+    };
+    match head.rsplit_once(' ') {
+        Some((verb, unit)) if unit.contains('.') => format!("{} {}", verb, description) == expect,
+        _ => false,
+    }
+}
+
+/// Removes the ANSI escape sequences that color systemd's status lines.
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            stripped.push(c);
+            continue;
+        }
+        if chars.next() == Some('[') {
+            // Control sequences end with a byte in the range `@` to `~`.
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn checks() -> Vec<SmokeCheck> {
+        vec![
+            parse_check("api-server=Started Bottlerocket API server").unwrap(),
+            parse_check("multi-user=Reached target Multi-User System").unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_parse_check() {
+        assert_eq!(
+            parse_check("login=localhost login:").unwrap(),
+            SmokeCheck::boot("login", "localhost login:")
+        );
+        assert!(parse_check("no expected output").is_err());
+    }
+
+    #[test]
+    fn test_parse_command_check() {
+        assert_eq!(
+            parse_command_check("os=apiclient get os").unwrap(),
+            SmokeCheck::command("os", &["apiclient", "get", "os"])
+        );
+        assert!(parse_command_check("os=").is_err());
+        assert!(parse_command_check("apiclient get os").is_err());
+    }
+
+    #[test]
+    fn test_validate_checks() {
+        let checks = serde_yaml::from_str::<SmokeChecks>(
+            r#"
+checks:
+  - name: login
+    expect: "localhost login:"
+  - name: os
+    command: [apiclient, get, os]
+    expect: '"arch"'
+"#,
+        )
+        .unwrap()
+        .checks;
+        assert!(validate_checks(&checks, true).is_ok());
+        assert!(validate_checks(&checks, false).is_err());
+        assert!(validate_checks(&[SmokeCheck::boot("login", "")], false).is_ok());
+
+        let no_expect = SmokeCheck {
+            name: "login".to_string(),
+            expect: None,
+            command: None,
+        };
+        assert!(validate_checks(&[no_expect], true).is_err());
+        let no_command = SmokeCheck::command("os", &[]);
+        assert!(validate_checks(&[no_command], true).is_err());
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("settings.motd"), "'settings.motd'");
+        assert_eq!(shell_quote("it's $HOME"), "'it'\\''s $HOME'");
+    }
+
+    #[test]
+    fn test_command_failure() {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        let output = |code: i32, stdout: &str| Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: b"Failed to connect to API server\n".to_vec(),
+        };
+        assert_eq!(command_failure(&output(0, "{}"), None), None);
+        assert_eq!(
+            command_failure(&output(0, r#"{"arch": "x86_64"}"#), Some("x86_64")),
+            None
+        );
+        assert!(command_failure(&output(0, "{}"), Some("x86_64")).is_some());
+        let reason = command_failure(&output(1, ""), None).unwrap();
+        assert!(reason.ends_with("Failed to connect to API server"));
+    }
+
+    #[test]
+    fn test_check_passed() {
+        let expect = "Started Bottlerocket API server";
+        assert!(check_passed(
+            "[  OK  ] Started Bottlerocket API server.",
+            expect
+        ));
+        assert!(check_passed(
+            "[\u{1b}[0;32m  OK  \u{1b}[0m] Started apiserver.service - Bottlerocket API server.\r",
+            expect
+        ));
+        assert!(check_passed(
+            "[  OK  ] Reached target multi-user.target - Multi-User System.",
+            "Reached target Multi-User System"
+        ));
+        assert!(check_passed("localhost login:", "localhost login:"));
+
+        assert!(!check_passed(
+            "         Starting Bottlerocket API server...",
+            expect
+        ));
+        assert!(!check_passed(
+            "[FAILED] Failed to start Bottlerocket API server.",
+            expect
+        ));
+        assert!(!check_passed(
+            "[  OK  ] Started Bottlerocket API server proxy.",
+            expect
+        ));
+        assert!(!check_passed(
+            "echo Started Bottlerocket API server",
+            expect
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_watch_lines() {
+        let console = b"[  OK  ] Started Bottlerocket API server.\n\
+            [  OK  ] Reached target Multi-User System.\n\
+            never read\n";
+        let mut log = Vec::new();
+        let passed = watch_lines(&console[..], &mut log, &checks(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(passed, ["api-server", "multi-user"]);
+        assert!(String::from_utf8(log)
+            .unwrap()
+            .ends_with("Multi-User System.\n"));
+    }
+
+    #[tokio::test]
+    async fn test_watch_lines_exited() {
+        let console = b"[  OK  ] Reached target Multi-User System.\n";
+        let passed = watch_lines(
+            &console[..],
+            &mut tokio::io::sink(),
+            &checks(),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(passed, ["multi-user"]);
+    }
+
+    #[tokio::test]
+    async fn test_watch_lines_timeout() {
+        // The console stays open without showing the second check, as a hung VM would.
+        let (console, mut vm) = tokio::io::duplex(1024);
+        vm.write_all(b"[  OK  ] Started Bottlerocket API server.\n")
+            .await
+            .unwrap();
+        let passed = watch_lines(
+            BufReader::new(console),
+            &mut tokio::io::sink(),
+            &checks(),
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap();
+        assert_eq!(passed, ["api-server"]);
+    }
+}
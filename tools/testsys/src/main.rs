@@ -3,6 +3,7 @@ use delete::Delete;
 use env_logger::Builder;
 use error::Result;
 use install::Install;
+use local::Local;
 use log::{debug, error, LevelFilter};
 use logs::Logs;
 use restart_test::RestartTest;
@@ -21,6 +22,7 @@ mod crds;
 mod delete;
 mod error;
 mod install;
+mod local;
 mod logs;
 mod metal_k8s;
 mod migration;
@@ -52,6 +54,10 @@ struct TestsysArgs {
 
 impl TestsysArgs {
     async fn run(self) -> Result<()> {
+        // Local tests boot the image on the build host and do not need a testsys cluster.
+        if let Command::Local(local) = self.command {
+            return local.run().await;
+        }
         let client = match self.kubeconfig {
             Some(path) => TestManager::new_from_kubeconfig_path(&path).await?,
             None => TestManager::new().await?,
@@ -65,6 +71,7 @@ impl TestsysArgs {
             Command::RestartTest(restart_test) => restart_test.run(client).await?,
             Command::Add(add) => add.run(client).await?,
             Command::Uninstall(uninstall) => uninstall.run(client).await?,
+            Command::Local(_) => unreachable!("local tests are run without a testsys cluster"),
         };
         Ok(())
     }
@@ -81,6 +88,7 @@ enum Command {
     RestartTest(RestartTest),
     Add(Add),
     Uninstall(Uninstall),
+    Local(Local),
 }
 
 #[tokio::main]
@@ -16,13 +16,17 @@ use pubsys_config::vmware::{
 use pubsys_config::InfraConfig;
 use serde::{Deserialize, Serialize};
 use serde_plain::{derive_display_from_serialize, derive_fromstr_from_deserialize};
-use snafu::{OptionExt, ResultExt};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::fs::read_to_string;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use testsys_config::{GenericVariantConfig, ResourceAgentType, TestConfig};
-use testsys_model::test_manager::TestManager;
-use testsys_model::SecretName;
+use testsys_model::test_manager::{CrdState, CrdType, SelectionParams, TestManager};
+use testsys_model::{Crd, SecretName};
+
+/// How often `--wait` checks whether the tests have finished.
+const WAIT_INTERVAL: Duration = Duration::from_secs(30);
 
 /// Run a set of tests for a given arch and variant
 #[derive(Debug, Parser)]
@@ -115,6 +119,19 @@ pub(crate) struct Run {
     /// The template file that should be used for custom testing.
     #[arg(long = "template-file", short = 'f')]
     custom_crd_template: Option<PathBuf>,
+
+    /// Wait for the tests to finish, and fail if any of them failed.
+    #[arg(long)]
+    wait: bool,
+
+    /// How long to wait for the tests to finish, in minutes, with `--wait`.
+    #[arg(long, default_value = "240", requires = "wait")]
+    wait_timeout: u64,
+
+    /// Retry a run of the same tests that failed, rather than failing because its objects already
+    /// exist. Its tests that passed are kept, the rest are restarted, and its resources are reused.
+    #[arg(long)]
+    retry: bool,
 }
 
 /// This is a CLI parsable version of `testsys_config::GenericVariantConfig`.
@@ -435,16 +452,104 @@ impl Run {
             }
         };
 
+        // The objects that a failed run left behind, and which of its tests passed.
+        let (existing, passed) = if self.retry {
+            let existing = client
+                .list(&SelectionParams::default())
+                .await?
+                .iter()
+                .filter_map(Crd::name)
+                .collect::<Vec<_>>();
+            let passed = tests_in_state(&client, &existing, CrdState::Passed).await?;
+            (existing, passed)
+        } else {
+            Default::default()
+        };
+
         debug!("Adding crds to testsys cluster");
+        let mut tests = Vec::new();
         for crd in crds {
-            let crd = client.create_object(crd).await?;
-            info!("Successfully added '{}'", crd.name().unwrap());
+            let is_test = matches!(crd, Crd::Test(_));
+            let name = crd.name().unwrap_or_default();
+            if existing.contains(&name) {
+                if passed.contains(&name) {
+                    info!("Keeping '{}', which already passed", name);
+                } else if is_test {
+                    client.restart_test(&name).await?;
+                    info!("Successfully restarted '{}'", name);
+                } else {
+                    info!("Reusing '{}'", name);
+                }
+            } else {
+                let crd = client.create_object(crd).await?;
+                info!("Successfully added '{}'", crd.name().unwrap());
+            }
+            if is_test {
+                tests.push(name);
+            }
         }
 
+        if self.wait {
+            wait(&client, &tests, Duration::from_secs(self.wait_timeout * 60)).await?;
+        }
         Ok(())
     }
 }
 
+/// Waits for the tests named `tests` to finish, and fails if any of them failed or if they don't
+/// finish within `timeout`.
+async fn wait(client: &TestManager, tests: &[String], timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    info!("Waiting for {} tests to finish", tests.len());
+    loop {
+        let running = tests_in_state(client, tests, CrdState::NotFinished).await?;
+        if running.is_empty() {
+            break;
+        }
+        ensure!(
+            start.elapsed() < timeout,
+            error::InvalidSnafu {
+                what: format!(
+                    "Tests did not finish within {} minutes: {}",
+                    timeout.as_secs() / 60,
+                    running.join(", ")
+                )
+            }
+        );
+        debug!("Waiting for {}", running.join(", "));
+        tokio::time::sleep(WAIT_INTERVAL).await;
+    }
+
+    let failed = tests_in_state(client, tests, CrdState::Failed).await?;
+    ensure!(
+        failed.is_empty(),
+        error::InvalidSnafu {
+            what: format!("Tests failed: {}", failed.join(", "))
+        }
+    );
+    info!("All {} tests passed", tests.len());
+    Ok(())
+}
+
+/// The names of the tests among `tests` that are in `state`.
+async fn tests_in_state(
+    client: &TestManager,
+    tests: &[String],
+    state: CrdState,
+) -> Result<Vec<String>> {
+    Ok(client
+        .list(&SelectionParams {
+            state: Some(state),
+            crd_type: Some(CrdType::Test),
+            ..Default::default()
+        })
+        .await?
+        .iter()
+        .filter_map(Crd::name)
+        .filter(|name| tests.contains(name))
+        .collect())
+}
+
 fn parse_key_val(s: &str) -> Result<(String, SecretName)> {
     let mut iter = s.splitn(2, '=');
     let key = iter.next().context(error::InvalidSnafu {
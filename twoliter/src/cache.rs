@@ -0,0 +1,737 @@
+use crate::common::{exec, fs};
+use anyhow::{bail, Context, Result};
+use async_walkdir::WalkDir;
+use buildsys_config::SOURCE_CACHE_INDEX_DIRECTORY;
+use chrono::DateTime;
+use futures::StreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha512};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use strum::{EnumIter, IntoEnumIterator};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+/// The local caches that grow as a project is built.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, EnumIter)]
+pub(crate) enum CacheKind {
+    /// Source archives downloaded from the lookaside cache into `packages/<package>`.
+    Lookaside,
+    /// Vendored dependency bundles generated from external files, e.g. `bundled-*.tar.gz`.
+    Bundles,
+    /// Built RPMs under `build/rpms`.
+    Rpms,
+    /// Docker images left behind by buildsys for this checkout, and dangling images that buildsys
+    /// built. An image's size includes any layers it shares with other images, so the size of
+    /// this cache is an upper bound on the space that removing it frees.
+    Images,
+}
+
+impl CacheKind {
+    pub(crate) fn all() -> Vec<Self> {
+        Self::iter().collect()
+    }
+}
+
+impl std::str::FromStr for CacheKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lookaside" => Ok(Self::Lookaside),
+            "bundles" => Ok(Self::Bundles),
+            "rpms" => Ok(Self::Rpms),
+            "images" => Ok(Self::Images),
+            _ => bail!(
+                "unknown cache '{}', expected one of lookaside, bundles, rpms, images",
+                s
+            ),
+        }
+    }
+}
+
+impl Display for CacheKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Lookaside => "lookaside",
+            Self::Bundles => "bundles",
+            Self::Rpms => "rpms",
+            Self::Images => "images",
+        })
+    }
+}
+
+/// A single item in a cache that can be removed independently of the others.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct CacheEntry {
+    pub(crate) kind: CacheKind,
+    pub(crate) location: CacheLocation,
+    pub(crate) size: u64,
+    pub(crate) modified: SystemTime,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum CacheLocation {
+    File(PathBuf),
+    Dir(PathBuf),
+    Image(String),
+}
+
+impl Display for CacheLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) | Self::Dir(path) => Display::fmt(&path.display(), f),
+            Self::Image(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Determines which cache entries are removed. An entry is removed if it is older than
+/// `older_than`, or if its cache is still larger than `max_size` after removing old entries, in
+/// which case the oldest entries go first.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PrunePolicy {
+    pub(crate) older_than: Option<Duration>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) all: bool,
+}
+
+impl PrunePolicy {
+    /// Returns the entries that should be removed, grouped by cache.
+    pub(crate) fn select(&self, entries: Vec<CacheEntry>, now: SystemTime) -> Vec<CacheEntry> {
+        let mut by_kind: BTreeMap<CacheKind, Vec<CacheEntry>> = BTreeMap::new();
+        for entry in entries {
+            by_kind.entry(entry.kind).or_default().push(entry);
+        }
+
+        let mut selected = Vec::new();
+        for (_, mut entries) in by_kind {
+            if self.all {
+                selected.extend(entries);
+                continue;
+            }
+            // Oldest first, so that size-based pruning removes the least recently used entries.
+            entries.sort_by_key(|entry| entry.modified);
+            let mut remaining: u64 = entries.iter().map(|entry| entry.size).sum();
+            for entry in entries {
+                let age = now.duration_since(entry.modified).unwrap_or_default();
+                let too_old = self.older_than.is_some_and(|limit| age > limit);
+                let too_big = self.max_size.is_some_and(|limit| remaining > limit);
+                if too_old || too_big {
+                    remaining -= entry.size;
+                    selected.push(entry);
+                }
+            }
+        }
+        selected
+    }
+}
+
+/// Finds every entry in the given caches for the project rooted at `project_dir`.
+pub(crate) async fn find_entries(
+    project_dir: &Path,
+    kinds: &[CacheKind],
+) -> Result<Vec<CacheEntry>> {
+    let mut entries = Vec::new();
+    if kinds.contains(&CacheKind::Lookaside) || kinds.contains(&CacheKind::Bundles) {
+        for entry in find_external_files(project_dir).await? {
+            if kinds.contains(&entry.kind) {
+                entries.push(entry);
+            }
+        }
+    }
+    if kinds.contains(&CacheKind::Rpms) {
+        entries.extend(find_rpms(&project_dir.join("build/rpms")).await?);
+    }
+    if kinds.contains(&CacheKind::Images) {
+        entries.extend(find_images(project_dir).await?);
+    }
+    Ok(entries)
+}
+
+/// Removes a cache entry.
+pub(crate) async fn remove(entry: &CacheEntry) -> Result<()> {
+    debug!("Removing {} cache entry '{}'", entry.kind, entry.location);
+    match &entry.location {
+        CacheLocation::File(path) => fs::remove_file(path).await,
+        CacheLocation::Dir(path) => fs::remove_dir_all(path).await,
+        CacheLocation::Image(id) => exec(
+            Command::new("docker").args(["rmi", "--force", id.as_str()]),
+            true,
+        )
+        .await
+        .map(|_| ()),
+    }
+}
+
+/// The parts of a package's `Cargo.toml` that describe files fetched from the lookaside cache.
+#[derive(Debug, Deserialize)]
+struct PackageManifest {
+    package: Option<PackageSection>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PackageSection {
+    metadata: Option<PackageMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PackageMetadata {
+    #[serde(rename = "build-package")]
+    build_package: Option<BuildPackage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BuildPackage {
+    external_files: Option<Vec<ExternalFile>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExternalFile {
+    path: Option<PathBuf>,
+    url: String,
+    bundle_modules: Option<Vec<String>>,
+    bundle_output_path: Option<PathBuf>,
+}
+
+impl ExternalFile {
+    /// The file name that buildsys saves the download as, relative to the package directory.
+    fn local_path(&self) -> Option<PathBuf> {
+        self.path.clone().or_else(|| {
+            self.url
+                .rsplit('/')
+                .next()
+                .filter(|name| !name.is_empty())
+                .map(PathBuf::from)
+        })
+    }
+
+    /// The bundle that buildsys generates from this file, if any.
+    fn bundle_path(&self) -> Option<PathBuf> {
+        self.bundle_modules.as_ref()?;
+        self.bundle_output_path.clone().or_else(|| {
+            let local = self.local_path()?;
+            Some(PathBuf::from(format!(
+                "bundled-{}",
+                local.file_name()?.to_string_lossy()
+            )))
+        })
+    }
+}
+
+async fn find_external_files(project_dir: &Path) -> Result<Vec<CacheEntry>> {
+    let packages_dir = project_dir.join("packages");
+    if !packages_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    let mut dirs = tokio::fs::read_dir(&packages_dir)
+        .await
+        .context(format!("Unable to read '{}'", packages_dir.display()))?;
+    while let Some(dir) = dirs
+        .next_entry()
+        .await
+        .context(format!("Unable to read '{}'", packages_dir.display()))?
+    {
+        let package_dir = dir.path();
+        let manifest_path = package_dir.join("Cargo.toml");
+        if !manifest_path.is_file() {
+            continue;
+        }
+        let manifest: PackageManifest = toml::from_str(&fs::read_to_string(&manifest_path).await?)
+            .context(format!("Unable to parse '{}'", manifest_path.display()))?;
+        let external_files = manifest
+            .package
+            .and_then(|p| p.metadata)
+            .and_then(|m| m.build_package)
+            .and_then(|b| b.external_files)
+            .unwrap_or_default();
+        for file in external_files {
+            let candidates = [
+                (CacheKind::Lookaside, file.local_path()),
+                (CacheKind::Bundles, file.bundle_path()),
+            ];
+            for (kind, path) in candidates {
+                if let Some(path) = path.map(|p| package_dir.join(p)) {
+                    if path.is_file() {
+                        let metadata = fs::metadata(&path).await?;
+                        entries.push(CacheEntry {
+                            kind,
+                            size: metadata.len(),
+                            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+                            location: CacheLocation::File(path),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Each package's RPMs are written to their own directory, which is treated as a single entry.
+async fn find_rpms(rpms_dir: &Path) -> Result<Vec<CacheEntry>> {
+    if !rpms_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    let mut dirs = tokio::fs::read_dir(rpms_dir)
+        .await
+        .context(format!("Unable to read '{}'", rpms_dir.display()))?;
+    while let Some(dir) = dirs
+        .next_entry()
+        .await
+        .context(format!("Unable to read '{}'", rpms_dir.display()))?
+    {
+        let path = dir.path();
+        let metadata = fs::metadata(&path).await?;
+        let (size, modified) = if metadata.is_dir() {
+            dir_usage(&path).await?
+        } else {
+            (metadata.len(), metadata.modified().unwrap_or(UNIX_EPOCH))
+        };
+        entries.push(CacheEntry {
+            kind: CacheKind::Rpms,
+            location: if metadata.is_dir() {
+                CacheLocation::Dir(path)
+            } else {
+                CacheLocation::File(path)
+            },
+            size,
+            modified,
+        });
+    }
+    Ok(entries)
+}
+
+/// Returns the total size of the files in a directory and the time the newest one was modified.
+async fn dir_usage(dir: &Path) -> Result<(u64, SystemTime)> {
+    let mut size = 0;
+    let mut modified = UNIX_EPOCH;
+    let mut walk = WalkDir::new(dir);
+    while let Some(entry) = walk.next().await {
+        let entry = entry.context(format!("Unable to read '{}'", dir.display()))?;
+        let metadata = entry
+            .metadata()
+            .await
+            .context(format!("Unable to read '{}'", entry.path().display()))?;
+        if metadata.is_file() {
+            size += metadata.len();
+            modified = modified.max(metadata.modified().unwrap_or(UNIX_EPOCH));
+        }
+    }
+    Ok((size, modified))
+}
+
+/// Buildsys names the repositories of the images it builds with one of these prefixes.
+const IMAGE_PREFIXES: [&str; 4] = [
+    "buildsys-pkg-",
+    "buildsys-kit-",
+    "buildsys-var-",
+    "buildsys-repack-",
+];
+
+/// Buildsys labels the images it builds with its version.
+const BUILDSYS_VERSION_LABEL: &str = "org.bottlerocket.buildsys.version";
+
+/// Finds images tagged by buildsys for this checkout, along with any dangling images that buildsys
+/// built, which can no longer be traced to a checkout.
+async fn find_images(project_dir: &Path) -> Result<Vec<CacheEntry>> {
+    let suffix = format!("-{}", checkout_token(project_dir));
+    let mut images = Vec::new();
+    for (id, reference) in list_images(&["--filter", "reference=buildsys-*"]).await? {
+        let (repository, _) = reference.rsplit_once(':').unwrap_or((&reference, ""));
+        if IMAGE_PREFIXES
+            .iter()
+            .any(|prefix| repository.starts_with(prefix))
+            && repository.ends_with(&suffix)
+        {
+            images.push((id, reference));
+        }
+    }
+    let label = format!("label={}", BUILDSYS_VERSION_LABEL);
+    for (id, _) in list_images(&["--filter", "dangling=true", "--filter", &label]).await? {
+        images.push((id.clone(), id));
+    }
+
+    let mut entries = Vec::new();
+    for (id, name) in images {
+        let inspect = exec(
+            Command::new("docker").args([
+                "image",
+                "inspect",
+                "--format",
+                "{{.Created}}\t{{.Size}}",
+                &id,
+            ]),
+            true,
+        )
+        .await
+        .context(format!("Unable to inspect docker image '{}'", id))?
+        .unwrap_or_default();
+        let parsed = inspect
+            .trim()
+            .split_once('\t')
+            .context(format!(
+                "Unexpected output from docker inspect: '{}'",
+                inspect
+            ))
+            .and_then(|(created, size)| {
+                let size = size
+                    .parse()
+                    .context(format!("Invalid image size '{}'", size))?;
+                Ok((parse_timestamp(created)?, size))
+            });
+        let (modified, size) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("Skipping docker image '{}': {}", name, e);
+                continue;
+            }
+        };
+        entries.push(CacheEntry {
+            kind: CacheKind::Images,
+            location: CacheLocation::Image(name),
+            size,
+            modified,
+        });
+    }
+    Ok(entries)
+}
+
+/// Lists the IDs and references of the images that match `filters`.
+async fn list_images(filters: &[&str]) -> Result<Vec<(String, String)>> {
+    let list = exec(
+        Command::new("docker")
+            .args(["image", "ls", "--no-trunc"])
+            .args(filters)
+            .args(["--format", "{{.ID}}\t{{.Repository}}:{{.Tag}}"]),
+        true,
+    )
+    .await
+    .context("Unable to list docker images")?
+    .unwrap_or_default();
+    Ok(list
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(id, reference)| (id.to_string(), reference.to_string()))
+        .collect())
+}
+
+/// Buildsys appends this token to image tags to avoid collisions between checkouts.
+pub(crate) fn checkout_token(project_dir: &Path) -> String {
+    let mut d = Sha512::new();
+    d.update(project_dir.display().to_string());
+    d.finalize()
+        .iter()
+        .take(6)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Parses an RFC 3339 timestamp as reported by `docker image inspect`, e.g.
+/// `2024-07-11T10:00:00.123456789Z`.
+pub(crate) fn parse_timestamp(s: &str) -> Result<SystemTime> {
+    let timestamp =
+        DateTime::parse_from_rfc3339(s).context(format!("Invalid timestamp '{}'", s))?;
+    Ok(timestamp.into())
+}
+
+/// A file in the local source cache that no longer matches the source cache index.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct DamagedFile {
+    pub(crate) path: PathBuf,
+    /// How the file differs, either `truncated` or `corrupt`.
+    pub(crate) damage: &'static str,
+}
+
+/// The index that a package build writes of its local source cache.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SourceCacheIndex {
+    package_dir: PathBuf,
+    files: BTreeMap<String, IndexEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexEntry {
+    sha512: String,
+    size: u64,
+}
+
+/// Checks every file in the source cache indexes of the project rooted at `project_dir` against
+/// its recorded digest. Returns how many files were checked, and the ones that are damaged. Files
+/// that have been removed since they were indexed are skipped.
+pub(crate) async fn verify_sources(project_dir: &Path) -> Result<(usize, Vec<DamagedFile>)> {
+    let index_dir = project_dir.join(SOURCE_CACHE_INDEX_DIRECTORY);
+    if !index_dir.is_dir() {
+        return Ok((0, Vec::new()));
+    }
+    let mut checked = 0;
+    let mut damaged = Vec::new();
+    let mut indexes = tokio::fs::read_dir(&index_dir)
+        .await
+        .context(format!("Unable to read '{}'", index_dir.display()))?;
+    while let Some(entry) = indexes
+        .next_entry()
+        .await
+        .context(format!("Unable to read '{}'", index_dir.display()))?
+    {
+        let path = entry.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let index: SourceCacheIndex = serde_json::from_str(&fs::read_to_string(&path).await?)
+            .context(format!("Unable to parse '{}'", path.display()))?;
+        for (name, expected) in index.files {
+            let file = project_dir.join(&index.package_dir).join(name);
+            if !file.is_file() {
+                continue;
+            }
+            checked += 1;
+            let size = fs::metadata(&file).await?.len();
+            let damage = if size < expected.size {
+                Some("truncated")
+            } else if file_digest(&file).await? != expected.sha512 {
+                Some("corrupt")
+            } else {
+                None
+            };
+            if let Some(damage) = damage {
+                damaged.push(DamagedFile { path: file, damage });
+            }
+        }
+    }
+    Ok((checked, damaged))
+}
+
+/// Returns the SHA-512 digest of a file, without reading all of it into memory.
+pub(crate) async fn file_digest(path: &Path) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut f =
+            std::fs::File::open(&path).context(format!("Unable to open '{}'", path.display()))?;
+        let mut d = Sha512::new();
+        std::io::copy(&mut f, &mut d).context(format!("Unable to read '{}'", path.display()))?;
+        Ok(d.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    })
+    .await
+    .context("Unable to find the digest of a cached file")?
+}
+
+/// Parses a duration such as `30d`, `12h`, `90m` or `45s`.
+pub(crate) fn parse_age(s: &str) -> Result<Duration> {
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = value
+        .parse()
+        .context(format!("Invalid age '{}', expected e.g. '30d'", s))?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 60 * 60,
+        "d" | "" => value * 60 * 60 * 24,
+        "w" => value * 60 * 60 * 24 * 7,
+        _ => bail!("Invalid age unit '{}', expected one of s, m, h, d, w", unit),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parses a size such as `500M` or `20G`. Units are powers of 1024.
+pub(crate) fn parse_size(s: &str) -> Result<u64> {
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = value
+        .parse()
+        .context(format!("Invalid size '{}', expected e.g. '20G'", s))?;
+    let shift = match unit.trim_end_matches(['B', 'b', 'i']) {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        _ => bail!("Invalid size unit '{}', expected one of K, M, G, T", unit),
+    };
+    value
+        .checked_mul(1 << shift)
+        .context(format!("Size '{}' is too large", s))
+}
+
+/// Formats a number of bytes for humans, e.g. `1.5 GiB`.
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Logs a per-cache summary of the entries that were, or would be, removed.
+pub(crate) fn summarize(selected: &[CacheEntry], dry_run: bool) {
+    let mut totals: BTreeMap<CacheKind, (usize, u64)> = BTreeMap::new();
+    for entry in selected {
+        let total = totals.entry(entry.kind).or_default();
+        total.0 += 1;
+        total.1 += entry.size;
+    }
+    let verb = if dry_run {
+        "Would reclaim"
+    } else {
+        "Reclaimed"
+    };
+    for (kind, (count, size)) in &totals {
+        info!(
+            "{} {} from {} {} cache entries",
+            verb,
+            format_size(*size),
+            count,
+            kind
+        );
+    }
+    let total: u64 = totals.values().map(|(_, size)| size).sum();
+    info!("{} {} in total", verb, format_size(total));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(kind: CacheKind, name: &str, size: u64, age_days: u64, now: SystemTime) -> CacheEntry {
+        CacheEntry {
+            kind,
+            location: CacheLocation::File(PathBuf::from(name)),
+            size,
+            modified: now - Duration::from_secs(age_days * 86400),
+        }
+    }
+
+    fn names(entries: &[CacheEntry]) -> Vec<String> {
+        entries.iter().map(|e| e.location.to_string()).collect()
+    }
+
+    #[test]
+    fn test_select_by_age() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry(CacheKind::Rpms, "old", 10, 40, now),
+            entry(CacheKind::Rpms, "new", 10, 1, now),
+        ];
+        let policy = PrunePolicy {
+            older_than: Some(parse_age("30d").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(names(&policy.select(entries, now)), vec!["old"]);
+    }
+
+    #[test]
+    fn test_select_by_size_removes_oldest_per_cache() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry(CacheKind::Rpms, "a", 50, 3, now),
+            entry(CacheKind::Rpms, "b", 50, 2, now),
+            entry(CacheKind::Rpms, "c", 50, 1, now),
+            entry(CacheKind::Lookaside, "d", 50, 5, now),
+        ];
+        let policy = PrunePolicy {
+            max_size: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(names(&policy.select(entries, now)), vec!["a"]);
+    }
+
+    #[test]
+    fn test_parse_age_and_size() {
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 3600));
+        assert_eq!(parse_age("2").unwrap(), Duration::from_secs(2 * 86400));
+        assert!(parse_age("1y").is_err());
+        assert_eq!(parse_size("20G").unwrap(), 20 << 30);
+        assert_eq!(parse_size("512MiB").unwrap(), 512 << 20);
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert!(parse_size("3X").is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(
+            parse_timestamp("1970-01-02T00:00:01Z").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(86401)
+        );
+        assert_eq!(
+            parse_timestamp("2024-07-11T10:00:00.123456789Z").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1720692000)
+        );
+        assert_eq!(
+            parse_timestamp("2024-07-11T12:00:00+02:00").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1720692000)
+        );
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_sources() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let package_dir = dir.path().join("packages/hello");
+        std::fs::create_dir_all(&package_dir).unwrap();
+        std::fs::create_dir_all(dir.path().join(SOURCE_CACHE_INDEX_DIRECTORY)).unwrap();
+        std::fs::write(package_dir.join("a.tar.gz"), b"hello").unwrap();
+        std::fs::write(package_dir.join("b.tar.gz"), b"hello").unwrap();
+        std::fs::write(package_dir.join("c.tar.gz"), b"jello").unwrap();
+        let hello = file_digest(&package_dir.join("a.tar.gz")).await.unwrap();
+        let index = serde_json::json!({
+            "package-dir": "packages/hello",
+            "files": {
+                "a.tar.gz": {"kind": "external-file", "sha512": hello, "size": 5},
+                "b.tar.gz": {"kind": "external-file", "sha512": hello, "size": 11},
+                "c.tar.gz": {"kind": "bundle", "sha512": hello, "size": 5},
+                "d.tar.gz": {"kind": "bundle", "sha512": hello, "size": 5},
+            }
+        });
+        std::fs::write(
+            dir.path()
+                .join(SOURCE_CACHE_INDEX_DIRECTORY)
+                .join("hello.json"),
+            index.to_string(),
+        )
+        .unwrap();
+
+        let (checked, damaged) = verify_sources(dir.path()).await.unwrap();
+        assert_eq!(checked, 3);
+        assert_eq!(
+            damaged,
+            vec![
+                DamagedFile {
+                    path: package_dir.join("b.tar.gz"),
+                    damage: "truncated"
+                },
+                DamagedFile {
+                    path: package_dir.join("c.tar.gz"),
+                    damage: "corrupt"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_external_file_paths() {
+        let file = ExternalFile {
+            path: None,
+            url: "https://example.com/src/hello-1.0.tar.gz".to_string(),
+            bundle_modules: Some(vec!["go".to_string()]),
+            bundle_output_path: None,
+        };
+        assert_eq!(file.local_path(), Some(PathBuf::from("hello-1.0.tar.gz")));
+        assert_eq!(
+            file.bundle_path(),
+            Some(PathBuf::from("bundled-hello-1.0.tar.gz"))
+        );
+    }
+}
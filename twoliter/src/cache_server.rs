@@ -0,0 +1,426 @@
+/*!
+A server for buildsys's remote cache, which keeps its entries in a local directory. An entry is
+fetched with GET, which answers 200 with the entry or 404, and stored with PUT, which answers 201.
+Entries are named by their SHA-512 key, in lowercase hex:
+
+* `<key>.tar` holds the outputs of a package build.
+* `sources/<sha512>` holds an external file, which is checked against its digest when it's stored.
+* `bundles/<key>` holds a bundle of vendored dependencies.
+
+If the server has a token, every request must carry it as `Authorization: Bearer <token>`. The
+server only speaks plain HTTP, so the token and the entries cross the network in the clear. A
+server that builds reach over the network must be put behind a reverse proxy that terminates TLS,
+and listen on an address that only the proxy can reach.
+
+At most `max_connections` connections are served at once; further clients wait to be accepted.
+An entry larger than `max_entry_size` is refused with 413 before any of it is read.
+Entries are written to a temporary file and renamed into place, so a request never sees an entry
+that is only partly written.
+*/
+
+use crate::cache::file_digest;
+use crate::common::fs;
+use anyhow::{Context, Result};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha512};
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+/// The directory of the entry store where entries are written before they are renamed into place.
+const TEMP_DIR: &str = ".tmp";
+
+/// The size of the chunks that entries are sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serves the remote cache entries in a directory.
+#[derive(Debug, Clone)]
+pub(crate) struct CacheServer {
+    dir: PathBuf,
+    token: Option<String>,
+    max_connections: usize,
+    max_entry_size: u64,
+}
+
+/// Which kind of entry a request is for, which decides where it's stored and how it's checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    Build,
+    Source,
+    Bundle,
+}
+
+impl CacheServer {
+    /// A server for the entries in `dir`, which requires `token` if there is one, serves at most
+    /// `max_connections` connections at once, and stores entries of up to `max_entry_size` bytes.
+    pub(crate) fn new(
+        dir: impl Into<PathBuf>,
+        token: Option<String>,
+        max_connections: usize,
+        max_entry_size: u64,
+    ) -> Self {
+        Self {
+            dir: dir.into(),
+            token: token.filter(|token| !token.is_empty()),
+            max_connections: max_connections.max(1),
+            max_entry_size,
+        }
+    }
+
+    /// Answers requests from `listener` until the process is stopped.
+    pub(crate) async fn serve(self, listener: TcpListener) -> Result<()> {
+        fs::create_dir_all(self.dir.join(TEMP_DIR)).await?;
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        let server = Arc::new(self);
+        loop {
+            // Wait for a connection to finish before accepting another once there are too many.
+            let permit = Arc::clone(&connections)
+                .acquire_owned()
+                .await
+                .context("Unable to wait for a connection to finish")?;
+            let (stream, peer) = listener
+                .accept()
+                .await
+                .context("Unable to accept a connection")?;
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    let server = Arc::clone(&server);
+                    async move { Ok::<_, Infallible>(server.handle(request).await) }
+                });
+                if let Err(e) = Http::new()
+                    .http1_only(true)
+                    .serve_connection(stream, service)
+                    .await
+                {
+                    debug!("Connection from {} failed: {:?}", peer, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    /// Answers a request, and logs the answer.
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let method = request.method().clone();
+        let path = request.uri().path().trim_start_matches('/').to_string();
+        let response = match self.respond(request, &path).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Unable to answer {} /{}: {:?}", method, path, e);
+                status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+        info!("{} /{} {}", method, path, response.status().as_u16());
+        response
+    }
+
+    async fn respond(&self, request: Request<Body>, path: &str) -> Result<Response<Body>> {
+        if let Some(token) = &self.token {
+            let authorization = request
+                .headers()
+                .get(AUTHORIZATION)
+                .map(|value| value.as_bytes())
+                .unwrap_or_default();
+            if !token_matches(authorization, format!("Bearer {token}").as_bytes()) {
+                return Ok(status(StatusCode::UNAUTHORIZED));
+            }
+        }
+        let Some(kind) = EntryKind::parse(path) else {
+            return Ok(status(StatusCode::NOT_FOUND));
+        };
+        let entry = self.dir.join(path);
+        match request.method().as_str() {
+            "GET" | "HEAD" => match tokio::fs::File::open(&entry).await {
+                Ok(file) => {
+                    let length = fs::metadata(&entry).await?.len();
+                    let body = if request.method() == Method::GET {
+                        file_body(file)
+                    } else {
+                        Body::empty()
+                    };
+                    Ok(Response::builder()
+                        .status(StatusCode::OK)
+                        .header(CONTENT_LENGTH, length)
+                        .body(body)
+                        .context("Unable to build the response")?)
+                }
+                Err(_) => Ok(status(StatusCode::NOT_FOUND)),
+            },
+            "PUT" => {
+                let length = request
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok());
+                let Some(length) = length else {
+                    return Ok(status(StatusCode::LENGTH_REQUIRED));
+                };
+                if length > self.max_entry_size {
+                    return Ok(status(StatusCode::PAYLOAD_TOO_LARGE));
+                }
+                match self.store(kind, &entry, request.into_body(), length).await {
+                    Ok(()) => Ok(status(StatusCode::CREATED)),
+                    Err(e) => {
+                        warn!("Unable to store '{}': {:?}", path, e);
+                        Ok(status(StatusCode::BAD_REQUEST))
+                    }
+                }
+            }
+            _ => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+        }
+    }
+
+    /// Reads an entry of `length` bytes from `body` and stores it at `path`. Sources that don't
+    /// match their digest aren't stored.
+    async fn store(&self, kind: EntryKind, path: &Path, body: Body, length: u64) -> Result<()> {
+        let tmp = self
+            .dir
+            .join(TEMP_DIR)
+            .join(uuid::Uuid::new_v4().to_string());
+        let mut file = tokio::fs::File::create(&tmp)
+            .await
+            .context(format!("Unable to create '{}'", tmp.display()))?;
+        let copied = write_body(body, &mut file).await;
+        drop(file);
+        let result = match copied {
+            Ok(copied) if copied == length => Ok(()),
+            Ok(copied) => Err(anyhow::anyhow!(
+                "The request ended after {copied} of {length} bytes"
+            )),
+            Err(e) => Err(e),
+        };
+        let result = match (result, kind) {
+            (Ok(()), EntryKind::Source) => {
+                let expected = path.file_name().unwrap_or_default().to_string_lossy();
+                match file_digest(&tmp).await {
+                    Ok(digest) if digest == expected => Ok(()),
+                    Ok(_) => Err(anyhow::anyhow!(
+                        "The source doesn't match its digest '{expected}'"
+                    )),
+                    Err(e) => Err(e),
+                }
+            }
+            (result, _) => result,
+        };
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&tmp, path).await
+    }
+}
+
+impl EntryKind {
+    /// The kind of entry at `path`, relative to the cache, if it's the name of an entry. Keys must
+    /// be lowercase, so that a key names the same entry on case-insensitive filesystems.
+    fn parse(path: &str) -> Option<Self> {
+        let is_key = |key: &str| {
+            key.len() == 128
+                && key
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        };
+        match path.split_once('/') {
+            Some(("sources", digest)) if is_key(digest) => Some(Self::Source),
+            Some(("bundles", key)) if is_key(key) => Some(Self::Bundle),
+            None if path.strip_suffix(".tar").is_some_and(is_key) => Some(Self::Build),
+            _ => None,
+        }
+    }
+}
+
+/// A response without a body.
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Whether the `Authorization` header `given` is `expected`. The headers are compared by their
+/// digests, so the comparison takes as long wherever they differ and whatever their lengths, and
+/// its timing doesn't tell a client how much of the token it has guessed.
+fn token_matches(given: &[u8], expected: &[u8]) -> bool {
+    let given = Sha512::digest(given);
+    let expected = Sha512::digest(expected);
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// A body that streams `file`, so that large entries aren't held in memory.
+fn file_body(mut file: tokio::fs::File) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            match file.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(read) => {
+                    let chunk = Bytes::copy_from_slice(&buffer[..read]);
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    debug!("Unable to read an entry: {:?}", e);
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+    body
+}
+
+/// Writes `body` to `file`, and returns how many bytes it had.
+async fn write_body(mut body: Body, file: &mut tokio::fs::File) -> Result<u64> {
+    let mut copied = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.context("Unable to read the request")?;
+        file.write_all(&chunk)
+            .await
+            .context("Unable to write the entry")?;
+        copied += chunk.len() as u64;
+    }
+    file.flush().await.context("Unable to write the entry")?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn digest(data: &[u8]) -> String {
+        Sha512::digest(data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Serves the cache in `dir` on a free local port, and returns its URL.
+    async fn serve(dir: &Path, token: Option<&str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = CacheServer::new(dir, token.map(str::to_string), 2, 1024);
+        tokio::spawn(server.serve(listener));
+        url
+    }
+
+    #[test]
+    fn test_entry_kind() {
+        let key = "a".repeat(128);
+        assert_eq!(
+            EntryKind::parse(&format!("{key}.tar")),
+            Some(EntryKind::Build)
+        );
+        assert_eq!(
+            EntryKind::parse(&format!("sources/{key}")),
+            Some(EntryKind::Source)
+        );
+        assert_eq!(
+            EntryKind::parse(&format!("bundles/{key}")),
+            Some(EntryKind::Bundle)
+        );
+        assert_eq!(EntryKind::parse(&key), None);
+        assert_eq!(EntryKind::parse(&format!("sources/../{key}")), None);
+        assert_eq!(EntryKind::parse("sources/abc"), None);
+        assert_eq!(
+            EntryKind::parse(&format!("bundles/{}", "A".repeat(128))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches(b"Bearer secret", b"Bearer secret"));
+        assert!(!token_matches(b"Bearer secreT", b"Bearer secret"));
+        assert!(!token_matches(b"Bearer", b"Bearer secret"));
+        assert!(!token_matches(b"", b"Bearer secret"));
+    }
+
+    #[tokio::test]
+    async fn test_put_and_get() {
+        let dir = TempDir::new().unwrap();
+        let url = serve(dir.path(), Some("secret")).await;
+        let entry = format!("{url}/sources/{}", digest(b"hello"));
+        let client = reqwest::Client::new();
+
+        let response = client
+            .put(&entry)
+            .bearer_auth("secret")
+            .body("hello")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+
+        let response = client
+            .get(&entry)
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "hello");
+
+        let response = client.get(&entry).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_put_source_checks_digest() {
+        let dir = TempDir::new().unwrap();
+        let url = serve(dir.path(), None).await;
+        let path = format!("sources/{}", digest(b"hello"));
+        let client = reqwest::Client::new();
+
+        let response = client
+            .put(format!("{url}/{path}"))
+            .body("jello")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert!(!dir.path().join(path).exists());
+
+        let key = "b".repeat(128);
+        let response = client
+            .get(format!("{url}/bundles/{key}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_put_too_large() {
+        let dir = TempDir::new().unwrap();
+        let url = serve(dir.path(), None).await;
+        let data = vec![0; 2048];
+        let path = format!("sources/{}", digest(&data));
+
+        let response = reqwest::Client::new()
+            .put(format!("{url}/{path}"))
+            .body(data)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!dir.path().join(path).exists());
+    }
+}
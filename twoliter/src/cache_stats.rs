@@ -0,0 +1,162 @@
+/*!
+Reports how much of a build was served from caches. Buildsys appends a record to
+`build/cache-log.json` for each build, with how many external files, vendored bundles, RPMs and
+docker layers it took from a cache and how many it had to download or build. After a build, the
+records from that run are added up, summarized in the log, and included in the JSON output.
+*/
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A record from the buildsys cache log.
+#[derive(Debug, Clone, Deserialize)]
+struct Record {
+    item: String,
+    cached: u64,
+    fresh: u64,
+    recorded: u64,
+}
+
+/// How many items of one kind were served from a cache, and how many were downloaded or built.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct CacheCount {
+    pub(crate) cached: u64,
+    pub(crate) fresh: u64,
+}
+
+impl CacheCount {
+    fn add(&mut self, record: &Record) {
+        self.cached += record.cached;
+        self.fresh += record.fresh;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cached == 0 && self.fresh == 0
+    }
+}
+
+/// The cache use of every build in a run of twoliter.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CacheStats {
+    pub(crate) external_files: CacheCount,
+    pub(crate) vendored_bundles: CacheCount,
+    pub(crate) rpms: CacheCount,
+    pub(crate) docker_layers: CacheCount,
+}
+
+impl CacheStats {
+    /// Reads the records in the cache log at `path` that were written at or after `since`, in
+    /// seconds since the epoch. Records that can't be read are left out.
+    pub(crate) async fn read(path: &Path, since: u64) -> Self {
+        let Ok(log) = tokio::fs::read_to_string(path).await else {
+            return Self::default();
+        };
+        Self::from_records(
+            log.lines()
+                .filter_map(|line| serde_json::from_str::<Record>(line).ok())
+                .filter(|record| record.recorded >= since),
+        )
+    }
+
+    fn from_records(records: impl IntoIterator<Item = Record>) -> Self {
+        let mut stats = Self::default();
+        for record in records {
+            let count = match record.item.as_str() {
+                "external-file" => &mut stats.external_files,
+                "vendored-bundle" => &mut stats.vendored_bundles,
+                "rpm" => &mut stats.rpms,
+                "docker-layer" => &mut stats.docker_layers,
+                _ => continue,
+            };
+            count.add(&record);
+        }
+        stats
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.counts().iter().all(|(_, _, count)| count.is_empty())
+    }
+
+    /// Each kind of item, with how to describe the ones that weren't cached.
+    fn counts(&self) -> [(&'static str, &'static str, &CacheCount); 4] {
+        [
+            ("external files", "downloaded", &self.external_files),
+            ("vendored bundles", "vendored", &self.vendored_bundles),
+            ("RPMs", "built", &self.rpms),
+            ("docker layers", "built", &self.docker_layers),
+        ]
+    }
+
+    /// A line such as `Cache: external files 12 cached, 2 downloaded; RPMs 40 cached, 5 built`.
+    pub(crate) fn summary(&self) -> String {
+        let parts = self
+            .counts()
+            .iter()
+            .filter(|(_, _, count)| !count.is_empty())
+            .map(|(name, fresh, count)| {
+                format!(
+                    "{} {} cached, {} {}",
+                    name, count.cached, count.fresh, fresh
+                )
+            })
+            .collect::<Vec<_>>();
+        format!("Cache: {}", parts.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("cache-log.json");
+        std::fs::write(
+            &path,
+            [
+                r#"{"artifact":"glibc","item":"external-file","cached":1,"fresh":0,"recorded":50}"#,
+                r#"{"artifact":"glibc","item":"external-file","cached":2,"fresh":1,"recorded":100}"#,
+                r#"{"artifact":"glibc","item":"rpm","cached":0,"fresh":4,"recorded":100}"#,
+                r#"{"artifact":"glibc","item":"docker-layer","cached":9,"fresh":3,"recorded":101}"#,
+                r#"{"artifact":"kernel-6.1","item":"rpm","cached":6,"fresh":0,"recorded":102}"#,
+                r#"{"artifact":"kernel-6.1","item":"rpm","cached":6,"#,
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        let stats = CacheStats::read(&path, 100).await;
+        assert_eq!(
+            stats,
+            CacheStats {
+                external_files: CacheCount {
+                    cached: 2,
+                    fresh: 1
+                },
+                vendored_bundles: CacheCount::default(),
+                rpms: CacheCount {
+                    cached: 6,
+                    fresh: 4
+                },
+                docker_layers: CacheCount {
+                    cached: 9,
+                    fresh: 3
+                },
+            }
+        );
+        assert_eq!(
+            stats.summary(),
+            "Cache: external files 2 cached, 1 downloaded; RPMs 6 cached, 4 built; \
+            docker layers 9 cached, 3 built"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_missing() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let stats = CacheStats::read(&dir.path().join("cache-log.json"), 0).await;
+        assert!(stats.is_empty());
+    }
+}
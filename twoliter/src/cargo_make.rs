@@ -1,8 +1,9 @@
-use crate::common::{exec_log, BUILDSYS_OUTPUT_GENERATION_ID};
+use crate::common::{exec, exec_log, BUILDSYS_OUTPUT_GENERATION_ID};
+use crate::logging::{self, LOG_FILTER_ENV};
 use anyhow::{bail, Result};
 use std::path::PathBuf;
 use tokio::process::Command;
-use tracing::trace;
+use tracing::{info_span, trace, Instrument};
 
 /// A struct used to invoke `cargo make` tasks with `twoliter`'s `Makefile.toml`.
 /// ```rust
@@ -44,16 +45,22 @@ pub struct CargoMake {
     makefile_path: Option<PathBuf>,
     project_dir: Option<PathBuf>,
     args: Vec<String>,
+    quiet: bool,
 }
 
 impl CargoMake {
     /// Create a new `cargo make` command. The sdk environment variable will be set based on the
-    /// definition in `Twoliter.toml`.
+    /// definition in `Twoliter.toml`, twoliter's version is passed on for builds to record, and
+    /// buildsys logs at the same level as twoliter.
     pub(crate) fn new(sdk: &str) -> Result<Self> {
-        Ok(Self::default().env("TLPRIVATE_SDK_IMAGE", sdk).env(
-            "BUILDSYS_OUTPUT_GENERATION_ID",
-            BUILDSYS_OUTPUT_GENERATION_ID.to_string(),
-        ))
+        Ok(Self::default()
+            .env("TLPRIVATE_SDK_IMAGE", sdk)
+            .env(
+                "BUILDSYS_OUTPUT_GENERATION_ID",
+                BUILDSYS_OUTPUT_GENERATION_ID.to_string(),
+            )
+            .env("TWOLITER_VERSION", env!("CARGO_PKG_VERSION"))
+            .env(LOG_FILTER_ENV, logging::buildsys_filter()))
     }
 
     /// Specify the path to the `Makefile.toml` for the `cargo make` command
@@ -74,6 +81,13 @@ impl CargoMake {
         self
     }
 
+    /// Capture the output of the command instead of printing it, whatever the log level. The
+    /// output is still shown if the command fails.
+    pub(crate) fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
     /// Specify environment variables that should be applied for this comand
     pub(crate) fn env<S1, S2>(mut self, key: S1, value: S2) -> Self
     where
@@ -113,26 +127,30 @@ impl CargoMake {
         S2: Into<String>,
         I: IntoIterator<Item = S2>,
     {
-        exec_log(
-            Command::new("cargo")
-                .arg("make")
-                .arg("--disable-check-for-updates")
-                .args(
-                    self.makefile_path.iter().flat_map(|path| {
-                        vec!["--makefile".to_string(), path.display().to_string()]
-                    }),
-                )
-                .args(
-                    self.project_dir
-                        .iter()
-                        .flat_map(|path| vec!["--cwd".to_string(), path.display().to_string()]),
-                )
-                .args(build_system_env_vars()?)
-                .args(&self.args)
-                .arg(task.into())
-                .args(args.into_iter().map(Into::into)),
-        )
-        .await
+        let task = task.into();
+        let span = info_span!("make", task = %task);
+        let mut cmd = Command::new("cargo");
+        cmd.arg("make")
+            .arg("--disable-check-for-updates")
+            .args(
+                self.makefile_path
+                    .iter()
+                    .flat_map(|path| vec!["--makefile".to_string(), path.display().to_string()]),
+            )
+            .args(
+                self.project_dir
+                    .iter()
+                    .flat_map(|path| vec!["--cwd".to_string(), path.display().to_string()]),
+            )
+            .args(build_system_env_vars()?)
+            .args(&self.args)
+            .arg(&task)
+            .args(args.into_iter().map(Into::into));
+        if self.quiet {
+            exec(&mut cmd, true).instrument(span).await.map(|_| ())
+        } else {
+            exec_log(&mut cmd).instrument(span).await
+        }
     }
 }
 
@@ -0,0 +1,344 @@
+/*!
+With `--ci github` or `--ci gitlab`, twoliter formats the output of builds for the CI system that it
+runs in. Cargo shows the output of a build only if it fails, all at once, so a failure is buried
+somewhere in tens of thousands of lines. Instead, the output of builds is captured, and as each
+package, kit or variant build finishes, twoliter prints its log folded into a group for each stage
+of the Dockerfile.
+
+Builds that fail are also annotated with what failed them, so that the CI system shows it on the
+summary of the run. When buildsys could tell where the problem is, such as a line of a spec that
+rpmbuild rejected, or a manifest that couldn't be parsed, the annotation points to the file and
+line. GitLab has no annotations in job logs, so the problem is highlighted after the groups.
+*/
+
+use crate::progress::{Problem, Record, State};
+use clap::ValueEnum;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The CI system whose log format twoliter writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CiFormat {
+    /// GitHub Actions workflow commands.
+    Github,
+    /// GitLab CI collapsible sections.
+    Gitlab,
+}
+
+static FORMAT: OnceLock<CiFormat> = OnceLock::new();
+
+/// Sets the CI format for this run of twoliter, if one was given.
+pub(crate) fn init(format: Option<CiFormat>) {
+    if let Some(format) = format {
+        let _ = FORMAT.set(format);
+    }
+}
+
+/// The CI format that twoliter writes, if any.
+pub(crate) fn format() -> Option<CiFormat> {
+    FORMAT.get().copied()
+}
+
+/// `text` folded into a group titled `title`, which starts folded.
+pub(crate) fn folded(format: CiFormat, title: &str, text: &str) -> String {
+    let time = crate::progress::now();
+    let mut s = group_start(format, title, title, time);
+    let _ = writeln!(s, "{}", text.trim_end());
+    s.push_str(&group_end(format, title, time));
+    s
+}
+
+/// What to print for a build that finished, given the contents of its log if it has one. Paths in
+/// annotations are made relative to `dir`, the directory that the CI system checked out.
+pub(crate) fn report(format: CiFormat, record: &Record, log: Option<&str>, dir: &Path) -> String {
+    let mut s = String::new();
+    let build = format!("{} {} ({})", record.kind, record.name, record.arch);
+    if record.state == State::Cached {
+        let _ = writeln!(s, "{build}: cached");
+        return s;
+    }
+    for (stage, lines) in log.map(stages).unwrap_or_default() {
+        let title = format!("{build}: {stage}");
+        let id = format!("{}_{}_{}_{}", record.kind, record.name, record.arch, stage);
+        s.push_str(&group_start(format, &id, &title, record.started));
+        for line in lines {
+            let _ = writeln!(s, "{line}");
+        }
+        s.push_str(&group_end(format, &id, record.updated));
+    }
+    if record.state == State::Failed {
+        let message = match &record.problem {
+            Some(problem) => problem.message.clone(),
+            None => match &record.stage {
+                Some(stage) => format!("The build failed in {stage}"),
+                None => "The build failed".to_string(),
+            },
+        };
+        let file = record
+            .problem
+            .as_ref()
+            .and_then(|problem| problem.file.as_deref())
+            .map(|file| file.strip_prefix(dir).unwrap_or(file));
+        let line = record.problem.as_ref().and_then(|problem| problem.line);
+        s.push_str(&annotation(format, &build, &message, file, line));
+    }
+    s
+}
+
+fn group_start(format: CiFormat, id: &str, title: &str, time: u64) -> String {
+    match format {
+        CiFormat::Github => format!("::group::{}\n", escape_data(title)),
+        CiFormat::Gitlab => format!(
+            "\x1b[0Ksection_start:{time}:{}[collapsed=true]\r\x1b[0K{title}\n",
+            section_name(id)
+        ),
+    }
+}
+
+fn group_end(format: CiFormat, id: &str, time: u64) -> String {
+    match format {
+        CiFormat::Github => "::endgroup::\n".to_string(),
+        CiFormat::Gitlab => format!("\x1b[0Ksection_end:{time}:{}\r\x1b[0K\n", section_name(id)),
+    }
+}
+
+/// An error annotation for `title`, at `file` and `line` if they are known.
+fn annotation(
+    format: CiFormat,
+    title: &str,
+    message: &str,
+    file: Option<&Path>,
+    line: Option<u32>,
+) -> String {
+    match format {
+        CiFormat::Github => {
+            let mut properties = Vec::new();
+            if let Some(file) = file {
+                properties.push(format!(
+                    "file={}",
+                    escape_property(&file.display().to_string())
+                ));
+                if let Some(line) = line {
+                    properties.push(format!("line={line}"));
+                }
+            }
+            properties.push(format!("title={}", escape_property(title)));
+            format!(
+                "::error {}::{}\n",
+                properties.join(","),
+                escape_data(message)
+            )
+        }
+        CiFormat::Gitlab => {
+            let location = match (file, line) {
+                (Some(file), Some(line)) => format!("{}:{line}: ", file.display()),
+                (Some(file), None) => format!("{}: ", file.display()),
+                _ => String::new(),
+            };
+            format!("\x1b[31;1mERROR: {title}: {location}{message}\x1b[0m\n")
+        }
+    }
+}
+
+/// Splits the output of `docker build` into the stages of the Dockerfile, in the order they
+/// started. BuildKit numbers each step, names the stage of the step on its first line, as in
+/// `#12 [rpmbuild 3/7] RUN rpmbuild ...`, and starts each line of its output with the number.
+/// Lines that aren't part of a stage's step, such as loading the Dockerfile, are put in `setup`,
+/// and lines that aren't part of any step are put with the stage before them.
+fn stages(log: &str) -> Vec<(String, Vec<&str>)> {
+    let mut stages: Vec<(String, Vec<&str>)> = Vec::new();
+    let mut steps: Vec<(&str, usize)> = Vec::new();
+    let mut current = None;
+    for line in log.lines() {
+        let index = match step(line) {
+            Some((number, stage)) => match steps.iter().find(|(n, _)| *n == number) {
+                Some((_, index)) => *index,
+                None => {
+                    let stage = stage.unwrap_or("setup");
+                    let index = match stages.iter().position(|(name, _)| name == stage) {
+                        Some(index) => index,
+                        None => {
+                            stages.push((stage.to_string(), Vec::new()));
+                            stages.len() - 1
+                        }
+                    };
+                    steps.push((number, index));
+                    index
+                }
+            },
+            None => match current {
+                Some(index) => index,
+                None => {
+                    stages.push(("setup".to_string(), Vec::new()));
+                    stages.len() - 1
+                }
+            },
+        };
+        stages[index].1.push(line);
+        current = Some(index);
+    }
+    stages
+}
+
+/// The number of the BuildKit step that `line` is part of, and the stage it names if it's the
+/// first line of the step.
+fn step(line: &str) -> Option<(&str, Option<&str>)> {
+    let rest = line.strip_prefix('#')?;
+    let (number, rest) = rest.split_once(' ')?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let stage = rest
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once(']'))
+        .and_then(|(step, _)| step.rsplit_once(' '))
+        .filter(|(_, count)| count.contains('/'))
+        .map(|(stage, _)| stage);
+    Some((number, stage))
+}
+
+/// GitLab only allows letters, numbers, `_`, `.` and `-` in the names of sections.
+fn section_name(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "_.-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    const LOG: &str = "\
+#1 [internal] load build definition from build.Dockerfile
+#1 DONE 0.0s
+#5 [rpmsetup 1/2] COPY ./packages/glibc/ .
+#5 CACHED
+#12 [rpmbuild 3/7] RUN rpmbuild -ba --clean glibc.spec
+#12 0.412 error: line 42: Unknown tag: Sumary: The GNU libc libraries
+#12 ERROR: process did not complete successfully: exit code: 1
+ERROR: failed to solve: process did not complete successfully
+";
+
+    fn record(state: State, problem: Option<Problem>) -> Record {
+        Record {
+            name: "glibc".to_string(),
+            kind: "package".to_string(),
+            arch: "x86_64".to_string(),
+            state,
+            stage: Some("rpmbuild".to_string()),
+            started: 100,
+            updated: 160,
+            log: None,
+            problem,
+            maintainers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_stages() {
+        let stages = stages(LOG);
+        let names = stages
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["setup", "rpmsetup", "rpmbuild"]);
+        assert_eq!(stages[0].1.len(), 2);
+        assert_eq!(stages[2].1.len(), 4);
+    }
+
+    #[test]
+    fn test_step() {
+        assert_eq!(
+            step("#12 [rpmbuild 3/7] RUN rpmbuild"),
+            Some(("12", Some("rpmbuild")))
+        );
+        assert_eq!(step("#1 [internal] load .dockerignore"), Some(("1", None)));
+        assert_eq!(step("#12 0.412 + make"), Some(("12", None)));
+        assert_eq!(step("# comment"), None);
+        assert_eq!(step("ERROR: failed to solve"), None);
+    }
+
+    #[test]
+    fn test_github_report() {
+        let problem = Problem {
+            message: "Unknown tag: Sumary: The GNU libc libraries".to_string(),
+            file: Some(PathBuf::from("/src/packages/glibc/glibc.spec")),
+            line: Some(42),
+        };
+        let report = report(
+            CiFormat::Github,
+            &record(State::Failed, Some(problem)),
+            Some(LOG),
+            Path::new("/src"),
+        );
+        assert!(report.starts_with("::group::package glibc (x86_64): setup\n#1 [internal]"));
+        assert_eq!(report.matches("::endgroup::").count(), 3);
+        assert!(report.ends_with(
+            "::error file=packages/glibc/glibc.spec,line=42,title=package glibc (x86_64)\
+             ::Unknown tag: Sumary: The GNU libc libraries\n"
+        ));
+    }
+
+    #[test]
+    fn test_gitlab_report() {
+        let report = report(
+            CiFormat::Gitlab,
+            &record(State::Failed, None),
+            Some(LOG),
+            Path::new("/src"),
+        );
+        assert!(report.starts_with(
+            "\x1b[0Ksection_start:100:package_glibc_x86_64_setup[collapsed=true]\r\x1b[0K\
+             package glibc (x86_64): setup\n"
+        ));
+        assert!(report.contains("\x1b[0Ksection_end:160:package_glibc_x86_64_rpmbuild\r\x1b[0K\n"));
+        assert!(report.ends_with(
+            "\x1b[31;1mERROR: package glibc (x86_64): The build failed in rpmbuild\x1b[0m\n"
+        ));
+    }
+
+    #[test]
+    fn test_cached_report() {
+        assert_eq!(
+            report(
+                CiFormat::Github,
+                &record(State::Cached, None),
+                None,
+                Path::new("/src")
+            ),
+            "package glibc (x86_64): cached\n"
+        );
+    }
+
+    #[test]
+    fn test_folded() {
+        assert_eq!(
+            folded(CiFormat::Github, "Error details", "line one\nline two\n"),
+            "::group::Error details\nline one\nline two\n::endgroup::\n"
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_data("100%\nof it"), "100%25%0Aof it");
+        assert_eq!(escape_property("a:b,c"), "a%3Ab%2Cc");
+    }
+}
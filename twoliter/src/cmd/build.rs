@@ -1,18 +1,34 @@
 use super::build_clean::BuildClean;
+use super::graph;
 use crate::cargo_make::CargoMake;
 use crate::common::fs;
-use crate::project::{self, Locked};
+use crate::critical_path::CriticalPath;
+use crate::metrics::Metrics;
+use crate::output;
+use crate::progress::{self, Progress};
+use crate::project::{self, BuildsysConfig, Locked};
+use crate::remote::RemoteHost;
 use crate::tools::install_tools;
-use anyhow::{Context, Result};
-use clap::Parser;
-use std::path::PathBuf;
+use anyhow::{ensure, Context, Result};
+use buildsys::manifest::ImageFeature;
+use buildsys_config::{
+    variant_changelog_path, variant_images_dir, ArtifactsDir, BUILD_PROGRESS_DIRECTORY,
+};
+use clap::{Args, Parser};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
 use tempfile::TempDir;
+use tracing::{info, instrument, warn};
 
 #[derive(Debug, Parser)]
 pub(crate) enum BuildCommand {
     Clean(BuildClean),
     Kit(BuildKit),
+    Package(BuildPackage),
     Variant(BuildVariant),
+    Variants(BuildVariants),
 }
 
 impl BuildCommand {
@@ -20,7 +36,9 @@ impl BuildCommand {
         match self {
             BuildCommand::Clean(command) => command.run().await,
             BuildCommand::Kit(command) => command.run().await,
+            BuildCommand::Package(command) => command.run().await,
             BuildCommand::Variant(command) => command.run().await,
+            BuildCommand::Variants(command) => command.run().await,
         }
     }
 }
@@ -40,50 +58,79 @@ pub(crate) struct BuildKit {
     pub(crate) kit: String,
 
     /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
-    /// Defaults to https://cache.bottlerocket.aws
+    /// This can be a `file://` URL or the absolute path of a local directory. Defaults to
+    /// https://cache.bottlerocket.aws
     pub(crate) lookaside_cache: Option<String>,
 
-    /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
-    /// from the upstream URL found in a package's `Cargo.toml`.
-    #[clap(long = "upstream-source-fallback")]
-    pub(crate) upstream_source_fallback: bool,
+    #[clap(flatten)]
+    pub(crate) options: BuildOptions,
 }
 
 impl BuildKit {
+    #[instrument(name = "build", skip_all, fields(kit = %self.kit, arch = %self.arch))]
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
         let project = project.load_lock::<Locked>().await?;
-        let toolsdir = project.project_dir().join("build/tools");
-        install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
-
-        let mut optional_envs = Vec::new();
+        let mut optional_envs = self.options.envs(&project)?;
 
         if let Some(lookaside_cache) = &self.lookaside_cache {
-            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache))
+            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache.to_string()))
         }
 
-        CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
-            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
+        let cargo_make = project_cargo_make(&project)
+            .await?
             .env("BUILDSYS_ARCH", &self.arch)
             .env("BUILDSYS_KIT", &self.kit)
-            .env("BUILDSYS_VERSION_IMAGE", project.release_version())
-            .env("GO_MODULES", project.find_go_modules().await?.join(" "))
-            .env(
-                "BUILDSYS_UPSTREAM_SOURCE_FALLBACK",
-                self.upstream_source_fallback.to_string(),
-            )
-            .envs(optional_envs.into_iter())
-            .makefile(makefile_path)
-            .project_dir(project.project_dir())
+            .envs(optional_envs.into_iter());
+
+        let metrics = Metrics::start(
+            project.metrics_settings(),
+            project.project_dir(),
+            "build kit",
+        );
+        let progress = Progress::start(project.project_dir());
+        let result = cargo_make
+            .quiet(progress.captures_output())
             .exec("build-kit")
-            .await
+            .await;
+        progress.finish().await;
+        metrics.finish(&result).await;
+        result?;
+
+        let kit_dir = project
+            .project_dir()
+            .join("build/kits")
+            .join(&self.kit)
+            .join(&self.arch);
+        if let Some(artifacts_dir) = self.options.artifacts_dir(&project) {
+            let (artifacts_dir, kit, arch, output_dir) = (
+                ArtifactsDir::new(artifacts_dir),
+                self.kit.clone(),
+                self.arch.clone(),
+                kit_dir.clone(),
+            );
+            tokio::task::spawn_blocking(move || {
+                artifacts_dir
+                    .store_kit(&kit, &arch, &output_dir)
+                    .with_context(|| {
+                        format!(
+                            "Unable to store the kit in '{}'",
+                            artifacts_dir.kit_dir(&kit, &arch).display()
+                        )
+                    })
+            })
+            .await??;
+        }
+
+        output::artifact(kit_dir).await;
+        Ok(())
     }
 }
 
-/// Build a Bottlerocket variant image.
+/// Build a single package, along with the packages it depends on. Its RPMs are written to
+/// `build/rpms/<package>`.
 #[derive(Debug, Parser)]
-pub(crate) struct BuildVariant {
+pub(crate) struct BuildPackage {
     /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
     #[clap(long = "project-path")]
     project_path: Option<PathBuf>,
@@ -92,42 +139,487 @@ pub(crate) struct BuildVariant {
     #[clap(long = "arch", default_value = "x86_64")]
     arch: String,
 
+    /// The name of the package to build, as given in its Cargo.toml.
+    package: String,
+
+    /// The variant to use for settings that depend on one, such as the output directory for
+    /// images. Packages are built the same way for every variant.
+    #[clap(long = "variant")]
+    variant: Option<String>,
+
+    /// Also build every package in the project that depends on this one, directly or indirectly,
+    /// to check that they still build with it.
+    #[clap(long = "dependents")]
+    dependents: bool,
+
+    #[clap(flatten)]
+    options: BuildOptions,
+}
+
+impl BuildPackage {
+    #[instrument(name = "build", skip_all, fields(package = %self.package, arch = %self.arch))]
+    pub(super) async fn run(&self) -> Result<()> {
+        ensure!(
+            self.variant.is_some() || self.options.artifacts_dir.is_none(),
+            "--artifacts-dir needs --variant, since the RPMs are stored by variant"
+        );
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+
+        let local_packages = LocalPackage::find_all(&project.project_dir()).await?;
+        let packages = if self.dependents {
+            dependents(&local_packages, &self.package)?
+        } else {
+            vec![self.package.clone()]
+        };
+        if packages.len() > 1 {
+            info!(
+                "Building {} and its dependents: {}",
+                self.package,
+                packages[1..].join(", ")
+            );
+        }
+
+        let mut optional_envs = self.options.envs(&project)?;
+
+        if let Some(variant) = &self.variant {
+            optional_envs.push(("BUILDSYS_VARIANT", variant.to_string()))
+        }
+
+        let cargo_make = project_cargo_make(&project)
+            .await?
+            .env("BUILDSYS_ARCH", &self.arch)
+            .env("PACKAGE", packages.join(" "))
+            .envs(optional_envs.into_iter());
+
+        let metrics = Metrics::start(
+            project.metrics_settings(),
+            project.project_dir(),
+            "build package",
+        );
+        let progress = Progress::start(project.project_dir());
+        let result = cargo_make
+            .quiet(progress.captures_output())
+            .exec("build-package")
+            .await;
+        progress.finish().await;
+        metrics.finish(&result).await;
+        result?;
+
+        let artifacts_dir = self.options.artifacts_dir(&project);
+        if let (Some(artifacts_dir), Some(variant)) = (artifacts_dir, &self.variant) {
+            let built = with_dependencies(
+                &local_packages,
+                local_packages
+                    .iter()
+                    .filter(|p| packages.contains(&p.crate_name))
+                    .map(|p| p.dir_name.clone())
+                    .collect(),
+            );
+            store_package_rpms(
+                &project.project_dir(),
+                &ArtifactsDir::new(artifacts_dir),
+                variant,
+                &self.arch,
+                &built,
+            )
+            .await?;
+        }
+
+        for package in local_packages
+            .iter()
+            .filter(|p| packages.contains(&p.crate_name))
+        {
+            output::artifacts_in(
+                project
+                    .project_dir()
+                    .join("build/rpms")
+                    .join(&package.package_name),
+            )
+            .await;
+        }
+        Ok(())
+    }
+}
+
+/// A package in the project's `packages` directory.
+#[derive(Debug, Clone, PartialEq)]
+struct LocalPackage {
+    /// The name in Cargo.toml, which Cargo builds it by.
+    crate_name: String,
+    /// The name of its spec and RPMs, which may be overridden with `package-name`.
+    package_name: String,
+    dir_name: String,
+    /// The directories of the packages it depends on.
+    dependencies: Vec<String>,
+}
+
+impl LocalPackage {
+    async fn find_all(project_dir: &Path) -> Result<Vec<Self>> {
+        let mut packages = Vec::new();
+        let packages_dir = project_dir.join("packages");
+        let mut entries = tokio::fs::read_dir(&packages_dir)
+            .await
+            .with_context(|| format!("Unable to list '{}'", packages_dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let manifest = entry.path().join("Cargo.toml");
+            if manifest.is_file() {
+                let dir_name = entry.file_name().to_string_lossy().to_string();
+                let cargo = fs::read_to_string(&manifest).await?;
+                packages.push(
+                    Self::parse(dir_name, &cargo)
+                        .with_context(|| format!("Unable to parse '{}'", manifest.display()))?,
+                );
+            }
+        }
+        Ok(packages)
+    }
+
+    fn parse(dir_name: String, cargo: &str) -> Result<Self> {
+        let cargo: toml::Table = toml::from_str(cargo)?;
+        let package = cargo.get("package").context("missing [package]")?;
+        let crate_name = package
+            .get("name")
+            .and_then(|v| v.as_str())
+            .context("missing package.name")?
+            .to_string();
+        let package_name = package
+            .get("metadata")
+            .and_then(|m| m.get("build-package"))
+            .and_then(|b| b.get("package-name"))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| crate_name.clone());
+        let dependencies = ["build-dependencies", "dependencies"]
+            .iter()
+            .filter_map(|section| cargo.get(*section).and_then(|deps| deps.as_table()))
+            .flat_map(|deps| deps.values())
+            .filter_map(|dep| dep.get("path").and_then(|p| p.as_str()))
+            .filter_map(|path| {
+                let path = Path::new(path);
+                let parent = path.parent()?.file_name()?;
+                (parent == "packages").then(|| path.file_name())?
+            })
+            .map(|name| name.to_string_lossy().to_string())
+            .collect();
+        Ok(Self {
+            crate_name,
+            package_name,
+            dir_name,
+            dependencies,
+        })
+    }
+}
+
+/// The package and every package that depends on it, directly or indirectly, by their Cargo
+/// names, starting with the package itself.
+fn dependents(packages: &[LocalPackage], package: &str) -> Result<Vec<String>> {
+    let root = packages
+        .iter()
+        .find(|p| p.crate_name == package)
+        .with_context(|| format!("Unable to find package '{}' in the project", package))?;
+    let mut found = vec![root];
+    let mut i = 0;
+    while i < found.len() {
+        let dir_name = &found[i].dir_name;
+        let next = packages
+            .iter()
+            .filter(|p| p.dependencies.contains(dir_name))
+            .collect::<Vec<_>>();
+        for p in next {
+            if !found.contains(&p) {
+                found.push(p);
+            }
+        }
+        i += 1;
+    }
+    Ok(found.into_iter().map(|p| p.crate_name.clone()).collect())
+}
+
+/// Build a Bottlerocket variant image.
+#[derive(Debug, Parser)]
+pub(crate) struct BuildVariant {
     /// The variant to build.
     variant: String,
 
-    /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
-    /// Defaults to https://cache.bottlerocket.aws
-    lookaside_cache: Option<String>,
+    #[clap(flatten)]
+    options: VariantBuildOptions,
+}
+
+impl BuildVariant {
+    pub(super) async fn run(&self) -> Result<()> {
+        self.options.run(&[self.variant.as_str()]).await
+    }
+}
+
+/// Build several Bottlerocket variant images. The kits and packages that any of the variants need
+/// are built together first, so that what the variants share is built once and as much as possible
+/// builds in parallel, and then the image of each variant is built in turn. Packages that are
+/// sensitive to the variant are still built for each variant that needs them.
+#[derive(Debug, Parser)]
+pub(crate) struct BuildVariants {
+    /// The variants to build.
+    #[clap(required = true)]
+    variants: Vec<String>,
+
+    #[clap(flatten)]
+    options: VariantBuildOptions,
+}
+
+impl BuildVariants {
+    pub(super) async fn run(&self) -> Result<()> {
+        let mut variants = Vec::new();
+        for variant in &self.variants {
+            if !variants.contains(&variant.as_str()) {
+                variants.push(variant.as_str());
+            }
+        }
+        self.options.run(&variants).await
+    }
+}
 
+/// The options that every build command has, which control how buildsys builds.
+#[derive(Debug, Default, Args)]
+pub(crate) struct BuildOptions {
     /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
     /// from the upstream URL found in a package's `Cargo.toml`.
     #[clap(long = "upstream-source-fallback")]
-    upstream_source_fallback: bool,
+    pub(crate) upstream_source_fallback: bool,
+
+    /// Use files from `source-overrides-dir` in `buildsys.toml` even if they don't match the
+    /// hashes in the package manifests.
+    #[clap(long = "allow-unverified-overrides")]
+    pub(crate) allow_unverified_overrides: bool,
+
+    /// The number of parallel jobs each package build may use. Defaults to the number of CPUs.
+    #[clap(long = "jobs")]
+    pub(crate) jobs: Option<NonZeroU16>,
+
+    /// When a package build fails, keep its environment as an image and print a command to start
+    /// a shell in it.
+    #[clap(long = "keep-on-failure")]
+    pub(crate) keep_on_failure: bool,
+
+    /// Build reproducibly, so that building the same commit again gives byte-identical outputs.
+    /// Timestamps are clamped to the time of the commit, or to `source-date-epoch` in
+    /// `buildsys.toml`.
+    #[clap(long = "reproducible")]
+    pub(crate) reproducible: bool,
+
+    /// Record why cargo ran each build, such as which file or environment variable changed, for
+    /// `twoliter debug explain-rebuild` to show.
+    #[clap(long = "explain-rebuilds")]
+    pub(crate) explain_rebuilds: bool,
+
+    /// A build profile from the `profile` table in Twoliter.toml, such as `dev` or `release`.
+    /// Flags given on the command line override the profile's settings.
+    #[clap(long = "profile")]
+    pub(crate) profile: Option<String>,
+
+    /// Also store what is built in `<dir>`, in a layout that stays the same between releases:
+    /// kits in `<dir>/kits/<kit>/<arch>`, and each variant's images, RPMs and metadata in
+    /// `<dir>/<variant>/<arch>`. A package build stores its RPMs there if it's given `--variant`.
+    /// Defaults to `artifacts-dir` in Twoliter.toml.
+    #[clap(long = "artifacts-dir")]
+    pub(crate) artifacts_dir: Option<PathBuf>,
+}
+
+impl BuildOptions {
+    /// The environment variables that pass the options to buildsys, along with the settings of
+    /// the build profile that the flags don't override.
+    fn envs(&self, project: &project::Project<Locked>) -> Result<Vec<(&'static str, String)>> {
+        let mut envs = Vec::new();
+        let flags = [
+            (
+                "BUILDSYS_UPSTREAM_SOURCE_FALLBACK",
+                self.upstream_source_fallback,
+            ),
+            (
+                "BUILDSYS_ALLOW_UNVERIFIED_OVERRIDES",
+                self.allow_unverified_overrides,
+            ),
+            ("BUILDSYS_KEEP_ON_FAILURE", self.keep_on_failure),
+            ("BUILDSYS_REPRODUCIBLE", self.reproducible),
+            ("BUILDSYS_EXPLAIN_REBUILDS", self.explain_rebuilds),
+        ];
+        envs.extend(
+            flags
+                .into_iter()
+                .filter(|(_, set)| *set)
+                .map(|(key, _)| (key, "true".to_string())),
+        );
+        if let Some(jobs) = self.jobs {
+            envs.push(("BUILDSYS_RPMBUILD_JOBS", jobs.to_string()))
+        }
+        add_profile_envs(project, self.profile.as_deref(), &mut envs)?;
+        Ok(envs)
+    }
+
+    /// The directory to also store what is built in, if there is one.
+    fn artifacts_dir(&self, project: &project::Project<Locked>) -> Option<PathBuf> {
+        self.artifacts_dir.clone().or(project.artifacts_dir())
+    }
+
+    /// The arguments that pass the same options to Twoliter on a remote host.
+    fn args(&self) -> Vec<String> {
+        let flags = [
+            ("--upstream-source-fallback", self.upstream_source_fallback),
+            (
+                "--allow-unverified-overrides",
+                self.allow_unverified_overrides,
+            ),
+            ("--keep-on-failure", self.keep_on_failure),
+            ("--reproducible", self.reproducible),
+            ("--explain-rebuilds", self.explain_rebuilds),
+        ];
+        let mut args = flags
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(flag, _)| flag.to_string())
+            .collect::<Vec<_>>();
+        let options = [
+            ("--jobs", self.jobs.map(|jobs| jobs.to_string())),
+            ("--profile", self.profile.clone()),
+        ];
+        for (option, value) in options {
+            if let Some(value) = value {
+                args.extend([option.to_string(), value]);
+            }
+        }
+        args
+    }
+}
+
+/// The options of `build variant` and `build variants`, which each variant is built with.
+#[derive(Debug, Args)]
+struct VariantBuildOptions {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture to build for. Several can be given separated by commas, such as
+    /// `x86_64,aarch64`, or `all` for every supported architecture. They are built one after the
+    /// other, and a manifest of the builds of each variant is written to
+    /// `build/images/<variant>-manifest.json`.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
+    /// This can be a `file://` URL or the absolute path of a local directory. Defaults to
+    /// https://cache.bottlerocket.aws
+    #[clap(long = "lookaside-cache")]
+    lookaside_cache: Option<String>,
+
+    #[clap(flatten)]
+    build: BuildOptions,
+
+    /// A git revision, such as the tag of the last release. When given, the new changelog entries
+    /// of every package that changed since then are written to `CHANGELOG-<variant>.md` next to
+    /// each variant's images.
+    #[clap(long = "changelog-baseline")]
+    changelog_baseline: Option<String>,
 
     /// Path to the Infra.toml file
     #[clap(long)]
     infra_toml: Option<PathBuf>,
+
+    /// Build on a host from the `remote` table in Twoliter.toml, and copy the images back to
+    /// `build/images`. The host must have Twoliter installed.
+    #[clap(long = "remote", conflicts_with_all = ["infra_toml", "artifacts_dir"])]
+    remote: Option<String>,
+
+    /// Enable an image feature, such as `fips`, for these builds even if the variants' manifests
+    /// don't. May be given more than once. The builds' metadata records the override.
+    #[clap(long = "enable-feature", value_parser = parse_image_feature)]
+    enable_features: Vec<String>,
+
+    /// Disable an image feature for these builds even if the variants' manifests enable it. May be
+    /// given more than once. The builds' metadata records the override.
+    #[clap(long = "disable-feature", value_parser = parse_image_feature)]
+    disable_features: Vec<String>,
 }
 
-impl BuildVariant {
-    pub(super) async fn run(&self) -> Result<()> {
+impl VariantBuildOptions {
+    /// Builds `variants`. With more than one, the kits and packages that any of them need are
+    /// built together first.
+    async fn run(&self, variants: &[&str]) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
         let project = project.load_lock::<Locked>().await?;
-        let toolsdir = project.project_dir().join("build/tools");
-        install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
+        check_features(&self.enable_features, &self.disable_features)?;
+        if let Some(remote) = &self.remote {
+            return self.run_remote(&project, remote, variants).await;
+        }
         // A temporary directory in the `build` directory
         let build_temp_dir = TempDir::new_in(project.project_dir())
             .context("Unable to create a tempdir for Twoliter's build")?;
         let packages_dir = build_temp_dir.path().join("sdk_rpms");
         fs::create_dir_all(&packages_dir).await?;
 
-        let mut optional_envs = Vec::new();
+        let dependencies = if variants.len() > 1 {
+            variant_dependencies(&project.project_dir(), variants).await?
+        } else {
+            Vec::new()
+        };
+        let (cargo_make, artifacts_dir) = self.cargo_make(&project).await?;
+        let cargo_makes = variants
+            .iter()
+            .map(|variant| cargo_make.clone().env("BUILDSYS_VARIANT", *variant))
+            .collect::<Vec<_>>();
+
+        // Each architecture is a separate build, but sources, crates and Go modules are fetched to
+        // the same place, so the builds after the first one find them already there.
+        let arches = parse_arches(&self.arch)?;
+        for arch in &arches {
+            // The kits and packages are built with the settings of the first variant, so that its
+            // build finds them all up to date.
+            if !dependencies.is_empty() {
+                info!(
+                    "Building what the variants need for {}: {}",
+                    arch,
+                    dependencies.join(", ")
+                );
+                build_dependencies(&project, &cargo_makes[0], arch, &dependencies).await?;
+            }
+            for (variant, cargo_make) in variants.iter().zip(&cargo_makes) {
+                if arches.len() > 1 || variants.len() > 1 {
+                    info!("Building {} for {}", variant, arch);
+                }
+                build_arch(&project, cargo_make, variant, arch, artifacts_dir.as_ref()).await?;
+            }
+        }
+
+        if arches.len() > 1 {
+            for variant in variants {
+                write_manifest(&project, variant, &arches).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets up the `CargoMake` that builds variants as the flags ask, which only lacks the
+    /// variant, along with the directory to also store their artifacts in, if there is one.
+    async fn cargo_make(
+        &self,
+        project: &project::Project<Locked>,
+    ) -> Result<(CargoMake, Option<ArtifactsDir>)> {
+        let mut optional_envs = self.build.envs(project)?;
 
         if let Some(lookaside_cache) = &self.lookaside_cache {
             optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache.to_string()))
         }
 
+        if let Some(baseline) = &self.changelog_baseline {
+            optional_envs.push(("BUILDSYS_CHANGELOG_BASELINE", baseline.to_string()))
+        }
+
+        if !self.enable_features.is_empty() {
+            optional_envs.push(("BUILDSYS_ENABLE_FEATURES", self.enable_features.join(",")))
+        }
+
+        if !self.disable_features.is_empty() {
+            optional_envs.push(("BUILDSYS_DISABLE_FEATURES", self.disable_features.join(",")))
+        }
+
         if let Some(infra_toml) = &self.infra_toml {
             optional_envs.push((
                 "PUBLISH_INFRA_CONFIG_PATH",
@@ -135,20 +627,792 @@ impl BuildVariant {
             ))
         }
 
+        // The artifacts are copied once each build is done, so the directory doesn't change what
+        // buildsys builds.
+        let artifacts_dir = match self.build.artifacts_dir(project) {
+            Some(dir) => {
+                fs::create_dir_all(&dir).await?;
+                Some(ArtifactsDir::new(fs::canonicalize(&dir).await?))
+            }
+            None => None,
+        };
+
+        let cargo_make = project_cargo_make(project)
+            .await?
+            .envs(optional_envs.into_iter());
+        Ok((cargo_make, artifacts_dir))
+    }
+
+    /// Builds `variants` on the remote host named `remote`, and copies the images it built back.
+    async fn run_remote(
+        &self,
+        project: &project::Project<Locked>,
+        remote: &str,
+        variants: &[&str],
+    ) -> Result<()> {
+        let project_dir = project.project_dir();
+        let arches = parse_arches(&self.arch)?;
+        let host = RemoteHost::connect(project.remote(remote)?, &project_dir).await?;
+        let result = async {
+            host.push(&project_dir).await?;
+            host.twoliter(&self.remote_args(variants)).await?;
+            for variant in variants {
+                for arch in &arches {
+                    host.pull(&project_dir, &format!("build/images/{arch}-{variant}"))
+                        .await?;
+                }
+                if arches.len() > 1 {
+                    let manifest = format!("build/images/{variant}-manifest.json");
+                    host.pull(&project_dir, &manifest).await?;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        host.release().await;
+        result?;
+
+        let images_dir = project_dir.join("build/images");
+        for variant in variants {
+            for arch in &arches {
+                output::artifacts_in(latest_images_dir(&images_dir, arch, variant)).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// The arguments that build the same variants in the same way on a remote host.
+    fn remote_args(&self, variants: &[&str]) -> Vec<String> {
+        let command = if variants.len() > 1 {
+            "variants"
+        } else {
+            "variant"
+        };
+        let mut args = vec!["build".to_string(), command.to_string()];
+        args.extend(variants.iter().map(|variant| variant.to_string()));
+        args.extend(["--arch".to_string(), self.arch.clone()]);
+        args.extend(self.build.args());
+        let options = [
+            ("--lookaside-cache", self.lookaside_cache.clone()),
+            ("--changelog-baseline", self.changelog_baseline.clone()),
+        ];
+        for (option, value) in options {
+            if let Some(value) = value {
+                args.extend([option.to_string(), value]);
+            }
+        }
+        for feature in &self.enable_features {
+            args.extend(["--enable-feature".to_string(), feature.clone()]);
+        }
+        for feature in &self.disable_features {
+            args.extend(["--disable-feature".to_string(), feature.clone()]);
+        }
+        args
+    }
+}
+
+/// Writes the manifest of the builds of `variant` for `arches`.
+async fn write_manifest(
+    project: &project::Project<Locked>,
+    variant: &str,
+    arches: &[String],
+) -> Result<()> {
+    let manifest = VariantManifest::new(
+        &project.project_dir().join("build/images"),
+        variant,
+        project.release_version(),
+        arches,
+    )
+    .await?;
+    let path = manifest.write().await?;
+    info!("Wrote the manifest of the builds to '{}'", path.display());
+    output::artifact(path).await;
+    Ok(())
+}
+
+#[instrument(name = "build", skip_all, fields(variant = %variant, arch = %arch))]
+async fn build_arch(
+    project: &project::Project<Locked>,
+    cargo_make: &CargoMake,
+    variant: &str,
+    arch: &str,
+    artifacts_dir: Option<&ArtifactsDir>,
+) -> Result<()> {
+    let metrics = Metrics::start(
+        project.metrics_settings(),
+        project.project_dir(),
+        "build variant",
+    );
+    let started = progress::now();
+    let progress = Progress::start(project.project_dir());
+    let result = cargo_make
+        .clone()
+        .env("BUILDSYS_ARCH", arch)
+        .quiet(progress.captures_output())
+        .exec("build")
+        .await;
+    progress.finish().await;
+    metrics.finish(&result).await;
+    result?;
+    report_critical_path(&project.project_dir(), arch, started).await;
+
+    if let Some(artifacts_dir) = artifacts_dir {
+        store_variant(&project.project_dir(), artifacts_dir, variant, arch).await?;
+    }
+
+    output::artifacts_in(latest_images_dir(
+        &project.project_dir().join("build/images"),
+        arch,
+        variant,
+    ))
+    .await;
+    Ok(())
+}
+
+/// Builds the kits and packages in `dependencies` for `arch` in one run of Cargo, which builds each
+/// of them and what they depend on once.
+#[instrument(name = "build", skip_all, fields(arch = %arch))]
+async fn build_dependencies(
+    project: &project::Project<Locked>,
+    cargo_make: &CargoMake,
+    arch: &str,
+    dependencies: &[String],
+) -> Result<()> {
+    let metrics = Metrics::start(
+        project.metrics_settings(),
+        project.project_dir(),
+        "build variants",
+    );
+    let progress = Progress::start(project.project_dir());
+    let result = cargo_make
+        .clone()
+        .env("BUILDSYS_ARCH", arch)
+        .env("PACKAGE", dependencies.join(" "))
+        .quiet(progress.captures_output())
+        .exec("build-package")
+        .await;
+    progress.finish().await;
+    metrics.finish(&result).await;
+    result
+}
+
+/// The kits and packages in the project that any of `variants` depend on, by their Cargo names.
+async fn variant_dependencies(project_dir: &Path, variants: &[&str]) -> Result<Vec<String>> {
+    let mut dependencies = Vec::new();
+    for variant in variants {
+        let manifest = project_dir
+            .join("variants")
+            .join(variant)
+            .join("Cargo.toml");
+        let cargo = fs::read_to_string(&manifest)
+            .await
+            .with_context(|| format!("Unable to find variant '{}' in the project", variant))?;
+        let found = local_dependencies(&cargo)
+            .with_context(|| format!("Unable to parse '{}'", manifest.display()))?;
+        for dependency in found {
+            if !dependencies.contains(&dependency) {
+                dependencies.push(dependency);
+            }
+        }
+    }
+    dependencies.sort();
+    Ok(dependencies)
+}
+
+/// The Cargo names of the dependencies in a manifest that are in the project, rather than crates.
+fn local_dependencies(cargo: &str) -> Result<Vec<String>> {
+    let cargo: toml::Table = toml::from_str(cargo)?;
+    Ok(["build-dependencies", "dependencies"]
+        .iter()
+        .filter_map(|section| cargo.get(*section).and_then(|deps| deps.as_table()))
+        .flat_map(|deps| deps.iter())
+        .filter(|(_, dep)| dep.get("path").is_some())
+        .map(|(name, dep)| {
+            dep.get("package")
+                .and_then(|p| p.as_str())
+                .unwrap_or(name.as_str())
+                .to_string()
+        })
+        .collect())
+}
+
+/// Fails if an image feature is both enabled and disabled.
+fn check_features(enable: &[String], disable: &[String]) -> Result<()> {
+    if let Some(feature) = enable.iter().find(|feature| disable.contains(feature)) {
+        anyhow::bail!("The image feature '{feature}' can't be both enabled and disabled");
+    }
+    Ok(())
+}
+
+/// Installs the tools and sets up a `CargoMake` with everything the tasks for `variant` need from
+/// the project, so that only the architecture and the task are left to choose.
+pub(super) async fn variant_cargo_make(
+    project: &project::Project<Locked>,
+    variant: &str,
+) -> Result<CargoMake> {
+    Ok(project_cargo_make(project)
+        .await?
+        .env("BUILDSYS_VARIANT", variant))
+}
+
+/// Installs the tools and sets up a `CargoMake` with everything that builds need from the project,
+/// so that it can be shared by the builds of several variants, or used to build a kit or package.
+async fn project_cargo_make(project: &project::Project<Locked>) -> Result<CargoMake> {
+    let toolsdir = project.project_dir().join("build/tools");
+    install_tools(&toolsdir).await?;
+    let buildsys_config = BuildsysConfig::load(project.project_dir()).await?;
+
+    let mut optional_envs = Vec::new();
+    optional_envs.extend(project.fetch_settings().envs());
+    optional_envs.extend(project.license_settings().envs());
+    optional_envs.extend(project.device_settings().envs());
+    optional_envs.extend(project.required_versions().envs());
+    optional_envs.extend(project.hook_envs());
+    if let Some(secrets) = project.build_secrets() {
+        optional_envs.push(("BUILDSYS_BUILD_SECRETS", secrets))
+    }
+
+    Ok(
         CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
+            .envs(buildsys_config.envs().into_iter())
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
-            .env("BUILDSYS_ARCH", &self.arch)
-            .env("BUILDSYS_VARIANT", &self.variant)
             .env("BUILDSYS_VERSION_IMAGE", project.release_version())
             .env("GO_MODULES", project.find_go_modules().await?.join(" "))
-            .env(
-                "BUILDSYS_UPSTREAM_SOURCE_FALLBACK",
-                self.upstream_source_fallback.to_string(),
-            )
             .envs(optional_envs.into_iter())
-            .makefile(makefile_path)
-            .project_dir(project.project_dir())
-            .exec("build")
-            .await
+            .makefile(toolsdir.join("Makefile.toml"))
+            .project_dir(project.project_dir()),
+    )
+}
+
+/// Logs the critical path of the builds for `arch` that started at or after `since`, in seconds
+/// since the epoch. Failing to find it never fails the build.
+async fn report_critical_path(project_dir: &Path, arch: &str, since: u64) {
+    let records = progress::read_records(&project_dir.join(BUILD_PROGRESS_DIRECTORY), since).await;
+    let dependencies = match graph::build_dependencies(project_dir).await {
+        Ok(dependencies) => dependencies,
+        Err(e) => {
+            warn!("Unable to find the critical path of the build: {e:#}");
+            return;
+        }
+    };
+    if let Some(path) = CriticalPath::new(&records, &dependencies, arch) {
+        info!("{}", path.summary());
+        output::critical_path(path);
+    }
+}
+
+/// Adds the settings of the build profile named `profile`, if any, to `envs`. Settings that `envs`
+/// already has, from the command's flags, are left out so that the flags override the profile.
+fn add_profile_envs(
+    project: &project::Project<Locked>,
+    profile: Option<&str>,
+    envs: &mut Vec<(&'static str, String)>,
+) -> Result<()> {
+    let Some(profile) = profile else {
+        return Ok(());
+    };
+    let profile_envs = project
+        .profile(profile)?
+        .envs()
+        .into_iter()
+        .filter(|(key, _)| !envs.iter().any(|(set, _)| set == key))
+        .collect::<Vec<_>>();
+    envs.extend(profile_envs);
+    Ok(())
+}
+
+/// Checks that `--enable-feature` and `--disable-feature` name an image feature that buildsys knows.
+fn parse_image_feature(feature: &str) -> Result<String> {
+    feature
+        .parse::<ImageFeature>()
+        .with_context(|| format!("'{feature}' is not an image feature"))?;
+    Ok(feature.to_string())
+}
+
+/// The architectures that builds for a variant support.
+const SUPPORTED_ARCHES: [&str; 2] = ["x86_64", "aarch64"];
+
+/// Parses `--arch`, which is one architecture, several separated by commas, or `all`.
+pub(super) fn parse_arches(arch: &str) -> Result<Vec<String>> {
+    if arch == "all" {
+        return Ok(SUPPORTED_ARCHES.iter().map(|a| a.to_string()).collect());
+    }
+    let mut arches = Vec::new();
+    for arch in arch.split(',').map(str::trim) {
+        ensure!(
+            SUPPORTED_ARCHES.contains(&arch),
+            "Unrecognized architecture '{}'; please use {} or 'all'",
+            arch,
+            SUPPORTED_ARCHES
+                .iter()
+                .map(|a| format!("'{a}'"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if !arches.iter().any(|a| a == arch) {
+            arches.push(arch.to_string());
+        }
+    }
+    Ok(arches)
+}
+
+/// The directory with the images from the latest build of a variant for an architecture.
+pub(super) fn latest_images_dir(images_dir: &Path, arch: &str, variant: &str) -> PathBuf {
+    variant_images_dir(images_dir, arch, variant).join("latest")
+}
+
+/// Describes the builds of a variant for several architectures, so that they can be published
+/// together.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct VariantManifest {
+    #[serde(skip)]
+    path: PathBuf,
+    variant: String,
+    version: String,
+    architectures: BTreeMap<String, ArchBuild>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ArchBuild {
+    /// The directory the build's images are in.
+    images_dir: PathBuf,
+    /// The contents of the build's `build-metadata.json`, if it wrote one.
+    build_metadata: Option<serde_json::Value>,
+    /// The size in bytes of each file the build produced, by name.
+    files: BTreeMap<String, u64>,
+}
+
+impl VariantManifest {
+    async fn new(
+        images_dir: &Path,
+        variant: &str,
+        version: &str,
+        arches: &[String],
+    ) -> Result<Self> {
+        let mut architectures = BTreeMap::new();
+        for arch in arches {
+            let dir = fs::canonicalize(latest_images_dir(images_dir, arch, variant)).await?;
+            let mut files = BTreeMap::new();
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("Unable to list '{}'", dir.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_file() {
+                    files.insert(
+                        entry.file_name().to_string_lossy().to_string(),
+                        metadata.len(),
+                    );
+                }
+            }
+            let build_metadata = match tokio::fs::read(dir.join("build-metadata.json")).await {
+                Ok(data) => Some(serde_json::from_slice(&data).with_context(|| {
+                    format!("Unable to parse the build metadata in '{}'", dir.display())
+                })?),
+                Err(_) => None,
+            };
+            architectures.insert(
+                arch.clone(),
+                ArchBuild {
+                    images_dir: dir,
+                    build_metadata,
+                    files,
+                },
+            );
+        }
+        Ok(Self {
+            path: images_dir.join(format!("{}-manifest.json", variant)),
+            variant: variant.to_string(),
+            version: version.to_string(),
+            architectures,
+        })
+    }
+
+    async fn write(&self) -> Result<&Path> {
+        let data = serde_json::to_vec_pretty(self).context("Unable to serialize the manifest")?;
+        fs::write(&self.path, data).await?;
+        Ok(&self.path)
+    }
+}
+
+/// Replaces the images, metadata and RPMs of `variant` for `arch` in the artifacts directory with
+/// those of its last build. This is a copy made after the build, so that a build that cargo found
+/// up to date is stored too. The RPMs are those of the project's packages that the variant uses.
+pub(super) async fn store_variant(
+    project_dir: &Path,
+    artifacts_dir: &ArtifactsDir,
+    variant: &str,
+    arch: &str,
+) -> Result<()> {
+    let local_packages = LocalPackage::find_all(project_dir).await?;
+    let manifest = project_dir
+        .join("variants")
+        .join(variant)
+        .join("Cargo.toml");
+    let variant_deps =
+        LocalPackage::parse(variant.to_string(), &fs::read_to_string(&manifest).await?)
+            .with_context(|| format!("Unable to parse '{}'", manifest.display()))?
+            .dependencies;
+    let rpms = package_rpms(
+        &project_dir.join("build/rpms"),
+        &with_dependencies(&local_packages, variant_deps),
+        arch,
+    )
+    .await?;
+
+    let images_dir = project_dir.join("build/images");
+    let output_dir = latest_images_dir(&images_dir, arch, variant);
+    let changelog = variant_changelog_path(&images_dir, arch, variant);
+    let (artifacts_dir, variant, arch) =
+        (artifacts_dir.clone(), variant.to_string(), arch.to_string());
+    tokio::task::spawn_blocking(move || {
+        artifacts_dir
+            .store_variant(&variant, &arch, &output_dir, &[changelog])
+            .and_then(|()| artifacts_dir.store_rpms(&variant, &arch, &rpms))
+            .with_context(|| {
+                format!(
+                    "Unable to store the artifacts of {} in '{}'",
+                    variant,
+                    artifacts_dir.root().display()
+                )
+            })
+    })
+    .await?
+}
+
+/// Stores the RPMs of `packages` for `arch` in the artifacts directory of `variant`, replacing
+/// other versions of them. RPMs stored before that the project no longer has are removed.
+async fn store_package_rpms(
+    project_dir: &Path,
+    artifacts_dir: &ArtifactsDir,
+    variant: &str,
+    arch: &str,
+    packages: &[&LocalPackage],
+) -> Result<()> {
+    let rpms_dir = project_dir.join("build/rpms");
+    let mut rpms = package_rpms(&rpms_dir, packages, arch).await?;
+    let stored = artifacts_dir.rpms_dir(variant, arch);
+    if stored.is_dir() {
+        let all = LocalPackage::find_all(project_dir).await?;
+        let current = package_rpms(&rpms_dir, &all.iter().collect::<Vec<_>>(), arch).await?;
+        let mut entries = tokio::fs::read_dir(&stored).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let still_built = current.iter().find(|rpm| rpm.file_name() == Some(&name));
+            if let Some(rpm) = still_built {
+                if !rpms.contains(rpm) {
+                    rpms.push(rpm.clone());
+                }
+            }
+        }
+    }
+
+    let (artifacts_dir, variant, arch) =
+        (artifacts_dir.clone(), variant.to_string(), arch.to_string());
+    tokio::task::spawn_blocking(move || {
+        artifacts_dir
+            .store_rpms(&variant, &arch, &rpms)
+            .with_context(|| {
+                format!(
+                    "Unable to store RPMs in '{}'",
+                    artifacts_dir.rpms_dir(&variant, &arch).display()
+                )
+            })
+    })
+    .await?
+}
+
+/// The packages in `dir_names`, by directory, and every package they depend on, directly or
+/// indirectly.
+fn with_dependencies(packages: &[LocalPackage], dir_names: Vec<String>) -> Vec<&LocalPackage> {
+    let mut found: Vec<&LocalPackage> = Vec::new();
+    let mut pending = dir_names;
+    while let Some(dir_name) = pending.pop() {
+        if found.iter().any(|p| p.dir_name == dir_name) {
+            continue;
+        }
+        if let Some(package) = packages.iter().find(|p| p.dir_name == dir_name) {
+            pending.extend(package.dependencies.iter().cloned());
+            found.push(package);
+        }
+    }
+    found
+}
+
+/// The RPMs for `arch`, or for any architecture, that the last builds of `packages` left in their
+/// directories under `rpms_dir`.
+async fn package_rpms(
+    rpms_dir: &Path,
+    packages: &[&LocalPackage],
+    arch: &str,
+) -> Result<Vec<PathBuf>> {
+    let suffixes = [format!(".{arch}.rpm"), ".noarch.rpm".to_string()];
+    let mut rpms = Vec::new();
+    for package in packages {
+        let dir = rpms_dir.join(&package.package_name);
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Unable to list '{}'", dir.display())),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if suffixes
+                .iter()
+                .any(|suffix| name.ends_with(suffix.as_str()))
+            {
+                rpms.push(entry.path());
+            }
+        }
+    }
+    rpms.sort();
+    Ok(rpms)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_remote_args() {
+        let build = BuildVariant::try_parse_from([
+            "variant",
+            "aws-dev",
+            "--arch",
+            "all",
+            "--reproducible",
+            "--profile",
+            "release",
+            "--remote",
+            "big",
+            "--lookaside-cache",
+            "https://cache.example.com",
+            "--enable-feature",
+            "fips",
+        ])
+        .unwrap();
+        assert_eq!(
+            build.options.remote_args(&[build.variant.as_str()]),
+            vec![
+                "build",
+                "variant",
+                "aws-dev",
+                "--arch",
+                "all",
+                "--reproducible",
+                "--profile",
+                "release",
+                "--lookaside-cache",
+                "https://cache.example.com",
+                "--enable-feature",
+                "fips"
+            ]
+        );
+        assert!(BuildVariant::try_parse_from([
+            "variant",
+            "aws-dev",
+            "--disable-feature",
+            "cgroups"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_build_variants() {
+        let build = BuildVariants::try_parse_from([
+            "variants",
+            "aws-dev",
+            "vmware-dev",
+            "--lookaside-cache",
+            "file:///cache",
+            "--reproducible",
+        ])
+        .unwrap();
+        assert_eq!(build.variants, vec!["aws-dev", "vmware-dev"]);
+        assert_eq!(
+            build.options.lookaside_cache.as_deref(),
+            Some("file:///cache")
+        );
+        assert!(build.options.build.reproducible);
+        assert!(BuildVariants::try_parse_from(["variants"]).is_err());
+
+        let build =
+            BuildVariants::try_parse_from(["variants", "aws-dev", "vmware-dev", "--remote", "big"])
+                .unwrap();
+        assert_eq!(build.options.remote.as_deref(), Some("big"));
+        assert!(BuildVariants::try_parse_from([
+            "variants",
+            "aws-dev",
+            "--remote",
+            "big",
+            "--artifacts-dir",
+            "out"
+        ])
+        .is_err());
+        assert_eq!(
+            build.options.remote_args(&["aws-dev", "vmware-dev"]),
+            vec![
+                "build",
+                "variants",
+                "aws-dev",
+                "vmware-dev",
+                "--arch",
+                "x86_64"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_options() {
+        let build = BuildPackage::try_parse_from([
+            "package",
+            "glibc",
+            "--jobs",
+            "4",
+            "--keep-on-failure",
+            "--profile",
+            "dev",
+        ])
+        .unwrap();
+        assert_eq!(build.options.jobs, NonZeroU16::new(4));
+        assert_eq!(
+            build.options.args(),
+            vec!["--keep-on-failure", "--jobs", "4", "--profile", "dev"]
+        );
+
+        let build = BuildKit::try_parse_from([
+            "kit",
+            "core-kit",
+            "--reproducible",
+            "--artifacts-dir",
+            "out",
+        ])
+        .unwrap();
+        assert!(build.options.reproducible);
+        assert_eq!(build.options.artifacts_dir, Some(PathBuf::from("out")));
+    }
+
+    #[test]
+    fn test_local_dependencies() {
+        let dependencies = local_dependencies(
+            r#"
+            [package]
+            name = "aws-dev"
+
+            [dependencies]
+            serde = "1"
+
+            [build-dependencies]
+            core-kit = { path = "../../kits/core-kit" }
+            extra = { path = "../../kits/extra-kit", package = "extra-kit" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(dependencies, vec!["core-kit", "extra-kit"]);
+    }
+
+    #[test]
+    fn test_parse_arches() {
+        assert_eq!(parse_arches("x86_64").unwrap(), vec!["x86_64"]);
+        assert_eq!(parse_arches("all").unwrap(), vec!["x86_64", "aarch64"]);
+        assert_eq!(
+            parse_arches("aarch64, x86_64,aarch64").unwrap(),
+            vec!["aarch64", "x86_64"]
+        );
+        assert!(parse_arches("x86_64,riscv64").is_err());
+        assert!(parse_arches("").is_err());
+    }
+
+    fn package(dir_name: &str, dependencies: &[&str]) -> LocalPackage {
+        LocalPackage {
+            crate_name: dir_name.replace('.', "_"),
+            package_name: dir_name.to_string(),
+            dir_name: dir_name.to_string(),
+            dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_dependents() {
+        let packages = vec![
+            package("pkg-a-1.27", &[]),
+            package("pkg-b", &["pkg-a-1.27"]),
+            package("pkg-c", &["pkg-b"]),
+            package("pkg-d", &[]),
+        ];
+        assert_eq!(
+            dependents(&packages, "pkg-a-1_27").unwrap(),
+            vec!["pkg-a-1_27", "pkg-b", "pkg-c"]
+        );
+        assert!(dependents(&packages, "pkg-z").is_err());
+    }
+
+    #[test]
+    fn test_parse_local_package() {
+        let package = LocalPackage::parse(
+            "pkg-a-1.27".to_string(),
+            r#"
+            [package]
+            name = "pkg-a-1_27"
+
+            [package.metadata.build-package]
+            package-name = "pkg-a-1.27"
+
+            [build-dependencies]
+            core-kit = { path = "../../kits/core-kit" }
+            glibc = { path = "../glibc" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(package.package_name, "pkg-a-1.27");
+        assert_eq!(package.dependencies, vec!["glibc"]);
+    }
+
+    #[test]
+    fn test_with_dependencies() {
+        let packages = vec![
+            package("pkg-a", &[]),
+            package("pkg-b", &["pkg-a"]),
+            package("pkg-c", &["pkg-b", "pkg-a"]),
+            package("pkg-d", &[]),
+        ];
+        let mut found = with_dependencies(&packages, vec!["pkg-c".to_string()])
+            .into_iter()
+            .map(|p| p.dir_name.as_str())
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, vec!["pkg-a", "pkg-b", "pkg-c"]);
+    }
+
+    #[tokio::test]
+    async fn test_package_rpms() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for (package, file) in [
+            ("glibc", "bottlerocket-glibc-2.38-1.x86_64.rpm"),
+            ("glibc", "bottlerocket-glibc-2.38-1.aarch64.rpm"),
+            ("glibc", ".fingerprint"),
+            ("filesystem", "bottlerocket-filesystem-1.0-1.noarch.rpm"),
+            ("unused", "bottlerocket-unused-1.0-1.x86_64.rpm"),
+        ] {
+            std::fs::create_dir_all(dir.path().join(package)).unwrap();
+            std::fs::write(dir.path().join(package).join(file), "").unwrap();
+        }
+        let packages = [
+            package("glibc", &[]),
+            package("filesystem", &[]),
+            package("missing", &[]),
+        ];
+        let packages = packages.iter().collect::<Vec<_>>();
+        assert_eq!(
+            package_rpms(dir.path(), &packages, "x86_64").await.unwrap(),
+            vec![
+                dir.path()
+                    .join("filesystem/bottlerocket-filesystem-1.0-1.noarch.rpm"),
+                dir.path()
+                    .join("glibc/bottlerocket-glibc-2.38-1.x86_64.rpm"),
+            ]
+        );
     }
 }
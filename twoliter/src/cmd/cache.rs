@@ -0,0 +1,267 @@
+use crate::cache::{self, CacheKind, PrunePolicy};
+use crate::cache_server::CacheServer;
+use crate::common::exec;
+use crate::project::{self, BuildsysConfig};
+use anyhow::{bail, ensure, Context, Result};
+use buildsys::layer_cache;
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+/// Group all cache commands
+#[derive(Debug, Parser)]
+pub(crate) enum CacheCommand {
+    Check(CacheCheck),
+    Prune(CachePrune),
+    Serve(CacheServe),
+    Verify(CacheVerify),
+}
+
+impl CacheCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            CacheCommand::Check(command) => command.run().await,
+            CacheCommand::Prune(command) => command.run().await,
+            CacheCommand::Serve(command) => command.run().await,
+            CacheCommand::Verify(command) => command.run().await,
+        }
+    }
+}
+
+/// Remove old or excess entries from the project's local caches: sources fetched from the
+/// lookaside cache, vendored dependency bundles, built RPMs, and leftover build images
+#[derive(Debug, Parser)]
+pub(crate) struct CachePrune {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Only prune these caches. One of lookaside, bundles, rpms, or images. Defaults to all caches
+    #[clap(long = "cache")]
+    caches: Vec<CacheKind>,
+
+    /// Remove entries that have not been modified for this long, e.g. "30d", "12h"
+    #[clap(long = "older-than", value_parser = cache::parse_age)]
+    older_than: Option<Duration>,
+
+    /// Remove the oldest entries until each cache is no larger than this, e.g. "20G". Images are
+    /// counted with any layers they share, so more images may be removed than needed
+    #[clap(long = "max-size", value_parser = cache::parse_size)]
+    max_size: Option<u64>,
+
+    /// Remove every entry in the selected caches
+    #[clap(long = "all", conflicts_with_all = ["older_than", "max_size"])]
+    all: bool,
+
+    /// Report what would be removed without removing anything
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+
+impl CachePrune {
+    pub(super) async fn run(&self) -> Result<()> {
+        ensure!(
+            self.all || self.older_than.is_some() || self.max_size.is_some(),
+            "one of --older-than, --max-size, or --all is required"
+        );
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let caches = if self.caches.is_empty() {
+            CacheKind::all()
+        } else {
+            self.caches.clone()
+        };
+        let policy = PrunePolicy {
+            older_than: self.older_than,
+            max_size: self.max_size,
+            all: self.all,
+        };
+
+        let entries = cache::find_entries(&project.project_dir(), &caches).await?;
+        let selected = policy.select(entries, SystemTime::now());
+        for entry in &selected {
+            if self.dry_run {
+                info!(
+                    "Would remove {} cache entry '{}'",
+                    entry.kind, entry.location
+                );
+            } else if let Err(e) = cache::remove(entry).await {
+                warn!("Unable to remove '{}': {:?}", entry.location, e);
+            }
+        }
+        cache::summarize(&selected, self.dry_run);
+        Ok(())
+    }
+}
+
+/// Serve a remote build cache over HTTP from a directory, for builds to share by setting
+/// BUILDSYS_REMOTE_CACHE to its URL. Built RPMs, external files and vendored bundles are fetched
+/// with GET and uploaded with PUT. The server doesn't speak TLS, so when builds reach it over the
+/// network it must be behind a reverse proxy that terminates TLS
+#[derive(Debug, Parser)]
+pub(crate) struct CacheServe {
+    /// The directory that the cache's entries are stored in
+    #[clap(long = "dir")]
+    dir: PathBuf,
+
+    /// The address to listen on
+    #[clap(long = "listen", default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// A bearer token that every request must carry. Builds send BUILDSYS_REMOTE_CACHE_TOKEN
+    #[clap(long = "token", env = "TWOLITER_CACHE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// The most connections to serve at once. Further clients wait until a connection closes
+    #[clap(long = "max-connections", default_value = "64")]
+    max_connections: usize,
+
+    /// The largest entry that may be stored, e.g. "10G". Larger uploads are refused with 413
+    #[clap(long = "max-entry-size", default_value = "10G", value_parser = cache::parse_size)]
+    max_entry_size: u64,
+}
+
+impl CacheServe {
+    pub(super) async fn run(&self) -> Result<()> {
+        if self.token.is_none() && !self.listen.ip().is_loopback() {
+            warn!(
+                "Serving the cache on {} without a token, so anyone who can reach it can upload",
+                self.listen
+            );
+        }
+        if !self.listen.ip().is_loopback() {
+            warn!(
+                "Serving the cache on {} without TLS; put it behind a reverse proxy that \
+                 terminates TLS so that tokens and entries aren't sent in the clear",
+                self.listen
+            );
+        }
+        let listener = tokio::net::TcpListener::bind(self.listen)
+            .await
+            .context(format!("Unable to listen on {}", self.listen))?;
+        info!(
+            "Serving the cache in '{}' on http://{}",
+            self.dir.display(),
+            self.listen
+        );
+        CacheServer::new(
+            &self.dir,
+            self.token.clone(),
+            self.max_connections,
+            self.max_entry_size,
+        )
+        .serve(listener)
+        .await
+    }
+}
+
+/// Check the files in the local source cache, the external files fetched for packages and the
+/// bundles made from them, against the digests that their builds recorded
+#[derive(Debug, Parser)]
+pub(crate) struct CacheVerify {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Remove damaged files, so that the next build fetches or makes them again
+    #[clap(long = "remove")]
+    remove: bool,
+}
+
+impl CacheVerify {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let (checked, damaged) = cache::verify_sources(&project.project_dir()).await?;
+        for file in &damaged {
+            warn!("'{}' is {}", file.path.display(), file.damage);
+            if self.remove {
+                if let Err(e) = crate::common::fs::remove_file(&file.path).await {
+                    warn!("Unable to remove '{}': {:?}", file.path.display(), e);
+                }
+            }
+        }
+        info!(
+            "Checked {} cached files, {} are damaged",
+            checked,
+            damaged.len()
+        );
+        if !damaged.is_empty() && !self.remove {
+            bail!(
+                "{} cached files are damaged, run again with --remove so that the next build \
+                fetches them again",
+                damaged.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Check the layer cache of the most recent published build of the project for the layers of each
+/// of its builds, which the first builds on a new machine reuse instead of building them again.
+/// The release pipeline exports the cache by building with BUILDSYS_LAYER_CACHE_PUSH, and builds
+/// fetch the layers they reuse from the registry when BUILDSYS_LAYER_CACHE is set to the same
+/// repository. This only checks the cache: nothing is fetched until a build needs it
+#[derive(Debug, Parser)]
+pub(crate) struct CacheCheck {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The repository of the layer cache. Defaults to BUILDSYS_LAYER_CACHE, from the environment
+    /// or from `layer-cache` in buildsys.toml
+    #[clap(long = "from")]
+    from: Option<String>,
+
+    /// The architecture whose layers are checked
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+}
+
+impl CacheCheck {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project_dir = project.project_dir();
+        let config = BuildsysConfig::load(&project_dir).await?;
+        let repo = self
+            .from
+            .clone()
+            .or_else(|| std::env::var("BUILDSYS_LAYER_CACHE").ok())
+            .or_else(|| config.var("BUILDSYS_LAYER_CACHE").map(String::from))
+            .filter(|repo| !repo.is_empty())
+            .context("No layer cache is set, use --from or set layer-cache in buildsys.toml")?;
+
+        let mut found = 0;
+        let manifests = super::lint::find_manifests(&project_dir).await?;
+        for manifest in &manifests {
+            let Some(dir) = manifest.parent() else {
+                continue;
+            };
+            let cache = layer_cache::cache_ref(&repo, &layer_cache::build_name(dir), &self.arch);
+            match exec(
+                Command::new("docker").args(["buildx", "imagetools", "inspect", &cache]),
+                true,
+            )
+            .await
+            {
+                Ok(_) => found += 1,
+                Err(e) => debug!("No layers in '{}': {:?}", cache, e),
+            }
+        }
+        ensure!(
+            found > 0,
+            "The layer cache '{}' has no layers for {} builds of this project",
+            repo,
+            self.arch
+        );
+        info!(
+            "The layer cache '{}' has layers for {} of {} builds, which they fetch as they need \
+             them",
+            repo,
+            found,
+            manifests.len()
+        );
+        Ok(())
+    }
+}
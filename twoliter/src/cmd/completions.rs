@@ -0,0 +1,177 @@
+use super::Args;
+use crate::project;
+use anyhow::Result;
+use clap::{CommandFactory, Parser, ValueEnum};
+use clap_complete::engine::{ArgValueCompleter, CompletionCandidate};
+use clap_complete::env::{Bash, EnvCompleter, Fish, Zsh};
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// The environment variable that asks twoliter to complete a command line instead of running it.
+pub(crate) const COMPLETE_VAR: &str = "COMPLETE";
+
+/// Print a script that completes twoliter commands in your shell. Variant, kit and package names
+/// are completed from the project in the current directory. For example, with bash:
+///
+///     source <(twoliter completions bash)
+#[derive(Debug, Parser)]
+pub(crate) struct Completions {
+    /// The shell to generate completions for.
+    #[clap(value_enum)]
+    shell: Shell,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// The kinds of names that are completed from the project layout. Each is a directory in the
+/// project with a subdirectory, containing a `Cargo.toml`, for each name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Names {
+    Variants,
+    Kits,
+    Packages,
+}
+
+impl Names {
+    /// The kind of names an argument takes, based on its name.
+    fn for_arg(id: &str) -> Option<Self> {
+        match id {
+            "variant" | "variants" => Some(Names::Variants),
+            "kit" => Some(Names::Kits),
+            "package" => Some(Names::Packages),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Names::Variants => "variants",
+            Names::Kits => "kits",
+            Names::Packages => "packages",
+        }
+    }
+}
+
+impl Completions {
+    pub(super) async fn run(&self) -> Result<()> {
+        // The script runs twoliter with `COMPLETE` set to complete each command line, which
+        // `command` describes.
+        let completer: &dyn EnvCompleter = match self.shell {
+            Shell::Bash => &Bash,
+            Shell::Zsh => &Zsh,
+            Shell::Fish => &Fish,
+        };
+        completer.write_registration(
+            COMPLETE_VAR,
+            "twoliter",
+            "twoliter",
+            "twoliter",
+            &mut std::io::stdout(),
+        )?;
+        Ok(())
+    }
+}
+
+/// The twoliter command, with the names of the project's variants, kits and packages completed
+/// for the arguments that take them.
+pub(crate) fn command() -> clap::Command {
+    with_name_completers(Args::command())
+}
+
+fn with_name_completers(mut cmd: clap::Command) -> clap::Command {
+    let args = cmd
+        .get_arguments()
+        .filter_map(|arg| Names::for_arg(arg.get_id().as_str()).map(|n| (arg.get_id().clone(), n)))
+        .collect::<Vec<_>>();
+    for (id, names) in args {
+        cmd = cmd.mut_arg(id, |arg| {
+            arg.add(ArgValueCompleter::new(move |current: &OsStr| {
+                complete_names(names, current)
+            }))
+        });
+    }
+    let subcommands = cmd
+        .get_subcommands()
+        .map(|sub| sub.get_name().to_string())
+        .collect::<Vec<_>>();
+    for name in subcommands {
+        cmd = cmd.mut_subcommand(name, with_name_completers);
+    }
+    cmd
+}
+
+/// The names of the given kind in the project that contains the current directory that start with
+/// `current`. Completion should never print errors into the user's command line, so anything that
+/// goes wrong just means there is nothing to complete.
+fn complete_names(names: Names, current: &OsStr) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    let Ok(project_file) = project::find_project_file(".") else {
+        return Vec::new();
+    };
+    let Some(project_dir) = project_file.parent() else {
+        return Vec::new();
+    };
+    project_names(project_dir, names)
+        .into_iter()
+        .filter(|name| name.starts_with(current.as_ref()))
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// Lists the names of the given kind in the project in `project_dir`.
+fn project_names(project_dir: &Path, names: Names) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(project_dir.join(names.as_str())) else {
+        return Vec::new();
+    };
+    let mut found = entries
+        .flatten()
+        .filter(|entry| entry.path().join("Cargo.toml").is_file())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    found.sort();
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap_complete::engine::complete;
+    use std::ffi::OsString;
+
+    fn candidates(line: &str) -> Vec<String> {
+        let args = line.split(' ').map(OsString::from).collect::<Vec<_>>();
+        let index = args.len() - 1;
+        complete(&mut command(), args, index, None)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.get_value().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_complete_shells() {
+        assert_eq!(candidates("twoliter completions "), ["bash", "zsh", "fish"]);
+    }
+
+    #[test]
+    fn test_project_names() {
+        let project = tempfile::TempDir::new().unwrap();
+        for package in ["glibc", "kernel-6.1"] {
+            let dir = project.path().join("packages").join(package);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("Cargo.toml"), "").unwrap();
+        }
+        std::fs::create_dir_all(project.path().join("packages").join("not-a-package")).unwrap();
+
+        assert_eq!(
+            project_names(project.path(), Names::Packages),
+            ["glibc", "kernel-6.1"]
+        );
+        assert!(project_names(project.path(), Names::Variants).is_empty());
+    }
+}
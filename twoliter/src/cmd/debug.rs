@@ -1,8 +1,14 @@
+use crate::docker::SdkRun;
+use crate::progress::{self, format_duration};
+use crate::project::{self, SDKLocked};
 use crate::tools::install_tools;
-use anyhow::Result;
+use anyhow::{ensure, Result};
+use buildsys::rebuild::Record;
+use buildsys_config::REBUILD_REASONS_DIRECTORY;
 use clap::Parser;
 use std::env;
-use std::path::PathBuf;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Parser)]
@@ -14,12 +20,16 @@ pub(crate) struct Debug {
 #[derive(Debug, Clone, Parser)]
 pub(crate) enum DebugAction {
     CheckTools(CheckToolArgs),
+    Sdk(DebugSdk),
+    ExplainRebuild(DebugExplainRebuild),
 }
 
 impl DebugAction {
     pub(crate) async fn run(&self) -> Result<()> {
         match self {
             DebugAction::CheckTools(c) => c.run().await,
+            DebugAction::Sdk(c) => c.run().await,
+            DebugAction::ExplainRebuild(c) => c.run().await,
         }
     }
 }
@@ -53,3 +63,194 @@ impl CheckToolArgs {
         Ok(())
     }
 }
+
+/// Prepares the builder's home directory for a package the way the `rpmsetup` stage of the build
+/// does: the spec and its sources go in place, and its build dependencies are installed from the
+/// packages and kits that have already been built. Then it starts a shell as the builder.
+const PACKAGE_SETUP: &str = r#"set -e
+cd /home/builder
+cp "/usr/lib/rpm/platform/${ARCH}-bottlerocket/macros" .rpmmacros
+cp .rpmmacros /root/.rpmmacros
+cat "/host/packages/${PACKAGE}/${PACKAGE}.spec" >> "rpmbuild/SPECS/${PACKAGE}.spec"
+find "/host/packages/${PACKAGE}" -maxdepth 1 -not -path '*/\.*' -type f \
+  -exec cp {} rpmbuild/SOURCES/ \;
+find /host/build/rpms -name '*.rpm' -size +0c -exec ln -snft rpmbuild/RPMS {} \+
+createrepo_c -q -x '*-debuginfo-*.rpm' -x '*-debugsource-*.rpm' --no-database rpmbuild/RPMS
+declare -a REPOS
+for repo in /host/build/kits/*/"${ARCH}" /host/build/external-kits/*/*/"${ARCH}" ; do
+  [ -d "${repo}" ] || continue
+  name="$(tr -s '/' '-' <<< "${repo#/host/build/}")"
+  REPOS+=("--repofrompath=${name},${repo}" --enablerepo "${name}")
+done
+dnf -y -q --disablerepo '*' --repofrompath repo,./rpmbuild/RPMS --enablerepo repo \
+  "${REPOS[@]}" --nogpgcheck --forcearch "${ARCH}" \
+  builddep "rpmbuild/SPECS/${PACKAGE}.spec" \
+  || echo "Unable to install all build dependencies, some may need to be built first" >&2
+chown -R builder:builder .rpmmacros rpmbuild
+echo "Build the package with:"
+echo "    rpmbuild -bb --undefine _auto_set_build_flags --define \"_target_cpu ${ARCH}\" rpmbuild/SPECS/${PACKAGE}.spec"
+exec runuser -u builder -- bash
+"#;
+
+/// Starts a shell in the project's SDK, with the project mounted the way buildsys mounts it for
+/// package builds, to reproduce build steps by hand. The project is read-only at `/host`.
+#[derive(Debug, Clone, Parser)]
+pub(crate) struct DebugSdk {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture to set up the SDK for.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// Put this package's spec and sources in place and install its build dependencies before
+    /// starting the shell, as a package build would.
+    #[clap(long = "package")]
+    package: Option<String>,
+}
+
+impl DebugSdk {
+    pub(crate) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<SDKLocked>().await?;
+        let sdk = SdkRun::new(
+            project.sdk_image().project_image_uri().to_string(),
+            project.project_dir(),
+            &self.arch,
+        );
+
+        match &self.package {
+            None => sdk.run(&["bash"]).await,
+            Some(package) => {
+                let spec = project
+                    .project_dir()
+                    .join("packages")
+                    .join(package)
+                    .join(format!("{package}.spec"));
+                ensure!(
+                    spec.is_file(),
+                    "Package '{}' has no spec file at '{}'",
+                    package,
+                    spec.display()
+                );
+                // Installing build dependencies needs root, so the setup script drops to the
+                // builder itself once it is done.
+                sdk.user("root")
+                    .env("PACKAGE", package)
+                    .run(&["bash", "-c", PACKAGE_SETUP])
+                    .await
+            }
+        }
+    }
+}
+
+/// Shows why cargo last ran each build, as recorded by builds with `--explain-rebuilds`: which
+/// watched files changed, which environment variables changed and how, or that there was no
+/// earlier build to compare with.
+#[derive(Debug, Clone, Parser)]
+pub(crate) struct DebugExplainRebuild {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Only show the builds of this package, kit or variant.
+    name: Option<String>,
+
+    /// Only show the builds for this architecture.
+    #[clap(long = "arch")]
+    arch: Option<String>,
+}
+
+impl DebugExplainRebuild {
+    pub(crate) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let dir = project.project_dir().join(REBUILD_REASONS_DIRECTORY);
+        let records: Vec<_> = read_rebuild_records(&dir)
+            .await
+            .into_iter()
+            .filter(|record| self.name.as_ref().map_or(true, |name| *name == record.name))
+            .filter(|record| self.arch.as_ref().map_or(true, |arch| *arch == record.arch))
+            .collect();
+        ensure!(
+            !records.is_empty(),
+            "No reasons for rebuilds are recorded in '{}', build with --explain-rebuilds first",
+            dir.display()
+        );
+
+        let now = progress::now();
+        for record in &records {
+            print!("{}", describe_rebuild(record, now));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the records of why builds ran, oldest first. Records that can't be read are skipped.
+async fn read_rebuild_records(dir: &Path) -> Vec<Record> {
+    let mut records = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return records;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let Ok(data) = tokio::fs::read(&path).await else {
+            continue;
+        };
+        if let Ok(record) = serde_json::from_slice::<Record>(&data) {
+            records.push(record);
+        }
+    }
+    records.sort_by(|a, b| (a.started, &a.name).cmp(&(b.started, &b.name)));
+    records
+}
+
+/// Describes why a build ran, with one line for the build and one for each reason.
+fn describe_rebuild(record: &Record, now: u64) -> String {
+    let mut text = format!(
+        "{} {} ({}), {} ago{}:\n",
+        record.kind,
+        record.name,
+        record.arch,
+        format_duration(now.saturating_sub(record.started)),
+        if record.succeeded { "" } else { ", failed" }
+    );
+    for reason in &record.reasons {
+        let _ = writeln!(text, "  - {reason}");
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buildsys::rebuild::Reason;
+
+    #[test]
+    fn test_describe_rebuild() {
+        let record: Record = serde_json::from_str(
+            r#"{"kind":"package","name":"kernel-6.1","arch":"x86_64","started":1000,
+            "succeeded":false,"files":{},"env":{},"reasons":[
+            {"reason":"file-modified","path":"kernel-6.1.spec"},
+            {"reason":"env-changed","name":"GOPROXY","old":null,"new":"direct"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            record.reasons[1],
+            Reason::EnvChanged {
+                name: "GOPROXY".to_string(),
+                old: None,
+                new: Some("direct".to_string()),
+            }
+        );
+        assert_eq!(
+            describe_rebuild(&record, 1125),
+            "package kernel-6.1 (x86_64), 2m05s ago, failed:\n\
+             \x20 - 'kernel-6.1.spec' was modified\n\
+             \x20 - GOPROXY changed from unset to 'direct'\n"
+        );
+    }
+}
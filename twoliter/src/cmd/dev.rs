@@ -0,0 +1,141 @@
+use crate::cargo_make::CargoMake;
+use crate::project::{self, BuildsysConfig, Locked};
+use crate::tools::install_tools;
+use anyhow::{bail, Result};
+use buildsys_config::PACKAGE_WATCH_DIRECTORY;
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+/// Commands that shorten the edit-build loop while working on a project.
+#[derive(Debug, Parser)]
+pub(crate) enum DevCommand {
+    Watch(Watch),
+}
+
+impl DevCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            DevCommand::Watch(command) => command.run().await,
+        }
+    }
+}
+
+/// Build a package, then build it again whenever one of the files it is built from changes. The
+/// RPMs of each successful build replace the package's RPMs in the build directory.
+#[derive(Debug, Parser)]
+pub(crate) struct Watch {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture to build for.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// The name of the package to build, as given in its Cargo.toml.
+    #[clap(long = "package")]
+    package: String,
+
+    /// How often to check for changes, in milliseconds.
+    #[clap(long = "interval", default_value = "500")]
+    interval: u64,
+}
+
+impl Watch {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        let toolsdir = project.project_dir().join("build/tools");
+        install_tools(&toolsdir).await?;
+        let makefile_path = toolsdir.join("Makefile.toml");
+
+        let buildsys_config = BuildsysConfig::load(project.project_dir()).await?;
+        let mut optional_envs = project.fetch_settings().envs();
+        optional_envs.extend(project.device_settings().envs());
+        optional_envs.extend(project.required_versions().envs());
+        optional_envs.extend(project.hook_envs());
+        if let Some(secrets) = project.build_secrets() {
+            optional_envs.push(("BUILDSYS_BUILD_SECRETS", secrets))
+        }
+
+        let cargo_make = CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
+            .envs(buildsys_config.envs().into_iter())
+            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
+            .env("BUILDSYS_ARCH", &self.arch)
+            .env("BUILDSYS_VERSION_IMAGE", project.release_version())
+            .env("GO_MODULES", project.find_go_modules().await?.join(" "))
+            .env("PACKAGE", &self.package)
+            .envs(optional_envs.into_iter())
+            .makefile(makefile_path)
+            .project_dir(project.project_dir());
+
+        // buildsys records the files that each package is built from as it builds it.
+        let watch_list = project
+            .project_dir()
+            .join(PACKAGE_WATCH_DIRECTORY)
+            .join(&self.package);
+        let interval = Duration::from_millis(self.interval);
+        let mut files = Vec::new();
+        loop {
+            info!("Building package '{}'", self.package);
+            match cargo_make.exec("build-package").await {
+                Ok(()) => info!("Built package '{}'", self.package),
+                Err(e) => error!("Failed to build package '{}': {:?}", self.package, e),
+            }
+
+            // Keep watching the previous files if this build failed before buildsys got as far as
+            // recording them.
+            match read_watch_list(&watch_list).await {
+                Some(recorded) => files = recorded,
+                None if files.is_empty() => bail!(
+                    "No source files were recorded for package '{}' in '{}'",
+                    self.package,
+                    watch_list.display()
+                ),
+                None => warn!(
+                    "Unable to read '{}', watching the same files as before",
+                    watch_list.display()
+                ),
+            }
+
+            info!(
+                "Watching {} files for changes to package '{}'",
+                files.len(),
+                self.package
+            );
+            let before = snapshot(&files).await;
+            loop {
+                tokio::time::sleep(interval).await;
+                if snapshot(&files).await != before {
+                    break;
+                }
+            }
+
+            // Editors often write a file in several steps, so give them a moment to finish.
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+async fn read_watch_list(path: &Path) -> Option<Vec<PathBuf>> {
+    let list = tokio::fs::read_to_string(path).await.ok()?;
+    Some(
+        list.lines()
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+    )
+}
+
+/// The modification time and size of each file, or `None` for files that don't exist, so that
+/// creating or deleting a file counts as a change.
+async fn snapshot(files: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    let mut snapshot = Vec::with_capacity(files.len());
+    for file in files {
+        let metadata = tokio::fs::metadata(file).await.ok();
+        snapshot.push(metadata.and_then(|m| Some((m.modified().ok()?, m.len()))));
+    }
+    snapshot
+}
@@ -0,0 +1,600 @@
+use crate::common::fs;
+use crate::project::{self, KitArchContents, KitContents, KitPackage};
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// The metadata that each variant build writes next to its images.
+pub(super) const BUILD_METADATA: &str = "build-metadata.json";
+
+#[derive(Debug, Parser)]
+pub(crate) enum DiffCommand {
+    Kit(DiffKit),
+    Variant(DiffVariant),
+}
+
+impl DiffCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            DiffCommand::Kit(command) => command.run().await,
+            DiffCommand::Variant(command) => command.run().await,
+        }
+    }
+}
+
+/// Compare two builds of a variant, and print the package versions, settings defaults and image
+/// sizes that changed between them as Markdown. Each build is given as its `build-metadata.json`,
+/// or as the directory that holds it, such as `build/images/x86_64-aws-dev/latest`.
+#[derive(Debug, Parser)]
+pub(crate) struct DiffVariant {
+    /// The metadata of the earlier build.
+    old: PathBuf,
+
+    /// The metadata of the later build.
+    new: PathBuf,
+}
+
+impl DiffVariant {
+    pub(super) async fn run(&self) -> Result<()> {
+        let old = BuildMetadata::load(&self.old).await?;
+        let new = BuildMetadata::load(&self.new).await?;
+        print!("{}", VariantDiff::new(&old, &new)?.report(&old, &new));
+        Ok(())
+    }
+}
+
+/// Compare two versions of a kit, and print the dependencies, image digests and package versions
+/// that changed between them as Markdown, along with the packages that were rebuilt without a new
+/// version. Each kit is given as an image URI, such as
+/// `public.ecr.aws/bottlerocket/bottlerocket-core-kit:v2.0.0`, and is pulled to compare it.
+#[derive(Debug, Parser)]
+pub(crate) struct DiffKit {
+    /// The image URI of the earlier kit.
+    old: String,
+
+    /// The image URI of the later kit.
+    new: String,
+}
+
+impl DiffKit {
+    pub(super) async fn run(&self) -> Result<()> {
+        let old = project::inspect_kit(&self.old).await?;
+        let new = project::inspect_kit(&self.new).await?;
+        print!("{}", KitDiff::new(&old, &new).report(&old, &new));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct BuildMetadata {
+    variant: String,
+    arch: String,
+    pub(super) version_id: String,
+    build_id: String,
+    packages: Vec<Package>,
+    /// The contents of each settings defaults file, by its path in the image.
+    #[serde(default)]
+    settings_defaults: BTreeMap<String, String>,
+    /// The size of each image file in bytes, by file name.
+    #[serde(default)]
+    image_sizes: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Package {
+    name: String,
+    epoch: String,
+    version: String,
+    release: String,
+}
+
+impl BuildMetadata {
+    pub(super) async fn load(path: &Path) -> Result<Self> {
+        let path = if path.is_dir() {
+            path.join(BUILD_METADATA)
+        } else {
+            path.to_path_buf()
+        };
+        let data = fs::read_to_string(&path).await?;
+        serde_json::from_str(&data)
+            .with_context(|| format!("Unable to parse build metadata '{}'", path.display()))
+    }
+
+    fn describe(&self) -> String {
+        format!("{}-{}", self.version_id, self.build_id)
+    }
+
+    /// Each package's version, as `epoch:version-release` with the epoch left out when it is 0.
+    fn package_versions(&self) -> BTreeMap<String, String> {
+        self.packages
+            .iter()
+            .map(|p| {
+                let version = if p.epoch == "0" || p.epoch.is_empty() {
+                    format!("{}-{}", p.version, p.release)
+                } else {
+                    format!("{}:{}-{}", p.epoch, p.version, p.release)
+                };
+                (p.name.clone(), version)
+            })
+            .collect()
+    }
+
+    /// Every settings default as a dotted key and its value. Defaults files are applied in order of
+    /// their names, so later files override earlier ones, as they do when the host boots.
+    fn settings_defaults(&self) -> Result<BTreeMap<String, String>> {
+        let mut merged = Table::new();
+        for (name, content) in &self.settings_defaults {
+            let table: Table = toml::from_str(content)
+                .with_context(|| format!("Unable to parse settings defaults in '{}'", name))?;
+            merge(&mut merged, table);
+        }
+        let mut flat = BTreeMap::new();
+        flatten(&mut flat, "", &Value::Table(merged));
+        Ok(flat)
+    }
+
+    /// The size of each image, with the version and build removed from its name so that images
+    /// from different builds can be matched up.
+    fn image_sizes(&self) -> BTreeMap<String, u64> {
+        let version = format!("-{}", self.describe());
+        self.image_sizes
+            .iter()
+            .map(|(name, size)| (name.replace(&version, ""), *size))
+            .collect()
+    }
+}
+
+fn merge(into: &mut Table, from: Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(table)) => merge(existing, table),
+            (_, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+fn flatten(flat: &mut BTreeMap<String, String>, prefix: &str, value: &Value) {
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten(flat, &key, value);
+            }
+        }
+        value => {
+            flat.insert(prefix.to_string(), value.to_string());
+        }
+    }
+}
+
+/// How something differs between two builds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change<T> {
+    Added(T),
+    Removed(T),
+    Changed(T, T),
+}
+
+fn diff<T: Clone + PartialEq>(
+    old: &BTreeMap<String, T>,
+    new: &BTreeMap<String, T>,
+) -> Vec<(String, Change<T>)> {
+    let mut changes = Vec::new();
+    for (key, old_value) in old {
+        match new.get(key) {
+            None => changes.push((key.clone(), Change::Removed(old_value.clone()))),
+            Some(new_value) if new_value != old_value => changes.push((
+                key.clone(),
+                Change::Changed(old_value.clone(), new_value.clone()),
+            )),
+            Some(_) => {}
+        }
+    }
+    for (key, new_value) in new {
+        if !old.contains_key(key) {
+            changes.push((key.clone(), Change::Added(new_value.clone())));
+        }
+    }
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    changes
+}
+
+#[derive(Debug)]
+pub(super) struct VariantDiff {
+    packages: Vec<(String, Change<String>)>,
+    settings: Vec<(String, Change<String>)>,
+    images: Vec<(String, Change<u64>)>,
+}
+
+impl VariantDiff {
+    pub(super) fn new(old: &BuildMetadata, new: &BuildMetadata) -> Result<Self> {
+        Ok(Self {
+            packages: diff(&old.package_versions(), &new.package_versions()),
+            settings: diff(&old.settings_defaults()?, &new.settings_defaults()?),
+            images: diff(&old.image_sizes(), &new.image_sizes()),
+        })
+    }
+
+    pub(super) fn report(&self, old: &BuildMetadata, new: &BuildMetadata) -> String {
+        let mut s = String::new();
+        let _ = writeln!(
+            s,
+            "## {} ({}): {} to {}",
+            new.variant,
+            new.arch,
+            old.describe(),
+            new.describe()
+        );
+        if old.variant != new.variant || old.arch != new.arch {
+            let _ = writeln!(
+                s,
+                "\nThe earlier build is of {} ({}).",
+                old.variant, old.arch
+            );
+        }
+
+        let _ = writeln!(s, "\n### Packages\n");
+        table(&mut s, "Package", &self.packages, |v| format!("`{v}`"));
+        let _ = writeln!(s, "\n### Settings defaults\n");
+        table(&mut s, "Setting", &self.settings, |v| format!("`{v}`"));
+
+        let _ = writeln!(s, "\n### Image sizes\n");
+        if self.images.is_empty() {
+            let _ = writeln!(s, "No changes.");
+        } else {
+            let _ = writeln!(s, "| Image | Old | New | Change |");
+            let _ = writeln!(s, "| --- | --- | --- | --- |");
+            for (name, change) in &self.images {
+                let (old, new, delta) = match change {
+                    Change::Added(new) => (String::new(), format_size(*new), String::new()),
+                    Change::Removed(old) => (format_size(*old), String::new(), String::new()),
+                    Change::Changed(old, new) => {
+                        (format_size(*old), format_size(*new), size_delta(*old, *new))
+                    }
+                };
+                let _ = writeln!(s, "| {name} | {old} | {new} | {delta} |");
+            }
+        }
+        s
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct KitDiff {
+    dependencies: Vec<(String, Change<String>)>,
+    digests: Vec<(String, Change<String>)>,
+    /// The package versions that changed, by architecture.
+    packages: BTreeMap<String, Vec<(String, Change<String>)>>,
+    /// The packages whose RPMs changed without a change in version, by architecture.
+    rebuilt: BTreeMap<String, Vec<String>>,
+}
+
+impl KitDiff {
+    pub(super) fn new(old: &KitContents, new: &KitContents) -> Self {
+        let digests = |kit: &KitContents| {
+            kit.arches
+                .iter()
+                .map(|(arch, contents)| (arch.clone(), contents.digest.clone()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let arches = old
+            .arches
+            .keys()
+            .chain(new.arches.keys())
+            .collect::<BTreeSet<_>>();
+        let mut packages = BTreeMap::new();
+        let mut rebuilt = BTreeMap::new();
+        for arch in arches {
+            let (old_arch, new_arch) = (old.arches.get(arch), new.arches.get(arch));
+            packages.insert(
+                arch.clone(),
+                diff(&package_versions(old_arch), &package_versions(new_arch)),
+            );
+            rebuilt.insert(arch.clone(), rebuilt_packages(old_arch, new_arch));
+        }
+        Self {
+            dependencies: diff(&old.dependencies, &new.dependencies),
+            digests: diff(&digests(old), &digests(new)),
+            packages,
+            rebuilt,
+        }
+    }
+
+    pub(super) fn report(&self, old: &KitContents, new: &KitContents) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "## {}: {} to {}", new.name, old.version, new.version);
+        if old.name != new.name {
+            let _ = writeln!(s, "\nThe earlier kit is {}.", old.name);
+        }
+
+        let _ = writeln!(s, "\n### Dependencies\n");
+        table(&mut s, "Dependency", &self.dependencies, |v| {
+            format!("`{v}`")
+        });
+        let _ = writeln!(s, "\n### Image digests\n");
+        table(&mut s, "Architecture", &self.digests, |v| format!("`{v}`"));
+
+        for (arch, packages) in &self.packages {
+            let _ = writeln!(s, "\n### Packages ({arch})\n");
+            table(&mut s, "Package", packages, |v| format!("`{v}`"));
+            let rebuilt = &self.rebuilt[arch];
+            if !rebuilt.is_empty() {
+                let names = rebuilt
+                    .iter()
+                    .map(|name| format!("`{name}`"))
+                    .collect::<Vec<_>>();
+                let _ = writeln!(
+                    s,
+                    "\nRebuilt without a version change: {}.",
+                    names.join(", ")
+                );
+            }
+        }
+        s
+    }
+}
+
+fn package_versions(contents: Option<&KitArchContents>) -> BTreeMap<String, String> {
+    contents
+        .map(|contents| {
+            contents
+                .packages
+                .iter()
+                .map(|(name, package)| (name.clone(), package.version.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The packages in both kits with the same version, but a different RPM.
+fn rebuilt_packages(old: Option<&KitArchContents>, new: Option<&KitArchContents>) -> Vec<String> {
+    let (Some(old), Some(new)) = (old, new) else {
+        return Vec::new();
+    };
+    new.packages
+        .iter()
+        .filter(|(name, package): &(&String, &KitPackage)| {
+            old.packages.get(*name).is_some_and(|old_package| {
+                old_package.version == package.version && old_package.sha256 != package.sha256
+            })
+        })
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Writes a Markdown table of changes, with added and removed rows marked as such.
+fn table(
+    s: &mut String,
+    heading: &str,
+    changes: &[(String, Change<String>)],
+    format: impl Fn(&str) -> String,
+) {
+    if changes.is_empty() {
+        let _ = writeln!(s, "No changes.");
+        return;
+    }
+    let _ = writeln!(s, "| {heading} | Old | New |");
+    let _ = writeln!(s, "| --- | --- | --- |");
+    for (name, change) in changes {
+        let (old, new) = match change {
+            Change::Added(new) => ("added".to_string(), format(new)),
+            Change::Removed(old) => (format(old), "removed".to_string()),
+            Change::Changed(old, new) => (format(old), format(new)),
+        };
+        let _ = writeln!(s, "| {name} | {old} | {new} |");
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn size_delta(old: u64, new: u64) -> String {
+    let sign = if new >= old { "+" } else { "-" };
+    let delta = format_size(new.abs_diff(old));
+    if old == 0 {
+        format!("{sign}{delta}")
+    } else {
+        let percent = (new as f64 - old as f64) / old as f64 * 100.0;
+        format!("{sign}{delta} ({percent:+.1}%)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metadata(build_id: &str, packages: &[(&str, &str)], defaults: &str) -> BuildMetadata {
+        BuildMetadata {
+            variant: "aws-dev".to_string(),
+            arch: "x86_64".to_string(),
+            version_id: "1.20.0".to_string(),
+            build_id: build_id.to_string(),
+            packages: packages
+                .iter()
+                .map(|(name, version)| Package {
+                    name: name.to_string(),
+                    epoch: "0".to_string(),
+                    version: version.to_string(),
+                    release: "1".to_string(),
+                })
+                .collect(),
+            settings_defaults: BTreeMap::from([(
+                "/usr/share/storewolf/defaults.d/10-base.toml".to_string(),
+                defaults.to_string(),
+            )]),
+            image_sizes: BTreeMap::from([(
+                format!("bottlerocket-aws-dev-x86_64-1.20.0-{build_id}.img.lz4"),
+                1024 * 1024,
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_variant_diff() {
+        let old = metadata(
+            "aaaa",
+            &[("kernel-6.1", "6.1.1"), ("glibc", "2.38")],
+            "[settings.motd]\ntext = \"hi\"\n[settings.ntp]\ntime-servers = [\"a\"]\n",
+        );
+        let mut new = metadata(
+            "bbbb",
+            &[("kernel-6.1", "6.1.2"), ("libz", "1.3")],
+            "[settings.motd]\ntext = \"hello\"\n[settings.ntp]\ntime-servers = [\"a\"]\n",
+        );
+        new.image_sizes.values_mut().for_each(|size| *size *= 2);
+
+        let diff = VariantDiff::new(&old, &new).unwrap();
+        assert_eq!(
+            diff.packages,
+            vec![
+                ("glibc".to_string(), Change::Removed("2.38-1".to_string())),
+                (
+                    "kernel-6.1".to_string(),
+                    Change::Changed("6.1.1-1".to_string(), "6.1.2-1".to_string())
+                ),
+                ("libz".to_string(), Change::Added("1.3-1".to_string())),
+            ]
+        );
+        assert_eq!(
+            diff.settings,
+            vec![(
+                "settings.motd.text".to_string(),
+                Change::Changed("\"hi\"".to_string(), "\"hello\"".to_string())
+            )]
+        );
+        assert_eq!(
+            diff.images,
+            vec![(
+                "bottlerocket-aws-dev-x86_64.img.lz4".to_string(),
+                Change::Changed(1024 * 1024, 2 * 1024 * 1024)
+            )]
+        );
+        assert!(diff.report(&old, &new).contains(
+            "| bottlerocket-aws-dev-x86_64.img.lz4 | 1.0 MiB | 2.0 MiB | +1.0 MiB (+100.0%) |"
+        ));
+    }
+
+    fn kit(version: &str, dependency: &str, packages: &[(&str, &str, &str)]) -> KitContents {
+        let packages = packages
+            .iter()
+            .map(|(name, version, sha256)| {
+                (
+                    name.to_string(),
+                    KitPackage {
+                        version: version.to_string(),
+                        sha256: sha256.to_string(),
+                    },
+                )
+            })
+            .collect();
+        KitContents {
+            name: "core-kit".to_string(),
+            version: version.to_string(),
+            dependencies: BTreeMap::from([(
+                "bottlerocket/bottlerocket-sdk".to_string(),
+                dependency.to_string(),
+            )]),
+            arches: BTreeMap::from([(
+                "x86_64".to_string(),
+                KitArchContents {
+                    digest: format!("sha256:{version}"),
+                    packages,
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_kit_diff() {
+        let old = kit(
+            "2.0.0",
+            "0.50.0",
+            &[
+                ("bottlerocket-glibc", "2.38-1", "aa"),
+                ("bottlerocket-kernel-6.1", "6.1.1-1", "bb"),
+                ("bottlerocket-openssl", "3.0.0-1", "cc"),
+            ],
+        );
+        let new = kit(
+            "2.1.0",
+            "0.51.0",
+            &[
+                ("bottlerocket-kernel-6.1", "6.1.2-1", "dd"),
+                ("bottlerocket-libz", "1.3-1", "ee"),
+                ("bottlerocket-openssl", "3.0.0-1", "ff"),
+            ],
+        );
+
+        let diff = KitDiff::new(&old, &new);
+        assert_eq!(
+            diff.dependencies,
+            vec![(
+                "bottlerocket/bottlerocket-sdk".to_string(),
+                Change::Changed("0.50.0".to_string(), "0.51.0".to_string())
+            )]
+        );
+        assert_eq!(
+            diff.digests,
+            vec![(
+                "x86_64".to_string(),
+                Change::Changed("sha256:2.0.0".to_string(), "sha256:2.1.0".to_string())
+            )]
+        );
+        assert_eq!(
+            diff.packages["x86_64"],
+            vec![
+                (
+                    "bottlerocket-glibc".to_string(),
+                    Change::Removed("2.38-1".to_string())
+                ),
+                (
+                    "bottlerocket-kernel-6.1".to_string(),
+                    Change::Changed("6.1.1-1".to_string(), "6.1.2-1".to_string())
+                ),
+                (
+                    "bottlerocket-libz".to_string(),
+                    Change::Added("1.3-1".to_string())
+                ),
+            ]
+        );
+        assert_eq!(diff.rebuilt["x86_64"], vec!["bottlerocket-openssl"]);
+        assert!(diff
+            .report(&old, &new)
+            .contains("Rebuilt without a version change: `bottlerocket-openssl`."));
+    }
+
+    #[test]
+    fn test_settings_defaults_override() {
+        let mut build = metadata("aaaa", &[], "[settings.motd]\ntext = \"hi\"\n");
+        build.settings_defaults.insert(
+            "/usr/share/storewolf/defaults.d/50-variant.toml".to_string(),
+            "[settings.motd]\ntext = \"variant\"\n".to_string(),
+        );
+        assert_eq!(
+            build.settings_defaults().unwrap().get("settings.motd.text"),
+            Some(&"\"variant\"".to_string())
+        );
+    }
+}
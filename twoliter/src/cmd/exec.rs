@@ -0,0 +1,78 @@
+use crate::docker::SdkRun;
+use crate::project::{self, SDKLocked};
+use anyhow::Result;
+use clap::Parser;
+use std::path::{Path, PathBuf};
+
+/// Run a command in the project's SDK, with the project mounted the way buildsys mounts it for
+/// package builds. The project is read-only at `/host`, and the command starts in the directory
+/// under `/host` that matches the current directory. For example:
+///
+///     twoliter exec -- rpmlint /host/packages/glibc/glibc.spec
+#[derive(Debug, Parser)]
+#[clap(trailing_var_arg = true)]
+pub(crate) struct Exec {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture to set up the SDK for.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// Run the command as root instead of as the user that builds packages.
+    #[clap(long = "root")]
+    root: bool,
+
+    /// The command to run, and its arguments.
+    #[clap(required = true)]
+    command: Vec<String>,
+}
+
+impl Exec {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<SDKLocked>().await?;
+        let mut sdk = SdkRun::new(
+            project.sdk_image().project_image_uri().to_string(),
+            project.project_dir(),
+            &self.arch,
+        );
+        if let Some(workdir) = host_workdir(&project.project_dir(), &std::env::current_dir()?) {
+            sdk = sdk.workdir(workdir);
+        }
+        if self.root {
+            sdk = sdk.user("root");
+        }
+        sdk.run(&self.command).await
+    }
+}
+
+/// The path of `dir` under the project's mount in the SDK, if it is in the project.
+fn host_workdir(project_dir: &Path, dir: &Path) -> Option<String> {
+    let project_dir = project_dir.canonicalize().ok()?;
+    let dir = dir.canonicalize().ok()?;
+    let relative = dir.strip_prefix(project_dir).ok()?;
+    Some(Path::new("/host").join(relative).display().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_host_workdir() {
+        let project = tempfile::TempDir::new().unwrap();
+        let package = project.path().join("packages/glibc");
+        std::fs::create_dir_all(&package).unwrap();
+        assert_eq!(
+            host_workdir(project.path(), &package).unwrap(),
+            "/host/packages/glibc"
+        );
+        assert_eq!(
+            host_workdir(project.path(), project.path()).unwrap(),
+            "/host"
+        );
+        assert!(host_workdir(&package, project.path()).is_none());
+    }
+}
@@ -1,5 +1,7 @@
+use crate::output;
 use crate::project::{self, Locked};
 use anyhow::Result;
+use buildsys_config::EXTERNAL_KIT_DIRECTORY;
 use clap::Parser;
 use std::path::PathBuf;
 
@@ -12,13 +14,23 @@ pub(crate) struct Fetch {
     /// Architecture of images to fetch
     #[clap(long = "arch", default_value = "x86_64")]
     pub(crate) arch: String,
+
+    /// Fetch the kits and SDK even if the registry reports different digests for them than
+    /// Twoliter.lock, such as when their tags were pushed again. A warning is logged instead.
+    #[clap(long = "allow-drift")]
+    pub(crate) allow_drift: bool,
 }
 
 impl Fetch {
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let project = project.load_lock::<Locked>().await?;
-        project.fetch(self.arch.as_str()).await?;
+        let project = if self.allow_drift {
+            project.load_lock_allowing_drift().await?
+        } else {
+            project.load_lock::<Locked>().await?
+        };
+        project.fetch(self.arch.as_str(), self.allow_drift).await?;
+        output::artifact(project.project_dir().join(EXTERNAL_KIT_DIRECTORY)).await;
         Ok(())
     }
 }
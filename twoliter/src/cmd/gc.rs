@@ -0,0 +1,73 @@
+use crate::cache::{self, checkout_token};
+use crate::gc::{self, GcFilter};
+use crate::project;
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Remove what earlier builds left behind: images tagged by buildsys, stopped build containers,
+/// unused build volumes, and temporary directories in the project
+#[derive(Debug, Parser)]
+pub(crate) struct Gc {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Only remove resources created more than this long ago, e.g. "12h". Builds that are still
+    /// running are left alone by default
+    #[clap(long = "older-than", value_parser = cache::parse_age, default_value = "1d")]
+    older_than: Duration,
+
+    /// Only remove the Docker resources of the checkout with this token, which buildsys appends to
+    /// their names. Defaults to this project's token
+    #[clap(long = "token", conflicts_with = "all_projects")]
+    token: Option<String>,
+
+    /// Remove the Docker resources of every checkout, rather than only this project's
+    #[clap(long = "all-projects")]
+    all_projects: bool,
+
+    /// Report what would be removed without removing anything
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+
+impl Gc {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project_dir = project.project_dir();
+        let token = match (&self.token, self.all_projects) {
+            (_, true) => None,
+            (Some(token), false) => Some(token.clone()),
+            (None, false) => Some(checkout_token(&project_dir)),
+        };
+        let filter = GcFilter {
+            older_than: Some(self.older_than),
+            token,
+        };
+
+        let resources = gc::find(&project_dir).await?;
+        let selected = filter.select(resources, SystemTime::now());
+        let mut removed = 0;
+        for resource in &selected {
+            if self.dry_run {
+                info!("Would remove {} '{}'", resource.kind, resource.name);
+            } else if let Err(e) = gc::remove(resource).await {
+                warn!(
+                    "Unable to remove {} '{}': {:?}",
+                    resource.kind, resource.name, e
+                );
+            } else {
+                removed += 1;
+            }
+        }
+        if self.dry_run {
+            info!("Would remove {} resources", selected.len());
+        } else {
+            info!("Removed {} resources", removed);
+        }
+        Ok(())
+    }
+}
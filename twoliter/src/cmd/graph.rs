@@ -0,0 +1,613 @@
+use crate::common::fs;
+use crate::critical_path::Dependencies;
+use crate::project;
+use anyhow::{ensure, Context, Result};
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Print the dependency graph of the project's packages, kits and variants. Edges come from the
+/// Cargo dependencies that order the build, the `BuildRequires` and `Requires` of each spec, and
+/// the `included-packages` and `feature-packages` of each variant, less those in its
+/// `excluded-packages` for every architecture. A Cargo dependency on a package whose RPMs the spec
+/// never asks for is marked as unused.
+#[derive(Debug, Parser)]
+pub(crate) struct Graph {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Only show what this variant depends on.
+    #[clap(long = "variant")]
+    variant: Option<String>,
+
+    /// How to print the graph.
+    #[clap(long = "format", value_enum, default_value = "dot")]
+    format: GraphFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GraphFormat {
+    /// Graphviz, for example to render with `dot -Tsvg`.
+    Dot,
+    Json,
+}
+
+impl Graph {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let mut graph = DependencyGraph::load(&project.project_dir()).await?;
+        if let Some(variant) = &self.variant {
+            let id = NodeId::new(NodeKind::Variant, variant);
+            ensure!(
+                graph.nodes.contains_key(&id),
+                "Unable to find variant '{}' in the project",
+                variant
+            );
+            graph = graph.reachable_from(&id);
+        }
+        match self.format {
+            GraphFormat::Dot => print!("{}", graph.dot()),
+            GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph.json())?),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum NodeKind {
+    Package,
+    Kit,
+    Variant,
+}
+
+impl NodeKind {
+    fn dir(&self) -> &'static str {
+        match self {
+            NodeKind::Package => "packages",
+            NodeKind::Kit => "kits",
+            NodeKind::Variant => "variants",
+        }
+    }
+
+    /// The kind of build, as buildsys records it.
+    fn build_kind(&self) -> &'static str {
+        match self {
+            NodeKind::Package => "package",
+            NodeKind::Kit => "kit",
+            NodeKind::Variant => "variant",
+        }
+    }
+}
+
+/// A package, kit or variant, named by its directory in the project.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct NodeId {
+    kind: NodeKind,
+    name: String,
+}
+
+impl NodeId {
+    fn new(kind: NodeKind, name: impl Into<String>) -> Self {
+        Self {
+            kind,
+            name: name.into(),
+        }
+    }
+
+    fn label(&self) -> String {
+        format!("{}/{}", self.kind.dir(), self.name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum EdgeKind {
+    /// A Cargo dependency, which makes the dependency build first.
+    Cargo,
+    /// A `BuildRequires` in the spec.
+    BuildRequires,
+    /// A `Requires` in the spec.
+    Requires,
+    /// An entry in a variant's `included-packages`.
+    Includes,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Whether the node was found in the project, rather than only referred to, for example by a
+    /// variant that includes a package from an external kit.
+    local: bool,
+}
+
+#[derive(Debug, Default)]
+struct DependencyGraph {
+    nodes: BTreeMap<NodeId, Node>,
+    edges: BTreeMap<(NodeId, NodeId), BTreeSet<EdgeKind>>,
+}
+
+/// The Cargo dependencies of the packages, kits and variants in the project, which decide the
+/// order they are built in.
+pub(super) async fn build_dependencies(project_dir: &Path) -> Result<Dependencies> {
+    let graph = DependencyGraph::load(project_dir).await?;
+    Ok(graph.build_dependencies())
+}
+
+/// What's read from a package, kit or variant directory.
+#[derive(Debug, Default)]
+struct Manifest {
+    /// Cargo dependencies, by the directory they point to.
+    dependencies: Vec<NodeId>,
+    included_packages: Vec<String>,
+    /// The RPMs a package's spec builds.
+    provides: Vec<String>,
+    build_requires: Vec<String>,
+    requires: Vec<String>,
+}
+
+impl DependencyGraph {
+    async fn load(project_dir: &Path) -> Result<Self> {
+        let mut manifests = BTreeMap::new();
+        for kind in [NodeKind::Package, NodeKind::Kit, NodeKind::Variant] {
+            let dir = project_dir.join(kind.dir());
+            if !dir.is_dir() {
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("Unable to list '{}'", dir.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.path().join("Cargo.toml").is_file() {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    let manifest = Manifest::load(&entry.path(), kind).await?;
+                    manifests.insert(NodeId::new(kind, name), manifest);
+                }
+            }
+        }
+        Ok(Self::from_manifests(manifests))
+    }
+
+    fn from_manifests(manifests: BTreeMap<NodeId, Manifest>) -> Self {
+        let mut graph = Self::default();
+        // The package that builds each RPM, so that spec dependencies can be resolved.
+        let mut providers = BTreeMap::new();
+        for (id, manifest) in &manifests {
+            graph.nodes.insert(id.clone(), Node { local: true });
+            for rpm in &manifest.provides {
+                providers.insert(rpm.clone(), id.clone());
+            }
+        }
+
+        for (id, manifest) in &manifests {
+            for dep in &manifest.dependencies {
+                graph.add_edge(id, dep, EdgeKind::Cargo);
+            }
+            for package in &manifest.included_packages {
+                let package = providers
+                    .get(package)
+                    .cloned()
+                    .unwrap_or_else(|| NodeId::new(NodeKind::Package, package));
+                graph.add_edge(id, &package, EdgeKind::Includes);
+            }
+            for (rpms, kind) in [
+                (&manifest.build_requires, EdgeKind::BuildRequires),
+                (&manifest.requires, EdgeKind::Requires),
+            ] {
+                // Dependencies on RPMs that aren't built here come from the SDK or external kits.
+                for provider in rpms.iter().filter_map(|rpm| providers.get(rpm)) {
+                    if provider != id {
+                        graph.add_edge(id, provider, kind);
+                    }
+                }
+            }
+        }
+        graph
+    }
+
+    fn add_edge(&mut self, from: &NodeId, to: &NodeId, kind: EdgeKind) {
+        self.nodes.entry(to.clone()).or_default();
+        self.edges
+            .entry((from.clone(), to.clone()))
+            .or_default()
+            .insert(kind);
+    }
+
+    fn build_dependencies(&self) -> Dependencies {
+        let mut dependencies = Dependencies::new();
+        for ((from, to), kinds) in &self.edges {
+            if kinds.contains(&EdgeKind::Cargo) {
+                dependencies
+                    .entry((from.kind.build_kind().to_string(), from.name.clone()))
+                    .or_default()
+                    .insert((to.kind.build_kind().to_string(), to.name.clone()));
+            }
+        }
+        dependencies
+    }
+
+    /// A Cargo dependency of a package on another package whose RPMs the spec doesn't require.
+    fn is_unused(from: &NodeId, to: &NodeId, kinds: &BTreeSet<EdgeKind>) -> bool {
+        from.kind == NodeKind::Package
+            && to.kind == NodeKind::Package
+            && kinds.len() == 1
+            && kinds.contains(&EdgeKind::Cargo)
+    }
+
+    /// The part of the graph that `root` depends on, directly or indirectly.
+    fn reachable_from(self, root: &NodeId) -> Self {
+        let mut seen = BTreeSet::from([root.clone()]);
+        let mut queue = vec![root.clone()];
+        while let Some(id) = queue.pop() {
+            for (from, to) in self.edges.keys() {
+                if *from == id && seen.insert(to.clone()) {
+                    queue.push(to.clone());
+                }
+            }
+        }
+        Self {
+            nodes: self
+                .nodes
+                .into_iter()
+                .filter(|(id, _)| seen.contains(id))
+                .collect(),
+            edges: self
+                .edges
+                .into_iter()
+                .filter(|((from, _), _)| seen.contains(from))
+                .collect(),
+        }
+    }
+
+    fn dot(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "digraph dependencies {{");
+        let _ = writeln!(s, "    rankdir=LR;");
+        for (id, node) in &self.nodes {
+            let shape = match id.kind {
+                NodeKind::Package => "box",
+                NodeKind::Kit => "folder",
+                NodeKind::Variant => "doubleoctagon",
+            };
+            let style = if node.local { "solid" } else { "dashed" };
+            let _ = writeln!(
+                s,
+                "    \"{}\" [label=\"{}\", shape={}, style={}];",
+                id.label(),
+                id.name,
+                shape,
+                style
+            );
+        }
+        for ((from, to), kinds) in &self.edges {
+            let attrs = if Self::is_unused(from, to, kinds) {
+                "style=dashed, color=red, label=\"unused\""
+            } else if kinds.contains(&EdgeKind::Cargo) {
+                "style=solid"
+            } else if kinds.contains(&EdgeKind::Includes) {
+                "style=bold, color=blue"
+            } else {
+                "style=dotted"
+            };
+            let _ = writeln!(
+                s,
+                "    \"{}\" -> \"{}\" [{}];",
+                from.label(),
+                to.label(),
+                attrs
+            );
+        }
+        let _ = writeln!(s, "}}");
+        s
+    }
+
+    fn json(&self) -> JsonGraph {
+        JsonGraph {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|(id, node)| JsonNode {
+                    id: id.label(),
+                    kind: id.kind,
+                    name: id.name.clone(),
+                    local: node.local,
+                })
+                .collect(),
+            edges: self
+                .edges
+                .iter()
+                .map(|((from, to), kinds)| JsonEdge {
+                    from: from.label(),
+                    to: to.label(),
+                    kinds: kinds.iter().copied().collect(),
+                    unused: Self::is_unused(from, to, kinds),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonGraph {
+    nodes: Vec<JsonNode>,
+    edges: Vec<JsonEdge>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonNode {
+    id: String,
+    kind: NodeKind,
+    name: String,
+    local: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonEdge {
+    from: String,
+    to: String,
+    kinds: Vec<EdgeKind>,
+    unused: bool,
+}
+
+impl Manifest {
+    async fn load(dir: &Path, kind: NodeKind) -> Result<Self> {
+        let path = dir.join("Cargo.toml");
+        let cargo: Table = toml::from_str(&fs::read_to_string(&path).await?)
+            .with_context(|| format!("Unable to parse '{}'", path.display()))?;
+
+        let mut manifest = Manifest::default();
+        for section in ["build-dependencies", "dependencies"] {
+            let deps = cargo.get(section).and_then(Value::as_table);
+            for dep in deps.into_iter().flat_map(|deps| deps.values()) {
+                if let Some(id) = dep.get("path").and_then(Value::as_str).and_then(path_node) {
+                    manifest.dependencies.push(id);
+                }
+            }
+        }
+
+        let metadata = cargo
+            .get("package")
+            .and_then(|p| p.get("metadata"))
+            .and_then(Value::as_table);
+        let get = |table: &str, key: &str| {
+            metadata
+                .and_then(|m| m.get(table))
+                .and_then(|t| t.get(key))
+                .cloned()
+        };
+        let mut packages = match get("build-variant", "included-packages") {
+            Some(Value::Array(packages)) => packages,
+            _ => Vec::new(),
+        };
+        // Packages that depend on image features, or that are excluded for only some
+        // architectures, are still part of the graph.
+        if let Some(Value::Table(features)) = get("build-variant", "feature-packages") {
+            for feature_packages in features.values().filter_map(Value::as_array) {
+                packages.extend(feature_packages.iter().cloned());
+            }
+        }
+        if !packages.is_empty() {
+            let excluded: BTreeSet<String> = match get("build-variant", "excluded-packages") {
+                Some(Value::Array(excluded)) => excluded
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect(),
+                _ => BTreeSet::new(),
+            };
+            manifest.included_packages = packages
+                .iter()
+                .filter_map(Value::as_str)
+                .filter(|package| !excluded.contains(*package))
+                .map(str::to_string)
+                .collect();
+        }
+
+        if kind == NodeKind::Package {
+            let dir_name = dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let package_name = get("build-package", "package-name")
+                .and_then(|v| v.as_str().map(str::to_string))
+                .unwrap_or(dir_name);
+            let spec = dir.join(format!("{package_name}.spec"));
+            if spec.is_file() {
+                let spec = Spec::parse(&fs::read_to_string(&spec).await?);
+                manifest.provides = spec.provides;
+                manifest.build_requires = spec.build_requires;
+                manifest.requires = spec.requires;
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// The package, kit or variant that a Cargo path dependency points to, from the last two parts of
+/// the path, such as `../../kits/core-kit`.
+fn path_node(path: &str) -> Option<NodeId> {
+    let mut parts = Path::new(path).components().rev();
+    let name = parts.next()?.as_os_str().to_string_lossy().to_string();
+    let kind = match parts.next()?.as_os_str().to_str()? {
+        "packages" => NodeKind::Package,
+        "kits" => NodeKind::Kit,
+        "variants" => NodeKind::Variant,
+        _ => return None,
+    };
+    Some(NodeId::new(kind, name))
+}
+
+/// The RPM names that a spec builds and depends on, without the `%{_cross_os}` prefix.
+/// Dependencies without the prefix are on the SDK rather than on packages, and are left out.
+#[derive(Debug, Default, PartialEq)]
+struct Spec {
+    provides: Vec<String>,
+    build_requires: Vec<String>,
+    requires: Vec<String>,
+}
+
+const CROSS_OS: &str = "%{_cross_os}";
+
+impl Spec {
+    fn parse(text: &str) -> Self {
+        let mut spec = Spec::default();
+        let mut name = None;
+        for line in text.lines().map(str::trim) {
+            if let Some(value) = tag_value(line, "Name") {
+                if let Some(value) = value.strip_prefix(CROSS_OS) {
+                    name = Some(value.to_string());
+                    spec.provides.push(value.to_string());
+                }
+            } else if let Some(value) = tag_value(line, "BuildRequires") {
+                spec.build_requires.extend(rpm_names(value));
+            } else if let Some(value) = tag_value(line, "Requires") {
+                spec.requires.extend(rpm_names(value));
+            } else if let Some(rest) = line.strip_prefix("%package") {
+                let mut words = rest.split_whitespace();
+                match (words.next(), words.next(), &name) {
+                    (Some("-n"), Some(full), _) => {
+                        if let Some(full) = full.strip_prefix(CROSS_OS) {
+                            spec.provides.push(full.to_string());
+                        }
+                    }
+                    (Some(suffix), _, Some(name)) => spec.provides.push(format!("{name}-{suffix}")),
+                    _ => {}
+                }
+            }
+        }
+        spec
+    }
+}
+
+/// The value of a spec tag such as `BuildRequires:`, which may also be a `Requires(post):`.
+fn tag_value<'a>(line: &'a str, tag: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(tag)?;
+    let rest = match rest.strip_prefix('(') {
+        Some(qualified) => qualified.split_once(')')?.1,
+        None => rest,
+    };
+    Some(rest.strip_prefix(':')?.trim())
+}
+
+/// The cross-compiled RPM names in a dependency list, such as `%{_cross_os}glibc-devel >= 2.38`.
+fn rpm_names(value: &str) -> Vec<String> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|word| word.strip_prefix(CROSS_OS))
+        .filter(|name| !name.is_empty() && !name.contains('%'))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = Spec::parse(
+            "Name: %{_cross_os}libfoo\n\
+            BuildRequires: %{_cross_os}glibc-devel >= 2.38, cmake\n\
+            Requires(post): %{_cross_os}systemd\n\
+            %package devel\n\
+            Requires: %{_cross_os}libfoo\n\
+            %package -n %{_cross_os}foo-tools\n",
+        );
+        assert_eq!(
+            spec,
+            Spec {
+                provides: vec![
+                    "libfoo".to_string(),
+                    "libfoo-devel".to_string(),
+                    "foo-tools".to_string()
+                ],
+                build_requires: vec!["glibc-devel".to_string()],
+                requires: vec!["systemd".to_string(), "libfoo".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_graph() {
+        let package = |name: &str| NodeId::new(NodeKind::Package, name);
+        let manifests = BTreeMap::from([
+            (
+                package("glibc"),
+                Manifest {
+                    provides: vec!["glibc".to_string(), "glibc-devel".to_string()],
+                    ..Default::default()
+                },
+            ),
+            (
+                package("libz"),
+                Manifest {
+                    provides: vec!["libz".to_string()],
+                    ..Default::default()
+                },
+            ),
+            (
+                package("app"),
+                Manifest {
+                    dependencies: vec![package("glibc"), package("libz")],
+                    provides: vec!["app".to_string()],
+                    build_requires: vec!["glibc-devel".to_string()],
+                    ..Default::default()
+                },
+            ),
+            (
+                NodeId::new(NodeKind::Variant, "aws-dev"),
+                Manifest {
+                    dependencies: vec![NodeId::new(NodeKind::Kit, "core-kit")],
+                    included_packages: vec!["app".to_string(), "kernel-6.1".to_string()],
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let graph = DependencyGraph::from_manifests(manifests);
+        let json = graph.json();
+        let edge = |from: &str, to: &str| {
+            json.edges
+                .iter()
+                .find(|e| e.from == from && e.to == to)
+                .unwrap()
+        };
+        assert_eq!(
+            edge("packages/app", "packages/glibc").kinds,
+            vec![EdgeKind::Cargo, EdgeKind::BuildRequires]
+        );
+        assert!(edge("packages/app", "packages/libz").unused);
+        assert!(
+            !json
+                .nodes
+                .iter()
+                .find(|n| n.id == "packages/kernel-6.1")
+                .unwrap()
+                .local
+        );
+
+        let dependencies = graph.build_dependencies();
+        assert_eq!(
+            dependencies[&("package".to_string(), "app".to_string())].len(),
+            2
+        );
+        assert!(!dependencies.contains_key(&("package".to_string(), "glibc".to_string())));
+
+        let variant = graph.reachable_from(&NodeId::new(NodeKind::Variant, "aws-dev"));
+        assert!(variant.nodes.contains_key(&package("glibc")));
+        assert!(variant
+            .dot()
+            .contains("\"variants/aws-dev\" -> \"packages/app\""));
+    }
+
+    #[test]
+    fn test_path_node() {
+        assert_eq!(
+            path_node("../../kits/core-kit"),
+            Some(NodeId::new(NodeKind::Kit, "core-kit"))
+        );
+        assert_eq!(path_node("../../tools/buildsys"), None);
+    }
+}
@@ -0,0 +1,436 @@
+/*!
+`twoliter lint` checks a project for mistakes that would otherwise only show up partway through a
+build:
+
+- each spec is run through rpmlint in the SDK, with the Bottlerocket RPM macros defined, and with
+  `rpmlint.toml` from the project directory as its configuration when there is one;
+- each manifest under `packages`, `kits` and `variants` is read the way buildsys reads it, and any
+  keys in its build metadata that buildsys doesn't recognize are reported;
+- each settings defaults file, the TOML files in any `defaults.d` directory, is checked for keys
+  that aren't part of the defaults format.
+
+Problems that would fail a build are errors, and the command fails if there are any.
+*/
+
+use crate::common::fs;
+use crate::docker::SdkRun;
+use crate::output;
+use crate::project::{self, SDKLocked};
+use anyhow::{bail, Context, Result};
+use async_walkdir::{Filtering, WalkDir};
+use buildsys::manifest::ManifestInfo;
+use clap::Parser;
+use futures::StreamExt;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+use tracing::info;
+
+/// The directories of a project that hold manifests that buildsys reads.
+const MANIFEST_DIRS: [&str; 3] = ["packages", "kits", "variants"];
+
+/// The name of the directories that hold settings defaults.
+const DEFAULTS_DIR: &str = "defaults.d";
+
+/// The tables that a settings defaults file may have.
+const DEFAULTS_KEYS: [&str; 4] = ["settings", "services", "configuration-files", "metadata"];
+
+/// The keys of each service in a settings defaults file.
+const SERVICE_KEYS: [&str; 2] = ["configuration-files", "restart-commands"];
+
+/// The keys of each configuration file in a settings defaults file.
+const CONFIGURATION_FILE_KEYS: [&str; 3] = ["path", "template-path", "mode"];
+
+/// The rpmlint configuration that projects can provide, relative to the project directory.
+const RPMLINT_CONFIG: &str = "rpmlint.toml";
+
+/// The exit code of the lint script when rpmlint isn't in the SDK.
+const RPMLINT_MISSING: i32 = 127;
+
+/// Runs rpmlint over the specs given as arguments, relative to the project, with the target's
+/// macros so that specs parse the way they do in a package build.
+const RPMLINT_SCRIPT: &str = r#"set -e
+command -v rpmlint >/dev/null || exit 127
+cp "/usr/lib/rpm/platform/${ARCH}-bottlerocket/macros" "${HOME}/.rpmmacros"
+cd /host
+exec rpmlint "$@"
+"#;
+
+/// Check specs, manifests and settings defaults for mistakes without building anything.
+#[derive(Debug, Parser)]
+pub(crate) struct Lint {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture whose RPM macros are defined when specs are checked.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// Don't run rpmlint, which needs the SDK, and only check manifests and settings defaults.
+    #[clap(long = "no-rpmlint")]
+    no_rpmlint: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in one of the project's files.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct Finding {
+    /// The file, relative to the project directory.
+    path: PathBuf,
+    severity: Severity,
+    message: String,
+}
+
+impl Finding {
+    pub(super) fn error(path: impl Into<PathBuf>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+
+    fn warning(path: impl Into<PathBuf>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", self.path.display(), severity, self.message)
+    }
+}
+
+impl Lint {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project_dir = project.project_dir();
+
+        let mut findings = Vec::new();
+        let manifests = find_manifests(&project_dir).await?;
+        for manifest in &manifests {
+            findings.extend(lint_manifest(&project_dir, manifest));
+        }
+        let defaults = find_defaults(&project_dir).await?;
+        for file in &defaults {
+            let relative = file.strip_prefix(&project_dir).unwrap_or(file);
+            let data = fs::read_to_string(file).await?;
+            findings.extend(lint_defaults(relative, &data));
+        }
+        let mut specs = 0;
+        if !self.no_rpmlint {
+            let project = project.load_lock::<SDKLocked>().await?;
+            let sdk = SdkRun::new(
+                project.sdk_image().project_image_uri().to_string(),
+                &project_dir,
+                &self.arch,
+            );
+            let paths = find_specs(&project_dir, &manifests);
+            specs = paths.len();
+            findings.extend(rpmlint(&sdk, &project_dir, &paths).await?);
+        }
+        findings.sort();
+
+        let errors = findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+            .count();
+        let warnings = findings.len() - errors;
+        for finding in &findings {
+            if output::is_json() {
+                output::warning(finding.to_string());
+            } else {
+                println!("{finding}");
+            }
+        }
+        output::detail("errors", errors.to_string());
+        output::detail("warnings", warnings.to_string());
+        info!(
+            "Checked {} manifests, {} settings defaults files and {} specs: {} errors, {} warnings",
+            manifests.len(),
+            defaults.len(),
+            specs,
+            errors,
+            warnings
+        );
+        if errors > 0 {
+            bail!("Found {} errors in the project", errors);
+        }
+        Ok(())
+    }
+}
+
+/// Finds the manifests of the project's packages, kits and variants.
+pub(super) async fn find_manifests(project_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut manifests = Vec::new();
+    for dir in MANIFEST_DIRS.map(|dir| project_dir.join(dir)) {
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Unable to list '{}'", dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let manifest = entry.path().join("Cargo.toml");
+            if manifest.is_file() {
+                manifests.push(manifest);
+            }
+        }
+    }
+    manifests.sort();
+    Ok(manifests)
+}
+
+/// Reads a manifest the way buildsys does, and reports it if buildsys can't read it or if it has
+/// build metadata that buildsys would ignore.
+fn lint_manifest(project_dir: &Path, manifest: &Path) -> Vec<Finding> {
+    let relative = manifest.strip_prefix(project_dir).unwrap_or(manifest);
+    let info = match ManifestInfo::new(manifest) {
+        Ok(info) => info,
+        Err(e) => return vec![Finding::error(relative, e.to_string())],
+    };
+    info.unknown_keys()
+        .into_iter()
+        .map(|key| {
+            Finding::error(
+                relative,
+                format!("unknown key 'package.metadata.{key}', which buildsys ignores"),
+            )
+        })
+        .collect()
+}
+
+/// The specs of the packages among `manifests`, relative to the project directory.
+fn find_specs(project_dir: &Path, manifests: &[PathBuf]) -> Vec<PathBuf> {
+    let packages_dir = project_dir.join("packages");
+    manifests
+        .iter()
+        .filter_map(|manifest| {
+            let package_dir = manifest.parent()?;
+            if package_dir.parent()? != packages_dir {
+                return None;
+            }
+            let info = ManifestInfo::new(manifest).ok()?;
+            let spec = package_dir.join(format!("{}.spec", info.package_name()));
+            if !spec.is_file() {
+                return None;
+            }
+            spec.strip_prefix(project_dir).ok().map(Path::to_path_buf)
+        })
+        .collect()
+}
+
+/// Runs rpmlint over `specs` in the SDK and collects what it reports.
+async fn rpmlint(sdk: &SdkRun, project_dir: &Path, specs: &[PathBuf]) -> Result<Vec<Finding>> {
+    if specs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut args = vec!["bash", "-c", RPMLINT_SCRIPT, "bash"]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    if project_dir.join(RPMLINT_CONFIG).is_file() {
+        args.extend(["--config".to_string(), RPMLINT_CONFIG.to_string()]);
+    }
+    args.extend(specs.iter().map(|spec| spec.display().to_string()));
+
+    let result = sdk.output(&args).await?;
+    if result.status.code() == Some(RPMLINT_MISSING) {
+        bail!("The SDK doesn't have rpmlint, run again with --no-rpmlint to skip it");
+    }
+    let findings = parse_rpmlint(&String::from_utf8_lossy(&result.stdout));
+    // rpmlint fails when it finds errors, which are reported as findings, or when it can't run.
+    if !result.status.success() && findings.is_empty() {
+        bail!(
+            "rpmlint failed with exit code {}: {}",
+            result.status.code().unwrap_or(1),
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    Ok(findings)
+}
+
+/// Reads the findings from rpmlint's output, such as
+/// `packages/hello/hello.spec:12: W: macro-in-comment %build`.
+fn parse_rpmlint(output: &str) -> Vec<Finding> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (location, rest) = line.split_once(": ")?;
+            let (severity, message) = rest.split_once(": ")?;
+            let path = location.split(':').next().unwrap_or(location);
+            let message = match location.split_once(':') {
+                Some((_, line)) => format!("line {line}: {message}"),
+                None => message.to_string(),
+            };
+            match severity {
+                "E" => Some(Finding::error(path, message)),
+                "W" => Some(Finding::warning(path, message)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Finds the settings defaults files in the project, the TOML files in any `defaults.d`
+/// directory.
+async fn find_defaults(project_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for dir in find_defaults_dirs(project_dir).await? {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Unable to list '{}'", dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "toml") && path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Finds the `defaults.d` directories in the project. The build directory and hidden directories
+/// are skipped.
+pub(super) async fn find_defaults_dirs(project_dir: &Path) -> Result<Vec<PathBuf>> {
+    let build_dir = project_dir.join("build");
+    let mut entries = WalkDir::new(project_dir).filter(move |entry| {
+        let build_dir = build_dir.clone();
+        async move {
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if hidden || entry.path() == build_dir || entry.file_name() == "target" {
+                Filtering::IgnoreDir
+            } else {
+                Filtering::Continue
+            }
+        }
+    });
+
+    let mut dirs = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context(format!("Unable to read '{}'", project_dir.display()))?;
+        let path = entry.path();
+        if entry.file_name() == DEFAULTS_DIR && path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Checks that a settings defaults file only has the tables and keys of the defaults format.
+/// Settings and metadata are defined by the variant's settings model, so only their place in
+/// the file is checked.
+fn lint_defaults(path: &Path, data: &str) -> Vec<Finding> {
+    let table: Table = match toml::from_str(data) {
+        Ok(table) => table,
+        Err(e) => return vec![Finding::error(path, format!("not valid TOML: {e}"))],
+    };
+
+    let mut findings = Vec::new();
+    for (key, value) in &table {
+        if !DEFAULTS_KEYS.contains(&key.as_str()) {
+            findings.push(Finding::error(path, format!("unknown table '{key}'")));
+            continue;
+        }
+        let known: &[&str] = match key.as_str() {
+            "services" => &SERVICE_KEYS,
+            "configuration-files" => &CONFIGURATION_FILE_KEYS,
+            _ => continue,
+        };
+        let Value::Table(entries) = value else {
+            findings.push(Finding::error(path, format!("'{key}' must be a table")));
+            continue;
+        };
+        for (name, entry) in entries {
+            let Value::Table(entry) = entry else {
+                findings.push(Finding::error(
+                    path,
+                    format!("'{key}.{name}' must be a table"),
+                ));
+                continue;
+            };
+            for field in entry
+                .keys()
+                .filter(|field| !known.contains(&field.as_str()))
+            {
+                findings.push(Finding::error(
+                    path,
+                    format!("unknown key '{key}.{name}.{field}'"),
+                ));
+            }
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_rpmlint() {
+        let output = "\
+packages/hello/hello.spec:12: W: macro-in-comment %build
+packages/hello/hello.spec: E: specfile-error error: line 3: Unknown tag: Licence:
+1 packages and 0 specfiles checked; 1 errors, 1 warnings, 0 badness; has taken 0.1 s
+";
+        assert_eq!(
+            parse_rpmlint(output),
+            vec![
+                Finding::warning(
+                    "packages/hello/hello.spec",
+                    "line 12: macro-in-comment %build"
+                ),
+                Finding::error(
+                    "packages/hello/hello.spec",
+                    "specfile-error error: line 3: Unknown tag: Licence:"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_defaults() {
+        let path = Path::new("defaults.d/50-hello.toml");
+        let data = r#"
+[settings.hello]
+greeting = "hi"
+
+[services.hello]
+configuration-files = ["hello-conf"]
+restart-command = ["/usr/bin/systemctl try-restart hello.service"]
+
+[configuration-files.hello-conf]
+path = "/etc/hello.conf"
+template-path = "/usr/share/templates/hello-conf"
+
+[setting.typo]
+"#;
+        assert_eq!(
+            lint_defaults(path, data),
+            vec![
+                Finding::error(path, "unknown key 'services.hello.restart-command'"),
+                Finding::error(path, "unknown table 'setting'"),
+            ]
+        );
+        assert!(lint_defaults(path, "[settings")[0]
+            .message
+            .starts_with("not valid TOML"));
+    }
+}
@@ -1,7 +1,10 @@
+use super::publish_kit::Publish;
 use crate::cargo_make::CargoMake;
-use crate::project::{self, Locked, SDKLocked, Unlocked};
+use crate::project::{self, BuildsysConfig, Locked, SDKLocked, Unlocked};
+use crate::tasks::{TaskContext, TaskRunner};
 use crate::tools::install_tools;
 use anyhow::Result;
+use buildsys::hooks::Stage;
 use clap::Parser;
 use std::path::PathBuf;
 
@@ -23,8 +26,21 @@ const MUST_VALIDATE_KITS_TARGETS: &[&str] = &[
     "default",
 ];
 
+/// The tasks that publish a variant's images, around which the project's publish hooks run.
+const PUBLISH_TASKS: &[&str] = &[
+    "ami",
+    "ssm",
+    "promote-ssm",
+    "repo",
+    "sync-repo",
+    "publish-marketplace",
+    "upload-ova",
+];
+
 /// Run a cargo make command in Twoliter's build environment. Known Makefile.toml environment
-/// variables will be passed-through to the cargo make invocation.
+/// variables will be passed-through to the cargo make invocation. Tasks that Twoliter implements
+/// natively, such as `fetch`, are run without cargo make unless `--cargo-make` is given. Tasks that
+/// publish a variant, such as `ami` and `repo`, run between the project's publish hooks.
 #[derive(Debug, Parser)]
 #[clap(trailing_var_arg = true)]
 pub(crate) struct Make {
@@ -43,6 +59,10 @@ pub(crate) struct Make {
     #[clap(long, env = "BUILDSYS_ARCH")]
     arch: String,
 
+    /// Run the task with cargo make even if Twoliter has a native implementation of it.
+    #[clap(long)]
+    cargo_make: bool,
+
     /// Cargo make task. E.g. the word "build" if we want to execute `cargo make build`.
     makefile_task: String,
 
@@ -57,15 +77,65 @@ impl Make {
         let sdk_source = self.locked_sdk(&project).await?;
         let toolsdir = project.project_dir().join("build/tools");
         install_tools(&toolsdir).await?;
+        let buildsys_config = BuildsysConfig::load(project.project_dir()).await?;
+        let task_runner = TaskRunner::default();
+        if !self.cargo_make
+            && self.additional_args.is_empty()
+            && task_runner.has_task(&self.makefile_task)
+        {
+            let go_modules = match std::env::var("GO_MODULES") {
+                Ok(modules) => modules.split_whitespace().map(String::from).collect(),
+                Err(_) => project.find_go_modules().await?,
+            };
+            let ctx = TaskContext {
+                project_dir: project.project_dir(),
+                arch: self.arch.clone(),
+                sdk_image: sdk_source,
+                tools_dir: toolsdir,
+                cargo_home: self.cargo_home.clone(),
+                go_modules,
+                envs: buildsys_config.envs().into_iter().collect(),
+            };
+            return task_runner.run(&self.makefile_task, &ctx).await;
+        }
+
+        let publish = PUBLISH_TASKS
+            .contains(&self.makefile_task.as_str())
+            .then(|| {
+                // The same defaults as `Makefile.toml`.
+                let variant = buildsys_config
+                    .setting("BUILDSYS_VARIANT")
+                    .unwrap_or_else(|| "aws-k8s-1.24".to_string());
+                let repo = buildsys_config
+                    .setting("PUBLISH_REPO")
+                    .unwrap_or_else(|| "default".to_string());
+                Publish::variant(&project, &self.makefile_task, &variant, &self.arch, &repo)
+            });
+        if let Some(publish) = &publish {
+            publish
+                .run_hooks(&project, &sdk_source, Stage::PrePublish)
+                .await?;
+        }
+
         let makefile_path = toolsdir.join("Makefile.toml");
         CargoMake::new(&sdk_source)?
+            .envs(buildsys_config.envs().into_iter())
             .env("CARGO_HOME", self.cargo_home.display().to_string())
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
             .env("BUILDSYS_VERSION_IMAGE", project.release_version())
+            .envs(project.required_versions().envs().into_iter())
+            .envs(project.hook_envs().into_iter())
             .makefile(makefile_path)
             .project_dir(project.project_dir())
             .exec_with_args(&self.makefile_task, self.additional_args.clone())
-            .await
+            .await?;
+
+        if let Some(publish) = &publish {
+            publish
+                .run_hooks(&project, &sdk_source, Stage::PostPublish)
+                .await?;
+        }
+        Ok(())
     }
 
     fn can_skip_kit_verification(&self, project: &project::Project<Unlocked>) -> bool {
@@ -250,6 +320,7 @@ mod test {
             project_path: Some(project_path),
             cargo_home: project_dir.to_owned(),
             arch: "x86_64".to_string(),
+            cargo_make: false,
             makefile_task: target_name.to_string(),
             additional_args: Vec::new(),
         };
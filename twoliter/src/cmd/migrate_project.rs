@@ -0,0 +1,76 @@
+use crate::common::fs;
+use crate::output;
+use crate::project::migrate::{Migration, ReleaseToml};
+use crate::project::{self, Project};
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Upgrade a Twoliter.toml written for an older version of twoliter, such as one with keys that
+/// were renamed or with its version in Release.toml, and create Twoliter.lock if there is none.
+/// The changes are printed as a diff before they are made
+#[derive(Debug, Parser)]
+pub(crate) struct MigrateProject {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Print the changes without making them
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+
+impl MigrateProject {
+    pub(super) async fn run(&self) -> Result<()> {
+        let path = match &self.project_path {
+            Some(path) => fs::canonicalize(path).await?,
+            None => project::find_project_file(".")?,
+        };
+        let project_dir = path.parent().context(format!(
+            "Unable to find the parent directory of '{}'",
+            path.display()
+        ))?;
+        let release_toml_path = project_dir.join("Release.toml");
+        let release_toml = if release_toml_path.is_file() {
+            Some(fs::read_to_string(&release_toml_path).await?)
+        } else {
+            None
+        };
+        let data = fs::read_to_string(&path).await?;
+        let migration = Migration::new(&data, release_toml.as_deref())
+            .with_context(|| format!("Unable to migrate '{}'", path.display()))?;
+        let lock_path = project_dir.join("Twoliter.lock");
+        let create_lock = !lock_path.is_file();
+
+        if migration.is_empty() && !create_lock {
+            info!("'{}' is already up to date", path.display());
+            return Ok(());
+        }
+        for change in &migration.changes {
+            println!("- {change}");
+        }
+        if create_lock {
+            println!("- Created Twoliter.lock");
+        }
+        print!("{}", migration.diff("Twoliter.toml"));
+        if self.dry_run {
+            return Ok(());
+        }
+
+        if !migration.is_empty() {
+            fs::write(&path, &migration.after).await?;
+            match &migration.release_toml {
+                ReleaseToml::Keep => {}
+                ReleaseToml::Remove => fs::remove_file(&release_toml_path).await?,
+                ReleaseToml::Update(data) => fs::write(&release_toml_path, data).await?,
+            }
+            output::artifact(&path).await;
+        }
+        if create_lock {
+            Project::load(&path).await?.create_lock().await?;
+            output::artifact(&lock_path).await;
+        }
+        Ok(())
+    }
+}
@@ -1,34 +1,89 @@
 mod build;
 mod build_clean;
+mod cache;
+pub(crate) mod completions;
 mod debug;
+mod dev;
+mod diff;
+mod exec;
 mod fetch;
+mod gc;
+mod graph;
+mod lint;
 mod make;
+mod migrate_project;
 mod publish_kit;
+mod release;
+mod release_notes;
+mod self_update;
+mod status;
+mod test_matrix;
 mod update;
+mod verify;
 
 use self::build::BuildCommand;
+use crate::ci::CiFormat;
+use crate::cmd::cache::CacheCommand;
+use crate::cmd::completions::Completions;
 use crate::cmd::debug::DebugAction;
+use crate::cmd::dev::DevCommand;
+use crate::cmd::diff::DiffCommand;
+use crate::cmd::exec::Exec;
 use crate::cmd::fetch::Fetch;
+use crate::cmd::gc::Gc;
+use crate::cmd::graph::Graph;
+use crate::cmd::lint::Lint;
 use crate::cmd::make::Make;
+use crate::cmd::migrate_project::MigrateProject;
 use crate::cmd::publish_kit::PublishCommand;
+use crate::cmd::release::Release;
+use crate::cmd::release_notes::ReleaseNotes;
+use crate::cmd::self_update::SelfCommand;
+use crate::cmd::status::Status;
+use crate::cmd::test_matrix::TestMatrix;
 use crate::cmd::update::Update;
+use crate::cmd::verify::VerifyCommand;
+use crate::logging;
+use crate::output::OutputFormat;
 use anyhow::Result;
 use clap::Parser;
-use env_logger::Builder;
-use log::LevelFilter;
-
-const DEFAULT_LEVEL_FILTER: LevelFilter = LevelFilter::Info;
+use tracing::level_filters::LevelFilter;
 
 /// A tool for building custom variants of Bottlerocket.
 #[derive(Debug, Parser)]
 #[clap(about, long_about = None, version)]
 pub(crate) struct Args {
-    /// Set the logging level. One of [off|error|warn|info|debug|trace]. Defaults to warn. You can
-    /// also leave this unset and use the RUST_LOG env variable. See
-    /// https://github.com/rust-cli/env_logger/
-    #[clap(long = "log-level")]
+    /// Set the logging level. One of [off|error|warn|info|debug|trace]. Defaults to info. Filters
+    /// for single modules can be added with the TWOLITER_LOG env variable, such as
+    /// `TWOLITER_LOG=twoliter::project=debug`.
+    #[clap(long = "log-level", global = true)]
     pub(crate) log_level: Option<LevelFilter>,
 
+    /// Log more: `-v` for debug messages, `-vv` for trace messages.
+    #[clap(
+        short = 'v',
+        long = "verbose",
+        global = true,
+        action = clap::ArgAction::Count,
+        conflicts_with = "quiet"
+    )]
+    pub(crate) verbose: u8,
+
+    /// Log less: `-q` for only warnings and errors, `-qq` for only errors.
+    #[clap(short = 'q', long = "quiet", global = true, action = clap::ArgAction::Count)]
+    pub(crate) quiet: u8,
+
+    /// How to report the result of the command. With `json`, a JSON object describing the result
+    /// is printed to stdout when the command finishes, and all other output goes to stderr.
+    #[clap(long = "output", global = true, value_enum, default_value = "text")]
+    pub(crate) output: OutputFormat,
+
+    /// Format the output of builds for a CI system. The log of each build is printed once it
+    /// finishes, folded by stage, and failures are annotated with the spec or manifest line that
+    /// caused them when it is known.
+    #[clap(long = "ci", global = true, value_enum)]
+    pub(crate) ci: Option<CiFormat>,
+
     #[clap(subcommand)]
     pub(crate) subcommand: Subcommand,
 }
@@ -39,10 +94,34 @@ pub(crate) enum Subcommand {
     #[clap(subcommand)]
     Build(BuildCommand),
 
+    /// Manage the local caches that grow as a project is built.
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+
+    Completions(Completions),
+
+    /// Tools for working on a project, such as rebuilding a package as it is edited.
+    #[clap(subcommand)]
+    Dev(DevCommand),
+
+    /// Compare things, such as two builds of a variant.
+    #[clap(subcommand)]
+    Diff(DiffCommand),
+
+    Exec(Exec),
+
     Fetch(Fetch),
 
+    Gc(Gc),
+
+    Graph(Graph),
+
+    Lint(Lint),
+
     Make(Make),
 
+    MigrateProject(MigrateProject),
+
     /// Update Twoliter.lock
     Update(Update),
 
@@ -50,47 +129,67 @@ pub(crate) enum Subcommand {
     #[clap(subcommand)]
     Publish(PublishCommand),
 
-    /// Commands that are used for checking and troubleshooting Twoliter's internals.
+    Release(Release),
+
+    ReleaseNotes(ReleaseNotes),
+
+    /// Manage this installation of twoliter, such as updating it to a new release.
+    #[clap(name = "self", subcommand)]
+    TwoliterSelf(SelfCommand),
+
+    Status(Status),
+
+    TestMatrix(TestMatrix),
+
+    /// Verify something, such as a kit dependency
+    #[clap(subcommand)]
+    Verify(VerifyCommand),
+
+    /// Commands that are used for troubleshooting Twoliter's internals and the project's builds.
     #[clap(subcommand)]
     Debug(DebugAction),
 }
 
+impl Args {
+    /// The log level from `--log-level`, or else from `-v` or `-q`.
+    pub(crate) fn log_level(&self) -> Option<LevelFilter> {
+        self.log_level
+            .or_else(|| logging::verbosity(self.verbose, self.quiet))
+    }
+}
+
 /// Entrypoint for the `twoliter` command line program.
 pub(super) async fn run(args: Args) -> Result<()> {
     match args.subcommand {
         Subcommand::Build(build_command) => build_command.run().await,
+        Subcommand::Cache(cache_command) => cache_command.run().await,
+        Subcommand::Completions(completions) => completions.run().await,
+        Subcommand::Dev(dev_command) => dev_command.run().await,
+        Subcommand::Diff(diff_command) => diff_command.run().await,
+        Subcommand::Exec(exec_args) => exec_args.run().await,
         Subcommand::Fetch(fetch_args) => fetch_args.run().await,
+        Subcommand::Gc(gc_args) => gc_args.run().await,
+        Subcommand::Graph(graph_args) => graph_args.run().await,
+        Subcommand::Lint(lint_args) => lint_args.run().await,
         Subcommand::Make(make_args) => make_args.run().await,
+        Subcommand::MigrateProject(migrate_project) => migrate_project.run().await,
         Subcommand::Update(update_args) => update_args.run().await,
         Subcommand::Publish(publish_command) => publish_command.run().await,
+        Subcommand::Release(release_args) => release_args.run().await,
+        Subcommand::ReleaseNotes(release_notes) => release_notes.run().await,
+        Subcommand::TwoliterSelf(self_command) => self_command.run().await,
+        Subcommand::Status(status) => status.run().await,
+        Subcommand::TestMatrix(test_matrix) => test_matrix.run().await,
+        Subcommand::Verify(verify_command) => verify_command.run().await,
         Subcommand::Debug(debug_action) => debug_action.run().await,
     }
 }
 
-/// use `level` if present, or else use `RUST_LOG` if present, or else use a default.
-pub(super) fn init_logger(level: Option<LevelFilter>) {
-    match (std::env::var(env_logger::DEFAULT_FILTER_ENV).ok(), level) {
-        (Some(_), None) => {
-            // RUST_LOG exists and level does not; use the environment variable.
-            Builder::from_default_env().init();
-        }
-        _ => {
-            // use provided log level or default for this crate only.
-            Builder::new()
-                .filter(
-                    Some(env!("CARGO_CRATE_NAME")),
-                    level.unwrap_or(DEFAULT_LEVEL_FILTER),
-                )
-                .init();
-        }
-    }
-}
-
 #[cfg(feature = "integ-tests")]
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::cmd::build::BuildKit;
+    use crate::cmd::build::{BuildKit, BuildOptions};
     use async_walkdir::WalkDir;
     use futures::stream::StreamExt;
     use std::collections::HashSet;
@@ -140,6 +239,7 @@ mod test {
         let command = Fetch {
             project_path: Some(project_path.to_path_buf()),
             arch: arch.into(),
+            allow_drift: false,
         };
         command.run().await.unwrap()
     }
@@ -160,7 +260,7 @@ mod test {
             arch: arch.to_string(),
             kit: kit_name.to_string(),
             lookaside_cache: None,
-            upstream_source_fallback: false,
+            options: BuildOptions::default(),
         };
 
         command.run().await.unwrap();
@@ -183,7 +283,7 @@ mod test {
             arch: arch.to_string(),
             kit: kit_name.to_string(),
             lookaside_cache: None,
-            upstream_source_fallback: false,
+            options: BuildOptions::default(),
         };
 
         command.run().await.unwrap();
@@ -207,7 +307,7 @@ mod test {
             arch: arch.to_string(),
             kit: kit_name.to_string(),
             lookaside_cache: None,
-            upstream_source_fallback: false,
+            options: BuildOptions::default(),
         };
 
         command.run().await.unwrap();
@@ -231,7 +331,7 @@ mod test {
             arch: arch.to_string(),
             kit: kit_name.to_string(),
             lookaside_cache: None,
-            upstream_source_fallback: false,
+            options: BuildOptions::default(),
         };
 
         command.run().await.unwrap();
@@ -1,7 +1,9 @@
 use crate::cargo_make::CargoMake;
-use crate::project::{self, Locked};
+use crate::output;
+use crate::project::{self, Locked, Project, ProjectLock};
 use crate::tools::install_tools;
 use anyhow::Result;
+use buildsys::hooks::{Stage, Target};
 use clap::Parser;
 use std::path::PathBuf;
 
@@ -34,6 +36,15 @@ pub(crate) struct PublishKit {
 
     /// Publish kit image to a different repository than the kit's name
     kit_repo: Option<String>,
+
+    /// Additional tags for the multi-arch kit image, e.g. "latest". The image is always tagged
+    /// with the kit's version.
+    #[clap(long = "tag")]
+    tags: Vec<String>,
+
+    /// Additional annotations for the multi-arch kit image, in the form "key=value"
+    #[clap(long = "annotation")]
+    annotations: Vec<String>,
 }
 
 impl PublishKit {
@@ -48,15 +59,125 @@ impl PublishKit {
             Some(kit_repo) => kit_repo,
             None => &self.kit_name,
         };
-        CargoMake::new(project.sdk_image().project_image_uri().to_string().as_str())?
+        let mut optional_envs = Vec::new();
+        if !self.tags.is_empty() {
+            optional_envs.push(("PUBLISH_KIT_TAGS", self.tags.join(",")));
+        }
+        if !self.annotations.is_empty() {
+            // Annotation values may contain commas, so these are separated by newlines instead.
+            optional_envs.push(("PUBLISH_KIT_ANNOTATIONS", self.annotations.join("\n")));
+        }
+
+        let version = project.release_version().to_string();
+        let sdk = project.sdk_image().project_image_uri().to_string();
+        let publish = Publish {
+            kind: "kit".to_string(),
+            name: self.kit_name.clone(),
+            arch: None,
+            // Publishing the same version to the same place again doesn't need the hooks again.
+            key: format!(
+                "{} {publish_kit_repo} {version} {}",
+                self.vendor,
+                self.tags.join(",")
+            ),
+            envs: vec![
+                ("TWOLITER_HOOK_VENDOR", self.vendor.clone()),
+                ("TWOLITER_HOOK_REPOSITORY", publish_kit_repo.to_string()),
+                ("TWOLITER_HOOK_VERSION", version),
+            ],
+        };
+        publish.run_hooks(&project, &sdk, Stage::PrePublish).await?;
+        CargoMake::new(&sdk)?
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
             .env("BUILDSYS_KIT", &self.kit_name)
             .env("BUILDSYS_VERSION_IMAGE", project.release_version())
             .env("PUBLISH_VENDOR", &self.vendor)
             .env("PUBLISH_KIT_REPO", publish_kit_repo)
+            .envs(optional_envs.into_iter())
             .makefile(makefile_path)
             .project_dir(project.project_dir())
             .exec("publish-kit")
-            .await
+            .await?;
+        publish
+            .run_hooks(&project, &sdk, Stage::PostPublish)
+            .await?;
+
+        output::detail("kit", &self.kit_name);
+        output::detail("vendor", &self.vendor);
+        output::detail("repository", publish_kit_repo);
+        output::detail("version", project.release_version());
+        if !self.tags.is_empty() {
+            output::detail("tags", self.tags.join(","));
+        }
+        Ok(())
+    }
+}
+
+/// Something that twoliter publishes, for the project's publish hooks.
+#[derive(Debug, Clone)]
+pub(super) struct Publish {
+    /// What is published, such as `kit`, or the task that publishes it, such as `ami`.
+    pub(super) kind: String,
+    pub(super) name: String,
+    pub(super) arch: Option<String>,
+    /// Where it is published, and its version, so that publishing it to the same place again
+    /// skips the hooks.
+    pub(super) key: String,
+    /// What the hooks see about where it is published, such as `TWOLITER_HOOK_REPOSITORY`.
+    pub(super) envs: Vec<(&'static str, String)>,
+}
+
+impl Publish {
+    /// A variant's image for `arch`, published by the Makefile task `task` to the TUF repo `repo`
+    /// or its AMIs. The hooks see the repo and the version in `TWOLITER_HOOK_REPOSITORY` and
+    /// `TWOLITER_HOOK_VERSION`.
+    pub(super) fn variant<L: ProjectLock>(
+        project: &Project<L>,
+        task: &str,
+        variant: &str,
+        arch: &str,
+        repo: &str,
+    ) -> Self {
+        let version = project.release_version().to_string();
+        Self {
+            kind: task.to_string(),
+            name: variant.to_string(),
+            arch: Some(arch.to_string()),
+            key: format!("{repo} {version}"),
+            envs: vec![
+                ("TWOLITER_HOOK_REPOSITORY", repo.to_string()),
+                ("TWOLITER_HOOK_VERSION", version),
+            ],
+        }
+    }
+
+    /// Runs the project's hooks for `stage` of this publish. Hooks that run in the SDK use `sdk`.
+    pub(super) async fn run_hooks<L: ProjectLock>(
+        &self,
+        project: &Project<L>,
+        sdk: &str,
+        stage: Stage,
+    ) -> Result<()> {
+        let hooks = project.hooks().at(&[stage]);
+        if hooks.is_empty() {
+            return Ok(());
+        }
+        let root = project.project_dir();
+        let sdk = sdk.to_string();
+        let publish = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let target = Target {
+                root: &root,
+                kind: &publish.kind,
+                name: &publish.name,
+                arch: publish.arch.as_deref(),
+                sdk: &sdk,
+                key: Some(publish.key.clone()),
+                envs: publish.envs.clone(),
+            };
+            hooks.run(stage, &target)
+        })
+        .await??;
+        Ok(())
     }
 }
@@ -0,0 +1,664 @@
+/*!
+`twoliter release` runs every stage of a release of the project's variants: it builds each variant
+for each architecture, boots the images to smoke test them, writes an SBOM of each image, signs
+the checksums of everything it produced, and publishes the images and repos, running the project's
+publish hooks around each publishing task. It finishes by writing a manifest of the release to
+`build/release/<version>/release-manifest.json`.
+
+Each stage is made of steps, one for each variant and architecture, except signing, which covers
+the whole release. A step that finishes is recorded in `build/release/<version>/checkpoint.json`,
+so when a release fails part of the way through, running the same command again resumes it from
+the step that failed.
+*/
+
+use super::build::{latest_images_dir, parse_arches, variant_cargo_make};
+use super::publish_kit::Publish;
+use crate::cargo_make::CargoMake;
+use crate::common::{exec_log, fs};
+use crate::metrics::Metrics;
+use crate::output;
+use crate::progress::Progress;
+use crate::project::{self, BuildsysConfig, Locked};
+use anyhow::{ensure, Context, Result};
+use buildsys::hooks;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::info;
+use uuid::Uuid;
+
+/// Release the project's variants: build, test, write SBOMs, sign, and publish them, resuming
+/// from the last step that failed.
+#[derive(Debug, Parser)]
+pub(crate) struct Release {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The variants to release, separated by commas.
+    #[clap(long = "variant", value_delimiter = ',', required = true)]
+    variants: Vec<String>,
+
+    /// The architectures to release, separated by commas, or `all` for every supported
+    /// architecture.
+    #[clap(long = "arch", default_value = "all")]
+    arch: String,
+
+    /// Path to the Infra.toml file
+    #[clap(long)]
+    infra_toml: Option<PathBuf>,
+
+    /// The cosign key to sign the release's checksums with. Needed unless signing is skipped.
+    #[clap(long = "cosign-key")]
+    cosign_key: Option<PathBuf>,
+
+    /// Stages to leave out of the release, separated by commas.
+    #[clap(long = "skip", value_enum, value_delimiter = ',')]
+    skip: Vec<Stage>,
+
+    /// Forget the steps that finished in earlier runs and start the release over.
+    #[clap(long = "restart")]
+    restart: bool,
+}
+
+/// The stages of a release, in the order they run.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, strum::Display,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub(crate) enum Stage {
+    /// Build the images of each variant for each architecture.
+    Build,
+    /// Boot each image in a local VM and run the smoke tests.
+    Test,
+    /// Write an SPDX SBOM of the packages installed in each image.
+    Sbom,
+    /// Write the checksums of the images and SBOMs, and sign them with cosign.
+    Sign,
+    /// Register each image as an AMI and set its SSM parameters.
+    PublishImages,
+    /// Add each image to its TUF repo and sync the repo.
+    PublishRepos,
+}
+
+impl Release {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        let arches = parse_arches(&self.arch)?;
+        let stages = Stage::value_variants()
+            .iter()
+            .copied()
+            .filter(|stage| !self.skip.contains(stage))
+            .collect::<Vec<_>>();
+        ensure!(
+            !stages.contains(&Stage::Sign) || self.cosign_key.is_some(),
+            "Signing the release needs a key; pass --cosign-key, or --skip sign to release \
+            without signing"
+        );
+
+        let version = project.release_version().to_string();
+        let release_dir = project.project_dir().join("build/release").join(&version);
+        fs::create_dir_all(&release_dir).await?;
+        let mut checkpoint =
+            Checkpoint::load(release_dir.join("checkpoint.json"), self.restart).await?;
+
+        let mut cargo_makes = BTreeMap::new();
+        for variant in &self.variants {
+            let mut cargo_make = variant_cargo_make(&project, variant).await?;
+            if let Some(infra_toml) = &self.infra_toml {
+                cargo_make = cargo_make.env(
+                    "PUBLISH_INFRA_CONFIG_PATH",
+                    infra_toml.display().to_string(),
+                );
+            }
+            cargo_makes.insert(variant.clone(), cargo_make);
+        }
+        // The same default as `Makefile.toml`.
+        let publish_repo = BuildsysConfig::load(project.project_dir())
+            .await?
+            .setting("PUBLISH_REPO")
+            .unwrap_or_else(|| "default".to_string());
+        let pipeline = Pipeline {
+            project: &project,
+            cargo_makes,
+            publish_repo,
+            release_dir: &release_dir,
+            cosign_key: self.cosign_key.as_deref(),
+            arches: &arches,
+        };
+
+        for step in plan(&stages, &self.variants, &arches) {
+            if checkpoint.is_done(&step) {
+                info!("Skipping '{}', which finished in an earlier run", step.id());
+                continue;
+            }
+            info!("Running '{}'", step.id());
+            pipeline.run_step(&step).await.with_context(|| {
+                format!(
+                    "Release step '{}' failed; run the same command again to resume from it",
+                    step.id()
+                )
+            })?;
+            checkpoint.complete(&step).await?;
+        }
+
+        let manifest = ReleaseManifest::new(&pipeline, &version, &stages).await?;
+        let path = release_dir.join("release-manifest.json");
+        let data = serde_json::to_vec_pretty(&manifest)
+            .context("Unable to serialize the release manifest")?;
+        fs::write(&path, data).await?;
+        info!("Wrote the manifest of the release to '{}'", path.display());
+        output::artifact(&path).await;
+        output::detail("release-version", version);
+        Ok(())
+    }
+}
+
+/// One unit of work in a release.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Step {
+    stage: Stage,
+    /// The variant and architecture the step is for, or `None` for a step that covers the whole
+    /// release.
+    target: Option<(String, String)>,
+}
+
+impl Step {
+    /// The name the step is recorded under in the checkpoint, such as `build/aws-dev/x86_64`.
+    fn id(&self) -> String {
+        match &self.target {
+            Some((variant, arch)) => format!("{}/{}/{}", self.stage, variant, arch),
+            None => self.stage.to_string(),
+        }
+    }
+}
+
+/// Lists the steps of `stages` in the order they run. Each stage finishes for every variant and
+/// architecture before the next one starts, so nothing is published until everything passed.
+fn plan(stages: &[Stage], variants: &[String], arches: &[String]) -> Vec<Step> {
+    let mut steps = Vec::new();
+    for &stage in stages {
+        if stage == Stage::Sign {
+            steps.push(Step {
+                stage,
+                target: None,
+            });
+            continue;
+        }
+        for variant in variants {
+            for arch in arches {
+                steps.push(Step {
+                    stage,
+                    target: Some((variant.clone(), arch.clone())),
+                });
+            }
+        }
+    }
+    steps
+}
+
+/// The steps of a release that have finished.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    #[serde(skip)]
+    path: PathBuf,
+    completed: BTreeSet<String>,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint at `path`, or starts a new one if there isn't one or `restart` is set.
+    async fn load(path: PathBuf, restart: bool) -> Result<Self> {
+        if restart || !path.exists() {
+            return Ok(Self {
+                path,
+                ..Default::default()
+            });
+        }
+        let data = fs::read(&path).await?;
+        let checkpoint: Self = serde_json::from_slice(&data)
+            .with_context(|| format!("Unable to parse the checkpoint '{}'", path.display()))?;
+        info!(
+            "Resuming the release with {} steps already done; pass --restart to start over",
+            checkpoint.completed.len()
+        );
+        Ok(Self { path, ..checkpoint })
+    }
+
+    fn is_done(&self, step: &Step) -> bool {
+        self.completed.contains(&step.id())
+    }
+
+    /// Records that `step` finished, and saves the checkpoint.
+    async fn complete(&mut self, step: &Step) -> Result<()> {
+        self.completed.insert(step.id());
+        let data = serde_json::to_vec_pretty(self).context("Unable to serialize the checkpoint")?;
+        fs::write(&self.path, data).await
+    }
+}
+
+/// What the steps of a release share.
+struct Pipeline<'a> {
+    project: &'a project::Project<Locked>,
+    /// The `CargoMake` for each variant, which only lacks the architecture.
+    cargo_makes: BTreeMap<String, CargoMake>,
+    /// The TUF repo that the images are published to, which the publish hooks see.
+    publish_repo: String,
+    release_dir: &'a Path,
+    cosign_key: Option<&'a Path>,
+    arches: &'a [String],
+}
+
+impl Pipeline<'_> {
+    async fn run_step(&self, step: &Step) -> Result<()> {
+        // Signing is the only step that covers the whole release.
+        let Some((variant, arch)) = &step.target else {
+            return self.sign().await;
+        };
+        let cargo_make = self.cargo_makes[variant].clone().env("BUILDSYS_ARCH", arch);
+        match step.stage {
+            Stage::Build => self.build(&cargo_make).await,
+            Stage::Test => cargo_make.exec("test-local").await,
+            Stage::Sbom => self.write_sbom(variant, arch).await,
+            Stage::Sign => self.sign().await,
+            Stage::PublishImages => {
+                self.publish(&cargo_make, "ami", variant, arch).await?;
+                self.publish(&cargo_make, "ssm", variant, arch).await
+            }
+            Stage::PublishRepos => {
+                self.publish(&cargo_make, "repo", variant, arch).await?;
+                self.publish(&cargo_make, "sync-repo", variant, arch).await
+            }
+        }
+    }
+
+    /// Runs the Makefile task `task` that publishes `variant` for `arch`, between the project's
+    /// publish hooks.
+    async fn publish(
+        &self,
+        cargo_make: &CargoMake,
+        task: &str,
+        variant: &str,
+        arch: &str,
+    ) -> Result<()> {
+        let publish = Publish::variant(self.project, task, variant, arch, &self.publish_repo);
+        let sdk = self.project.sdk_image().project_image_uri().to_string();
+        publish
+            .run_hooks(self.project, &sdk, hooks::Stage::PrePublish)
+            .await?;
+        cargo_make.exec(task).await?;
+        publish
+            .run_hooks(self.project, &sdk, hooks::Stage::PostPublish)
+            .await
+    }
+
+    async fn build(&self, cargo_make: &CargoMake) -> Result<()> {
+        let project_dir = self.project.project_dir();
+        let metrics = Metrics::start(
+            self.project.metrics_settings(),
+            &project_dir,
+            "build variant",
+        );
+        let progress = Progress::start(&project_dir);
+        let result = cargo_make
+            .clone()
+            .quiet(progress.captures_output())
+            .exec("build")
+            .await;
+        progress.finish().await;
+        metrics.finish(&result).await;
+        result
+    }
+
+    fn images_dir(&self, variant: &str, arch: &str) -> PathBuf {
+        latest_images_dir(
+            &self.project.project_dir().join("build/images"),
+            arch,
+            variant,
+        )
+    }
+
+    fn sbom_path(&self, variant: &str, arch: &str) -> PathBuf {
+        self.release_dir
+            .join("sbom")
+            .join(format!("{}-{}.spdx.json", variant, arch))
+    }
+
+    /// Writes an SBOM of the packages in the image, from the inventory the build left next to it.
+    async fn write_sbom(&self, variant: &str, arch: &str) -> Result<()> {
+        let inventory_path = self
+            .images_dir(variant, arch)
+            .join("application-inventory.json");
+        let inventory: Inventory = serde_json::from_slice(&fs::read(&inventory_path).await?)
+            .with_context(|| {
+                format!(
+                    "Unable to parse the inventory '{}'",
+                    inventory_path.display()
+                )
+            })?;
+        let name = format!("{}-{}-{}", variant, arch, self.project.release_version());
+        let document = spdx_document(&name, &chrono::Utc::now().to_rfc3339(), &inventory);
+        let path = self.sbom_path(variant, arch);
+        fs::create_dir_all(self.release_dir.join("sbom")).await?;
+        let data = serde_json::to_vec_pretty(&document).context("Unable to serialize the SBOM")?;
+        fs::write(&path, data).await?;
+        output::artifact(&path).await;
+        Ok(())
+    }
+
+    /// Writes the checksums of every image and SBOM in the release to `SHA256SUMS`, with paths
+    /// relative to the project, and signs it with cosign.
+    async fn sign(&self) -> Result<()> {
+        let project_dir = fs::canonicalize(self.project.project_dir()).await?;
+        let mut files = Vec::new();
+        for variant in self.cargo_makes.keys() {
+            for arch in self.arches {
+                let images_dir = fs::canonicalize(self.images_dir(variant, arch)).await?;
+                files.extend(image_files(&images_dir).await?.into_keys());
+                let sbom = self.sbom_path(variant, arch);
+                if sbom.exists() {
+                    files.push(fs::canonicalize(sbom).await?);
+                }
+            }
+        }
+
+        let mut checksums = String::new();
+        for file in files {
+            let digest = tokio::task::spawn_blocking({
+                let file = file.clone();
+                move || sha256(&file)
+            })
+            .await??;
+            let relative = file.strip_prefix(&project_dir).unwrap_or(&file);
+            checksums.push_str(&format!("{}  {}\n", digest, relative.display()));
+        }
+        let sums_path = self.release_dir.join("SHA256SUMS");
+        fs::write(&sums_path, checksums).await?;
+
+        // `sign` only runs when the release has a key.
+        let key = self.cosign_key.context("No key to sign the release with")?;
+        let signature_path = self.release_dir.join("SHA256SUMS.sig");
+        exec_log(
+            Command::new("cosign")
+                .arg("sign-blob")
+                .arg("--yes")
+                .arg("--key")
+                .arg(key)
+                .arg("--output-signature")
+                .arg(&signature_path)
+                .arg(&sums_path),
+        )
+        .await
+        .context("Unable to sign the release checksums")?;
+        output::artifact(&sums_path).await;
+        output::artifact(&signature_path).await;
+        Ok(())
+    }
+}
+
+/// The regular files in an images directory, with their sizes. Symlinks, such as the images'
+/// friendly names, are left out.
+async fn image_files(dir: &Path) -> Result<BTreeMap<PathBuf, u64>> {
+    let mut files = BTreeMap::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Unable to list '{}'", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            files.insert(entry.path(), metadata.len());
+        }
+    }
+    Ok(files)
+}
+
+fn sha256(path: &Path) -> Result<String> {
+    let mut f = std::fs::File::open(path)
+        .with_context(|| format!("Unable to open '{}'", path.display()))?;
+    let mut digest = Sha256::new();
+    std::io::copy(&mut f, &mut digest)
+        .with_context(|| format!("Unable to read '{}'", path.display()))?;
+    Ok(digest
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// The `application-inventory.json` that a build writes next to its images.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Inventory {
+    content: Vec<InventoryPackage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InventoryPackage {
+    name: String,
+    version: String,
+    release: String,
+    #[serde(default)]
+    architecture: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    summary: String,
+}
+
+/// Describes the packages of an inventory as an SPDX 2.3 document.
+fn spdx_document(name: &str, created: &str, inventory: &Inventory) -> serde_json::Value {
+    let packages = inventory
+        .content
+        .iter()
+        .enumerate()
+        .map(|(i, package)| {
+            let version = format!("{}-{}", package.version, package.release);
+            let homepage = if package.url.is_empty() {
+                "NOASSERTION"
+            } else {
+                &package.url
+            };
+            json!({
+                "SPDXID": format!("SPDXRef-Package-{}", i),
+                "name": package.name,
+                "versionInfo": version,
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "homepage": homepage,
+                "summary": package.summary,
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": format!(
+                        "pkg:rpm/bottlerocket/{}@{}?arch={}",
+                        package.name, version, package.architecture
+                    ),
+                }],
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!("urn:uuid:{}", Uuid::new_v4()),
+        "creationInfo": {
+            "created": created,
+            "creators": [format!("Tool: twoliter-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+    })
+}
+
+/// Describes everything a release produced.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ReleaseManifest {
+    version: String,
+    stages: Vec<Stage>,
+    builds: BTreeMap<String, BTreeMap<String, ReleaseBuild>>,
+    checksums: Option<PathBuf>,
+    signature: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ReleaseBuild {
+    /// The directory the build's images are in.
+    images_dir: PathBuf,
+    /// The size in bytes of each file the build produced, by name.
+    files: BTreeMap<String, u64>,
+    sbom: Option<PathBuf>,
+    /// The AMIs the images were registered as, by region, if they were published.
+    amis: Option<serde_json::Value>,
+    /// The SSM parameters that were set for the AMIs, if they were published.
+    ssm_parameters: Option<serde_json::Value>,
+}
+
+impl ReleaseManifest {
+    async fn new(pipeline: &Pipeline<'_>, version: &str, stages: &[Stage]) -> Result<Self> {
+        let mut builds = BTreeMap::new();
+        for variant in pipeline.cargo_makes.keys() {
+            let mut arch_builds = BTreeMap::new();
+            for arch in pipeline.arches {
+                let images_dir = fs::canonicalize(pipeline.images_dir(variant, arch)).await?;
+                let files = image_files(&images_dir)
+                    .await?
+                    .into_iter()
+                    .filter_map(|(path, size)| {
+                        Some((path.file_name()?.to_string_lossy().to_string(), size))
+                    })
+                    .collect::<BTreeMap<_, _>>();
+                let sbom = Some(pipeline.sbom_path(variant, arch)).filter(|path| path.exists());
+                let amis = read_json_with_suffix(&images_dir, &files, "-amis.json").await?;
+                let ssm_parameters =
+                    read_json_with_suffix(&images_dir, &files, "-ssm-params.json").await?;
+                arch_builds.insert(
+                    arch.clone(),
+                    ReleaseBuild {
+                        images_dir,
+                        files,
+                        sbom,
+                        amis,
+                        ssm_parameters,
+                    },
+                );
+            }
+            builds.insert(variant.clone(), arch_builds);
+        }
+        let existing = |name: &str| Some(pipeline.release_dir.join(name)).filter(|p| p.exists());
+        Ok(Self {
+            version: version.to_string(),
+            stages: stages.to_vec(),
+            builds,
+            checksums: existing("SHA256SUMS"),
+            signature: existing("SHA256SUMS.sig"),
+        })
+    }
+}
+
+/// Reads the JSON file among `files` whose name ends with `suffix`, if there is one.
+async fn read_json_with_suffix(
+    dir: &Path,
+    files: &BTreeMap<String, u64>,
+    suffix: &str,
+) -> Result<Option<serde_json::Value>> {
+    let Some(name) = files.keys().find(|name| name.ends_with(suffix)) else {
+        return Ok(None);
+    };
+    let path = dir.join(name);
+    let value = serde_json::from_slice(&fs::read(&path).await?)
+        .with_context(|| format!("Unable to parse '{}'", path.display()))?;
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_plan() {
+        let steps = plan(
+            &[Stage::Build, Stage::Sign, Stage::PublishRepos],
+            &strings(&["aws-dev", "metal-dev"]),
+            &strings(&["x86_64", "aarch64"]),
+        );
+        let ids = steps.iter().map(Step::id).collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                "build/aws-dev/x86_64",
+                "build/aws-dev/aarch64",
+                "build/metal-dev/x86_64",
+                "build/metal-dev/aarch64",
+                "sign",
+                "publish-repos/aws-dev/x86_64",
+                "publish-repos/aws-dev/aarch64",
+                "publish-repos/metal-dev/x86_64",
+                "publish-repos/metal-dev/aarch64",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("checkpoint.json");
+        let steps = plan(
+            &[Stage::Build, Stage::Test],
+            &strings(&["aws-dev"]),
+            &strings(&["x86_64"]),
+        );
+
+        let mut checkpoint = Checkpoint::load(path.clone(), false).await.unwrap();
+        assert!(!checkpoint.is_done(&steps[0]));
+        checkpoint.complete(&steps[0]).await.unwrap();
+
+        let resumed = Checkpoint::load(path.clone(), false).await.unwrap();
+        assert!(resumed.is_done(&steps[0]));
+        assert!(!resumed.is_done(&steps[1]));
+
+        let restarted = Checkpoint::load(path, true).await.unwrap();
+        assert!(!restarted.is_done(&steps[0]));
+    }
+
+    #[test]
+    fn test_spdx_document() {
+        let inventory: Inventory = serde_json::from_value(json!({
+            "Content": [{
+                "Name": "kernel-6.1",
+                "Publisher": "Bottlerocket",
+                "Version": "6.1.90",
+                "Release": "1.1718134234.2d4a1b0c.br1",
+                "Epoch": "0",
+                "Architecture": "x86_64",
+                "Url": "https://www.kernel.org/",
+                "Summary": "The Linux kernel",
+            }]
+        }))
+        .unwrap();
+        let document = spdx_document("aws-dev-x86_64-1.0.0", "2024-01-01T00:00:00Z", &inventory);
+        assert_eq!(document["spdxVersion"], "SPDX-2.3");
+        let packages = document["packages"].as_array().unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(
+            packages[0]["versionInfo"],
+            "6.1.90-1.1718134234.2d4a1b0c.br1"
+        );
+        assert_eq!(
+            packages[0]["externalRefs"][0]["referenceLocator"],
+            "pkg:rpm/bottlerocket/kernel-6.1@6.1.90-1.1718134234.2d4a1b0c.br1?arch=x86_64"
+        );
+    }
+}
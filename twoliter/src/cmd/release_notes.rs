@@ -0,0 +1,296 @@
+use super::diff::{BuildMetadata, KitDiff, VariantDiff, BUILD_METADATA};
+use crate::common::fs;
+use crate::output;
+use crate::project::{self, BuildsysConfig, Locked};
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::info;
+
+/// Write the release notes of the project as Markdown. They combine what changed in each kit in
+/// Twoliter.lock since the last release, and for each variant built for this release in the images
+/// directory, `build/images` unless `BUILDSYS_IMAGES_DIR` moves it, what changed since its build
+/// of the last release, along with the changelogs of its packages. Build the variants with
+/// `--changelog-baseline v<version>` to include the changelogs.
+#[derive(Debug, Parser)]
+pub(crate) struct ReleaseNotes {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The version of the last release, such as `1.20.0`. Its Twoliter.lock is read from the git
+    /// tag `v<version>`, and its variant builds from the images directory.
+    #[clap(long = "since")]
+    since: String,
+
+    /// Only include this variant. May be given more than once. Defaults to every variant built for
+    /// this release.
+    #[clap(long = "variant")]
+    variants: Vec<String>,
+
+    /// Write the release notes to this file, rather than printing them.
+    #[clap(long = "file")]
+    file: Option<PathBuf>,
+}
+
+impl ReleaseNotes {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        let project_dir = project.project_dir();
+        let version = project.release_version();
+
+        let mut notes = String::new();
+        let _ = writeln!(notes, "# Release v{version}");
+        let _ = writeln!(notes, "\nChanges since v{}.", self.since);
+
+        let _ = writeln!(notes, "\n## Kits");
+        let old_kits = project::kit_uris_at(&project_dir, &format!("v{}", self.since)).await?;
+        write_kits(&mut notes, &old_kits, &project.kit_uris()).await?;
+
+        let _ = writeln!(notes, "\n## Variants");
+        let images_dir = BuildsysConfig::load(&project_dir)
+            .await?
+            .images_dir(&project_dir);
+        let builds = find_builds(&images_dir, version, &self.since, &self.variants).await?;
+        if builds.is_empty() {
+            let _ = writeln!(notes, "\nNo variants were built for v{version}.");
+        }
+        for build in &builds {
+            write_variant(&mut notes, build, &self.since).await?;
+        }
+
+        match &self.file {
+            Some(file) => {
+                fs::write(file, notes).await?;
+                info!("Wrote the release notes to '{}'", file.display());
+                output::artifact(file).await;
+            }
+            None => print!("{notes}"),
+        }
+        Ok(())
+    }
+}
+
+/// Writes what changed in each kit, given the image URIs of the kits at the last release and now.
+async fn write_kits(
+    notes: &mut String,
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+) -> Result<()> {
+    if old.is_empty() && new.is_empty() {
+        let _ = writeln!(notes, "\nThe project doesn't depend on any kits.");
+    }
+    for name in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+        match (old.get(name), new.get(name)) {
+            (Some(old), Some(new)) if old == new => {
+                let _ = writeln!(notes, "\n### {name}\n\nUnchanged at `{new}`.");
+            }
+            (Some(old), Some(new)) => {
+                let old = project::inspect_kit(old).await?;
+                let new = project::inspect_kit(new).await?;
+                let _ = write!(
+                    notes,
+                    "\n{}",
+                    demote(&KitDiff::new(&old, &new).report(&old, &new), 1)
+                );
+            }
+            (None, Some(new)) => {
+                let _ = writeln!(notes, "\n### {name}\n\nAdded at `{new}`.");
+            }
+            (Some(old), None) => {
+                let _ = writeln!(notes, "\n### {name}\n\nRemoved; it was `{old}`.");
+            }
+            (None, None) => (),
+        }
+    }
+    Ok(())
+}
+
+/// A variant built for this release, and its build of the last release if it's still around.
+#[derive(Debug)]
+struct VariantBuilds {
+    variant: String,
+    arch: String,
+    /// The directory that holds every build of the variant for the architecture.
+    dir: PathBuf,
+    new: PathBuf,
+    old: Option<PathBuf>,
+}
+
+/// Finds the builds in `images_dir` of `variants`, or of every variant, whose latest build is of
+/// `version`, along with their builds of `since`.
+async fn find_builds(
+    images_dir: &Path,
+    version: &str,
+    since: &str,
+    variants: &[String],
+) -> Result<Vec<VariantBuilds>> {
+    let mut builds = Vec::new();
+    if !images_dir.is_dir() {
+        return Ok(builds);
+    }
+    let read_error = || format!("Unable to read '{}'", images_dir.display());
+    let mut entries = tokio::fs::read_dir(images_dir)
+        .await
+        .with_context(read_error)?;
+    while let Some(entry) = entries.next_entry().await.with_context(read_error)? {
+        let dir = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Each directory is named `<arch>-<variant>`, and architectures don't have dashes.
+        let Some((arch, variant)) = name.split_once('-') else {
+            continue;
+        };
+        if !variants.is_empty() && !variants.iter().any(|v| v == variant) {
+            continue;
+        }
+        let new = dir.join("latest").join(BUILD_METADATA);
+        if !new.is_file() || BuildMetadata::load(&new).await?.version_id != version {
+            continue;
+        }
+        builds.push(VariantBuilds {
+            variant: variant.to_string(),
+            arch: arch.to_string(),
+            old: find_build_of(&dir, since).await?,
+            dir,
+            new,
+        });
+    }
+    builds.sort_by(|a, b| (&a.variant, &a.arch).cmp(&(&b.variant, &b.arch)));
+    Ok(builds)
+}
+
+/// Finds the metadata of the most recent build of `version` in `dir`, whose builds are named
+/// `<version>-<build>`.
+async fn find_build_of(dir: &Path, version: &str) -> Result<Option<PathBuf>> {
+    let prefix = format!("{version}-");
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    let read_error = || format!("Unable to read '{}'", dir.display());
+    let mut entries = tokio::fs::read_dir(dir).await.with_context(read_error)?;
+    while let Some(entry) = entries.next_entry().await.with_context(read_error)? {
+        let metadata = entry.path().join(BUILD_METADATA);
+        if !entry.file_name().to_string_lossy().starts_with(&prefix) || !metadata.is_file() {
+            continue;
+        }
+        let modified = fs::metadata(&metadata)
+            .await?
+            .modified()
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        if newest
+            .as_ref()
+            .map_or(true, |(newest, _)| modified > *newest)
+        {
+            newest = Some((modified, metadata));
+        }
+    }
+    Ok(newest.map(|(_, metadata)| metadata))
+}
+
+/// Writes what changed in a variant since its build of `since`, and the changelogs of its
+/// packages.
+async fn write_variant(notes: &mut String, build: &VariantBuilds, since: &str) -> Result<()> {
+    let new = BuildMetadata::load(&build.new).await?;
+    match &build.old {
+        Some(old) => {
+            let old = BuildMetadata::load(old).await?;
+            let report = VariantDiff::new(&old, &new)?.report(&old, &new);
+            let _ = write!(notes, "\n{}", demote(&report, 1));
+        }
+        None => {
+            let _ = writeln!(
+                notes,
+                "\n### {} ({})\n\nNo build of v{since} was found in '{}' to compare with.",
+                build.variant,
+                build.arch,
+                build.dir.display()
+            );
+        }
+    }
+
+    let changelog = build.dir.join(format!("CHANGELOG-{}.md", build.variant));
+    if changelog.is_file() {
+        let _ = write!(
+            notes,
+            "\n{}",
+            demote(&fs::read_to_string(&changelog).await?, 3)
+        );
+    } else {
+        let _ = writeln!(
+            notes,
+            "\nNo package changelogs were found. Build the variant with `--changelog-baseline \
+             v{since}` to include them."
+        );
+    }
+    Ok(())
+}
+
+/// Moves the headings of a Markdown document down by `levels`, to nest it under another heading.
+fn demote(markdown: &str, levels: usize) -> String {
+    let prefix = "#".repeat(levels);
+    markdown
+        .lines()
+        .map(|line| {
+            if line.starts_with('#') {
+                format!("{prefix}{line}\n")
+            } else {
+                format!("{line}\n")
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_demote() {
+        assert_eq!(
+            demote("# Changes\n\n## glibc\n\n* Fixed a bug\n", 2),
+            "### Changes\n\n#### glibc\n\n* Fixed a bug\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_builds() {
+        let images = TempDir::new().unwrap();
+        let build = |dir: &str, version: &str| {
+            let dir = images.path().join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(
+                dir.join(BUILD_METADATA),
+                format!(
+                    r#"{{"variant": "aws-dev", "arch": "x86_64", "version_id": "{version}",
+                    "build_id": "abc", "packages": []}}"#
+                ),
+            )
+            .unwrap();
+        };
+        build("x86_64-aws-dev/1.20.0-abc", "1.20.0");
+        build("x86_64-aws-dev/1.21.0-def", "1.21.0");
+        build("x86_64-aws-dev/latest", "1.21.0");
+        build("aarch64-aws-k8s/latest", "1.19.0");
+
+        let builds = find_builds(images.path(), "1.21.0", "1.20.0", &[])
+            .await
+            .unwrap();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].variant, "aws-dev");
+        assert_eq!(
+            builds[0].old,
+            Some(
+                images
+                    .path()
+                    .join("x86_64-aws-dev/1.20.0-abc/build-metadata.json")
+            )
+        );
+        let builds = find_builds(images.path(), "1.21.0", "1.20.0", &["aws-k8s".to_string()])
+            .await
+            .unwrap();
+        assert!(builds.is_empty());
+    }
+}
@@ -0,0 +1,380 @@
+use crate::common::exec;
+use crate::output;
+use crate::project;
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::{debug, info};
+
+/// Twoliter's upstream releases.
+const DEFAULT_RELEASES_URL: &str = "https://github.com/bottlerocket-os/twoliter/releases";
+
+/// Upstream releases are signed without a key by twoliter's release workflow, which uploads the
+/// signature and certificate of each archive as a `.bundle` next to it, so the signature is
+/// checked against the identity of the workflow, as it runs for a release tag, instead.
+const UPSTREAM_IDENTITY: &str =
+    r"^https://github\.com/bottlerocket-os/twoliter/\.github/workflows/release\.yml@refs/tags/";
+const UPSTREAM_ISSUER: &str = "https://token.actions.githubusercontent.com";
+
+/// Manage this installation of twoliter
+#[derive(Debug, Parser)]
+pub(crate) enum SelfCommand {
+    Update(SelfUpdate),
+}
+
+impl SelfCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            SelfCommand::Update(command) => command.run().await,
+        }
+    }
+}
+
+/// Replace this twoliter with a release from the releases endpoint in the project's Twoliter.toml,
+/// or from twoliter's upstream releases. The checksum and signature of the release are checked
+/// before the executable is replaced, which happens in a single rename
+#[derive(Debug, Parser)]
+pub(crate) struct SelfUpdate {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent, and use the upstream
+    /// releases if there is none
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Install this version, even if it is older, instead of the latest release
+    #[clap(long = "version")]
+    version: Option<Version>,
+
+    /// Check the signature with this public key instead of the one in Twoliter.toml
+    #[clap(long = "cosign-key")]
+    cosign_key: Option<PathBuf>,
+
+    /// Download and check the release without replacing the executable
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+
+/// The `self-update` table of Twoliter.toml. `releases-url` is laid out like GitHub releases, with
+/// the archives of a version under `download/v<version>/` and those of the latest release under
+/// `latest/download/`. Releases from anywhere but upstream must be signed with the key in
+/// `cosign-key`, a path relative to the project directory.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SelfUpdateSettings {
+    releases_url: Option<String>,
+    cosign_key: Option<PathBuf>,
+}
+
+/// Only the `self-update` table is read, rather than loading the project, so that a project that
+/// requires a newer twoliter can still be used to update it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ProjectFile {
+    #[serde(default)]
+    self_update: SelfUpdateSettings,
+}
+
+impl SelfUpdate {
+    pub(super) async fn run(&self) -> Result<()> {
+        let settings = self.settings().await?;
+        let releases_url = settings
+            .releases_url
+            .as_deref()
+            .unwrap_or(DEFAULT_RELEASES_URL)
+            .trim_end_matches('/');
+        let upstream = releases_url == DEFAULT_RELEASES_URL;
+        let cosign_key = self.cosign_key.clone().or(settings.cosign_key);
+        ensure!(
+            upstream || cosign_key.is_some(),
+            "Releases from '{releases_url}' must be signed with a key; set 'cosign-key' in the \
+             'self-update' table of Twoliter.toml or pass --cosign-key"
+        );
+
+        let current = Version::parse(env!("CARGO_PKG_VERSION"))?;
+        if self.version.as_ref() == Some(&current) {
+            info!("Twoliter is already at version {current}");
+            return Ok(());
+        }
+
+        let exe = std::env::current_exe()
+            .and_then(|exe| exe.canonicalize())
+            .context("Unable to find the twoliter executable")?;
+        let exe_dir = exe
+            .parent()
+            .context("The twoliter executable has no directory")?;
+        // Download next to the executable, so that the new one can be renamed over it.
+        let work_dir = tempfile::Builder::new()
+            .prefix(".twoliter-update")
+            .tempdir_in(exe_dir)
+            .with_context(|| format!("Unable to create a directory in '{}'", exe_dir.display()))?;
+
+        let release = Release::new(releases_url, self.version.as_ref());
+        info!("Downloading '{}'", release.url(""));
+        let archive = download(&release.url(""), work_dir.path(), &release.archive).await?;
+        let checksum = download(&release.url(".sha256"), work_dir.path(), "checksum").await?;
+        check_checksum(&archive, &checksum).await?;
+
+        let verified = match &cosign_key {
+            Some(key) => {
+                let signature = download(&release.url(".sig"), work_dir.path(), "sig").await?;
+                info!("Verifying the signature with '{}'", key.display());
+                exec(
+                    Command::new("cosign")
+                        .args(["verify-blob", "--key"])
+                        .arg(key)
+                        .arg("--signature")
+                        .arg(&signature)
+                        .arg(&archive),
+                    true,
+                )
+                .await
+            }
+            None => {
+                let bundle = download(&release.url(".bundle"), work_dir.path(), "bundle").await?;
+                info!("Verifying the signature of the upstream release");
+                exec(
+                    Command::new("cosign")
+                        .arg("verify-blob")
+                        .arg("--bundle")
+                        .arg(&bundle)
+                        .args(["--certificate-identity-regexp", UPSTREAM_IDENTITY])
+                        .args(["--certificate-oidc-issuer", UPSTREAM_ISSUER])
+                        .arg(&archive),
+                    true,
+                )
+                .await
+            }
+        };
+        verified.context("The signature of the release could not be verified")?;
+
+        let extract_dir = work_dir.path().join("extract");
+        tokio::fs::create_dir(&extract_dir).await?;
+        exec(
+            Command::new("tar")
+                .args(["--extract", "--xz", "--file"])
+                .arg(&archive)
+                .arg("--directory")
+                .arg(&extract_dir),
+            true,
+        )
+        .await
+        .context("Unable to extract the release")?;
+        let new_exe = find_executable(&extract_dir)?;
+
+        let version_output = exec(Command::new(&new_exe).arg("--version"), true)
+            .await?
+            .unwrap_or_default();
+        let version = parse_version_output(&version_output)?;
+        output::detail("version", version.to_string());
+        match &self.version {
+            Some(pinned) => ensure!(
+                *pinned == version,
+                "The release for version {pinned} contains version {version}"
+            ),
+            None if version <= current => {
+                info!("Twoliter {current} is up to date");
+                return Ok(());
+            }
+            None => {}
+        }
+
+        if self.dry_run {
+            info!("Twoliter {version} was downloaded and verified, but not installed");
+            return Ok(());
+        }
+        std::fs::set_permissions(&new_exe, std::fs::Permissions::from_mode(0o755))
+            .context("Unable to make the new twoliter executable")?;
+        std::fs::rename(&new_exe, &exe)
+            .with_context(|| format!("Unable to replace '{}'", exe.display()))?;
+        info!("Updated twoliter from {current} to {version}");
+        Ok(())
+    }
+
+    /// Reads the `self-update` table from the project's Twoliter.toml, if there is a project.
+    async fn settings(&self) -> Result<SelfUpdateSettings> {
+        let path = match &self.project_path {
+            Some(path) => path.clone(),
+            None => match project::find_project_file(".") {
+                Ok(path) => path,
+                Err(e) => {
+                    debug!("Using the upstream releases: {e}");
+                    return Ok(SelfUpdateSettings::default());
+                }
+            },
+        };
+        let text = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Unable to read '{}'", path.display()))?;
+        let mut settings = toml::from_str::<ProjectFile>(&text)
+            .with_context(|| format!("Unable to parse '{}'", path.display()))?
+            .self_update;
+        if let (Some(key), Some(dir)) = (&settings.cosign_key, path.parent()) {
+            settings.cosign_key = Some(dir.join(key));
+        }
+        Ok(settings)
+    }
+}
+
+/// Where the archive of a release for this host is, along with the files that check it, which
+/// are named for the archive.
+#[derive(Debug)]
+struct Release {
+    base: String,
+    archive: String,
+}
+
+impl Release {
+    fn new(releases_url: &str, version: Option<&Version>) -> Self {
+        let base = match version {
+            Some(version) => format!("{releases_url}/download/v{version}"),
+            None => format!("{releases_url}/latest/download"),
+        };
+        Self {
+            base,
+            archive: format!(
+                "twoliter-{}-unknown-linux-musl.tar.xz",
+                std::env::consts::ARCH
+            ),
+        }
+    }
+
+    fn url(&self, suffix: &str) -> String {
+        format!("{}/{}{suffix}", self.base, self.archive)
+    }
+}
+
+/// Downloads `url` to a file named `name` in `dir`.
+async fn download(url: &str, dir: &Path, name: &str) -> Result<PathBuf> {
+    debug!("Downloading '{url}'");
+    let bytes = reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Unable to download '{url}'"))?
+        .bytes()
+        .await
+        .with_context(|| format!("Unable to download '{url}'"))?;
+    let path = dir.join(name);
+    tokio::fs::write(&path, bytes)
+        .await
+        .with_context(|| format!("Unable to write '{}'", path.display()))?;
+    Ok(path)
+}
+
+/// Checks the archive against the checksum file, which has the SHA-256 digest first, optionally
+/// followed by the name of the archive, as `sha256sum` prints it.
+async fn check_checksum(archive: &Path, checksum: &Path) -> Result<()> {
+    let expected = tokio::fs::read_to_string(checksum).await?;
+    let expected = parse_checksum(&expected)?;
+    let data = tokio::fs::read(archive).await?;
+    let actual: String = Sha256::digest(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    ensure!(
+        actual == expected,
+        "The release's SHA-256 digest is {actual}, but its checksum file has {expected}"
+    );
+    Ok(())
+}
+
+fn parse_checksum(text: &str) -> Result<String> {
+    let digest = text.split_whitespace().next().unwrap_or_default();
+    ensure!(
+        digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()),
+        "The checksum file doesn't start with a SHA-256 digest"
+    );
+    Ok(digest.to_ascii_lowercase())
+}
+
+/// Finds the twoliter executable in the extracted archive, which keeps it either at the top or in
+/// a directory named for the archive.
+fn find_executable(dir: &Path) -> Result<PathBuf> {
+    let top = dir.join("twoliter");
+    if top.is_file() {
+        return Ok(top);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path().join("twoliter");
+        if path.is_file() {
+            return Ok(path);
+        }
+    }
+    bail!("The release has no twoliter executable")
+}
+
+/// Parses the output of `twoliter --version`, such as `twoliter 0.6.0`.
+fn parse_version_output(output: &str) -> Result<Version> {
+    let version = output
+        .split_whitespace()
+        .nth(1)
+        .with_context(|| format!("Unable to find a version in '{}'", output.trim()))?;
+    Version::parse(version).with_context(|| format!("Unable to parse version '{version}'"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_release_urls() {
+        let release = Release::new(DEFAULT_RELEASES_URL, Some(&Version::new(0, 6, 0)));
+        let archive = format!(
+            "twoliter-{}-unknown-linux-musl.tar.xz",
+            std::env::consts::ARCH
+        );
+        assert_eq!(
+            release.url(".sha256"),
+            format!("{DEFAULT_RELEASES_URL}/download/v0.6.0/{archive}.sha256")
+        );
+        let latest = Release::new("https://example.com/twoliter", None);
+        assert_eq!(
+            latest.url(""),
+            format!("https://example.com/twoliter/latest/download/{archive}")
+        );
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        let digest = "A".repeat(64);
+        assert_eq!(
+            parse_checksum(&format!("{digest}  twoliter.tar.xz\n")).unwrap(),
+            "a".repeat(64)
+        );
+        assert!(parse_checksum("").is_err());
+        assert!(parse_checksum("abc123  twoliter.tar.xz").is_err());
+    }
+
+    #[test]
+    fn test_parse_version_output() {
+        assert_eq!(
+            parse_version_output("twoliter 0.6.0\n").unwrap(),
+            Version::new(0, 6, 0)
+        );
+        assert!(parse_version_output("twoliter").is_err());
+    }
+
+    #[test]
+    fn test_project_file() {
+        let project: ProjectFile = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [self-update]
+            releases-url = "https://example.com/twoliter"
+            cosign-key = "keys/twoliter.pub"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            project.self_update.releases_url.as_deref(),
+            Some("https://example.com/twoliter")
+        );
+        let project: ProjectFile = toml::from_str("schema-version = 1").unwrap();
+        assert!(project.self_update.releases_url.is_none());
+    }
+}
@@ -0,0 +1,562 @@
+use super::lint;
+use crate::cache::file_digest;
+use crate::common::{exec, BUILDSYS_OUTPUT_GENERATION_ID};
+use crate::output;
+use crate::progress::{self, State};
+use crate::project::{self, BuildsysConfig};
+use anyhow::{ensure, Result};
+use async_walkdir::WalkDir;
+use buildsys::manifest::ManifestInfo;
+use buildsys::BuildType;
+use buildsys_config::{BUILD_INPUTS_DIRECTORY, BUILD_PROGRESS_DIRECTORY};
+use clap::Parser;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// The prefix of the inputs that are the outputs of the packages that a build depends on.
+const DEPENDENCY_INPUT: &str = "dependency/";
+
+/// Show which packages and variants are up to date and which would be rebuilt, and why, by
+/// comparing the inputs that their last successful builds recorded with the project as it is now
+#[derive(Debug, Parser)]
+pub(crate) struct Status {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Only show this variant and the packages that go into it
+    #[clap(long = "variant")]
+    variant: Option<String>,
+
+    /// The architecture whose builds are shown
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+}
+
+/// The inputs of the last successful build of a package or variant, as buildsys records them.
+#[derive(Debug, Deserialize)]
+struct InputsRecord {
+    kind: String,
+    name: String,
+    arch: String,
+    /// The digest or value of each input, by name.
+    inputs: BTreeMap<String, String>,
+    /// Where each file input is, relative to the project directory if it's in it.
+    files: BTreeMap<String, PathBuf>,
+    /// Where each directory input is, like `files`, by the name its files are recorded under.
+    #[serde(default)]
+    dirs: BTreeMap<String, PathBuf>,
+    /// The environment variables that the build watched, as they were set for it.
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
+    /// The packages whose outputs went into the build.
+    #[serde(default)]
+    dependencies: Vec<String>,
+}
+
+/// The value inputs of builds that can be known without building, as they are now.
+#[derive(Debug, Default)]
+struct Current {
+    /// The image ID of the SDK, if it's in the local image store.
+    sdk: Option<String>,
+    /// The settings in `buildsys.toml`, which are read along with the environment.
+    config: BuildsysConfig,
+}
+
+/// What changed since a build last succeeded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Changes {
+    /// The inputs that changed, other than the outputs of packages.
+    inputs: Vec<String>,
+    /// The packages whose outputs changed.
+    dependencies: BTreeSet<String>,
+}
+
+/// Whether a build is up to date, and if not, why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Freshness {
+    UpToDate,
+    /// There is no record of a successful build.
+    NotBuilt,
+    /// The inputs that changed, and the packages it depends on that changed or will be rebuilt.
+    Stale {
+        inputs: Vec<String>,
+        dependencies: BTreeSet<String>,
+    },
+}
+
+impl Status {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project_dir = project.project_dir();
+
+        let mut packages = BTreeSet::new();
+        let mut variants = BTreeMap::new();
+        for manifest in lint::find_manifests(&project_dir).await? {
+            let Ok(info) = ManifestInfo::new(&manifest) else {
+                continue;
+            };
+            match info.build_type() {
+                Ok(BuildType::Package) => {
+                    packages.insert(info.package_name().to_string());
+                }
+                Ok(BuildType::Variant) => {
+                    let name = manifest
+                        .parent()
+                        .and_then(Path::file_name)
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    let included = info.included_packages().cloned().unwrap_or_default();
+                    variants.insert(name, included);
+                }
+                _ => {}
+            }
+        }
+        if let Some(variant) = &self.variant {
+            ensure!(
+                variants.contains_key(variant),
+                "There is no variant named '{variant}' in the project"
+            );
+        }
+
+        let sdk = match project.direct_sdk_image_dep() {
+            Some(Ok(sdk)) => image_id(&sdk.project_image_uri().to_string()).await,
+            _ => None,
+        };
+        let current = Current {
+            sdk,
+            config: BuildsysConfig::load(&project_dir).await?,
+        };
+        let records = read_inputs(&project_dir.join(BUILD_INPUTS_DIRECTORY), &self.arch).await;
+        let mut changes = BTreeMap::new();
+        let mut dependencies = BTreeMap::new();
+        for record in records.values() {
+            changes.insert(
+                record.name.clone(),
+                changed_inputs(&project_dir, record, &current).await,
+            );
+            dependencies.insert(record.name.clone(), record.dependencies.clone());
+        }
+        for (variant, included) in &variants {
+            dependencies
+                .entry(variant.clone())
+                .or_insert_with(|| included.clone());
+        }
+
+        // With --variant, only the packages that go into the variant are shown, including the ones
+        // that they depend on.
+        let shown_variants = variants
+            .keys()
+            .filter(|variant| self.variant.as_ref().map_or(true, |v| v == *variant))
+            .cloned()
+            .collect::<Vec<_>>();
+        let shown_packages = match &self.variant {
+            Some(variant) => closure(variant, &dependencies)
+                .into_iter()
+                .filter(|package| packages.contains(package))
+                .collect(),
+            None => packages.clone(),
+        };
+
+        let mut resolved = BTreeMap::new();
+        let mut builds = Vec::new();
+        for package in &shown_packages {
+            let freshness = resolve(package, &packages, &changes, &dependencies, &mut resolved);
+            builds.push(("package", package.clone(), freshness));
+        }
+        for variant in &shown_variants {
+            // Variants aren't dependencies of anything, so they're resolved like packages that
+            // nothing depends on.
+            let freshness = resolve(variant, &packages, &changes, &dependencies, &mut resolved);
+            builds.push(("variant", variant.clone(), freshness));
+        }
+
+        let durations = progress::read_records(&project_dir.join(BUILD_PROGRESS_DIRECTORY), 0)
+            .await
+            .into_iter()
+            .filter(|record| record.arch == self.arch && record.state == State::Done)
+            .map(|record| {
+                let duration = record.updated.saturating_sub(record.started);
+                ((record.kind, record.name), duration)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let stale = builds
+            .iter()
+            .filter(|(_, _, freshness)| *freshness != Freshness::UpToDate)
+            .collect::<Vec<_>>();
+        let mut estimate = 0;
+        let mut unknown = 0;
+        for (kind, name, _) in &stale {
+            match durations.get(&(kind.to_string(), name.clone())) {
+                Some(duration) => estimate += duration,
+                None => unknown += 1,
+            }
+        }
+        let stale_packages = stale
+            .iter()
+            .filter(|(kind, _, _)| *kind == "package")
+            .count();
+        let stale_variants = stale.len() - stale_packages;
+
+        println!(
+            "On {}, {} of {} packages and {} of {} variants would be rebuilt",
+            self.arch,
+            stale_packages,
+            shown_packages.len(),
+            stale_variants,
+            shown_variants.len()
+        );
+        for (kind, name, freshness) in &stale {
+            println!("  {kind} {name}: {}", describe(freshness));
+        }
+        if !stale.is_empty() {
+            let mut line = format!(
+                "Estimated rebuild time: {}, if built one at a time",
+                progress::format_duration(estimate)
+            );
+            if unknown > 0 {
+                line.push_str(&format!(", and {unknown} with no recorded time"));
+            }
+            println!("{line}");
+        }
+        output::detail("stale-packages", stale_packages.to_string());
+        output::detail("stale-variants", stale_variants.to_string());
+        output::detail("estimated-rebuild-seconds", estimate.to_string());
+        Ok(())
+    }
+}
+
+/// The ID of `image` in the local image store, if it's there.
+async fn image_id(image: &str) -> Option<String> {
+    let output = exec(
+        Command::new("docker").args(["image", "inspect", "--format", "{{.Id}}", image]),
+        true,
+    )
+    .await
+    .ok()??;
+    Some(output.trim().to_string()).filter(|id| !id.is_empty())
+}
+
+/// Reads the inputs that package and variant builds for `arch` recorded in `dir`, by the name of
+/// the build. Records are only read from the file that buildsys writes for them, so that records
+/// left by older versions of buildsys, which didn't name them by kind, are ignored.
+async fn read_inputs(dir: &Path, arch: &str) -> BTreeMap<String, InputsRecord> {
+    let mut records = BTreeMap::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return records;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        // A record that can't be read is treated like a build that never succeeded.
+        let Ok(data) = tokio::fs::read(&path).await else {
+            continue;
+        };
+        let Ok(record) = serde_json::from_slice::<InputsRecord>(&data) else {
+            continue;
+        };
+        let file_name = format!("{}-{}-{}.json", record.kind, record.name, record.arch);
+        if record.arch == arch
+            && matches!(record.kind.as_str(), "package" | "variant")
+            && path
+                .file_name()
+                .is_some_and(|name| name == file_name.as_str())
+        {
+            records.insert(record.name.clone(), record);
+        }
+    }
+    records
+}
+
+/// The inputs of `record` that are no longer the ones the build read: files whose contents
+/// changed, files that were added to its directories, and values that differ from `current`.
+/// Watched variables are only compared when the environment or `buildsys.toml` sets them, since
+/// otherwise they take their defaults from `Makefile.toml`.
+async fn changed_inputs(project_dir: &Path, record: &InputsRecord, current: &Current) -> Changes {
+    let mut changes = Changes::default();
+    for (input, path) in &record.files {
+        let path = project_dir.join(path);
+        let digest = if path.is_file() {
+            file_digest(&path).await.ok()
+        } else {
+            Some("missing".to_string())
+        };
+        if digest.as_ref() != record.inputs.get(input) {
+            changes.add(input);
+        }
+    }
+    for (name, dir) in &record.dirs {
+        for file in list_files(&project_dir.join(dir)).await {
+            let input = format!("file:{name}/{}", file.display());
+            if !record.inputs.contains_key(&input) {
+                changes.add(&input);
+            }
+        }
+    }
+
+    let generation = BUILDSYS_OUTPUT_GENERATION_ID.to_string();
+    let values = [
+        ("sdk", current.sdk.as_ref()),
+        ("output-generation", Some(&generation)),
+    ];
+    for (input, value) in values {
+        if let (Some(value), Some(recorded)) = (value, record.inputs.get(input)) {
+            if value != recorded {
+                changes.add(input);
+            }
+        }
+    }
+    for (var, recorded) in &record.vars {
+        if current
+            .config
+            .setting(var)
+            .is_some_and(|value| value != *recorded)
+        {
+            changes.add(var);
+        }
+    }
+    changes
+}
+
+/// The files in `dir` and its subdirectories, relative to it, without following links.
+async fn list_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut walk = WalkDir::new(dir);
+    while let Some(Ok(entry)) = walk.next().await {
+        if entry
+            .file_type()
+            .await
+            .is_ok_and(|file_type| file_type.is_file())
+        {
+            let path = entry.path();
+            files.push(path.strip_prefix(dir).unwrap_or(&path).to_path_buf());
+        }
+    }
+    files
+}
+
+impl Changes {
+    /// Notes that `input` changed. Inputs from the outputs of a package note the package instead.
+    fn add(&mut self, input: &str) {
+        let name = input.strip_prefix("file:").unwrap_or(input);
+        match name.strip_prefix(DEPENDENCY_INPUT) {
+            Some(dependency) => {
+                let dependency = dependency.split('/').next().unwrap_or(dependency);
+                self.dependencies.insert(dependency.to_string());
+            }
+            None => self.inputs.push(name.to_string()),
+        }
+    }
+}
+
+/// The builds that `name` depends on, directly or through other builds.
+fn closure(name: &str, dependencies: &BTreeMap<String, Vec<String>>) -> BTreeSet<String> {
+    let mut found = BTreeSet::new();
+    let mut queue = vec![name.to_string()];
+    while let Some(next) = queue.pop() {
+        for dependency in dependencies.get(&next).into_iter().flatten() {
+            if found.insert(dependency.clone()) {
+                queue.push(dependency.clone());
+            }
+        }
+    }
+    found
+}
+
+/// Decides whether the build of `name` is up to date. A build with no record of its inputs was
+/// never built. Otherwise it is stale if any of its inputs changed, or if a package it depends on
+/// changed or will be rebuilt. Dependencies that aren't among `packages`, such as the packages of
+/// external kits, are only stale if their outputs changed.
+fn resolve(
+    name: &str,
+    packages: &BTreeSet<String>,
+    changes: &BTreeMap<String, Changes>,
+    dependencies: &BTreeMap<String, Vec<String>>,
+    resolved: &mut BTreeMap<String, Freshness>,
+) -> Freshness {
+    if let Some(freshness) = resolved.get(name) {
+        return freshness.clone();
+    }
+    let Some(changed) = changes.get(name) else {
+        resolved.insert(name.to_string(), Freshness::NotBuilt);
+        return Freshness::NotBuilt;
+    };
+    // Marked up to date while its dependencies are resolved, so that a cycle ends.
+    resolved.insert(name.to_string(), Freshness::UpToDate);
+    let mut stale_dependencies = changed.dependencies.clone();
+    for dependency in dependencies.get(name).into_iter().flatten() {
+        if !packages.contains(dependency) {
+            continue;
+        }
+        let freshness = resolve(dependency, packages, changes, dependencies, resolved);
+        if freshness != Freshness::UpToDate {
+            stale_dependencies.insert(dependency.clone());
+        }
+    }
+    let freshness = if changed.inputs.is_empty() && stale_dependencies.is_empty() {
+        Freshness::UpToDate
+    } else {
+        Freshness::Stale {
+            inputs: changed.inputs.clone(),
+            dependencies: stale_dependencies,
+        }
+    };
+    resolved.insert(name.to_string(), freshness.clone());
+    freshness
+}
+
+/// Why a build would be rebuilt, such as `changed spec; depends on glibc`.
+fn describe(freshness: &Freshness) -> String {
+    match freshness {
+        Freshness::UpToDate => "up to date".to_string(),
+        Freshness::NotBuilt => "not built yet".to_string(),
+        Freshness::Stale {
+            inputs,
+            dependencies,
+        } => {
+            let mut reasons = Vec::new();
+            if !inputs.is_empty() {
+                reasons.push(format!("changed {}", inputs.join(", ")));
+            }
+            if !dependencies.is_empty() {
+                let dependencies = dependencies.iter().cloned().collect::<Vec<_>>();
+                reasons.push(format!("depends on {}", dependencies.join(", ")));
+            }
+            reasons.join("; ")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_freshness() {
+        let packages = BTreeSet::from_iter(names(&["glibc", "libfoo", "hello", "new"]));
+        let changes = BTreeMap::from([
+            (
+                "glibc".to_string(),
+                Changes {
+                    inputs: names(&["spec"]),
+                    dependencies: BTreeSet::new(),
+                },
+            ),
+            ("libfoo".to_string(), Changes::default()),
+            ("hello".to_string(), Changes::default()),
+            ("aws-dev".to_string(), Changes::default()),
+        ]);
+        let dependencies = BTreeMap::from([
+            ("glibc".to_string(), Vec::new()),
+            ("libfoo".to_string(), names(&["glibc", "kernel-6.1"])),
+            ("hello".to_string(), Vec::new()),
+            ("aws-dev".to_string(), names(&["libfoo", "hello", "new"])),
+        ]);
+        let mut resolved = BTreeMap::new();
+        let mut check =
+            |name: &str| resolve(name, &packages, &changes, &dependencies, &mut resolved);
+
+        assert_eq!(describe(&check("glibc")), "changed spec");
+        assert_eq!(describe(&check("libfoo")), "depends on glibc");
+        assert_eq!(check("hello"), Freshness::UpToDate);
+        assert_eq!(check("new"), Freshness::NotBuilt);
+        assert_eq!(describe(&check("aws-dev")), "depends on libfoo, new");
+        assert_eq!(
+            closure("aws-dev", &dependencies),
+            BTreeSet::from_iter(names(&["glibc", "hello", "kernel-6.1", "libfoo", "new"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_changed_inputs() {
+        let project_dir = tempfile::tempdir().unwrap();
+        let spec = project_dir.path().join("glibc.spec");
+        std::fs::write(&spec, "Name: glibc\n").unwrap();
+        let digest = file_digest(&spec).await.unwrap();
+        let rpm = "build/rpms/libfoo/bottlerocket-libfoo.rpm";
+        let rpm_input = "file:dependency/libfoo/bottlerocket-libfoo.rpm".to_string();
+        let record = InputsRecord {
+            kind: "package".to_string(),
+            name: "glibc".to_string(),
+            arch: "x86_64".to_string(),
+            inputs: BTreeMap::from([
+                ("file:spec".to_string(), digest),
+                ("file:source/fix.patch".to_string(), "missing".to_string()),
+                (rpm_input.clone(), "abcd".to_string()),
+                (
+                    "file:dependency/kernel/kernel.rpm".to_string(),
+                    "missing".to_string(),
+                ),
+                ("sdk".to_string(), "sha256:1234".to_string()),
+                ("output-generation".to_string(), "1".to_string()),
+            ]),
+            files: BTreeMap::from([
+                ("file:spec".to_string(), PathBuf::from("glibc.spec")),
+                (
+                    "file:source/fix.patch".to_string(),
+                    PathBuf::from("fix.patch"),
+                ),
+                (rpm_input, PathBuf::from(rpm)),
+            ]),
+            dirs: BTreeMap::from([(
+                "dependency/kernel".to_string(),
+                PathBuf::from("build/rpms/kernel"),
+            )]),
+            vars: BTreeMap::from([
+                (
+                    "BUILDSYS_STATUS_TEST_CHANGED".to_string(),
+                    "old".to_string(),
+                ),
+                ("BUILDSYS_STATUS_TEST_SAME".to_string(), "same".to_string()),
+                ("BUILDSYS_STATUS_TEST_UNSET".to_string(), "old".to_string()),
+            ]),
+            dependencies: names(&["libfoo", "kernel"]),
+        };
+        let mut current = Current {
+            sdk: Some("sha256:1234".to_string()),
+            config: BuildsysConfig::default(),
+        };
+        assert_eq!(
+            changed_inputs(project_dir.path(), &record, &current).await,
+            Changes {
+                inputs: Vec::new(),
+                dependencies: BTreeSet::from_iter(names(&["libfoo"])),
+            }
+        );
+
+        std::fs::write(&spec, "Name: glibc\nVersion: 2.38\n").unwrap();
+        std::fs::write(project_dir.path().join("fix.patch"), "").unwrap();
+        let kernel_dir = project_dir.path().join("build/rpms/kernel");
+        std::fs::create_dir_all(&kernel_dir).unwrap();
+        std::fs::write(kernel_dir.join("kernel-devel.rpm"), "").unwrap();
+        std::fs::write(
+            project_dir.path().join("buildsys.toml"),
+            "status-test-changed = \"new\"\nstatus-test-same = \"same\"\n",
+        )
+        .unwrap();
+        current.sdk = Some("sha256:5678".to_string());
+        current.config = BuildsysConfig::load(project_dir.path()).await.unwrap();
+        assert_eq!(
+            changed_inputs(project_dir.path(), &record, &current).await,
+            Changes {
+                inputs: names(&[
+                    "source/fix.patch",
+                    "spec",
+                    "sdk",
+                    "BUILDSYS_STATUS_TEST_CHANGED"
+                ]),
+                dependencies: BTreeSet::from_iter(names(&["kernel", "libfoo"])),
+            }
+        );
+    }
+}
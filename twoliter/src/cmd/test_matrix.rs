@@ -0,0 +1,521 @@
+/*!
+`twoliter test-matrix` tests several variants on several architectures with several kinds of tests
+at once. Each combination is a cell of the matrix, which runs as its own `cargo make` task: `local`
+boots the image in a local VM with `testsys local`, and any other test type, such as `quick` or
+`conformance`, runs in the testsys cluster with `testsys run --wait`. A retry of a cell in the
+cluster runs `testsys run --wait --retry`, which restarts the tests that failed rather than creating
+them again.
+
+Once every cell has finished, their results are logged as one table, and the command fails if any
+cell that is required failed. Cells can be marked optional, for example for tests that are known to
+be flaky on one architecture.
+
+The `test` table of Twoliter.toml sets how many times cells are retried when they fail, and which
+cells are quarantined. Quarantined cells are run and retried like the rest, but their results are
+logged in a table of their own and never fail the command, so that known-flaky tests stay visible
+without blocking a release.
+*/
+
+use super::build::{parse_arches, variant_cargo_make};
+use crate::cargo_make::CargoMake;
+use crate::output;
+use crate::progress::format_duration;
+use crate::project::{self, Locked, TestSettings};
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use futures::stream::{self, StreamExt};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Write};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// The test type that boots the image in a local VM rather than in the testsys cluster.
+const LOCAL_TEST: &str = "local";
+
+/// Test variants on architectures with test types, several at once, and log the results as one
+/// table. Fails if any cell that isn't marked optional fails.
+#[derive(Debug, Parser)]
+pub(crate) struct TestMatrix {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The variants to test, separated by commas.
+    #[clap(long = "variant", value_delimiter = ',', required = true)]
+    variants: Vec<String>,
+
+    /// The architectures to test, separated by commas, or `all` for every supported architecture.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// The tests to run for each variant and architecture, separated by commas: `local` to boot
+    /// the image in a local VM, or a testsys test type such as `quick`, `conformance` or
+    /// `migration`.
+    #[clap(long = "test", value_delimiter = ',', default_value = "quick")]
+    tests: Vec<String>,
+
+    /// The most cells to test at once.
+    #[clap(long = "jobs", default_value = "4")]
+    jobs: NonZeroUsize,
+
+    /// Cells whose failure doesn't fail the command, separated by commas, as
+    /// `<variant>/<arch>/<test>` where any part may be `*`, such as `*/aarch64/conformance`.
+    #[clap(long = "optional", value_delimiter = ',')]
+    optional: Vec<CellPattern>,
+
+    /// Path to the Infra.toml file
+    #[clap(long)]
+    infra_toml: Option<PathBuf>,
+}
+
+impl TestMatrix {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        let arches = parse_arches(&self.arch)?;
+        ensure!(!self.tests.is_empty(), "No tests were given");
+
+        let mut cargo_makes = BTreeMap::new();
+        for variant in &self.variants {
+            let mut cargo_make = variant_cargo_make(&project, variant).await?;
+            if let Some(infra_toml) = &self.infra_toml {
+                cargo_make = cargo_make.env(
+                    "PUBLISH_INFRA_CONFIG_PATH",
+                    infra_toml.display().to_string(),
+                );
+            }
+            cargo_makes.insert(variant.clone(), cargo_make);
+        }
+
+        let policy = Policy::new(self.optional.clone(), project.test_settings())?;
+        let cells = cells(&self.variants, &arches, &self.tests, &policy);
+        info!(
+            "Testing {} cells, {} at a time",
+            cells.len(),
+            self.jobs.get().min(cells.len())
+        );
+        let mut results = stream::iter(cells)
+            .map(|cell| {
+                let cargo_make = &cargo_makes[&cell.variant];
+                async move {
+                    let start = Instant::now();
+                    let mut attempts = 0;
+                    let result = loop {
+                        attempts += 1;
+                        match run_cell(cargo_make, &cell, attempts > 1).await {
+                            Err(e) if attempts <= cell.retries => {
+                                warn!("Retrying {} after attempt {} failed: {}", cell, attempts, e)
+                            }
+                            result => break result,
+                        }
+                    };
+                    CellResult::new(cell, result, attempts, start.elapsed())
+                }
+            })
+            .buffer_unordered(self.jobs.get())
+            .collect::<Vec<_>>()
+            .await;
+        results.sort_by(|a, b| a.cell.cmp(&b.cell));
+
+        let (quarantined, results): (Vec<_>, Vec<_>) = results
+            .into_iter()
+            .partition(|result| result.cell.quarantined);
+        info!("Test results:\n{}", table(&results));
+        if !quarantined.is_empty() {
+            info!("Quarantined test results:\n{}", table(&quarantined));
+        }
+        for result in results.iter().chain(&quarantined) {
+            let outcome = if result.error.is_none() {
+                "passed"
+            } else {
+                "failed"
+            };
+            let outcome = if result.cell.quarantined {
+                format!("{outcome} (quarantined)")
+            } else {
+                outcome.to_string()
+            };
+            output::detail(format!("test-matrix/{}", result.cell), outcome);
+        }
+
+        let failed = results
+            .iter()
+            .filter(|result| result.error.is_some() && result.cell.required)
+            .map(|result| result.cell.to_string())
+            .collect::<Vec<_>>();
+        if !failed.is_empty() {
+            bail!("Required tests failed: {}", failed.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// Runs the test of one cell, and waits for it to finish. A `retry` restarts the tests in the
+/// cluster that the last attempt left behind.
+async fn run_cell(cargo_make: &CargoMake, cell: &Cell, retry: bool) -> Result<()> {
+    info!("Starting {}", cell);
+    let cargo_make = cargo_make
+        .clone()
+        .env("BUILDSYS_ARCH", &cell.arch)
+        .quiet(true);
+    if cell.test == LOCAL_TEST {
+        cargo_make.exec("test-local").await
+    } else {
+        let args = if retry {
+            vec!["--wait", "--retry"]
+        } else {
+            vec!["--wait"]
+        };
+        cargo_make
+            .env("TESTSYS_TEST", &cell.test)
+            .exec_with_args("test", args)
+            .await
+    }
+}
+
+/// One variant, architecture and test type in the matrix.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Cell {
+    variant: String,
+    arch: String,
+    test: String,
+    /// Whether a failure of this cell fails the command.
+    required: bool,
+    /// Whether the cell is known to be flaky, and reported separately.
+    quarantined: bool,
+    /// How many more times the cell is run if it fails.
+    retries: u32,
+}
+
+impl Display for Cell {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}/{}", self.variant, self.arch, self.test)
+    }
+}
+
+/// Which cells are required, quarantined and retried.
+#[derive(Debug, Default)]
+struct Policy {
+    optional: Vec<CellPattern>,
+    quarantine: Vec<CellPattern>,
+    retries: Vec<(CellPattern, u32)>,
+}
+
+impl Policy {
+    /// The policy for the cells that match `optional`, and the project's test settings.
+    fn new(optional: Vec<CellPattern>, settings: &TestSettings) -> Result<Self> {
+        let context = |pattern: &str| {
+            format!("Invalid test pattern '{pattern}' in the 'test' table of Twoliter.toml")
+        };
+        Ok(Self {
+            optional,
+            quarantine: settings
+                .quarantine
+                .iter()
+                .map(|pattern| {
+                    pattern
+                        .parse::<CellPattern>()
+                        .with_context(|| context(pattern))
+                })
+                .collect::<Result<_>>()?,
+            retries: settings
+                .retries
+                .iter()
+                .map(|(pattern, retries)| {
+                    let parsed = pattern.parse::<CellPattern>();
+                    Ok((parsed.with_context(|| context(pattern))?, *retries))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    fn apply(&self, cell: &mut Cell) {
+        let matches = |patterns: &[CellPattern]| patterns.iter().any(|p| p.matches(cell));
+        let quarantined = matches(&self.quarantine);
+        let optional = matches(&self.optional);
+        cell.quarantined = quarantined;
+        cell.required = !quarantined && !optional;
+        cell.retries = self
+            .retries
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(cell))
+            .map(|(_, retries)| *retries)
+            .max()
+            .unwrap_or(0);
+    }
+}
+
+/// Every combination of the variants, architectures and tests, with `policy` applied to each.
+fn cells(variants: &[String], arches: &[String], tests: &[String], policy: &Policy) -> Vec<Cell> {
+    let mut cells = Vec::new();
+    for variant in variants {
+        for arch in arches {
+            for test in tests {
+                let mut cell = Cell {
+                    variant: variant.clone(),
+                    arch: arch.clone(),
+                    test: test.clone(),
+                    required: true,
+                    quarantined: false,
+                    retries: 0,
+                };
+                policy.apply(&mut cell);
+                cells.push(cell);
+            }
+        }
+    }
+    cells
+}
+
+/// A pattern for cells, as `<variant>/<arch>/<test>` where any part may be `*` to match anything.
+#[derive(Debug, Clone, PartialEq)]
+struct CellPattern {
+    variant: Option<String>,
+    arch: Option<String>,
+    test: Option<String>,
+}
+
+impl CellPattern {
+    fn matches(&self, cell: &Cell) -> bool {
+        let part = |pattern: &Option<String>, value: &str| {
+            pattern.as_deref().map_or(true, |pattern| pattern == value)
+        };
+        part(&self.variant, &cell.variant)
+            && part(&self.arch, &cell.arch)
+            && part(&self.test, &cell.test)
+    }
+}
+
+impl FromStr for CellPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s.split('/').collect::<Vec<_>>();
+        let [variant, arch, test] = parts[..] else {
+            bail!("Expected '<variant>/<arch>/<test>', such as '*/aarch64/conformance', not '{s}'");
+        };
+        let part = |part: &str| (part != "*").then(|| part.to_string());
+        Ok(Self {
+            variant: part(variant),
+            arch: part(arch),
+            test: part(test),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct CellResult {
+    cell: Cell,
+    /// Why the cell failed, if it did.
+    error: Option<String>,
+    /// How many times the cell was run.
+    attempts: u32,
+    duration: Duration,
+}
+
+impl CellResult {
+    fn new(cell: Cell, result: Result<()>, attempts: u32, duration: Duration) -> Self {
+        let error = match result {
+            Ok(()) => {
+                info!("Finished {}", cell);
+                None
+            }
+            Err(e) => {
+                warn!("Failed {}: {:?}", cell, e);
+                Some(e.to_string())
+            }
+        };
+        Self {
+            cell,
+            error,
+            attempts,
+            duration,
+        }
+    }
+}
+
+/// The results as a table with a row for each cell.
+fn table(results: &[CellResult]) -> String {
+    let mut rows = vec![[
+        "VARIANT".to_string(),
+        "ARCH".to_string(),
+        "TEST".to_string(),
+        "RESULT".to_string(),
+        "ATTEMPTS".to_string(),
+        "TIME".to_string(),
+    ]];
+    for result in results {
+        let outcome = match (&result.error, result.cell.required) {
+            (None, _) => "passed".to_string(),
+            (Some(_), true) => "FAILED".to_string(),
+            (Some(_), false) if result.cell.quarantined => "failed (quarantined)".to_string(),
+            (Some(_), false) => "failed (optional)".to_string(),
+        };
+        rows.push([
+            result.cell.variant.clone(),
+            result.cell.arch.clone(),
+            result.cell.test.clone(),
+            outcome,
+            result.attempts.to_string(),
+            format_duration(result.duration.as_secs()),
+        ]);
+    }
+
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.len());
+        }
+    }
+    let mut s = String::new();
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(column, width)| format!("{column:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        let _ = writeln!(s, "{}", line.trim_end());
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_cells() {
+        let policy = Policy {
+            optional: vec!["*/aarch64/conformance".parse().unwrap()],
+            ..Default::default()
+        };
+        let cells = cells(
+            &strings(&["aws-dev", "aws-k8s-1.30"]),
+            &strings(&["x86_64", "aarch64"]),
+            &strings(&["quick", "conformance"]),
+            &policy,
+        );
+        assert_eq!(cells.len(), 8);
+        let optional = cells
+            .iter()
+            .filter(|cell| !cell.required)
+            .map(|cell| cell.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            optional,
+            vec![
+                "aws-dev/aarch64/conformance",
+                "aws-k8s-1.30/aarch64/conformance"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policy() {
+        let settings = TestSettings {
+            retries: BTreeMap::from([
+                ("*/*/conformance".to_string(), 1),
+                ("aws-k8s-1.30/*/conformance".to_string(), 3),
+            ]),
+            quarantine: vec!["aws-k8s-1.30/aarch64/conformance".to_string()],
+        };
+        let policy = Policy::new(Vec::new(), &settings).unwrap();
+        let cells = cells(
+            &strings(&["aws-dev", "aws-k8s-1.30"]),
+            &strings(&["aarch64"]),
+            &strings(&["quick", "conformance"]),
+            &policy,
+        );
+        let summary = cells
+            .iter()
+            .map(|cell| {
+                (
+                    cell.to_string(),
+                    cell.required,
+                    cell.quarantined,
+                    cell.retries,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("aws-dev/aarch64/quick".to_string(), true, false, 0),
+                ("aws-dev/aarch64/conformance".to_string(), true, false, 1),
+                ("aws-k8s-1.30/aarch64/quick".to_string(), true, false, 0),
+                (
+                    "aws-k8s-1.30/aarch64/conformance".to_string(),
+                    false,
+                    true,
+                    3
+                ),
+            ]
+        );
+
+        let settings = TestSettings {
+            quarantine: vec!["aws-dev".to_string()],
+            ..Default::default()
+        };
+        assert!(Policy::new(Vec::new(), &settings).is_err());
+    }
+
+    #[test]
+    fn test_cell_pattern() {
+        assert!("aws-dev/x86_64".parse::<CellPattern>().is_err());
+        assert_eq!(
+            "aws-dev/*/local".parse::<CellPattern>().unwrap(),
+            CellPattern {
+                variant: Some("aws-dev".to_string()),
+                arch: None,
+                test: Some("local".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let cell = |test: &str, required: bool, quarantined: bool| Cell {
+            variant: "aws-dev".to_string(),
+            arch: "x86_64".to_string(),
+            test: test.to_string(),
+            required,
+            quarantined,
+            retries: 1,
+        };
+        let results = [
+            CellResult {
+                cell: cell("quick", true, false),
+                error: None,
+                attempts: 2,
+                duration: Duration::from_secs(125),
+            },
+            CellResult {
+                cell: cell("conformance", false, false),
+                error: Some("Command was unsuccessful".to_string()),
+                attempts: 2,
+                duration: Duration::from_secs(3600),
+            },
+            CellResult {
+                cell: cell("migration", false, true),
+                error: Some("Command was unsuccessful".to_string()),
+                attempts: 2,
+                duration: Duration::from_secs(600),
+            },
+        ];
+        assert_eq!(
+            table(&results),
+            "VARIANT  ARCH    TEST         RESULT                ATTEMPTS  TIME\n\
+             aws-dev  x86_64  quick        passed                2         2m05s\n\
+             aws-dev  x86_64  conformance  failed (optional)     2         1h00m00s\n\
+             aws-dev  x86_64  migration    failed (quarantined)  2         10m00s\n"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::output;
 use crate::project;
 use anyhow::Result;
 use clap::Parser;
@@ -13,7 +14,8 @@ pub(crate) struct Update {
 impl Update {
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
-        project.create_lock().await?;
+        let project = project.create_lock().await?;
+        output::artifact(project.project_dir().join("Twoliter.lock")).await;
         Ok(())
     }
 }
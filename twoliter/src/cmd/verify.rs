@@ -0,0 +1,651 @@
+use super::build::{store_variant, variant_cargo_make};
+use super::lint::{self, Finding};
+use crate::common::fs;
+use crate::docker::SdkRun;
+use crate::metrics::Metrics;
+use crate::output;
+use crate::progress::Progress;
+use crate::project::{self, KitVerifyOptions, Locked};
+use anyhow::{ensure, Context, Result};
+use buildsys::manifest::ManifestInfo;
+use buildsys::spec::SpecInfo;
+use buildsys::BuildType;
+use buildsys_config::ArtifactsDir;
+use clap::Parser;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Group all verify commands
+#[derive(Debug, Parser)]
+pub(crate) enum VerifyCommand {
+    Kit(VerifyKit),
+    Project(VerifyProject),
+    Reproducibility(VerifyReproducibility),
+}
+
+impl VerifyCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            VerifyCommand::Kit(command) => command.run().await,
+            VerifyCommand::Project(command) => command.run().await,
+            VerifyCommand::Reproducibility(command) => command.run().await,
+        }
+    }
+}
+
+/// Verify a kit dependency against Twoliter.lock, including any attached signatures and SBOMs and
+/// the checksums of the RPMs it contains
+#[derive(Debug, Parser)]
+pub(crate) struct VerifyKit {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Name of the kit to verify, as it appears in Twoliter.lock
+    kit_name: String,
+
+    /// Vendor of the kit, needed when more than one vendor provides a kit with the same name
+    #[clap(long = "vendor")]
+    vendor: Option<String>,
+
+    /// Fail if no cosign signature is attached to the kit image
+    #[clap(long = "require-signature")]
+    require_signature: bool,
+
+    /// Fail if no SBOM or attestation is attached to the kit image
+    #[clap(long = "require-sbom")]
+    require_sbom: bool,
+
+    /// Public key used to verify the kit's cosign signature
+    #[clap(long = "cosign-key")]
+    cosign_key: Option<PathBuf>,
+
+    /// Architectures to verify RPMs for. Defaults to every architecture in the kit image
+    #[clap(long = "arch")]
+    arch: Vec<String>,
+}
+
+impl VerifyKit {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let options = KitVerifyOptions {
+            vendor: self.vendor.clone(),
+            require_signature: self.require_signature,
+            require_sbom: self.require_sbom,
+            cosign_key: self.cosign_key.clone(),
+            arches: self.arch.clone(),
+        };
+        project.verify_kit(&self.kit_name, &options).await
+    }
+}
+
+/// Check that the project is consistent without building anything: Twoliter.lock matches
+/// Twoliter.toml and the SDK and kits it names can be resolved, the manifests of packages, kits and
+/// variants can be read, the sources and patches that specs name are in the package or fetched by
+/// it, and the files in `defaults.d` directories exist. Meant to fail fast in CI
+#[derive(Debug, Parser)]
+pub(crate) struct VerifyProject {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Don't resolve the SDK and kits in the registry, and only check the project's files
+    #[clap(long = "offline")]
+    offline: bool,
+}
+
+impl VerifyProject {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project_dir = project.project_dir();
+
+        let mut findings = Vec::new();
+        if !self.offline {
+            if let Err(e) = project.load_lock::<Locked>().await {
+                findings.push(Finding::error("Twoliter.lock", format!("{e:#}")));
+            }
+        }
+        let manifests = lint::find_manifests(&project_dir).await?;
+        for manifest in &manifests {
+            let relative = manifest.strip_prefix(&project_dir).unwrap_or(manifest);
+            match ManifestInfo::new(manifest) {
+                Ok(info) if matches!(info.build_type(), Ok(BuildType::Package)) => {
+                    findings.extend(verify_package(&project_dir, manifest, &info));
+                }
+                Ok(_) => {}
+                Err(e) => findings.push(Finding::error(relative, e.to_string())),
+            }
+        }
+        let defaults_dirs = lint::find_defaults_dirs(&project_dir).await?;
+        for dir in &defaults_dirs {
+            findings.extend(verify_defaults_dir(&project_dir, dir).await?);
+        }
+        findings.sort();
+
+        for finding in &findings {
+            if output::is_json() {
+                output::warning(finding.to_string());
+            } else {
+                println!("{finding}");
+            }
+        }
+        output::detail("errors", findings.len().to_string());
+        info!(
+            "Checked {} manifests and {} defaults directories: {} errors",
+            manifests.len(),
+            defaults_dirs.len(),
+            findings.len()
+        );
+        ensure!(
+            findings.is_empty(),
+            "Found {} errors in the project",
+            findings.len()
+        );
+        Ok(())
+    }
+}
+
+/// Checks that the package in the directory of `manifest` has its spec, and that the sources and
+/// patches that the spec names are either in the package directory or fetched by buildsys.
+fn verify_package(project_dir: &Path, manifest: &Path, info: &ManifestInfo) -> Vec<Finding> {
+    let Some(package_dir) = manifest.parent() else {
+        return Vec::new();
+    };
+    let spec = package_dir.join(format!("{}.spec", info.package_name()));
+    let relative = spec.strip_prefix(project_dir).unwrap_or(&spec);
+    if !spec.is_file() {
+        return vec![Finding::error(relative, "the package has no spec")];
+    }
+    let spec_info = match SpecInfo::new(&spec) {
+        Ok(spec_info) => spec_info,
+        Err(e) => return vec![Finding::error(relative, e.to_string())],
+    };
+    let provided = provided_sources(info);
+    spec_info
+        .sources
+        .iter()
+        .map(|source| ("source", source))
+        .chain(spec_info.patches.iter().map(|patch| ("patch", patch)))
+        .filter(|(_, file)| !provided.contains(*file) && !package_dir.join(file).is_file())
+        .map(|(kind, file)| {
+            Finding::error(
+                relative,
+                format!(
+                    "{kind} '{}' is not in the package directory or its external-files",
+                    file.display()
+                ),
+            )
+        })
+        .collect()
+}
+
+/// The files that buildsys puts in the package directory before the package is built: external
+/// files and the bundles made from them.
+fn provided_sources(info: &ManifestInfo) -> BTreeSet<PathBuf> {
+    let mut provided = BTreeSet::new();
+    for file in info.external_files().into_iter().flatten() {
+        let url_name = file.url.split(['?', '#']).next().unwrap_or_default();
+        let name = match &file.path {
+            Some(path) => path.clone(),
+            None => PathBuf::from(url_name.rsplit('/').next().unwrap_or_default()),
+        };
+        if file.bundle_modules.is_some() {
+            provided.insert(match &file.bundle_output_path {
+                Some(path) => path.clone(),
+                None => PathBuf::from(format!("bundled-{}", name.display())),
+            });
+        }
+        provided.insert(name);
+    }
+    provided
+}
+
+/// Checks that each entry of a `defaults.d` directory is a file, since they are often links to
+/// the defaults of other variants, which break when those are renamed.
+async fn verify_defaults_dir(project_dir: &Path, dir: &Path) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Unable to list '{}'", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.is_file() {
+            continue;
+        }
+        let relative = path.strip_prefix(project_dir).unwrap_or(&path);
+        let message = match tokio::fs::read_link(&path).await {
+            Ok(target) => format!("links to '{}', which does not exist", target.display()),
+            Err(_) if path.is_dir() => "is a directory, which storewolf ignores".to_string(),
+            Err(_) => "is not a file".to_string(),
+        };
+        findings.push(Finding::error(relative, message));
+    }
+    Ok(findings)
+}
+
+/// Build a variant twice from a clean tree, and report which of its RPMs and images differ between
+/// the builds. Both builds are reproducible builds, as with `twoliter build variant
+/// --reproducible`. With `--reference`, the variant is built once and compared against the
+/// artifacts of an earlier build instead. The comparison is written to
+/// `build/reproducibility/<variant>/<arch>/report.json`, and the command fails if any file differs.
+#[derive(Debug, Parser)]
+pub(crate) struct VerifyReproducibility {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The variant to build
+    variant: String,
+
+    /// The architecture to build for
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// An artifacts directory from an earlier build, as written by `twoliter build variant
+    /// --artifacts-dir`, to compare a single build against
+    #[clap(long = "reference")]
+    reference: Option<PathBuf>,
+
+    /// Run diffoscope in the SDK on each pair of files that differ, and keep its reports next to
+    /// the comparison
+    #[clap(long = "diffoscope")]
+    diffoscope: bool,
+}
+
+impl VerifyReproducibility {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        let work_dir = project
+            .project_dir()
+            .join("build/reproducibility")
+            .join(&self.variant)
+            .join(&self.arch);
+        fs::remove_dir_all(&work_dir).await?;
+
+        let reference = match &self.reference {
+            Some(dir) => ArtifactsDir::new(fs::canonicalize(dir).await?),
+            None => self.build(&project, &work_dir.join("first")).await?,
+        };
+        let build = self.build(&project, &work_dir.join("second")).await?;
+
+        let comparison = Comparison {
+            variant: &self.variant,
+            arch: &self.arch,
+            reference: reference.root(),
+            build: build.root(),
+            differences: compare(
+                &self.digests(&reference).await?,
+                &self.digests(&build).await?,
+            ),
+        };
+        let report = work_dir.join("report.json");
+        fs::write(
+            &report,
+            serde_json::to_vec_pretty(&comparison)
+                .context("Unable to serialize the reproducibility report")?,
+        )
+        .await?;
+        output::artifact(&report).await;
+        if !output::is_json() {
+            print!("{}", comparison.report());
+        }
+
+        if self.diffoscope && !comparison.differences.is_empty() {
+            self.explain(&project, &comparison, &work_dir.join("diffoscope"))
+                .await?;
+        }
+
+        ensure!(
+            comparison.differences.is_empty(),
+            "{} files differ between the builds of {} for {}; see '{}'",
+            comparison.differences.len(),
+            self.variant,
+            self.arch,
+            report.display()
+        );
+        info!(
+            "The builds of {} for {} are identical",
+            self.variant, self.arch
+        );
+        Ok(())
+    }
+
+    /// Cleans the project and builds the variant reproducibly, storing its artifacts under `root`.
+    async fn build(&self, project: &project::Project<Locked>, root: &Path) -> Result<ArtifactsDir> {
+        info!(
+            "Building {} for {} from a clean tree",
+            self.variant, self.arch
+        );
+        fs::create_dir_all(root).await?;
+        let artifacts_dir = ArtifactsDir::new(fs::canonicalize(root).await?);
+
+        // Nothing from an earlier build may be reused. Cleaning removes the tools, so they are
+        // installed again for the build.
+        variant_cargo_make(project, &self.variant)
+            .await?
+            .exec("clean")
+            .await?;
+        let cargo_make = variant_cargo_make(project, &self.variant)
+            .await?
+            .env("BUILDSYS_ARCH", &self.arch)
+            .env("BUILDSYS_REPRODUCIBLE", "true");
+
+        let metrics = Metrics::start(
+            project.metrics_settings(),
+            project.project_dir(),
+            "verify reproducibility",
+        );
+        let progress = Progress::start(project.project_dir());
+        let result = cargo_make
+            .quiet(progress.captures_output())
+            .exec("build")
+            .await;
+        progress.finish().await;
+        metrics.finish(&result).await;
+        result?;
+
+        store_variant(
+            &project.project_dir(),
+            &artifacts_dir,
+            &self.variant,
+            &self.arch,
+        )
+        .await?;
+        Ok(artifacts_dir)
+    }
+
+    /// The digest of each RPM and image in an artifacts directory, by its path in the directory.
+    async fn digests(&self, artifacts_dir: &ArtifactsDir) -> Result<BTreeMap<String, String>> {
+        let mut digests = BTreeMap::new();
+        for (kind, dir) in [
+            (
+                "images",
+                artifacts_dir.images_dir(&self.variant, &self.arch),
+            ),
+            ("rpms", artifacts_dir.rpms_dir(&self.variant, &self.arch)),
+        ] {
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("Unable to list '{}'", dir.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let name = format!("{kind}/{}", entry.file_name().to_string_lossy());
+                digests.insert(name, file_digest(path).await?);
+            }
+        }
+        Ok(digests)
+    }
+
+    /// Runs diffoscope on each file that is in both builds but differs, and writes what it finds
+    /// to `<dir>/<path>.txt`.
+    async fn explain(
+        &self,
+        project: &project::Project<Locked>,
+        comparison: &Comparison<'_>,
+        dir: &Path,
+    ) -> Result<()> {
+        let sdk = SdkRun::new(
+            project.sdk_image().project_image_uri().to_string(),
+            project.project_dir(),
+            &self.arch,
+        )
+        .volume(comparison.reference, "/reproducibility/reference")
+        .volume(comparison.build, "/reproducibility/build");
+        let artifacts_dir = format!("{}/{}", self.variant, self.arch);
+
+        for difference in &comparison.differences {
+            if difference.reference.is_none() || difference.build.is_none() {
+                continue;
+            }
+            let path = &difference.path;
+            info!("Running diffoscope on {}", path);
+            let diffoscope = sdk
+                .output(&[
+                    "diffoscope".to_string(),
+                    "--text".to_string(),
+                    "-".to_string(),
+                    format!("/reproducibility/reference/{artifacts_dir}/{path}"),
+                    format!("/reproducibility/build/{artifacts_dir}/{path}"),
+                ])
+                .await?;
+            // diffoscope exits with 1 when the files differ, and 2 when it fails.
+            if !matches!(diffoscope.status.code(), Some(0) | Some(1)) {
+                output::warning(format!(
+                    "diffoscope was unable to compare {}: {}",
+                    path,
+                    String::from_utf8_lossy(&diffoscope.stderr).trim()
+                ));
+                continue;
+            }
+            let report = dir.join(format!("{path}.txt"));
+            if let Some(parent) = report.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::write(&report, &diffoscope.stdout).await?;
+            output::artifact(&report).await;
+        }
+        Ok(())
+    }
+}
+
+/// The SHA-256 digest of a file, or where it points if it's a symlink, such as the images that
+/// point to the versioned image files.
+async fn file_digest(path: PathBuf) -> Result<String> {
+    if let Ok(target) = tokio::fs::read_link(&path).await {
+        return Ok(format!("symlink:{}", target.display()));
+    }
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        let mut f =
+            std::fs::File::open(&path).context(format!("failed to open '{}'", path.display()))?;
+        let mut digest = Sha256::new();
+        std::io::copy(&mut f, &mut digest)
+            .context(format!("failed to read '{}'", path.display()))?;
+        Ok(digest
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    })
+    .await?
+}
+
+/// The outcome of comparing two builds of a variant.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Comparison<'a> {
+    variant: &'a str,
+    arch: &'a str,
+    /// The artifacts directory of the first build, or of the reference build.
+    reference: &'a Path,
+    /// The artifacts directory of the build that was compared against it.
+    build: &'a Path,
+    differences: Vec<Difference>,
+}
+
+impl Comparison<'_> {
+    /// Describes the comparison as Markdown.
+    fn report(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# Reproducibility of {} for {}\n",
+            self.variant, self.arch
+        );
+        let _ = writeln!(out, "* Reference: `{}`", self.reference.display());
+        let _ = writeln!(out, "* Build: `{}`\n", self.build.display());
+        if self.differences.is_empty() {
+            let _ = writeln!(out, "Every RPM and image is identical.");
+            return out;
+        }
+        let _ = writeln!(out, "| File | Reference | Build |");
+        let _ = writeln!(out, "| --- | --- | --- |");
+        for difference in &self.differences {
+            let _ = writeln!(
+                out,
+                "| `{}` | {} | {} |",
+                difference.path,
+                short_digest(difference.reference.as_deref()),
+                short_digest(difference.build.as_deref())
+            );
+        }
+        out
+    }
+}
+
+/// A file that differs between two builds, with its digest in each. A digest is `None` when the
+/// file is missing from that build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Difference {
+    path: String,
+    reference: Option<String>,
+    build: Option<String>,
+}
+
+/// Finds the files that are only in one build, or whose digests differ.
+fn compare(
+    reference: &BTreeMap<String, String>,
+    build: &BTreeMap<String, String>,
+) -> Vec<Difference> {
+    reference
+        .keys()
+        .chain(build.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|path| reference.get(*path) != build.get(*path))
+        .map(|path| Difference {
+            path: path.clone(),
+            reference: reference.get(path).cloned(),
+            build: build.get(path).cloned(),
+        })
+        .collect()
+}
+
+fn short_digest(digest: Option<&str>) -> String {
+    match digest {
+        None => "missing".to_string(),
+        Some(digest) if digest.starts_with("symlink:") => format!("`{digest}`"),
+        Some(digest) => format!("`{}`", &digest[..digest.len().min(12)]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn digests(files: &[(&str, &str)]) -> BTreeMap<String, String> {
+        files
+            .iter()
+            .map(|(path, digest)| (path.to_string(), digest.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_compare() {
+        let reference = digests(&[
+            ("images/root.ext4.lz4", "aaaa"),
+            ("rpms/glibc.rpm", "bbbb"),
+            ("rpms/kernel.rpm", "cccc"),
+        ]);
+        let build = digests(&[
+            ("images/root.ext4.lz4", "aaaa"),
+            ("images/boot.ext4.lz4", "dddd"),
+            ("rpms/glibc.rpm", "eeee"),
+        ]);
+        assert_eq!(
+            compare(&reference, &build),
+            vec![
+                Difference {
+                    path: "images/boot.ext4.lz4".to_string(),
+                    reference: None,
+                    build: Some("dddd".to_string()),
+                },
+                Difference {
+                    path: "rpms/glibc.rpm".to_string(),
+                    reference: Some("bbbb".to_string()),
+                    build: Some("eeee".to_string()),
+                },
+                Difference {
+                    path: "rpms/kernel.rpm".to_string(),
+                    reference: Some("cccc".to_string()),
+                    build: None,
+                },
+            ]
+        );
+        assert!(compare(&reference, &reference).is_empty());
+    }
+
+    #[test]
+    fn test_report() {
+        let differences = vec![Difference {
+            path: "rpms/glibc.rpm".to_string(),
+            reference: Some("0123456789abcdef".to_string()),
+            build: None,
+        }];
+        let comparison = Comparison {
+            variant: "aws-dev",
+            arch: "x86_64",
+            reference: Path::new("/first"),
+            build: Path::new("/second"),
+            differences,
+        };
+        let report = comparison.report();
+        assert!(report.contains("# Reproducibility of aws-dev for x86_64"));
+        assert!(report.contains("| `rpms/glibc.rpm` | `0123456789ab` | missing |"));
+    }
+
+    #[test]
+    fn test_verify_package() {
+        let project_dir = tempfile::tempdir().unwrap();
+        let package_dir = project_dir.path().join("packages/hello");
+        std::fs::create_dir_all(&package_dir).unwrap();
+        let manifest = package_dir.join("Cargo.toml");
+        let info: ManifestInfo = toml::from_str(
+            r#"
+            [package]
+            name = "hello"
+
+            [package.metadata.build-package]
+            generate-changelog = true
+
+            [[package.metadata.build-package.external-files]]
+            url = "https://example.com/releases/hello-1.0.tar.gz?download=1"
+            sha512 = "abcd"
+            bundle-modules = ["go"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            provided_sources(&info),
+            BTreeSet::from([
+                PathBuf::from("bundled-hello-1.0.tar.gz"),
+                PathBuf::from("hello-1.0.tar.gz"),
+            ])
+        );
+
+        let spec = "packages/hello/hello.spec";
+        assert_eq!(
+            verify_package(project_dir.path(), &manifest, &info),
+            vec![Finding::error(spec, "the package has no spec")]
+        );
+        std::fs::write(
+            package_dir.join("hello.spec"),
+            "Source0: hello-1.0.tar.gz\nSource1: bundled-hello-1.0.tar.gz\n\
+             Source2: hello.service\nSource3: %{name}.conf\nPatch0001: 0001-fix.patch\n",
+        )
+        .unwrap();
+        std::fs::write(package_dir.join("hello.service"), "").unwrap();
+        assert_eq!(
+            verify_package(project_dir.path(), &manifest, &info),
+            vec![Finding::error(
+                spec,
+                "patch '0001-fix.patch' is not in the package directory or its external-files"
+            )]
+        );
+    }
+}
@@ -1,6 +1,8 @@
+use crate::interrupt;
 use anyhow::{ensure, Context, Result};
-use log::{self, LevelFilter};
+use buildsys::redact;
 use tokio::process::Command;
+use tracing::level_filters::LevelFilter;
 use tracing::{debug, instrument};
 
 /// This is passed as an environment variable to Buildsys. Buildsys tells Cargo to watch this
@@ -13,10 +15,7 @@ pub(crate) const BUILDSYS_OUTPUT_GENERATION_ID: u32 = 1;
 /// Pipes stdout/stderr when logging `LevelFilter` is more verbose than `Warn`.
 #[instrument(level = "trace", skip(cmd))]
 pub(crate) async fn exec_log(cmd: &mut Command) -> Result<()> {
-    let quiet = matches!(
-        log::max_level(),
-        LevelFilter::Off | LevelFilter::Error | LevelFilter::Warn
-    );
+    let quiet = LevelFilter::current() <= LevelFilter::WARN;
     exec(cmd, quiet).await?;
     Ok(())
 }
@@ -24,21 +23,27 @@ pub(crate) async fn exec_log(cmd: &mut Command) -> Result<()> {
 /// Run a `tokio::process::Command` and return a `Result` letting us know whether or not it worked.
 /// `quiet` determines whether or not the command output will be piped to `stdout/stderr`. When
 /// `quiet=true`, no output will be shown and will be returned instead.
-#[instrument(level = "trace")]
+#[instrument(level = "trace", skip(cmd))]
 pub(crate) async fn exec(cmd: &mut Command, quiet: bool) -> Result<Option<String>> {
-    debug!("Running: {:?}", cmd);
+    // Commands are given credentials in their environment and arguments, which aren't logged.
+    debug!("Running: {}", redact::text(&format!("{:?}", cmd)));
+    let _running = interrupt::Running::start();
     Ok(if quiet {
         // For quiet levels of logging we capture stdout and stderr
         let output = cmd
             .output()
             .await
             .context("Unable to start command".to_string())?;
+        ensure!(
+            output.status.success() || !interrupt::interrupted(),
+            interrupt::Interrupted
+        );
         ensure!(
             output.status.success(),
             "Command was unsuccessful, exit code {}:\n{}\n{}",
             output.status.code().unwrap_or(1),
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
+            redact::text(&String::from_utf8_lossy(&output.stdout)),
+            redact::text(&String::from_utf8_lossy(&output.stderr))
         );
 
         Some(
@@ -46,12 +51,20 @@ pub(crate) async fn exec(cmd: &mut Command, quiet: bool) -> Result<Option<String
                 .context("Unable to convert command output to `String`")?,
         )
     } else {
-        // For less quiet log levels we stream to stdout and stderr.
+        // For less quiet log levels we stream to stdout and stderr. Stdout is kept for the
+        // command's report when it is printed as JSON.
+        if crate::output::is_json() {
+            cmd.stdout(std::process::Stdio::from(std::io::stderr()));
+        }
         let status = cmd
             .status()
             .await
             .context("Unable to start command".to_string())?;
 
+        ensure!(
+            status.success() || !interrupt::interrupted(),
+            interrupt::Interrupted
+        );
         ensure!(
             status.success(),
             "Command was unsuccessful, exit code {}",
@@ -0,0 +1,251 @@
+/*!
+After a variant is built, the builds that decided how long it took are reported, so that we know
+which packages are worth making faster or caching. Cargo runs builds as soon as their dependencies
+are done, so the variant can't be built faster than its critical path: the chain of builds, each
+depending on the one before it, that took the longest in total. The builds that took the longest
+on their own are reported as well, since they hold up the rest when the host has few cores.
+
+Build times come from the progress records that buildsys leaves in `build/progress`, and the order
+of the builds from the Cargo dependencies of the packages, kits and variants in the project.
+Builds that Cargo skipped because they were up to date are taken to have taken no time at all.
+*/
+
+use crate::progress::{format_duration, Record, State};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The number of the longest builds to report.
+const TOP_BUILDS: usize = 5;
+
+/// A build, by its kind as buildsys records it and its name, such as `("package", "glibc")`.
+pub(crate) type BuildId = (String, String);
+
+/// The builds that each build depends on, which are built before it.
+pub(crate) type Dependencies = BTreeMap<BuildId, BTreeSet<BuildId>>;
+
+/// The critical path and longest builds of one architecture.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CriticalPath {
+    arch: String,
+    /// The builds on the critical path, in the order they ran.
+    path: Vec<Step>,
+    duration_secs: u64,
+    /// The builds that took the longest, longest first.
+    longest: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Step {
+    kind: String,
+    name: String,
+    duration_secs: u64,
+}
+
+impl CriticalPath {
+    /// Finds the critical path through the builds for `arch` in `records`, or nothing if none of
+    /// them ran.
+    pub(crate) fn new(records: &[Record], dependencies: &Dependencies, arch: &str) -> Option<Self> {
+        let durations = records
+            .iter()
+            .filter(|r| r.arch == arch && r.state != State::Cached)
+            .map(|r| {
+                (
+                    (r.kind.clone(), r.name.clone()),
+                    r.updated.saturating_sub(r.started),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        if !records
+            .iter()
+            .any(|r| r.arch == arch && r.state == State::Done)
+        {
+            return None;
+        }
+
+        let mut finder = PathFinder {
+            durations: &durations,
+            dependencies,
+            longest: BTreeMap::new(),
+        };
+        let ends = durations
+            .keys()
+            .chain(dependencies.keys())
+            .collect::<Vec<_>>();
+        let mut end = None;
+        let mut duration_secs = 0;
+        for id in ends {
+            let total = finder.longest_to(id, &mut BTreeSet::new());
+            if end.is_none() || total > duration_secs {
+                end = Some(id.clone());
+                duration_secs = total;
+            }
+        }
+
+        // Walk back from the end of the path through the dependency that finished last.
+        let mut path = Vec::new();
+        let mut next = end;
+        while let Some(id) = next {
+            next = finder
+                .longest
+                .get(&id)
+                .and_then(|(_, before)| before.clone());
+            if let Some(&duration_secs) = durations.get(&id) {
+                path.push(Step::new(&id, duration_secs));
+            }
+        }
+        path.reverse();
+
+        let mut longest = durations
+            .iter()
+            .map(|(id, &duration_secs)| Step::new(id, duration_secs))
+            .collect::<Vec<_>>();
+        longest.sort_by(|a, b| b.duration_secs.cmp(&a.duration_secs));
+        longest.truncate(TOP_BUILDS);
+
+        Some(Self {
+            arch: arch.to_string(),
+            path,
+            duration_secs,
+            longest,
+        })
+    }
+
+    /// A summary to log, such as `Critical path for x86_64 (45m10s): package glibc (5m02s) ->
+    /// package kernel-6.1 (38m55s) -> variant aws-dev (1m13s)`.
+    pub(crate) fn summary(&self) -> String {
+        let steps = |steps: &[Step]| {
+            steps
+                .iter()
+                .map(|step| {
+                    format!(
+                        "{} {} ({})",
+                        step.kind,
+                        step.name,
+                        format_duration(step.duration_secs)
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        format!(
+            "Critical path for {} ({}): {}; longest builds: {}",
+            self.arch,
+            format_duration(self.duration_secs),
+            steps(&self.path).join(" -> "),
+            steps(&self.longest).join(", ")
+        )
+    }
+}
+
+impl Step {
+    fn new((kind, name): &BuildId, duration_secs: u64) -> Self {
+        Self {
+            kind: kind.clone(),
+            name: name.clone(),
+            duration_secs,
+        }
+    }
+}
+
+struct PathFinder<'a> {
+    durations: &'a BTreeMap<BuildId, u64>,
+    dependencies: &'a Dependencies,
+    /// The longest time to build each build and everything it depends on, and the dependency on
+    /// the path to it that took the longest.
+    longest: BTreeMap<BuildId, (u64, Option<BuildId>)>,
+}
+
+impl PathFinder<'_> {
+    /// The longest time from the start of the build until `id` was done. Dependencies that are
+    /// already being visited are skipped, so that a cycle can't recurse forever.
+    fn longest_to(&mut self, id: &BuildId, visiting: &mut BTreeSet<BuildId>) -> u64 {
+        if let Some((total, _)) = self.longest.get(id) {
+            return *total;
+        }
+        visiting.insert(id.clone());
+        let mut before = None;
+        let mut before_secs = 0;
+        let dependencies = self.dependencies;
+        for dependency in dependencies.get(id).into_iter().flatten() {
+            if visiting.contains(dependency) {
+                continue;
+            }
+            let total = self.longest_to(dependency, visiting);
+            if before.is_none() || total > before_secs {
+                before = Some(dependency.clone());
+                before_secs = total;
+            }
+        }
+        visiting.remove(id);
+        let total = before_secs + self.durations.get(id).copied().unwrap_or_default();
+        self.longest.insert(id.clone(), (total, before));
+        total
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(kind: &str, name: &str) -> BuildId {
+        (kind.to_string(), name.to_string())
+    }
+
+    fn record(kind: &str, name: &str, state: State, secs: u64) -> Record {
+        Record {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            arch: "x86_64".to_string(),
+            state,
+            stage: None,
+            started: 100,
+            updated: 100 + secs,
+            log: None,
+            problem: None,
+            maintainers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_critical_path() {
+        let dependencies = Dependencies::from([
+            (
+                id("package", "app"),
+                BTreeSet::from([id("package", "glibc"), id("package", "libz")]),
+            ),
+            (
+                id("package", "libz"),
+                BTreeSet::from([id("package", "glibc")]),
+            ),
+            (
+                id("variant", "aws-dev"),
+                BTreeSet::from([id("package", "app"), id("package", "kernel-6.1")]),
+            ),
+        ]);
+        let records = [
+            record("package", "glibc", State::Done, 300),
+            record("package", "libz", State::Done, 60),
+            record("package", "app", State::Done, 120),
+            record("package", "kernel-6.1", State::Done, 400),
+            record("package", "libcap", State::Cached, 1),
+            record("variant", "aws-dev", State::Done, 90),
+        ];
+        let path = CriticalPath::new(&records, &dependencies, "x86_64").unwrap();
+        assert_eq!(path.duration_secs, 570);
+        assert_eq!(
+            path.path
+                .iter()
+                .map(|step| step.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["glibc", "libz", "app", "aws-dev"]
+        );
+        assert_eq!(path.longest[0].name, "kernel-6.1");
+        assert_eq!(path.longest.len(), 5);
+        assert!(path
+            .summary()
+            .starts_with("Critical path for x86_64 (9m30s): package glibc (5m00s) -> "));
+
+        assert_eq!(CriticalPath::new(&records, &dependencies, "aarch64"), None);
+    }
+}
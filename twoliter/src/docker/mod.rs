@@ -1,4 +1,6 @@
 mod commands;
 mod image;
+mod run;
 
 pub(crate) use self::image::ImageUri;
+pub(crate) use self::run::SdkRun;
@@ -0,0 +1,124 @@
+use anyhow::{ensure, Context, Result};
+use buildsys::redact;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use tokio::process::Command;
+use tracing::debug;
+
+/// The user that buildsys runs package builds as in the SDK.
+const BUILDER_USER: &str = "builder";
+const BUILDER_HOME: &str = "/home/builder";
+
+/// A `docker run` of the project's SDK, with the project mounted where package builds see it:
+/// the whole project read-only at `/host`, the project's `.cargo` directory as the builder's
+/// `.cargo`, and the project's `sources` where spec files expect them.
+#[derive(Debug, Clone)]
+pub(crate) struct SdkRun {
+    sdk: String,
+    project_dir: PathBuf,
+    arch: String,
+    user: String,
+    workdir: String,
+    envs: Vec<(String, String)>,
+    volumes: Vec<(PathBuf, String)>,
+}
+
+impl SdkRun {
+    pub(crate) fn new(sdk: impl Into<String>, project_dir: impl AsRef<Path>, arch: &str) -> Self {
+        Self {
+            sdk: sdk.into(),
+            project_dir: project_dir.as_ref().to_path_buf(),
+            arch: arch.to_string(),
+            user: BUILDER_USER.to_string(),
+            workdir: BUILDER_HOME.to_string(),
+            envs: Vec::new(),
+            volumes: Vec::new(),
+        }
+    }
+
+    /// Run as `user` rather than as the builder.
+    pub(crate) fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = user.into();
+        self
+    }
+
+    /// Start in `workdir` rather than the builder's home directory.
+    pub(crate) fn workdir(mut self, workdir: impl Into<String>) -> Self {
+        self.workdir = workdir.into();
+        self
+    }
+
+    /// Set an environment variable in the container.
+    pub(crate) fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Also mount `host_path` read-only at `container_path`, for files outside the project.
+    pub(crate) fn volume(
+        mut self,
+        host_path: impl AsRef<Path>,
+        container_path: impl Into<String>,
+    ) -> Self {
+        self.volumes
+            .push((host_path.as_ref().to_path_buf(), container_path.into()));
+        self
+    }
+
+    fn command<S: AsRef<str>>(&self, args: &[S], interactive: bool) -> Command {
+        let root = self.project_dir.display();
+        let mut cmd = Command::new("docker");
+        cmd.args(["run", "--rm", "--network", "host"]);
+        // Attach a terminal when there is one, so that shells and interactive tools work.
+        if interactive && std::io::stdin().is_terminal() {
+            cmd.arg("-it");
+        } else {
+            cmd.arg("-i");
+        }
+        cmd.args(["--user", &self.user, "--workdir", &self.workdir])
+            .args(["-v", &format!("{root}:/host:ro")])
+            .args(["-v", &format!("{root}/.cargo:{BUILDER_HOME}/.cargo:ro")])
+            .args([
+                "-v",
+                &format!("{root}/sources:{BUILDER_HOME}/rpmbuild/BUILD/sources:ro"),
+            ])
+            .args(["-e", &format!("ARCH={}", self.arch)]);
+        for (host_path, container_path) in &self.volumes {
+            cmd.args([
+                "-v",
+                &format!("{}:{container_path}:ro", host_path.display()),
+            ]);
+        }
+        for (key, value) in &self.envs {
+            cmd.args(["-e", &format!("{key}={value}")]);
+        }
+        cmd.arg(&self.sdk).args(args.iter().map(AsRef::as_ref));
+        cmd
+    }
+
+    /// Runs `args` in the SDK with the terminal attached, and fails if the command does.
+    pub(crate) async fn run<S: AsRef<str>>(&self, args: &[S]) -> Result<()> {
+        let mut cmd = self.command(args, true);
+        debug!("Running: {}", redact::text(&format!("{:?}", cmd)));
+        // Stdout is kept for the command's report when it is printed as JSON.
+        if crate::output::is_json() {
+            cmd.stdout(std::process::Stdio::from(std::io::stderr()));
+        }
+        let status = cmd.status().await.context("Unable to start docker")?;
+        ensure!(
+            status.success(),
+            "Command in the SDK was unsuccessful, exit code {}",
+            status.code().unwrap_or(1)
+        );
+        Ok(())
+    }
+
+    /// Runs `args` in the SDK without a terminal, and returns its exit code and what it printed.
+    /// The exit code isn't checked, since tools such as `diff` use it to report what they found.
+    pub(crate) async fn output<S: AsRef<str>>(&self, args: &[S]) -> Result<Output> {
+        let mut cmd = self.command(args, false);
+        debug!("Running: {}", redact::text(&format!("{:?}", cmd)));
+        cmd.output().await.context("Unable to start docker")
+    }
+}
@@ -0,0 +1,286 @@
+/*!
+Builds that fail, or are interrupted, can leave Docker resources and temporary directories behind.
+Buildsys names what it creates after the checkout it builds, as `buildsys-<kind>-<name>-<arch>-
+<token>`, with a suffix such as `-bypass` or `-debug`, where the token is derived from the path of
+the project. This finds the resources with those names, and the directories that Twoliter's builds
+create in the project, so that they can be removed.
+*/
+
+use crate::cache::parse_timestamp;
+use crate::common::{exec, fs};
+use anyhow::{Context, Result};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+use tracing::debug;
+
+/// Buildsys adds this prefix to the names of the images, containers and volumes it creates.
+const BUILDSYS_PREFIX: &str = "buildsys-";
+
+/// The suffixes that buildsys adds after the token for resources that go with a build's image.
+const BUILDSYS_SUFFIXES: [&str; 2] = ["-bypass", "-debug"];
+
+/// The length of the token that buildsys derives from the path of the project.
+const TOKEN_LEN: usize = 12;
+
+/// The prefix of the temporary directories that builds create in the project.
+const TEMP_DIR_PREFIX: &str = ".tmp";
+
+/// The kinds of resources that builds leave behind, in the order they are removed, since images
+/// can't be removed while a container uses them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub(crate) enum ResourceKind {
+    Container,
+    Image,
+    Volume,
+    TempDir,
+}
+
+impl Display for ResourceKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Container => "container",
+            Self::Image => "image",
+            Self::Volume => "volume",
+            Self::TempDir => "temporary directory",
+        })
+    }
+}
+
+/// Something that a build left behind.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) struct Resource {
+    pub(crate) kind: ResourceKind,
+    /// The name of the Docker resource, or the path of the directory.
+    pub(crate) name: String,
+    pub(crate) created: SystemTime,
+}
+
+/// Which of the resources that builds left behind are removed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GcFilter {
+    /// Only resources created more than this long ago.
+    pub(crate) older_than: Option<Duration>,
+    /// Only Docker resources with this token, or those of any project if not set.
+    pub(crate) token: Option<String>,
+}
+
+impl GcFilter {
+    /// Returns the resources that should be removed, in the order to remove them.
+    pub(crate) fn select(&self, resources: Vec<Resource>, now: SystemTime) -> Vec<Resource> {
+        let mut selected = resources
+            .into_iter()
+            .filter(|resource| {
+                let age = now.duration_since(resource.created).unwrap_or_default();
+                self.older_than.map_or(true, |limit| age > limit)
+            })
+            .filter(|resource| match (&self.token, resource.kind) {
+                (_, ResourceKind::TempDir) | (None, _) => true,
+                (Some(token), _) => buildsys_token(&resource.name) == Some(token.as_str()),
+            })
+            .collect::<Vec<_>>();
+        selected.sort();
+        selected
+    }
+}
+
+/// Finds the resources that builds of any project left behind in Docker, and the temporary
+/// directories in the project rooted at `project_dir`. Containers that are running are skipped.
+pub(crate) async fn find(project_dir: &Path) -> Result<Vec<Resource>> {
+    let mut resources = Vec::new();
+    resources.extend(find_docker(ResourceKind::Container).await?);
+    resources.extend(find_docker(ResourceKind::Image).await?);
+    resources.extend(find_docker(ResourceKind::Volume).await?);
+    for dir in [project_dir.to_path_buf(), project_dir.join("build")] {
+        resources.extend(find_temp_dirs(&dir).await?);
+    }
+    Ok(resources)
+}
+
+/// Removes a resource.
+pub(crate) async fn remove(resource: &Resource) -> Result<()> {
+    debug!("Removing {} '{}'", resource.kind, resource.name);
+    let args = match resource.kind {
+        ResourceKind::Container => ["container", "rm", "--force"],
+        ResourceKind::Image => ["image", "rm", "--force"],
+        ResourceKind::Volume => ["volume", "rm", "--force"],
+        ResourceKind::TempDir => return fs::remove_dir_all(&resource.name).await,
+    };
+    exec(Command::new("docker").args(args).arg(&resource.name), true)
+        .await
+        .map(|_| ())
+}
+
+/// The token in a name that buildsys gave a resource, if it is one.
+fn buildsys_token(name: &str) -> Option<&str> {
+    let name = name.strip_prefix(BUILDSYS_PREFIX)?;
+    let name = name
+        .split_once(':')
+        .map_or(name, |(repository, _)| repository);
+    let name = BUILDSYS_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name);
+    let (_, token) = name.rsplit_once('-')?;
+    (token.len() == TOKEN_LEN && token.bytes().all(|b| b.is_ascii_hexdigit())).then_some(token)
+}
+
+/// Finds the Docker resources of `kind` that buildsys created.
+async fn find_docker(kind: ResourceKind) -> Result<Vec<Resource>> {
+    let (list, inspect): (&[&str], _) = match kind {
+        ResourceKind::Container => (
+            &[
+                "container",
+                "ls",
+                "--all",
+                "--filter",
+                "status=created",
+                "--filter",
+                "status=exited",
+                "--format",
+                "{{.Names}}",
+            ],
+            ["container", "inspect", "--format", "{{.Created}}"],
+        ),
+        ResourceKind::Image => (
+            &["image", "ls", "--format", "{{.Repository}}:{{.Tag}}"],
+            ["image", "inspect", "--format", "{{.Created}}"],
+        ),
+        ResourceKind::Volume => (
+            &[
+                "volume",
+                "ls",
+                "--filter",
+                "dangling=true",
+                "--format",
+                "{{.Name}}",
+            ],
+            ["volume", "inspect", "--format", "{{.CreatedAt}}"],
+        ),
+        ResourceKind::TempDir => return Ok(Vec::new()),
+    };
+    let names = exec(Command::new("docker").args(list), true)
+        .await
+        .context(format!("Unable to list docker {kind}s"))?
+        .unwrap_or_default();
+
+    let mut resources = Vec::new();
+    for name in names.lines().filter(|name| buildsys_token(name).is_some()) {
+        let created = exec(Command::new("docker").args(inspect).arg(name), true)
+            .await
+            .context(format!("Unable to inspect docker {kind} '{name}'"))?
+            .unwrap_or_default();
+        // Resources whose creation time can't be parsed are kept unless every age is removed.
+        let created = parse_timestamp(created.trim()).unwrap_or_else(|e| {
+            debug!("Unable to find when docker {kind} '{name}' was created: {e}");
+            SystemTime::now()
+        });
+        resources.push(Resource {
+            kind,
+            name: name.to_string(),
+            created,
+        });
+    }
+    Ok(resources)
+}
+
+/// Finds the temporary directories that builds created in `dir`.
+async fn find_temp_dirs(dir: &Path) -> Result<Vec<Resource>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut resources = Vec::new();
+    let read_error = || format!("Unable to read '{}'", dir.display());
+    let mut entries = tokio::fs::read_dir(dir).await.with_context(read_error)?;
+    while let Some(entry) = entries.next_entry().await.with_context(read_error)? {
+        let path = entry.path();
+        if !is_temp_dir_name(&entry.file_name().to_string_lossy()) || !path.is_dir() {
+            continue;
+        }
+        let created = fs::metadata(&path).await?.modified().unwrap_or(UNIX_EPOCH);
+        resources.push(Resource {
+            kind: ResourceKind::TempDir,
+            name: path.display().to_string(),
+            created,
+        });
+    }
+    Ok(resources)
+}
+
+/// Whether `name` is one that `tempfile` gives the directories it creates.
+fn is_temp_dir_name(name: &str) -> bool {
+    name.strip_prefix(TEMP_DIR_PREFIX)
+        .is_some_and(|rest| rest.len() == 6 && rest.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn resource(kind: ResourceKind, name: &str, age_hours: u64, now: SystemTime) -> Resource {
+        Resource {
+            kind,
+            name: name.to_string(),
+            created: now - Duration::from_secs(age_hours * 3600),
+        }
+    }
+
+    #[test]
+    fn test_buildsys_token() {
+        let token = Some("0123456789ab");
+        assert_eq!(
+            buildsys_token("buildsys-pkg-kernel-6.1-x86_64-0123456789ab"),
+            token
+        );
+        assert_eq!(
+            buildsys_token("buildsys-pkg-glibc-aarch64-0123456789ab-debug:latest"),
+            token
+        );
+        assert_eq!(
+            buildsys_token("buildsys-var-aws-dev-x86_64-0123456789ab-bypass"),
+            token
+        );
+        assert_eq!(buildsys_token("buildsys-pkg-glibc-aarch64-latest"), None);
+        assert_eq!(buildsys_token("bottlerocket-sdk:v0.50.0"), None);
+    }
+
+    #[test]
+    fn test_is_temp_dir_name() {
+        assert!(is_temp_dir_name(".tmpA1b2C3"));
+        assert!(!is_temp_dir_name(".tmp"));
+        assert!(!is_temp_dir_name("tmpA1b2C3"));
+    }
+
+    #[test]
+    fn test_select() {
+        let now = SystemTime::now();
+        let ours = "buildsys-pkg-glibc-x86_64-0123456789ab";
+        let theirs = "buildsys-pkg-glibc-x86_64-ba9876543210";
+        let resources = vec![
+            resource(ResourceKind::Image, ours, 48, now),
+            resource(ResourceKind::Image, theirs, 48, now),
+            resource(ResourceKind::Container, &format!("{ours}-bypass"), 48, now),
+            resource(ResourceKind::TempDir, "/project/.tmpA1b2C3", 48, now),
+            resource(ResourceKind::Image, &format!("{ours}-debug"), 1, now),
+        ];
+        let filter = GcFilter {
+            older_than: Some(Duration::from_secs(24 * 3600)),
+            token: Some("0123456789ab".to_string()),
+        };
+        let names = filter
+            .select(resources.clone(), now)
+            .into_iter()
+            .map(|resource| resource.name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                format!("{ours}-bypass"),
+                ours.to_string(),
+                "/project/.tmpA1b2C3".to_string()
+            ]
+        );
+        assert_eq!(GcFilter::default().select(resources, now).len(), 5);
+    }
+}
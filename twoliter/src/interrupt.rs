@@ -0,0 +1,87 @@
+/*!
+Pressing Ctrl-C sends SIGINT to twoliter and to the commands it runs, such as `cargo make` and the
+buildsys processes under it. Buildsys stops its `docker` builds and removes the containers and
+images they were using, so rather than exiting right away, twoliter waits for the command that is
+running to finish cleaning up, and then fails as interrupted. Pressing Ctrl-C again, or while no
+command is running, exits right away.
+*/
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tracing::warn;
+
+/// The exit status of a process that was stopped by SIGINT, which shells report as interrupted.
+pub(crate) const EXIT_STATUS: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The number of commands that are running.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// The error for a command that was interrupted.
+#[derive(Debug)]
+pub(crate) struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Handles Ctrl-C for as long as twoliter runs.
+pub(crate) fn install() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) || RUNNING.load(Ordering::SeqCst) == 0 {
+                eprintln!("Interrupted");
+                std::process::exit(EXIT_STATUS);
+            }
+            warn!("Interrupted, waiting for the build to clean up; press Ctrl-C again to exit now");
+        }
+    });
+}
+
+/// Whether twoliter has been interrupted.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Whether `error` comes from a command that was interrupted.
+pub(crate) fn is_interrupted(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| e.is::<Interrupted>())
+}
+
+/// Marks a command as running until it is dropped, so that Ctrl-C waits for it to finish.
+pub(crate) struct Running(());
+
+impl Running {
+    pub(crate) fn start() -> Self {
+        RUNNING.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_is_interrupted() {
+        let error = Err::<(), _>(Interrupted)
+            .context("Unable to build the variant")
+            .unwrap_err();
+        assert!(is_interrupted(&error));
+        assert!(!is_interrupted(&anyhow::anyhow!(
+            "Command was unsuccessful"
+        )));
+    }
+}
@@ -0,0 +1,129 @@
+/*!
+Twoliter logs with `tracing` to stderr. How much it logs is set with `-q` and `-v`, which can be
+repeated, or with `--log-level`, and defaults to `info`. Filters for single modules, or for other
+crates, can be added in `TWOLITER_LOG` with the syntax of `tracing_subscriber`'s `EnvFilter`, such
+as `twoliter::project=debug,buildsys::builder=trace`.
+
+The same level and filters are passed on to buildsys. Messages logged inside a span carry its
+fields, such as the package and architecture being built.
+*/
+
+use crate::output;
+use std::fmt::Debug;
+use std::sync::OnceLock;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// The environment variable with filters to add to the log level.
+pub(crate) const LOG_FILTER_ENV: &str = "TWOLITER_LOG";
+
+/// Read instead of `TWOLITER_LOG` if only it is set, since it was used before.
+const LEGACY_LOG_FILTER_ENV: &str = "RUST_LOG";
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::INFO;
+
+/// The level and the filters from the environment, kept to pass on to buildsys.
+static SETTINGS: OnceLock<(LevelFilter, Option<String>)> = OnceLock::new();
+
+/// The log level for `-v` given `verbose` times, or `-q` given `quiet` times, if either was given.
+pub(crate) fn verbosity(verbose: u8, quiet: u8) -> Option<LevelFilter> {
+    match (verbose, quiet) {
+        (0, 0) => None,
+        (0, 1) => Some(LevelFilter::WARN),
+        (0, 2) => Some(LevelFilter::ERROR),
+        (0, _) => Some(LevelFilter::OFF),
+        (1, _) => Some(LevelFilter::DEBUG),
+        _ => Some(LevelFilter::TRACE),
+    }
+}
+
+/// Starts logging at `level`, or the default level, with the filters from the environment added.
+pub(crate) fn init(level: Option<LevelFilter>) {
+    let level = level.unwrap_or(DEFAULT_LEVEL);
+    let env = std::env::var(LOG_FILTER_ENV)
+        .or_else(|_| std::env::var(LEGACY_LOG_FILTER_ENV))
+        .ok()
+        .filter(|filter| !filter.trim().is_empty());
+    let filter = EnvFilter::builder().parse_lossy(directives("twoliter", level, env.as_deref()));
+    let _ = SETTINGS.set((level, env));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(RecordWarnings)
+        .init();
+}
+
+/// The filters for buildsys, which reads them from `TWOLITER_LOG`.
+pub(crate) fn buildsys_filter() -> String {
+    let (level, env) = SETTINGS.get().cloned().unwrap_or((DEFAULT_LEVEL, None));
+    directives("buildsys", level, env.as_deref())
+}
+
+/// Logs `target` at `level`, followed by the filters from the environment so that they can
+/// override it for single modules.
+fn directives(target: &str, level: LevelFilter, env: Option<&str>) -> String {
+    match env {
+        Some(env) => format!("{target}={level},{env}"),
+        None => format!("{target}={level}"),
+    }
+}
+
+/// Adds the warnings that are logged to the command's output.
+struct RecordWarnings;
+
+impl<S: Subscriber> Layer<S> for RecordWarnings {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        if *event.metadata().level() <= Level::WARN {
+            let mut message = Message::default();
+            event.record(&mut message);
+            output::warning(message.0);
+        }
+    }
+}
+
+/// The message of an event, without its other fields.
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verbosity() {
+        assert_eq!(verbosity(0, 0), None);
+        assert_eq!(verbosity(0, 1), Some(LevelFilter::WARN));
+        assert_eq!(verbosity(0, 5), Some(LevelFilter::OFF));
+        assert_eq!(verbosity(1, 0), Some(LevelFilter::DEBUG));
+        assert_eq!(verbosity(2, 0), Some(LevelFilter::TRACE));
+    }
+
+    #[test]
+    fn test_directives() {
+        assert_eq!(
+            directives("buildsys", LevelFilter::DEBUG, None),
+            "buildsys=debug"
+        );
+        assert_eq!(
+            directives(
+                "twoliter",
+                LevelFilter::INFO,
+                Some("twoliter::project=trace")
+            ),
+            "twoliter=info,twoliter::project=trace"
+        );
+    }
+}
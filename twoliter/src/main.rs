@@ -1,14 +1,29 @@
-use crate::cmd::{init_logger, Args};
+use crate::cmd::Args;
 use anyhow::Result;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
+use clap_complete::CompleteEnv;
+use std::time::Instant;
 
+mod cache;
+mod cache_server;
+mod cache_stats;
 mod cargo_make;
+mod ci;
 mod cmd;
 mod common;
 mod compatibility;
+mod critical_path;
 mod docker;
+mod gc;
+mod interrupt;
+mod logging;
+mod metrics;
+mod output;
+mod progress;
 mod project;
+mod remote;
 mod schema_version;
+mod tasks;
 /// Test code that should only be compiled when running tests.
 #[cfg(test)]
 mod test;
@@ -18,7 +33,31 @@ mod tools;
 /// the `main` function.
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-    init_logger(args.log_level);
-    cmd::run(args).await
+    // Completes the command line and exits, when a completion script runs twoliter to do so.
+    CompleteEnv::with_factory(cmd::completions::command)
+        .var(cmd::completions::COMPLETE_VAR)
+        .complete();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    logging::init(args.log_level());
+    output::init(args.output);
+    ci::init(args.ci);
+    interrupt::install();
+
+    let started = Instant::now();
+    let result = cmd::run(args).await;
+    output::finish(output::command_name(&matches), started.elapsed(), &result);
+    if let Err(e) = &result {
+        if interrupt::is_interrupted(e) {
+            eprintln!("{e}");
+            std::process::exit(interrupt::EXIT_STATUS);
+        }
+        // The captured output of a failed build is long, so only the error is left unfolded.
+        if let Some(format) = ci::format() {
+            eprintln!("Error: {e}");
+            eprint!("{}", ci::folded(format, "Error details", &format!("{e:?}")));
+            std::process::exit(1);
+        }
+    }
+    result
 }
@@ -0,0 +1,530 @@
+/*!
+Opt-in metrics about builds, for dashboards that track build health across many machines.
+
+Buildsys already leaves records of each build it runs, in `build/progress`, and of each external
+file it fetches, in `build/fetch-log.jsonl`. When metrics are enabled in Twoliter.toml, a build
+command gathers the records from its own run into a [`BuildReport`] with build durations, cache
+hits, fetch sizes, and why builds failed, and writes it as JSON. The report can also be sent to a
+statsd server or an OpenTelemetry collector.
+
+Metrics are best-effort: failing to gather or send them is logged and never fails the build.
+*/
+
+use crate::progress::{self, Record, State};
+use crate::project::MetricsSettings;
+use anyhow::{Context, Result};
+use buildsys_config::{BUILD_PROGRESS_DIRECTORY, FETCH_LOG, FETCH_LOG_PREVIOUS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Records the metrics of one build command, from `start` until `finish`.
+pub(crate) struct Metrics {
+    settings: MetricsSettings,
+    project_dir: PathBuf,
+    command: &'static str,
+    started: u64,
+    timer: Instant,
+}
+
+impl Metrics {
+    /// Starts recording the metrics of `command`, such as `build variant`.
+    pub(crate) fn start(
+        settings: &MetricsSettings,
+        project_dir: impl AsRef<Path>,
+        command: &'static str,
+    ) -> Self {
+        Self {
+            settings: settings.clone(),
+            project_dir: project_dir.as_ref().to_path_buf(),
+            command,
+            started: progress::now(),
+            timer: Instant::now(),
+        }
+    }
+
+    /// Gathers the metrics of the command, which ended with `result`, and exports them. Does
+    /// nothing unless metrics are enabled.
+    pub(crate) async fn finish(self, result: &Result<()>) {
+        if !self.settings.enabled {
+            return;
+        }
+        let report = BuildReport::new(
+            self.command,
+            self.started,
+            self.timer.elapsed(),
+            result.is_ok(),
+            &progress::read_records(
+                &self.project_dir.join(BUILD_PROGRESS_DIRECTORY),
+                self.started,
+            )
+            .await,
+            &read_fetch_log(&self.project_dir, self.started).await,
+        );
+
+        let report_dir = self.settings.report_dir(&self.project_dir);
+        match report.write(&report_dir).await {
+            Ok(path) => debug!("Wrote build metrics to '{}'", path.display()),
+            Err(e) => warn!("Unable to write build metrics: {e:#}"),
+        }
+        if let Some(statsd) = &self.settings.statsd {
+            if let Err(e) = send_statsd(statsd, &report.statsd_lines(self.settings.prefix())) {
+                warn!("Unable to send build metrics to '{statsd}': {e:#}");
+            }
+        }
+        if let Some(otlp) = &self.settings.otlp {
+            if let Err(e) = send_otlp(otlp, &report.otlp(self.settings.prefix())).await {
+                warn!("Unable to send build metrics to '{otlp}': {e:#}");
+            }
+        }
+    }
+}
+
+/// One fetch attempt from the buildsys fetch log.
+#[derive(Debug, Clone, Deserialize)]
+struct FetchRecord {
+    source: String,
+    success: bool,
+    #[serde(default)]
+    bytes: Option<u64>,
+    started: u64,
+}
+
+/// Reads the fetch attempts that started at or after `since`, including those in the log that
+/// buildsys moved aside while the command ran.
+async fn read_fetch_log(project_dir: &Path, since: u64) -> Vec<FetchRecord> {
+    let mut records = Vec::new();
+    for log in [FETCH_LOG_PREVIOUS, FETCH_LOG] {
+        let Ok(log) = tokio::fs::read_to_string(project_dir.join(log)).await else {
+            continue;
+        };
+        records.extend(
+            log.lines()
+                .filter_map(|line| serde_json::from_str::<FetchRecord>(line).ok())
+                .filter(|record| record.started >= since),
+        );
+    }
+    records
+}
+
+/// The metrics of one build command.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BuildReport {
+    command: String,
+    /// Seconds since the epoch.
+    started: u64,
+    duration_secs: f64,
+    success: bool,
+    builds: Vec<BuildMetrics>,
+    cache: CacheMetrics,
+    fetch: FetchMetrics,
+    /// How many builds failed in each category, such as `package:rpmbuild`.
+    failures: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct BuildMetrics {
+    name: String,
+    kind: String,
+    arch: String,
+    state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<String>,
+    duration_secs: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    maintainers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CacheMetrics {
+    built: u64,
+    cached: u64,
+    /// The share of finished builds that came from the cache, from 0 to 1.
+    hit_rate: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct FetchMetrics {
+    files: u64,
+    bytes: u64,
+    failures: u64,
+    /// The bytes fetched from each source, such as `lookaside-cache` or `upstream`.
+    bytes_by_source: BTreeMap<String, u64>,
+}
+
+impl BuildReport {
+    fn new(
+        command: &str,
+        started: u64,
+        duration: Duration,
+        success: bool,
+        records: &[Record],
+        fetches: &[FetchRecord],
+    ) -> Self {
+        let builds = records
+            .iter()
+            .map(|r| BuildMetrics {
+                name: r.name.clone(),
+                kind: r.kind.clone(),
+                arch: r.arch.clone(),
+                state: match r.state {
+                    State::Running => "stopped",
+                    State::Cached => "cached",
+                    State::Done => "done",
+                    State::Failed => "failed",
+                },
+                stage: r.stage.clone(),
+                duration_secs: r.updated.saturating_sub(r.started),
+                maintainers: r.maintainers.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut cache = CacheMetrics::default();
+        let mut failures = BTreeMap::new();
+        for record in records {
+            match record.state {
+                State::Done => cache.built += 1,
+                State::Cached => cache.cached += 1,
+                State::Failed => {
+                    let category = format!(
+                        "{}:{}",
+                        record.kind,
+                        record.stage.as_deref().unwrap_or("setup")
+                    );
+                    *failures.entry(category).or_default() += 1;
+                }
+                State::Running => (),
+            }
+        }
+        if cache.built + cache.cached > 0 {
+            cache.hit_rate = cache.cached as f64 / (cache.built + cache.cached) as f64;
+        }
+
+        let mut fetch = FetchMetrics::default();
+        for record in fetches {
+            if record.success {
+                let bytes = record.bytes.unwrap_or_default();
+                fetch.files += 1;
+                fetch.bytes += bytes;
+                *fetch
+                    .bytes_by_source
+                    .entry(record.source.clone())
+                    .or_default() += bytes;
+            } else {
+                fetch.failures += 1;
+            }
+        }
+        // Failed fetches aren't build failures on their own, since another source may have had the
+        // file. A command that failed without any failed build failed outside of buildsys.
+        if !success && failures.is_empty() {
+            *failures.entry("twoliter".to_string()).or_default() += 1;
+        }
+
+        Self {
+            command: command.to_string(),
+            started,
+            duration_secs: duration.as_secs_f64(),
+            success,
+            builds,
+            cache,
+            fetch,
+            failures,
+        }
+    }
+
+    /// Writes the report to `<command>-<started>.json` in `dir`, and returns its path.
+    async fn write(&self, dir: &Path) -> Result<PathBuf> {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Unable to create '{}'", dir.display()))?;
+        let path = dir.join(format!(
+            "{}-{}.json",
+            self.command.replace(' ', "-"),
+            self.started
+        ));
+        let data = serde_json::to_vec_pretty(self).context("Unable to serialize build metrics")?;
+        tokio::fs::write(&path, data)
+            .await
+            .with_context(|| format!("Unable to write '{}'", path.display()))?;
+        Ok(path)
+    }
+
+    /// The report as statsd lines, with metric names under `prefix`.
+    fn statsd_lines(&self, prefix: &str) -> Vec<String> {
+        let command = metric_name(&self.command);
+        let mut lines = vec![
+            format!(
+                "{prefix}.command.{command}.duration:{}|ms",
+                (self.duration_secs * 1000.0) as u64
+            ),
+            format!(
+                "{prefix}.command.{command}.{}:1|c",
+                if self.success { "success" } else { "failure" }
+            ),
+            format!("{prefix}.cache.hit_rate:{}|g", self.cache.hit_rate),
+            format!("{prefix}.cache.built:{}|c", self.cache.built),
+            format!("{prefix}.cache.cached:{}|c", self.cache.cached),
+            format!("{prefix}.fetch.files:{}|c", self.fetch.files),
+            format!("{prefix}.fetch.bytes:{}|c", self.fetch.bytes),
+            format!("{prefix}.fetch.failures:{}|c", self.fetch.failures),
+        ];
+        for build in &self.builds {
+            lines.push(format!(
+                "{prefix}.build.{}.{}.duration:{}|ms",
+                metric_name(&build.kind),
+                build.state,
+                build.duration_secs * 1000
+            ));
+        }
+        for (category, count) in &self.failures {
+            lines.push(format!(
+                "{prefix}.failures.{}:{count}|c",
+                metric_name(category)
+            ));
+        }
+        lines
+    }
+
+    /// The report as an OTLP `ExportMetricsServiceRequest`, in its JSON encoding, with metric
+    /// names under `prefix`.
+    fn otlp(&self, prefix: &str) -> serde_json::Value {
+        let time =
+            (self.started as u128 * 1_000_000_000 + (self.duration_secs * 1e9) as u128).to_string();
+        let attributes = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+                .collect::<Vec<_>>()
+        };
+        let gauge = |name: &str, unit: &str, points: Vec<(f64, Vec<serde_json::Value>)>| {
+            json!({
+                "name": format!("{prefix}.{name}"),
+                "unit": unit,
+                "gauge": {
+                    "dataPoints": points
+                        .into_iter()
+                        .map(|(value, attributes)| json!({
+                            "asDouble": value,
+                            "timeUnixNano": time,
+                            "attributes": attributes,
+                        }))
+                        .collect::<Vec<_>>(),
+                },
+            })
+        };
+
+        let command = attributes(&[
+            ("command", self.command.as_str()),
+            ("success", if self.success { "true" } else { "false" }),
+        ]);
+        let mut metrics = vec![
+            gauge(
+                "command.duration",
+                "s",
+                vec![(self.duration_secs, command.clone())],
+            ),
+            gauge(
+                "cache.hit_rate",
+                "1",
+                vec![(self.cache.hit_rate, command.clone())],
+            ),
+            gauge(
+                "fetch.bytes",
+                "By",
+                self.fetch
+                    .bytes_by_source
+                    .iter()
+                    .map(|(source, bytes)| {
+                        (*bytes as f64, attributes(&[("source", source.as_str())]))
+                    })
+                    .collect(),
+            ),
+            gauge(
+                "fetch.failures",
+                "1",
+                vec![(self.fetch.failures as f64, command.clone())],
+            ),
+            gauge(
+                "build.duration",
+                "s",
+                self.builds
+                    .iter()
+                    .map(|b| {
+                        (
+                            b.duration_secs as f64,
+                            attributes(&[
+                                ("name", b.name.as_str()),
+                                ("kind", b.kind.as_str()),
+                                ("arch", b.arch.as_str()),
+                                ("state", b.state),
+                            ]),
+                        )
+                    })
+                    .collect(),
+            ),
+        ];
+        if !self.failures.is_empty() {
+            metrics.push(gauge(
+                "failures",
+                "1",
+                self.failures
+                    .iter()
+                    .map(|(category, count)| {
+                        (
+                            *count as f64,
+                            attributes(&[("category", category.as_str())]),
+                        )
+                    })
+                    .collect(),
+            ));
+        }
+
+        json!({
+            "resourceMetrics": [{
+                "resource": {"attributes": attributes(&[("service.name", "twoliter")])},
+                "scopeMetrics": [{
+                    "scope": {"name": "twoliter", "version": env!("CARGO_PKG_VERSION")},
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+}
+
+/// Makes `s` safe to use as part of a statsd metric name.
+fn metric_name(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Sends each line to the statsd server at `addr` in its own datagram.
+fn send_statsd(addr: &str, lines: &[String]) -> Result<()> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").context("Unable to open a UDP socket")?;
+    socket
+        .connect(addr)
+        .with_context(|| format!("Unable to resolve '{addr}'"))?;
+    for line in lines {
+        socket
+            .send(line.as_bytes())
+            .with_context(|| format!("Unable to send '{line}'"))?;
+    }
+    Ok(())
+}
+
+async fn send_otlp(url: &str, body: &serde_json::Value) -> Result<()> {
+    reqwest::Client::new()
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .context("Unable to post metrics")?
+        .error_for_status()
+        .context("The collector rejected the metrics")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(name: &str, state: State, stage: Option<&str>, secs: u64) -> Record {
+        Record {
+            name: name.to_string(),
+            kind: "package".to_string(),
+            arch: "x86_64".to_string(),
+            state,
+            stage: stage.map(str::to_string),
+            started: 100,
+            updated: 100 + secs,
+            log: None,
+            problem: None,
+            maintainers: Vec::new(),
+        }
+    }
+
+    fn report() -> BuildReport {
+        BuildReport::new(
+            "build variant",
+            100,
+            Duration::from_secs(90),
+            false,
+            &[
+                record("glibc", State::Done, Some("rpmbuild"), 60),
+                record("libz", State::Cached, None, 0),
+                record("libcap", State::Cached, None, 1),
+                record("kernel-6.1", State::Failed, Some("rpmbuild"), 30),
+            ],
+            &[
+                FetchRecord {
+                    source: "lookaside-cache".to_string(),
+                    success: true,
+                    bytes: Some(1000),
+                    started: 100,
+                },
+                FetchRecord {
+                    source: "upstream".to_string(),
+                    success: false,
+                    bytes: None,
+                    started: 100,
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn test_report() {
+        let report = report();
+        assert_eq!(report.cache.built, 1);
+        assert_eq!(report.cache.cached, 2);
+        assert!((report.cache.hit_rate - 2.0 / 3.0).abs() < f64::EPSILON);
+        assert_eq!(report.fetch.files, 1);
+        assert_eq!(report.fetch.bytes, 1000);
+        assert_eq!(report.fetch.failures, 1);
+        assert_eq!(
+            report.failures,
+            BTreeMap::from([("package:rpmbuild".to_string(), 1)])
+        );
+        assert_eq!(report.builds[0].duration_secs, 60);
+    }
+
+    #[test]
+    fn test_statsd_lines() {
+        let lines = report().statsd_lines("twoliter");
+        assert!(lines.contains(&"twoliter.command.build_variant.duration:90000|ms".to_string()));
+        assert!(lines.contains(&"twoliter.command.build_variant.failure:1|c".to_string()));
+        assert!(lines.contains(&"twoliter.build.package.done.duration:60000|ms".to_string()));
+        assert!(lines.contains(&"twoliter.failures.package_rpmbuild:1|c".to_string()));
+    }
+
+    #[test]
+    fn test_otlp() {
+        let otlp = report().otlp("twoliter");
+        let metrics = &otlp["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "twoliter.command.duration");
+        assert_eq!(metrics[0]["gauge"]["dataPoints"][0]["asDouble"], 90.0);
+        assert_eq!(
+            metrics[0]["gauge"]["dataPoints"][0]["timeUnixNano"],
+            "190000000000"
+        );
+    }
+
+    #[test]
+    fn test_command_failure_without_build_failure() {
+        let report = BuildReport::new("build kit", 0, Duration::ZERO, false, &[], &[]);
+        assert_eq!(
+            report.failures,
+            BTreeMap::from([("twoliter".to_string(), 1)])
+        );
+        assert_eq!(report.cache.hit_rate, 0.0);
+    }
+}
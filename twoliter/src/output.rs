@@ -0,0 +1,295 @@
+/*!
+With `--output json`, twoliter prints a single JSON object to stdout when a command finishes, so
+that CI pipelines can consume its results without scraping logs. The output of the build tools and
+twoliter's own logs go to stderr instead. Commands add what they produced to the report as they
+go, and any warnings that twoliter logs are collected along the way. Builds that failed are listed
+with their maintainers, so that CI can send each failure to the people who own it.
+*/
+
+use crate::cache_stats::CacheStats;
+use crate::critical_path::CriticalPath;
+use crate::progress::Record;
+use anyhow::Result;
+use clap::{ArgMatches, ValueEnum};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// How twoliter reports the result of a command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Logs meant for people.
+    #[default]
+    Text,
+    /// A JSON object on stdout when the command finishes.
+    Json,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+static REPORT: Mutex<Report> = Mutex::new(Report::new());
+
+#[derive(Debug, Serialize)]
+struct Report {
+    command: String,
+    success: bool,
+    duration_ms: u128,
+    artifacts: Vec<Artifact>,
+    details: BTreeMap<String, String>,
+    warnings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<CacheStats>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    critical_paths: Vec<CriticalPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failures: Vec<Failure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Report {
+    const fn new() -> Self {
+        Self {
+            command: String::new(),
+            success: false,
+            duration_ms: 0,
+            artifacts: Vec::new(),
+            details: BTreeMap::new(),
+            warnings: Vec::new(),
+            cache: None,
+            critical_paths: Vec::new(),
+            failures: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+/// Something a command produced. Files have a digest and size; directories only have a path.
+#[derive(Debug, Serialize)]
+struct Artifact {
+    path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+/// A build that failed, and who owns it.
+#[derive(Debug, PartialEq, Serialize)]
+struct Failure {
+    name: String,
+    kind: String,
+    arch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    problem: Option<String>,
+    maintainers: Vec<String>,
+}
+
+impl From<&Record> for Failure {
+    fn from(record: &Record) -> Self {
+        Self {
+            name: record.name.clone(),
+            kind: record.kind.clone(),
+            arch: record.arch.clone(),
+            stage: record.stage.clone(),
+            problem: record.problem.as_ref().map(|p| p.message.clone()),
+            maintainers: record.maintainers.clone(),
+        }
+    }
+}
+
+/// Sets the output format for this run of twoliter.
+pub(crate) fn init(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+/// Whether twoliter will print a JSON report, in which case stdout is reserved for it.
+pub(crate) fn is_json() -> bool {
+    FORMAT.get() == Some(&OutputFormat::Json)
+}
+
+fn with_report(f: impl FnOnce(&mut Report)) {
+    if let Ok(mut report) = REPORT.lock() {
+        f(&mut report)
+    }
+}
+
+/// Records a file or directory that the command produced. Files are hashed, so nothing is
+/// recorded unless the report will be printed.
+pub(crate) async fn artifact(path: impl AsRef<Path>) {
+    if !is_json() {
+        return;
+    }
+    let path = path.as_ref().to_path_buf();
+    let artifact = tokio::task::spawn_blocking(move || describe(path)).await;
+    match artifact {
+        Ok(Ok(artifact)) => with_report(|r| r.artifacts.push(artifact)),
+        Ok(Err(e)) => warning(format!("Unable to describe artifact: {:?}", e)),
+        Err(e) => warning(format!("Unable to describe artifact: {}", e)),
+    }
+}
+
+/// Records each file in a directory as an artifact. Subdirectories are left out.
+pub(crate) async fn artifacts_in(dir: impl AsRef<Path>) {
+    if !is_json() {
+        return;
+    }
+    let dir = dir.as_ref();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warning(format!(
+                "Unable to list artifacts in '{}': {}",
+                dir.display(),
+                e
+            ));
+            return;
+        }
+    };
+    let mut files = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.path().is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    for file in files {
+        artifact(file).await;
+    }
+}
+
+fn describe(path: PathBuf) -> Result<Artifact> {
+    let path = path.canonicalize().unwrap_or(path);
+    if !path.is_file() {
+        return Ok(Artifact {
+            path,
+            sha256: None,
+            size: None,
+        });
+    }
+    let mut f = std::fs::File::open(&path)?;
+    let mut digest = Sha256::new();
+    let size = std::io::copy(&mut f, &mut digest)?;
+    Ok(Artifact {
+        path,
+        sha256: Some(
+            digest
+                .finalize()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        ),
+        size: Some(size),
+    })
+}
+
+/// Records a named value about the result, such as the image a kit was published to.
+pub(crate) fn detail(key: impl Into<String>, value: impl Into<String>) {
+    let (key, value) = (key.into(), value.into());
+    with_report(|r| {
+        r.details.insert(key, value);
+    })
+}
+
+/// Records how much of the builds that ran were served from caches.
+pub(crate) fn cache_stats(stats: CacheStats) {
+    with_report(|r| r.cache = Some(stats))
+}
+
+/// Records the critical path of the builds for one architecture.
+pub(crate) fn critical_path(path: CriticalPath) {
+    with_report(|r| r.critical_paths.push(path))
+}
+
+/// Records a build that failed.
+pub(crate) fn failure(record: &Record) {
+    let failure = Failure::from(record);
+    with_report(|r| r.failures.push(failure))
+}
+
+/// Records a warning. Warnings that twoliter logs are recorded automatically.
+pub(crate) fn warning(message: impl Into<String>) {
+    let message = message.into();
+    with_report(|r| r.warnings.push(message))
+}
+
+/// The name of the subcommand that was run, such as `build variant`.
+pub(crate) fn command_name(matches: &ArgMatches) -> String {
+    let mut names = Vec::new();
+    let mut matches = matches;
+    while let Some((name, sub)) = matches.subcommand() {
+        names.push(name);
+        matches = sub;
+    }
+    names.join(" ")
+}
+
+/// Prints the report for the command, if one was requested.
+pub(crate) fn finish(command: String, duration: Duration, result: &Result<()>) {
+    if !is_json() {
+        return;
+    }
+    with_report(|r| {
+        r.command = command;
+        r.success = result.is_ok();
+        r.duration_ms = duration.as_millis();
+        r.error = result.as_ref().err().map(|e| format!("{:?}", e));
+        match serde_json::to_string(&*r) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to serialize the command output: {}", e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::{Arg, Command};
+
+    #[test]
+    fn test_command_name() {
+        let cmd = Command::new("twoliter")
+            .arg(Arg::new("log-level").long("log-level"))
+            .subcommand(Command::new("build").subcommand(Command::new("variant")));
+        let matches = cmd.get_matches_from(["twoliter", "build", "variant"]);
+        assert_eq!(command_name(&matches), "build variant");
+    }
+
+    #[test]
+    fn test_failure() {
+        let record: Record = serde_json::from_str(
+            r#"{"name":"kernel-6.1","kind":"package","arch":"x86_64","state":"failed",
+            "stage":"rpmbuild","started":100,"updated":160,
+            "problem":{"message":"Bad exit status"},"maintainers":["@bottlerocket-os/kernel"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(Failure::from(&record)).unwrap(),
+            serde_json::json!({
+                "name": "kernel-6.1",
+                "kind": "package",
+                "arch": "x86_64",
+                "stage": "rpmbuild",
+                "problem": "Bad exit status",
+                "maintainers": ["@bottlerocket-os/kernel"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_describe_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("image.img");
+        std::fs::write(&path, b"hello").unwrap();
+        let artifact = describe(path).unwrap();
+        assert_eq!(artifact.size, Some(5));
+        assert_eq!(
+            artifact.sha256.unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+}
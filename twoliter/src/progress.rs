@@ -0,0 +1,436 @@
+/*!
+Shows what a build is doing while it runs. Cargo hides the output of each package build until it
+finishes, so buildsys records the progress of every build it runs in the project's
+`build/progress` directory, and this module reads those records back.
+
+When stdout is a terminal, the running builds are redrawn in place with a spinner, the stage of
+the Dockerfile each one is in, and how long it has taken. Otherwise, a plain status line is logged
+every so often, which is easier to read in CI logs. With `--ci`, the log of each build is also
+printed for the CI system once the build finishes; see the `ci` module.
+
+Once the builds finish, a summary of how much they took from caches is logged as well. Failed
+builds are named in it along with their maintainers, if their packages list any, and are added to
+the JSON report so that CI can send each failure to the people who own it.
+*/
+
+use crate::cache_stats::CacheStats;
+use crate::ci;
+use buildsys_config::{BUILD_PROGRESS_DIRECTORY, CACHE_LOG};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io::{IsTerminal, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+
+/// How often the terminal is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// How often a status line is logged when stdout is not a terminal.
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+/// The most running builds to show at once, so the display fits on the screen.
+const MAX_RUNNING_LINES: usize = 16;
+const SPINNER: [char; 4] = ['-', '\\', '|', '/'];
+
+/// A build's progress, as recorded by buildsys.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Record {
+    pub(crate) name: String,
+    pub(crate) kind: String,
+    pub(crate) arch: String,
+    pub(crate) state: State,
+    pub(crate) stage: Option<String>,
+    /// Seconds since the epoch.
+    pub(crate) started: u64,
+    #[serde(default)]
+    pub(crate) updated: u64,
+    /// Where the output of the build is saved.
+    #[serde(default)]
+    pub(crate) log: Option<PathBuf>,
+    /// What failed the build, if buildsys could tell.
+    #[serde(default)]
+    pub(crate) problem: Option<Problem>,
+    /// Who owns what was built, as listed in the package's manifest.
+    #[serde(default)]
+    pub(crate) maintainers: Vec<String>,
+}
+
+/// What failed a build, and the file and line that it was found in, if it was found in one.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Problem {
+    pub(crate) message: String,
+    pub(crate) file: Option<PathBuf>,
+    pub(crate) line: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum State {
+    Running,
+    Cached,
+    Done,
+    Failed,
+}
+
+/// Displays the progress of the builds that buildsys runs, until `finish` is called.
+pub(crate) struct Progress {
+    interactive: bool,
+    stop: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl Progress {
+    /// Starts showing the progress of builds in the project.
+    pub(crate) fn start(project_dir: impl AsRef<Path>) -> Self {
+        let interactive = std::io::stdout().is_terminal()
+            && !crate::output::is_json()
+            && ci::format().is_none()
+            && LevelFilter::current() <= LevelFilter::INFO;
+        let stop = Arc::new(AtomicBool::new(false));
+        let display = Display {
+            dir: project_dir.as_ref().join(BUILD_PROGRESS_DIRECTORY),
+            cache_log: project_dir.as_ref().join(CACHE_LOG),
+            started: Instant::now(),
+            started_secs: now(),
+            interactive,
+            lines: 0,
+            frame: 0,
+            reported: HashSet::new(),
+        };
+        let task = tokio::spawn(display.run(stop.clone()));
+        Self {
+            interactive,
+            stop,
+            task,
+        }
+    }
+
+    /// Whether the output of the build should be captured rather than printed, since progress is
+    /// drawn on the terminal or the logs of builds are printed for CI.
+    pub(crate) fn captures_output(&self) -> bool {
+        self.interactive || ci::format().is_some()
+    }
+
+    /// Stops showing progress and prints a summary of the builds that ran and their cache use.
+    pub(crate) async fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.task.await;
+    }
+}
+
+struct Display {
+    dir: PathBuf,
+    cache_log: PathBuf,
+    started: Instant,
+    /// Records from before this time were left by earlier runs.
+    started_secs: u64,
+    interactive: bool,
+    /// The number of lines drawn last time, which are cleared before drawing again.
+    lines: usize,
+    frame: usize,
+    /// The builds whose logs have been printed for CI, by name, architecture and start time.
+    reported: HashSet<(String, String, u64)>,
+}
+
+impl Display {
+    async fn run(mut self, stop: Arc<AtomicBool>) {
+        let mut last_status = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            tokio::time::sleep(REDRAW_INTERVAL).await;
+            let records = self.read().await;
+            self.report(&records).await;
+            if self.interactive {
+                self.draw(&records);
+            } else if last_status.elapsed() >= STATUS_INTERVAL && !records.is_empty() {
+                info!("{}", status_line(&records, self.started.elapsed(), now()));
+                last_status = Instant::now();
+            }
+        }
+
+        let records = self.read().await;
+        self.report(&records).await;
+        if self.interactive {
+            self.draw(&[]);
+        }
+        if !records.is_empty() {
+            info!("{}", summary(&records, self.started.elapsed()));
+        }
+        for record in records.iter().filter(|r| r.state == State::Failed) {
+            crate::output::failure(record);
+        }
+        let cache_stats = CacheStats::read(&self.cache_log, self.started_secs).await;
+        if !cache_stats.is_empty() {
+            info!("{}", cache_stats.summary());
+            crate::output::cache_stats(cache_stats);
+        }
+    }
+
+    /// Reads the records of builds that started since twoliter did.
+    async fn read(&self) -> Vec<Record> {
+        read_records(&self.dir, self.started_secs).await
+    }
+
+    /// Prints the logs of the builds that finished since last time, if twoliter is run in CI.
+    async fn report(&mut self, records: &[Record]) {
+        let Some(format) = ci::format() else {
+            return;
+        };
+        let dir = std::env::current_dir().unwrap_or_default();
+        for record in records.iter().filter(|r| r.state != State::Running) {
+            let key = (record.name.clone(), record.arch.clone(), record.started);
+            if !self.reported.insert(key) {
+                continue;
+            }
+            let log = match &record.log {
+                Some(log) => tokio::fs::read(log).await.ok(),
+                None => None,
+            };
+            let log = log.map(|log| String::from_utf8_lossy(&log).into_owned());
+            let report = ci::report(format, record, log.as_deref(), &dir);
+            let mut stderr = std::io::stderr().lock();
+            let _ = stderr.write_all(report.as_bytes());
+            let _ = stderr.flush();
+        }
+    }
+
+    /// Replaces what was drawn last time with the progress of the running builds.
+    fn draw(&mut self, records: &[Record]) {
+        let mut s = String::new();
+        if self.lines > 0 {
+            // Move to the start of the first line drawn last time and clear everything below.
+            let _ = write!(s, "\x1b[{}F\x1b[J", self.lines);
+        }
+        self.lines = 0;
+        if !records.is_empty() {
+            let now = now();
+            let spinner = SPINNER[self.frame % SPINNER.len()];
+            self.frame += 1;
+            let _ = writeln!(s, "{}", header(records, self.started.elapsed()));
+            self.lines += 1;
+            let running = records
+                .iter()
+                .filter(|r| r.state == State::Running)
+                .collect::<Vec<_>>();
+            for record in running.iter().take(MAX_RUNNING_LINES) {
+                let _ = writeln!(s, "  {} {}", spinner, describe(record, now));
+                self.lines += 1;
+            }
+            if running.len() > MAX_RUNNING_LINES {
+                let _ = writeln!(s, "    and {} more", running.len() - MAX_RUNNING_LINES);
+                self.lines += 1;
+            }
+        }
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(s.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Counts {
+    running: usize,
+    done: usize,
+    cached: usize,
+    failed: usize,
+}
+
+fn counts(records: &[Record]) -> Counts {
+    let mut counts = Counts::default();
+    for record in records {
+        match record.state {
+            State::Running => counts.running += 1,
+            State::Done => counts.done += 1,
+            State::Cached => counts.cached += 1,
+            State::Failed => counts.failed += 1,
+        }
+    }
+    counts
+}
+
+fn header(records: &[Record], elapsed: Duration) -> String {
+    let c = counts(records);
+    format!(
+        "Building [{}]: {} running, {} done, {} cached, {} failed",
+        format_duration(elapsed.as_secs()),
+        c.running,
+        c.done,
+        c.cached,
+        c.failed
+    )
+}
+
+/// A running build, such as `package kernel-6.1 (x86_64) in rpmbuild for 2m05s`.
+fn describe(record: &Record, now: u64) -> String {
+    format!(
+        "{} {} ({}) in {} for {}",
+        record.kind,
+        record.name,
+        record.arch,
+        record.stage.as_deref().unwrap_or("setup"),
+        format_duration(now.saturating_sub(record.started))
+    )
+}
+
+fn status_line(records: &[Record], elapsed: Duration, now: u64) -> String {
+    let running = records
+        .iter()
+        .filter(|r| r.state == State::Running)
+        .map(|r| describe(r, now))
+        .collect::<Vec<_>>();
+    if running.is_empty() {
+        header(records, elapsed)
+    } else {
+        format!("{}; {}", header(records, elapsed), running.join(", "))
+    }
+}
+
+fn summary(records: &[Record], elapsed: Duration) -> String {
+    let c = counts(records);
+    let mut s = format!(
+        "Finished {} builds in {} ({} cached)",
+        c.done + c.cached,
+        format_duration(elapsed.as_secs()),
+        c.cached
+    );
+    let failed = records
+        .iter()
+        .filter(|r| r.state == State::Failed)
+        .map(|r| match r.maintainers.as_slice() {
+            [] => format!("{} ({})", r.name, r.arch),
+            maintainers => format!(
+                "{} ({}, maintainers: {})",
+                r.name,
+                r.arch,
+                maintainers.join(" ")
+            ),
+        })
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        let _ = write!(s, ", failed: {}", failed.join(", "));
+    }
+    // Builds that never recorded how they ended were stopped along with the rest of the build.
+    let stopped = records.iter().filter(|r| r.state == State::Running).count();
+    if stopped > 0 {
+        let _ = write!(s, ", {} stopped", stopped);
+    }
+    s
+}
+
+/// Reads the records in `dir` of builds that started at or after `since`, in seconds since the
+/// epoch, ordered by when they started.
+pub(crate) async fn read_records(dir: &Path, since: u64) -> Vec<Record> {
+    let mut records = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return records;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        // A record that can't be read is being replaced, and will be read next time.
+        let Ok(data) = tokio::fs::read(&path).await else {
+            continue;
+        };
+        if let Ok(record) = serde_json::from_slice::<Record>(&data) {
+            if record.started >= since {
+                records.push(record);
+            }
+        }
+    }
+    records.sort_by(|a, b| (a.started, &a.name).cmp(&(b.started, &b.name)));
+    records
+}
+
+/// Formats seconds like `1h02m03s`, `2m05s` or `7s`.
+pub(crate) fn format_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h{:02}m{:02}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m{:02}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(name: &str, state: State, stage: Option<&str>) -> Record {
+        Record {
+            name: name.to_string(),
+            kind: "package".to_string(),
+            arch: "x86_64".to_string(),
+            state,
+            stage: stage.map(str::to_string),
+            started: 100,
+            updated: 100,
+            log: None,
+            problem: None,
+            maintainers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(7), "7s");
+        assert_eq!(format_duration(125), "2m05s");
+        assert_eq!(format_duration(3723), "1h02m03s");
+    }
+
+    #[test]
+    fn test_status_line() {
+        let records = vec![
+            record("glibc", State::Done, Some("rpmbuild")),
+            record("kernel-6.1", State::Running, Some("rpmbuild")),
+            record("libz", State::Cached, None),
+        ];
+        assert_eq!(
+            status_line(&records, Duration::from_secs(61), 225),
+            "Building [1m01s]: 1 running, 1 done, 1 cached, 0 failed; \
+            package kernel-6.1 (x86_64) in rpmbuild for 2m05s"
+        );
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let record: Record = serde_json::from_str(
+            r#"{"name":"kernel-6.1","kind":"package","arch":"aarch64","state":"failed",
+            "stage":"rpmbuild","started":100,"updated":160}"#,
+        )
+        .unwrap();
+        assert_eq!(record.state, State::Failed);
+        assert_eq!(
+            summary(&[record], Duration::from_secs(60)),
+            "Finished 0 builds in 1m00s (0 cached), failed: kernel-6.1 (aarch64)"
+        );
+    }
+
+    #[test]
+    fn test_summary_maintainers() {
+        let kernel: Record = serde_json::from_str(
+            r#"{"name":"kernel-6.1","kind":"package","arch":"x86_64","state":"failed",
+            "started":100,"updated":160,"maintainers":["@bottlerocket-os/kernel","jane"]}"#,
+        )
+        .unwrap();
+        let records = [kernel, record("glibc", State::Failed, None)];
+        assert_eq!(
+            summary(&records, Duration::from_secs(60)),
+            "Finished 0 builds in 1m00s (0 cached), failed: \
+            kernel-6.1 (x86_64, maintainers: @bottlerocket-os/kernel jane), glibc (x86_64)"
+        );
+    }
+}
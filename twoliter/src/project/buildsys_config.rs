@@ -0,0 +1,220 @@
+/*!
+An optional `buildsys.toml` file next to `Twoliter.toml` can hold buildsys settings that would
+otherwise have to be exported as `BUILDSYS_*` environment variables. Each key is the name of the
+variable without the `BUILDSYS_` prefix, in kebab case, so `lookaside-cache` sets
+`BUILDSYS_LOOKASIDE_CACHE`:
+
+```toml
+lookaside-cache = "https://cache.example.com"
+upstream-source-fallback = true
+rpmbuild-jobs = 4
+```
+
+Variables that are set in the environment override the file, and command line flags and
+`Twoliter.toml` settings override both. Run twoliter with `--log-level debug` to see where each
+setting came from. The file is meant for local development, so it is usually left out of version
+control.
+*/
+
+use crate::common::fs;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+use tracing::debug;
+
+/// The name of the buildsys settings file, relative to the project directory.
+pub(crate) const BUILDSYS_CONFIG: &str = "buildsys.toml";
+
+/// Variables that twoliter derives from `Twoliter.toml` and doesn't allow to be set any other way.
+const RESERVED: [&str; 5] = [
+    "BUILDSYS_OUTPUT_GENERATION_ID",
+    "BUILDSYS_REGISTRY",
+    "BUILDSYS_SDK_NAME",
+    "BUILDSYS_SDK_VERSION",
+    "BUILDSYS_VERSION_IMAGE",
+];
+
+/// The settings from a project's `buildsys.toml`, as environment variables.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct BuildsysConfig {
+    path: Option<PathBuf>,
+    vars: BTreeMap<String, String>,
+}
+
+impl BuildsysConfig {
+    /// Loads `buildsys.toml` from the project directory. A project without one has no settings.
+    pub(crate) async fn load(project_dir: impl AsRef<Path>) -> Result<Self> {
+        let path = project_dir.as_ref().join(BUILDSYS_CONFIG);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let data = fs::read_to_string(&path).await?;
+        let mut config = Self::parse(&data).with_context(|| {
+            format!("Unable to load buildsys settings from '{}'", path.display())
+        })?;
+        config.path = Some(path);
+        Ok(config)
+    }
+
+    fn parse(data: &str) -> Result<Self> {
+        let table: Table = toml::from_str(data)?;
+        let mut vars = BTreeMap::new();
+        for (key, value) in table {
+            let var = env_name(&key)?;
+            let value = match value {
+                Value::String(s) => s,
+                Value::Integer(i) => i.to_string(),
+                Value::Boolean(b) => b.to_string(),
+                Value::Array(items) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        Value::String(s) => Ok(s),
+                        _ => bail!("'{}' must be a list of strings", key),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .join(" "),
+                _ => bail!(
+                    "'{}' must be a string, integer, boolean or list of strings",
+                    key
+                ),
+            };
+            vars.insert(var, value);
+        }
+        Ok(Self { path: None, vars })
+    }
+
+    /// The value that the file sets for `var`, if it sets one.
+    pub(crate) fn var(&self, var: &str) -> Option<&str> {
+        self.vars.get(var).map(String::as_str)
+    }
+
+    /// The directory that variant builds write their images to. Like `Makefile.toml`, it's
+    /// `BUILDSYS_IMAGES_DIR`, or else `images` in `BUILDSYS_BUILD_DIR`, and relative paths are
+    /// relative to `project_dir`.
+    pub(crate) fn images_dir(&self, project_dir: &Path) -> PathBuf {
+        if let Some(dir) = self.setting("BUILDSYS_IMAGES_DIR") {
+            return project_dir.join(dir);
+        }
+        let build_dir = self.setting("BUILDSYS_BUILD_DIR");
+        project_dir
+            .join(build_dir.as_deref().unwrap_or("build"))
+            .join("images")
+    }
+
+    /// The value of `var` from the environment, or else from the file, if either sets it to
+    /// something other than an empty string.
+    pub(crate) fn setting(&self, var: &str) -> Option<String> {
+        std::env::var(var)
+            .ok()
+            .or_else(|| self.var(var).map(String::from))
+            .filter(|value| !value.is_empty())
+    }
+
+    /// The variables to pass to buildsys. Variables that are already set in the environment are
+    /// left out so that they take precedence. Where each setting comes from is logged at the debug
+    /// level.
+    pub(crate) fn envs(&self) -> Vec<(String, String)> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+        self.vars
+            .iter()
+            .filter(|(var, _)| {
+                let overridden = std::env::var_os(var).is_some();
+                if overridden {
+                    debug!(
+                        "{} is set in the environment, which overrides '{}'",
+                        var,
+                        path.display()
+                    );
+                } else {
+                    debug!("{} is set by '{}'", var, path.display());
+                }
+                !overridden
+            })
+            .map(|(var, value)| (var.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// Converts a key like `lookaside-cache` into the variable it sets, `BUILDSYS_LOOKASIDE_CACHE`.
+fn env_name(key: &str) -> Result<String> {
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        bail!(
+            "'{}' is not a valid setting, keys must be kebab case like 'lookaside-cache'",
+            key
+        );
+    }
+    let var = format!("BUILDSYS_{}", key.replace('-', "_").to_ascii_uppercase());
+    if RESERVED.contains(&var.as_str()) {
+        bail!("'{}' can not be set in {}", key, BUILDSYS_CONFIG);
+    }
+    Ok(var)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = BuildsysConfig::parse(
+            r#"
+            lookaside-cache = "https://cache.example.com"
+            upstream-source-fallback = true
+            rpmbuild-jobs = 4
+            build-secrets = ["id=netrc,src=/home/me/.netrc", "id=token,env=TOKEN"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.vars.get("BUILDSYS_LOOKASIDE_CACHE").unwrap(),
+            "https://cache.example.com"
+        );
+        assert_eq!(
+            config
+                .vars
+                .get("BUILDSYS_UPSTREAM_SOURCE_FALLBACK")
+                .unwrap(),
+            "true"
+        );
+        assert_eq!(config.var("BUILDSYS_RPMBUILD_JOBS"), Some("4"));
+        assert_eq!(config.var("BUILDSYS_LAYER_CACHE"), None);
+        assert_eq!(
+            config.vars.get("BUILDSYS_BUILD_SECRETS").unwrap(),
+            "id=netrc,src=/home/me/.netrc id=token,env=TOKEN"
+        );
+    }
+
+    #[test]
+    fn test_images_dir() {
+        let project_dir = Path::new("/project");
+        let config = BuildsysConfig::default();
+        assert_eq!(
+            config.images_dir(project_dir),
+            PathBuf::from("/project/build/images")
+        );
+        let config = BuildsysConfig::parse("build-dir = \"/scratch/build\"").unwrap();
+        assert_eq!(
+            config.images_dir(project_dir),
+            PathBuf::from("/scratch/build/images")
+        );
+        let config = BuildsysConfig::parse("images-dir = \"out/images\"").unwrap();
+        assert_eq!(
+            config.images_dir(project_dir),
+            PathBuf::from("/project/out/images")
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_keys() {
+        assert!(BuildsysConfig::parse("BUILDSYS_ARCH = \"x86_64\"").is_err());
+        assert!(BuildsysConfig::parse("version-image = \"1.0.0\"").is_err());
+        assert!(BuildsysConfig::parse("[fetch]\nretries = 3").is_err());
+    }
+}
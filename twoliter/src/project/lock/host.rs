@@ -0,0 +1,150 @@
+use crate::common::fs;
+use anyhow::{Context, Result};
+use buildsys::os_release::os_name;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Where the host that the project was locked on is recorded, relative to the project. It's kept
+/// with the build state rather than in Twoliter.lock, which is committed and shared by everyone
+/// who builds the project, whatever their host.
+const LOCK_HOST: &str = "build/state/lock-host.toml";
+
+/// The host that a project was locked on. It's recorded when the project is locked, so that a
+/// build on a host that differs can say how, which helps to explain builds that only fail on some
+/// machines. Anything that couldn't be found out is left out.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HostEnvironment {
+    /// The name of the host's OS, from `/etc/os-release`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    /// The release of the host's kernel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// The version of the Docker daemon.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub twoliter: Option<String>,
+}
+
+impl HostEnvironment {
+    /// Finds out what host twoliter is running on.
+    pub(crate) async fn detect() -> Self {
+        Self {
+            os: os_name(fs::read_to_string("/etc/os-release").await.ok().as_deref()),
+            kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+                .await
+                .ok()
+                .map(|s| s.trim().to_string()),
+            arch: Some(std::env::consts::ARCH.to_string()),
+            docker: docker_version().await,
+            twoliter: Some(env!("CARGO_PKG_VERSION").to_string()),
+        }
+    }
+
+    /// Records this host as the one that the project in `project_dir` was locked on.
+    pub(crate) async fn record(&self, project_dir: &Path) -> Result<()> {
+        let path = project_dir.join(LOCK_HOST);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let host = toml::to_string(self).context("failed to serialize the lock host")?;
+        fs::write(&path, host).await
+    }
+
+    /// The host that the project in `project_dir` was locked on, if it was recorded.
+    pub(crate) async fn load(project_dir: &Path) -> Option<Self> {
+        let host = fs::read_to_string(project_dir.join(LOCK_HOST)).await.ok()?;
+        toml::from_str(&host)
+            .map_err(|e| debug!("Unable to parse the lock host: {e}"))
+            .ok()
+    }
+
+    /// The fields that differ between this host and `other`, as the name of the field and the
+    /// values on each host. Fields that either host doesn't know are skipped.
+    pub(crate) fn differences(&self, other: &Self) -> Vec<(&'static str, String, String)> {
+        [
+            ("os", &self.os, &other.os),
+            ("kernel", &self.kernel, &other.kernel),
+            ("arch", &self.arch, &other.arch),
+            ("docker", &self.docker, &other.docker),
+            ("twoliter", &self.twoliter, &other.twoliter),
+        ]
+        .into_iter()
+        .filter_map(|(field, ours, theirs)| match (ours, theirs) {
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                Some((field, ours.clone(), theirs.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+    }
+
+    /// Warns about each way that `current` differs from this host, which the project was locked
+    /// on.
+    pub(crate) fn warn_differences(&self, current: &Self) {
+        for (field, locked, current) in self.differences(current) {
+            warn!(
+                "The host's {field} is '{current}', but Twoliter.lock was created on a host \
+                with '{locked}', which may explain differences between builds"
+            );
+        }
+    }
+}
+
+async fn docker_version() -> Option<String> {
+    let output = Command::new("docker")
+        .args(["version", "--format", "{{.Server.Version}}"])
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        debug!("Unable to find the Docker daemon's version");
+        return None;
+    }
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!version.is_empty()).then_some(version)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record() {
+        let project_dir = tempfile::tempdir().unwrap();
+        assert!(HostEnvironment::load(project_dir.path()).await.is_none());
+        let host = HostEnvironment {
+            os: Some("Fedora Linux 40 (Forty)".to_string()),
+            twoliter: Some("0.5.0".to_string()),
+            ..Default::default()
+        };
+        host.record(project_dir.path()).await.unwrap();
+        assert_eq!(HostEnvironment::load(project_dir.path()).await, Some(host));
+    }
+
+    #[test]
+    fn test_differences() {
+        let locked = HostEnvironment {
+            os: Some("Fedora Linux 40 (Forty)".to_string()),
+            docker: Some("25.0.3".to_string()),
+            twoliter: Some("0.5.0".to_string()),
+            ..Default::default()
+        };
+        let current = HostEnvironment {
+            os: Some("Fedora Linux 40 (Forty)".to_string()),
+            kernel: Some("6.8.5".to_string()),
+            docker: Some("26.1.0".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            locked.differences(&current),
+            vec![("docker", "25.0.3".to_string(), "26.1.0".to_string())]
+        );
+    }
+}
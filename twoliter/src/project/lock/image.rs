@@ -6,14 +6,13 @@ use crate::project::{Image, ProjectImage, ValidIdentifier, VendedArtifact};
 use anyhow::{bail, Context, Result};
 use base64::Engine;
 use futures::{pin_mut, stream, StreamExt, TryStreamExt};
-use log::trace;
 use oci_cli_wrapper::{ConfigView, DockerArchitecture, ImageTool};
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 use std::fmt::{Debug, Display, Formatter};
 use std::path::Path;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, trace, warn};
 
 /// The OCI config label prefix to which the supported kit metadata version is appended.
 ///
@@ -72,10 +71,8 @@ impl VendedArtifact for LockedImage {
 #[serde(deny_unknown_fields)]
 pub(crate) struct ImageMetadata {
     /// The name of the kit
-    #[expect(dead_code)]
     pub name: String,
     /// The version of the kit
-    #[expect(dead_code)]
     pub version: Version,
     /// The required sdk of the kit,
     pub sdk: Image,
@@ -101,7 +98,7 @@ pub(crate) struct EncodedKitMetadata(String);
 
 impl EncodedKitMetadata {
     #[instrument(level = "trace")]
-    async fn try_from_image(image_uri: &str, image_tool: &ImageTool) -> Result<Self> {
+    pub(super) async fn try_from_image(image_uri: &str, image_tool: &ImageTool) -> Result<Self> {
         tracing::trace!(image_uri, "Extracting kit metadata from OCI image config");
         let config = image_tool.get_config(image_uri).await?;
         let kit_metadata = EncodedKitMetadata(Self::extract_encoded_kit_metadata(&config)?);
@@ -192,6 +189,8 @@ impl Debug for EncodedKitMetadata {
 pub struct ImageResolver {
     image: ProjectImage,
     skip_metadata_retrieval: bool,
+    locked_digest: Option<String>,
+    allow_drift: bool,
 }
 
 impl ImageResolver {
@@ -199,9 +198,20 @@ impl ImageResolver {
         Ok(Self {
             image: image.clone(),
             skip_metadata_retrieval: false,
+            locked_digest: None,
+            allow_drift: false,
         })
     }
 
+    /// Check that the image's manifest still has the digest from Twoliter.lock when extracting
+    /// it, so that a tag that was pushed again after the lock was resolved isn't used by mistake.
+    /// With `allow_drift`, a different digest is logged as a warning instead.
+    pub(crate) fn verify_locked_digest(mut self, digest: &str, allow_drift: bool) -> Self {
+        self.locked_digest = Some(digest.to_string());
+        self.allow_drift = allow_drift;
+        self
+    }
+
     /// Skip metadata retrieval when resolving images.
     ///
     /// This is useful for SDKs, which don't store image metadata (no deps.)
@@ -215,12 +225,11 @@ impl ImageResolver {
         fields(image = %self.image, uri = %self.image.project_image_uri())
     )]
     /// Calculate the digest of the locked image
-    async fn calculate_digest(&self, image_tool: &ImageTool) -> Result<String> {
+    pub(super) async fn calculate_digest(&self, image_tool: &ImageTool) -> Result<String> {
         let image_uri = self.image.project_image_uri();
         let image_uri_str = image_uri.to_string();
         let manifest_bytes = image_tool.get_manifest(image_uri_str.as_str()).await?;
-        let digest = sha2::Sha256::digest(manifest_bytes.as_slice());
-        let digest = base64::engine::general_purpose::STANDARD.encode(digest.as_slice());
+        let digest = manifest_digest(&manifest_bytes);
         debug!(
             "Calculated digest for locked image '{}': '{}'",
             image_uri, digest,
@@ -232,7 +241,7 @@ impl ImageResolver {
         level = "trace",
         fields(image = %self.image, uri = %self.image.project_image_uri())
     )]
-    async fn get_manifest(&self, image_tool: &ImageTool) -> Result<ManifestListView> {
+    pub(super) async fn get_manifest(&self, image_tool: &ImageTool) -> Result<ManifestListView> {
         let uri = self.image.project_image_uri().to_string();
         debug!(image=%self.image, uri, "Fetching image manifest.");
         let manifest_bytes = image_tool.get_manifest(uri.as_str()).await?;
@@ -327,9 +336,20 @@ impl ImageResolver {
         create_dir_all(&target_path).await?;
         create_dir_all(&cache_path).await?;
 
-        // First get the manifest for the specific requested architecture
+        // First get the manifest for the specific requested architecture, from the same manifest
+        // list whose digest is checked against the lock.
         let uri = self.image.project_image_uri();
-        let manifest_list = self.get_manifest(image_tool).await?;
+        let manifest_bytes = image_tool.get_manifest(uri.to_string().as_str()).await?;
+        if let Some(locked_digest) = &self.locked_digest {
+            check_drift(
+                &self.image,
+                locked_digest,
+                &manifest_digest(&manifest_bytes),
+                self.allow_drift,
+            )?;
+        }
+        let manifest_list: ManifestListView = serde_json::from_slice(manifest_bytes.as_slice())
+            .context("failed to deserialize manifest list")?;
         let docker_arch = DockerArchitecture::try_from(arch)?;
         let manifest = manifest_list
             .manifests
@@ -360,6 +380,40 @@ impl ImageResolver {
     }
 }
 
+/// The digest that Twoliter.lock records for an image: the base64-encoded SHA-256 of its manifest.
+fn manifest_digest(manifest_bytes: &[u8]) -> String {
+    let digest = sha2::Sha256::digest(manifest_bytes);
+    base64::engine::general_purpose::STANDARD.encode(digest.as_slice())
+}
+
+/// Fails if the digest the registry reports for `image` isn't the one in Twoliter.lock, or only
+/// warns if `allow_drift` is set.
+pub(super) fn check_drift(
+    image: &impl Display,
+    locked_digest: &str,
+    digest: &str,
+    allow_drift: bool,
+) -> Result<()> {
+    if locked_digest == digest {
+        return Ok(());
+    }
+    if allow_drift {
+        warn!(
+            "Using '{}' with digest '{}', although Twoliter.lock expects '{}'",
+            image, digest, locked_digest
+        );
+        return Ok(());
+    }
+    bail!(
+        "the registry reports digest '{}' for '{}', but Twoliter.lock expects '{}'; the image's \
+        tag may have been pushed again. Run `twoliter update` to accept the new image, or pass \
+        `--allow-drift` to fetch it anyway",
+        digest,
+        image,
+        locked_digest
+    )
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -449,4 +503,14 @@ mod test {
             "bar".to_string()
         );
     }
+
+    #[test]
+    fn test_check_drift() {
+        assert!(check_drift(&"core-kit", "abc=", "abc=", false).is_ok());
+        assert!(check_drift(&"core-kit", "abc=", "def=", true).is_ok());
+        let err = check_drift(&"core-kit", "abc=", "def=", false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'def='") && err.contains("--allow-drift"));
+    }
 }
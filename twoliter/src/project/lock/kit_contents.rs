@@ -0,0 +1,203 @@
+use super::archive::OCIArchive;
+use super::image::{EncodedKitMetadata, ImageMetadata};
+use super::verify_kit::rpm_arch;
+use super::views::ManifestListView;
+use anyhow::{ensure, Context, Result};
+use async_walkdir::WalkDir;
+use futures::StreamExt;
+use oci_cli_wrapper::ImageTool;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{debug, info, instrument};
+
+/// What a kit image holds: its metadata, and the packages in the image for each architecture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KitContents {
+    pub name: String,
+    pub version: String,
+    /// The SDK and kits the kit was built with, by `vendor/name`, with their versions.
+    pub dependencies: BTreeMap<String, String>,
+    /// The contents of the image for each architecture, by RPM architecture.
+    pub arches: BTreeMap<String, KitArchContents>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KitArchContents {
+    /// The digest of the architecture's image.
+    pub digest: String,
+    /// Each package in the image, by name.
+    pub packages: BTreeMap<String, KitPackage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KitPackage {
+    /// The package's version, as `version-release`.
+    pub version: String,
+    /// The SHA-256 digest of the package's RPM.
+    pub sha256: String,
+}
+
+/// Pulls the kit image at `uri`, such as
+/// `public.ecr.aws/bottlerocket/bottlerocket-core-kit:v2.0.0`, and lists what it holds. Kits don't
+/// need to be dependencies of a project to be inspected.
+#[instrument(level = "trace")]
+pub(crate) async fn inspect_kit(uri: &str) -> Result<KitContents> {
+    let image_tool = ImageTool::from_builtin_krane();
+    let (registry, repository) = split_image_uri(uri)?;
+    info!("Inspecting kit '{}'", uri);
+
+    let manifest_bytes = image_tool.get_manifest(uri).await?;
+    let manifest_list: ManifestListView = serde_json::from_slice(manifest_bytes.as_slice())
+        .context("failed to deserialize manifest list")?;
+    let first = manifest_list
+        .manifests
+        .first()
+        .context(format!("no images found for kit '{}'", uri))?;
+    let metadata: ImageMetadata = EncodedKitMetadata::try_from_image(
+        &format!("{registry}/{repository}@{}", first.digest),
+        &image_tool,
+    )
+    .await?
+    .try_into()
+    .context("Failed to decode and parse kit metadata")?;
+
+    let work_dir = tempfile::TempDir::new().context("failed to create temporary directory")?;
+    let mut arches = BTreeMap::new();
+    for manifest in &manifest_list.manifests {
+        let Some(platform) = &manifest.platform else {
+            continue;
+        };
+        let arch = rpm_arch(&platform.architecture);
+        let archive = OCIArchive::new(
+            registry,
+            repository,
+            &manifest.digest,
+            work_dir.path().join("cache"),
+        )?;
+        archive.pull_image(&image_tool).await?;
+        let kit_dir = work_dir.path().join(arch);
+        archive.unpack_layers(&kit_dir).await?;
+        arches.insert(
+            arch.to_string(),
+            KitArchContents {
+                digest: manifest.digest.clone(),
+                packages: kit_packages(&kit_dir).await?,
+            },
+        );
+    }
+
+    let dependencies = std::iter::once(&metadata.sdk)
+        .chain(&metadata.kits)
+        .map(|image| {
+            (
+                format!("{}/{}", image.vendor, image.name),
+                image.version.to_string(),
+            )
+        })
+        .collect();
+    Ok(KitContents {
+        name: metadata.name,
+        version: metadata.version.to_string(),
+        dependencies,
+        arches,
+    })
+}
+
+/// Splits an image URI into its registry and repository, leaving out its tag or digest.
+fn split_image_uri(uri: &str) -> Result<(&str, &str)> {
+    let name = match uri.split_once('@') {
+        Some((name, _digest)) => name,
+        None => match uri.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => name,
+            _ => uri,
+        },
+    };
+    let (registry, repository) = name
+        .split_once('/')
+        .context(format!("no registry found in image URI '{}'", uri))?;
+    ensure!(
+        !repository.is_empty(),
+        "no repository found in image URI '{}'",
+        uri
+    );
+    Ok((registry, repository))
+}
+
+/// Finds the RPMs in an extracted kit, and reads the name and version of each from its file name.
+async fn kit_packages(kit_dir: &Path) -> Result<BTreeMap<String, KitPackage>> {
+    let mut packages = BTreeMap::new();
+    let mut entries = WalkDir::new(kit_dir);
+    while let Some(entry) = entries.next().await {
+        let path = entry.context("error while searching for kit RPMs")?.path();
+        let Some((name, version)) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_rpm_file_name)
+        else {
+            continue;
+        };
+        let sha256 = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || -> Result<String> {
+                let mut f = std::fs::File::open(&path)
+                    .context(format!("failed to open '{}'", path.display()))?;
+                let mut digest = Sha256::new();
+                std::io::copy(&mut f, &mut digest)
+                    .context(format!("failed to read '{}'", path.display()))?;
+                Ok(digest
+                    .finalize()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect())
+            }
+        })
+        .await??;
+        packages.insert(name, KitPackage { version, sha256 });
+    }
+    debug!("Found {} RPMs in '{}'", packages.len(), kit_dir.display());
+    Ok(packages)
+}
+
+/// Reads the name and `version-release` of a package from an RPM file name of the form
+/// `name-version-release.arch.rpm`.
+fn parse_rpm_file_name(file_name: &str) -> Option<(String, String)> {
+    let (nvr, _arch) = file_name.strip_suffix(".rpm")?.rsplit_once('.')?;
+    let (name_version, release) = nvr.rsplit_once('-')?;
+    let (name, version) = name_version.rsplit_once('-')?;
+    Some((name.to_string(), format!("{version}-{release}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_image_uri() {
+        assert_eq!(
+            split_image_uri("public.ecr.aws/bottlerocket/bottlerocket-core-kit:v2.0.0").unwrap(),
+            ("public.ecr.aws", "bottlerocket/bottlerocket-core-kit")
+        );
+        assert_eq!(
+            split_image_uri("localhost:5000/core-kit@sha256:0123abcd").unwrap(),
+            ("localhost:5000", "core-kit")
+        );
+        assert_eq!(
+            split_image_uri("localhost:5000/core-kit").unwrap(),
+            ("localhost:5000", "core-kit")
+        );
+        assert!(split_image_uri("core-kit:v2.0.0").is_err());
+    }
+
+    #[test]
+    fn test_parse_rpm_file_name() {
+        assert_eq!(
+            parse_rpm_file_name("bottlerocket-kernel-6.1-devel-6.1.90-1.1718134234.br1.x86_64.rpm"),
+            Some((
+                "bottlerocket-kernel-6.1-devel".to_string(),
+                "6.1.90-1.1718134234.br1".to_string()
+            ))
+        );
+        assert_eq!(parse_rpm_file_name("repomd.xml"), None);
+    }
+}
@@ -5,29 +5,41 @@
 
 /// Contains operations for working with an OCI Archive
 mod archive;
+/// Records the host that a project was locked on
+mod host;
 /// Covers resolution and validation of a single image dependency in a lock file
 mod image;
+/// Lists the packages in a kit image, whether or not the project depends on it
+mod kit_contents;
 /// Provides tools for marking artifacts as having been verified against the Twoliter lockfile
 mod verification;
+/// Verifies a kit dependency's digest, attachments and packages against the Twoliter lockfile
+mod verify_kit;
 /// Implements view models of common OCI manifest and configuration types
 mod views;
 
+pub(crate) use self::kit_contents::{inspect_kit, KitArchContents, KitContents, KitPackage};
 pub(crate) use self::verification::VerificationTagger;
+pub(crate) use self::verify_kit::{verify_kit, KitVerifyOptions};
 
+use crate::common::exec;
 use crate::common::fs::{create_dir_all, read, write};
 use crate::project::{Project, ValidIdentifier};
 use crate::schema_version::SchemaVersion;
 use anyhow::{bail, ensure, Context, Result};
+use host::HostEnvironment;
 use image::{ImageResolver, LockedImage};
 use oci_cli_wrapper::ImageTool;
 use olpc_cjson::CanonicalFormatter as CanonicalJsonFormatter;
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
 use std::mem::take;
+use std::path::Path;
 use tokio::fs::read_to_string;
+use tokio::process::Command;
 use tracing::{debug, error, info, instrument};
 
 use super::{Locked, ProjectLock, Unlocked};
@@ -146,19 +158,27 @@ impl Lock {
         write(&lock_file_path, lock_str)
             .await
             .context("failed to write lock file")?;
+        HostEnvironment::detect()
+            .await
+            .record(&project.project_dir())
+            .await?;
         Ok(lock_state)
     }
 
     /// Loads the lockfile for the given project.
     ///
     /// Re-resolves the project's dependencies to ensure that the lockfile matches the state of the
-    /// world.
+    /// world. If the only difference is that the registry reports new digests for the same
+    /// images, `allow_drift` continues with the new digests after a warning.
     #[instrument(level = "trace", skip(project))]
-    pub(super) async fn load(project: &Project<Unlocked>) -> Result<Self> {
+    pub(super) async fn load(project: &Project<Unlocked>, allow_drift: bool) -> Result<Self> {
         info!("Resolving project references to check against lock file");
 
         let current_lock = Self::current_lock_state(project).await?;
         let resolved_lock = Self::resolve(project).await?;
+        if let Some(host) = HostEnvironment::load(&project.project_dir()).await {
+            host.warn_differences(&HostEnvironment::detect().await);
+        }
 
         debug!(
             current_lock=?current_lock,
@@ -166,6 +186,12 @@ impl Lock {
             "Comparing resolved lock to current lock state"
         );
         if current_lock != resolved_lock {
+            if let Some(drifted) = current_lock.digest_drift(&resolved_lock) {
+                for (locked, resolved) in drifted {
+                    image::check_drift(locked, &locked.digest, &resolved.digest, allow_drift)?;
+                }
+                return Ok(resolved_lock);
+            }
             error!(
                 current_lock=?current_lock,
                 resolved_lock=?resolved_lock,
@@ -193,6 +219,61 @@ impl Lock {
         Ok(lock)
     }
 
+    /// Loads the lockfile as it was committed at the git `revision`, such as the tag of a release.
+    pub(super) async fn at_revision(project_dir: &Path, revision: &str) -> Result<Self> {
+        let lock_str = exec(
+            Command::new("git")
+                .args(["show", &format!("{revision}:./{TWOLITER_LOCK}")])
+                .current_dir(project_dir),
+            true,
+        )
+        .await
+        .with_context(|| format!("Unable to read {TWOLITER_LOCK} at '{revision}'"))?
+        .unwrap_or_default();
+        toml::from_str(&lock_str).context("failed to deserialize lockfile")
+    }
+
+    /// The image URI of each kit, by name, such as
+    /// `public.ecr.aws/bottlerocket/bottlerocket-core-kit:v2.0.0`.
+    pub(super) fn kit_uris(&self) -> BTreeMap<String, String> {
+        self.kit
+            .iter()
+            .map(|kit| {
+                (
+                    kit.name.to_string(),
+                    format!("{}:v{}", kit.source, kit.version),
+                )
+            })
+            .collect()
+    }
+
+    /// If this lock and `resolved` only differ in the digests of their images, returns the pairs
+    /// of images whose digests differ.
+    fn digest_drift<'a>(
+        &'a self,
+        resolved: &'a Self,
+    ) -> Option<Vec<(&'a LockedImage, &'a LockedImage)>> {
+        if self.schema_version != resolved.schema_version || self.kit.len() != resolved.kit.len() {
+            return None;
+        }
+        let mut drifted = Vec::new();
+        let pairs =
+            std::iter::once((&self.sdk, &resolved.sdk)).chain(self.kit.iter().zip(&resolved.kit));
+        for (locked, found) in pairs {
+            if locked.name != found.name
+                || locked.vendor != found.vendor
+                || locked.version != found.version
+                || locked.source != found.source
+            {
+                return None;
+            }
+            if locked.digest != found.digest {
+                drifted.push((locked, found));
+            }
+        }
+        Some(drifted)
+    }
+
     fn external_kit_metadata(&self) -> ExternalKitMetadata {
         ExternalKitMetadata {
             sdk: self.sdk.clone(),
@@ -200,10 +281,23 @@ impl Lock {
         }
     }
 
-    /// Fetches all external kits defined in a Twoliter.lock to the build directory
+    /// Fetches all external kits defined in a Twoliter.lock to the build directory. The digests
+    /// the registry reports for the SDK and each kit must match the lock, unless `allow_drift` is
+    /// set.
     #[instrument(level = "trace", skip_all)]
-    pub(crate) async fn fetch(&self, project: &Project<Locked>, arch: &str) -> Result<()> {
+    pub(crate) async fn fetch(
+        &self,
+        project: &Project<Locked>,
+        arch: &str,
+        allow_drift: bool,
+    ) -> Result<()> {
         let image_tool = ImageTool::from_builtin_krane();
+        let sdk = project.as_project_image(&self.sdk)?;
+        let sdk_digest = ImageResolver::from_image(&sdk)?
+            .calculate_digest(&image_tool)
+            .await?;
+        image::check_drift(&self.sdk, &self.sdk.digest, &sdk_digest, allow_drift)?;
+
         let target_dir = project.external_kits_dir();
         create_dir_all(&target_dir).await.context(format!(
             "failed to create external-kits directory at {}",
@@ -214,9 +308,10 @@ impl Lock {
             dependencies = ?self.kit.iter().map(ToString::to_string).collect::<Vec<_>>(),
             "Extracting kit dependencies."
         );
-        for image in self.kit.iter() {
-            let image = project.as_project_image(image)?;
-            let resolver = ImageResolver::from_image(&image)?;
+        for locked in self.kit.iter() {
+            let image = project.as_project_image(locked)?;
+            let resolver = ImageResolver::from_image(&image)?
+                .verify_locked_digest(&locked.digest, allow_drift);
             resolver
                 .extract(&image_tool, &project.external_kits_dir(), arch)
                 .await?;
@@ -332,3 +427,39 @@ impl Lock {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn locked(name: &str, version: &str, digest: &str) -> LockedImage {
+        LockedImage {
+            name: ValidIdentifier(name.to_string()),
+            version: Version::parse(version).unwrap(),
+            vendor: ValidIdentifier("bottlerocket".to_string()),
+            source: format!("public.ecr.aws/bottlerocket/{name}:v{version}"),
+            digest: digest.to_string(),
+        }
+    }
+
+    fn lock(kit_version: &str, kit_digest: &str) -> Lock {
+        Lock {
+            schema_version: SchemaVersion::default(),
+            sdk: locked("bottlerocket-sdk", "0.50.0", "sdk="),
+            kit: vec![locked("core-kit", kit_version, kit_digest)],
+        }
+    }
+
+    #[test]
+    fn test_digest_drift() {
+        let current = lock("2.0.0", "abc=");
+
+        let repushed = lock("2.0.0", "def=");
+        let drifted = current.digest_drift(&repushed).unwrap();
+        assert_eq!(drifted.len(), 1);
+        assert_eq!(drifted[0].1.digest, "def=");
+
+        assert!(current.digest_drift(&current).unwrap().is_empty());
+        assert!(current.digest_drift(&lock("2.1.0", "def=")).is_none());
+    }
+}
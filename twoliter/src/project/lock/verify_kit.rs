@@ -0,0 +1,244 @@
+use super::image::{ImageResolver, LockedImage};
+use super::Lock;
+use crate::common::exec_log;
+use crate::project::{Project, Unlocked};
+use anyhow::{bail, ensure, Context, Result};
+use async_walkdir::WalkDir;
+use futures::StreamExt;
+use oci_cli_wrapper::{DockerArchitecture, ImageTool};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::{debug, info, instrument, warn};
+
+/// The tag suffixes cosign uses when attaching artifacts to an image in a registry.
+const COSIGN_SIGNATURE_SUFFIX: &str = "sig";
+const COSIGN_SBOM_SUFFIX: &str = "sbom";
+const COSIGN_ATTESTATION_SUFFIX: &str = "att";
+
+/// Controls which checks are performed by [`verify_kit`] beyond the lockfile digest check, which
+/// always runs.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct KitVerifyOptions {
+    /// Only consider kits from this vendor when looking the kit up in Twoliter.lock.
+    pub vendor: Option<String>,
+    /// Fail if the kit has no cosign signature attached.
+    pub require_signature: bool,
+    /// Fail if the kit has no SBOM or attestation attached.
+    pub require_sbom: bool,
+    /// Verify the cosign signature against this public key.
+    pub cosign_key: Option<PathBuf>,
+    /// The architectures whose RPMs should be checked. All architectures in the manifest list are
+    /// checked when this is empty.
+    pub arches: Vec<String>,
+}
+
+/// Verifies a kit that the project depends on against Twoliter.lock. The manifest digest must
+/// match the lockfile, the embedded kit metadata must be consistent across architectures, any
+/// attached signatures and SBOMs are checked, and every RPM in the kit must pass `rpm -K`.
+#[instrument(level = "trace", skip(project))]
+pub(crate) async fn verify_kit(
+    project: &Project<Unlocked>,
+    kit_name: &str,
+    options: &KitVerifyOptions,
+) -> Result<()> {
+    let lock = Lock::current_lock_state(project).await?;
+    let locked = find_locked_kit(&lock, kit_name, options.vendor.as_deref())?;
+    let image = project.as_project_image(locked)?;
+    let resolver = ImageResolver::from_image(&image)?;
+    let image_tool = ImageTool::from_builtin_krane();
+
+    info!("Verifying kit '{}'", locked);
+    let digest = resolver.calculate_digest(&image_tool).await?;
+    ensure!(
+        digest == locked.digest,
+        "digest of kit '{}' is '{}' but Twoliter.lock expects '{}'",
+        locked,
+        digest,
+        locked.digest
+    );
+    info!("Manifest digest matches Twoliter.lock");
+
+    // Resolving checks that the kit metadata is present and identical for every architecture.
+    resolver.resolve(&image_tool).await?;
+    info!("Kit metadata is consistent across architectures");
+
+    let uri = image.project_image_uri();
+    let registry = uri.registry.as_ref().context("no registry found for kit")?;
+    let repo = format!("{}/{}", registry, uri.repo);
+    let registry_digest = image_tool.get_digest(uri.to_string().as_str()).await?;
+    verify_attachments(&image_tool, &repo, &registry_digest, options).await?;
+
+    let sdk = project.as_project_image(&lock.sdk)?;
+    let manifest_list = resolver.get_manifest(&image_tool).await?;
+    let arches = if options.arches.is_empty() {
+        manifest_list
+            .manifests
+            .iter()
+            .filter_map(|manifest| manifest.platform.as_ref())
+            .map(|platform| rpm_arch(&platform.architecture).to_string())
+            .collect()
+    } else {
+        options.arches.clone()
+    };
+    let kits_dir = tempfile::TempDir::new().context("failed to create temporary directory")?;
+    for arch in arches {
+        resolver
+            .extract(&image_tool, kits_dir.path(), &arch)
+            .await?;
+        let kit_dir = kits_dir
+            .path()
+            .join(locked.vendor.to_string())
+            .join(locked.name.to_string())
+            .join(&arch);
+        verify_rpms(&kit_dir, &sdk.project_image_uri().to_string()).await?;
+        info!("All RPMs for '{}' passed verification", arch);
+    }
+
+    info!("Kit '{}' verified", locked);
+    Ok(())
+}
+
+fn find_locked_kit<'a>(
+    lock: &'a Lock,
+    kit_name: &str,
+    vendor: Option<&str>,
+) -> Result<&'a LockedImage> {
+    let mut matches = lock.kit.iter().filter(|kit| {
+        kit.name.as_ref() == kit_name && vendor.map_or(true, |v| kit.vendor.as_ref() == v)
+    });
+    let locked = matches
+        .next()
+        .context(format!("kit '{}' was not found in Twoliter.lock", kit_name))?;
+    if matches.next().is_some() {
+        bail!(
+            "kit '{}' is provided by more than one vendor in Twoliter.lock, please specify one",
+            kit_name
+        );
+    }
+    Ok(locked)
+}
+
+/// Returns the tag cosign uses for an artifact attached to the image with the given digest, e.g.
+/// `sha256:abc` becomes `sha256-abc.sig`.
+fn cosign_tag(digest: &str, suffix: &str) -> Result<String> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .context(format!("invalid image digest '{}'", digest))?;
+    Ok(format!("{algorithm}-{hex}.{suffix}"))
+}
+
+/// Kit images are pushed with docker architecture names, but kits are extracted by RPM arch.
+pub(super) fn rpm_arch(arch: &DockerArchitecture) -> &'static str {
+    match arch {
+        DockerArchitecture::Amd64 => "x86_64",
+        DockerArchitecture::Arm64 => "aarch64",
+    }
+}
+
+/// Checks for a cosign signature, SBOM and attestation attached to the kit image.
+async fn verify_attachments(
+    image_tool: &ImageTool,
+    repo: &str,
+    digest: &str,
+    options: &KitVerifyOptions,
+) -> Result<()> {
+    let signature = attachment_exists(image_tool, repo, digest, COSIGN_SIGNATURE_SUFFIX).await?;
+    let sbom = attachment_exists(image_tool, repo, digest, COSIGN_SBOM_SUFFIX).await?
+        || attachment_exists(image_tool, repo, digest, COSIGN_ATTESTATION_SUFFIX).await?;
+
+    match (signature, &options.cosign_key) {
+        (true, Some(key)) => {
+            info!("Verifying kit signature with '{}'", key.display());
+            exec_log(
+                Command::new("cosign")
+                    .arg("verify")
+                    .arg("--key")
+                    .arg(key)
+                    .arg(format!("{repo}@{digest}")),
+            )
+            .await
+            .context("kit signature verification failed")?;
+        }
+        (true, None) => info!("Kit is signed, pass --cosign-key to verify the signature"),
+        (false, _) if options.require_signature || options.cosign_key.is_some() => {
+            bail!("no signature is attached to kit image '{repo}@{digest}'")
+        }
+        (false, _) => warn!("No signature is attached to the kit image"),
+    }
+
+    if sbom {
+        info!("Kit has an attached SBOM or attestation");
+    } else if options.require_sbom {
+        bail!("no SBOM or attestation is attached to kit image '{repo}@{digest}'");
+    } else {
+        warn!("No SBOM or attestation is attached to the kit image");
+    }
+    Ok(())
+}
+
+async fn attachment_exists(
+    image_tool: &ImageTool,
+    repo: &str,
+    digest: &str,
+    suffix: &str,
+) -> Result<bool> {
+    let uri = format!("{repo}:{}", cosign_tag(digest, suffix)?);
+    match image_tool.get_manifest(uri.as_str()).await {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            debug!("Unable to fetch '{}', treating it as absent: {}", uri, e);
+            Ok(false)
+        }
+    }
+}
+
+/// Runs `rpm -K` in the SDK against every RPM in an extracted kit, which checks the header and
+/// payload digests of each package.
+async fn verify_rpms(kit_dir: &Path, sdk_uri: &str) -> Result<()> {
+    let mut rpms = Vec::new();
+    let mut entries = WalkDir::new(kit_dir);
+    while let Some(entry) = entries.next().await {
+        let path = entry.context("error while searching for kit RPMs")?.path();
+        if path.extension().is_some_and(|ext| ext == "rpm") {
+            let relative = path
+                .strip_prefix(kit_dir)
+                .context("found RPM outside of the kit directory")?;
+            rpms.push(Path::new("/kit").join(relative));
+        }
+    }
+    ensure!(!rpms.is_empty(), "no RPMs found in '{}'", kit_dir.display());
+    rpms.sort();
+    debug!("Checking {} RPMs in '{}'", rpms.len(), kit_dir.display());
+
+    exec_log(
+        Command::new("docker")
+            .args(["run", "--rm", "--network", "none", "-v"])
+            .arg(format!("{}:/kit:ro", kit_dir.display()))
+            .arg(sdk_uri)
+            .args(["rpm", "-K", "--nosignature"])
+            .args(rpms),
+    )
+    .await
+    .context(format!(
+        "RPM verification failed for '{}'",
+        kit_dir.display()
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cosign_tag() {
+        assert_eq!(
+            cosign_tag("sha256:0123abcd", COSIGN_SIGNATURE_SUFFIX).unwrap(),
+            "sha256-0123abcd.sig"
+        );
+        assert_eq!(
+            cosign_tag("sha256:0123abcd", COSIGN_SBOM_SUFFIX).unwrap(),
+            "sha256-0123abcd.sbom"
+        );
+        assert!(cosign_tag("0123abcd", COSIGN_ATTESTATION_SUFFIX).is_err());
+    }
+}
@@ -0,0 +1,457 @@
+//! Upgrades older `Twoliter.toml` project files to the schema that this version of twoliter reads.
+//! The file is edited rather than rewritten, so that comments and formatting are kept.
+
+use super::is_valid_id_char;
+use crate::compatibility::SUPPORTED_TWOLITER_PROJECT_SCHEMA_VERSION;
+use anyhow::{bail, ensure, Context, Result};
+use std::fmt::Write as _;
+use toml_edit::{value, DocumentMut, Item, Key, Table, TableLike};
+
+/// The top-level tables that older versions of twoliter read, but that are no longer used.
+const REMOVED_TABLES: &[(&str, &str)] =
+    &[("toolchain", "the toolchain is now provided by the SDK")];
+
+/// The changes that bring a project file up to date.
+#[derive(Debug)]
+pub(crate) struct Migration {
+    pub(crate) before: String,
+    pub(crate) after: String,
+    /// A description of each change, in the order they were made.
+    pub(crate) changes: Vec<String>,
+    /// What becomes of `Release.toml`, whose version is moved into the project file.
+    pub(crate) release_toml: ReleaseToml,
+}
+
+/// What becomes of `Release.toml` once its version is moved into the project file.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ReleaseToml {
+    /// There is no `Release.toml`, or it has no version, so it's left alone.
+    Keep,
+    /// `Release.toml` has nothing but its version, so it's removed.
+    Remove,
+    /// `Release.toml` has other keys, such as its migrations, so only its version is removed,
+    /// leaving these contents.
+    Update(String),
+}
+
+impl Migration {
+    /// Migrates `data`, the contents of a `Twoliter.toml` file, given the contents of the
+    /// `Release.toml` file next to it, if there is one.
+    pub(crate) fn new(data: &str, release_toml: Option<&str>) -> Result<Self> {
+        let mut doc: DocumentMut = data.parse().context("Unable to parse the project file")?;
+        let mut changes = Vec::new();
+
+        // Keys were once written in snake case, but twoliter only reads kebab case. The keys are
+        // taken out and put back in order, so that new ones can go first.
+        let root = doc.as_table_mut();
+        let mut entries = Vec::new();
+        for name in root.iter().map(|(k, _)| k.to_string()).collect::<Vec<_>>() {
+            let (key, item) = root.remove_entry(&name).context("Missing key")?;
+            if !name.contains('_') {
+                entries.push((key, item));
+                continue;
+            }
+            let new = name.replace('_', "-");
+            ensure!(
+                !root.contains_key(&new) && !entries.iter().any(|(k, _)| k.get() == new),
+                "The project file has both '{name}' and '{new}'"
+            );
+            changes.push(format!("Renamed '{name}' to '{new}'"));
+            entries.push((rename(&key, &new), item));
+        }
+
+        let supported = i64::from(SUPPORTED_TWOLITER_PROJECT_SCHEMA_VERSION);
+        match entries
+            .iter_mut()
+            .find(|(k, _)| k.get() == "schema-version")
+        {
+            Some((_, item)) => {
+                let version = item
+                    .as_integer()
+                    .context("The schema-version in the project file is not a number")?;
+                ensure!(
+                    version <= supported,
+                    "The project file has schema-version {version}, but this version of twoliter \
+                     supports up to schema-version {supported}. Please upgrade twoliter"
+                );
+                if version < supported {
+                    changes.push(format!(
+                        "Changed schema-version from {version} to {supported}"
+                    ));
+                    *item = value(supported);
+                }
+            }
+            None => {
+                changes.push(format!("Added schema-version {supported}"));
+                insert_first(&mut entries, 0, "schema-version", value(supported));
+            }
+        }
+
+        // The version of the project was once kept in Release.toml, which is deprecated.
+        let (release_version, release_toml) = match release_toml {
+            Some(release_toml) => take_release_toml_version(release_toml)?,
+            None => (None, ReleaseToml::Keep),
+        };
+        let current = entries
+            .iter()
+            .find(|(k, _)| k.get() == "release-version")
+            .map(|(_, item)| item.as_str().map(str::to_string));
+        match (current, &release_version) {
+            (Some(current), Some(version)) => ensure!(
+                current.as_ref() == Some(version),
+                "The version in Release.toml, '{version}', does not match the release-version \
+                 in the project file"
+            ),
+            (Some(_), None) => {}
+            (None, Some(version)) => {
+                changes.push(format!(
+                    "Added release-version '{version}' from Release.toml"
+                ));
+                insert_first(&mut entries, 1, "release-version", value(version));
+            }
+            (None, None) => bail!(
+                "The project file has no release-version, and there is no Release.toml to take \
+                 it from. Please add one, such as release-version = \"1.0.0\""
+            ),
+        }
+        match release_toml {
+            ReleaseToml::Keep => {}
+            ReleaseToml::Remove => changes.push("Removed Release.toml".to_string()),
+            ReleaseToml::Update(_) => {
+                changes.push("Removed the version from Release.toml".to_string())
+            }
+        }
+
+        for (key, item) in entries {
+            root.insert_formatted(&key, item);
+        }
+
+        for (name, reason) in REMOVED_TABLES {
+            if root.remove(name).is_some() {
+                changes.push(format!("Removed [{name}], since {reason}"));
+            }
+        }
+
+        // The SDK was once given with its registry, rather than with a vendor.
+        let vendors = vendors(&doc);
+        let moved = match doc.get_mut("sdk").and_then(Item::as_table_like_mut) {
+            Some(sdk) => take_registry(sdk, &vendors)?,
+            None => None,
+        };
+        if let Some((name, registry)) = moved {
+            changes.push(format!("Moved the registry of the SDK to [vendor.{name}]"));
+            if !vendors.iter().any(|(vendor, _)| *vendor == name) {
+                add_vendor(&mut doc, &name, registry)?;
+            }
+        }
+
+        Ok(Self {
+            before: data.to_string(),
+            after: doc.to_string(),
+            changes,
+            release_toml,
+        })
+    }
+
+    /// Whether the project file is already up to date.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The changes to the project file as a unified diff, with `name` as the name of the file.
+    pub(crate) fn diff(&self, name: &str) -> String {
+        diff(name, &self.before, &self.after)
+    }
+}
+
+/// Whether `data` looks like a project file that `twoliter migrate-project` would update, so that
+/// errors from reading it can say so.
+pub(crate) fn is_outdated(data: &str) -> bool {
+    Migration::new(data, None).is_ok_and(|migration| !migration.is_empty())
+}
+
+/// Takes the version out of the contents of a `Release.toml` file, if it has one, and returns it
+/// with what becomes of the file. The rest of the file is edited rather than rewritten.
+fn take_release_toml_version(release_toml: &str) -> Result<(Option<String>, ReleaseToml)> {
+    let mut doc: DocumentMut = release_toml
+        .parse()
+        .context("Unable to parse Release.toml")?;
+    let Some(version) = doc.remove("version") else {
+        return Ok((None, ReleaseToml::Keep));
+    };
+    let version = version
+        .as_str()
+        .map(str::to_string)
+        .context("The version in Release.toml is not a string")?;
+    let release_toml = if doc.as_table().is_empty() {
+        ReleaseToml::Remove
+    } else {
+        ReleaseToml::Update(doc.to_string())
+    };
+    Ok((Some(version), release_toml))
+}
+
+/// `key` with the name `name`, keeping the comments and whitespace around it.
+fn rename(key: &Key, name: &str) -> Key {
+    let mut new = Key::new(name);
+    *new.leaf_decor_mut() = key.leaf_decor().clone();
+    new
+}
+
+/// Inserts a new key at `index`, or at the end if there are fewer entries. A key that goes first
+/// takes the comments before the key that used to be first, since they're usually about the file.
+fn insert_first(entries: &mut Vec<(Key, Item)>, index: usize, name: &str, item: Item) {
+    let index = index.min(entries.len());
+    let mut key = Key::new(name);
+    if index == 0 {
+        if let Some((first, _)) = entries.first_mut() {
+            *key.leaf_decor_mut() = first.leaf_decor().clone();
+            first.leaf_decor_mut().clear();
+        }
+    }
+    entries.insert(index, (key, item));
+}
+
+/// The name of each vendor in the project file and its registry.
+fn vendors(doc: &DocumentMut) -> Vec<(String, String)> {
+    doc.get("vendor")
+        .and_then(Item::as_table_like)
+        .map(|vendors| {
+            vendors
+                .iter()
+                .filter_map(|(name, vendor)| {
+                    let registry = vendor.as_table_like()?.get("registry")?.as_str()?;
+                    Some((name.to_string(), registry.to_string()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Replaces the `registry` of `sdk` with a `vendor`, named for an existing vendor with the same
+/// registry, or else for the last part of the registry. Returns the vendor and the registry.
+fn take_registry(
+    sdk: &mut dyn TableLike,
+    vendors: &[(String, String)],
+) -> Result<Option<(String, Item)>> {
+    if sdk.contains_key("vendor") {
+        return Ok(None);
+    }
+    let Some(registry) = sdk.remove("registry") else {
+        return Ok(None);
+    };
+    let name = registry
+        .as_str()
+        .context("The registry of the SDK is not a string")?;
+    let name = match vendors.iter().find(|(_, r)| r == name) {
+        Some((vendor, _)) => vendor.clone(),
+        None => vendor_name(name),
+    };
+    sdk.insert("vendor", value(&name));
+    Ok(Some((name, registry)))
+}
+
+/// Adds `[vendor.<name>]` to the project file with `registry`.
+fn add_vendor(doc: &mut DocumentMut, name: &str, registry: Item) -> Result<()> {
+    let vendors = doc
+        .entry("vendor")
+        .or_insert_with(|| {
+            let mut vendors = Table::new();
+            vendors.set_implicit(true);
+            Item::Table(vendors)
+        })
+        .as_table_like_mut()
+        .context("The vendor key in the project file is not a table")?;
+    let mut vendor = Table::new();
+    vendor.insert("registry", registry);
+    vendors.insert(name, Item::Table(vendor));
+    Ok(())
+}
+
+/// A vendor name for `registry`, such as `bottlerocket` for `public.ecr.aws/bottlerocket`.
+fn vendor_name(registry: &str) -> String {
+    let name = registry
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if is_valid_id_char(c) { c } else { '-' })
+        .collect::<String>();
+    if name.is_empty() {
+        "default".to_string()
+    } else {
+        name
+    }
+}
+
+/// A unified diff of `before` and `after`, with three lines of context around each change.
+fn diff(name: &str, before: &str, after: &str) -> String {
+    const CONTEXT: usize = 3;
+    let old = before.lines().collect::<Vec<_>>();
+    let new = after.lines().collect::<Vec<_>>();
+
+    // The length of the longest common subsequence of `old[i..]` and `new[j..]`.
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((' ', i, j));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', i, j));
+            i += 1;
+        } else {
+            ops.push(('+', i, j));
+            j += 1;
+        }
+    }
+
+    let mut s = String::new();
+    let changed = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, (op, _, _))| *op != ' ')
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    if changed.is_empty() {
+        return s;
+    }
+    let _ = writeln!(s, "--- a/{name}\n+++ b/{name}");
+    let mut index = 0;
+    while index < changed.len() {
+        let start = changed[index].saturating_sub(CONTEXT);
+        let mut end = changed[index];
+        while index < changed.len() && changed[index] <= end + 2 * CONTEXT {
+            end = changed[index];
+            index += 1;
+        }
+        let end = (end + CONTEXT + 1).min(ops.len());
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|(op, _, _)| *op != '+').count();
+        let new_len = hunk.iter().filter(|(op, _, _)| *op != '-').count();
+        let (_, old_start, new_start) = hunk[0];
+        let _ = writeln!(
+            s,
+            "@@ -{},{old_len} +{},{new_len} @@",
+            old_start + usize::from(old_len > 0),
+            new_start + usize::from(new_len > 0)
+        );
+        for (op, i, j) in hunk {
+            let line = if *op == '+' { new[*j] } else { old[*i] };
+            let _ = writeln!(s, "{op}{line}");
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let before = r#"# Our project
+schema_version = 1
+release_version = "1.2.0"
+
+[sdk]
+registry = "public.ecr.aws/bottlerocket"
+name = "bottlerocket-sdk"
+version = "0.42.0"
+
+[toolchain]
+registry = "public.ecr.aws/bottlerocket"
+name = "bottlerocket-toolchain"
+version = "0.42.0"
+"#;
+        let migration = Migration::new(before, None).unwrap();
+        assert_eq!(
+            migration.after,
+            r#"# Our project
+schema-version = 1
+release-version = "1.2.0"
+
+[sdk]
+name = "bottlerocket-sdk"
+version = "0.42.0"
+vendor = "bottlerocket"
+
+[vendor.bottlerocket]
+registry = "public.ecr.aws/bottlerocket"
+"#
+        );
+        assert_eq!(migration.changes.len(), 4);
+        assert_eq!(migration.release_toml, ReleaseToml::Keep);
+        assert!(is_outdated(before));
+        assert!(!is_outdated(&migration.after));
+    }
+
+    #[test]
+    fn test_migrate_release_toml() {
+        let before = "[vendor.bottlerocket]\nregistry = \"public.ecr.aws/bottlerocket\"\n";
+        let migration = Migration::new(before, Some("version = \"1.2.0\"\n")).unwrap();
+        assert_eq!(
+            migration.after,
+            "schema-version = 1\nrelease-version = \"1.2.0\"\n[vendor.bottlerocket]\n\
+             registry = \"public.ecr.aws/bottlerocket\"\n"
+        );
+        assert_eq!(migration.release_toml, ReleaseToml::Remove);
+        assert!(Migration::new(before, None).is_err());
+
+        let release_toml = "version = \"1.2.0\"\n\n[migrations]\n\"(0.1.0, 1.2.0)\" = []\n";
+        let migration = Migration::new(before, Some(release_toml)).unwrap();
+        assert_eq!(
+            migration.release_toml,
+            ReleaseToml::Update("\n[migrations]\n\"(0.1.0, 1.2.0)\" = []\n".to_string())
+        );
+
+        let migration = Migration::new(&migration.after, Some("[migrations]\n")).unwrap();
+        assert!(migration.is_empty());
+        assert_eq!(migration.release_toml, ReleaseToml::Keep);
+    }
+
+    #[test]
+    fn test_migrate_current() {
+        let current = "schema-version = 1\nrelease-version = \"1.0.0\"\n";
+        let migration = Migration::new(current, None).unwrap();
+        assert!(migration.is_empty());
+        assert_eq!(migration.after, current);
+        assert_eq!(migration.diff("Twoliter.toml"), "");
+        assert!(Migration::new("schema-version = 2\nrelease-version = \"1.0.0\"\n", None).is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let after = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+        let expected = [
+            "--- a/f",
+            "+++ b/f",
+            "@@ -1,5 +1,5 @@",
+            " a",
+            "-b",
+            "+B",
+            " c",
+            " d",
+            " e",
+            "@@ -8,3 +8,4 @@",
+            " h",
+            " i",
+            " j",
+            "+k",
+        ];
+        assert_eq!(diff("f", before, after), expected.join("\n") + "\n");
+    }
+}
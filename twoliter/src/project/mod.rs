@@ -1,8 +1,13 @@
+pub(crate) mod buildsys_config;
 mod lock;
+pub(crate) mod migrate;
 pub(crate) mod vendor;
 
+pub(crate) use self::buildsys_config::BuildsysConfig;
 pub(crate) use self::vendor::ArtifactVendor;
-pub(crate) use lock::VerificationTagger;
+pub(crate) use lock::{
+    inspect_kit, KitArchContents, KitContents, KitPackage, KitVerifyOptions, VerificationTagger,
+};
 
 use self::lock::{Lock, LockedSDK, Override};
 use crate::common::fs::{self, read_to_string};
@@ -10,18 +15,19 @@ use crate::compatibility::SUPPORTED_TWOLITER_PROJECT_SCHEMA_VERSION;
 use crate::docker::ImageUri;
 use crate::schema_version::SchemaVersion;
 use anyhow::{ensure, Context, Result};
-use async_recursion::async_recursion;
 use async_trait::async_trait;
 use async_walkdir::WalkDir;
+use buildsys::hooks::{Hook, Hooks, Stage, PACKAGE_HOOKS_VAR, VARIANT_HOOKS_VAR};
 use buildsys_config::{EXTERNAL_KIT_DIRECTORY, EXTERNAL_KIT_METADATA};
 use futures::stream::StreamExt;
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::Hash;
+use std::num::{NonZeroU16, NonZeroU64};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use toml::Table;
@@ -45,6 +51,31 @@ pub(crate) async fn load_or_find_project(user_path: Option<PathBuf>) -> Result<P
     Ok(project)
 }
 
+/// Recursively search for a file named `Twoliter.toml` starting in `dir`. If it is not found,
+/// move up (i.e. `cd ..`) until it is found. Return an error if there is no parent directory.
+pub(crate) fn find_project_file(dir: impl AsRef<Path>) -> Result<PathBuf> {
+    let dir = dir.as_ref();
+    trace!("Looking for Twoliter.toml in '{}'", dir.display());
+    ensure!(
+        dir.is_dir(),
+        "Unable to locate Twoliter.toml in '{}': not a directory",
+        dir.display()
+    );
+    let dir = dir
+        .canonicalize()
+        .context(format!("Unable to canonicalize '{}'", dir.display()))?;
+    let filepath = dir.join("Twoliter.toml");
+    if filepath.is_file() {
+        return Ok(filepath);
+    }
+    // Move up a level and recurse.
+    let parent = dir
+        .parent()
+        .context("Unable to find Twoliter.toml file")?
+        .to_owned();
+    find_project_file(parent)
+}
+
 /// Represents the structure of a `Twoliter.toml` project file.
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub(crate) struct Project<L: ProjectLock> {
@@ -68,6 +99,40 @@ pub(crate) struct Project<L: ProjectLock> {
 
     overrides: BTreeMap<String, BTreeMap<String, Override>>,
 
+    /// How buildsys downloads external files.
+    fetch: FetchSettings,
+
+    /// Secrets made available to builds, by ID.
+    secrets: BTreeMap<ValidIdentifier, Secret>,
+
+    /// The licenses that packages in the project's variants may use.
+    licenses: LicenseSettings,
+
+    /// The devices of the host that package builds may use.
+    devices: DeviceSettings,
+
+    /// Named sets of build settings, selected with `twoliter build --profile`.
+    profiles: BTreeMap<ValidIdentifier, ProfileSettings>,
+
+    /// Whether and where to export metrics about builds.
+    metrics: MetricsSettings,
+
+    /// How `twoliter test-matrix` retries and quarantines tests.
+    test: TestSettings,
+
+    /// Named hosts that builds can run on, selected with `twoliter build variant --remote`.
+    remotes: BTreeMap<ValidIdentifier, RemoteSettings>,
+
+    /// Commands that run before and after builds and publishes, by name.
+    hooks: BTreeMap<ValidIdentifier, Hook>,
+
+    /// Where builds also store their artifacts, in a stable layout, relative to the project
+    /// directory.
+    artifacts_dir: Option<PathBuf>,
+
+    /// The versions of twoliter and buildsys that can build the project.
+    required_versions: RequiredVersions,
+
     /// The resolved and locked dependencies of the project.
     lock: L,
 }
@@ -79,10 +144,18 @@ impl Project<Unlocked> {
         let data = fs::read_to_string(&path)
             .await
             .context(format!("Unable to read project file '{}'", path.display()))?;
-        let unvalidated: UnvalidatedProject = toml::from_str(&data).context(format!(
-            "Unable to deserialize project file '{}'",
-            path.display()
-        ))?;
+        let unvalidated: UnvalidatedProject = toml::from_str(&data).with_context(|| {
+            let hint = if migrate::is_outdated(&data) {
+                ". It was written for an older version of twoliter, and can be updated with \
+                 'twoliter migrate-project'"
+            } else {
+                ""
+            };
+            format!(
+                "Unable to deserialize project file '{}'{hint}",
+                path.display()
+            )
+        })?;
         let project = unvalidated.validate(path).await?;
 
         // When projects are resolved, tags are written indicating which artifacts have been checked
@@ -94,33 +167,13 @@ impl Project<Unlocked> {
         Ok(project)
     }
 
-    /// Recursively search for a file named `Twoliter.toml` starting in `dir`. If it is not found,
-    /// move up (i.e. `cd ..`) until it is found. Return an error if there is no parent directory.
-    #[async_recursion]
+    /// Search for a file named `Twoliter.toml` starting in `dir`, as [`find_project_file`] does,
+    /// and load it.
     pub(crate) async fn find_and_load<P>(dir: P) -> Result<Self>
     where
         P: Send + AsRef<Path>,
     {
-        let dir = dir.as_ref();
-        trace!("Looking for Twoliter.toml in '{}'", dir.display());
-        ensure!(
-            dir.is_dir(),
-            "Unable to locate Twoliter.toml in '{}': not a directory",
-            dir.display()
-        );
-        let dir = dir
-            .canonicalize()
-            .context(format!("Unable to canonicalize '{}'", dir.display()))?;
-        let filepath = dir.join("Twoliter.toml");
-        if filepath.is_file() {
-            return Self::load(&filepath).await;
-        }
-        // Move up a level and recurse.
-        let parent = dir
-            .parent()
-            .context("Unable to find Twoliter.toml file")?
-            .to_owned();
-        Self::find_and_load(parent).await
+        Self::load(find_project_file(dir)?).await
     }
 
     pub(crate) async fn create_lock(self) -> Result<Project<Locked>> {
@@ -132,7 +185,20 @@ impl Project<Unlocked> {
         VerificationTagger::cleanup_existing_tags(self.external_kits_dir()).await?;
 
         let resolved_lock = NL::load_lock(self, private::SealToken).await?;
+        self.tag_verified(resolved_lock).await
+    }
+
+    /// Loads the full lock like `load_lock::<Locked>`, but if the registry only reports new
+    /// digests for the images in Twoliter.lock, such as when a tag was pushed again, continues
+    /// with the new images after a warning instead of failing.
+    pub(crate) async fn load_lock_allowing_drift(&self) -> Result<Project<Locked>> {
+        VerificationTagger::cleanup_existing_tags(self.external_kits_dir()).await?;
 
+        let resolved_lock = Locked(Lock::load(self, true).await?);
+        self.tag_verified(resolved_lock).await
+    }
+
+    async fn tag_verified<NL: ProjectLock>(&self, resolved_lock: NL) -> Result<Project<NL>> {
         resolved_lock
             .verification_tagger(private::SealToken)
             .write_tags(self.external_kits_dir())
@@ -140,6 +206,16 @@ impl Project<Unlocked> {
 
         Ok(self.with_new_lock(resolved_lock))
     }
+
+    /// Verifies a kit dependency against the digest recorded in Twoliter.lock, along with its
+    /// attached signatures, SBOMs and the checksums of the RPMs it contains.
+    pub(crate) async fn verify_kit(
+        &self,
+        kit_name: &str,
+        options: &KitVerifyOptions,
+    ) -> Result<()> {
+        lock::verify_kit(self, kit_name, options).await
+    }
 }
 
 impl<L: ProjectLock> Project<L> {
@@ -154,6 +230,17 @@ impl<L: ProjectLock> Project<L> {
             vendor: self.vendor.clone(),
             kit: self.kit.clone(),
             overrides: self.overrides.clone(),
+            fetch: self.fetch.clone(),
+            secrets: self.secrets.clone(),
+            licenses: self.licenses.clone(),
+            devices: self.devices.clone(),
+            profiles: self.profiles.clone(),
+            metrics: self.metrics.clone(),
+            test: self.test.clone(),
+            remotes: self.remotes.clone(),
+            hooks: self.hooks.clone(),
+            artifacts_dir: self.artifacts_dir.clone(),
+            required_versions: self.required_versions.clone(),
             lock: new_lock.into(),
         }
     }
@@ -178,6 +265,114 @@ impl<L: ProjectLock> Project<L> {
         self.schema_version
     }
 
+    pub(crate) fn fetch_settings(&self) -> &FetchSettings {
+        &self.fetch
+    }
+
+    pub(crate) fn license_settings(&self) -> &LicenseSettings {
+        &self.licenses
+    }
+
+    pub(crate) fn device_settings(&self) -> &DeviceSettings {
+        &self.devices
+    }
+
+    pub(crate) fn metrics_settings(&self) -> &MetricsSettings {
+        &self.metrics
+    }
+
+    pub(crate) fn test_settings(&self) -> &TestSettings {
+        &self.test
+    }
+
+    pub(crate) fn required_versions(&self) -> &RequiredVersions {
+        &self.required_versions
+    }
+
+    /// The remote build host named `name` in the `remote` table of Twoliter.toml.
+    pub(crate) fn remote(&self, name: &str) -> Result<&RemoteSettings> {
+        self.remotes
+            .iter()
+            .find(|(id, _)| id.as_ref() == name)
+            .map(|(_, remote)| remote)
+            .with_context(|| {
+                format!(
+                    "Twoliter.toml has no remote build host named '{name}'; the hosts are: {}",
+                    self.remotes
+                        .keys()
+                        .map(|id| format!("'{id}'"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+
+    /// The hooks in the `hooks` table of Twoliter.toml.
+    pub(crate) fn hooks(&self) -> Hooks {
+        Hooks::new(
+            self.hooks
+                .iter()
+                .map(|(id, hook)| (id.to_string(), hook.clone()))
+                .collect(),
+        )
+    }
+
+    /// The buildsys environment variables for the hooks that run around package builds and
+    /// variant builds, each with only the hooks of its builds. A variable that is already set in
+    /// the environment is left out, so that it can override the project's hooks.
+    pub(crate) fn hook_envs(&self) -> Vec<(&'static str, String)> {
+        let hooks = self.hooks();
+        [
+            (PACKAGE_HOOKS_VAR, hooks.at(&Stage::PACKAGE_BUILD)),
+            (VARIANT_HOOKS_VAR, hooks.at(&Stage::VARIANT_BUILD)),
+        ]
+        .into_iter()
+        .filter(|(var, hooks)| !hooks.is_empty() && std::env::var_os(var).is_none())
+        .map(|(var, hooks)| (var, hooks.to_json()))
+        .collect()
+    }
+
+    /// The build profile named `name` in the `profile` table of Twoliter.toml.
+    pub(crate) fn profile(&self, name: &str) -> Result<&ProfileSettings> {
+        self.profiles
+            .iter()
+            .find(|(id, _)| id.as_ref() == name)
+            .map(|(_, profile)| profile)
+            .with_context(|| {
+                format!(
+                    "Twoliter.toml has no build profile named '{name}'; the profiles are: {}",
+                    self.profiles
+                        .keys()
+                        .map(|id| format!("'{id}'"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+
+    /// The directory that builds also store their artifacts in, if the project sets one. See
+    /// [`buildsys_config::ArtifactsDir`] for its layout.
+    pub(crate) fn artifacts_dir(&self) -> Option<PathBuf> {
+        self.artifacts_dir
+            .as_ref()
+            .map(|dir| self.project_dir.join(dir))
+    }
+
+    /// The project's secrets in the form buildsys expects in `BUILDSYS_BUILD_SECRETS`, or `None`
+    /// if there are no secrets.
+    pub(crate) fn build_secrets(&self) -> Option<String> {
+        if self.secrets.is_empty() {
+            return None;
+        }
+        Some(
+            self.secrets
+                .iter()
+                .map(|(id, secret)| secret.build_spec(id))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+
     pub(crate) fn release_version(&self) -> &str {
         self.release_version.as_str()
     }
@@ -281,9 +476,9 @@ impl Project<SDKLocked> {
 
 impl Project<Locked> {
     /// Fetches all external kits defined in a Twoliter.lock to the build directory
-    pub(crate) async fn fetch(&self, arch: &str) -> Result<()> {
+    pub(crate) async fn fetch(&self, arch: &str, allow_drift: bool) -> Result<()> {
         let Locked(lock) = &self.lock;
-        lock.fetch(self, arch).await
+        lock.fetch(self, arch, allow_drift).await
     }
 
     #[expect(dead_code)]
@@ -301,6 +496,22 @@ impl Project<Locked> {
         self.as_project_image(&lock.sdk)
             .expect("Could not find SDK vendor despite lock resolution succeeding?")
     }
+
+    /// The image URI of each kit in Twoliter.lock, by name, such as
+    /// `public.ecr.aws/bottlerocket/bottlerocket-core-kit:v2.0.0`.
+    pub(crate) fn kit_uris(&self) -> BTreeMap<String, String> {
+        let Locked(lock) = &self.lock;
+        lock.kit_uris()
+    }
+}
+
+/// The image URI of each kit in the project's Twoliter.lock as it was at the git `revision`, such
+/// as the tag of a release, by name.
+pub(crate) async fn kit_uris_at(
+    project_dir: &Path,
+    revision: &str,
+) -> Result<BTreeMap<String, String>> {
+    Ok(Lock::at_revision(project_dir, revision).await?.kit_uris())
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -488,6 +699,451 @@ impl VendedArtifact for Image {
     }
 }
 
+/// Controls how buildsys downloads external files, set in the `fetch` table of Twoliter.toml. Any
+/// setting left out uses the buildsys default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct FetchSettings {
+    /// The maximum rate at which each external file is downloaded, in bytes per second.
+    pub rate_limit: Option<NonZeroU64>,
+    /// How many times a download that fails with a transient error is retried.
+    pub retries: Option<u32>,
+    /// Seconds to wait before the first retry. The wait doubles after each retry.
+    pub backoff: Option<u64>,
+    /// The longest wait between retries, in seconds.
+    pub max_backoff: Option<u64>,
+}
+
+impl FetchSettings {
+    /// The buildsys environment variables for these settings. Variables that are already set in
+    /// the environment are left out, so that they can override the project's settings.
+    pub(crate) fn envs(&self) -> Vec<(&'static str, String)> {
+        [
+            (
+                "BUILDSYS_FETCH_RATE_LIMIT",
+                self.rate_limit.map(|v| v.to_string()),
+            ),
+            (
+                "BUILDSYS_FETCH_RETRIES",
+                self.retries.map(|v| v.to_string()),
+            ),
+            (
+                "BUILDSYS_FETCH_BACKOFF",
+                self.backoff.map(|v| v.to_string()),
+            ),
+            (
+                "BUILDSYS_FETCH_MAX_BACKOFF",
+                self.max_backoff.map(|v| v.to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter(|(key, _)| std::env::var_os(key).is_none())
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect()
+    }
+}
+
+/// Checks the licenses of the packages installed in a variant, set in the `licenses` table of
+/// Twoliter.toml. Every license named in a package's `License` tag must be in `allow`, if it is
+/// given, and must not be in `deny`. Leaving both out skips the check.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct LicenseSettings {
+    /// SPDX identifiers of the only licenses that packages may use.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// SPDX identifiers of licenses that packages may not use.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl LicenseSettings {
+    /// The buildsys environment variables for these settings. Variables that are already set in
+    /// the environment are left out, so that they can override the project's settings.
+    pub(crate) fn envs(&self) -> Vec<(&'static str, String)> {
+        [
+            ("BUILDSYS_LICENSE_ALLOW", &self.allow),
+            ("BUILDSYS_LICENSE_DENY", &self.deny),
+        ]
+        .into_iter()
+        .filter(|(key, licenses)| !licenses.is_empty() && std::env::var_os(key).is_none())
+        .map(|(key, licenses)| (key, licenses.join(",")))
+        .collect()
+    }
+
+    /// Checks that each license is a plausible SPDX identifier, since the lists are passed to the
+    /// build separated by commas.
+    fn validate(self) -> Result<Self> {
+        for license in self.allow.iter().chain(&self.deny) {
+            ensure!(
+                !license.is_empty()
+                    && license
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || ".-+".contains(c)),
+                "'{license}' in the 'licenses' table is not a valid SPDX license identifier"
+            );
+        }
+        Ok(self)
+    }
+}
+
+/// The devices of the host that package builds may ask for with `check-devices` in their manifest,
+/// set in the `devices` table of Twoliter.toml. Builds can't use any devices unless `allow` names
+/// them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DeviceSettings {
+    /// CDI device names, such as `nvidia.com/gpu=all`.
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+impl DeviceSettings {
+    /// The buildsys environment variables for these settings. A variable that is already set in
+    /// the environment is left out, so that it can override the project's settings.
+    pub(crate) fn envs(&self) -> Vec<(&'static str, String)> {
+        if self.allow.is_empty() || std::env::var_os("BUILDSYS_ALLOWED_DEVICES").is_some() {
+            return Vec::new();
+        }
+        vec![("BUILDSYS_ALLOWED_DEVICES", self.allow.join(","))]
+    }
+
+    /// Checks that each device is a CDI device name, `vendor/class=name`, since the list is passed
+    /// to the build separated by commas.
+    fn validate(self) -> Result<Self> {
+        for device in &self.allow {
+            let parts = device
+                .split_once('=')
+                .and_then(|(kind, name)| kind.split_once('/').map(|(v, c)| [v, c, name]));
+            ensure!(
+                parts.is_some_and(|parts| parts.iter().all(|part| {
+                    !part.is_empty()
+                        && part
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+                })),
+                "'{device}' in the 'devices' table is not a CDI device name, 'vendor/class=name'"
+            );
+        }
+        Ok(self)
+    }
+}
+
+/// A named set of build settings in the `profile` table of Twoliter.toml, such as `[profile.dev]`,
+/// so that developers and CI can each build with one flag instead of a list of variables. Settings
+/// that a profile leaves out keep their defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ProfileSettings {
+    /// The lz4 compression level of variant images, from 1 (fastest) to 12 (smallest).
+    pub compression_level: Option<u8>,
+    /// Whether every package build runs or skips the spec's `%check`.
+    pub run_checks: Option<bool>,
+    /// Whether package builds generate debuginfo packages.
+    pub debuginfo: Option<bool>,
+    /// Whether builds are reproducible.
+    pub reproducible: Option<bool>,
+    /// The number of parallel jobs each package build may use.
+    pub jobs: Option<NonZeroU16>,
+    /// The number of packages that are built at once.
+    pub concurrent_builds: Option<NonZeroU16>,
+}
+
+impl ProfileSettings {
+    /// The buildsys environment variables for this profile. Variables that are already set in
+    /// the environment are left out, so that they can override the profile.
+    pub(crate) fn envs(&self) -> Vec<(&'static str, String)> {
+        [
+            (
+                "BUILDSYS_COMPRESSION_LEVEL",
+                self.compression_level.map(|v| v.to_string()),
+            ),
+            (
+                "BUILDSYS_RUN_CHECKS",
+                self.run_checks.map(|v| v.to_string()),
+            ),
+            ("BUILDSYS_DEBUGINFO", self.debuginfo.map(|v| v.to_string())),
+            (
+                "BUILDSYS_REPRODUCIBLE",
+                self.reproducible.map(|v| v.to_string()),
+            ),
+            ("BUILDSYS_RPMBUILD_JOBS", self.jobs.map(|v| v.to_string())),
+            (
+                "BUILDSYS_JOBS",
+                self.concurrent_builds.map(|v| v.to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter(|(key, _)| std::env::var_os(key).is_none())
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect()
+    }
+
+    fn validate(self, id: &ValidIdentifier) -> Result<Self> {
+        if let Some(level) = self.compression_level {
+            ensure!(
+                (1..=12).contains(&level),
+                "The compression-level of profile '{id}' is {level}, but it must be from 1 to 12"
+            );
+        }
+        Ok(self)
+    }
+}
+
+/// Exports metrics about builds, set in the `metrics` table of Twoliter.toml. Nothing is recorded
+/// unless `enabled` is true. A JSON report of each build is written to `report-dir`, and the same
+/// metrics can also be sent to a statsd server, over UDP, or an OpenTelemetry collector, over
+/// OTLP/HTTP with JSON.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct MetricsSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Where reports are written, relative to the project directory. Defaults to `build/metrics`.
+    pub report_dir: Option<PathBuf>,
+    /// The `host:port` of a statsd server.
+    pub statsd: Option<String>,
+    /// The URL that OTLP metrics are posted to, such as `http://localhost:4318/v1/metrics`.
+    pub otlp: Option<String>,
+    /// The prefix of metric names. Defaults to `twoliter`.
+    pub prefix: Option<String>,
+}
+
+impl MetricsSettings {
+    /// The directory that reports are written to.
+    pub(crate) fn report_dir(&self, project_dir: &Path) -> PathBuf {
+        project_dir.join(
+            self.report_dir
+                .as_deref()
+                .unwrap_or(Path::new("build/metrics")),
+        )
+    }
+
+    pub(crate) fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("twoliter")
+    }
+
+    fn validate(self) -> Result<Self> {
+        if let Some(statsd) = &self.statsd {
+            ensure!(
+                statsd
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()),
+                "'{statsd}' in the 'metrics' table is not a statsd 'host:port'"
+            );
+        }
+        if let Some(otlp) = &self.otlp {
+            ensure!(
+                otlp.starts_with("http://") || otlp.starts_with("https://"),
+                "'{otlp}' in the 'metrics' table is not an http or https URL"
+            );
+        }
+        if let Some(prefix) = &self.prefix {
+            ensure!(
+                !prefix.is_empty()
+                    && prefix
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "._".contains(c)),
+                "'{prefix}' in the 'metrics' table is not a valid metric name prefix"
+            );
+        }
+        Ok(self)
+    }
+}
+
+/// How `twoliter test-matrix` treats tests that fail, set in the `test` table of Twoliter.toml.
+/// Tests are given as patterns, `<variant>/<arch>/<test>`, where any part may be `*`. A test that
+/// fails is run again as many times as `retries` gives for the patterns that match it, taking the
+/// largest. Quarantined tests are known to be flaky; they are run and retried like any other, but
+/// reported separately, and their failures don't fail the command.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct TestSettings {
+    /// The number of times that the tests matching each pattern are retried.
+    #[serde(default)]
+    pub retries: BTreeMap<String, u32>,
+    /// The patterns of the tests that are quarantined.
+    #[serde(default)]
+    pub quarantine: Vec<String>,
+}
+
+impl TestSettings {
+    fn validate(self) -> Result<Self> {
+        for pattern in self.retries.keys().chain(&self.quarantine) {
+            ensure!(
+                pattern.split('/').count() == 3,
+                "'{pattern}' in the 'test' table is not a test pattern, '<variant>/<arch>/<test>'"
+            );
+        }
+        Ok(self)
+    }
+}
+
+/// A host that builds can run on, in the `remote` table of Twoliter.toml, such as `[remote.big]`,
+/// and selected with `twoliter build variant --remote big`. The project is copied to the host with
+/// rsync over SSH, the build runs there with the host's Twoliter, and the images are copied back.
+/// Set exactly one of `host`, an SSH destination such as `builder@build.example.com`, or
+/// `launch-template`, the name of an EC2 launch template that an instance is started from for the
+/// build and terminated after it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RemoteSettings {
+    pub host: Option<String>,
+    pub launch_template: Option<String>,
+    /// The AWS region of the launch template. Defaults to the region of the AWS CLI.
+    pub region: Option<String>,
+    /// The user to log in to instances as. Defaults to `ec2-user`.
+    pub user: Option<String>,
+    /// Where the project is copied to on the host, relative to the home directory. Defaults to
+    /// `twoliter/<name of the project directory>`.
+    pub dir: Option<String>,
+}
+
+impl RemoteSettings {
+    fn validate(self, id: &ValidIdentifier) -> Result<Self> {
+        ensure!(
+            self.host.is_some() != self.launch_template.is_some(),
+            "Remote build host '{id}' must have exactly one of 'host' or 'launch-template'"
+        );
+        ensure!(
+            self.host.is_none() || (self.region.is_none() && self.user.is_none()),
+            "Remote build host '{id}' has a 'host', so its 'region' and 'user' aren't used; \
+             give the user in the host, as 'user@host'"
+        );
+        Ok(self)
+    }
+}
+
+/// The versions of twoliter and buildsys that can build the project, set as semver requirements
+/// with `required-twoliter-version` and `required-buildsys-version` in Twoliter.toml. Twoliter
+/// checks its own version when it loads the project, and buildsys checks its version when a build
+/// starts, so that a project that uses newer features isn't quietly misbuilt by older tools.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub(crate) struct RequiredVersions {
+    twoliter: Option<String>,
+    buildsys: Option<String>,
+}
+
+impl RequiredVersions {
+    /// Checks that each requirement that is given can be parsed.
+    fn new(twoliter: Option<String>, buildsys: Option<String>) -> Result<Self> {
+        for (key, required) in [
+            ("required-twoliter-version", &twoliter),
+            ("required-buildsys-version", &buildsys),
+        ] {
+            if let Some(required) = required {
+                VersionReq::parse(required).with_context(|| {
+                    format!("'{key}' in Twoliter.toml is not a version requirement: '{required}'")
+                })?;
+            }
+        }
+        Ok(Self { twoliter, buildsys })
+    }
+
+    /// Checks that `version` of twoliter meets the project's requirement.
+    fn check_twoliter(&self, version: &str) -> Result<()> {
+        let Some(required) = &self.twoliter else {
+            return Ok(());
+        };
+        let required = VersionReq::parse(required)?;
+        let version = Version::parse(version)?;
+        ensure!(
+            required.matches(&version),
+            "The project requires twoliter {required}, but this is twoliter {version}. Upgrade \
+             twoliter to a version that meets the requirement"
+        );
+        Ok(())
+    }
+
+    /// The buildsys environment variables for the requirements. Buildsys checks its own version,
+    /// since it doesn't always have the same version as twoliter.
+    pub(crate) fn envs(&self) -> Vec<(&'static str, String)> {
+        self.buildsys
+            .iter()
+            .map(|required| ("BUILDSYS_REQUIRED_VERSION", required.clone()))
+            .collect()
+    }
+}
+
+/// A secret that builds can use without it being stored in any image, such as a `.netrc` for
+/// private Go modules. Set in the `secrets` table of Twoliter.toml with exactly one of `path`, a
+/// file relative to the project directory, or `env`, the name of an environment variable.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Secret {
+    pub path: Option<PathBuf>,
+    pub env: Option<String>,
+}
+
+impl Secret {
+    fn build_spec(&self, id: &ValidIdentifier) -> String {
+        match (&self.path, &self.env) {
+            (Some(path), _) => format!("id={},src={}", id, path.display()),
+            (None, Some(env)) => format!("id={},env={}", id, env),
+            (None, None) => format!("id={}", id),
+        }
+    }
+
+    /// Checks that exactly one source is given, and makes `path` absolute. A leading `~/` refers
+    /// to the user's home directory.
+    fn validate(self, id: &ValidIdentifier, project_dir: &Path) -> Result<Self> {
+        let path = match (self.path, &self.env) {
+            (Some(_), Some(_)) | (None, None) => {
+                anyhow::bail!("secret '{id}' must have exactly one of 'path' or 'env'")
+            }
+            (None, Some(env)) => {
+                ensure!(
+                    !env.is_empty() && !env.contains([' ', ',', '=']),
+                    "secret '{id}' has an invalid environment variable name '{env}'"
+                );
+                None
+            }
+            (Some(path), None) => {
+                let path = match path.strip_prefix("~") {
+                    Ok(rest) => PathBuf::from(
+                        std::env::var_os("HOME")
+                            .context(format!("unable to expand '~' in secret '{id}'"))?,
+                    )
+                    .join(rest),
+                    Err(_) => project_dir.join(path),
+                };
+                let display = path.display().to_string();
+                ensure!(
+                    !display.contains([' ', ',']),
+                    "the path '{display}' for secret '{id}' cannot contain spaces or commas"
+                );
+                Some(path)
+            }
+        };
+        Ok(Self {
+            path,
+            env: self.env,
+        })
+    }
+}
+
+/// Checks that a hook in the `hooks` table of Twoliter.toml has a command, and that its inputs and
+/// outputs are in the project directory, since hooks in the SDK only see the project.
+fn validate_hook(id: &ValidIdentifier, hook: &Hook) -> Result<()> {
+    ensure!(
+        hook.command
+            .first()
+            .is_some_and(|program| !program.is_empty()),
+        "hook '{id}' must have a command"
+    );
+    for path in hook.inputs.iter().chain(&hook.outputs) {
+        ensure!(
+            path.is_relative()
+                && !path
+                    .components()
+                    .any(|c| matches!(c, std::path::Component::ParentDir)),
+            "the path '{}' of hook '{id}' must be relative to the project directory, without '..'",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
 /// This is used to `Deserialize` a project, then run validation code before returning a valid
 /// [`Project`]. This is necessary both because there is no post-deserialization serde hook for
 /// validation and, even if there was, we need to know the project directory path in order to check
@@ -500,6 +1156,18 @@ struct UnvalidatedProject {
     sdk: Option<Image>,
     vendor: Option<BTreeMap<ValidIdentifier, Vendor>>,
     kit: Option<Vec<Image>>,
+    fetch: Option<FetchSettings>,
+    secrets: Option<BTreeMap<ValidIdentifier, Secret>>,
+    licenses: Option<LicenseSettings>,
+    devices: Option<DeviceSettings>,
+    profile: Option<BTreeMap<ValidIdentifier, ProfileSettings>>,
+    metrics: Option<MetricsSettings>,
+    test: Option<TestSettings>,
+    remote: Option<BTreeMap<ValidIdentifier, RemoteSettings>>,
+    hooks: Option<BTreeMap<ValidIdentifier, Hook>>,
+    artifacts_dir: Option<PathBuf>,
+    required_twoliter_version: Option<String>,
+    required_buildsys_version: Option<String>,
 }
 
 impl UnvalidatedProject {
@@ -514,9 +1182,54 @@ impl UnvalidatedProject {
             ))?
             .to_path_buf();
 
+        // Checked first, since a project that needs a newer twoliter may not make sense to this.
+        let required_versions = RequiredVersions::new(
+            self.required_twoliter_version.clone(),
+            self.required_buildsys_version.clone(),
+        )?;
+        required_versions
+            .check_twoliter(env!("CARGO_PKG_VERSION"))
+            .with_context(|| format!("Unable to build the project in '{}'", filepath.display()))?;
+
         self.check_vendor_availability().await?;
         self.check_release_toml(&project_dir).await?;
         let overrides = self.check_and_load_overrides(&project_dir).await?;
+        let secrets = self
+            .secrets
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(id, secret)| {
+                let secret = secret.validate(&id, &project_dir)?;
+                Ok((id, secret))
+            })
+            .collect::<Result<_>>()?;
+        let profiles = self
+            .profile
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(id, profile)| {
+                let profile = profile.validate(&id)?;
+                Ok((id, profile))
+            })
+            .collect::<Result<_>>()?;
+        let remotes = self
+            .remote
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(id, remote)| {
+                let remote = remote.validate(&id)?;
+                Ok((id, remote))
+            })
+            .collect::<Result<_>>()?;
+        let hooks = self
+            .hooks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(id, hook)| {
+                validate_hook(&id, &hook)?;
+                Ok((id, hook))
+            })
+            .collect::<Result<_>>()?;
 
         Ok(Project {
             filepath,
@@ -527,6 +1240,17 @@ impl UnvalidatedProject {
             vendor: self.vendor.unwrap_or_default(),
             kit: self.kit.unwrap_or_default(),
             overrides,
+            fetch: self.fetch.unwrap_or_default(),
+            secrets,
+            licenses: self.licenses.unwrap_or_default().validate()?,
+            devices: self.devices.unwrap_or_default().validate()?,
+            profiles,
+            metrics: self.metrics.unwrap_or_default().validate()?,
+            test: self.test.unwrap_or_default().validate()?,
+            remotes,
+            hooks,
+            artifacts_dir: self.artifacts_dir,
+            required_versions,
             lock: Unlocked,
         })
     }
@@ -670,7 +1394,7 @@ pub struct Locked(Lock);
 #[async_trait]
 impl ProjectLock for Locked {
     async fn load_lock(project: &Project<Unlocked>, _: private::SealToken) -> Result<Self> {
-        Lock::load(project).await.map(Self)
+        Lock::load(project, false).await.map(Self)
     }
 
     fn verification_tagger(&self, _: private::SealToken) -> VerificationTagger {
@@ -846,10 +1570,170 @@ mod test {
                 version: Version::new(1, 20, 0),
                 vendor: ValidIdentifier("not-bottlerocket".into()),
             }]),
+            fetch: None,
+            secrets: None,
+            licenses: None,
+            devices: None,
+            profile: None,
+            metrics: None,
+            test: None,
+            remote: None,
+            hooks: None,
+            artifacts_dir: None,
+            required_twoliter_version: None,
+            required_buildsys_version: None,
         };
         assert!(project.check_vendor_availability().await.is_err());
     }
 
+    #[test]
+    fn test_fetch_settings() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [fetch]
+            rate-limit = 1048576
+            retries = 3
+            "#,
+        )
+        .unwrap();
+        let fetch = project.fetch.unwrap();
+        assert_eq!(fetch.rate_limit, NonZeroU64::new(1048576));
+        assert_eq!(fetch.retries, Some(3));
+        assert_eq!(fetch.backoff, None);
+
+        let envs = fetch.envs();
+        assert!(envs.contains(&("BUILDSYS_FETCH_RETRIES", "3".to_string())));
+        assert!(!envs.iter().any(|(key, _)| *key == "BUILDSYS_FETCH_BACKOFF"));
+    }
+
+    #[test]
+    fn test_license_settings() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [licenses]
+            deny = ["AGPL-3.0-only", "SSPL-1.0"]
+            "#,
+        )
+        .unwrap();
+        let licenses = project.licenses.unwrap().validate().unwrap();
+        assert!(licenses.allow.is_empty());
+        assert_eq!(
+            licenses.envs(),
+            vec![(
+                "BUILDSYS_LICENSE_DENY",
+                "AGPL-3.0-only,SSPL-1.0".to_string()
+            )]
+        );
+
+        let invalid = LicenseSettings {
+            allow: vec!["MIT OR Apache-2.0".to_string()],
+            deny: Vec::new(),
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_device_settings() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [devices]
+            allow = ["nvidia.com/gpu=all", "vendor.com/fpga=0"]
+            "#,
+        )
+        .unwrap();
+        let devices = project.devices.unwrap().validate().unwrap();
+        assert_eq!(
+            devices.envs(),
+            vec![(
+                "BUILDSYS_ALLOWED_DEVICES",
+                "nvidia.com/gpu=all,vendor.com/fpga=0".to_string()
+            )]
+        );
+
+        for invalid in ["/dev/nvidia0", "nvidia.com/gpu", "a/b=c,d"] {
+            let devices = DeviceSettings {
+                allow: vec![invalid.to_string()],
+            };
+            assert!(devices.validate().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_profile_settings() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [profile.dev]
+            compression-level = 1
+            debuginfo = false
+            jobs = 4
+
+            [profile.release]
+            run-checks = true
+            reproducible = true
+            "#,
+        )
+        .unwrap();
+        let profiles = project.profile.unwrap();
+        let dev = &profiles[&ValidIdentifier("dev".into())];
+        let envs = dev
+            .clone()
+            .validate(&ValidIdentifier("dev".into()))
+            .unwrap()
+            .envs();
+        assert!(envs.contains(&("BUILDSYS_COMPRESSION_LEVEL", "1".to_string())));
+        assert!(envs.contains(&("BUILDSYS_DEBUGINFO", "false".to_string())));
+        assert!(envs.contains(&("BUILDSYS_RPMBUILD_JOBS", "4".to_string())));
+        assert!(!envs.iter().any(|(key, _)| *key == "BUILDSYS_RUN_CHECKS"));
+
+        let release = &profiles[&ValidIdentifier("release".into())];
+        assert_eq!(release.run_checks, Some(true));
+        assert_eq!(release.compression_level, None);
+
+        let invalid = ProfileSettings {
+            compression_level: Some(13),
+            ..Default::default()
+        };
+        assert!(invalid.validate(&ValidIdentifier("dev".into())).is_err());
+    }
+
+    #[test]
+    fn test_secrets() {
+        let project_dir = Path::new("/project");
+        let netrc = Secret {
+            path: Some("secrets/netrc".into()),
+            env: None,
+        };
+        let id = ValidIdentifier("netrc".into());
+        let netrc = netrc.validate(&id, project_dir).unwrap();
+        assert_eq!(netrc.build_spec(&id), "id=netrc,src=/project/secrets/netrc");
+
+        let token = Secret {
+            path: None,
+            env: Some("GITHUB_TOKEN".into()),
+        };
+        let id = ValidIdentifier("token".into());
+        let token = token.validate(&id, project_dir).unwrap();
+        assert_eq!(token.build_spec(&id), "id=token,env=GITHUB_TOKEN");
+
+        let both = Secret {
+            path: Some("netrc".into()),
+            env: Some("TOKEN".into()),
+        };
+        assert!(both.validate(&id, project_dir).is_err());
+    }
+
     #[tokio::test]
     async fn test_release_toml_check_ok() {
         let tempdir = TempDir::new().unwrap();
@@ -878,4 +1762,189 @@ mod test {
         assert_eq!(go_modules.len(), 1, "Expected to find 1 go module");
         assert_eq!(go_modules.first().unwrap(), "hello-go");
     }
+
+    #[test]
+    fn test_metrics_settings() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [metrics]
+            enabled = true
+            statsd = "localhost:8125"
+            "#,
+        )
+        .unwrap();
+        let metrics = project.metrics.unwrap().validate().unwrap();
+        assert!(metrics.enabled);
+        assert_eq!(metrics.prefix(), "twoliter");
+        assert_eq!(
+            metrics.report_dir(Path::new("/project")),
+            PathBuf::from("/project/build/metrics")
+        );
+
+        let invalid = MetricsSettings {
+            statsd: Some("localhost".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = MetricsSettings {
+            otlp: Some("localhost:4318".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_remote_settings() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [remote.big]
+            host = "builder@build.example.com"
+
+            [remote.ec2]
+            launch-template = "bottlerocket-builder"
+            region = "us-west-2"
+            "#,
+        )
+        .unwrap();
+        for (id, remote) in project.remote.unwrap() {
+            assert!(remote.validate(&id).is_ok());
+        }
+
+        let id = ValidIdentifier("big".into());
+        assert!(RemoteSettings::default().validate(&id).is_err());
+        let invalid = RemoteSettings {
+            host: Some("build.example.com".to_string()),
+            launch_template: Some("bottlerocket-builder".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate(&id).is_err());
+        let invalid = RemoteSettings {
+            host: Some("build.example.com".to_string()),
+            user: Some("builder".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate(&id).is_err());
+    }
+
+    #[test]
+    fn test_hook_settings() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [hooks.scan]
+            stage = "post-variant-build"
+            run-in = "sdk"
+            command = ["tools/scan.sh", "{name}"]
+            inputs = ["tools/scan.sh"]
+            outputs = ["build/reports/{name}-{arch}.json"]
+
+            [hooks.upload]
+            stage = "post-publish"
+            command = ["tools/upload.sh"]
+            "#,
+        )
+        .unwrap();
+        let hooks = project.hooks.unwrap();
+        for (id, hook) in &hooks {
+            assert!(validate_hook(id, hook).is_ok());
+        }
+        let scan = &hooks[&ValidIdentifier("scan".into())];
+
+        let invalid = Hook {
+            command: Vec::new(),
+            ..scan.clone()
+        };
+        assert!(validate_hook(&ValidIdentifier("scan".into()), &invalid).is_err());
+        for path in ["/tmp/report.json", "../report.json"] {
+            let invalid = Hook {
+                outputs: vec![PathBuf::from(path)],
+                ..scan.clone()
+            };
+            assert!(validate_hook(&ValidIdentifier("scan".into()), &invalid).is_err());
+        }
+
+        let hooks = Hooks::new(
+            hooks
+                .into_iter()
+                .map(|(id, hook)| (id.to_string(), hook))
+                .collect(),
+        );
+        let variant_hooks: Hooks = hooks.at(&Stage::VARIANT_BUILD).to_json().parse().unwrap();
+        assert_eq!(variant_hooks.to_string(), "scan (post-variant-build)");
+        assert!(hooks.at(&Stage::PACKAGE_BUILD).is_empty());
+    }
+
+    #[test]
+    fn test_required_versions() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+            required-twoliter-version = ">=0.6.0"
+            required-buildsys-version = "^0.2"
+            "#,
+        )
+        .unwrap();
+        let required = RequiredVersions::new(
+            project.required_twoliter_version,
+            project.required_buildsys_version,
+        )
+        .unwrap();
+        assert!(required.check_twoliter("0.6.1").is_ok());
+        assert!(required.check_twoliter("0.5.0").is_err());
+        assert_eq!(
+            required.envs(),
+            vec![("BUILDSYS_REQUIRED_VERSION", "^0.2".to_string())]
+        );
+
+        assert!(RequiredVersions::default().check_twoliter("0.5.0").is_ok());
+        assert!(RequiredVersions::new(Some("latest".to_string()), None).is_err());
+    }
+
+    #[test]
+    fn test_test_settings() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+
+            [test]
+            quarantine = ["aws-k8s-1.30/aarch64/conformance"]
+
+            [test.retries]
+            "*/*/conformance" = 2
+            "#,
+        )
+        .unwrap();
+        let test = project.test.unwrap().validate().unwrap();
+        assert_eq!(test.retries["*/*/conformance"], 2);
+        assert_eq!(test.quarantine, vec!["aws-k8s-1.30/aarch64/conformance"]);
+
+        let invalid = TestSettings {
+            quarantine: vec!["aws-dev/conformance".to_string()],
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_artifacts_dir() {
+        let project: UnvalidatedProject = toml::from_str(
+            r#"
+            schema-version = 1
+            release-version = "1.0.0"
+            artifacts-dir = "dist"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(project.artifacts_dir, Some(PathBuf::from("dist")));
+    }
 }
@@ -0,0 +1,345 @@
+/*!
+Builds can run on a bigger host than the one that Twoliter is run on. The project is copied to the
+host with rsync over SSH, leaving out the `build` directory so that the host keeps its own builds
+between runs, and Twoliter is run there with the same arguments. Its output is streamed back over
+SSH, and once it succeeds the images it built are copied back into the project's `build`
+directory, where they would have been if the build had run here.
+
+A host can also be an EC2 instance that is started from a launch template for the build, and
+terminated once the build is done, whether it succeeded, failed or was interrupted with Ctrl-C. The
+AWS CLI is used to manage the instance, so it uses the same credentials as the AWS CLI.
+
+Named hosts must already be in the user's `known_hosts`, as for any other SSH connection. Only the
+host keys of instances started for the build are trusted on first use, since they are always new.
+*/
+
+use crate::common::{exec, fs};
+use crate::interrupt;
+use crate::project::RemoteSettings;
+use anyhow::{bail, ensure, Context, Result};
+use std::path::Path;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// The user that Twoliter logs in to instances as, unless the settings give another.
+const DEFAULT_USER: &str = "ec2-user";
+
+/// The options that SSH and rsync connect with.
+const SSH_OPTIONS: [&str; 2] = ["-o", "BatchMode=yes"];
+
+/// The options that SSH and rsync connect to instances started for the build with. Their host keys
+/// haven't been seen before, since the instances are always new, so they're trusted.
+const INSTANCE_SSH_OPTIONS: [&str; 2] = ["-o", "StrictHostKeyChecking=accept-new"];
+
+/// A host that a build runs on.
+#[derive(Debug)]
+pub(crate) struct RemoteHost {
+    /// The SSH destination, `user@host`.
+    destination: String,
+    /// Where the project is copied to, relative to the home directory.
+    dir: String,
+    /// The instance that was started for the build, if one was.
+    instance: Option<Instance>,
+}
+
+#[derive(Debug)]
+struct Instance {
+    id: String,
+    region: Option<String>,
+    /// Keeps the first Ctrl-C from exiting right away while the instance is up, so that it's
+    /// terminated before twoliter exits.
+    _running: interrupt::Running,
+}
+
+impl RemoteHost {
+    /// Connects to the host that `settings` give, starting an instance if they give a launch
+    /// template, and makes the directory that the project at `project_dir` is copied to.
+    pub(crate) async fn connect(settings: &RemoteSettings, project_dir: &Path) -> Result<Self> {
+        let dir = match &settings.dir {
+            Some(dir) => dir.clone(),
+            None => format!(
+                "twoliter/{}",
+                project_dir
+                    .file_name()
+                    .context("The project directory has no name")?
+                    .to_string_lossy()
+            ),
+        };
+        let host = match (&settings.host, &settings.launch_template) {
+            (Some(host), _) => Self {
+                destination: host.clone(),
+                dir,
+                instance: None,
+            },
+            (None, Some(template)) => {
+                let instance = Instance::start(template, settings.region.clone()).await?;
+                // The instance is terminated if it can't be reached.
+                let address = match instance.address().await {
+                    Ok(address) => address,
+                    Err(e) => {
+                        instance.terminate().await;
+                        return Err(e);
+                    }
+                };
+                let user = settings.user.as_deref().unwrap_or(DEFAULT_USER);
+                Self {
+                    destination: format!("{user}@{address}"),
+                    dir,
+                    instance: Some(instance),
+                }
+            }
+            (None, None) => bail!("The remote build host has no 'host' or 'launch-template'"),
+        };
+        let mkdir = format!("mkdir -p {}", shell_quote(&host.dir));
+        if let Err(e) = host.ssh(mkdir, true).await {
+            host.release().await;
+            return Err(e).context(format!("Unable to connect to '{}'", host.destination));
+        }
+        Ok(host)
+    }
+
+    /// Copies the project at `project_dir` to the host, apart from its `build` directory. Files
+    /// that were removed from the project are removed from the host's copy.
+    pub(crate) async fn push(&self, project_dir: &Path) -> Result<()> {
+        check_interrupted()?;
+        info!("Copying the project to '{}'", self.destination);
+        exec(
+            Command::new("rsync")
+                .args([
+                    "--archive",
+                    "--compress",
+                    "--delete",
+                    "--exclude",
+                    "/build/",
+                ])
+                .arg("--rsh")
+                .arg(self.ssh_command())
+                .arg(format!("{}/", project_dir.display()))
+                .arg(format!("{}:{}/", self.destination, self.dir)),
+            true,
+        )
+        .await
+        .context(format!(
+            "Unable to copy the project to '{}'",
+            self.destination
+        ))?;
+        Ok(())
+    }
+
+    /// Runs Twoliter in the host's copy of the project with `args`, and streams its output.
+    pub(crate) async fn twoliter(&self, args: &[String]) -> Result<()> {
+        check_interrupted()?;
+        info!(
+            "Running twoliter {} on '{}'",
+            args.join(" "),
+            self.destination
+        );
+        let args = args
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let command = format!("cd {} && twoliter {}", shell_quote(&self.dir), args);
+        self.ssh(command, false)
+            .await
+            .context(format!("The build on '{}' failed", self.destination))
+    }
+
+    /// Copies the file or directory at `path`, relative to the project, from the host's copy of
+    /// the project to the project at `project_dir`.
+    pub(crate) async fn pull(&self, project_dir: &Path, path: &str) -> Result<()> {
+        check_interrupted()?;
+        info!("Copying '{}' from '{}'", path, self.destination);
+        let local = project_dir.join(path);
+        let parent = local.parent().unwrap_or(project_dir);
+        fs::create_dir_all(parent).await?;
+        exec(
+            Command::new("rsync")
+                .args(["--archive", "--compress"])
+                .arg("--rsh")
+                .arg(self.ssh_command())
+                .arg(format!("{}:{}/{}", self.destination, self.dir, path))
+                .arg(format!("{}/", parent.display())),
+            true,
+        )
+        .await
+        .context(format!(
+            "Unable to copy '{}' from '{}'",
+            path, self.destination
+        ))?;
+        Ok(())
+    }
+
+    /// Terminates the instance that was started for the build, if one was.
+    pub(crate) async fn release(self) {
+        if let Some(instance) = self.instance {
+            instance.terminate().await;
+        }
+    }
+
+    /// Runs `command` with the host's shell.
+    async fn ssh(&self, command: String, quiet: bool) -> Result<()> {
+        exec(
+            Command::new("ssh")
+                .args(self.ssh_options())
+                .arg(&self.destination)
+                .arg(command),
+            quiet,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// The options that SSH and rsync connect to the host with.
+    fn ssh_options(&self) -> Vec<&'static str> {
+        let mut options = SSH_OPTIONS.to_vec();
+        if self.instance.is_some() {
+            options.extend(INSTANCE_SSH_OPTIONS);
+        }
+        options
+    }
+
+    /// The SSH command that rsync connects with.
+    fn ssh_command(&self) -> String {
+        format!("ssh {}", self.ssh_options().join(" "))
+    }
+}
+
+impl Instance {
+    /// Starts an instance from `template`, and waits until its status checks pass, so that it can
+    /// be logged in to.
+    async fn start(template: &str, region: Option<String>) -> Result<Self> {
+        info!("Starting an instance from launch template '{}'", template);
+        let id = aws(
+            &region,
+            &[
+                "ec2",
+                "run-instances",
+                "--launch-template",
+                &format!("LaunchTemplateName={template}"),
+                "--count",
+                "1",
+                "--query",
+                "Instances[0].InstanceId",
+                "--output",
+                "text",
+            ],
+        )
+        .await
+        .context(format!(
+            "Unable to start an instance from launch template '{template}'"
+        ))?;
+        ensure!(
+            !id.is_empty() && id != "None",
+            "The AWS CLI didn't give the ID of the instance it started"
+        );
+        let instance = Self {
+            id,
+            region,
+            _running: interrupt::Running::start(),
+        };
+        info!("Waiting for instance '{}' to be ready", instance.id);
+        if let Err(e) = aws(
+            &instance.region,
+            &[
+                "ec2",
+                "wait",
+                "instance-status-ok",
+                "--instance-ids",
+                &instance.id,
+            ],
+        )
+        .await
+        {
+            instance.terminate().await;
+            return Err(e).context("The instance didn't become ready");
+        }
+        Ok(instance)
+    }
+
+    /// The public DNS name of the instance, or its private IP address if it has no public name.
+    async fn address(&self) -> Result<String> {
+        let addresses = aws(
+            &self.region,
+            &[
+                "ec2",
+                "describe-instances",
+                "--instance-ids",
+                &self.id,
+                "--query",
+                "Reservations[0].Instances[0].[PublicDnsName,PrivateIpAddress]",
+                "--output",
+                "text",
+            ],
+        )
+        .await?;
+        let address = addresses
+            .split_whitespace()
+            .find(|address| *address != "None")
+            .map(str::to_string);
+        address.with_context(|| format!("Instance '{}' has no address", self.id))
+    }
+
+    /// Terminates the instance. Failing to terminate it is logged, since there is nothing else to
+    /// be done about it.
+    async fn terminate(&self) {
+        info!("Terminating instance '{}'", self.id);
+        let result = aws(
+            &self.region,
+            &["ec2", "terminate-instances", "--instance-ids", &self.id],
+        )
+        .await;
+        if let Err(e) = result {
+            warn!(
+                "Unable to terminate instance '{}'; terminate it yourself: {:?}",
+                self.id, e
+            );
+        }
+    }
+}
+
+/// Runs the AWS CLI with `args`, and returns its trimmed output.
+async fn aws(region: &Option<String>, args: &[&str]) -> Result<String> {
+    let mut command = Command::new("aws");
+    command.args(args);
+    if let Some(region) = region {
+        command.args(["--region", region]);
+    }
+    let output = exec(&mut command, true).await?.unwrap_or_default();
+    Ok(output.trim().to_string())
+}
+
+/// Fails if twoliter was interrupted, so that a build on an instance stops between steps and the
+/// instance is terminated.
+fn check_interrupted() -> Result<()> {
+    ensure!(!interrupt::interrupted(), interrupt::Interrupted);
+    Ok(())
+}
+
+/// Quotes `arg` for a POSIX shell, since SSH runs commands on the host with its shell.
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("aws-dev"), "aws-dev");
+        assert_eq!(
+            shell_quote("--arch=x86_64,aarch64"),
+            "--arch=x86_64,aarch64"
+        );
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("my project"), "'my project'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$(rm -rf ~)"), "'$(rm -rf ~)'");
+    }
+}
@@ -0,0 +1,231 @@
+//! The tasks that remove what builds leave behind: the build directories, the Cargo target
+//! directories, and the caches of crates and Go modules.
+
+use super::{Task, TaskContext};
+use crate::common::{exec_log, fs};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+/// Removes everything that builds write, but not the caches.
+#[derive(Debug)]
+pub(super) struct Clean;
+
+#[async_trait]
+impl Task for Clean {
+    fn name(&self) -> &'static str {
+        "clean"
+    }
+
+    fn dependencies(&self) -> &'static [&'static str] {
+        &[
+            "clean-sources",
+            "clean-packages",
+            "clean-kits",
+            "clean-images",
+            "clean-logs",
+            "clean-repos",
+            "clean-state",
+            "clean-tools",
+            "clean-metadata",
+            "clean-workspace",
+        ]
+    }
+
+    async fn run(&self, _ctx: &TaskContext) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Removes the Cargo target directory of the sources workspace and the project's built tools.
+#[derive(Debug)]
+pub(super) struct CleanSources;
+
+#[async_trait]
+impl Task for CleanSources {
+    fn name(&self) -> &'static str {
+        "clean-sources"
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        let manifest = ctx.sources_dir().join("Cargo.toml");
+        if manifest.is_file() {
+            exec_log(
+                Command::new("cargo")
+                    .args(["clean", "--manifest-path"])
+                    .arg(&manifest),
+            )
+            .await?;
+        }
+
+        let bin = ctx.project_tools_dir().join("bin");
+        if let Ok(mut entries) = tokio::fs::read_dir(&bin).await {
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() {
+                    fs::remove_dir_all(entry.path()).await?;
+                } else {
+                    fs::remove_file(entry.path()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes the Cargo target directories of the project workspace, for both architectures.
+#[derive(Debug)]
+pub(super) struct CleanWorkspace;
+
+#[async_trait]
+impl Task for CleanWorkspace {
+    fn name(&self) -> &'static str {
+        "clean-workspace"
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        // The workspace manifest could be the old one under variants, or the new one at the
+        // project root, and the target directory could be under either.
+        for workspace in ["variants", "."] {
+            let manifest = ctx.project_dir.join(workspace).join("Cargo.toml");
+            if !manifest.metadata().is_ok_and(|m| m.len() > 0) {
+                continue;
+            }
+            for target_dir in ["target", "variants/target"] {
+                for arch in ["x86_64", "aarch64"] {
+                    let targets = ctx.project_dir.join(target_dir).join(arch);
+                    if !targets.is_dir() {
+                        continue;
+                    }
+                    exec_log(
+                        Command::new("cargo")
+                            .args(["clean", "--manifest-path"])
+                            .arg(&manifest)
+                            .env("CARGO_TARGET_DIR", &targets),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes build directories.
+#[derive(Debug)]
+pub(super) struct CleanDir {
+    name: &'static str,
+    dirs: fn(&TaskContext) -> Vec<PathBuf>,
+}
+
+impl CleanDir {
+    pub(super) const PACKAGES: Self = Self {
+        name: "clean-packages",
+        dirs: |ctx| vec![ctx.packages_dir()],
+    };
+
+    pub(super) const KITS: Self = Self {
+        name: "clean-kits",
+        dirs: |ctx| vec![ctx.kits_dir(), ctx.external_kits_dir()],
+    };
+
+    pub(super) const IMAGES: Self = Self {
+        name: "clean-images",
+        dirs: |ctx| vec![ctx.images_dir()],
+    };
+
+    pub(super) const LOGS: Self = Self {
+        name: "clean-logs",
+        dirs: |ctx| vec![ctx.logs_dir()],
+    };
+
+    pub(super) const REPOS: Self = Self {
+        name: "clean-repos",
+        dirs: |ctx| vec![ctx.repos_dir()],
+    };
+
+    pub(super) const STATE: Self = Self {
+        name: "clean-state",
+        dirs: |ctx| vec![ctx.state_dir()],
+    };
+
+    pub(super) const TOOLS: Self = Self {
+        name: "clean-tools",
+        dirs: |ctx| vec![ctx.tools_dir.clone()],
+    };
+
+    pub(super) const METADATA: Self = Self {
+        name: "clean-metadata",
+        dirs: |ctx| vec![ctx.metadata_dir()],
+    };
+}
+
+#[async_trait]
+impl Task for CleanDir {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        for dir in (self.dirs)(ctx) {
+            fs::remove_dir_all(dir).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes the caches of crates and Go modules.
+#[derive(Debug)]
+pub(super) struct PurgeCache;
+
+#[async_trait]
+impl Task for PurgeCache {
+    fn name(&self) -> &'static str {
+        "purge-cache"
+    }
+
+    fn dependencies(&self) -> &'static [&'static str] {
+        &["purge-go-vendor", "purge-cargo"]
+    }
+
+    async fn run(&self, _ctx: &TaskContext) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Removes the Go module cache.
+#[derive(Debug)]
+pub(super) struct PurgeGoVendor;
+
+#[async_trait]
+impl Task for PurgeGoVendor {
+    fn name(&self) -> &'static str {
+        "purge-go-vendor"
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        let cache = ctx.go_mod_cache();
+        if !cache.is_dir() {
+            return Ok(());
+        }
+        // Go makes its module cache read-only, so it has to be made writable to be removed.
+        // See https://github.com/golang/go/issues/27455
+        exec_log(Command::new("chmod").arg("-R").arg("755").arg(&cache)).await?;
+        fs::remove_dir_all(cache).await
+    }
+}
+
+/// Removes the crates in `CARGO_HOME`.
+#[derive(Debug)]
+pub(super) struct PurgeCargo;
+
+#[async_trait]
+impl Task for PurgeCargo {
+    fn name(&self) -> &'static str {
+        "purge-cargo"
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        fs::remove_dir_all(&ctx.cargo_home).await
+    }
+}
@@ -0,0 +1,236 @@
+//! The tasks that prepare the build directories and fetch everything a build needs before it can
+//! run without the network: the SDK image, the Rust crates, and the vendored Go modules.
+
+use super::{Task, TaskContext};
+use crate::common::{exec_log, fs};
+use anyhow::{bail, ensure, Result};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+/// Checks the architecture and creates the build directories.
+#[derive(Debug)]
+pub(super) struct Setup;
+
+#[async_trait]
+impl Task for Setup {
+    fn name(&self) -> &'static str {
+        "setup"
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        match ctx.arch.as_str() {
+            "x86_64" | "aarch64" => (),
+            arch => bail!(
+                "Unrecognized architecture '{}'; please use 'x86_64' or 'aarch64'",
+                arch
+            ),
+        }
+        for dir in [
+            ctx.build_dir(),
+            ctx.output_dir(),
+            ctx.packages_dir(),
+            ctx.kits_dir(),
+            ctx.external_kits_dir(),
+            ctx.state_dir(),
+            ctx.metadata_dir(),
+            ctx.go_mod_cache(),
+        ] {
+            fs::create_dir_all(dir).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the programs a build runs are installed.
+#[derive(Debug)]
+pub(super) struct SetupBuild;
+
+#[async_trait]
+impl Task for SetupBuild {
+    fn name(&self) -> &'static str {
+        "setup-build"
+    }
+
+    fn dependencies(&self) -> &'static [&'static str] {
+        &["setup"]
+    }
+
+    async fn run(&self, _ctx: &TaskContext) -> Result<()> {
+        for program in ["docker", "gzip", "lz4"] {
+            ensure!(
+                which(program).is_some(),
+                "required program '{}' not found",
+                program
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Fetches everything.
+#[derive(Debug)]
+pub(super) struct Fetch;
+
+#[async_trait]
+impl Task for Fetch {
+    fn name(&self) -> &'static str {
+        "fetch"
+    }
+
+    fn dependencies(&self) -> &'static [&'static str] {
+        &["fetch-sdk", "fetch-sources", "fetch-vendored"]
+    }
+
+    async fn run(&self, _ctx: &TaskContext) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Pulls the SDK image, unless it's already present.
+#[derive(Debug)]
+pub(super) struct FetchSdk;
+
+#[async_trait]
+impl Task for FetchSdk {
+    fn name(&self) -> &'static str {
+        "fetch-sdk"
+    }
+
+    fn dependencies(&self) -> &'static [&'static str] {
+        &["setup-build"]
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        // Twoliter leaves this marker once it has verified the SDK against the lockfile.
+        let verified = ctx.external_kits_dir().join(".sdk-verified");
+        ensure!(
+            fs::metadata(&verified).await.is_ok_and(|m| m.len() > 0),
+            "Twoliter could not validate '{}', refusing to continue",
+            ctx.sdk_image
+        );
+
+        let present = Command::new("docker")
+            .args(["image", "inspect", ctx.sdk_image.as_str()])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await
+            .is_ok_and(|status| status.success());
+        if !present {
+            exec_log(Command::new("docker").args(["pull", ctx.sdk_image.as_str()]))
+                .await
+                .map_err(|e| e.context(format!("failed to pull '{}'", ctx.sdk_image)))?;
+        }
+        Ok(())
+    }
+}
+
+/// The Cargo workspaces whose crates are fetched, relative to the project directory.
+const WORKSPACES: [&str; 3] = ["sources", "variants", "."];
+
+/// Fetches the crates of the project's Cargo workspaces into `CARGO_HOME`.
+#[derive(Debug)]
+pub(super) struct FetchSources;
+
+impl FetchSources {
+    fn manifests(ctx: &TaskContext) -> Vec<PathBuf> {
+        WORKSPACES
+            .iter()
+            .map(|ws| ctx.project_dir.join(ws).join("Cargo.toml"))
+            .filter(|manifest| manifest.metadata().is_ok_and(|m| m.len() > 0))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Task for FetchSources {
+    fn name(&self) -> &'static str {
+        "fetch-sources"
+    }
+
+    fn dependencies(&self) -> &'static [&'static str] {
+        &["setup"]
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        for manifest in Self::manifests(ctx) {
+            exec_log(
+                Command::new("cargo")
+                    .args(["fetch", "--locked", "--manifest-path"])
+                    .arg(&manifest)
+                    .env("CARGO_HOME", &ctx.cargo_home),
+            )
+            .await?;
+        }
+
+        // The crates are read by builds that run as another user in the SDK.
+        exec_log(
+            Command::new("chmod")
+                .arg("-R")
+                .arg("o+r")
+                .arg(&ctx.cargo_home),
+        )
+        .await
+    }
+}
+
+/// Vendors the dependencies of each Go module in the project's sources.
+#[derive(Debug)]
+pub(super) struct FetchVendored;
+
+#[async_trait]
+impl Task for FetchVendored {
+    fn name(&self) -> &'static str {
+        "fetch-vendored"
+    }
+
+    fn dependencies(&self) -> &'static [&'static str] {
+        &["fetch-sdk"]
+    }
+
+    fn inputs(&self, ctx: &TaskContext) -> Vec<PathBuf> {
+        ctx.go_modules
+            .iter()
+            .flat_map(|module| {
+                let dir = ctx.sources_dir().join(module);
+                [dir.join("go.mod"), dir.join("go.sum")]
+            })
+            .collect()
+    }
+
+    fn outputs(&self, ctx: &TaskContext) -> Vec<PathBuf> {
+        ctx.go_modules
+            .iter()
+            .map(|module| ctx.sources_dir().join(module).join("vendor/modules.txt"))
+            .collect()
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()> {
+        for module in &ctx.go_modules {
+            exec_log(
+                Command::new(ctx.tools_dir.join("docker-go"))
+                    .arg("--module-path")
+                    .arg(ctx.sources_dir().join(module))
+                    .args(["--sdk-image", ctx.sdk_image.as_str()])
+                    .arg("--go-mod-cache")
+                    .arg(ctx.go_mod_cache())
+                    .args([
+                        "--command",
+                        "go list -mod=readonly ./... >/dev/null && go mod vendor",
+                    ]),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// The path of `program` if it's found in `PATH`.
+fn which(program: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH").and_then(|path| {
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .find(|candidate| candidate.is_file())
+    })
+}
@@ -0,0 +1,400 @@
+/*!
+Native implementations of build steps that used to live only in `Makefile.toml`.
+
+Each step is a [`Task`] with a name, the tasks it depends on, and the files it reads and writes.
+The [`TaskRunner`] runs a task after its dependencies, in order, and skips any task whose declared
+outputs are newer than its declared inputs. Tasks are named after the `Makefile.toml` tasks they
+replace, so `twoliter make` can run the native version of a task and fall back to `cargo make` for
+the rest.
+
+Tasks find the build directories through the same `BUILDSYS_*` variables that `Makefile.toml`
+reads, with the same defaults, so a directory that's moved for `cargo make` is moved for the native
+tasks too.
+*/
+mod clean;
+mod fetch;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, info};
+
+/// The settings that tasks share, which `Makefile.toml` gets from its environment.
+#[derive(Debug, Clone)]
+pub(crate) struct TaskContext {
+    pub(crate) project_dir: PathBuf,
+    pub(crate) arch: String,
+    pub(crate) sdk_image: String,
+    pub(crate) tools_dir: PathBuf,
+    pub(crate) cargo_home: PathBuf,
+    pub(crate) go_modules: Vec<String>,
+    /// Variables that Twoliter passes to `cargo make` on top of its environment, such as those
+    /// set in `Twoliter.toml`. The environment takes precedence.
+    pub(crate) envs: BTreeMap<String, String>,
+}
+
+impl TaskContext {
+    /// The value of the variable `name`, if it's set and not empty.
+    fn var(&self, name: &str) -> Option<String> {
+        std::env::var(name)
+            .ok()
+            .or_else(|| self.envs.get(name).cloned())
+            .filter(|value| !value.is_empty())
+    }
+
+    /// The directory named by the variable `name`, or `default` if it isn't set. Relative paths
+    /// are relative to the project directory, which is where `cargo make` runs.
+    fn dir(&self, name: &str, default: impl FnOnce() -> PathBuf) -> PathBuf {
+        match self.var(name) {
+            Some(dir) => self.project_dir.join(dir),
+            None => default(),
+        }
+    }
+
+    pub(crate) fn build_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_BUILD_DIR", || self.project_dir.join("build"))
+    }
+
+    pub(crate) fn packages_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_PACKAGES_DIR", || self.build_dir().join("rpms"))
+    }
+
+    pub(crate) fn kits_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_KITS_DIR", || self.build_dir().join("kits"))
+    }
+
+    pub(crate) fn external_kits_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_EXTERNAL_KITS_DIR", || {
+            self.build_dir().join("external-kits")
+        })
+    }
+
+    pub(crate) fn state_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_STATE_DIR", || self.build_dir().join("state"))
+    }
+
+    pub(crate) fn images_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_IMAGES_DIR", || self.build_dir().join("images"))
+    }
+
+    pub(crate) fn logs_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_LOGS_DIR", || self.build_dir().join("logs"))
+    }
+
+    pub(crate) fn metadata_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_METADATA_DIR", || {
+            self.build_dir().join("metadata")
+        })
+    }
+
+    pub(crate) fn repos_dir(&self) -> PathBuf {
+        self.dir("PUBLISH_REPO_BASE_DIR", || self.build_dir().join("repos"))
+    }
+
+    /// The images directory of the variant that `BUILDSYS_VARIANT` names.
+    pub(crate) fn output_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_OUTPUT_DIR", || {
+            // The same default variant as `Makefile.toml`.
+            let variant = self
+                .var("BUILDSYS_VARIANT")
+                .unwrap_or_else(|| "aws-k8s-1.24".to_string());
+            self.images_dir().join(format!("{}-{}", self.arch, variant))
+        })
+    }
+
+    pub(crate) fn sources_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_SOURCES_DIR", || self.project_dir.join("sources"))
+    }
+
+    /// The project's own tools, not the ones that Twoliter installs in `tools_dir`.
+    pub(crate) fn project_tools_dir(&self) -> PathBuf {
+        self.dir("BUILDSYS_TOOLS_DIR", || self.project_dir.join("tools"))
+    }
+
+    pub(crate) fn go_mod_cache(&self) -> PathBuf {
+        self.dir("GO_MOD_CACHE", || {
+            self.project_dir.join(".gomodcache/pkg/mod")
+        })
+    }
+}
+
+/// A step of the build.
+#[async_trait]
+pub(crate) trait Task: Debug + Send + Sync {
+    /// The name of the task, which matches the `Makefile.toml` task it replaces.
+    fn name(&self) -> &'static str;
+
+    /// The names of the tasks that must run before this one.
+    fn dependencies(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The files this task reads. A change to any of them means the task must run again.
+    fn inputs(&self, _ctx: &TaskContext) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// The files this task writes. A task that declares no outputs always runs.
+    fn outputs(&self, _ctx: &TaskContext) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    async fn run(&self, ctx: &TaskContext) -> Result<()>;
+}
+
+/// Every task with a native implementation.
+fn all_tasks() -> Vec<Box<dyn Task>> {
+    vec![
+        Box::new(fetch::Setup),
+        Box::new(fetch::SetupBuild),
+        Box::new(fetch::Fetch),
+        Box::new(fetch::FetchSdk),
+        Box::new(fetch::FetchSources),
+        Box::new(fetch::FetchVendored),
+        Box::new(clean::Clean),
+        Box::new(clean::CleanSources),
+        Box::new(clean::CleanWorkspace),
+        Box::new(clean::CleanDir::PACKAGES),
+        Box::new(clean::CleanDir::KITS),
+        Box::new(clean::CleanDir::IMAGES),
+        Box::new(clean::CleanDir::LOGS),
+        Box::new(clean::CleanDir::REPOS),
+        Box::new(clean::CleanDir::STATE),
+        Box::new(clean::CleanDir::TOOLS),
+        Box::new(clean::CleanDir::METADATA),
+        Box::new(clean::PurgeCache),
+        Box::new(clean::PurgeGoVendor),
+        Box::new(clean::PurgeCargo),
+    ]
+}
+
+/// Runs native tasks along with their dependencies.
+#[derive(Debug)]
+pub(crate) struct TaskRunner {
+    tasks: Vec<Box<dyn Task>>,
+}
+
+impl Default for TaskRunner {
+    fn default() -> Self {
+        Self { tasks: all_tasks() }
+    }
+}
+
+impl TaskRunner {
+    /// Whether `name` has a native implementation.
+    pub(crate) fn has_task(&self, name: &str) -> bool {
+        self.task(name).is_some()
+    }
+
+    fn task(&self, name: &str) -> Option<&dyn Task> {
+        self.tasks
+            .iter()
+            .find(|task| task.name() == name)
+            .map(|task| task.as_ref())
+    }
+
+    /// The tasks to run for `name`, each one after its dependencies.
+    fn plan(&self, name: &str) -> Result<Vec<&dyn Task>> {
+        fn visit<'a>(
+            runner: &'a TaskRunner,
+            name: &str,
+            visiting: &mut Vec<String>,
+            planned: &mut Vec<&'a dyn Task>,
+        ) -> Result<()> {
+            if planned.iter().any(|task| task.name() == name) {
+                return Ok(());
+            }
+            if visiting.iter().any(|n| n == name) {
+                bail!(
+                    "Task '{}' depends on itself: {} -> {}",
+                    name,
+                    visiting.join(" -> "),
+                    name
+                );
+            }
+            let task = runner
+                .task(name)
+                .with_context(|| format!("Task '{}' has no native implementation", name))?;
+            visiting.push(name.to_string());
+            for dependency in task.dependencies() {
+                visit(runner, dependency, visiting, planned)?;
+            }
+            visiting.pop();
+            planned.push(task);
+            Ok(())
+        }
+
+        let mut planned = Vec::new();
+        visit(self, name, &mut Vec::new(), &mut planned)?;
+        Ok(planned)
+    }
+
+    /// Run the task named `name` after its dependencies.
+    pub(crate) async fn run(&self, name: &str, ctx: &TaskContext) -> Result<()> {
+        for task in self.plan(name)? {
+            if is_up_to_date(&task.inputs(ctx), &task.outputs(ctx))? {
+                info!("Skipping task '{}', which is up to date", task.name());
+                continue;
+            }
+            info!("Running task '{}'", task.name());
+            task.run(ctx)
+                .await
+                .with_context(|| format!("Task '{}' failed", task.name()))?;
+        }
+        Ok(())
+    }
+}
+
+/// A task is up to date when it declares outputs, they all exist, and none of them is older than
+/// the newest of its inputs.
+fn is_up_to_date(inputs: &[PathBuf], outputs: &[PathBuf]) -> Result<bool> {
+    if outputs.is_empty() {
+        return Ok(false);
+    }
+    let Some(oldest_output) = oldest(outputs)? else {
+        return Ok(false);
+    };
+    for input in inputs {
+        if let Some(modified) = modified(input)? {
+            if modified > oldest_output {
+                debug!("'{}' changed since the last run", input.display());
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// The modification time of the oldest path, or `None` if any of them is missing.
+fn oldest(paths: &[PathBuf]) -> Result<Option<SystemTime>> {
+    let mut oldest = None;
+    for path in paths {
+        match modified(path)? {
+            Some(modified) => {
+                oldest = Some(oldest.map_or(modified, |o: SystemTime| o.min(modified)));
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(oldest)
+}
+
+fn modified(path: &Path) -> Result<Option<SystemTime>> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.modified().with_context(|| {
+            format!(
+                "Unable to get the modification time of '{}'",
+                path.display()
+            )
+        })?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => {
+            Err(e).with_context(|| format!("Unable to get metadata for '{}'", path.display()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use filetime::{set_file_mtime, FileTime};
+
+    #[test]
+    fn test_plan_orders_dependencies_first() {
+        let runner = TaskRunner::default();
+        let plan = runner
+            .plan("fetch")
+            .unwrap()
+            .iter()
+            .map(|task| task.name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            plan,
+            vec![
+                "setup",
+                "setup-build",
+                "fetch-sdk",
+                "fetch-sources",
+                "fetch-vendored",
+                "fetch"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_task() {
+        let runner = TaskRunner::default();
+        assert!(!runner.has_task("build-variant"));
+        assert!(runner.plan("build-variant").is_err());
+    }
+
+    fn context(envs: &[(&str, &str)]) -> TaskContext {
+        TaskContext {
+            project_dir: PathBuf::from("/project"),
+            arch: "x86_64".to_string(),
+            sdk_image: "sdk".to_string(),
+            tools_dir: PathBuf::from("/project/build/tools"),
+            cargo_home: PathBuf::from("/project/.cargo"),
+            go_modules: Vec::new(),
+            envs: envs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_dirs() {
+        let ctx = context(&[]);
+        assert_eq!(ctx.packages_dir(), PathBuf::from("/project/build/rpms"));
+        assert_eq!(
+            ctx.output_dir(),
+            PathBuf::from("/project/build/images/x86_64-aws-k8s-1.24")
+        );
+
+        let ctx = context(&[
+            ("BUILDSYS_BUILD_DIR", "/scratch/build"),
+            ("BUILDSYS_KITS_DIR", "kits-out"),
+            ("BUILDSYS_VARIANT", "aws-dev"),
+            ("BUILDSYS_STATE_DIR", ""),
+        ]);
+        assert_eq!(ctx.packages_dir(), PathBuf::from("/scratch/build/rpms"));
+        assert_eq!(ctx.kits_dir(), PathBuf::from("/project/kits-out"));
+        assert_eq!(ctx.state_dir(), PathBuf::from("/scratch/build/state"));
+        assert_eq!(
+            ctx.output_dir(),
+            PathBuf::from("/scratch/build/images/x86_64-aws-dev")
+        );
+    }
+
+    #[test]
+    fn test_plan_clean() {
+        let runner = TaskRunner::default();
+        let plan = runner.plan("clean").unwrap();
+        assert_eq!(plan.len(), 11);
+        assert_eq!(plan.last().unwrap().name(), "clean");
+    }
+
+    #[test]
+    fn test_is_up_to_date() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = dir.path().join("go.mod");
+        let output = dir.path().join("modules.txt");
+        std::fs::write(&input, "module example").unwrap();
+
+        // Missing outputs, or no outputs at all, mean the task has to run.
+        assert!(!is_up_to_date(&[input.clone()], &[output.clone()]).unwrap());
+        assert!(!is_up_to_date(&[input.clone()], &[]).unwrap());
+
+        std::fs::write(&output, "# example").unwrap();
+        set_file_mtime(&input, FileTime::from_unix_time(1_000, 0)).unwrap();
+        set_file_mtime(&output, FileTime::from_unix_time(2_000, 0)).unwrap();
+        assert!(is_up_to_date(&[input.clone()], &[output.clone()]).unwrap());
+
+        set_file_mtime(&input, FileTime::from_unix_time(3_000, 0)).unwrap();
+        assert!(!is_up_to_date(&[input], &[output]).unwrap());
+    }
+}